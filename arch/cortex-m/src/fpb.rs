@@ -0,0 +1,151 @@
+//! ARM Cortex-M Flash Patch and Breakpoint unit.
+//!
+//! The FPB compares instruction fetches against a small set of code
+//! comparators and raises a debug event when one matches. Without a halting
+//! debugger connected the event is taken as the DebugMonitor exception, which
+//! `Fpb::enable()` turns on. Chips that route the DebugMonitor vector to the
+//! hard fault handler then see a breakpoint hit in a process as a fault of that
+//! process, which lets an in-kernel debugger such as the GDB stub capsule stop
+//! it. ARMv6-M has no DebugMonitor exception, and raises a hard fault instead.
+//! Breakpoints hit in the kernel are not supported, and `set` cannot tell
+//! kernel code from process code, so callers only set addresses in the flash of
+//! the process they debug.
+//!
+//! Documented in the ARMv7-M Architecture Reference Manual, C1.11, and the
+//! ARMv6-M Architecture Reference Manual, C1.8.
+
+use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite};
+use kernel::common::StaticRef;
+use kernel::hil::breakpoint::Breakpoints;
+use kernel::ErrorCode;
+
+/// The number of code comparators this driver uses at most. Implementations
+/// provide from 2 to 8 in practice.
+const MAX_COMPARATORS: usize = 8;
+
+#[repr(C)]
+struct FpbRegisters {
+    fp_ctrl: ReadWrite<u32, Control::Register>,
+    _fp_remap: ReadOnly<u32>,
+    fp_comp: [ReadWrite<u32, Comparator::Register>; MAX_COMPARATORS],
+}
+
+register_bitfields![u32,
+    Control [
+        /// FPB architecture revision: 0 for version 1, 1 for version 2.
+        REV             OFFSET(28) NUMBITS(4),
+
+        /// Most significant bits of the number of code comparators.
+        NUM_CODE2       OFFSET(12) NUMBITS(3),
+
+        /// Least significant bits of the number of code comparators.
+        NUM_CODE1       OFFSET(4)  NUMBITS(4),
+
+        /// Must be written as 1 for a write to the register to take effect.
+        KEY             OFFSET(1)  NUMBITS(1),
+
+        /// Enable the FPB.
+        ENABLE          OFFSET(0)  NUMBITS(1)
+    ],
+
+    Comparator [
+        /// Version 1: which halfword of the word at `COMP` to break on.
+        REPLACE         OFFSET(30) NUMBITS(2) [
+            Remap = 0b00,
+            LowerHalfword = 0b01,
+            UpperHalfword = 0b10,
+            BothHalfwords = 0b11
+        ],
+
+        /// Version 1: bits [28:2] of the address to compare against.
+        COMP            OFFSET(2)  NUMBITS(27) [],
+
+        /// Enable the comparator.
+        ENABLE          OFFSET(0)  NUMBITS(1) []
+    ]
+];
+
+const FPB_BASE: StaticRef<FpbRegisters> =
+    unsafe { StaticRef::new(0xE0002000 as *const FpbRegisters) };
+
+/// Debug Exception and Monitor Control Register, part of the System Control
+/// Block debug registers.
+const DEMCR: StaticRef<ReadWrite<u32>> =
+    unsafe { StaticRef::new(0xE000EDFC as *const ReadWrite<u32>) };
+
+/// Enable the DebugMonitor exception.
+const DEMCR_MON_EN: u32 = 1 << 16;
+
+/// The ARM Cortex-M Flash Patch and Breakpoint unit, as `Breakpoints`.
+pub struct Fpb {
+    _private: (),
+}
+
+pub static FPB: Fpb = unsafe { Fpb::new() };
+
+impl Fpb {
+    /// Create the driver. Only `FPB` should exist, as all share the hardware.
+    pub const unsafe fn new() -> Fpb {
+        Fpb { _private: () }
+    }
+
+    /// Enable the FPB and the DebugMonitor exception, with every comparator
+    /// cleared.
+    pub fn enable(&self) {
+        for index in 0..self.count() {
+            self.clear(index);
+        }
+        FPB_BASE
+            .fp_ctrl
+            .write(Control::KEY::SET + Control::ENABLE::SET);
+        DEMCR.set(DEMCR.get() | DEMCR_MON_EN);
+    }
+
+    /// Disable the FPB and the DebugMonitor exception.
+    pub fn disable(&self) {
+        FPB_BASE
+            .fp_ctrl
+            .write(Control::KEY::SET + Control::ENABLE::CLEAR);
+        DEMCR.set(DEMCR.get() & !DEMCR_MON_EN);
+    }
+}
+
+impl Breakpoints for Fpb {
+    fn count(&self) -> usize {
+        let num_code = (FPB_BASE.fp_ctrl.read(Control::NUM_CODE2) << 4)
+            | FPB_BASE.fp_ctrl.read(Control::NUM_CODE1);
+        core::cmp::min(num_code as usize, MAX_COMPARATORS)
+    }
+
+    fn set(&self, index: usize, address: usize) -> Result<(), ErrorCode> {
+        if index >= self.count() || address & 1 != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+
+        if FPB_BASE.fp_ctrl.read(Control::REV) == 0 {
+            // Version 1 can only break on the code region, below 0x20000000,
+            // and selects the halfword of an aligned word.
+            if address >= 0x2000_0000 {
+                return Err(ErrorCode::INVAL);
+            }
+            let replace = if address & 2 == 0 {
+                Comparator::REPLACE::LowerHalfword
+            } else {
+                Comparator::REPLACE::UpperHalfword
+            };
+            FPB_BASE.fp_comp[index].write(
+                replace + Comparator::COMP.val((address as u32) >> 2) + Comparator::ENABLE::SET,
+            );
+        } else {
+            // Version 2 compares the whole address, with bit 0 as the enable.
+            FPB_BASE.fp_comp[index].set(address as u32 | 1);
+        }
+        Ok(())
+    }
+
+    fn clear(&self, index: usize) {
+        if index < MAX_COMPARATORS {
+            FPB_BASE.fp_comp[index].set(0);
+        }
+    }
+}
@@ -8,9 +8,11 @@
 
 use core::fmt::Write;
 
+pub mod fpb;
 pub mod mpu;
 pub mod nvic;
 pub mod scb;
+pub mod semihosting;
 pub mod support;
 pub mod syscall;
 pub mod systick;
@@ -526,15 +528,19 @@ unsafe extern "C" fn hard_fault_handler_arm_v7m_continued(
         // marked as in an error state and handled by the kernel.
         asm!(
             "
-        /* Read the relevant SCB registers. */
+        /* Read the relevant SCB registers. The fault status bits stay set
+         * until written, so clear them for the next fault to report only
+         * its own causes. */
         ldr r0, =SCB_REGISTERS  /* Global variable address */
         ldr r1, =0xE000ED14     /* SCB CCR register address */
         ldr r2, [r1, #0]        /* CCR */
         str r2, [r0, #0]
         ldr r2, [r1, #20]       /* CFSR */
         str r2, [r0, #4]
+        str r2, [r1, #20]       /* Clear the CFSR bits that are set */
         ldr r2, [r1, #24]       /* HFSR */
         str r2, [r0, #8]
+        str r2, [r1, #24]       /* Clear the HFSR bits that are set */
         ldr r2, [r1, #32]       /* MMFAR */
         str r2, [r0, #12]
         ldr r2, [r1, #36]       /* BFAR */
@@ -619,11 +625,17 @@ pub unsafe extern "C" fn hard_fault_handler_arm_v7m() {
 }
 
 pub unsafe fn print_cortexm_state(writer: &mut dyn Write) {
-    let _ccr = syscall::SCB_REGISTERS[0];
-    let cfsr = syscall::SCB_REGISTERS[1];
-    let hfsr = syscall::SCB_REGISTERS[2];
-    let mmfar = syscall::SCB_REGISTERS[3];
-    let bfar = syscall::SCB_REGISTERS[4];
+    print_fault_status(&syscall::SCB_REGISTERS, writer);
+}
+
+/// Decode the SCB fault registers, in the order they are stored in
+/// `SCB_REGISTERS`, into the causes of a fault.
+pub(crate) fn print_fault_status(scb_registers: &[u32; 5], writer: &mut dyn Write) {
+    let _ccr = scb_registers[0];
+    let cfsr = scb_registers[1];
+    let hfsr = scb_registers[2];
+    let mmfar = scb_registers[3];
+    let bfar = scb_registers[4];
 
     let iaccviol = (cfsr & 0x01) == 0x01;
     let daccviol = (cfsr & 0x02) == 0x02;
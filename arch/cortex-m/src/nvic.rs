@@ -141,6 +141,32 @@ pub unsafe fn has_pending() -> bool {
         != 0
 }
 
+/// Like `next_pending()`, but ignores the interrupts whose bits are set in
+/// `mask`, which holds one word per 32 interrupts. Interrupts past the end of
+/// `mask` are not ignored.
+pub unsafe fn next_pending_with_mask(mask: &[u32]) -> Option<u32> {
+    for (block, ispr) in NVIC
+        .ispr
+        .iter()
+        .take(number_of_nvic_registers())
+        .enumerate()
+    {
+        let ispr = ispr.get() & !mask.get(block).copied().unwrap_or(0);
+
+        if ispr != 0 {
+            let bit = ispr.trailing_zeros();
+            return Some(block as u32 * 32 + bit);
+        }
+    }
+    None
+}
+
+/// Like `has_pending()`, but ignores the interrupts whose bits are set in
+/// `mask`.
+pub unsafe fn has_pending_with_mask(mask: &[u32]) -> bool {
+    next_pending_with_mask(mask).is_some()
+}
+
 /// An opaque wrapper for a single NVIC interrupt.
 ///
 /// Hand these out to low-level driver to let them control their own interrupts
@@ -176,4 +202,12 @@ impl Nvic {
 
         NVIC.icpr[idx / 32].set(1 << (self.0 & 31));
     }
+
+    /// Set pending state, so the interrupt is serviced as if the peripheral
+    /// had raised it
+    pub fn set_pending(&self) {
+        let idx = self.0 as usize;
+
+        NVIC.ispr[idx / 32].set(1 << (self.0 & 31));
+    }
 }
@@ -0,0 +1,96 @@
+//! ARM semihosting console and exit, for running under QEMU or a debugger.
+//!
+//! Semihosting requests are `bkpt 0xAB` instructions that the host catches.
+//! Without a host to catch them, for example on hardware without a debugger
+//! attached, they fault, so boards must only use this when they are built to
+//! run in a simulator. QEMU needs `-semihosting` to enable it.
+//!
+//! `Semihosting` implements `IoWrite`, so it can be used as the writer of the
+//! panic handler, and `SimulationExit`, so simulated runs end with the exit
+//! code of the test result:
+//!
+//! ```rust
+//! let runner = static_init!(
+//!     capsules::self_test::SelfTestRunner<'static>,
+//!     capsules::self_test::SelfTestRunner::new(tests, &cortexm::semihosting::SEMIHOSTING)
+//! );
+//! ```
+
+use core::fmt::Write;
+
+use kernel::debug::IoWrite;
+use kernel::simulation::{self, SimulationExit, SimulationResult};
+
+/// Write the character pointed to by the argument to the debug console.
+const SYS_WRITEC: usize = 0x03;
+/// Report that the application exited, for a reason but without a code.
+const SYS_EXIT: usize = 0x18;
+/// Report that the application exited, for a reason and with a code.
+const SYS_EXIT_EXTENDED: usize = 0x20;
+
+/// Exit reasons for `SYS_EXIT`.
+const ADP_STOPPED_APPLICATION_EXIT: usize = 0x20026;
+const ADP_STOPPED_RUN_TIME_ERROR_UNKNOWN: usize = 0x20023;
+
+/// Make a semihosting request.
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+unsafe fn call(operation: usize, argument: usize) -> usize {
+    let result;
+    asm!(
+        "bkpt #0xab",
+        inout("r0") operation => result,
+        in("r1") argument,
+        options(nostack, preserves_flags),
+    );
+    result
+}
+
+// Mock implementation for tests on Travis-CI.
+#[cfg(not(any(target_arch = "arm", target_os = "none")))]
+unsafe fn call(_operation: usize, _argument: usize) -> usize {
+    unimplemented!()
+}
+
+pub struct Semihosting {}
+
+pub static SEMIHOSTING: Semihosting = Semihosting {};
+
+impl Write for Semihosting {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl IoWrite for Semihosting {
+    fn write(&mut self, buf: &[u8]) {
+        for byte in buf {
+            unsafe {
+                call(SYS_WRITEC, byte as *const u8 as usize);
+            }
+        }
+    }
+}
+
+impl SimulationExit for Semihosting {
+    fn exit(&self, result: SimulationResult) -> ! {
+        unsafe {
+            simulation::report(&mut Semihosting {}, result);
+
+            let block = [ADP_STOPPED_APPLICATION_EXIT, result.code() as usize];
+            call(SYS_EXIT_EXTENDED, block.as_ptr() as usize);
+
+            // Hosts that do not support `SYS_EXIT_EXTENDED` return, so fall
+            // back to an exit reason, which QEMU turns into 0 or 1.
+            let reason = match result {
+                SimulationResult::Pass => ADP_STOPPED_APPLICATION_EXIT,
+                SimulationResult::Fail(_) => ADP_STOPPED_RUN_TIME_ERROR_UNKNOWN,
+            };
+            call(SYS_EXIT, reason);
+
+            loop {
+                crate::support::wfi();
+            }
+        }
+    }
+}
@@ -37,6 +37,15 @@ extern "C" {
 // Space for 8 u32s: r0-r3, r12, lr, pc, and xPSR
 const SVC_FRAME_SIZE: usize = 32;
 
+/// What the hardfault handler recorded when a process faulted.
+#[derive(Clone, Copy)]
+struct ProcessFault {
+    /// The SCB registers, in the order of `SCB_REGISTERS`.
+    scb_registers: [u32; 5],
+    /// The stacked PC, if the stack pointer was valid.
+    pc: Option<usize>,
+}
+
 /// This holds all of the state that the kernel must keep for the process when
 /// the process is not executing.
 #[derive(Default)]
@@ -45,6 +54,7 @@ pub struct CortexMStoredState {
     yield_pc: usize,
     psr: usize,
     psp: usize,
+    fault: Option<ProcessFault>,
 }
 
 /// Implementation of the `UserspaceKernelBoundary` for the Cortex-M non-floating point
@@ -79,6 +89,7 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
         state.yield_pc = 0;
         state.psr = 0x01000000; // Set the Thumb bit and clear everything else.
         state.psp = app_brk as usize; // Set to top of process-accessible memory.
+        state.fault = None;
 
         // Make sure there's enough room on the stack for the initial SVC frame.
         if (app_brk as usize - accessible_memory_start as usize) < SVC_FRAME_SIZE {
@@ -215,6 +226,19 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
         let syscall_fired = read_volatile(&SYSCALL_FIRED);
         write_volatile(&mut SYSCALL_FIRED, 0);
 
+        // Keep the fault registers with the process, so they can be shown
+        // when it is printed even if other processes fault later.
+        if app_fault == 1 {
+            state.fault = Some(ProcessFault {
+                scb_registers: read_volatile(&SCB_REGISTERS),
+                pc: if invalid_stack_pointer {
+                    None
+                } else {
+                    Some(read_volatile(new_stack_pointer.offset(6)))
+                },
+            });
+        }
+
         // Now decide the reason based on which flags were set.
         let switch_reason = if app_fault == 1 || invalid_stack_pointer {
             // APP_HARD_FAULT takes priority. This means we hit the hardfault
@@ -266,6 +290,23 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
         state: &CortexMStoredState,
         writer: &mut dyn Write,
     ) {
+        if let Some(fault) = state.fault {
+            crate::print_fault_status(&fault.scb_registers, writer);
+            match fault.pc {
+                Some(pc) => {
+                    let _ = writer.write_fmt(format_args!(
+                        "Faulting PC:                        {:#010X}\r\n",
+                        pc
+                    ));
+                }
+                None => {
+                    let _ = writer.write_fmt(format_args!(
+                        "Faulting PC:                        unknown, invalid stack pointer\r\n"
+                    ));
+                }
+            }
+        }
+
         // Validate the stored stack pointer is valid.
         if state.psp < accessible_memory_start as usize
             || (state.psp + SVC_FRAME_SIZE) > app_brk as usize
@@ -344,4 +385,148 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
             },
         ));
     }
+
+    unsafe fn stack_trace(
+        &self,
+        accessible_memory_start: *const u8,
+        app_brk: *const u8,
+        state: &CortexMStoredState,
+        trace: &mut [usize],
+    ) -> usize {
+        // Thumb code uses r7 as the frame pointer. It points to the frame
+        // record of the current function: the frame pointer of its caller,
+        // followed by its return address.
+        let word = mem::size_of::<usize>();
+        let mut frame_pointer = state.regs[3];
+        let mut depth = 0;
+        while depth < trace.len() {
+            if frame_pointer % word != 0
+                || frame_pointer < accessible_memory_start as usize
+                || frame_pointer
+                    .checked_add(2 * word)
+                    .map_or(true, |end| end > app_brk as usize)
+            {
+                break;
+            }
+            let record = frame_pointer as *const usize;
+            let caller_frame_pointer = read_volatile(record);
+            // Clear the Thumb bit.
+            trace[depth] = read_volatile(record.offset(1)) & !1;
+            depth += 1;
+
+            // The stack grows down, so callers have frames at higher
+            // addresses. Anything else is not a frame record.
+            if caller_frame_pointer <= frame_pointer {
+                break;
+            }
+            frame_pointer = caller_frame_pointer;
+        }
+        depth
+    }
+
+    unsafe fn debug_register(
+        &self,
+        accessible_memory_start: *const u8,
+        app_brk: *const u8,
+        state: &CortexMStoredState,
+        index: usize,
+    ) -> Option<usize> {
+        match index {
+            4..=11 => Some(state.regs[index - 4]),
+            _ => {
+                let frame = exception_frame(accessible_memory_start, app_brk, state)?;
+                match index {
+                    0..=3 => Some(read_volatile(frame.add(index))),
+                    GDB_R12 => Some(read_volatile(frame.add(4))),
+                    GDB_SP => {
+                        // The hardware aligned the stack to 8 bytes before
+                        // pushing the frame if bit 9 of the stacked xPSR is set.
+                        let padding = if read_volatile(frame.add(7)) & (1 << 9) != 0 {
+                            4
+                        } else {
+                            0
+                        };
+                        Some(state.psp + SVC_FRAME_SIZE + padding)
+                    }
+                    GDB_LR => Some(read_volatile(frame.add(5))),
+                    GDB_PC => Some(read_volatile(frame.add(6))),
+                    GDB_XPSR => Some(read_volatile(frame.add(7))),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    unsafe fn set_debug_register(
+        &self,
+        accessible_memory_start: *const u8,
+        app_brk: *const u8,
+        state: &mut CortexMStoredState,
+        index: usize,
+        value: usize,
+    ) -> Result<(), ()> {
+        if let 4..=11 = index {
+            state.regs[index - 4] = value;
+            return Ok(());
+        }
+        let frame =
+            exception_frame(accessible_memory_start, app_brk, state).ok_or(())? as *mut usize;
+        let offset = match index {
+            0..=3 => index,
+            GDB_R12 => 4,
+            GDB_LR => 5,
+            // Execution must stay in Thumb state, so the Thumb bit of the
+            // stacked PC and xPSR cannot be changed. The stack pointer moves
+            // the frame itself, so cannot be changed either.
+            GDB_PC => {
+                write_volatile(frame.add(6), value & !1);
+                return Ok(());
+            }
+            _ => return Err(()),
+        };
+        write_volatile(frame.add(offset), value);
+        Ok(())
+    }
+}
+
+/// Register numbers of the GDB M-profile target description, `GDB_TARGET_XML`,
+/// beyond r0-r11.
+const GDB_R12: usize = 12;
+const GDB_SP: usize = 13;
+const GDB_LR: usize = 14;
+const GDB_PC: usize = 15;
+const GDB_XPSR: usize = 16;
+
+/// The GDB target description of a process: the M-profile core registers,
+/// numbered from 0 in order.
+pub const GDB_TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target><architecture>arm</architecture>\
+<feature name=\"org.gnu.gdb.arm.m-profile\">\
+<reg name=\"r0\" bitsize=\"32\"/><reg name=\"r1\" bitsize=\"32\"/>\
+<reg name=\"r2\" bitsize=\"32\"/><reg name=\"r3\" bitsize=\"32\"/>\
+<reg name=\"r4\" bitsize=\"32\"/><reg name=\"r5\" bitsize=\"32\"/>\
+<reg name=\"r6\" bitsize=\"32\"/><reg name=\"r7\" bitsize=\"32\"/>\
+<reg name=\"r8\" bitsize=\"32\"/><reg name=\"r9\" bitsize=\"32\"/>\
+<reg name=\"r10\" bitsize=\"32\"/><reg name=\"r11\" bitsize=\"32\"/>\
+<reg name=\"r12\" bitsize=\"32\"/>\
+<reg name=\"sp\" bitsize=\"32\" type=\"data_ptr\"/>\
+<reg name=\"lr\" bitsize=\"32\"/>\
+<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/>\
+<reg name=\"xpsr\" bitsize=\"32\"/>\
+</feature></target>";
+
+/// The exception frame the hardware stacked when the process last entered the
+/// kernel, if it lies within the memory of the process.
+unsafe fn exception_frame(
+    accessible_memory_start: *const u8,
+    app_brk: *const u8,
+    state: &CortexMStoredState,
+) -> Option<*const usize> {
+    if state.psp < accessible_memory_start as usize || state.psp + SVC_FRAME_SIZE > app_brk as usize
+    {
+        None
+    } else {
+        Some(state.psp as *const usize)
+    }
 }
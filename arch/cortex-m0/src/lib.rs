@@ -9,6 +9,7 @@
 // valid on cortex-m0.
 pub use cortexm::support;
 
+pub use cortexm::fpb;
 pub use cortexm::nvic;
 pub use cortexm::print_cortexm_state as print_cortexm0_state;
 pub use cortexm::syscall;
@@ -12,6 +12,7 @@ pub mod mpu {
 // valid on cortex-m7.
 pub use cortexm::support;
 
+pub use cortexm::fpb;
 pub use cortexm::generic_isr_arm_v7m as generic_isr;
 pub use cortexm::hard_fault_handler_arm_v7m as hard_fault_handler;
 pub use cortexm::initialize_ram_jump_to_main;
@@ -0,0 +1,99 @@
+//! HTIF console and exit, for running under Spike or Verilator harnesses.
+//!
+//! The Host-Target Interface is a pair of 64-bit words, `tohost` and
+//! `fromhost`, that the simulator finds by their symbols in the ELF file and
+//! polls. Writing `(code << 1) | 1` to `tohost` ends the simulation with exit
+//! code `code`; other commands name a device and a command in the top 16 bits.
+//! Boards must only use this in simulators that implement HTIF, as elsewhere
+//! nothing answers and console writes wait forever.
+//!
+//! `Htif` implements `IoWrite`, so it can be used as the writer of the panic
+//! handler, and `SimulationExit`, so simulated runs end with the exit code of
+//! the test result:
+//!
+//! ```rust
+//! let runner = static_init!(
+//!     capsules::self_test::SelfTestRunner<'static>,
+//!     capsules::self_test::SelfTestRunner::new(tests, &rv32i::htif::HTIF)
+//! );
+//! ```
+
+use core::fmt::Write;
+use core::ptr::{read_volatile, write_volatile};
+
+use kernel::debug::IoWrite;
+use kernel::simulation::{self, SimulationExit, SimulationResult};
+
+/// The console device, and its command to write a character.
+const DEVICE_CONSOLE: u32 = 1;
+const CONSOLE_PUTCHAR: u32 = 1;
+
+/// A 64-bit HTIF word, as two 32-bit halves, low half first.
+#[repr(C, align(8))]
+struct HtifWord([u32; 2]);
+
+#[export_name = "tohost"]
+static mut TOHOST: HtifWord = HtifWord([0; 2]);
+
+#[export_name = "fromhost"]
+static mut FROMHOST: HtifWord = HtifWord([0; 2]);
+
+/// Send a command to the host, waiting for any previous command to be taken.
+unsafe fn command(high: u32, low: u32) {
+    while read_volatile(&TOHOST.0[0]) != 0 || read_volatile(&TOHOST.0[1]) != 0 {
+        acknowledge();
+    }
+    // The host may read `tohost` between the two stores, so the half that
+    // makes it a command must be written last. Writing the low half first
+    // would, for a console command, briefly look like an exit.
+    write_volatile(&mut TOHOST.0[1], high);
+    write_volatile(&mut TOHOST.0[0], low);
+}
+
+/// Clear the response to a command, if the host has sent one. Returns
+/// whether it had.
+unsafe fn acknowledge() -> bool {
+    if read_volatile(&FROMHOST.0[0]) == 0 && read_volatile(&FROMHOST.0[1]) == 0 {
+        return false;
+    }
+    write_volatile(&mut FROMHOST.0[0], 0);
+    write_volatile(&mut FROMHOST.0[1], 0);
+    true
+}
+
+pub struct Htif {}
+
+pub static HTIF: Htif = Htif {};
+
+impl Write for Htif {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl IoWrite for Htif {
+    fn write(&mut self, buf: &[u8]) {
+        for byte in buf {
+            unsafe {
+                command(
+                    (DEVICE_CONSOLE << 24) | (CONSOLE_PUTCHAR << 16),
+                    *byte as u32,
+                );
+                while !acknowledge() {}
+            }
+        }
+    }
+}
+
+impl SimulationExit for Htif {
+    fn exit(&self, result: SimulationResult) -> ! {
+        unsafe {
+            simulation::report(&mut Htif {}, result);
+            command(0, (result.code() << 1) | 1);
+            loop {
+                crate::support::wfi();
+            }
+        }
+    }
+}
@@ -9,6 +9,7 @@ use core::fmt::Write;
 
 pub mod clic;
 pub mod epmp;
+pub mod htif;
 pub mod machine_timer;
 pub mod pmp;
 pub mod support;
@@ -508,6 +509,124 @@ pub unsafe fn print_mcause(mcval: csr::mcause::Trap, writer: &mut dyn Write) {
     }
 }
 
+/// The kind of access that raised an exception.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FaultAccess {
+    /// Fetching an instruction.
+    InstructionFetch,
+    /// Loading from memory.
+    Load,
+    /// Storing to memory, or an atomic memory operation.
+    Store,
+    /// The exception was not raised by a memory access.
+    None,
+}
+
+/// What caused an exception, decoded from `mcause` and `mtval`.
+///
+/// What `mtval` holds depends on the exception, and cores may leave it zero
+/// where the privileged specification allows them to, so `address` and
+/// `instruction` are only set when `mtval` has something to say.
+#[derive(Copy, Clone, Debug)]
+pub struct FaultInfo {
+    pub exception: csr::mcause::Exception,
+    pub access: FaultAccess,
+    /// Whether the access was misaligned, rather than not permitted.
+    pub misaligned: bool,
+    /// Whether the access was translated by a page table.
+    pub page_fault: bool,
+    /// The address that was accessed, or the address of the breakpoint.
+    pub address: Option<usize>,
+    /// The illegal instruction.
+    pub instruction: Option<usize>,
+}
+
+impl FaultInfo {
+    /// Decode an exception. Returns `None` for interrupts and environment
+    /// calls, which are not faults.
+    pub fn decode(mcause: usize, mtval: usize) -> Option<FaultInfo> {
+        let exception = match csr::mcause::Trap::from(mcause) {
+            csr::mcause::Trap::Interrupt(_) => return None,
+            csr::mcause::Trap::Exception(exception) => exception,
+        };
+        let (access, misaligned, page_fault) = match exception {
+            csr::mcause::Exception::UserEnvCall
+            | csr::mcause::Exception::SupervisorEnvCall
+            | csr::mcause::Exception::MachineEnvCall => return None,
+            csr::mcause::Exception::InstructionMisaligned => {
+                (FaultAccess::InstructionFetch, true, false)
+            }
+            csr::mcause::Exception::InstructionFault => {
+                (FaultAccess::InstructionFetch, false, false)
+            }
+            csr::mcause::Exception::InstructionPageFault => {
+                (FaultAccess::InstructionFetch, false, true)
+            }
+            csr::mcause::Exception::LoadMisaligned => (FaultAccess::Load, true, false),
+            csr::mcause::Exception::LoadFault => (FaultAccess::Load, false, false),
+            csr::mcause::Exception::LoadPageFault => (FaultAccess::Load, false, true),
+            csr::mcause::Exception::StoreMisaligned => (FaultAccess::Store, true, false),
+            csr::mcause::Exception::StoreFault => (FaultAccess::Store, false, false),
+            csr::mcause::Exception::StorePageFault => (FaultAccess::Store, false, true),
+            csr::mcause::Exception::IllegalInstruction
+            | csr::mcause::Exception::Breakpoint
+            | csr::mcause::Exception::Unknown => (FaultAccess::None, false, false),
+        };
+
+        // A zero address is still worth reporting for accesses, as it is
+        // what a null pointer dereference looks like.
+        let address = match exception {
+            csr::mcause::Exception::Breakpoint if mtval != 0 => Some(mtval),
+            _ if access != FaultAccess::None => Some(mtval),
+            _ => None,
+        };
+        let instruction = match exception {
+            csr::mcause::Exception::IllegalInstruction if mtval != 0 => Some(mtval),
+            _ => None,
+        };
+
+        Some(FaultInfo {
+            exception,
+            access,
+            misaligned,
+            page_fault,
+            address,
+            instruction,
+        })
+    }
+}
+
+/// Print the decoded cause of a fault.
+pub unsafe fn print_fault_info(info: &FaultInfo, writer: &mut dyn Write) {
+    let _ = writer.write_fmt(format_args!("\r\n---| RISC-V Fault Status |---\r\n"));
+    let _ = writer.write_fmt(format_args!("Cause:             "));
+    print_mcause(csr::mcause::Trap::Exception(info.exception), writer);
+    let _ = writer.write_fmt(format_args!("\r\n"));
+
+    let access = match info.access {
+        FaultAccess::InstructionFetch => "Instruction fetch",
+        FaultAccess::Load => "Load",
+        FaultAccess::Store => "Store/AMO",
+        FaultAccess::None => "None",
+    };
+    let reason = if info.misaligned {
+        " (misaligned)"
+    } else if info.page_fault {
+        " (page fault)"
+    } else if info.access != FaultAccess::None {
+        " (not permitted)"
+    } else {
+        ""
+    };
+    let _ = writer.write_fmt(format_args!("Faulting Access:   {}{}\r\n", access, reason));
+    if let Some(address) = info.address {
+        let _ = writer.write_fmt(format_args!("Faulting Address:  {:#010X}\r\n", address));
+    }
+    if let Some(instruction) = info.instruction {
+        let _ = writer.write_fmt(format_args!("Instruction:       {:#010X}\r\n", instruction));
+    }
+}
+
 /// Prints out RISCV machine state, including basic system registers
 /// (mcause, mstatus, mtvec, mepc, mtval, interrupt status).
 pub unsafe fn print_riscv_state(writer: &mut dyn Write) {
@@ -1,6 +1,8 @@
 //! Kernel-userland system call interface for RISC-V architecture.
 
 use core::fmt::Write;
+use core::mem;
+use core::ptr::read_volatile;
 
 use crate::csr::mcause;
 use kernel;
@@ -34,6 +36,7 @@ pub struct Riscv32iStoredState {
 // restore logic in switch_to_process() below.
 const R_RA: usize = 0;
 const R_SP: usize = 1;
+const R_S0: usize = 7;
 const R_A0: usize = 9;
 const R_A1: usize = 10;
 const R_A2: usize = 11;
@@ -493,8 +496,120 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
         let _ = writer.write_fmt(format_args!(
             ")\
              \r\n mtval:  {:#010X}\
-             \r\n\r\n",
+             \r\n",
             state.mtval,
         ));
+        if let Some(info) = crate::FaultInfo::decode(state.mcause as usize, state.mtval as usize) {
+            crate::print_fault_info(&info, writer);
+        }
+        let _ = writer.write_fmt(format_args!("\r\n"));
+    }
+
+    unsafe fn stack_trace(
+        &self,
+        accessible_memory_start: *const u8,
+        app_brk: *const u8,
+        state: &Riscv32iStoredState,
+        trace: &mut [usize],
+    ) -> usize {
+        // With frame pointers, `s0` points just above the frame record of the
+        // current function: its return address is the word below, and the
+        // frame pointer of its caller the word below that.
+        let word = mem::size_of::<usize>();
+        let mut frame_pointer = state.regs[R_S0] as usize;
+        let mut depth = 0;
+        while depth < trace.len() {
+            if frame_pointer % word != 0
+                || frame_pointer < accessible_memory_start as usize + 2 * word
+                || frame_pointer > app_brk as usize
+            {
+                break;
+            }
+            let record = (frame_pointer - 2 * word) as *const usize;
+            let caller_frame_pointer = read_volatile(record);
+            trace[depth] = read_volatile(record.offset(1));
+            depth += 1;
+
+            // The stack grows down, so callers have frames at higher
+            // addresses. Anything else is not a frame record.
+            if caller_frame_pointer <= frame_pointer {
+                break;
+            }
+            frame_pointer = caller_frame_pointer;
+        }
+        depth
+    }
+
+    unsafe fn debug_register(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &Riscv32iStoredState,
+        index: usize,
+    ) -> Option<usize> {
+        // GDB numbers x0 to x31, then pc.
+        match index {
+            0 => Some(0),
+            1..=31 => Some(state.regs[index - 1] as usize),
+            32 => Some(state.pc as usize),
+            _ => None,
+        }
+    }
+
+    unsafe fn set_debug_register(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut Riscv32iStoredState,
+        index: usize,
+        value: usize,
+    ) -> Result<(), ()> {
+        match index {
+            1..=31 => state.regs[index - 1] = value as u32,
+            32 => state.pc = value as u32,
+            _ => return Err(()),
+        }
+        Ok(())
     }
 }
+
+/// The GDB target description of a process: x0 to x31, then pc, numbered from
+/// 0 in order.
+pub const GDB_TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target><architecture>riscv:rv32</architecture>\
+<feature name=\"org.gnu.gdb.riscv.cpu\">\
+<reg name=\"zero\" bitsize=\"32\"/>\
+<reg name=\"ra\" bitsize=\"32\" type=\"code_ptr\"/>\
+<reg name=\"sp\" bitsize=\"32\" type=\"data_ptr\"/>\
+<reg name=\"gp\" bitsize=\"32\"/>\
+<reg name=\"tp\" bitsize=\"32\"/>\
+<reg name=\"t0\" bitsize=\"32\"/>\
+<reg name=\"t1\" bitsize=\"32\"/>\
+<reg name=\"t2\" bitsize=\"32\"/>\
+<reg name=\"fp\" bitsize=\"32\" type=\"data_ptr\"/>\
+<reg name=\"s1\" bitsize=\"32\"/>\
+<reg name=\"a0\" bitsize=\"32\"/>\
+<reg name=\"a1\" bitsize=\"32\"/>\
+<reg name=\"a2\" bitsize=\"32\"/>\
+<reg name=\"a3\" bitsize=\"32\"/>\
+<reg name=\"a4\" bitsize=\"32\"/>\
+<reg name=\"a5\" bitsize=\"32\"/>\
+<reg name=\"a6\" bitsize=\"32\"/>\
+<reg name=\"a7\" bitsize=\"32\"/>\
+<reg name=\"s2\" bitsize=\"32\"/>\
+<reg name=\"s3\" bitsize=\"32\"/>\
+<reg name=\"s4\" bitsize=\"32\"/>\
+<reg name=\"s5\" bitsize=\"32\"/>\
+<reg name=\"s6\" bitsize=\"32\"/>\
+<reg name=\"s7\" bitsize=\"32\"/>\
+<reg name=\"s8\" bitsize=\"32\"/>\
+<reg name=\"s9\" bitsize=\"32\"/>\
+<reg name=\"s10\" bitsize=\"32\"/>\
+<reg name=\"s11\" bitsize=\"32\"/>\
+<reg name=\"t3\" bitsize=\"32\"/>\
+<reg name=\"t4\" bitsize=\"32\"/>\
+<reg name=\"t5\" bitsize=\"32\"/>\
+<reg name=\"t6\" bitsize=\"32\"/>\
+<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/>\
+</feature></target>";
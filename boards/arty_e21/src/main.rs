@@ -120,7 +120,7 @@ pub unsafe fn main() {
         115200,
         dynamic_deferred_caller,
     )
-    .finalize(());
+    .finalize(components::uart_mux_component_helper!());
 
     let console = components::console::ConsoleComponent::new(board_kernel, uart_mux).finalize(());
 
@@ -17,6 +17,7 @@ use kernel::component::Component;
 use kernel::hil::gpio::Interrupt;
 use kernel::hil::i2c::I2CMaster;
 use kernel::hil::led::LedHigh;
+use kernel::hil::radio::RadioEnergyDetect;
 use kernel::hil::symmetric_encryption::AES128;
 use kernel::hil::time::Alarm;
 use kernel::hil::time::Counter;
@@ -129,6 +130,10 @@ pub struct Platform {
     >,
     temperature: &'static capsules::temperature::TemperatureSensor<'static>,
     humidity: &'static capsules::humidity::HumiditySensor<'static>,
+    secure_boot: &'static capsules::secure_boot::SecureBoot<
+        'static,
+        nrf52840::adafruit_bootloader::AdafruitBootloaderInfo,
+    >,
 }
 
 impl kernel::Platform for Platform {
@@ -151,6 +156,7 @@ impl kernel::Platform for Platform {
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             capsules::temperature::DRIVER_NUM => f(Some(self.temperature)),
             capsules::humidity::DRIVER_NUM => f(Some(self.humidity)),
+            capsules::secure_boot::DRIVER_NUM => f(Some(self.secure_boot)),
             _ => f(None),
         }
     }
@@ -175,6 +181,14 @@ unsafe fn get_peripherals() -> &'static mut Nrf52840DefaultPeripherals<'static>
 pub unsafe fn main() {
     nrf52840::init();
 
+    // Read what the bootloader left before anything can touch its settings.
+    let boot_status = static_init!(
+        nrf52840::adafruit_bootloader::AdafruitBootloaderInfo,
+        nrf52840::adafruit_bootloader::AdafruitBootloaderInfo::read(
+            nrf52840::adafruit_bootloader::NRF52840_SETTINGS
+        )
+    );
+
     let nrf52840_peripherals = get_peripherals();
 
     // set up circular peripheral dependencies
@@ -361,7 +375,7 @@ pub unsafe fn main() {
 
     // Create a shared UART channel for the console and for kernel debug.
     let uart_mux = components::console::UartMuxComponent::new(cdc, 115200, dynamic_deferred_caller)
-        .finalize(());
+        .finalize(components::uart_mux_component_helper!());
 
     // Setup the console.
     let console = components::console::ConsoleComponent::new(board_kernel, uart_mux).finalize(());
@@ -508,6 +522,29 @@ pub unsafe fn main() {
         nrf52840::ieee802154_radio::Radio,
         nrf52840::aes::AesECB<'static>
     ));
+    ieee802154_radio.set_energy_detect(
+        &base_peripherals.ieee802154_radio,
+        &create_capability!(capabilities::RawRadioAccessCapability),
+    );
+    base_peripherals
+        .ieee802154_radio
+        .set_energy_detect_client(ieee802154_radio);
+
+    // BLE and 802.15.4 share the radio
+    nrf52_components::RadioArbiterComponent::new(
+        &base_peripherals.ble_radio,
+        &base_peripherals.ieee802154_radio,
+        mux_alarm,
+    )
+    .finalize(());
+
+    let secure_boot = static_init!(
+        capsules::secure_boot::SecureBoot<
+            'static,
+            nrf52840::adafruit_bootloader::AdafruitBootloaderInfo,
+        >,
+        capsules::secure_boot::SecureBoot::new(boot_status)
+    );
 
     //--------------------------------------------------------------------------
     // FINAL SETUP AND BOARD BOOT
@@ -532,6 +569,7 @@ pub unsafe fn main() {
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
         temperature: temperature,
         humidity: humidity,
+        secure_boot: secure_boot,
     };
 
     let chip = static_init!(
@@ -12,12 +12,19 @@
 //! ```rust
 //! let uart_mux = UartMuxComponent::new(&sam4l::usart::USART3,
 //!                                      115200,
-//!                                      deferred_caller).finalize(());
+//!                                      deferred_caller)
+//!     .finalize(components::uart_mux_component_helper!());
 //! let console = ConsoleComponent::new(board_kernel, uart_mux).finalize(());
 //! ```
+//!
+//! Each use of `uart_mux_component_helper!` allocates its own mux and receive
+//! buffer, so a board can multiplex several UARTs, one `UartMuxComponent` for
+//! each.
 // Author: Philip Levis <pal@cs.stanford.edu>
 // Last modified: 1/08/2020
 
+use core::mem::MaybeUninit;
+
 use capsules::console;
 use capsules::virtual_uart::{MuxUart, UartDevice};
 use kernel::capabilities;
@@ -26,7 +33,19 @@ use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil;
 use kernel::hil::uart;
-use kernel::static_init;
+use kernel::{static_init, static_init_half};
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! uart_mux_component_helper {
+    () => {{
+        use capsules::virtual_uart::{MuxUart, RX_BUF_LEN};
+        use core::mem::MaybeUninit;
+        static mut UART_MUX: MaybeUninit<MuxUart<'static>> = MaybeUninit::uninit();
+        static mut RX_BUF: [u8; RX_BUF_LEN] = [0; RX_BUF_LEN];
+        (&mut UART_MUX, &mut RX_BUF)
+    };};
+}
 
 pub struct UartMuxComponent {
     uart: &'static dyn uart::Uart<'static>,
@@ -49,15 +68,19 @@ impl UartMuxComponent {
 }
 
 impl Component for UartMuxComponent {
-    type StaticInput = ();
+    type StaticInput = (
+        &'static mut MaybeUninit<MuxUart<'static>>,
+        &'static mut [u8; capsules::virtual_uart::RX_BUF_LEN],
+    );
     type Output = &'static MuxUart<'static>;
 
-    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
-        let uart_mux = static_init!(
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let uart_mux = static_init_half!(
+            static_buffer.0,
             MuxUart<'static>,
             MuxUart::new(
                 self.uart,
-                &mut capsules::virtual_uart::RX_BUF,
+                static_buffer.1,
                 self.baud_rate,
                 self.deferred_caller,
             )
@@ -110,11 +133,13 @@ impl Component for ConsoleComponent {
                 console_uart,
                 &mut console::WRITE_BUF,
                 &mut console::READ_BUF,
+                &mut console::READ_QUEUE,
                 self.board_kernel.create_grant(&grant_cap)
             )
         );
         hil::uart::Transmit::set_transmit_client(console_uart, console);
         hil::uart::Receive::set_receive_client(console_uart, console);
+        console.initialize();
 
         console
     }
@@ -0,0 +1,210 @@
+//! Component for a CTAP2 authenticator over USB.
+//!
+//! This provides a component for using the in-kernel CTAP2 authenticator,
+//! which handles FIDO2 requests from the USB host itself, instead of passing
+//! CTAP HID packets to an application like the CTAP component. The user
+//! confirms requests with a button, which must not be used by anything else.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",     // Manufacturer
+//!     "FIDO Key",      // Product
+//!     "Serial No. 5",  // Serial number
+//! ];
+//!
+//!     let (ctap, authenticator) = components::ctap2::Ctap2Component::new(
+//!         &earlgrey::usbdev::USB,
+//!         0x1337, // My important company
+//!         0x0DEC, // My device name
+//!         strings,
+//!         sha,
+//!         p256,
+//!         mux_alarm,
+//!         &peripherals.gpio_port[10],
+//!         kernel::hil::gpio::ActivationMode::ActiveLow,
+//!         kernel::hil::gpio::FloatingState::PullUp,
+//!         [0; 16], // AAGUID
+//!     )
+//!     .finalize(components::ctap2_component_helper!(
+//!         lowrisc::usbdev::Usb,
+//!         capsules::sha256::Sha256Software<'static>,
+//!         capsules::p256::P256Software<'static>,
+//!         earlgrey::timer::RvTimer<'static>,
+//!     ));
+//!
+//!     ctap.enable();
+//!     ctap.attach();
+//!     authenticator.start();
+//! ```
+
+use capsules::ctap2::authenticator::Ctap2Authenticator;
+use capsules::usb::ctap::CtapHid;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::digest::Digest;
+use kernel::hil::gpio;
+use kernel::hil::signature::{KeyGenerate, SignatureSign, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
+
+/// Size of the largest CTAP2 message the authenticator takes.
+pub const MESSAGE_LEN: usize = 1024;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ctap2_component_helper {
+    ($U:ty, $D:ty, $S:ty, $A:ty $(,)?) => {{
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<capsules::usb::ctap::CtapHid<'static, $U>> =
+            MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<
+            capsules::ctap2::authenticator::Ctap2Authenticator<
+                'static,
+                capsules::usb::ctap::CtapHid<'static, $U>,
+                $D,
+                $S,
+                VirtualMuxAlarm<'static, $A>,
+            >,
+        > = MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct Ctap2Component<
+    U: 'static + hil::usb::UsbController<'static>,
+    D: 'static + Digest<'static, [u8; 32]>,
+    S: 'static + KeyGenerate<'static> + SignatureSign<'static>,
+    A: 'static + Alarm<'static>,
+> {
+    usb: &'static U,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    digest: &'static D,
+    signer: &'static S,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    button: &'static dyn gpio::InterruptPin<'static>,
+    button_mode: gpio::ActivationMode,
+    button_floating_state: gpio::FloatingState,
+    aaguid: [u8; 16],
+}
+
+impl<
+        U: 'static + hil::usb::UsbController<'static>,
+        D: 'static + Digest<'static, [u8; 32]>,
+        S: 'static + KeyGenerate<'static> + SignatureSign<'static>,
+        A: 'static + Alarm<'static>,
+    > Ctap2Component<U, D, S, A>
+{
+    pub fn new(
+        usb: &'static U,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        digest: &'static D,
+        signer: &'static S,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        button: &'static dyn gpio::InterruptPin<'static>,
+        button_mode: gpio::ActivationMode,
+        button_floating_state: gpio::FloatingState,
+        aaguid: [u8; 16],
+    ) -> Ctap2Component<U, D, S, A> {
+        Ctap2Component {
+            usb,
+            vendor_id,
+            product_id,
+            strings,
+            digest,
+            signer,
+            alarm_mux,
+            button,
+            button_mode,
+            button_floating_state,
+            aaguid,
+        }
+    }
+}
+
+impl<
+        U: 'static + hil::usb::UsbController<'static>,
+        D: 'static + Digest<'static, [u8; 32]>,
+        S: 'static + KeyGenerate<'static> + SignatureSign<'static>,
+        A: 'static + Alarm<'static>,
+    > Component for Ctap2Component<U, D, S, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<CtapHid<'static, U>>,
+        &'static mut MaybeUninit<
+            Ctap2Authenticator<'static, CtapHid<'static, U>, D, S, VirtualMuxAlarm<'static, A>>,
+        >,
+    );
+    type Output = (
+        &'static CtapHid<'static, U>,
+        &'static Ctap2Authenticator<
+            'static,
+            CtapHid<'static, U>,
+            D,
+            S,
+            VirtualMuxAlarm<'static, A>,
+        >,
+    );
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let alarm = static_init_half!(
+            s.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        let ctap = static_init_half!(
+            s.1,
+            CtapHid<'static, U>,
+            CtapHid::new(self.usb, self.vendor_id, self.product_id, self.strings)
+        );
+        self.usb.set_client(ctap);
+
+        let send_packet = static_init!([u8; 64], [0; 64]);
+        let recv_packet = static_init!([u8; 64], [0; 64]);
+        let message = static_init!([u8; MESSAGE_LEN], [0; MESSAGE_LEN]);
+        let hash = static_init!([u8; 32], [0; 32]);
+        let signature = static_init!([u8; SIGNATURE_LEN], [0; SIGNATURE_LEN]);
+        let public_key = static_init!([u8; PUBLIC_KEY_LEN], [0; PUBLIC_KEY_LEN]);
+
+        let authenticator = static_init_half!(
+            s.2,
+            Ctap2Authenticator<'static, CtapHid<'static, U>, D, S, VirtualMuxAlarm<'static, A>>,
+            Ctap2Authenticator::new(
+                ctap,
+                self.digest,
+                self.signer,
+                alarm,
+                self.button,
+                self.button_mode,
+                self.button_floating_state,
+                self.aaguid,
+                send_packet,
+                recv_packet,
+                message,
+                hash,
+                signature,
+                public_key,
+            )
+        );
+
+        ctap.set_client(authenticator);
+        self.digest.set_client(authenticator);
+        self.signer.set_key_client(authenticator);
+        self.signer.set_sign_client(authenticator);
+        alarm.set_alarm_client(authenticator);
+        self.button.set_client(authenticator);
+
+        (ctap, authenticator)
+    }
+}
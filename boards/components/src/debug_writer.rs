@@ -14,6 +14,18 @@
 //! )
 //! .finalize(());
 //! ```
+//!
+//! To limit how much debug output is written per kernel loop iteration, keep
+//! the returned `DebugWriter` and register it for deferred calls:
+//!
+//! ```rust
+//! let debug_writer = DebugWriterComponent::new(uart_mux).finalize(());
+//! debug_writer.set_max_burst(
+//!     32,
+//!     dynamic_deferred_caller,
+//!     dynamic_deferred_caller.register(debug_writer).unwrap(),
+//! );
+//! ```
 
 // Author: Brad Campbell <bradjc@virginia.edu>
 // Last modified: 11/07/2019
@@ -52,7 +64,7 @@ unsafe impl capabilities::ProcessManagementCapability for Capability {}
 
 impl Component for DebugWriterComponent {
     type StaticInput = ();
-    type Output = ();
+    type Output = &'static kernel::debug::DebugWriter;
 
     unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
         let buf = static_init!(
@@ -76,6 +88,8 @@ impl Component for DebugWriterComponent {
             kernel::debug::DebugWriterWrapper::new(debugger)
         );
         kernel::debug::set_debug_writer_wrapper(debug_wrapper);
+
+        debugger
     }
 }
 
@@ -93,7 +107,7 @@ impl<U: uart::Uart<'static> + uart::Transmit<'static> + 'static> Component
     for DebugWriterNoMuxComponent<U>
 {
     type StaticInput = ();
-    type Output = ();
+    type Output = &'static kernel::debug::DebugWriter;
 
     unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
         let buf = static_init!(
@@ -123,5 +137,7 @@ impl<U: uart::Uart<'static> + uart::Transmit<'static> + 'static> Component
             parity: uart::Parity::None,
             hw_flow_control: false,
         });
+
+        debugger
     }
 }
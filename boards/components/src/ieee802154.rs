@@ -64,6 +64,7 @@ pub struct Ieee802154Component<
     pan_id: capsules::net::ieee802154::PanID,
     short_addr: u16,
     deferred_caller: &'static DynamicDeferredCall,
+    limits: Option<&'static capsules::radio_limits::RadioLimits>,
 }
 
 impl<
@@ -86,8 +87,16 @@ impl<
             pan_id,
             short_addr,
             deferred_caller,
+            limits: None,
         }
     }
+
+    /// Refuse to transmit while the channel or TX power of the radio is
+    /// outside `limits`.
+    pub fn radio_limits(mut self, limits: &'static capsules::radio_limits::RadioLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
 }
 
 static mut RADIO_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
@@ -139,6 +148,9 @@ impl<
             AwakeMac<'static, R>,
             AwakeMac::new(self.radio)
         );
+        if let Some(limits) = self.limits {
+            awake_mac.set_limits(limits);
+        }
         self.radio.set_transmit_client(awake_mac);
         self.radio.set_receive_client(awake_mac, &mut RADIO_RX_BUF);
 
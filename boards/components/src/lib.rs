@@ -11,6 +11,7 @@ pub mod cdc;
 pub mod console;
 pub mod crc;
 pub mod ctap;
+pub mod ctap2;
 pub mod debug_queue;
 pub mod debug_writer;
 pub mod ft6x06;
@@ -5,6 +5,7 @@ use kernel::debug;
 use kernel::debug::IoWrite;
 use kernel::hil::gpio::Configure;
 use kernel::hil::led;
+use kernel::simulation::{self, SimulationExit, SimulationResult};
 
 use crate::CHIP;
 use crate::PROCESSES;
@@ -32,6 +33,31 @@ impl IoWrite for Writer {
     }
 }
 
+/// Reports the result of a simulated run over the UART.
+///
+/// Neither Verilator nor QEMU expose an exit device to Earlgrey software, so
+/// in addition to the Tock markers this prints the `PASS!`/`FAIL!` strings
+/// that the OpenTitan simulation harness looks for, and then sleeps.
+pub struct SimExit {}
+
+pub static SIM_EXIT: SimExit = SimExit {};
+
+impl SimulationExit for SimExit {
+    fn exit(&self, result: SimulationResult) -> ! {
+        unsafe {
+            let writer = &mut WRITER;
+            simulation::report(writer, result);
+            match result {
+                SimulationResult::Pass => writer.write(b"PASS!\r\n"),
+                SimulationResult::Fail(_) => writer.write(b"FAIL!\r\n"),
+            }
+            loop {
+                rv32i::support::wfi();
+            }
+        }
+    }
+}
+
 /// Panic handler.
 #[cfg(not(test))]
 #[no_mangle]
@@ -47,6 +73,9 @@ pub unsafe extern "C" fn panic_fmt(pi: &PanicInfo) -> ! {
 
     let writer = &mut WRITER;
 
+    #[cfg(feature = "trace_record")]
+    crate::TRACE.map(|trace| trace.dump(writer));
+
     debug::panic(
         &mut [first_led],
         writer,
@@ -31,6 +31,11 @@ mod multi_alarm_test;
 mod tickv_test;
 
 pub mod io;
+/// Generated by `tools/trace_replay.py`.
+#[cfg(feature = "trace_replay")]
+mod replay_trace {
+    include!("replay_trace.rs");
+}
 pub mod usb;
 
 const NUM_PROCS: usize = 4;
@@ -40,13 +45,27 @@ const NUM_PROCS: usize = 4;
 // at least.
 static mut PROCESSES: [Option<&'static dyn kernel::procs::Process>; 4] = [None; NUM_PROCS];
 
+/// The interrupt service of the chip. With `trace_record` it is wrapped to
+/// record every interrupt it services.
+#[cfg(not(feature = "trace_record"))]
+type InterruptService = EarlGreyDefaultPeripherals<'static>;
+#[cfg(feature = "trace_record")]
+type InterruptService =
+    kernel::replay::RecordingInterruptService<'static, EarlGreyDefaultPeripherals<'static>>;
+
 static mut CHIP: Option<
     &'static earlgrey::chip::EarlGrey<
         VirtualMuxAlarm<'static, earlgrey::timer::RvTimer>,
-        EarlGreyDefaultPeripherals,
+        InterruptService,
     >,
 > = None;
 
+/// Events recorded with `trace_record`, printed by the panic handler.
+#[cfg(feature = "trace_record")]
+static mut TRACE: Option<&'static kernel::replay::TraceBuffer> = None;
+#[cfg(feature = "trace_record")]
+static mut TRACE_EVENTS: [Option<kernel::replay::TraceEvent>; 256] = [None; 256];
+
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::procs::PanicFaultPolicy = kernel::procs::PanicFaultPolicy {};
 
@@ -78,6 +97,7 @@ struct EarlGreyNexysVideo {
         capsules::virtual_uart::UartDevice<'static>,
     >,
     i2c_master: &'static capsules::i2c_master::I2CMasterDriver<'static, lowrisc::i2c::I2c<'static>>,
+    secure_boot: &'static capsules::secure_boot::SecureBoot<'static, earlgrey::boot_log::BootLog>,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -94,6 +114,7 @@ impl Platform for EarlGreyNexysVideo {
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             capsules::low_level_debug::DRIVER_NUM => f(Some(self.lldb)),
             capsules::i2c_master::DRIVER_NUM => f(Some(self.i2c_master)),
+            capsules::secure_boot::DRIVER_NUM => f(Some(self.secure_boot)),
             _ => f(None),
         }
     }
@@ -113,12 +134,16 @@ pub unsafe fn main() {
         EarlGreyDefaultPeripherals::new()
     );
 
+    // Read what the ROM_EXT left before the retention SRAM can be reused.
+    let boot_status = static_init!(
+        earlgrey::boot_log::BootLog,
+        earlgrey::boot_log::BootLog::read(earlgrey::boot_log::BOOT_LOG_BASE, &peripherals.hmac)
+    );
+
     // initialize capabilities
     let process_mgmt_cap = create_capability!(capabilities::ProcessManagementCapability);
     let memory_allocation_cap = create_capability!(capabilities::MemoryAllocationCapability);
 
-    let main_loop_cap = create_capability!(capabilities::MainLoopCapability);
-
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
 
     let dynamic_deferred_call_clients =
@@ -142,7 +167,7 @@ pub unsafe fn main() {
         earlgrey::uart::UART0_BAUDRATE,
         dynamic_deferred_caller,
     )
-    .finalize(());
+    .finalize(components::uart_mux_component_helper!());
 
     // LEDs
     // Start with half on and half off
@@ -197,6 +222,16 @@ pub unsafe fn main() {
         VirtualMuxAlarm<'static, earlgrey::timer::RvTimer>,
         VirtualMuxAlarm::new(mux_alarm)
     );
+    #[cfg(feature = "self_test")]
+    let self_test_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, earlgrey::timer::RvTimer>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    #[cfg(feature = "self_test")]
+    let contract_test_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, earlgrey::timer::RvTimer>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
     let alarm = static_init!(
         capsules::alarm::AlarmDriver<'static, VirtualMuxAlarm<'static, earlgrey::timer::RvTimer>>,
         capsules::alarm::AlarmDriver::new(
@@ -206,12 +241,32 @@ pub unsafe fn main() {
     );
     hil::time::Alarm::set_alarm_client(virtual_alarm_user, alarm);
 
+    #[cfg(not(feature = "trace_record"))]
+    let interrupt_service = &*peripherals;
+    #[cfg(feature = "trace_record")]
+    let interrupt_service = {
+        let trace = static_init!(
+            kernel::replay::TraceBuffer,
+            kernel::replay::TraceBuffer::new(&mut TRACE_EVENTS)
+        );
+        board_kernel.set_trace_recorder(trace, &process_mgmt_cap);
+        TRACE = Some(trace);
+        static_init!(
+            InterruptService,
+            kernel::replay::RecordingInterruptService::new(peripherals, trace)
+        )
+    };
+
     let chip = static_init!(
         earlgrey::chip::EarlGrey<
             VirtualMuxAlarm<'static, earlgrey::timer::RvTimer>,
-            EarlGreyDefaultPeripherals,
+            InterruptService,
         >,
-        earlgrey::chip::EarlGrey::new(scheduler_timer_virtual_alarm, peripherals, hardware_alarm)
+        earlgrey::chip::EarlGrey::new(
+            scheduler_timer_virtual_alarm,
+            interrupt_service,
+            hardware_alarm
+        )
     );
     scheduler_timer_virtual_alarm.set_alarm_client(chip.scheduler_timer());
     CHIP = Some(chip);
@@ -329,6 +384,11 @@ pub unsafe fn main() {
         static _ezero: u8;
     }
 
+    let secure_boot = static_init!(
+        capsules::secure_boot::SecureBoot<'static, earlgrey::boot_log::BootLog>,
+        capsules::secure_boot::SecureBoot::new(boot_status)
+    );
+
     let earlgrey_nexysvideo = EarlGreyNexysVideo {
         gpio: gpio,
         led: led,
@@ -337,6 +397,7 @@ pub unsafe fn main() {
         hmac,
         lldb: lldb,
         i2c_master,
+        secure_boot,
     };
 
     // This is PMP support for kernel regions
@@ -407,12 +468,73 @@ pub unsafe fn main() {
     });
     debug!("OpenTitan initialisation complete. Entering main loop");
 
-    let scheduler = components::sched::priority::PriorityComponent::new(board_kernel).finalize(());
-    board_kernel.kernel_loop(
-        &earlgrey_nexysvideo,
-        chip,
-        None::<&kernel::ipc::IPC<NUM_PROCS>>,
-        scheduler,
-        &main_loop_cap,
-    );
+    #[cfg(feature = "self_test")]
+    {
+        use kernel::hil::self_test::SelfTest;
+
+        let alarm_test = static_init!(
+            capsules::self_test::AlarmSelfTest<
+                'static,
+                VirtualMuxAlarm<'static, earlgrey::timer::RvTimer>,
+            >,
+            capsules::self_test::AlarmSelfTest::new(self_test_virtual_alarm)
+        );
+        self_test_virtual_alarm.set_alarm_client(alarm_test);
+        let alarm_contract = static_init!(
+            capsules::hil_contract::AlarmContractTest<
+                'static,
+                VirtualMuxAlarm<'static, earlgrey::timer::RvTimer>,
+            >,
+            capsules::hil_contract::AlarmContractTest::new(contract_test_virtual_alarm)
+        );
+        contract_test_virtual_alarm.set_alarm_client(alarm_contract);
+        let tests = static_init!(
+            [&'static dyn SelfTest<'static>; 2],
+            [alarm_test, alarm_contract]
+        );
+        let runner = static_init!(
+            capsules::self_test::SelfTestRunner<'static>,
+            capsules::self_test::SelfTestRunner::new(tests, &io::SIM_EXIT)
+        );
+        runner.start();
+    }
+
+    // Re-inject a recorded trace instead of running processes, then report
+    // whether the capsules followed the recorded run.
+    #[cfg(feature = "trace_replay")]
+    {
+        use kernel::simulation::{SimulationExit, SimulationResult};
+
+        let replayer = kernel::replay::Replayer::new(
+            board_kernel,
+            &replay_trace::TRACE,
+            peripherals,
+            &create_capability!(capabilities::ExternalProcessCapability),
+        );
+        let result = match replayer.replay(&earlgrey_nexysvideo) {
+            Ok(()) => {
+                debug!("Replayed {} events", replayer.position());
+                SimulationResult::Pass
+            }
+            Err(error) => {
+                debug!("Replay stopped: {:?}", error);
+                SimulationResult::Fail(1)
+            }
+        };
+        io::SIM_EXIT.exit(result);
+    }
+
+    #[cfg(not(feature = "trace_replay"))]
+    {
+        let main_loop_cap = create_capability!(capabilities::MainLoopCapability);
+        let scheduler =
+            components::sched::priority::PriorityComponent::new(board_kernel).finalize(());
+        board_kernel.kernel_loop(
+            &earlgrey_nexysvideo,
+            chip,
+            None::<&kernel::ipc::IPC<NUM_PROCS>>,
+            scheduler,
+            &main_loop_cap,
+        );
+    }
 }
@@ -131,7 +131,7 @@ pub unsafe fn main() {
         115200,
         dynamic_deferred_caller,
     )
-    .finalize(());
+    .finalize(components::uart_mux_component_helper!());
 
     // LEDs
     let led = components::led::LedsComponent::new(components::led_component_helper!(
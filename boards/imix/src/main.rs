@@ -311,8 +311,8 @@ pub unsafe fn main() {
     // # CONSOLE
     // Create a shared UART channel for the consoles and for kernel debug.
     peripherals.usart3.set_mode(sam4l::usart::UsartMode::Uart);
-    let uart_mux =
-        UartMuxComponent::new(&peripherals.usart3, 115200, dynamic_deferred_caller).finalize(());
+    let uart_mux = UartMuxComponent::new(&peripherals.usart3, 115200, dynamic_deferred_caller)
+        .finalize(components::uart_mux_component_helper!());
 
     let pconsole = ProcessConsoleComponent::new(board_kernel, uart_mux).finalize(());
     let console = ConsoleComponent::new(board_kernel, uart_mux).finalize(());
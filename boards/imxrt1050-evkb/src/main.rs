@@ -270,7 +270,7 @@ pub unsafe fn main() {
         115200,
         dynamic_deferred_caller,
     )
-    .finalize(());
+    .finalize(components::uart_mux_component_helper!());
     io::WRITER.set_initialized();
 
     // Create capabilities that the board needs to call certain protected kernel
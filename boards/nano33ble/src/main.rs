@@ -307,7 +307,7 @@ pub unsafe fn main() {
 
     // Create a shared UART channel for the console and for kernel debug.
     let uart_mux = components::console::UartMuxComponent::new(cdc, 115200, dynamic_deferred_caller)
-        .finalize(());
+        .finalize(components::uart_mux_component_helper!());
 
     let pconsole =
         components::process_console::ProcessConsoleComponent::new(board_kernel, uart_mux)
@@ -402,6 +402,14 @@ pub unsafe fn main() {
         nrf52840::ieee802154_radio::Radio,
         nrf52840::aes::AesECB<'static>
     ));
+
+    // BLE and 802.15.4 share the radio
+    nrf52_components::RadioArbiterComponent::new(
+        &base_peripherals.ble_radio,
+        &base_peripherals.ieee802154_radio,
+        mux_alarm,
+    )
+    .finalize(());
     use capsules::net::ipv6::ip_utils::IPAddr;
 
     let local_ip_ifaces = static_init!(
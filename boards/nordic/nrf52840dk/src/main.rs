@@ -70,11 +70,14 @@ use capsules::net::ieee802154::MacAddress;
 use capsules::net::ipv6::ip_utils::IPAddr;
 use capsules::virtual_aes_ccm::MuxAES128CCM;
 use capsules::virtual_alarm::VirtualMuxAlarm;
+use capsules::virtual_ble::{MuxBleRadio, VirtualBleRadio};
 use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::component::Component;
+use kernel::hil::ble_advertising::BleAdvertisementDriver;
 use kernel::hil::led::LedLow;
+use kernel::hil::radio::RadioEnergyDetect;
 use kernel::hil::symmetric_encryption::AES128;
-use kernel::hil::time::Counter;
+use kernel::hil::time::{Alarm, Counter};
 #[allow(unused_imports)]
 use kernel::hil::usb::Client;
 #[allow(unused_imports)]
@@ -111,6 +114,9 @@ const SPI_MX25R6435F_HOLD_PIN: Pin = Pin::P0_23;
 
 // Constants related to the configuration of the 15.4 network stack
 const PAN_ID: u16 = 0xABCD;
+
+/// Eddystone namespace of the identification beacon, with the device address as the instance.
+const BEACON_NAMESPACE: [u8; 10] = [0x74, 0x6f, 0x63, 0x6b, 0x6f, 0x73, 0x00, 0x00, 0x00, 0x01];
 const DST_MAC_ADDR: capsules::net::ieee802154::MacAddress =
     capsules::net::ieee802154::MacAddress::Short(49138);
 const DEFAULT_CTX_PREFIX_LEN: u8 = 8; //Length of context for 6LoWPAN compression
@@ -124,6 +130,12 @@ pub mod io;
 // - Set to true to use Segger RTT over USB.
 const USB_DEBUGGING: bool = false;
 
+// Whether to mask interrupt sources that interrupt more than 1000 times a
+// second. Off by default: USBD, UARTE and RADIO can legitimately exceed this
+// during bulk transfers, fast baud rates or busy channels, and masking them
+// would drop data rather than shed a storm.
+const INTERRUPT_RATE_LIMITING: bool = false;
+
 // State for loading and holding applications.
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::procs::PanicFaultPolicy = kernel::procs::PanicFaultPolicy {};
@@ -144,7 +156,7 @@ pub static mut STACK_MEMORY: [u8; 0x2000] = [0; 0x2000];
 pub struct Platform {
     ble_radio: &'static capsules::ble_advertising_driver::BLE<
         'static,
-        nrf52840::ble_radio::Radio<'static>,
+        VirtualBleRadio<'static, nrf52840::ble_radio::Radio<'static>>,
         VirtualMuxAlarm<'static, nrf52840::rtc::Rtc<'static>>,
     >,
     ieee802154_radio: &'static capsules::ieee802154::RadioDriver<'static>,
@@ -337,6 +349,54 @@ pub unsafe fn main() {
         .finalize(components::alarm_mux_component_helper!(nrf52840::rtc::Rtc));
     let alarm = components::alarm::AlarmDriverComponent::new(board_kernel, mux_alarm)
         .finalize(components::alarm_component_helper!(nrf52840::rtc::Rtc));
+    // Timestamp button and radio events with the alarm clock.
+    board_kernel.set_event_clock(rtc);
+
+    if INTERRUPT_RATE_LIMITING {
+        // Mask any source that interrupts more than 1000 times a second, and
+        // service it again after 5 seconds. GPIOTE is exempt, and each GPIO
+        // pin is limited on its own instead.
+        let interrupt_limiter = static_init!(
+            kernel::interrupt_rate::InterruptRateLimiter<'static, nrf52840::rtc::Rtc<'static>, 64>,
+            kernel::interrupt_rate::InterruptRateLimiter::new(rtc, 1000, 1000)
+        );
+        interrupt_limiter.set_exempt(&nrf52840::chip::INTERRUPT_MONITOR_EXEMPT);
+        let storm_alarm = static_init!(
+            VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+            VirtualMuxAlarm::new(mux_alarm)
+        );
+        let storm_recovery = static_init!(
+            kernel::interrupt_rate::InterruptStormRecovery<
+                'static,
+                VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+                64,
+            >,
+            kernel::interrupt_rate::InterruptStormRecovery::new(storm_alarm, chip, 5000)
+        );
+        storm_alarm.set_alarm_client(storm_recovery);
+        interrupt_limiter.set_client(storm_recovery);
+        chip.set_interrupt_monitor(interrupt_limiter);
+
+        let pin_limiter = static_init!(
+            kernel::interrupt_rate::InterruptRateLimiter<'static, nrf52840::rtc::Rtc<'static>, 48>,
+            kernel::interrupt_rate::InterruptRateLimiter::new(rtc, 1000, 1000)
+        );
+        let pin_storm_alarm = static_init!(
+            VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+            VirtualMuxAlarm::new(mux_alarm)
+        );
+        let pin_storm_recovery = static_init!(
+            kernel::interrupt_rate::InterruptStormRecovery<
+                'static,
+                VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+                48,
+            >,
+            kernel::interrupt_rate::InterruptStormRecovery::new(pin_storm_alarm, gpio_port, 5000)
+        );
+        pin_storm_alarm.set_alarm_client(pin_storm_recovery);
+        pin_limiter.set_client(pin_storm_recovery);
+        gpio_port.set_interrupt_monitor(pin_limiter);
+    }
 
     let channel = nrf52_components::UartChannelComponent::new(
         uart_channel,
@@ -346,7 +406,7 @@ pub unsafe fn main() {
     .finalize(());
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 3], Default::default());
+        static_init!([DynamicDeferredCallClientState; 4], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -356,7 +416,7 @@ pub unsafe fn main() {
     // Create a shared UART channel for the console and for kernel debug.
     let uart_mux =
         components::console::UartMuxComponent::new(channel, 115200, dynamic_deferred_caller)
-            .finalize(());
+            .finalize(components::uart_mux_component_helper!());
 
     let pconsole =
         components::process_console::ProcessConsoleComponent::new(board_kernel, uart_mux)
@@ -364,12 +424,83 @@ pub unsafe fn main() {
 
     // Setup the console.
     let console = components::console::ConsoleComponent::new(board_kernel, uart_mux).finalize(());
-    // Create the debugger object that handles calls to `debug!()`.
-    components::debug_writer::DebugWriterComponent::new(uart_mux).finalize(());
+    // Create the debugger object that handles calls to `debug!()`. Limit how
+    // much it writes per kernel loop iteration so that debugging does not
+    // disturb the radios.
+    let debug_writer = components::debug_writer::DebugWriterComponent::new(uart_mux).finalize(());
+    debug_writer.set_max_burst(
+        32,
+        dynamic_deferred_caller,
+        dynamic_deferred_caller
+            .register(debug_writer)
+            .expect("no deferred call slot available for debug writer"),
+    );
 
-    let ble_radio =
-        nrf52_components::BLEComponent::new(board_kernel, &base_peripherals.ble_radio, mux_alarm)
-            .finalize(());
+    // The BLE advertising driver shares the radio with a beacon that identifies the board
+    // whatever the processes do.
+    let ble_mux = static_init!(
+        MuxBleRadio<'static, nrf52840::ble_radio::Radio>,
+        MuxBleRadio::new(&base_peripherals.ble_radio)
+    );
+    let ble_driver_radio = static_init!(
+        VirtualBleRadio<'static, nrf52840::ble_radio::Radio>,
+        VirtualBleRadio::new(ble_mux)
+    );
+    ble_driver_radio.setup();
+    let ble_alarm = static_init!(
+        VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let ble_radio = static_init!(
+        capsules::ble_advertising_driver::BLE<
+            'static,
+            VirtualBleRadio<'static, nrf52840::ble_radio::Radio>,
+            VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+        >,
+        capsules::ble_advertising_driver::BLE::new(
+            ble_driver_radio,
+            board_kernel.create_grant(&memory_allocation_capability),
+            &mut capsules::ble_advertising_driver::BUF,
+            ble_alarm
+        )
+    );
+    BleAdvertisementDriver::set_receive_client(ble_driver_radio, ble_radio);
+    BleAdvertisementDriver::set_transmit_client(ble_driver_radio, ble_radio);
+    ble_alarm.set_alarm_client(ble_radio);
+
+    let beacon_radio = static_init!(
+        VirtualBleRadio<'static, nrf52840::ble_radio::Radio>,
+        VirtualBleRadio::new(ble_mux)
+    );
+    beacon_radio.setup();
+    let beacon_alarm = static_init!(
+        VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    // A static random address, which has the two most significant bits set
+    let mut beacon_address = nrf52840::ficr::FICR_INSTANCE.address();
+    beacon_address[5] |= 0xc0;
+    let beacon = static_init!(
+        capsules::ble_beacon::BleBeacon<
+            'static,
+            VirtualBleRadio<'static, nrf52840::ble_radio::Radio>,
+            VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+        >,
+        capsules::ble_beacon::BleBeacon::new(
+            beacon_radio,
+            beacon_alarm,
+            &mut capsules::ble_beacon::BUF,
+            beacon_address,
+            1000,
+        )
+    );
+    beacon_alarm.set_alarm_client(beacon);
+    BleAdvertisementDriver::set_transmit_client(beacon_radio, beacon);
+    let _ = beacon.start(&capsules::ble_beacon::eddystone_uid_data(
+        BEACON_NAMESPACE,
+        nrf52840::ficr::FICR_INSTANCE.address(),
+        0xee,
+    ));
 
     let aes_mux = static_init!(
         MuxAES128CCM<'static, nrf52840::aes::AesECB>,
@@ -397,6 +528,21 @@ pub unsafe fn main() {
         nrf52840::ieee802154_radio::Radio,
         nrf52840::aes::AesECB<'static>
     ));
+    ieee802154_radio.set_energy_detect(
+        &base_peripherals.ieee802154_radio,
+        &create_capability!(capabilities::RawRadioAccessCapability),
+    );
+    base_peripherals
+        .ieee802154_radio
+        .set_energy_detect_client(ieee802154_radio);
+
+    // BLE and 802.15.4 share the radio
+    nrf52_components::RadioArbiterComponent::new(
+        &base_peripherals.ble_radio,
+        &base_peripherals.ieee802154_radio,
+        mux_alarm,
+    )
+    .finalize(());
 
     let local_ip_ifaces = static_init!(
         [IPAddr; 3],
@@ -535,6 +681,85 @@ pub unsafe fn main() {
     // ctap.enable();
     // ctap.attach();
 
+    //--------------------------------------------------------------------------
+    // USB CTAP2 AUTHENTICATOR EXAMPLE
+    //--------------------------------------------------------------------------
+    // Uncomment to experiment with this. The authenticator handles FIDO2
+    // requests in the kernel, with software SHA-256 and P-256. The chunked
+    // executor needs one more `DynamicDeferredCallClientState` above, and the
+    // P-256 signer takes the TRNG, so the RNG driver has to be removed. It
+    // also needs `kernel::hil::entropy::Entropy32` and `kernel::hil::rng::Rng`
+    // in scope.
+
+    // let strings = static_init!(
+    //     [&str; 3],
+    //     [
+    //         "Nordic Semiconductor", // Manufacturer
+    //         "nRF52840dk - TockOS",  // Product
+    //         "serial0001",           // Serial number
+    //     ]
+    // );
+
+    // let executor = static_init!(
+    //     capsules::chunked_executor::ChunkedExecutor<'static>,
+    //     capsules::chunked_executor::ChunkedExecutor::new(dynamic_deferred_caller, 20_000)
+    // );
+    // executor.initialize_callback_handle(dynamic_deferred_caller.register(executor).unwrap());
+
+    // let sha_task = static_init!(
+    //     capsules::chunked_executor::ExecutorTask<'static>,
+    //     capsules::chunked_executor::ExecutorTask::new(executor)
+    // );
+    // sha_task.setup();
+    // let sha = static_init!(
+    //     capsules::sha256::Sha256Software<'static>,
+    //     capsules::sha256::Sha256Software::new(sha_task)
+    // );
+    // sha_task.set_client(sha);
+
+    // let entropy_to_random = static_init!(
+    //     capsules::rng::Entropy32ToRandom<'static>,
+    //     capsules::rng::Entropy32ToRandom::new(&base_peripherals.trng)
+    // );
+    // base_peripherals.trng.set_client(entropy_to_random);
+
+    // let p256_task = static_init!(
+    //     capsules::chunked_executor::ExecutorTask<'static>,
+    //     capsules::chunked_executor::ExecutorTask::new(executor)
+    // );
+    // p256_task.setup();
+    // let p256 = static_init!(
+    //     capsules::p256::P256Software<'static>,
+    //     capsules::p256::P256Software::new(p256_task, entropy_to_random)
+    // );
+    // p256_task.set_client(p256);
+    // entropy_to_random.set_client(p256);
+
+    // let (ctap, authenticator) = components::ctap2::Ctap2Component::new(
+    //     &nrf52840_peripherals.usbd,
+    //     0x1915, // Nordic Semiconductor
+    //     0x503a, // lowRISC generic FS USB
+    //     strings,
+    //     sha,
+    //     p256,
+    //     mux_alarm,
+    //     // Take the button out of the button driver above.
+    //     &nrf52840_peripherals.gpio_port[BUTTON4_PIN],
+    //     kernel::hil::gpio::ActivationMode::ActiveLow,
+    //     kernel::hil::gpio::FloatingState::PullUp,
+    //     [0; 16], // AAGUID
+    // )
+    // .finalize(components::ctap2_component_helper!(
+    //     nrf52840::usbd::Usbd,
+    //     capsules::sha256::Sha256Software<'static>,
+    //     capsules::p256::P256Software<'static>,
+    //     nrf52840::rtc::Rtc<'static>,
+    // ));
+
+    // ctap.enable();
+    // ctap.attach();
+    // authenticator.start();
+
     let platform = Platform {
         button,
         ble_radio,
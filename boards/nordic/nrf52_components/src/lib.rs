@@ -1,9 +1,11 @@
 #![no_std]
 
 pub mod ble;
+pub mod radio_arbiter;
 pub mod startup;
 
 pub use self::ble::BLEComponent;
+pub use self::radio_arbiter::RadioArbiterComponent;
 pub use self::startup::{
     NrfClockComponent, NrfStartupComponent, UartChannel, UartChannelComponent, UartPins,
 };
@@ -0,0 +1,70 @@
+//! Component sharing the radio between BLE and IEEE 802.15.4 on nRF52 based
+//! platforms.
+//!
+//! Boards that set up both the BLE advertising driver and the 802.15.4
+//! driver must also set up this component, or the two drivers reconfigure
+//! the radio under each other.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! RadioArbiterComponent::new(
+//!     &base_peripherals.ble_radio,
+//!     &base_peripherals.ieee802154_radio,
+//!     mux_alarm,
+//! )
+//! .finalize(());
+//! ```
+
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+
+use nrf52::radio_arbiter::{Arbiter, Protocol};
+use nrf52::rtc::Rtc;
+
+use kernel::component::Component;
+use kernel::hil::time::Alarm;
+use kernel::static_init;
+
+pub struct RadioArbiterComponent {
+    ble_radio: &'static nrf52::ble_radio::Radio<'static>,
+    ieee802154_radio: &'static nrf52::ieee802154_radio::Radio<'static>,
+    mux_alarm: &'static MuxAlarm<'static, Rtc<'static>>,
+}
+
+impl RadioArbiterComponent {
+    pub fn new(
+        ble_radio: &'static nrf52::ble_radio::Radio,
+        ieee802154_radio: &'static nrf52::ieee802154_radio::Radio,
+        mux_alarm: &'static MuxAlarm<'static, Rtc>,
+    ) -> RadioArbiterComponent {
+        RadioArbiterComponent {
+            ble_radio: ble_radio,
+            ieee802154_radio: ieee802154_radio,
+            mux_alarm: mux_alarm,
+        }
+    }
+}
+
+impl Component for RadioArbiterComponent {
+    type StaticInput = ();
+    type Output = &'static Arbiter<'static, VirtualMuxAlarm<'static, Rtc<'static>>>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let arbiter_alarm = static_init!(
+            VirtualMuxAlarm<'static, Rtc>,
+            VirtualMuxAlarm::new(self.mux_alarm)
+        );
+        let arbiter = static_init!(
+            Arbiter<'static, VirtualMuxAlarm<'static, Rtc>>,
+            Arbiter::new(arbiter_alarm)
+        );
+        arbiter_alarm.set_alarm_client(arbiter);
+
+        arbiter.set_user(Protocol::Ieee802154, self.ieee802154_radio);
+        arbiter.set_user(Protocol::Ble, self.ble_radio);
+        self.ieee802154_radio.set_arbiter(arbiter);
+        self.ble_radio.set_arbiter(arbiter);
+
+        arbiter
+    }
+}
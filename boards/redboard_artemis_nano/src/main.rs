@@ -17,6 +17,7 @@ use kernel::component::Component;
 use kernel::hil::i2c::I2CMaster;
 use kernel::hil::led::LedHigh;
 use kernel::hil::time::Counter;
+use kernel::power::{PowerDomain, PowerRegistry};
 use kernel::Platform;
 use kernel::{create_capability, debug, static_init};
 
@@ -63,6 +64,11 @@ struct RedboardArtemisNano {
         apollo3::ble::Ble<'static>,
         VirtualMuxAlarm<'static, apollo3::stimer::STimer<'static>>,
     >,
+    #[cfg(feature = "buzzer")]
+    buzzer: &'static capsules::buzzer_driver::Buzzer<
+        'static,
+        VirtualMuxAlarm<'static, apollo3::stimer::STimer<'static>>,
+    >,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -78,6 +84,8 @@ impl Platform for RedboardArtemisNano {
             capsules::console::DRIVER_NUM => f(Some(self.console)),
             capsules::i2c_master::DRIVER_NUM => f(Some(self.i2c_master)),
             capsules::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
+            #[cfg(feature = "buzzer")]
+            capsules::buzzer_driver::DRIVER_NUM => f(Some(self.buzzer)),
             _ => f(None),
         }
     }
@@ -92,9 +100,11 @@ pub unsafe fn main() {
 
     let peripherals = static_init!(Apollo3DefaultPeripherals, Apollo3DefaultPeripherals::new());
 
-    // No need to statically allocate mcu/pwr/clk_ctrl because they are only used in main!
+    // No need to statically allocate mcu/clk_ctrl because they are only used in main!
     let mcu_ctrl = apollo3::mcuctrl::McuCtrl::new();
-    let pwr_ctrl = apollo3::pwrctrl::PwrCtrl::new();
+    // The power controller is kept to power the IOMs and UARTs while they
+    // are in use.
+    let pwr_ctrl = static_init!(apollo3::pwrctrl::PwrCtrl, apollo3::pwrctrl::PwrCtrl::new());
     let clkgen = apollo3::clkgen::ClkGen::new();
 
     clkgen.set_clock_frequency(apollo3::clkgen::ClockFrequency::Freq48MHz);
@@ -114,9 +124,32 @@ pub unsafe fn main() {
 
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
 
-    // Power up components
-    pwr_ctrl.enable_uart0();
-    pwr_ctrl.enable_iom2();
+    // Power the IOMs and UARTs only while drivers use them
+    let power_registry = static_init!(PowerRegistry<'static>, PowerRegistry::new(pwr_ctrl));
+    let power_domains = static_init!(
+        [PowerDomain<'static>; 8],
+        [
+            PowerDomain::new(apollo3::pwrctrl::IOM0),
+            PowerDomain::new(apollo3::pwrctrl::IOM1),
+            PowerDomain::new(apollo3::pwrctrl::IOM2),
+            PowerDomain::new(apollo3::pwrctrl::IOM3),
+            PowerDomain::new(apollo3::pwrctrl::IOM4),
+            PowerDomain::new(apollo3::pwrctrl::IOM5),
+            PowerDomain::new(apollo3::pwrctrl::UART0),
+            PowerDomain::new(apollo3::pwrctrl::UART1),
+        ]
+    );
+    for domain in power_domains.iter() {
+        power_registry.register(domain);
+    }
+    peripherals.iom0.set_power_domain(&power_domains[0]);
+    peripherals.iom1.set_power_domain(&power_domains[1]);
+    peripherals.iom2.set_power_domain(&power_domains[2]);
+    peripherals.iom3.set_power_domain(&power_domains[3]);
+    peripherals.iom4.set_power_domain(&power_domains[4]);
+    peripherals.iom5.set_power_domain(&power_domains[5]);
+    peripherals.uart0.set_power_domain(&power_domains[6]);
+    peripherals.uart1.set_power_domain(&power_domains[7]);
 
     // Enable PinCfg
     &peripherals
@@ -140,7 +173,7 @@ pub unsafe fn main() {
         115200,
         dynamic_deferred_caller,
     )
-    .finalize(());
+    .finalize(components::uart_mux_component_helper!());
 
     // Setup the console.
     let console = components::console::ConsoleComponent::new(board_kernel, uart_mux).finalize(());
@@ -204,6 +237,49 @@ pub unsafe fn main() {
 
     let ble_radio = ble::BLEComponent::new(board_kernel, &peripherals.ble, mux_alarm).finalize(());
 
+    // Buzzer on pad 18, which carries CT4, the output of CTIMER timer A1.
+    #[cfg(feature = "buzzer")]
+    let buzzer = {
+        use kernel::hil::time::Alarm;
+
+        let pwm = static_init!(apollo3::pwm::Pwm, apollo3::pwm::Pwm::new());
+        let mux_pwm = static_init!(
+            capsules::virtual_pwm::MuxPwm<'static, apollo3::pwm::Pwm>,
+            capsules::virtual_pwm::MuxPwm::new(pwm)
+        );
+        let buzzer_timer = peripherals
+            .ctimer
+            .claim(1, apollo3::ctimer::Segment::A)
+            .unwrap();
+        let virtual_pwm_buzzer = static_init!(
+            capsules::virtual_pwm::PwmPinUser<'static, apollo3::pwm::Pwm>,
+            capsules::virtual_pwm::PwmPinUser::new(
+                mux_pwm,
+                apollo3::pwm::PwmPin::new(&peripherals.gpio_port[18], 2, 4, 2, buzzer_timer)
+            )
+        );
+        virtual_pwm_buzzer.add_to_mux();
+
+        let virtual_alarm_buzzer = static_init!(
+            VirtualMuxAlarm<'static, apollo3::stimer::STimer<'static>>,
+            VirtualMuxAlarm::new(mux_alarm)
+        );
+        let buzzer: &'static _ = static_init!(
+            capsules::buzzer_driver::Buzzer<
+                'static,
+                VirtualMuxAlarm<'static, apollo3::stimer::STimer<'static>>,
+            >,
+            capsules::buzzer_driver::Buzzer::new(
+                virtual_pwm_buzzer,
+                virtual_alarm_buzzer,
+                capsules::buzzer_driver::DEFAULT_MAX_BUZZ_TIME_MS,
+                board_kernel.create_grant(&memory_allocation_cap)
+            )
+        );
+        virtual_alarm_buzzer.set_alarm_client(buzzer);
+        buzzer
+    };
+
     mcu_ctrl.print_chip_revision();
 
     debug!("Initialization complete. Entering main loop");
@@ -229,6 +305,8 @@ pub unsafe fn main() {
             led,
             i2c_master,
             ble_radio,
+            #[cfg(feature = "buzzer")]
+            buzzer,
         }
     );
 
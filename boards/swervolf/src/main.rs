@@ -97,7 +97,7 @@ pub unsafe fn main() {
         115200,
         dynamic_deferred_caller,
     )
-    .finalize(());
+    .finalize(components::uart_mux_component_helper!());
 
     let mtimer = static_init!(
         swervolf_eh1::syscon::SysCon,
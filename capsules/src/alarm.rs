@@ -1,5 +1,26 @@
 //! Tock syscall driver capsule for Alarms, which issue callbacks when
 //! a point in time has been reached.
+//!
+//! Periodic alarms
+//! ---------------
+//!
+//! Command `7` sets an alarm that the kernel rearms itself every `period`
+//! ticks, so a process does not need to set a new alarm after each callback.
+//! Each expiration is `period` ticks after the previous one, not after the
+//! callback ran, so periodic alarms do not drift.
+//!
+//! If the kernel is late enough that one or more whole periods have already
+//! passed, by default the missed expirations fire back to back. With
+//! coalescing enabled they are skipped instead: the alarm fires once and is
+//! rearmed for the next expiration still in the future, and the callback's
+//! third argument is the number of expirations skipped.
+//!
+//! The period must be at least `MIN_PERIOD_US` microseconds, and at least the
+//! shortest delay the underlying alarm can be set for. A shorter period would
+//! keep the kernel busy with the callbacks of a single process.
+//!
+//! Alarms at a wall-clock time, which survive a reset, are set through
+//! `capsules::wall_clock_alarm` instead.
 
 use core::cell::Cell;
 use core::mem;
@@ -10,6 +31,9 @@ use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Alarm as usize;
 
+/// Shortest period of a periodic alarm, in microseconds.
+pub const MIN_PERIOD_US: u32 = 1000;
+
 #[derive(Copy, Clone, Debug)]
 enum Expiration {
     Disabled,
@@ -19,6 +43,10 @@ enum Expiration {
 #[derive(Copy, Clone)]
 pub struct AlarmData {
     expiration: Expiration,
+    /// Ticks between expirations of a periodic alarm, or 0 for a one-shot.
+    period: u32,
+    /// Skip expirations of a periodic alarm that were missed.
+    coalesce: bool,
     callback: Upcall,
 }
 
@@ -26,6 +54,8 @@ impl Default for AlarmData {
     fn default() -> AlarmData {
         AlarmData {
             expiration: Expiration::Disabled,
+            period: 0,
+            coalesce: false,
             callback: Upcall::default(),
         }
     }
@@ -183,6 +213,11 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
     /// - `3`: Stop the alarm if it is outstanding
     /// - `4`: Set an alarm to fire at a given clock value `time`.
     /// - `5`: Set an alarm to fire at a given clock value `time` relative to `now` (EXPERIMENTAL).
+    /// - `6`: Set an alarm to fire `dt` (`data2`) ticks after `reference` (`data`).
+    /// - `7`: Set a periodic alarm that fires every `period` (`data`) ticks,
+    ///        starting `period` ticks from now. Bit 0 of `data2` enables
+    ///        coalescing of missed expirations. Returns `INVAL` if `period` is
+    ///        shorter than `MIN_PERIOD_US` or than the alarm's `minimum_dt`.
     fn command(
         &self,
         cmd_type: usize,
@@ -206,6 +241,7 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
                         reference: reference as u32,
                         dt: dt as u32,
                     };
+                    td.period = 0;
                     (
                         CommandReturn::success_u32(reference.wrapping_add(dt) as u32),
                         true,
@@ -229,6 +265,7 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
                             },
                             _ => {
                                 td.expiration = Expiration::Disabled;
+                                td.period = 0;
                                 let new_num_armed = self.num_armed.get() - 1;
                                 self.num_armed.set(new_num_armed);
                                 (CommandReturn::success(), true)
@@ -258,6 +295,22 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
                         let dt = data2;
                         rearm(reference, dt)
                     }
+                    7 /* Set periodic expiration */ => {
+                        let period = data as u32;
+                        let min_period = A::ticks_from_us(MIN_PERIOD_US)
+                            .into_u32()
+                            .max(self.alarm.minimum_dt().into_u32())
+                            .max(1);
+                        if data > u32::MAX as usize || period < min_period {
+                            (CommandReturn::failure(ErrorCode::INVAL), false)
+                        } else {
+                            let reference = now.into_u32() as usize;
+                            let result = rearm(reference, period as usize);
+                            td.period = period;
+                            td.coalesce = data2 & 1 != 0;
+                            result
+                        }
+                    }
                     _ => (CommandReturn::failure(ErrorCode::NOSUPPORT), false)
                 }
             })
@@ -284,12 +337,28 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmDriver<'a, A> {
                     Ticks32::from(reference),
                     Ticks32::from(reference.wrapping_add(dt)),
                 ) {
-                    alarm.expiration = Expiration::Disabled;
-                    self.num_armed.set(self.num_armed.get() - 1);
+                    let expired = reference.wrapping_add(dt);
+                    let mut skipped = 0;
+                    if alarm.period == 0 {
+                        alarm.expiration = Expiration::Disabled;
+                        self.num_armed.set(self.num_armed.get() - 1);
+                    } else {
+                        // Rearm from the expiration rather than from now so
+                        // the alarm does not drift.
+                        let mut next = expired;
+                        if alarm.coalesce {
+                            skipped = now.into_u32().wrapping_sub(expired) / alarm.period;
+                            next = next.wrapping_add(skipped.wrapping_mul(alarm.period));
+                        }
+                        alarm.expiration = Expiration::Enabled {
+                            reference: next,
+                            dt: alarm.period,
+                        };
+                    }
                     alarm.callback.schedule(
                         now.into_u32() as usize,
-                        reference.wrapping_add(dt) as usize,
-                        0,
+                        expired as usize,
+                        skipped as usize,
                     );
                 }
             }
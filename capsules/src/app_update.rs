@@ -0,0 +1,768 @@
+//! Lets a privileged process update an installed app by sending a binary
+//! diff against it instead of the whole new image.
+//!
+//! Over slow links, such as LoRa, sending a full image for every update of a
+//! large app is expensive, while a new version usually differs from the
+//! installed one in a small part of its blocks. This driver takes a patch in
+//! a simple format derived from bsdiff, applies it against the installed
+//! image, and writes the result to a staging region of flash. Once the patch
+//! is complete, the staged image is hashed with SHA-256 and compared with the
+//! hash the updater expects. Only if they match is the app stopped and the
+//! staged image copied over it. The kernel loads processes when the board
+//! boots, so the new version runs after the next reboot.
+//!
+//! The new image has to be the same size as the one it replaces, so that the
+//! apps after it in the app flash stay where the kernel looks for them. As
+//! TBF objects are padded to a power of two, most updates keep their size;
+//! those that do not have to be installed whole.
+//!
+//! The first block of the app, with its TBF header, is written last. Before
+//! the rest of the image is copied, that block is written with a header size
+//! of 0, which the kernel skips as an invalid header while keeping the total
+//! size to find the next app. If power fails during the copy, the kernel
+//! finds no app in the slot rather than the old header over a partly new
+//! image, and the updater can install it again.
+//!
+//! Updating apps changes what runs on the board, so the driver only accepts
+//! commands from processes whose TBF header grants them (see the
+//! `Permissions` TLV in `doc/TockBinaryFormat.md`), and the board has to give
+//! it the `ProcessManagementCapability` and the `RawFlashAccessCapability`.
+//!
+//! Patch Format
+//! ------------
+//!
+//! All integers are little-endian. The patch starts with an 8 byte header:
+//! the magic `TBDP` followed by the size of the new image as a `u32`. Then
+//! follow records, each a one byte kind and, except for `END`, a four byte
+//! argument:
+//!
+//! - `0` `END`: the new image is complete. It must come last.
+//! - `1` `DIFF`: the argument is followed by as many bytes, each of which is
+//!   added, wrapping, to the next byte of the old image to give the next byte
+//!   of the new image.
+//! - `2` `EXTRA`: the argument is followed by as many bytes, which are copied
+//!   to the new image as they are.
+//! - `3` `SEEK`: moves the position in the old image by the argument, an
+//!   `i32`.
+//!
+//! The position in the old image starts at 0 and is only moved by `DIFF` and
+//! `SEEK` records.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//! struct RawFlashCap;
+//! unsafe impl capabilities::RawFlashAccessCapability for RawFlashCap {}
+//!
+//! let app_update = static_init!(
+//!     capsules::app_update::AppUpdate<'static, ProcessMgmtCap>,
+//!     capsules::app_update::AppUpdate::new(
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         &RawFlashCap,
+//!         app_flash,
+//!         staging_flash,
+//!         nv_to_page,
+//!         sha,
+//!         board_kernel.create_grant(&grant_cap),
+//!         &mut capsules::app_update::BUFFER,
+//!         &mut capsules::app_update::HASH,
+//!     )
+//! );
+//! nv_to_page.set_client(app_update);
+//! sha.set_client(app_update);
+//! ```
+//!
+//! `app_flash` and `staging_flash` are the memory-mapped app flash and a
+//! region of flash of the same size or smaller, which must not overlap.
+//! `nv_to_page` must be able to write both, at the addresses they are mapped
+//! to.
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: the next chunk of the patch.
+//! - Read-only `1`: the expected SHA-256 hash of the new image.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Operation done. The first argument is the status and the second the
+//!   command that finished, `2` or `3`.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Always allowed.
+//! - `1`: Start updating the app at offset `arg1` in the app flash, as given
+//!   by the `installed_apps` driver. The offset must be the start of an app.
+//! - `2`: Apply the first `arg1` bytes of the allowed patch chunk.
+//! - `3`: Verify the new image against the allowed hash and install it.
+//! - `4`: Abandon the update.
+//!
+//! Commands return `NOSUPPORT` if the caller does not have permission, and
+//! `BUSY` if another process is updating an app. An update that fails ends;
+//! the updater has to start over. The hash not matching fails with `FAIL`,
+//! and a new image of the wrong size with `SIZE`.
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::mem;
+
+use kernel::capabilities::{ProcessManagementCapability, RawFlashAccessCapability};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest::{self, Digest};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice};
+use tock_tbf::parse::parse_tbf_header_lengths;
+use tock_tbf::types::InitialTbfParseError;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::AppUpdate as usize;
+
+pub static mut BUFFER: [u8; 512] = [0; 512];
+pub static mut HASH: [u8; 32] = [0; 32];
+
+const MAGIC: [u8; 4] = *b"TBDP";
+const HEADER_LEN: usize = 8;
+
+const RECORD_END: u8 = 0;
+const RECORD_DIFF: u8 = 1;
+const RECORD_EXTRA: u8 = 2;
+const RECORD_SEEK: u8 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for the next chunk of the patch.
+    Patching,
+    /// Applying a chunk of the patch.
+    Applying,
+    /// Hashing the staged image.
+    Verifying,
+    /// Copying the staged image over the app.
+    Installing,
+}
+
+/// Which part of the staged image `install` copies next.
+#[derive(Clone, Copy, PartialEq)]
+enum InstallStep {
+    /// The first block, with the TBF header marked invalid.
+    Invalidate,
+    /// The blocks after the first.
+    Body,
+    /// The first block as staged, which makes the new app valid.
+    Commit,
+    Done,
+}
+
+/// Where the parser is in the patch.
+#[derive(Clone, Copy)]
+enum Parse {
+    Header {
+        read: usize,
+        value: [u8; HEADER_LEN],
+    },
+    /// Expecting the kind of the next record.
+    Record,
+    Argument {
+        kind: u8,
+        read: usize,
+        value: [u8; 4],
+    },
+    /// In the data of a `DIFF` record, with this many bytes left.
+    Diff(usize),
+    /// In the data of an `EXTRA` record, with this many bytes left.
+    Extra(usize),
+    End,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    patch: ReadOnlyAppSlice,
+    hash: ReadOnlyAppSlice,
+}
+
+pub struct AppUpdate<'a, C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+    app_flash: &'static [u8],
+    staging: &'static [u8],
+    storage: &'a dyn NonvolatileStorage<'static>,
+    digest: &'a dyn Digest<'a, [u8; 32]>,
+    apps: Grant<App>,
+    buffer: TakeCell<'static, [u8]>,
+    hash: TakeCell<'static, [u8; 32]>,
+    current_app: OptionalCell<ProcessId>,
+    state: Cell<State>,
+    install_step: Cell<InstallStep>,
+    parse: Cell<Parse>,
+    /// The installed image being updated.
+    slot: Cell<&'static [u8]>,
+    old_position: Cell<usize>,
+    new_size: Cell<usize>,
+    /// Bytes of the new image produced so far.
+    produced: Cell<usize>,
+    /// Bytes of the new image in `buffer`, not yet written.
+    buffered: Cell<usize>,
+    /// Bytes of the new image written, hashed or installed so far.
+    position: Cell<usize>,
+    chunk_length: Cell<usize>,
+    chunk_position: Cell<usize>,
+}
+
+impl<'a, C: ProcessManagementCapability> AppUpdate<'a, C> {
+    pub fn new(
+        kernel: &'static Kernel,
+        capability: C,
+        _flash_capability: &dyn RawFlashAccessCapability,
+        app_flash: &'static [u8],
+        staging: &'static [u8],
+        storage: &'a dyn NonvolatileStorage<'static>,
+        digest: &'a dyn Digest<'a, [u8; 32]>,
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+        hash: &'static mut [u8; 32],
+    ) -> AppUpdate<'a, C> {
+        AppUpdate {
+            kernel,
+            capability,
+            app_flash,
+            staging,
+            storage,
+            digest,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            hash: TakeCell::new(hash),
+            current_app: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            install_step: Cell::new(InstallStep::Invalidate),
+            parse: Cell::new(Parse::Header {
+                read: 0,
+                value: [0; HEADER_LEN],
+            }),
+            slot: Cell::new(&[]),
+            old_position: Cell::new(0),
+            new_size: Cell::new(0),
+            produced: Cell::new(0),
+            buffered: Cell::new(0),
+            position: Cell::new(0),
+            chunk_length: Cell::new(0),
+            chunk_position: Cell::new(0),
+        }
+    }
+
+    /// The total size of the TBF object at the start of `flash`.
+    fn object_size(flash: &'static [u8]) -> Option<usize> {
+        let lengths: &'static [u8; 8] = flash.get(0..8)?.try_into().ok()?;
+        match parse_tbf_header_lengths(lengths) {
+            Ok((_, _, total_size)) | Err(InitialTbfParseError::InvalidHeader(total_size)) => {
+                Some(total_size as usize).filter(|size| *size > 0)
+            }
+            Err(InitialTbfParseError::UnableToParse) => None,
+        }
+    }
+
+    /// The TBF object that starts at `offset` in the app flash, or `None` if
+    /// no object starts there.
+    fn find_slot(&self, offset: usize) -> Option<&'static [u8]> {
+        let mut current = 0;
+        while current < offset {
+            current += Self::object_size(self.app_flash.get(current..)?)?;
+        }
+        if current != offset {
+            return None;
+        }
+        let flash = self.app_flash.get(current..)?;
+        flash.get(0..Self::object_size(flash)?)
+    }
+
+    fn start(&self, appid: ProcessId, offset: usize) -> Result<(), ErrorCode> {
+        if self.current_app.map_or(false, |current| *current != appid) {
+            return Err(ErrorCode::BUSY);
+        }
+        if self.state.get() != State::Idle && self.state.get() != State::Patching {
+            return Err(ErrorCode::BUSY);
+        }
+        let slot = self.find_slot(offset).ok_or(ErrorCode::INVAL)?;
+
+        self.current_app.set(appid);
+        self.state.set(State::Patching);
+        self.parse.set(Parse::Header {
+            read: 0,
+            value: [0; HEADER_LEN],
+        });
+        self.slot.set(slot);
+        self.old_position.set(0);
+        self.new_size.set(0);
+        self.produced.set(0);
+        self.buffered.set(0);
+        self.position.set(0);
+        Ok(())
+    }
+
+    /// Feed one byte of the patch to the parser, returning the byte of the
+    /// new image it produces, if any.
+    fn step(&self, byte: u8) -> Result<Option<u8>, ErrorCode> {
+        let (parse, output) = match self.parse.get() {
+            Parse::Header { read, mut value } => {
+                value[read] = byte;
+                if read + 1 < HEADER_LEN {
+                    (
+                        Parse::Header {
+                            read: read + 1,
+                            value,
+                        },
+                        None,
+                    )
+                } else {
+                    if value[0..4] != MAGIC {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    let new_size = u32::from_le_bytes([value[4], value[5], value[6], value[7]]);
+                    if new_size as usize > self.staging.len() {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    self.new_size.set(new_size as usize);
+                    (Parse::Record, None)
+                }
+            }
+            Parse::Record => match byte {
+                RECORD_END => {
+                    if self.produced.get() != self.new_size.get() {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    (Parse::End, None)
+                }
+                RECORD_DIFF | RECORD_EXTRA | RECORD_SEEK => (
+                    Parse::Argument {
+                        kind: byte,
+                        read: 0,
+                        value: [0; 4],
+                    },
+                    None,
+                ),
+                _ => return Err(ErrorCode::INVAL),
+            },
+            Parse::Argument {
+                kind,
+                read,
+                mut value,
+            } => {
+                value[read] = byte;
+                if read + 1 < value.len() {
+                    (
+                        Parse::Argument {
+                            kind,
+                            read: read + 1,
+                            value,
+                        },
+                        None,
+                    )
+                } else {
+                    let argument = u32::from_le_bytes(value);
+                    match kind {
+                        _ if argument == 0 => (Parse::Record, None),
+                        RECORD_DIFF => (Parse::Diff(argument as usize), None),
+                        RECORD_EXTRA => (Parse::Extra(argument as usize), None),
+                        _ => {
+                            let position = (self.old_position.get() as isize)
+                                .checked_add(argument as i32 as isize)
+                                .filter(|position| *position >= 0)
+                                .ok_or(ErrorCode::INVAL)?;
+                            self.old_position.set(position as usize);
+                            (Parse::Record, None)
+                        }
+                    }
+                }
+            }
+            Parse::Diff(remaining) => {
+                let position = self.old_position.get();
+                let old = *self.slot.get().get(position).ok_or(ErrorCode::INVAL)?;
+                self.old_position.set(position + 1);
+                let next = if remaining > 1 {
+                    Parse::Diff(remaining - 1)
+                } else {
+                    Parse::Record
+                };
+                (next, Some(old.wrapping_add(byte)))
+            }
+            Parse::Extra(remaining) => {
+                let next = if remaining > 1 {
+                    Parse::Extra(remaining - 1)
+                } else {
+                    Parse::Record
+                };
+                (next, Some(byte))
+            }
+            Parse::End => return Err(ErrorCode::INVAL),
+        };
+
+        if output.is_some() {
+            if self.produced.get() == self.new_size.get() {
+                return Err(ErrorCode::INVAL);
+            }
+            self.produced.set(self.produced.get() + 1);
+        }
+        self.parse.set(parse);
+        Ok(output)
+    }
+
+    /// Apply the current chunk of the patch until it is used up or the
+    /// buffer is full, writing the buffer to the staging region when it is
+    /// full or the new image is complete.
+    fn apply(&self) -> Result<(), ErrorCode> {
+        let appid = self.current_app.extract().ok_or(ErrorCode::FAIL)?;
+        let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+
+        let res = self
+            .apps
+            .enter(appid, |app| {
+                app.patch.map_or(Err(ErrorCode::RESERVE), |patch| {
+                    let mut buffered = self.buffered.get();
+                    let mut position = self.chunk_position.get();
+                    while position < self.chunk_length.get() && buffered < buffer.len() {
+                        let byte = *patch.get(position).ok_or(ErrorCode::SIZE)?;
+                        position += 1;
+                        if let Some(output) = self.step(byte)? {
+                            buffer[buffered] = output;
+                            buffered += 1;
+                        }
+                    }
+                    self.chunk_position.set(position);
+                    self.buffered.set(buffered);
+                    Ok(())
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        if let Err(e) = res {
+            self.buffer.replace(buffer);
+            return Err(e);
+        }
+
+        let buffered = self.buffered.get();
+        let complete = match self.parse.get() {
+            Parse::End => true,
+            _ => false,
+        };
+        if buffered == buffer.len() || (complete && buffered > 0) {
+            let address = self.staging.as_ptr() as usize + self.position.get();
+            self.storage.write(buffer, address, buffered)
+        } else {
+            self.buffer.replace(buffer);
+            self.state.set(State::Patching);
+            self.notify(Ok(()), 2);
+            Ok(())
+        }
+    }
+
+    /// Add the next block of the staged image to the digest, or compute the
+    /// digest once it is all added.
+    fn verify(&self) -> Result<(), ErrorCode> {
+        let image = &self.staging[..self.new_size.get()];
+        let position = self.position.get();
+        if position < image.len() {
+            let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+            let length = cmp::min(buffer.len(), image.len() - position);
+            buffer[..length].copy_from_slice(&image[position..position + length]);
+            let mut data = LeasableBuffer::new(buffer);
+            data.slice(0..length);
+            match self.digest.add_data(data) {
+                Ok(added) => {
+                    self.position.set(position + added);
+                    Ok(())
+                }
+                Err((e, buffer)) => {
+                    self.buffer.replace(buffer);
+                    Err(e)
+                }
+            }
+        } else {
+            let hash = self.hash.take().ok_or(ErrorCode::RESERVE)?;
+            self.digest.run(hash).map_err(|(e, hash)| {
+                self.hash.replace(hash);
+                e
+            })
+        }
+    }
+
+    /// Check the digest of the staged image against the one the updater
+    /// expects, and if they match start installing it.
+    fn verified(&self, digest: &[u8; 32]) -> Result<(), ErrorCode> {
+        let appid = self.current_app.extract().ok_or(ErrorCode::FAIL)?;
+        let matches = self
+            .apps
+            .enter(appid, |app| {
+                app.hash
+                    .map_or(Err(ErrorCode::RESERVE), |hash| Ok(hash.as_ref() == digest))
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+        if !matches {
+            return Err(ErrorCode::FAIL);
+        }
+        let slot = self.slot.get();
+        if Self::object_size(&self.staging[..self.new_size.get()]) != Some(slot.len()) {
+            return Err(ErrorCode::SIZE);
+        }
+
+        // The process must not run while its flash is rewritten.
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if process.flash_start() == slot.as_ptr() {
+                    process.stop();
+                }
+            });
+        self.state.set(State::Installing);
+        self.install_step.set(InstallStep::Invalidate);
+        self.position.set(0);
+        self.install()
+    }
+
+    /// Copy the next block of the staged image over the app, writing the
+    /// first block, which holds the TBF header, last.
+    fn install(&self) -> Result<(), ErrorCode> {
+        let size = self.new_size.get();
+        let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+        let first_len = cmp::min(buffer.len(), size);
+        let (start, length) = match self.install_step.get() {
+            InstallStep::Invalidate => (0, first_len),
+            InstallStep::Body if self.position.get() < size => {
+                let position = self.position.get();
+                (position, cmp::min(buffer.len(), size - position))
+            }
+            InstallStep::Body | InstallStep::Commit => {
+                self.install_step.set(InstallStep::Commit);
+                (0, first_len)
+            }
+            InstallStep::Done => {
+                self.buffer.replace(buffer);
+                self.finish(Ok(()), 3);
+                return Ok(());
+            }
+        };
+
+        buffer[..length].copy_from_slice(&self.staging[start..start + length]);
+        if self.install_step.get() == InstallStep::Invalidate {
+            // A header size below the minimum makes the kernel skip the app.
+            buffer[2..4].copy_from_slice(&0u16.to_le_bytes());
+        }
+        let address = self.slot.get().as_ptr() as usize + start;
+        self.storage.write(buffer, address, length)
+    }
+
+    /// Move on from the block of the image that was just written.
+    fn installed(&self) -> Result<(), ErrorCode> {
+        match self.install_step.get() {
+            InstallStep::Invalidate => self.install_step.set(InstallStep::Body),
+            InstallStep::Body => {}
+            InstallStep::Commit | InstallStep::Done => self.install_step.set(InstallStep::Done),
+        }
+        self.install()
+    }
+
+    /// Signal the updater that command `command` finished.
+    fn notify(&self, result: Result<(), ErrorCode>, command: usize) {
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), command, 0);
+            });
+        });
+    }
+
+    /// End the update.
+    fn finish(&self, result: Result<(), ErrorCode>, command: usize) {
+        self.notify(result, command);
+        self.state.set(State::Idle);
+        self.current_app.clear();
+    }
+
+    /// End the update if a step failed.
+    fn check(&self, result: Result<(), ErrorCode>, command: usize) {
+        if let Err(e) = result {
+            self.finish(Err(e), command);
+        }
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> NonvolatileStorageClient<'static> for AppUpdate<'a, C> {
+    fn read_done(&self, _buffer: &'static mut [u8], _length: usize) {}
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.position.set(self.position.get() + length);
+        match self.state.get() {
+            State::Applying => {
+                self.buffered.set(0);
+                self.check(self.apply(), 2);
+            }
+            State::Installing => self.check(self.installed(), 3),
+            State::Idle | State::Patching | State::Verifying => {}
+        }
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> digest::Client<'a, [u8; 32]> for AppUpdate<'a, C> {
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        self.buffer.replace(data);
+        self.check(result.and_then(|()| self.verify()), 3);
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; 32]) {
+        let res = result.and_then(|()| self.verified(digest));
+        self.hash.replace(digest);
+        self.check(res, 3);
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> Driver for AppUpdate<'a, C> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The next chunk of the patch
+    /// - `1`: The expected hash of the new image
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.patch);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut slice, &mut app.hash);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup an operation done callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Update an installed app.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Start updating the app at offset `arg1`
+    /// - `2`: Apply `arg1` bytes of the patch
+    /// - `3`: Verify and install the new image
+    /// - `4`: Abandon the update
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        if cmd_num == 0 {
+            return CommandReturn::success();
+        }
+        if !self.kernel.command_permitted(appid, DRIVER_NUM, cmd_num) {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+        if cmd_num != 1 && !self.current_app.contains(&appid) {
+            return CommandReturn::failure(if self.current_app.is_some() {
+                ErrorCode::BUSY
+            } else {
+                ErrorCode::INVAL
+            });
+        }
+
+        let res = match cmd_num {
+            1 => self.start(appid, arg1),
+            2 => {
+                if self.state.get() != State::Patching {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    self.chunk_length.set(arg1);
+                    self.chunk_position.set(0);
+                    self.state.set(State::Applying);
+                    let res = self.apply();
+                    if res.is_err() {
+                        self.state.set(State::Idle);
+                        self.current_app.clear();
+                    }
+                    res
+                }
+            }
+            3 => match (self.state.get(), self.parse.get()) {
+                (State::Patching, Parse::End) => {
+                    let hash_len = self.apps.enter(appid, |app| app.hash.len()).unwrap_or(0);
+                    if hash_len != 32 {
+                        Err(ErrorCode::INVAL)
+                    } else {
+                        self.state.set(State::Verifying);
+                        self.position.set(0);
+                        self.digest.clear_data();
+                        let res = self.verify();
+                        if res.is_err() {
+                            self.state.set(State::Idle);
+                            self.current_app.clear();
+                        }
+                        res
+                    }
+                }
+                (State::Patching, _) => Err(ErrorCode::INVAL),
+                _ => Err(ErrorCode::BUSY),
+            },
+            4 => {
+                if self.state.get() == State::Patching {
+                    self.state.set(State::Idle);
+                    self.current_app.clear();
+                    Ok(())
+                } else {
+                    Err(ErrorCode::BUSY)
+                }
+            }
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
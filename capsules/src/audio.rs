@@ -0,0 +1,410 @@
+//! Provides userspace with tone and sample playback over an I2S controller.
+//!
+//! Audio is played as 16-bit stereo, with the same sample sent to both
+//! channels. One process can play at a time; the others get `BUSY` until
+//! it has finished.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let audio = static_init!(
+//!     capsules::audio::Audio<'static>,
+//!     capsules::audio::Audio::new(
+//!         &base_peripherals.i2s,
+//!         &mut capsules::audio::BUFFER1,
+//!         &mut capsules::audio::BUFFER2,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! kernel::hil::i2s::I2s::set_client(&base_peripherals.i2s, audio);
+//! audio.initialize();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: samples to play, as signed 16-bit little-endian mono
+//!   samples.
+//!
+//! ### Subscribe
+//!
+//! - `0`: playback finished. The callback gets the status and the number of
+//!   samples played. The status is `SIZE` if playback stopped because the
+//!   allowed buffer no longer holds the samples being played.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Play a square wave tone at `arg1` Hz for `arg2` milliseconds.
+//! - `2`: Play the first `arg1` samples of the allowed buffer.
+//! - `3`: Stop playback.
+//! - `4`: Set the sample rate to `arg1` Hz. Returns the rate used, which may
+//!   differ from the one requested.
+
+use core::cell::Cell;
+use core::mem;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::i2s;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Audio as usize;
+
+/// Length of each playback buffer, in stereo samples.
+pub const BUFFER_LEN: usize = 256;
+
+pub static mut BUFFER1: [u32; BUFFER_LEN] = [0; BUFFER_LEN];
+pub static mut BUFFER2: [u32; BUFFER_LEN] = [0; BUFFER_LEN];
+
+/// Sample rate used until a process sets another one.
+pub const DEFAULT_SAMPLE_RATE: u32 = 16000;
+
+/// Amplitude of generated tones, about a quarter of full scale.
+const TONE_AMPLITUDE: i16 = 8000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Source {
+    Idle,
+    /// Square wave with the given half period, in samples.
+    Tone(u32),
+    Samples,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    samples: ReadOnlyAppSlice,
+}
+
+pub struct Audio<'a> {
+    i2s: &'a dyn i2s::I2s<'a>,
+    buffer1: TakeCell<'static, [u32]>,
+    buffer2: TakeCell<'static, [u32]>,
+    sample_rate: Cell<u32>,
+    source: Cell<Source>,
+    /// Samples generated or copied so far.
+    position: Cell<usize>,
+    /// Total number of samples to play.
+    length: Cell<usize>,
+    /// Set once every sample has been queued, playback stops when the
+    /// buffer holding the last samples has been played.
+    draining: Cell<bool>,
+    /// Why playback was stopped early, reported when it has stopped.
+    error: OptionalCell<ErrorCode>,
+    current_app: OptionalCell<ProcessId>,
+    apps: Grant<App>,
+}
+
+/// Pack a mono sample into a stereo word.
+fn stereo(sample: i16) -> u32 {
+    let sample = sample as u16 as u32;
+    sample | (sample << 16)
+}
+
+impl<'a> Audio<'a> {
+    pub fn new(
+        i2s: &'a dyn i2s::I2s<'a>,
+        buffer1: &'static mut [u32],
+        buffer2: &'static mut [u32],
+        grant: Grant<App>,
+    ) -> Audio<'a> {
+        Audio {
+            i2s: i2s,
+            buffer1: TakeCell::new(buffer1),
+            buffer2: TakeCell::new(buffer2),
+            sample_rate: Cell::new(DEFAULT_SAMPLE_RATE),
+            source: Cell::new(Source::Idle),
+            position: Cell::new(0),
+            length: Cell::new(0),
+            draining: Cell::new(false),
+            error: OptionalCell::empty(),
+            current_app: OptionalCell::empty(),
+            apps: grant,
+        }
+    }
+
+    /// Configure the I2S controller with the default sample rate.
+    pub fn initialize(&self) {
+        let _ = self.set_sample_rate(DEFAULT_SAMPLE_RATE);
+    }
+
+    fn set_sample_rate(&self, rate: u32) -> Result<u32, ErrorCode> {
+        let rate = self.i2s.configure(i2s::Configuration {
+            sample_rate: rate,
+            sample_width: i2s::SampleWidth::Bits16,
+            channels: i2s::Channels::Stereo,
+        })?;
+        self.sample_rate.set(rate);
+        Ok(rate)
+    }
+
+    /// Fill `buffer` with the next samples, padding with silence. Returns
+    /// the number of samples written.
+    fn fill(&self, buffer: &mut [u32]) -> Result<usize, ErrorCode> {
+        let position = self.position.get();
+        let count = core::cmp::min(buffer.len(), self.length.get() - position);
+        let result = match self.source.get() {
+            Source::Tone(half_period) => {
+                for (i, word) in buffer[..count].iter_mut().enumerate() {
+                    let phase = (position + i) as u32 / half_period;
+                    let sample = if phase % 2 == 0 {
+                        TONE_AMPLITUDE
+                    } else {
+                        -TONE_AMPLITUDE
+                    };
+                    *word = stereo(sample);
+                }
+                Ok(())
+            }
+            Source::Samples => self.current_app.map_or(Err(ErrorCode::FAIL), |appid| {
+                self.apps
+                    .enter(*appid, |app| {
+                        app.samples.map_or(Err(ErrorCode::SIZE), |samples| {
+                            // The process may have allowed a shorter buffer
+                            // since playback started.
+                            let samples = samples
+                                .get(position * 2..(position + count) * 2)
+                                .ok_or(ErrorCode::SIZE)?;
+                            for (word, sample) in
+                                buffer[..count].iter_mut().zip(samples.chunks_exact(2))
+                            {
+                                *word = stereo(i16::from_le_bytes([sample[0], sample[1]]));
+                            }
+                            Ok(())
+                        })
+                    })
+                    .unwrap_or_else(|err| Err(err.into()))
+            }),
+            Source::Idle => Ok(()),
+        };
+        let count = if result.is_ok() { count } else { 0 };
+        for word in buffer[count..].iter_mut() {
+            *word = 0;
+        }
+        self.position.set(position + count);
+        result.map(|()| count)
+    }
+
+    fn start(&self, appid: ProcessId, source: Source, length: usize) -> Result<(), ErrorCode> {
+        if self.current_app.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if length == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        let (buffer1, buffer2) = match (self.buffer1.take(), self.buffer2.take()) {
+            (Some(buffer1), Some(buffer2)) => (buffer1, buffer2),
+            (buffer1, buffer2) => {
+                buffer1.map(|buffer| self.buffer1.replace(buffer));
+                buffer2.map(|buffer| self.buffer2.replace(buffer));
+                return Err(ErrorCode::BUSY);
+            }
+        };
+
+        self.current_app.set(appid);
+        self.source.set(source);
+        self.position.set(0);
+        self.length.set(length);
+        self.draining.set(false);
+        self.error.clear();
+        if let Err(e) = self.fill(buffer1).and_then(|_| self.fill(buffer2)) {
+            self.buffer1.replace(buffer1);
+            self.buffer2.replace(buffer2);
+            self.current_app.clear();
+            self.source.set(Source::Idle);
+            return Err(e);
+        }
+        let (len1, len2) = (buffer1.len(), buffer2.len());
+        self.i2s
+            .start_output(buffer1, len1, buffer2, len2)
+            .map_err(|(e, buffer1, buffer2)| {
+                self.buffer1.replace(buffer1);
+                self.buffer2.replace(buffer2);
+                self.current_app.clear();
+                self.source.set(Source::Idle);
+                e
+            })
+    }
+}
+
+impl i2s::Client for Audio<'_> {
+    fn buffer_done(&self, buffer: &'static mut [u32], _length: usize) {
+        if self.draining.get() {
+            // The last samples have been played.
+            self.buffer1.replace(buffer);
+            let _ = self.i2s.stop();
+            return;
+        }
+        match self.fill(buffer) {
+            Ok(0) => self.draining.set(true),
+            Ok(_) => {}
+            Err(e) => {
+                self.error.set(e);
+                self.buffer1.replace(buffer);
+                let _ = self.i2s.stop();
+                return;
+            }
+        }
+        let length = buffer.len();
+        if let Err((_, buffer)) = self.i2s.provide_buffer(buffer, length) {
+            self.buffer1.replace(buffer);
+            let _ = self.i2s.stop();
+        }
+    }
+
+    fn stopped(&self) {
+        if let Ok((buffer1, buffer2)) = self.i2s.retrieve_buffers() {
+            for buffer in [buffer1, buffer2].iter_mut() {
+                if let Some(buffer) = buffer.take() {
+                    if self.buffer1.is_none() {
+                        self.buffer1.replace(buffer);
+                    } else {
+                        self.buffer2.replace(buffer);
+                    }
+                }
+            }
+        }
+        self.source.set(Source::Idle);
+        let played = self.position.get();
+        let result = self.error.take().map_or(Ok(()), Err);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), played, 0);
+            });
+        });
+    }
+}
+
+impl Driver for Audio<'_> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Samples to play
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => {
+                if self.current_app.contains(&appid) {
+                    // The samples are being played from this buffer.
+                    Err(ErrorCode::BUSY)
+                } else {
+                    self.apps
+                        .enter(appid, |app| {
+                            mem::swap(&mut app.samples, &mut slice);
+                        })
+                        .map_err(ErrorCode::from)
+                }
+            }
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Playback finished callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Control playback.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Play a tone of `arg1` Hz for `arg2` ms
+    /// - `2`: Play `arg1` samples from the allowed buffer
+    /// - `3`: Stop playback
+    /// - `4`: Set the sample rate
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
+        let res = match cmd_num {
+            0 => Ok(()),
+            1 => {
+                let rate = self.sample_rate.get() as usize;
+                if arg1 == 0 || arg1 > rate / 2 {
+                    Err(ErrorCode::INVAL)
+                } else {
+                    let half_period = (rate / (arg1 * 2)) as u32;
+                    rate.checked_mul(arg2)
+                        .ok_or(ErrorCode::INVAL)
+                        .and_then(|samples| {
+                            self.start(appid, Source::Tone(half_period), samples / 1000)
+                        })
+                }
+            }
+            2 => self
+                .apps
+                .enter(appid, |app| app.samples.len())
+                .map_err(ErrorCode::from)
+                .and_then(|len| {
+                    if arg1.checked_mul(2).map_or(true, |bytes| bytes > len) {
+                        Err(ErrorCode::SIZE)
+                    } else {
+                        self.start(appid, Source::Samples, arg1)
+                    }
+                }),
+            3 => {
+                if self.current_app.contains(&appid) {
+                    self.i2s.stop()
+                } else {
+                    Err(ErrorCode::OFF)
+                }
+            }
+            4 => {
+                if self.current_app.is_some() {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    match self.set_sample_rate(arg1 as u32) {
+                        Ok(rate) => return CommandReturn::success_u32(rate),
+                        Err(e) => Err(e),
+                    }
+                }
+            }
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
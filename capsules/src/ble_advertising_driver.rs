@@ -8,16 +8,44 @@
 //! Processes can also control the TX power used for their advertisements.
 //!
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
-//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
+//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header. On radios that support
+//! BLE 5 extended advertising, processes can instead advertise up to 245 bytes: each packet on
+//! the advertising channels (`ADV_EXT_IND`) points to a packet on a data channel
+//! (`AUX_ADV_IND`) that carries the data. Extended advertisements are neither connectable nor
+//! scannable.
+//!
+//! Scannable advertisements (`ADV_IND` and `ADV_SCAN_IND`) of a process that shares scan response
+//! data are answered: on radios that support it, a `SCAN_REQ` for the process's address gets a
+//! `SCAN_RSP` with that data in reply, sent by the radio itself since it must follow the request
+//! within 150 µs.
 //!
 //! ### Allow system calls
 //!
-//! There is one ReadWrite and one ReadOnly allow buffers, both at index `0`.
+//! There is one ReadWrite allow buffer, at index `0`, and four ReadOnly allow buffers, at
+//! indices `0` to `3`.
 //!
-//! * ReadOnly: Advertising data, containing the full _payload_ (i.e. excluding the header) the
-//!             process wishes to advertise.
+//! * ReadOnly 0: Advertising data, containing the full _payload_ (i.e. excluding the header) the
+//!               process wishes to advertise.
+//! * ReadOnly 1: Extended advertising data, the payload of extended advertisements.
+//! * ReadOnly 2: Scan filter list. While it is not empty, the driver drops the received
+//!               advertisements that match none of its entries, without waking the process.
+//! * ReadOnly 3: Scan response data, the payload of the `SCAN_RSP` after the address, up to 31
+//!               bytes. While it is not empty, the process's scannable advertisements are
+//!               answered.
 //! * ReadWrite: Passive scanning buffer, which is populated during BLE scans with complete (i.e.
 //!              including headers) advertising packets received on channels 37, 38 and 39.
+//!              Byte 0 is the PDU header, with the advertiser's address type in bit 6 (0 for
+//!              public, 1 for random), byte 1 the length, bytes 2 to 7 the advertiser's
+//!              address and the advertising data follows.
+//!
+//! The scan filter list is a sequence of entries, each a type byte followed by a value:
+//!
+//! * 0: an advertiser address, 6 bytes in the order of the packet. Matches advertisements from
+//!      that address.
+//! * 1, 2, 3: a 16-bit, 32-bit or 128-bit service UUID, 2, 4 or 16 bytes, little-endian.
+//!      Matches advertisements that list the UUID as a service, or carry service data for it.
+//!
+//! Parsing stops at the first entry with an unknown type or that is cut short.
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
@@ -34,7 +62,17 @@
 //!  The `subscribe` is used to specify the specific operation, currently:
 //!
 //! * 0: provides a callback user-space when a device scanning for advertisements
-//!      and the callback is used to invoke user-space processes.
+//!      and the callback is used to invoke user-space processes. The callback
+//!      gets the length of the packet copied to the scan buffer, and as its
+//!      third argument:
+//!      * bits 0 to 7: the RSSI the packet was received with, in dBm as a two's
+//!        complement byte, or 0 if the radio does not measure it,
+//!      * bit 8: the advertiser's address type, 0 for public and 1 for random,
+//!      * bits 16 to 23: the length of the advertising data, excluding the
+//!        header and address, even if the scan buffer was too short for all of
+//!        it.
+//!
+//!      Packets with an invalid CRC are dropped.
 //!
 //! The possible return codes from the `allow` system call indicate the following:
 //!
@@ -47,15 +85,31 @@
 //! `command number` is used to specify the specific operation, currently
 //! the following commands are supported:
 //!
-//! * 0: start advertisement
+//! * 0: start advertisement, with the PDU type in `data` and the interval in ms (at least 20) in
+//!      `subcommand`. Each advertising event sends the advertising data on the process's
+//!      advertising channels (see command 7); events are skipped while no advertising data is
+//!      shared.
 //! * 1: stop advertisement or scanning
+//! * 2: set the TX power of the process's advertisements, in dBm as a two's complement byte in
+//!      `data`, between -20 and 10 dBm and supported by the radio. Each process has its own TX
+//!      power, 0 dBm by default, which takes effect from its next advertising event.
 //! * 5: start scanning
+//! * 6: start extended advertisement, with the PHY of the data channel packets in `data` (0 for
+//!      LE 1M, 1 for LE 2M and 2 for LE Coded) and the interval in ms (at least 20) in
+//!      `subcommand`. Each advertising event picks a data channel at random; events are skipped
+//!      while no extended advertising data is shared.
+//! * 7: set the primary advertising channels of the process, as a bit map in `data` with bit 0
+//!      for channel 37, bit 1 for channel 38 and bit 2 for channel 39. At least one channel must
+//!      be set. Each process advertises on all three channels by default, and the map takes
+//!      effect from its next advertising event.
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
 //! * Ok(()):      The command was successful
 //! * BUSY:        The driver is currently busy with other tasks
-//! * ENOSUPPORT:   The operation is not supported
+//! * INVAL:       The TX power is outside of the range of BLE, the PDU type or PHY is invalid, or
+//!                the channel map is empty
+//! * ENOSUPPORT:   The operation, TX power, PHY or channel map is not supported
 //!
 //! Usage
 //! -----
@@ -84,6 +138,50 @@
 //! ble_radio_virtual_alarm.set_client(ble_radio);
 //! ```
 //!
+//! ### Address rotation
+//!
+//! By default each process advertises with a static address derived from its process ID. A
+//! board with an entropy source to spare can have the driver give each process a new random
+//! non-resolvable private address periodically, so observers cannot track the device by its
+//! address:
+//!
+//! ```rust
+//! ble_radio.enable_address_rotation(entropy, 15 * 60 * 1000);
+//! entropy.set_client(ble_radio);
+//! ```
+//!
+//! A process keeps its static address until the first random address is ready. The address
+//! never changes in the middle of an advertising event.
+//!
+//! With an identity resolving key (IRK) and an AES engine of its own, the driver gives out
+//! resolvable private addresses instead, which peers that were given the IRK can still
+//! recognize. The driver must be the client of the engine, so it cannot share it with, for
+//! example, the 802.15.4 stack. The engine only needs to implement AES-128-CTR, such as
+//! `nrf5x::aes::AesECB`:
+//!
+//! ```rust
+//! ble_radio.enable_address_rotation(entropy, 15 * 60 * 1000);
+//! ble_radio.enable_resolvable_addresses(
+//!     &base_peripherals.ecb,
+//!     &IDENTITY_RESOLVING_KEY,
+//!     &mut capsules::ble_advertising_driver::AES_BUF,
+//! );
+//! entropy.set_client(ble_radio);
+//! kernel::hil::symmetric_encryption::AES128::set_client(&base_peripherals.ecb, ble_radio);
+//! ```
+//!
+//! ### Regulatory limits
+//!
+//! A board that ships in several regions can give the driver the limits of the region it is in
+//! (see `capsules::radio_limits`). The driver then refuses TX powers above the BLE cap and channel
+//! maps that include a forbidden advertising channel, and skips forbidden channels in the advertising
+//! events of processes that did not set a channel map. The limits can change at run time, so each
+//! advertising event is also sent at no more than the cap, whatever TX power the process set.
+//!
+//! ```rust
+//! ble_radio.set_limits(radio_limits);
+//! ```
+//!
 //! ### Authors
 //! * Niklas Adolfsson <niklasadolfsson1@gmail.com>
 //! * Fredrik Nilsson <frednils@student.chalmers.se>
@@ -104,24 +202,121 @@
 use core::cell::Cell;
 use core::cmp;
 use core::mem;
-use kernel::common::cells::OptionalCell;
+use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::debug;
 use kernel::hil::ble_advertising;
-use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_advertising::{Phy, RadioChannel};
+use kernel::hil::entropy::{self, Entropy32};
+use kernel::hil::symmetric_encryption::{self, AES128Ctr, AES128, AES128_BLOCK_SIZE};
 use kernel::hil::time::{Frequency, Ticks};
 use kernel::{CommandReturn, ErrorCode, Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
 
 /// Syscall driver number.
 use crate::driver;
+use crate::radio_limits::{Radio, RadioLimits};
 pub const DRIVER_NUM: usize = driver::NUM::BleAdvertising as usize;
 
-/// Advertisement Buffer
-pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+/// Advertisement Buffer, large enough for both packets of an extended advertisement
+pub static mut BUF: [u8; BUF_LENGTH] = [0; BUF_LENGTH];
+
+/// Buffers of the AES engine, for the input and the output of `ah`
+pub static mut AES_BUF: [u8; 2 * AES128_BLOCK_SIZE] = [0; 2 * AES128_BLOCK_SIZE];
+
+/// An AES engine the driver can compute the hash of resolvable private addresses with.
+pub trait AddressCipher<'a>: AES128<'a> + AES128Ctr {}
+impl<'a, T: AES128<'a> + AES128Ctr> AddressCipher<'a> for T {}
 
 const PACKET_ADDR_LEN: usize = 6;
 const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
 
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.4 Common Extended
+// Advertising Payload Format
+//
+// The ADV_EXT_IND holds the extended header length and AdvMode, the extended header flags, the
+// ADI and the AuxPtr. The AUX_ADV_IND holds the extended header length and AdvMode, the flags,
+// the AdvA and the ADI, followed by the data.
+const EXT_PRIMARY_LENGTH: usize = 2 + 1 + 1 + 2 + 3;
+const AUX_HEADER_LENGTH: usize = 2 + 1 + 1 + PACKET_ADDR_LEN + 2;
+const AUX_PACKET_LENGTH: usize = 2 + 255;
+const EXT_ADV_DATA_LENGTH: usize = AUX_PACKET_LENGTH - AUX_HEADER_LENGTH;
+const BUF_LENGTH: usize = EXT_PRIMARY_LENGTH + AUX_PACKET_LENGTH;
+
+const EXT_HEADER_ADVA: u8 = 1 << 0;
+const EXT_HEADER_ADI: u8 = 1 << 3;
+const EXT_HEADER_AUX_PTR: u8 = 1 << 4;
+/// `AuxOffset` is in units of 30 µs
+const AUX_OFFSET_UNIT_US: u32 = 30;
+
+// Types of the entries of a scan filter list
+const FILTER_ADDRESS: u8 = 0;
+const FILTER_UUID16: u8 = 1;
+const FILTER_UUID32: u8 = 2;
+const FILTER_UUID128: u8 = 3;
+
+// Core Specification Supplement, Part A, section 1: the AD types of service UUID lists, both
+// incomplete and complete, and of service data, for 16-bit, 32-bit and 128-bit UUIDs
+const AD_TYPE_UUID16_LISTS: [u8; 2] = [0x02, 0x03];
+const AD_TYPE_UUID32_LISTS: [u8; 2] = [0x04, 0x05];
+const AD_TYPE_UUID128_LISTS: [u8; 2] = [0x06, 0x07];
+const AD_TYPE_SERVICE_DATA_UUID16: u8 = 0x16;
+const AD_TYPE_SERVICE_DATA_UUID32: u8 = 0x20;
+const AD_TYPE_SERVICE_DATA_UUID128: u8 = 0x21;
+
+/// The entries of a scan filter list, as their type and value.
+fn filter_entries(mut filter: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    core::iter::from_fn(move || {
+        let (&kind, rest) = filter.split_first()?;
+        let len = match kind {
+            FILTER_ADDRESS => PACKET_ADDR_LEN,
+            FILTER_UUID16 => 2,
+            FILTER_UUID32 => 4,
+            FILTER_UUID128 => 16,
+            _ => return None,
+        };
+        let value = rest.get(..len)?;
+        filter = &rest[len..];
+        Some((kind, value))
+    })
+}
+
+/// The AD structures of advertising data, as their AD type and data.
+fn ad_structures(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    core::iter::from_fn(move || {
+        let (&len, rest) = data.split_first()?;
+        // A zero length ends the significant part of the data
+        let structure = rest.get(..len as usize).filter(|_| len > 0)?;
+        data = &rest[len as usize..];
+        Some((structure[0], &structure[1..]))
+    })
+}
+
+/// Whether an advertising packet, header included, matches a scan filter entry.
+fn filter_matches(kind: u8, value: &[u8], packet: &[u8]) -> bool {
+    if kind == FILTER_ADDRESS {
+        return packet.get(2..2 + PACKET_ADDR_LEN) == Some(value);
+    }
+
+    // Only these PDUs carry advertising data after the advertiser's address
+    match packet[0] & 0x0f {
+        ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | SCAN_RESP => {}
+        _ => return false,
+    }
+    let (lists, service_data) = match kind {
+        FILTER_UUID16 => (AD_TYPE_UUID16_LISTS, AD_TYPE_SERVICE_DATA_UUID16),
+        FILTER_UUID32 => (AD_TYPE_UUID32_LISTS, AD_TYPE_SERVICE_DATA_UUID32),
+        _ => (AD_TYPE_UUID128_LISTS, AD_TYPE_SERVICE_DATA_UUID128),
+    };
+    let adv_data = packet.get(2 + PACKET_ADDR_LEN..).unwrap_or(&[]);
+    ad_structures(adv_data).any(|(ad_type, data)| {
+        if lists.contains(&ad_type) {
+            data.chunks_exact(value.len()).any(|uuid| uuid == value)
+        } else {
+            ad_type == service_data && data.get(..value.len()) == Some(value)
+        }
+    })
+}
+
 #[derive(PartialEq, Debug)]
 enum BLEState {
     NotInitialized,
@@ -132,6 +327,54 @@ enum BLEState {
     Advertising(RadioChannel),
 }
 
+// Bluetooth Core Specification: Vol. 6, Part B, section 1.3.2.2 Resolvable Private Address
+//
+// The 24-bit `prand` has its two most significant bits equal to 0 and 1, and its random part
+// has at least one bit set to 0 and one set to 1.
+fn resolvable_prand(random: u32) -> Option<u32> {
+    let random = random & 0x3f_ffff;
+    if random == 0 || random == 0x3f_ffff {
+        None
+    } else {
+        Some(0x40_0000 | random)
+    }
+}
+
+// Bluetooth Core Specification: Vol. 3, Part H, section 2.2.2 Random Address Hash function ah
+//
+// `ah` is the AES-128 encryption, with the IRK, of `prand` padded with zeros to 128 bits, most
+// significant byte first.
+fn ah_plaintext(prand: u32) -> [u8; AES128_BLOCK_SIZE] {
+    let mut plaintext = [0; AES128_BLOCK_SIZE];
+    plaintext[13..].copy_from_slice(&prand.to_be_bytes()[1..]);
+    plaintext
+}
+
+// The resolvable private address of `prand`, given the encryption of its `ah_plaintext`. The
+// hash is the 24 least significant bits of the encryption, and the address is `prand` followed
+// by the hash, sent least significant byte first.
+fn resolvable_address(prand: u32, ciphertext: &[u8]) -> [u8; PACKET_ADDR_LEN] {
+    let prand = prand.to_le_bytes();
+    [
+        ciphertext[15],
+        ciphertext[14],
+        ciphertext[13],
+        prand[0],
+        prand[1],
+        prand[2],
+    ]
+}
+
+// The highest TX power, as a two's complement byte, that is at most both `requested` and `cap`
+// dBm and that the radio supports, or `None` if the radio supports none of them.
+fn capped_tx_power(requested: u8, cap: i8, supported: impl Fn(u8) -> bool) -> Option<u8> {
+    let highest = cmp::min(requested as i8, cap);
+    (i8::MIN..=highest)
+        .rev()
+        .map(|dbm| dbm as u8)
+        .find(|&power| supported(power))
+}
+
 #[derive(Copy, Clone)]
 enum Expiration {
     Disabled,
@@ -160,11 +403,15 @@ const ADV_DIRECTED_IND: AdvPduType = 0b0001;
 const ADV_NONCONN_IND: AdvPduType = 0b0010;
 #[allow(dead_code)]
 const SCAN_REQ: AdvPduType = 0b0011;
-#[allow(dead_code)]
 const SCAN_RESP: AdvPduType = 0b0100;
 #[allow(dead_code)]
 const CONNECT_IND: AdvPduType = 0b0101;
 const ADV_SCAN_IND: AdvPduType = 0b0110;
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3
+const ADV_EXT_IND: AdvPduType = 0b0111;
+
+// Channel map of all three primary advertising channels, 37 to 39 from bit 0
+const ADV_CHANNEL_MAP_ALL: u8 = 0b111;
 
 /// Process specific memory
 pub struct App {
@@ -174,9 +421,24 @@ pub struct App {
     // Advertising meta-data
     adv_data: ReadOnlyAppSlice,
     address: [u8; PACKET_ADDR_LEN],
+    /// When the current private address was set, in the ticks of `BLE::now_ticks`, or `None`
+    /// while the process uses its static address.
+    address_rotated_at: Option<u64>,
     pdu_type: AdvPduType,
     advertisement_interval_ms: u32,
     tx_power: u8,
+    /// The primary advertising channels the process advertises on, 37 to 39 from bit 0.
+    channel_map: u8,
+    scan_rsp_data: ReadOnlyAppSlice,
+
+    // Extended advertising meta-data
+    ext_adv_data: ReadOnlyAppSlice,
+    /// The PHY of the data channel packets, while advertising extended advertisements.
+    secondary_phy: Option<Phy>,
+    /// The data channel of the current advertising event.
+    secondary_channel: RadioChannel,
+    /// The Advertising Data ID of the current advertising event.
+    adv_did: u16,
     /// The state of an app-specific pseudo random number.
     ///
     /// For example, it can be used for the pseudo-random `advDelay` parameter.
@@ -186,6 +448,7 @@ pub struct App {
 
     // Scanning meta-data
     scan_buffer: ReadWriteAppSlice,
+    scan_filter: ReadOnlyAppSlice,
     scan_callback: kernel::Upcall,
 }
 
@@ -195,12 +458,20 @@ impl Default for App {
             alarm_data: AlarmData::new(),
             adv_data: ReadOnlyAppSlice::default(),
             scan_buffer: ReadWriteAppSlice::default(),
+            scan_filter: ReadOnlyAppSlice::default(),
             address: [0; PACKET_ADDR_LEN],
+            address_rotated_at: None,
             pdu_type: ADV_NONCONN_IND,
             scan_callback: kernel::Upcall::default(),
             process_status: Some(BLEState::NotInitialized),
             tx_power: 0,
+            channel_map: ADV_CHANNEL_MAP_ALL,
+            scan_rsp_data: ReadOnlyAppSlice::default(),
             advertisement_interval_ms: 200,
+            ext_adv_data: ReadOnlyAppSlice::default(),
+            secondary_phy: None,
+            secondary_channel: RadioChannel::DataChannel0,
+            adv_did: 0,
             // Just use any non-zero starting value by default
             random_nonce: 0xdeadbeef,
         }
@@ -225,6 +496,10 @@ impl App {
     // Byte 6            0xf0
     // FIXME: For now use ProcessId as "randomness"
     fn generate_random_address(&mut self, appid: kernel::ProcessId) -> Result<(), ErrorCode> {
+        if self.address_rotated_at.is_some() {
+            // Keep the private address until it is rotated
+            return Ok(());
+        }
         self.address = [
             0xf0,
             (appid.id() & 0xff) as u8,
@@ -236,13 +511,117 @@ impl App {
         Ok(())
     }
 
+    // The first primary advertising channel in the channel map after `previous`, or the first in
+    // the map if `previous` is `None`. Channels not in `allowed` are skipped.
+    fn next_advertising_channel(
+        &self,
+        previous: Option<RadioChannel>,
+        allowed: u8,
+    ) -> Option<RadioChannel> {
+        let channels = [
+            RadioChannel::AdvertisingChannel37,
+            RadioChannel::AdvertisingChannel38,
+            RadioChannel::AdvertisingChannel39,
+        ];
+        let first = previous.map_or(0, |previous| {
+            channels
+                .iter()
+                .position(|&channel| channel == previous)
+                .map_or(channels.len(), |index| index + 1)
+        });
+        (first..channels.len())
+            .find(|&index| self.channel_map & allowed & (1 << index) != 0)
+            .map(|index| channels[index])
+    }
+
+    // Bluetooth Core Specification: Vol. 6, Part B, section 1.3.2.2 Non-resolvable Private
+    // Address
+    //
+    // The two most significant bits of the address shall be equal to 0, and the random part of
+    // the address shall have at least one bit set to 0 and one set to 1. The address is sent
+    // least significant byte first.
+    //
+    // Returns false, leaving the address unchanged, if `random` does not give a valid address.
+    fn set_private_address(&mut self, random: [u32; 2], now: u64) -> bool {
+        let low = random[0].to_le_bytes();
+        let high = random[1].to_le_bytes();
+        let address = [low[0], low[1], low[2], low[3], high[0], high[1] & 0x3f];
+        let all_zeros = address.iter().all(|&b| b == 0);
+        let all_ones = address[..5].iter().all(|&b| b == 0xff) && address[5] == 0x3f;
+        if all_zeros || all_ones {
+            return false;
+        }
+        self.address = address;
+        self.address_rotated_at = Some(now);
+        true
+    }
+
+    fn set_resolvable_address(&mut self, prand: u32, ciphertext: &[u8], now: u64) {
+        self.address = resolvable_address(prand, ciphertext);
+        self.address_rotated_at = Some(now);
+    }
+
+    // Whether the address of the process should be replaced by a new private address, for a
+    // rotation interval of `rotation` ticks. Addresses are not changed in the middle of an
+    // advertising event.
+    fn address_rotation_due(&self, now: u64, rotation: u64) -> bool {
+        let idle = match self.process_status {
+            Some(BLEState::Advertising(_)) | Some(BLEState::NotInitialized) | None => false,
+            _ => true,
+        };
+        rotation != 0
+            && idle
+            && self
+                .address_rotated_at
+                .map_or(true, |at| now.saturating_sub(at) >= rotation)
+    }
+
+    // Whether a received advertising packet, header included, passes the scan filter list of the
+    // process: it is empty or the packet matches one of its entries.
+    fn scan_filter_accepts(&self, packet: &[u8]) -> bool {
+        self.scan_filter.map_or(true, |filter| {
+            filter.is_empty()
+                || filter_entries(filter).any(|(kind, value)| filter_matches(kind, value, packet))
+        })
+    }
+
+    // Prepares a new advertising event. Extended advertisements of the event are sent on a
+    // random data channel, and with a new Advertising Data ID: the driver cannot tell when the
+    // process changes its data, so scanners must not drop them as duplicates.
+    fn new_advertising_event(&mut self) {
+        self.secondary_channel =
+            RadioChannel::data_channel(self.random_nonce() % 37).unwrap_or(self.secondary_channel);
+        self.adv_did = (self.adv_did + 1) & 0x0fff;
+    }
+
     fn send_advertisement<'a, B, A>(
         &self,
         ble: &BLE<'a, B, A>,
         channel: RadioChannel,
     ) -> Result<(), ErrorCode>
     where
-        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        B: ble_advertising::BleAdvertisementDriver<'a>
+            + ble_advertising::BleExtendedAdvertisementDriver<'a>
+            + ble_advertising::BleScanResponseDriver<'a>
+            + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        match self.secondary_phy {
+            Some(phy) => self.send_extended_advertisement(ble, channel, phy),
+            None => self.send_legacy_advertisement(ble, channel),
+        }
+    }
+
+    fn send_legacy_advertisement<'a, B, A>(
+        &self,
+        ble: &BLE<'a, B, A>,
+        channel: RadioChannel,
+    ) -> Result<(), ErrorCode>
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a>
+            + ble_advertising::BleExtendedAdvertisementDriver<'a>
+            + ble_advertising::BleScanResponseDriver<'a>
+            + ble_advertising::BleConfig,
         A: kernel::hil::time::Alarm<'a>,
     {
         self.adv_data.map_or(Err(ErrorCode::FAIL), |adv_data| {
@@ -250,7 +629,7 @@ impl App {
                 .take()
                 .map_or(Err(ErrorCode::FAIL), |kernel_tx| {
                     let adv_data_len =
-                        cmp::min(kernel_tx.len() - PACKET_ADDR_LEN - 2, adv_data.len());
+                        cmp::min(PACKET_LENGTH - PACKET_ADDR_LEN - 2, adv_data.len());
                     let adv_data_corrected = &adv_data.as_ref()[..adv_data_len];
                     let payload_len = adv_data_corrected.len() + PACKET_ADDR_LEN;
                     {
@@ -272,9 +651,109 @@ impl App {
                         data[..adv_data_len].copy_from_slice(adv_data_corrected);
                     }
                     let total_len = cmp::min(PACKET_LENGTH, payload_len + 2);
+
+                    let scannable = matches!(self.pdu_type, ADV_IND | ADV_SCAN_IND)
+                        && ble.radio.supports_scan_response();
+                    let rsp_len = if scannable {
+                        self.scan_rsp_data.map_or(0, |scan_rsp_data| {
+                            self.write_scan_response(&mut kernel_tx[total_len..], &scan_rsp_data)
+                        })
+                    } else {
+                        0
+                    };
+                    if rsp_len == 0 {
+                        ble.radio
+                            .transmit_advertisement(kernel_tx, total_len, channel);
+                        return Ok(());
+                    }
                     ble.radio
-                        .transmit_advertisement(kernel_tx, total_len, channel);
-                    Ok(())
+                        .transmit_scannable_advertisement(kernel_tx, total_len, rsp_len, channel)
+                        .map_err(|(e, kernel_tx)| {
+                            ble.kernel_tx.replace(kernel_tx);
+                            e
+                        })
+                })
+        })
+    }
+
+    // Write the SCAN_RSP of the process, with up to 31 bytes of `data`, at the start of `buf`.
+    // Returns its length, or 0 if there is no data to respond with.
+    fn write_scan_response(&self, buf: &mut [u8], data: &[u8]) -> usize {
+        if data.is_empty() {
+            return 0;
+        }
+        let data_len = cmp::min(PACKET_LENGTH - PACKET_ADDR_LEN - 2, data.len());
+        let (header, payload) = buf.split_at_mut(2);
+        // Set TxAdd like the advertisement, whose AdvA it shares
+        header[0] = SCAN_RESP | 1 << ADV_HEADER_TXADD_OFFSET;
+        header[1] = (PACKET_ADDR_LEN + data_len) as u8;
+        let (adva, rsp_data) = payload.split_at_mut(PACKET_ADDR_LEN);
+        adva.copy_from_slice(&self.address);
+        rsp_data[..data_len].copy_from_slice(&data[..data_len]);
+        2 + PACKET_ADDR_LEN + data_len
+    }
+
+    fn send_extended_advertisement<'a, B, A>(
+        &self,
+        ble: &BLE<'a, B, A>,
+        channel: RadioChannel,
+        phy: Phy,
+    ) -> Result<(), ErrorCode>
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a>
+            + ble_advertising::BleExtendedAdvertisementDriver<'a>
+            + ble_advertising::BleScanResponseDriver<'a>
+            + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        self.ext_adv_data.map_or(Err(ErrorCode::FAIL), |adv_data| {
+            ble.kernel_tx
+                .take()
+                .map_or(Err(ErrorCode::FAIL), |kernel_tx| {
+                    let adv_data_len = cmp::min(EXT_ADV_DATA_LENGTH, adv_data.len());
+                    let aux_len = AUX_HEADER_LENGTH + adv_data_len;
+                    // The SID is always 0
+                    let adi = self.adv_did.to_le_bytes();
+                    {
+                        let (primary, aux) = kernel_tx.split_at_mut(EXT_PRIMARY_LENGTH);
+
+                        // The AdvMode of both packets is 0: non-connectable and non-scannable
+                        primary[0] = ADV_EXT_IND;
+                        primary[1] = (EXT_PRIMARY_LENGTH - 2) as u8;
+                        primary[2] = (EXT_PRIMARY_LENGTH - 3) as u8;
+                        primary[3] = EXT_HEADER_ADI | EXT_HEADER_AUX_PTR;
+                        primary[4..6].copy_from_slice(&adi);
+                        // Channel index, clock accuracy of 51 to 500 ppm, 30 µs offset units,
+                        // offset and PHY
+                        let aux_offset = ble.radio.aux_offset_us() / AUX_OFFSET_UNIT_US;
+                        let aux_ptr = self.secondary_channel.get_channel_index()
+                            | (aux_offset & 0x1fff) << 8
+                            | (phy as u32) << 21;
+                        primary[6..9].copy_from_slice(&aux_ptr.to_le_bytes()[..3]);
+
+                        // Set TxAdd because AdvA field is going to be a "random" address
+                        aux[0] = ADV_EXT_IND | 1 << ADV_HEADER_TXADD_OFFSET;
+                        aux[1] = (aux_len - 2) as u8;
+                        aux[2] = (AUX_HEADER_LENGTH - 3) as u8;
+                        aux[3] = EXT_HEADER_ADVA | EXT_HEADER_ADI;
+                        aux[4..4 + PACKET_ADDR_LEN].copy_from_slice(&self.address);
+                        aux[4 + PACKET_ADDR_LEN..AUX_HEADER_LENGTH].copy_from_slice(&adi);
+                        aux[AUX_HEADER_LENGTH..aux_len]
+                            .copy_from_slice(&adv_data.as_ref()[..adv_data_len]);
+                    }
+                    ble.radio
+                        .transmit_extended_advertisement(
+                            kernel_tx,
+                            EXT_PRIMARY_LENGTH,
+                            aux_len,
+                            channel,
+                            self.secondary_channel,
+                            phy,
+                        )
+                        .map_err(|(e, kernel_tx)| {
+                            ble.kernel_tx.replace(kernel_tx);
+                            e
+                        })
                 })
         })
     }
@@ -304,7 +783,10 @@ impl App {
 
 pub struct BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleExtendedAdvertisementDriver<'a>
+        + ble_advertising::BleScanResponseDriver<'a>
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     radio: &'a B,
@@ -314,11 +796,29 @@ where
     alarm: &'a A,
     sending_app: OptionalCell<kernel::ProcessId>,
     receiving_app: OptionalCell<kernel::ProcessId>,
+    entropy: OptionalCell<&'a dyn Entropy32<'a>>,
+    /// Ticks between address rotations, or 0 if addresses are not rotated.
+    address_rotation: Cell<u64>,
+    entropy_requested: Cell<bool>,
+    /// The alarm time `ticks` was last updated at.
+    last_now: Cell<A::Ticks>,
+    /// Ticks since the driver was created, which don't wrap like the alarm time.
+    ticks: Cell<u64>,
+    aes: OptionalCell<&'a dyn AddressCipher<'a>>,
+    identity_resolving_key: OptionalCell<&'a [u8; 16]>,
+    aes_input: TakeCell<'a, [u8]>,
+    aes_output: TakeCell<'a, [u8]>,
+    /// The process and the `prand` of the resolvable private address being computed.
+    resolving: OptionalCell<(kernel::ProcessId, u32)>,
+    limits: OptionalCell<&'a RadioLimits>,
 }
 
 impl<'a, B, A> BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleExtendedAdvertisementDriver<'a>
+        + ble_advertising::BleScanResponseDriver<'a>
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     pub fn new(
@@ -335,9 +835,193 @@ where
             alarm: alarm,
             sending_app: OptionalCell::empty(),
             receiving_app: OptionalCell::empty(),
+            entropy: OptionalCell::empty(),
+            address_rotation: Cell::new(0),
+            entropy_requested: Cell::new(false),
+            last_now: Cell::new(alarm.now()),
+            ticks: Cell::new(0),
+            aes: OptionalCell::empty(),
+            identity_resolving_key: OptionalCell::empty(),
+            aes_input: TakeCell::empty(),
+            aes_output: TakeCell::empty(),
+            resolving: OptionalCell::empty(),
+            limits: OptionalCell::empty(),
+        }
+    }
+
+    /// Give each process a new non-resolvable private address every `interval_ms` milliseconds,
+    /// generated from `entropy`. The driver must be set as the client of `entropy`.
+    pub fn enable_address_rotation(&self, entropy: &'a dyn Entropy32<'a>, interval_ms: u32) {
+        let interval = A::Frequency::frequency() as u64 * interval_ms as u64 / 1000;
+        self.address_rotation.set(cmp::max(interval, 1));
+        self.entropy.set(entropy);
+    }
+
+    /// Rotate to resolvable private addresses of `identity_resolving_key`, hashed with `aes`,
+    /// rather than to non-resolvable ones. The driver must be set as the client of `aes`, and
+    /// `buffer` is usually `AES_BUF`.
+    pub fn enable_resolvable_addresses(
+        &self,
+        aes: &'a dyn AddressCipher<'a>,
+        identity_resolving_key: &'a [u8; 16],
+        buffer: &'a mut [u8; 2 * AES128_BLOCK_SIZE],
+    ) {
+        let (input, output) = buffer.split_at_mut(AES128_BLOCK_SIZE);
+        self.aes_input.replace(input);
+        self.aes_output.replace(output);
+        self.identity_resolving_key.set(identity_resolving_key);
+        self.aes.set(aes);
+    }
+
+    // The ticks since the driver was created. The alarm time wraps, after only 2^24 ticks on
+    // some chips, so this must be called at least once per wrap: the alarm fires at least once
+    // per advertising interval while a process advertises.
+    fn now_ticks(&self) -> u64 {
+        let now = self.alarm.now();
+        let elapsed = now.wrapping_sub(self.last_now.get()).into_u32();
+        self.last_now.set(now);
+        self.ticks.set(self.ticks.get() + elapsed as u64);
+        self.ticks.get()
+    }
+
+    // Starts computing the hash of a resolvable private address for `appid`. Returns false if
+    // the AES engine could not be started.
+    fn start_resolvable_address(
+        &self,
+        aes: &'a dyn AddressCipher<'a>,
+        appid: kernel::ProcessId,
+        prand: u32,
+    ) -> bool {
+        let (input, output) = match (self.aes_input.take(), self.aes_output.take()) {
+            (Some(input), Some(output)) => (input, output),
+            (input, output) => {
+                input.map(|input| self.aes_input.replace(input));
+                output.map(|output| self.aes_output.replace(output));
+                return false;
+            }
+        };
+        // The first block of the CTR keystream is the encryption of the IV, so encrypting
+        // zeros with the plaintext as the IV is the ECB encryption of the plaintext.
+        for byte in input.iter_mut() {
+            *byte = 0;
+        }
+        let configured = self.identity_resolving_key.map_or(false, |key| {
+            aes.enable();
+            aes.set_mode_aes128ctr(true);
+            aes.set_key(&key[..]).is_ok() && aes.set_iv(&ah_plaintext(prand)).is_ok()
+        });
+        if configured {
+            aes.start_message();
+            match aes.crypt(Some(input), output, 0, AES128_BLOCK_SIZE) {
+                None => {
+                    self.resolving.set((appid, prand));
+                    return true;
+                }
+                Some((_, input, output)) => {
+                    input.map(|input| self.aes_input.replace(input));
+                    self.aes_output.replace(output);
+                }
+            }
+        } else {
+            self.aes_input.replace(input);
+            self.aes_output.replace(output);
+        }
+        false
+    }
+
+    /// Refuse TX powers and advertising channels outside `limits`.
+    pub fn set_limits(&self, limits: &'a RadioLimits) {
+        self.limits.set(limits);
+    }
+
+    // The TX power of an advertising event of a process that set `requested`. The limits may
+    // have been lowered since the process set it, so it is capped again at each event.
+    fn event_tx_power(&self, requested: u8) -> Option<u8> {
+        let cap = self
+            .limits
+            .map_or(i8::MAX, |limits| limits.max_tx_power_dbm(Radio::Ble));
+        capped_tx_power(requested, cap, |power| {
+            self.radio.check_tx_power(power).is_ok()
+        })
+    }
+
+    // The advertising channels the limits allow, as a channel map.
+    fn allowed_channel_map(&self) -> u8 {
+        self.limits.map_or(ADV_CHANNEL_MAP_ALL, |limits| {
+            (0..3)
+                .filter(|&bit| limits.check_channel(Radio::Ble, 37 + bit).is_ok())
+                .fold(0, |map, bit| map | 1 << bit)
+        })
+    }
+
+    // Requests entropy for new addresses, if none is requested already.
+    fn request_address_rotation(&self) {
+        if self.entropy_requested.get() {
+            return;
+        }
+        let requested = self.entropy.map_or(false, |entropy| entropy.get().is_ok());
+        self.entropy_requested.set(requested);
+    }
+
+    // Sends the advertisement of `app` on `channel`, as part of its current
+    // advertising event. If it cannot be sent, for example because the process
+    // has not shared any advertising data, the event ends early so the radio
+    // is free for other processes.
+    fn advertise(&self, appid: kernel::ProcessId, app: &mut App, channel: RadioChannel) {
+        app.process_status = Some(BLEState::Advertising(channel));
+        self.sending_app.set(appid);
+        if app.send_advertisement(&self, channel).is_err() {
+            self.sending_app.clear();
+            self.busy.set(false);
+            app.process_status = Some(BLEState::AdvertisingIdle);
+            app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
         }
     }
 
+    // Starts periodic advertising events of the process, of extended advertisements with data
+    // channel packets on `secondary_phy` if it is set.
+    fn start_advertising(
+        &self,
+        appid: kernel::ProcessId,
+        pdu_type: AdvPduType,
+        secondary_phy: Option<Phy>,
+        interval: usize,
+    ) -> CommandReturn {
+        self.app
+            .enter(appid, |app| {
+                if let Some(BLEState::Initialized) = app.process_status {
+                    match (pdu_type, secondary_phy) {
+                        (ADV_IND, None)
+                        | (ADV_NONCONN_IND, None)
+                        | (ADV_SCAN_IND, None)
+                        | (ADV_EXT_IND, Some(_)) => {
+                            app.pdu_type = pdu_type;
+                            app.secondary_phy = secondary_phy;
+                            app.process_status = Some(BLEState::AdvertisingIdle);
+                            app.random_nonce = self.alarm.now().into_u32();
+                            app.advertisement_interval_ms = cmp::max(20, interval as u32);
+                            app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                            Ok(())
+                        }
+                        _ => Err(ErrorCode::INVAL),
+                    }
+                } else {
+                    Err(ErrorCode::BUSY)
+                }
+            })
+            .map_or_else(
+                |err| CommandReturn::failure(err.into()),
+                |res| match res {
+                    Ok(_) => {
+                        // must be called outside closure passed to grant region!
+                        self.reset_active_alarm();
+                        CommandReturn::success()
+                    }
+                    Err(e) => CommandReturn::failure(e.into()),
+                },
+            )
+    }
+
     // Determines which app timer will expire next and sets the underlying alarm
     // to it.
     //
@@ -374,7 +1058,10 @@ where
 // Timer alarm
 impl<'a, B, A> kernel::hil::time::AlarmClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleExtendedAdvertisementDriver<'a>
+        + ble_advertising::BleScanResponseDriver<'a>
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     // When an alarm is fired, we find which apps have expired timers. Expired
@@ -391,8 +1078,14 @@ where
     // recently performed an operation.
     fn alarm(&self) {
         let now = self.alarm.now();
+        let now_ticks = self.now_ticks();
+        let rotation_due = Cell::new(false);
 
         self.app.each(|appid, app| {
+            if app.address_rotation_due(now_ticks, self.address_rotation.get()) {
+                rotation_due.set(true);
+            }
+
             if let Expiration::Enabled(reference, dt) = app.alarm_data.expiration {
                 let exp = A::Ticks::from(reference.wrapping_add(dt));
                 let t0 = A::Ticks::from(reference);
@@ -413,19 +1106,28 @@ where
                     match app.process_status {
                         Some(BLEState::AdvertisingIdle) => {
                             self.busy.set(true);
-                            app.process_status =
-                                Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
-                            self.sending_app.set(appid);
-                            let _ = self.radio.set_tx_power(app.tx_power);
-                            let _ =
-                                app.send_advertisement(&self, RadioChannel::AdvertisingChannel37);
+                            app.new_advertising_event();
+                            // The whole event uses the TX power of the process, within the
+                            // limits
+                            let tx_power = self.event_tx_power(app.tx_power);
+                            let channel =
+                                app.next_advertising_channel(None, self.allowed_channel_map());
+                            match (tx_power, channel) {
+                                (Some(tx_power), Some(channel)) => {
+                                    let _ = self.radio.set_tx_power(tx_power);
+                                    self.advertise(appid, app, channel);
+                                }
+                                _ => {
+                                    self.busy.set(false);
+                                    app.set_next_alarm::<A::Frequency>(now.into_u32());
+                                }
+                            }
                         }
                         Some(BLEState::ScanningIdle) => {
                             self.busy.set(true);
                             app.process_status =
                                 Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
                             self.receiving_app.set(appid);
-                            let _ = self.radio.set_tx_power(app.tx_power);
                             self.radio
                                 .receive_advertisement(RadioChannel::AdvertisingChannel37);
                         }
@@ -434,19 +1136,126 @@ where
                 }
             }
         });
+        if rotation_due.get() {
+            self.request_address_rotation();
+        }
         self.reset_active_alarm();
     }
 }
 
+// Callback from the entropy source with randomness for new addresses
+impl<'a, B, A> entropy::Client32 for BLE<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleExtendedAdvertisementDriver<'a>
+        + ble_advertising::BleScanResponseDriver<'a>
+        + ble_advertising::BleConfig,
+    A: kernel::hil::time::Alarm<'a>,
+{
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> entropy::Continue {
+        if error.is_err() {
+            self.entropy_requested.set(false);
+            return entropy::Continue::Done;
+        }
+
+        let now = self.now_ticks();
+        let rotation = self.address_rotation.get();
+        let mut exhausted = false;
+
+        if let Some(aes) = self.aes.extract() {
+            // One address is hashed at a time, the next is requested once it is done
+            if self.resolving.is_some() {
+                self.entropy_requested.set(false);
+                return entropy::Continue::Done;
+            }
+            let due = self.app.iter().find_map(|cntr| {
+                let appid = cntr.processid();
+                cntr.enter(|app| app.address_rotation_due(now, rotation))
+                    .then(|| appid)
+            });
+            if let Some(appid) = due {
+                match entropy.next() {
+                    Some(random) => match resolvable_prand(random) {
+                        Some(prand) => {
+                            self.start_resolvable_address(aes, appid, prand);
+                        }
+                        None => exhausted = true,
+                    },
+                    None => exhausted = true,
+                }
+            }
+        } else {
+            for cntr in self.app.iter() {
+                cntr.enter(|app| {
+                    while !exhausted && app.address_rotation_due(now, rotation) {
+                        match (entropy.next(), entropy.next()) {
+                            (Some(low), Some(high)) => {
+                                app.set_private_address([low, high], now);
+                            }
+                            _ => exhausted = true,
+                        }
+                    }
+                });
+            }
+        }
+
+        if exhausted {
+            entropy::Continue::More
+        } else {
+            self.entropy_requested.set(false);
+            entropy::Continue::Done
+        }
+    }
+}
+
+// Callback from the AES engine with the hash of a resolvable private address
+impl<'a, B, A> symmetric_encryption::Client<'a> for BLE<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleExtendedAdvertisementDriver<'a>
+        + ble_advertising::BleScanResponseDriver<'a>
+        + ble_advertising::BleConfig,
+    A: kernel::hil::time::Alarm<'a>,
+{
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        if let Some((appid, prand)) = self.resolving.take() {
+            let now = self.now_ticks();
+            let _ = self.app.enter(appid, |app| {
+                app.set_resolvable_address(prand, dest, now);
+            });
+        }
+        source.map(|source| self.aes_input.replace(source));
+        self.aes_output.replace(dest);
+
+        // Other processes may be waiting for an address
+        let now = self.now_ticks();
+        let rotation = self.address_rotation.get();
+        if self
+            .app
+            .iter()
+            .any(|cntr| cntr.enter(|app| app.address_rotation_due(now, rotation)))
+        {
+            self.request_address_rotation();
+        }
+    }
+}
+
 // Callback from the radio once a RX event occur
 impl<'a, B, A> ble_advertising::RxClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleExtendedAdvertisementDriver<'a>
+        + ble_advertising::BleScanResponseDriver<'a>
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     fn receive_event(&self, buf: &'static mut [u8], len: u8, result: Result<(), ErrorCode>) {
-        self.receiving_app.map(|appid| {
-            let _ = self.app.enter(*appid, |app| {
+        self.receiving_app.take().map(|appid| {
+            let res = self.app.enter(appid, |app| {
                 // Validate the received data, because ordinary BLE packets can be bigger than 39
                 // bytes. Thus, we need to check for that!
                 // Moreover, we use the packet header to find size but the radio reads maximum
@@ -456,18 +1265,30 @@ where
                 // Packets that are bigger than 39 bytes are likely `Channel PDUs` which should
                 // only be sent on the other 37 RadioChannel channels.
 
-                if len <= PACKET_LENGTH as u8 && result == Ok(()) {
-                    // write to buffer in userland
-                    let success = app.scan_buffer.mut_map_or(false, |userland| {
-                        userland[0..len as usize].copy_from_slice(&buf[0..len as usize]);
-                        true
+                // Packets with a bad CRC are dropped, as are those too short to hold the
+                // header and the advertiser's address, and those the scan filter list of the
+                // process rejects.
+                let len = len as usize;
+                if len <= PACKET_LENGTH
+                    && len >= 2 + PACKET_ADDR_LEN
+                    && result == Ok(())
+                    && app.scan_filter_accepts(&buf[..len])
+                {
+                    // write to buffer in userland, truncating to its size
+                    let copied = app.scan_buffer.mut_map_or(None, |userland| {
+                        let copied = cmp::min(len, userland.len());
+                        userland[..copied].copy_from_slice(&buf[..copied]);
+                        Some(copied)
                     });
 
-                    if success {
+                    if let Some(copied) = copied {
+                        let rssi = self.radio.last_rssi().unwrap_or(0) as u8;
+                        let address_type = (buf[0] >> ADV_HEADER_TXADD_OFFSET) & 1;
+                        let data_len = len - 2 - PACKET_ADDR_LEN;
                         app.scan_callback.schedule(
                             kernel::into_statuscode(result),
-                            len as usize,
-                            0,
+                            copied,
+                            rssi as usize | (address_type as usize) << 8 | data_len << 16,
                         );
                     }
                 }
@@ -476,15 +1297,14 @@ where
                     Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
                         app.process_status =
                             Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38));
-                        self.receiving_app.set(*appid);
-                        let _ = self.radio.set_tx_power(app.tx_power);
+                        self.receiving_app.set(appid);
                         self.radio
                             .receive_advertisement(RadioChannel::AdvertisingChannel38);
                     }
                     Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38)) => {
                         app.process_status =
                             Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39));
-                        self.receiving_app.set(*appid);
+                        self.receiving_app.set(appid);
                         self.radio
                             .receive_advertisement(RadioChannel::AdvertisingChannel39);
                     }
@@ -494,9 +1314,13 @@ where
                         app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
                     }
                     // Invalid state => don't care
-                    _ => (),
+                    _ => self.busy.set(false),
                 }
             });
+            if res.is_err() {
+                // The process is gone, so its scanning event is over
+                self.busy.set(false);
+            }
             self.reset_active_alarm();
         });
     }
@@ -505,40 +1329,39 @@ where
 // Callback from the radio once a TX event occur
 impl<'a, B, A> ble_advertising::TxClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleExtendedAdvertisementDriver<'a>
+        + ble_advertising::BleScanResponseDriver<'a>
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     // The Result<(), ErrorCode> indicates valid CRC or not, not used yet but could be used for
     // re-transmissions for invalid CRCs
     fn transmit_event(&self, buf: &'static mut [u8], _crc_ok: Result<(), ErrorCode>) {
         self.kernel_tx.replace(buf);
-        self.sending_app.map(|appid| {
-            let _ = self.app.enter(*appid, |app| {
+        self.sending_app.take().map(|appid| {
+            let res = self.app.enter(appid, |app| {
                 match app.process_status {
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
-                        self.sending_app.set(*appid);
-                        let _ = self.radio.set_tx_power(app.tx_power);
-                        let _ = app.send_advertisement(&self, RadioChannel::AdvertisingChannel38);
-                    }
-
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
-                        self.sending_app.set(*appid);
-                        let _ = app.send_advertisement(&self, RadioChannel::AdvertisingChannel39);
-                    }
-
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::AdvertisingIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                    Some(BLEState::Advertising(channel)) => {
+                        match app
+                            .next_advertising_channel(Some(channel), self.allowed_channel_map())
+                        {
+                            Some(next) => self.advertise(appid, app, next),
+                            None => {
+                                self.busy.set(false);
+                                app.process_status = Some(BLEState::AdvertisingIdle);
+                                app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                            }
+                        }
                     }
                     // Invalid state => don't care
-                    _ => (),
+                    _ => self.busy.set(false),
                 }
             });
+            if res.is_err() {
+                // The process is gone, so its advertising event is over
+                self.busy.set(false);
+            }
             self.reset_active_alarm();
         });
     }
@@ -547,7 +1370,10 @@ where
 // System Call implementation
 impl<'a, B, A> kernel::Driver for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleExtendedAdvertisementDriver<'a>
+        + ble_advertising::BleScanResponseDriver<'a>
+        + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     fn command(
@@ -559,38 +1385,7 @@ where
     ) -> CommandReturn {
         match command_num {
             // Start periodic advertisements
-            0 => {
-                self.app
-                    .enter(appid, |app| {
-                        if let Some(BLEState::Initialized) = app.process_status {
-                            let pdu_type = data as AdvPduType;
-                            match pdu_type {
-                                ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
-                                    app.pdu_type = pdu_type;
-                                    app.process_status = Some(BLEState::AdvertisingIdle);
-                                    app.random_nonce = self.alarm.now().into_u32();
-                                    app.advertisement_interval_ms = cmp::max(20, interval as u32);
-                                    app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
-                                    Ok(())
-                                }
-                                _ => Err(ErrorCode::INVAL),
-                            }
-                        } else {
-                            Err(ErrorCode::BUSY)
-                        }
-                    })
-                    .map_or_else(
-                        |err| CommandReturn::failure(err.into()),
-                        |res| match res {
-                            Ok(_) => {
-                                // must be called outside closure passed to grant region!
-                                self.reset_active_alarm();
-                                CommandReturn::success()
-                            }
-                            Err(e) => CommandReturn::failure(e.into()),
-                        },
-                    )
-            }
+            0 => self.start_advertising(appid, data as AdvPduType, None, interval),
 
             // Stop periodic advertisements or passive scanning
             1 => self
@@ -611,29 +1406,29 @@ where
             // Maximum Output Power:    10 mW (+10 dBm)
             //
             // data - Transmitting power in dBm
-            2 => {
-                self.app
-                    .enter(appid, |app| {
-                        if app.process_status != Some(BLEState::ScanningIdle)
-                            && app.process_status != Some(BLEState::AdvertisingIdle)
-                        {
-                            match data as u8 {
-                                tx_power @ 0..=10 | tx_power @ 0xec..=0xff => {
-                                    // query the underlying chip if the power level is supported
-                                    let status = self.radio.set_tx_power(tx_power);
-                                    if let Ok(()) = status {
-                                        app.tx_power = tx_power;
-                                    }
-                                    status.into()
-                                }
-                                _ => CommandReturn::failure(ErrorCode::INVAL),
-                            }
-                        } else {
-                            CommandReturn::failure(ErrorCode::BUSY)
-                        }
-                    })
-                    .unwrap_or_else(|err| err.into())
-            }
+            //
+            // The power is only checked here. It is applied at the start of each
+            // advertising event of the process, so it does not affect the events
+            // of other processes.
+            2 => match data as u8 {
+                tx_power @ 0..=10 | tx_power @ 0xec..=0xff => {
+                    // query the underlying chip if the power level is supported
+                    let limited = self.limits.map_or(Ok(()), |limits| {
+                        limits.check_tx_power(Radio::Ble, tx_power as i8)
+                    });
+                    match limited.and_then(|()| self.radio.check_tx_power(tx_power)) {
+                        Ok(()) => self
+                            .app
+                            .enter(appid, |app| {
+                                app.tx_power = tx_power;
+                                CommandReturn::success()
+                            })
+                            .unwrap_or_else(|err| err.into()),
+                        Err(e) => CommandReturn::failure(e),
+                    }
+                }
+                _ => CommandReturn::failure(ErrorCode::INVAL),
+            },
 
             // Passive scanning mode
             5 => {
@@ -660,6 +1455,42 @@ where
                     )
             }
 
+            // Start periodic extended advertisements
+            6 => match Phy::from_index(data) {
+                Some(phy) if self.radio.supports_secondary_phy(phy) => {
+                    self.start_advertising(appid, ADV_EXT_IND, Some(phy), interval)
+                }
+                Some(_) => CommandReturn::failure(ErrorCode::NOSUPPORT),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
+            // Restrict advertising to a subset of the primary advertising channels
+            //
+            // data - Channel map, bit 0 for channel 37, bit 1 for 38 and bit 2 for 39
+            //
+            // Like the TX power, the map applies from the next advertising event of the process.
+            7 => match data {
+                1..=0b111 => {
+                    let channel_map = data as u8;
+                    let limited = if channel_map & !self.allowed_channel_map() != 0 {
+                        Err(ErrorCode::INVAL)
+                    } else {
+                        Ok(())
+                    };
+                    match limited.and_then(|()| self.radio.check_channel_map(channel_map)) {
+                        Ok(()) => self
+                            .app
+                            .enter(appid, |app| {
+                                app.channel_map = channel_map;
+                                CommandReturn::success()
+                            })
+                            .unwrap_or_else(|err| err.into()),
+                        Err(e) => CommandReturn::failure(e),
+                    }
+                }
+                _ => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
         .into()
@@ -683,6 +1514,35 @@ where
                 })
                 .unwrap_or_else(|err| Err(err.into())),
 
+            // Extended advertisement buffer
+            1 => self
+                .app
+                .enter(appid, |app| {
+                    app.generate_random_address(appid).map(|_| {
+                        app.process_status = Some(BLEState::Initialized);
+                        mem::swap(&mut app.ext_adv_data, &mut slice);
+                    })
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+
+            // Scan filter list
+            2 => self
+                .app
+                .enter(appid, |app| {
+                    mem::swap(&mut app.scan_filter, &mut slice);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+
+            // Scan response buffer
+            3 => self
+                .app
+                .enter(appid, |app| {
+                    mem::swap(&mut app.scan_rsp_data, &mut slice);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+
             // Operation not supported
             _ => Err(ErrorCode::NOSUPPORT),
         };
@@ -745,3 +1605,61 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Bluetooth Core Specification: Vol. 3, Part H, appendix D.7, sample data of `ah`
+    #[test]
+    fn test_resolvable_address() {
+        let prand = resolvable_prand(0x708194).unwrap();
+        assert_eq!(prand, 0x708194);
+        assert_eq!(
+            ah_plaintext(prand),
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x70, 0x81, 0x94]
+        );
+        let ciphertext = [
+            0x15, 0x9d, 0x5f, 0xb7, 0x2e, 0xbe, 0x23, 0x11, 0xa4, 0x8c, 0x1b, 0xdc, 0xc4, 0x0d,
+            0xfb, 0xaa,
+        ];
+        // Sent least significant byte first: the hash 0x0dfbaa, then prand
+        assert_eq!(
+            resolvable_address(prand, &ciphertext),
+            [0xaa, 0xfb, 0x0d, 0x94, 0x81, 0x70]
+        );
+    }
+
+    #[test]
+    fn test_resolvable_prand() {
+        assert_eq!(resolvable_prand(0xffff_ffff), None);
+        assert_eq!(resolvable_prand(0xffc0_0000), None);
+        assert_eq!(resolvable_prand(0xffc0_0001), Some(0x40_0001));
+        assert_eq!(resolvable_prand(0x003f_fffe), Some(0x7f_fffe));
+    }
+
+    #[test]
+    fn test_capped_tx_power() {
+        // The TX powers of the nRF52 radio
+        let supported = |power: u8| [4, 0, 0xfc, 0xf8, 0xf4, 0xf0, 0xec, 0xd8].contains(&power);
+        assert_eq!(capped_tx_power(4, i8::MAX, supported), Some(4));
+        assert_eq!(capped_tx_power(4, 0, supported), Some(0));
+        assert_eq!(capped_tx_power(4, -2, supported), Some(0xfc));
+        assert_eq!(capped_tx_power(0xf8, 0, supported), Some(0xf8));
+        assert_eq!(capped_tx_power(0, -50, supported), None);
+    }
+
+    #[test]
+    fn test_address_rotation_due() {
+        let mut app = App::default();
+        app.process_status = Some(BLEState::AdvertisingIdle);
+        assert!(!app.address_rotation_due(0, 0));
+        assert!(app.address_rotation_due(0, 100));
+        // Past the 2^24 ticks after which a 24-bit alarm time wraps
+        app.address_rotated_at = Some(0x00ff_fff0);
+        assert!(!app.address_rotation_due(0x0100_0010, 100));
+        assert!(app.address_rotation_due(0x0100_0060, 100));
+        app.process_status = Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
+        assert!(!app.address_rotation_due(0x0100_0060, 100));
+    }
+}
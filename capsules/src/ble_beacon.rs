@@ -0,0 +1,271 @@
+//! Bluetooth Low Energy beacon advertised by the kernel
+//!
+//! Advertises fixed advertising data as non-connectable advertisements (`ADV_NONCONN_IND`) on
+//! the three primary advertising channels, without any process. This suits identification or
+//! recovery beacons, which must keep going whatever the processes on the board do.
+//! `ibeacon_data()` and `eddystone_uid_data()` build the advertising data of the two common
+//! beacon formats.
+//!
+//! The beacon becomes the transmit client of the radio it is given. To keep the BLE advertising
+//! driver as well, give each of them a `VirtualBleRadio` of the same `virtual_ble::MuxBleRadio`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let beacon_radio = static_init!(
+//!     VirtualBleRadio<'static, nrf52840::ble_radio::Radio>,
+//!     VirtualBleRadio::new(ble_mux)
+//! );
+//! beacon_radio.setup();
+//! let beacon_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let beacon = static_init!(
+//!     capsules::ble_beacon::BleBeacon<
+//!         'static,
+//!         VirtualBleRadio<'static, nrf52840::ble_radio::Radio>,
+//!         VirtualMuxAlarm<'static, Rtc>,
+//!     >,
+//!     capsules::ble_beacon::BleBeacon::new(
+//!         beacon_radio,
+//!         beacon_alarm,
+//!         &mut capsules::ble_beacon::BUF,
+//!         [0x5a, 0x2f, 0x01, 0x93, 0x7c, 0xc0],
+//!         1000,
+//!     )
+//! );
+//! beacon_alarm.set_alarm_client(beacon);
+//! hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(beacon_radio, beacon);
+//! let data = capsules::ble_beacon::eddystone_uid_data(NAMESPACE, INSTANCE, 0xee);
+//! beacon.start(&data);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::TakeCell;
+use kernel::hil::ble_advertising::{self, RadioChannel};
+use kernel::hil::time::{self, Ticks};
+use kernel::ErrorCode;
+
+pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+
+const PACKET_ADDR_LEN: usize = 6;
+const PACKET_LENGTH: usize = 39;
+const ADV_DATA_LENGTH: usize = PACKET_LENGTH - 2 - PACKET_ADDR_LEN;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.3, with TxAdd set for a
+// random address
+const ADV_NONCONN_IND: u8 = 0b0010;
+const ADV_HEADER_TXADD: u8 = 1 << 6;
+
+// Core Specification Supplement, Part A, section 1.3: LE General Discoverable Mode, BR/EDR Not
+// Supported
+const AD_FLAGS: [u8; 3] = [0x02, 0x01, 0x06];
+
+/// Advertising data of an iBeacon: the flags and Apple's manufacturer specific data with
+/// `uuid`, `major`, `minor` and the signal strength at 1 m, `measured_power`, in dBm as a two's
+/// complement byte.
+pub fn ibeacon_data(uuid: [u8; 16], major: u16, minor: u16, measured_power: u8) -> [u8; 30] {
+    let mut data = [0; 30];
+    data[..3].copy_from_slice(&AD_FLAGS);
+    // Manufacturer specific data of Apple (0x004c), beacon type 0x02 of 0x15 bytes
+    data[3..9].copy_from_slice(&[0x1a, 0xff, 0x4c, 0x00, 0x02, 0x15]);
+    data[9..25].copy_from_slice(&uuid);
+    data[25..27].copy_from_slice(&major.to_be_bytes());
+    data[27..29].copy_from_slice(&minor.to_be_bytes());
+    data[29] = measured_power;
+    data
+}
+
+/// Advertising data of an Eddystone-UID frame with `namespace` and `instance`, and the signal
+/// strength at 0 m, `tx_power`, in dBm as a two's complement byte.
+pub fn eddystone_uid_data(namespace: [u8; 10], instance: [u8; 6], tx_power: u8) -> [u8; 31] {
+    let mut data = [0; 31];
+    data[..3].copy_from_slice(&AD_FLAGS);
+    // Complete list of 16-bit service UUIDs with Eddystone's (0xfeaa), then its service data
+    // with frame type 0x00 (UID)
+    data[3..7].copy_from_slice(&[0x03, 0x03, 0xaa, 0xfe]);
+    data[7..12].copy_from_slice(&[0x17, 0x16, 0xaa, 0xfe, 0x00]);
+    data[12] = tx_power;
+    data[13..23].copy_from_slice(&namespace);
+    data[23..29].copy_from_slice(&instance);
+    // The last two bytes are reserved
+    data
+}
+
+pub struct BleBeacon<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver<'a>,
+    A: time::Alarm<'a>,
+{
+    radio: &'a B,
+    alarm: &'a A,
+    buffer: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    interval_ms: u32,
+    /// The channel of the advertisement being sent, during an advertising event.
+    channel: Cell<Option<RadioChannel>>,
+    running: Cell<bool>,
+    /// State of the pseudo-random `advDelay`.
+    random_nonce: Cell<u32>,
+}
+
+impl<'a, B, A> BleBeacon<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver<'a>,
+    A: time::Alarm<'a>,
+{
+    /// Advertise from the static random `address`, given in the order of the packet, every
+    /// `interval_ms` milliseconds (at least 100 for non-connectable advertisements).
+    pub fn new(
+        radio: &'a B,
+        alarm: &'a A,
+        buffer: &'static mut [u8],
+        address: [u8; PACKET_ADDR_LEN],
+        interval_ms: u32,
+    ) -> BleBeacon<'a, B, A> {
+        buffer[2..2 + PACKET_ADDR_LEN].copy_from_slice(&address);
+        BleBeacon {
+            radio: radio,
+            alarm: alarm,
+            buffer: TakeCell::new(buffer),
+            len: Cell::new(0),
+            interval_ms: cmp::max(interval_ms, 100),
+            channel: Cell::new(None),
+            running: Cell::new(false),
+            // Just use any non-zero starting value
+            random_nonce: Cell::new(0xdeadbeef),
+        }
+    }
+
+    /// Start advertising `data`, of up to 31 bytes. Returns `BUSY` while an advertising event
+    /// is under way.
+    pub fn start(&self, data: &[u8]) -> Result<(), ErrorCode> {
+        if data.len() > ADV_DATA_LENGTH {
+            return Err(ErrorCode::SIZE);
+        }
+        self.buffer.map_or(Err(ErrorCode::BUSY), |buffer| {
+            buffer[0] = ADV_NONCONN_IND | ADV_HEADER_TXADD;
+            buffer[1] = (PACKET_ADDR_LEN + data.len()) as u8;
+            buffer[2 + PACKET_ADDR_LEN..2 + PACKET_ADDR_LEN + data.len()].copy_from_slice(data);
+            self.len.set(2 + PACKET_ADDR_LEN + data.len());
+            Ok(())
+        })?;
+        self.random_nonce
+            .set(self.random_nonce.get() ^ self.alarm.now().into_u32());
+        if !self.running.replace(true) {
+            self.set_next_alarm();
+        }
+        Ok(())
+    }
+
+    /// Stop advertising after the current advertising event, if any.
+    pub fn stop(&self) {
+        self.running.set(false);
+        let _ = self.alarm.disarm();
+    }
+
+    // Schedule the next advertising event after the interval and a pseudo-random `advDelay` of
+    // 0 to 10 ms.
+    //
+    // Uses the [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm, like the BLE
+    // advertising driver.
+    fn set_next_alarm(&self) {
+        let mut nonce = self.random_nonce.get();
+        nonce ^= nonce << 13;
+        nonce ^= nonce >> 17;
+        nonce ^= nonce << 5;
+        self.random_nonce.set(nonce);
+
+        let delay_ms = self.interval_ms.saturating_add(nonce % 11);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(delay_ms));
+    }
+
+    fn advertise(&self, channel: RadioChannel) {
+        self.buffer.take().map(|buffer| {
+            self.channel.set(Some(channel));
+            self.radio
+                .transmit_advertisement(buffer, self.len.get(), channel);
+        });
+    }
+}
+
+impl<'a, B, A> time::AlarmClient for BleBeacon<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver<'a>,
+    A: time::Alarm<'a>,
+{
+    fn alarm(&self) {
+        if self.running.get() {
+            self.advertise(RadioChannel::AdvertisingChannel37);
+        }
+    }
+}
+
+impl<'a, B, A> ble_advertising::TxClient for BleBeacon<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver<'a>,
+    A: time::Alarm<'a>,
+{
+    fn transmit_event(&self, buf: &'static mut [u8], _result: Result<(), ErrorCode>) {
+        self.buffer.replace(buf);
+        match self.channel.take() {
+            Some(RadioChannel::AdvertisingChannel37) => {
+                self.advertise(RadioChannel::AdvertisingChannel38)
+            }
+            Some(RadioChannel::AdvertisingChannel38) => {
+                self.advertise(RadioChannel::AdvertisingChannel39)
+            }
+            _ => {
+                if self.running.get() {
+                    self.set_next_alarm();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ibeacon_data() {
+        let uuid = [
+            0xe2, 0xc5, 0x6d, 0xb5, 0xdf, 0xfb, 0x48, 0xd2, 0xb0, 0x60, 0xd0, 0xf5, 0xa7, 0x10,
+            0x96, 0xe0,
+        ];
+        let data = ibeacon_data(uuid, 0x0102, 0x0304, 0xc5);
+        assert_eq!(
+            data[..9],
+            [0x02, 0x01, 0x06, 0x1a, 0xff, 0x4c, 0x00, 0x02, 0x15]
+        );
+        assert_eq!(data[9..25], uuid);
+        assert_eq!(data[25..], [0x01, 0x02, 0x03, 0x04, 0xc5]);
+        // The manufacturer specific data fills the rest of the payload
+        assert_eq!(data[3] as usize, data.len() - 4);
+        assert!(data.len() <= ADV_DATA_LENGTH);
+    }
+
+    #[test]
+    fn test_eddystone_uid_data() {
+        let namespace = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let instance = [0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5];
+        let data = eddystone_uid_data(namespace, instance, 0xee);
+        assert_eq!(
+            data[..13],
+            [0x02, 0x01, 0x06, 0x03, 0x03, 0xaa, 0xfe, 0x17, 0x16, 0xaa, 0xfe, 0x00, 0xee]
+        );
+        assert_eq!(data[13..23], namespace);
+        assert_eq!(data[23..29], instance);
+        assert_eq!(data[29..], [0, 0]);
+        // The service data fills the rest of the payload
+        assert_eq!(data[7] as usize, data.len() - 8);
+        assert_eq!(data.len(), ADV_DATA_LENGTH);
+    }
+}
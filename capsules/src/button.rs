@@ -48,8 +48,9 @@
 //! - `0`: Set callback for pin interrupts. Note setting this callback has
 //!   no reliance on individual pins being configured as interrupts. The
 //!   interrupt will be called with two parameters: the index of the button
-//!   that triggered the interrupt and the pressed (1) or not pressed (0) state
-//!   of the button.
+//!   that triggered the interrupt, the pressed (1) or not pressed (0) state
+//!   of the button and, if the board has set an event clock, the tick count
+//!   when the button changed state (otherwise 0).
 
 use core::cell::Cell;
 use kernel::hil::gpio;
@@ -238,7 +239,9 @@ impl<'a, P: gpio::InterruptPin<'a>> gpio::ClientWithValue for Button<'a, P> {
         self.apps.each(|_, cntr| {
             if cntr.1 & (1 << pin_num) != 0 {
                 interrupt_count.set(interrupt_count.get() + 1);
-                cntr.0.schedule(pin_num as usize, button_state as usize, 0);
+                let timestamp = cntr.0.event_timestamp().unwrap_or(0);
+                cntr.0
+                    .schedule(pin_num as usize, button_state as usize, timestamp as usize);
             }
         });
 
@@ -0,0 +1,389 @@
+//! Provides userspace with touch events from capacitive sense pads.
+//!
+//! The capsule scans every channel of a CapSense controller periodically.
+//! For each channel it keeps a baseline, which slowly follows the
+//! measurements while the pad is not touched to compensate for drift from
+//! temperature and humidity. A pad is touched when a measurement exceeds the
+//! baseline by more than the channel's threshold, and released again when it
+//! falls below half that.
+//!
+//! Channels are only scanned while at least one process has enabled touch
+//! events. Each time scanning starts, the first `CALIBRATION_SAMPLES`
+//! measurements of each channel calibrate it: their average is the
+//! baseline, and how far they spread is the noise of the channel. Pads must
+//! not be touched while they calibrate.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let capsense_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let capsense = static_init!(
+//!     capsules::capsense::CapSense<'static, VirtualMuxAlarm<'static, apollo3::stimer::STimer>>,
+//!     capsules::capsense::CapSense::new(
+//!         &peripherals.capsense,
+//!         capsense_alarm,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! kernel::hil::capsense::CapSense::set_client(&peripherals.capsense, capsense);
+//! capsense_alarm.set_alarm_client(capsense);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: touch events. The callback gets the channel, `1` if the pad was
+//!   touched or `0` if it was released, and the measurement.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the number of channels.
+//! - `1`: Enable touch events for this process.
+//! - `2`: Disable touch events for this process.
+//! - `3`: Set the threshold of channel `arg1` to `arg2`, in raw counts. `0`
+//!   selects the default of a tenth of the baseline, or four times the
+//!   noise if that is more.
+//! - `4`: Get the last measurement and the baseline of channel `arg1`.
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+
+use kernel::hil::capsense::{self, CapSenseClient};
+use kernel::hil::time::{self, Alarm};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::CapSense as usize;
+
+/// Most channels the capsule keeps state for.
+pub const MAX_CHANNELS: usize = 8;
+
+/// Time between scans of all channels.
+const SCAN_INTERVAL_MS: u32 = 20;
+
+/// The default threshold is the baseline divided by this.
+const DEFAULT_THRESHOLD_DIVISOR: u32 = 10;
+
+/// The default threshold is at least the noise of a channel times this.
+const NOISE_MARGIN: u32 = 4;
+
+/// Weight of a new measurement in the baseline, as a power of two.
+const BASELINE_SHIFT: u32 = 4;
+
+/// Measurements of each channel that calibrate it.
+const CALIBRATION_SAMPLES: u32 = 8;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    enabled: bool,
+}
+
+/// Touch detection for one channel.
+#[derive(Default)]
+struct Channel {
+    baseline: Cell<u32>,
+    /// Threshold set by a process, or `0` for the default.
+    threshold: Cell<u32>,
+    last: Cell<u32>,
+    touched: Cell<bool>,
+    /// Calibration measurements taken so far, and their sum, minimum and
+    /// maximum.
+    samples: Cell<u32>,
+    sum: Cell<u32>,
+    min: Cell<u32>,
+    max: Cell<u32>,
+}
+
+impl Channel {
+    fn calibrate(&self) {
+        self.samples.set(0);
+        self.sum.set(0);
+        self.min.set(u32::MAX);
+        self.max.set(0);
+        self.touched.set(false);
+    }
+
+    fn calibrated(&self) -> bool {
+        self.samples.get() >= CALIBRATION_SAMPLES
+    }
+
+    fn threshold(&self) -> u32 {
+        match self.threshold.get() {
+            0 => cmp::max(
+                self.baseline.get() / DEFAULT_THRESHOLD_DIVISOR,
+                (self.max.get() - self.min.get()).saturating_mul(NOISE_MARGIN),
+            ),
+            threshold => threshold,
+        }
+    }
+
+    /// Add a measurement, and return whether the pad was touched or released
+    /// by it.
+    fn update(&self, value: u32) -> Option<bool> {
+        self.last.set(value);
+        if !self.calibrated() {
+            self.samples.set(self.samples.get() + 1);
+            self.sum.set(self.sum.get().saturating_add(value));
+            self.min.set(cmp::min(self.min.get(), value));
+            self.max.set(cmp::max(self.max.get(), value));
+            if self.calibrated() {
+                self.baseline.set(self.sum.get() / CALIBRATION_SAMPLES);
+            }
+            return None;
+        }
+
+        let baseline = self.baseline.get();
+        let threshold = self.threshold();
+        let touched = self.touched.get();
+        let event = if !touched && value > baseline.saturating_add(threshold) {
+            self.touched.set(true);
+            Some(true)
+        } else if touched && value < baseline.saturating_add(threshold / 2) {
+            self.touched.set(false);
+            Some(false)
+        } else {
+            None
+        };
+
+        if !self.touched.get() {
+            let baseline = baseline - (baseline >> BASELINE_SHIFT) + (value >> BASELINE_SHIFT);
+            self.baseline.set(baseline);
+        }
+        event
+    }
+}
+
+pub struct CapSense<'a, A: Alarm<'a>> {
+    capsense: &'a dyn capsense::CapSense<'a>,
+    alarm: &'a A,
+    channels: [Channel; MAX_CHANNELS],
+    scanning: Cell<bool>,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> CapSense<'a, A> {
+    pub fn new(
+        capsense: &'a dyn capsense::CapSense<'a>,
+        alarm: &'a A,
+        grant: Grant<App>,
+    ) -> CapSense<'a, A> {
+        CapSense {
+            capsense: capsense,
+            alarm: alarm,
+            channels: Default::default(),
+            scanning: Cell::new(false),
+            apps: grant,
+        }
+    }
+
+    fn channels(&self) -> usize {
+        cmp::min(self.capsense.channels(), MAX_CHANNELS)
+    }
+
+    fn start_scanning(&self) {
+        if !self.scanning.get() && self.channels() > 0 {
+            self.scanning.set(true);
+            self.channels.iter().for_each(Channel::calibrate);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(SCAN_INTERVAL_MS));
+        }
+    }
+
+    /// Stop scanning once no process wants touch events anymore.
+    fn update_scanning(&self) {
+        let enabled = Cell::new(false);
+        self.apps.each(|_, app| {
+            if app.enabled {
+                enabled.set(true);
+            }
+        });
+        if enabled.get() {
+            self.start_scanning();
+        } else if self.scanning.get() {
+            self.scanning.set(false);
+            let _ = self.alarm.disarm();
+        }
+    }
+
+    /// Measure `channel`, or wait for the next scan after the last one.
+    fn measure_from(&self, channel: usize) {
+        if !self.scanning.get() {
+            return;
+        }
+        for channel in channel..self.channels() {
+            if self.capsense.measure(channel).is_ok() {
+                return;
+            }
+        }
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(SCAN_INTERVAL_MS));
+    }
+
+    fn notify(&self, channel: usize, touched: bool, value: u32) {
+        self.apps.each(|_, app| {
+            if app.enabled {
+                app.callback
+                    .schedule(channel, touched as usize, value as usize);
+            }
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for CapSense<'a, A> {
+    fn alarm(&self) {
+        self.measure_from(0);
+    }
+}
+
+impl<'a, A: Alarm<'a>> CapSenseClient for CapSense<'a, A> {
+    fn measurement_done(&self, channel: usize, result: Result<u32, ErrorCode>) {
+        if channel >= MAX_CHANNELS {
+            return;
+        }
+        if let Ok(value) = result {
+            if let Some(touched) = self.channels[channel].update(value) {
+                self.notify(channel, touched, value);
+            }
+        }
+        self.measure_from(channel + 1);
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for CapSense<'a, A> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Touch event callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Control touch sensing.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the number of channels.
+    /// - `1`: Enable touch events
+    /// - `2`: Disable touch events
+    /// - `3`: Set the threshold of a channel
+    /// - `4`: Get the last measurement and baseline of a channel
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success_u32(self.channels() as u32),
+            1 | 2 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app| app.enabled = cmd_num == 1)
+                    .map_err(ErrorCode::from);
+                match res {
+                    Ok(()) => {
+                        self.update_scanning();
+                        CommandReturn::success()
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+            3 => {
+                if arg1 >= self.channels() {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    self.channels[arg1].threshold.set(arg2 as u32);
+                    CommandReturn::success()
+                }
+            }
+            4 => {
+                if arg1 >= self.channels() {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    let channel = &self.channels[arg1];
+                    CommandReturn::success_u32_u32(channel.last.get(), channel.baseline.get())
+                }
+            }
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Channel, CALIBRATION_SAMPLES};
+
+    fn calibrated(values: &[u32]) -> Channel {
+        let channel = Channel::default();
+        channel.calibrate();
+        for i in 0..CALIBRATION_SAMPLES as usize {
+            assert_eq!(channel.update(values[i % values.len()]), None);
+        }
+        channel
+    }
+
+    #[test]
+    fn test_calibration() {
+        let channel = calibrated(&[1000, 1010, 990, 1000]);
+        assert_eq!(channel.baseline.get(), 1000);
+        // A tenth of the baseline is more than four times the noise.
+        assert_eq!(channel.threshold(), 100);
+
+        let noisy = calibrated(&[1000, 1050, 950, 1000]);
+        assert_eq!(noisy.threshold(), 400);
+
+        channel.threshold.set(20);
+        assert_eq!(channel.threshold(), 20);
+    }
+
+    #[test]
+    fn test_touch() {
+        let channel = calibrated(&[1000]);
+        assert_eq!(channel.update(1050), None);
+        assert_eq!(channel.update(1150), Some(true));
+        // The baseline does not follow a touched pad.
+        assert_eq!(channel.update(1200), None);
+        assert_eq!(channel.update(1060), None);
+        assert_eq!(channel.update(1040), Some(false));
+        assert!(!channel.touched.get());
+    }
+
+    #[test]
+    fn test_touched_while_calibrating() {
+        // Nothing is reported until the channel is calibrated.
+        let channel = Channel::default();
+        channel.calibrate();
+        for _ in 1..CALIBRATION_SAMPLES {
+            assert_eq!(channel.update(2000), None);
+        }
+        assert!(!channel.calibrated());
+        assert_eq!(channel.update(2000), None);
+        assert!(channel.calibrated());
+    }
+}
@@ -0,0 +1,1117 @@
+//! Driver for cellular modems controlled with AT commands over a UART, such
+//! as the Quectel BG95 and BG77 LTE-M modules.
+//!
+//! The driver powers the modem on and off, brings up a PDP context with the
+//! APN a process gives it, and lets processes open TCP and UDP sockets
+//! through the modem's own IP stack. Unsolicited result codes (URCs) from the
+//! modem, such as data arriving on a socket or the network changing the
+//! registration state, are passed on to processes as events.
+//!
+//! The modem runs one AT command at a time, and so does the driver: while a
+//! command is outstanding, other requests get `BUSY`.
+//!
+//! Sockets use the modem's buffer access mode. The modem keeps received data
+//! until a process reads it, and tells the driver when there is some. A
+//! socket belongs to the process that opened it. The sockets of a process
+//! that exited are closed on the modem when they are needed again.
+//!
+//! The modem is turned on by holding its PWRKEY line low. Boards usually
+//! drive PWRKEY through a transistor, so the driver sets the `pwrkey` pin to
+//! press the key. The driver assumes the modem is off when the board starts.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let modem_uart = static_init!(UartDevice, UartDevice::new(modem_uart_mux, true));
+//! modem_uart.setup();
+//! let modem_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let cellular = static_init!(
+//!     capsules::cellular::Cellular<'static, VirtualMuxAlarm<'static, apollo3::stimer::STimer>>,
+//!     capsules::cellular::Cellular::new(
+//!         board_kernel,
+//!         modem_uart,
+//!         &gpio_port[MODEM_PWRKEY_PIN],
+//!         modem_alarm,
+//!         &mut capsules::cellular::TX_BUF,
+//!         &mut capsules::cellular::RX_BUF,
+//!         &mut capsules::cellular::LINE_BUF,
+//!         &mut capsules::cellular::DATA_BUF,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hil::uart::Transmit::set_transmit_client(modem_uart, cellular);
+//! hil::uart::Receive::set_receive_client(modem_uart, cellular);
+//! modem_alarm.set_alarm_client(cellular);
+//! cellular.start();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-write `0`: buffer data received on a socket is copied to.
+//! - Read-only `0`: the APN to attach with, or the host to open a socket to,
+//!   as ASCII text up to the first NUL byte or the end of the buffer.
+//! - Read-only `1`: data to send on a socket.
+//!
+//! ### Subscribe
+//!
+//! - `0`: operation done. The callback gets the result and a value: the
+//!   socket for command `5`, the number of bytes sent or read for commands
+//!   `6` and `7`, and `0` otherwise.
+//! - `1`: event. The callback gets the event and its argument:
+//!   - `0`: the registration state changed, to the argument. It is the
+//!     `<stat>` of `+CEREG`, for example `1` when registered on the home
+//!     network and `5` when roaming.
+//!   - `1`: data arrived on the socket in the argument.
+//!   - `2`: the remote end closed the socket in the argument. The process
+//!     must still close it.
+//!   - `3`: the network deactivated the PDP context.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Power the modem on. It is done once the modem is ready for
+//!   commands.
+//! - `2`: Power the modem off, closing all sockets.
+//! - `3`: Attach: activate the PDP context with the APN in read-only buffer
+//!   `0`.
+//! - `4`: Detach: deactivate the PDP context, closing all sockets.
+//! - `5`: Open a socket to the host in read-only buffer `0`. The first
+//!   argument is the protocol, `0` for TCP or `1` for UDP, and the second
+//!   the remote port. Returns `NOMEM` if all sockets are in use.
+//! - `6`: Send data on a socket. The first argument is the socket and the
+//!   second the number of bytes of read-only buffer `1` to send.
+//! - `7`: Read the data received on a socket into the read-write buffer.
+//!   The first argument is the socket.
+//! - `8`: Close a socket.
+//! - `9`: Get the registration state, as for event `0`.
+//!
+//! Commands `2` to `8` return `OFF` while the modem is off and `BUSY` while
+//! another command is outstanding. Commands `5` to `8` return `INVAL` before
+//! attaching or for a socket the process did not open.
+//!
+//! Powering off and detaching close the sockets of every process, so
+//! commands `2` and `4` return `NOSUPPORT` unless the caller has permission
+//! (see the `Permissions` TLV in `doc/TockBinaryFormat.md`).
+
+use core::cell::Cell;
+use core::cmp;
+use core::fmt::{self, Write};
+use core::mem;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::time::{self, Alarm};
+use kernel::hil::uart;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Cellular as usize;
+
+pub static mut TX_BUF: [u8; 160] = [0; 160];
+pub static mut RX_BUF: [u8; 1] = [0; 1];
+pub static mut LINE_BUF: [u8; 64] = [0; 64];
+/// Socket data is sent and read in chunks of at most the size of this
+/// buffer.
+pub static mut DATA_BUF: [u8; 512] = [0; 512];
+
+/// Sockets, which are connection IDs `0` to `NUM_SOCKETS - 1` on the modem.
+const NUM_SOCKETS: usize = 4;
+
+/// The PDP context the driver uses.
+const CONTEXT: usize = 1;
+
+/// Time PWRKEY is held to turn the modem on.
+const PWRKEY_MS: u32 = 700;
+/// Time the modem may take to send `RDY` once PWRKEY is released.
+const BOOT_TIMEOUT_MS: u32 = 10_000;
+/// Time the modem may take to respond to a local command.
+const COMMAND_TIMEOUT_MS: u32 = 5_000;
+/// Time the modem may take to respond to a command that goes over the
+/// network.
+const NETWORK_TIMEOUT_MS: u32 = 150_000;
+
+const PROTOCOL_TCP: usize = 0;
+const PROTOCOL_UDP: usize = 1;
+
+const EVENT_REGISTRATION: usize = 0;
+const EVENT_DATA: usize = 1;
+const EVENT_CLOSED: usize = 2;
+const EVENT_DETACHED: usize = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Power {
+    Off,
+    /// PWRKEY is held.
+    PressingKey,
+    /// Waiting for `RDY`, then turning echo off and registration URCs on.
+    Booting,
+    On,
+}
+
+/// The AT command outstanding.
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    /// `ATE0`
+    EchoOff,
+    /// `AT+CEREG=1`
+    RegistrationUrc,
+    /// `AT+QICSGP`
+    SetApn,
+    /// `AT+QIACT`
+    Activate,
+    /// `AT+QIDEACT`
+    Deactivate,
+    /// `AT+QICLOSE` for a socket of a process that exited, before opening
+    /// it again.
+    CloseStale(usize),
+    /// `AT+QIOPEN`
+    Open(usize),
+    /// `AT+QIOPEN` returned `OK`, waiting for its `+QIOPEN` URC.
+    OpenResult(usize),
+    /// `AT+QISEND`, waiting for its `>` prompt.
+    SendPrompt(usize),
+    /// The data after the prompt, waiting for `SEND OK`.
+    SendData(usize),
+    /// `AT+QIRD`
+    Read(usize),
+    /// `AT+QICLOSE`
+    Close(usize),
+    /// `AT+QPOWD`
+    PowerDown,
+}
+
+#[derive(Default)]
+pub struct App {
+    done_callback: Upcall,
+    event_callback: Upcall,
+    rx_buffer: ReadWriteAppSlice,
+    text_buffer: ReadOnlyAppSlice,
+    tx_buffer: ReadOnlyAppSlice,
+}
+
+/// Writes formatted text into a buffer, noting if it does not fit.
+struct WriteAdapter {
+    buffer: &'static mut [u8],
+    used: usize,
+    overflow: bool,
+}
+
+impl Write for WriteAdapter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.buffer.get_mut(self.used..self.used + s.len()) {
+            Some(slice) => {
+                slice.copy_from_slice(s.as_bytes());
+                self.used += s.len();
+            }
+            None => self.overflow = true,
+        }
+        Ok(())
+    }
+}
+
+/// The rest of `line` if it starts with `prefix`.
+fn after<'l>(line: &'l [u8], prefix: &[u8]) -> Option<&'l [u8]> {
+    if line.starts_with(prefix) {
+        Some(&line[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+fn parse_number(text: &[u8]) -> Option<usize> {
+    if text.is_empty() {
+        return None;
+    }
+    text.iter().try_fold(0usize, |value, &c| match c {
+        b'0'..=b'9' => value.checked_mul(10)?.checked_add((c - b'0') as usize),
+        _ => None,
+    })
+}
+
+/// The text in `buffer` up to its first NUL byte, if it can be quoted in an
+/// AT command.
+fn quotable(buffer: &[u8]) -> Option<&str> {
+    let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    let text = &buffer[..len];
+    if text.is_empty() || text.iter().any(|&c| c < 0x20 || c > 0x7e || c == b'"') {
+        return None;
+    }
+    core::str::from_utf8(text).ok()
+}
+
+/// A line from the modem.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Response {
+    /// `RDY`: the modem booted.
+    Ready,
+    /// `+CEREG`, with the registration state.
+    Registration(usize),
+    /// `+QIURC: "recv"`, with the socket.
+    DataReceived(usize),
+    /// `+QIURC: "closed"`, with the socket.
+    Closed(usize),
+    /// `+QIURC: "pdpdeact"`
+    Deactivated,
+    /// `+QIOPEN`, with the socket and the error, which are `None` if they
+    /// are not numbers.
+    Opened(Option<usize>, Option<usize>),
+    /// `+QIRD`, with the length of the data that follows.
+    ReadLength(usize),
+    Ok,
+    SendOk,
+    Error,
+}
+
+fn parse_line(line: &[u8]) -> Option<Response> {
+    if line == b"RDY" {
+        Some(Response::Ready)
+    } else if let Some(rest) = after(line, b"+CEREG: ") {
+        parse_number(rest).map(Response::Registration)
+    } else if let Some(rest) = after(line, b"+QIURC: \"recv\",") {
+        parse_number(rest).map(Response::DataReceived)
+    } else if let Some(rest) = after(line, b"+QIURC: \"closed\",") {
+        parse_number(rest).map(Response::Closed)
+    } else if after(line, b"+QIURC: \"pdpdeact\",").is_some() {
+        Some(Response::Deactivated)
+    } else if let Some(rest) = after(line, b"+QIOPEN: ") {
+        let mut fields = rest.split(|&c| c == b',').map(parse_number);
+        let socket = fields.next().flatten();
+        let error = fields.next().flatten();
+        Some(Response::Opened(socket, error))
+    } else if let Some(rest) = after(line, b"+QIRD: ") {
+        // UDP responses add the remote address after the length.
+        let len = rest
+            .split(|&c| c == b',')
+            .next()
+            .and_then(parse_number)
+            .unwrap_or(0);
+        Some(Response::ReadLength(len))
+    } else if line == b"OK" {
+        Some(Response::Ok)
+    } else if line == b"SEND OK" {
+        Some(Response::SendOk)
+    } else if line == b"ERROR" || line == b"SEND FAIL" || line.starts_with(b"+CME ERROR") {
+        Some(Response::Error)
+    } else {
+        None
+    }
+}
+
+pub struct Cellular<'a, A: Alarm<'a>> {
+    kernel: &'static Kernel,
+    uart: &'a dyn uart::UartData<'a>,
+    pwrkey: &'a dyn gpio::Pin,
+    alarm: &'a A,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    line: TakeCell<'static, [u8]>,
+    /// Length of the line being received, or `usize::MAX` while skipping a
+    /// line too long for the buffer.
+    line_len: Cell<usize>,
+    data: TakeCell<'static, [u8]>,
+    /// Whether the data buffer is being transmitted.
+    data_in_flight: Cell<bool>,
+    /// Bytes of socket data being sent, or to read into the data buffer.
+    data_len: Cell<usize>,
+    /// Bytes of socket data read into the data buffer so far.
+    data_received: Cell<usize>,
+    power: Cell<Power>,
+    attached: Cell<bool>,
+    registration: Cell<usize>,
+    command: Cell<Option<Command>>,
+    /// Protocol and port of the socket being opened.
+    open_params: Cell<(usize, usize)>,
+    /// Process whose command is outstanding.
+    current: OptionalCell<ProcessId>,
+    sockets: [OptionalCell<ProcessId>; NUM_SOCKETS],
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> Cellular<'a, A> {
+    pub fn new(
+        kernel: &'static Kernel,
+        uart: &'a dyn uart::UartData<'a>,
+        pwrkey: &'a dyn gpio::Pin,
+        alarm: &'a A,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        line: &'static mut [u8],
+        data: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> Cellular<'a, A> {
+        Cellular {
+            kernel: kernel,
+            uart: uart,
+            pwrkey: pwrkey,
+            alarm: alarm,
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            line: TakeCell::new(line),
+            line_len: Cell::new(0),
+            data: TakeCell::new(data),
+            data_in_flight: Cell::new(false),
+            data_len: Cell::new(0),
+            data_received: Cell::new(0),
+            power: Cell::new(Power::Off),
+            attached: Cell::new(false),
+            registration: Cell::new(0),
+            command: Cell::new(None),
+            open_params: Cell::new((PROTOCOL_TCP, 0)),
+            current: OptionalCell::empty(),
+            sockets: [
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+            ],
+            apps: grant,
+        }
+    }
+
+    /// Release PWRKEY and start receiving from the modem.
+    pub fn start(&self) {
+        self.pwrkey.make_output();
+        self.pwrkey.clear();
+        self.rx_buffer.take().map(|buffer| {
+            let _ = self.uart.receive_buffer(buffer, 1);
+        });
+    }
+
+    fn check_ready(&self) -> Result<(), ErrorCode> {
+        match self.power.get() {
+            Power::Off => Err(ErrorCode::OFF),
+            Power::On if self.command.get().is_none() => Ok(()),
+            _ => Err(ErrorCode::BUSY),
+        }
+    }
+
+    fn check_owner(&self, appid: ProcessId, socket: usize) -> Result<(), ErrorCode> {
+        match self.sockets.get(socket) {
+            Some(owner) if owner.contains(&appid) => Ok(()),
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+
+    fn clear_sockets(&self) {
+        self.sockets.iter().for_each(|owner| owner.clear());
+    }
+
+    /// Send `AT` followed by `args` on behalf of `appid`, failing it if the
+    /// modem does not respond within `timeout_ms`.
+    fn send(
+        &self,
+        appid: ProcessId,
+        command: Command,
+        timeout_ms: u32,
+        args: fmt::Arguments,
+    ) -> Result<(), ErrorCode> {
+        let buffer = self.tx_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let mut writer = WriteAdapter {
+            buffer: buffer,
+            used: 0,
+            overflow: false,
+        };
+        let _ = write!(writer, "AT{}\r", args);
+        let WriteAdapter {
+            buffer,
+            used,
+            overflow,
+        } = writer;
+        if overflow {
+            // A command cut short would be garbage to the modem.
+            self.tx_buffer.replace(buffer);
+            return Err(ErrorCode::SIZE);
+        }
+
+        match self.uart.transmit_buffer(buffer, used) {
+            Ok(()) => {
+                self.command.set(Some(command));
+                self.current.set(appid);
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(timeout_ms));
+                Ok(())
+            }
+            Err((e, buffer)) => {
+                self.tx_buffer.replace(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    /// Send the next command of the current operation.
+    fn next(&self, command: Command, timeout_ms: u32, args: fmt::Arguments) {
+        self.command.set(None);
+        let result = self
+            .current
+            .extract()
+            .map_or(Err(ErrorCode::FAIL), |appid| {
+                self.send(appid, command, timeout_ms, args)
+            });
+        if let Err(e) = result {
+            self.finish(Err(e));
+        }
+    }
+
+    /// End the current operation and tell the process the result.
+    fn finish(&self, result: Result<usize, ErrorCode>) {
+        self.command.set(None);
+        self.data_len.set(0);
+        self.data_received.set(0);
+        let _ = self.alarm.disarm();
+        if self.power.get() == Power::Booting {
+            // The modem answered, so it is on even if a command failed.
+            self.power.set(Power::On);
+        }
+        self.current.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| match result {
+                Ok(value) => app.done_callback.schedule(0, value, 0),
+                Err(e) => app
+                    .done_callback
+                    .schedule(kernel::into_statuscode(Err(e)), 0, 0),
+            });
+        });
+    }
+
+    fn notify_owner(&self, socket: usize, event: usize) {
+        if let Some(owner) = self.sockets.get(socket).and_then(|owner| owner.extract()) {
+            let _ = self.apps.enter(owner, |app| {
+                app.event_callback.schedule(event, socket, 0);
+            });
+        }
+    }
+
+    fn notify_all(&self, event: usize, arg: usize) {
+        self.apps.each(|_, app| {
+            app.event_callback.schedule(event, arg, 0);
+        });
+    }
+
+    fn power_on(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        match self.power.get() {
+            Power::Off => {}
+            Power::On => return Err(ErrorCode::ALREADY),
+            Power::PressingKey | Power::Booting => return Err(ErrorCode::BUSY),
+        }
+        self.pwrkey.set();
+        self.power.set(Power::PressingKey);
+        self.current.set(appid);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(PWRKEY_MS));
+        Ok(())
+    }
+
+    fn attach(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        self.check_ready()?;
+        if self.attached.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.apps
+            .enter(appid, |app| {
+                app.text_buffer.map_or(Err(ErrorCode::INVAL), |text| {
+                    let apn = quotable(text).ok_or(ErrorCode::INVAL)?;
+                    self.send(
+                        appid,
+                        Command::SetApn,
+                        COMMAND_TIMEOUT_MS,
+                        format_args!("+QICSGP={},1,\"{}\",\"\",\"\",0", CONTEXT, apn),
+                    )
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn open(&self, appid: ProcessId, protocol: usize, port: usize) -> Result<(), ErrorCode> {
+        self.check_ready()?;
+        if !self.attached.get() || protocol > PROTOCOL_UDP || port == 0 || port > 0xffff {
+            return Err(ErrorCode::INVAL);
+        }
+        self.open_params.set((protocol, port));
+
+        if let Some(socket) = (0..NUM_SOCKETS).find(|&socket| self.sockets[socket].is_none()) {
+            return self.send_open(appid, socket);
+        }
+        // Reclaim a socket from a process that exited.
+        let stale = (0..NUM_SOCKETS).find(|&socket| {
+            self.sockets[socket]
+                .extract()
+                .map_or(false, |owner| self.apps.enter(owner, |_| ()).is_err())
+        });
+        match stale {
+            Some(socket) => self.send(
+                appid,
+                Command::CloseStale(socket),
+                NETWORK_TIMEOUT_MS,
+                format_args!("+QICLOSE={}", socket),
+            ),
+            None => Err(ErrorCode::NOMEM),
+        }
+    }
+
+    fn send_open(&self, appid: ProcessId, socket: usize) -> Result<(), ErrorCode> {
+        let (protocol, port) = self.open_params.get();
+        let service = if protocol == PROTOCOL_TCP {
+            "TCP"
+        } else {
+            "UDP"
+        };
+        self.apps
+            .enter(appid, |app| {
+                app.text_buffer.map_or(Err(ErrorCode::INVAL), |text| {
+                    let host = quotable(text).ok_or(ErrorCode::INVAL)?;
+                    self.send(
+                        appid,
+                        Command::Open(socket),
+                        NETWORK_TIMEOUT_MS,
+                        format_args!(
+                            "+QIOPEN={},{},\"{}\",\"{}\",{},0,0",
+                            CONTEXT, socket, service, host, port
+                        ),
+                    )
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn send_data(&self, appid: ProcessId, socket: usize, len: usize) -> Result<(), ErrorCode> {
+        self.check_ready()?;
+        self.check_owner(appid, socket)?;
+        let data = self.data.take().ok_or(ErrorCode::BUSY)?;
+        let len = self
+            .apps
+            .enter(appid, |app| {
+                app.tx_buffer.map_or(0, |src| {
+                    let len = cmp::min(cmp::min(len, src.len()), data.len());
+                    data[..len].copy_from_slice(&src[..len]);
+                    len
+                })
+            })
+            .unwrap_or(0);
+        self.data.replace(data);
+        if len == 0 {
+            return Err(ErrorCode::SIZE);
+        }
+        self.send(
+            appid,
+            Command::SendPrompt(socket),
+            COMMAND_TIMEOUT_MS,
+            format_args!("+QISEND={},{}", socket, len),
+        )?;
+        self.data_len.set(len);
+        Ok(())
+    }
+
+    /// The modem prompted for the data of `AT+QISEND`.
+    fn transmit_data(&self, socket: usize) {
+        match self.data.take() {
+            Some(data) => match self.uart.transmit_buffer(data, self.data_len.get()) {
+                Ok(()) => {
+                    self.data_in_flight.set(true);
+                    self.command.set(Some(Command::SendData(socket)));
+                }
+                Err((e, data)) => {
+                    self.data.replace(data);
+                    self.finish(Err(e));
+                }
+            },
+            None => self.finish(Err(ErrorCode::BUSY)),
+        }
+    }
+
+    fn read(&self, appid: ProcessId, socket: usize) -> Result<(), ErrorCode> {
+        self.check_ready()?;
+        self.check_owner(appid, socket)?;
+        let len = self
+            .apps
+            .enter(appid, |app| app.rx_buffer.len())
+            .unwrap_or(0);
+        let len = cmp::min(len, self.data.map_or(0, |data| data.len()));
+        if len == 0 {
+            return Err(ErrorCode::SIZE);
+        }
+        self.send(
+            appid,
+            Command::Read(socket),
+            COMMAND_TIMEOUT_MS,
+            format_args!("+QIRD={},{}", socket, len),
+        )
+    }
+
+    /// Whether the modem is sending the data of a `+QIRD` response.
+    fn reading_data(&self) -> bool {
+        match self.command.get() {
+            Some(Command::Read(_)) => self.data_received.get() < self.data_len.get(),
+            _ => false,
+        }
+    }
+
+    fn receive_byte(&self, byte: u8) {
+        if self.reading_data() {
+            let received = self.data_received.get();
+            self.data.map(|data| data[received] = byte);
+            self.data_received.set(received + 1);
+            return;
+        }
+        if byte == b'>' && self.line_len.get() == 0 {
+            // The prompt is not followed by a line break.
+            if let Some(Command::SendPrompt(socket)) = self.command.get() {
+                self.transmit_data(socket);
+                return;
+            }
+        }
+
+        self.line.take().map(|line| {
+            let len = self.line_len.get();
+            match byte {
+                b'\r' => {}
+                b'\n' => {
+                    if len > 0 && len <= line.len() {
+                        self.handle_line(&line[..len]);
+                    }
+                    self.line_len.set(0);
+                }
+                _ if len < line.len() => {
+                    line[len] = byte;
+                    self.line_len.set(len + 1);
+                }
+                _ => self.line_len.set(usize::MAX),
+            }
+            self.line.replace(line);
+        });
+    }
+
+    fn handle_line(&self, line: &[u8]) {
+        match parse_line(line) {
+            Some(Response::Ready) => {
+                if self.power.get() == Power::Booting && self.command.get().is_none() {
+                    self.next(Command::EchoOff, COMMAND_TIMEOUT_MS, format_args!("E0"));
+                }
+            }
+            Some(Response::Registration(stat)) => {
+                self.registration.set(stat);
+                self.notify_all(EVENT_REGISTRATION, stat);
+            }
+            Some(Response::DataReceived(socket)) => self.notify_owner(socket, EVENT_DATA),
+            Some(Response::Closed(socket)) => self.notify_owner(socket, EVENT_CLOSED),
+            Some(Response::Deactivated) => {
+                // The modem closed the sockets with the context.
+                self.attached.set(false);
+                self.notify_all(EVENT_DETACHED, 0);
+            }
+            Some(Response::Opened(socket, error)) => self.open_result(socket, error),
+            Some(Response::ReadLength(len)) => {
+                if let Some(Command::Read(_)) = self.command.get() {
+                    let capacity = self.data.map_or(0, |data| data.len());
+                    self.data_len.set(cmp::min(len, capacity));
+                    self.data_received.set(0);
+                }
+            }
+            Some(Response::Ok) => self.command_ok(),
+            Some(Response::SendOk) => {
+                if let Some(Command::SendData(_)) = self.command.get() {
+                    self.finish(Ok(self.data_len.get()));
+                }
+            }
+            Some(Response::Error) => {
+                if self.command.get().is_some() {
+                    self.finish(Err(ErrorCode::FAIL));
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn command_ok(&self) {
+        match self.command.get() {
+            Some(Command::EchoOff) => self.next(
+                Command::RegistrationUrc,
+                COMMAND_TIMEOUT_MS,
+                format_args!("+CEREG=1"),
+            ),
+            Some(Command::RegistrationUrc) => {
+                self.power.set(Power::On);
+                self.finish(Ok(0));
+            }
+            Some(Command::SetApn) => self.next(
+                Command::Activate,
+                NETWORK_TIMEOUT_MS,
+                format_args!("+QIACT={}", CONTEXT),
+            ),
+            Some(Command::Activate) => {
+                self.attached.set(true);
+                self.finish(Ok(0));
+            }
+            Some(Command::Deactivate) => {
+                self.attached.set(false);
+                self.clear_sockets();
+                self.finish(Ok(0));
+            }
+            Some(Command::CloseStale(socket)) => {
+                self.sockets[socket].clear();
+                self.command.set(None);
+                let result = self
+                    .current
+                    .extract()
+                    .map_or(Err(ErrorCode::FAIL), |appid| self.send_open(appid, socket));
+                if let Err(e) = result {
+                    self.finish(Err(e));
+                }
+            }
+            Some(Command::Open(socket)) => self.command.set(Some(Command::OpenResult(socket))),
+            Some(Command::Read(_)) => {
+                let len = self.data_received.get();
+                self.current.map(|appid| {
+                    self.data.map(|data| {
+                        let _ = self.apps.enter(*appid, |app| {
+                            app.rx_buffer.mut_map_or((), |buffer| {
+                                // The process may have allowed a smaller
+                                // buffer since the read started.
+                                let len = cmp::min(len, buffer.len());
+                                buffer[..len].copy_from_slice(&data[..len]);
+                            });
+                        });
+                    });
+                });
+                self.finish(Ok(len));
+            }
+            Some(Command::Close(socket)) => {
+                self.sockets[socket].clear();
+                self.finish(Ok(0));
+            }
+            Some(Command::PowerDown) => {
+                self.power.set(Power::Off);
+                self.attached.set(false);
+                self.clear_sockets();
+                self.finish(Ok(0));
+            }
+            // These end with the `+QIOPEN` URC or `SEND OK`.
+            Some(Command::OpenResult(_))
+            | Some(Command::SendPrompt(_))
+            | Some(Command::SendData(_))
+            | None => {}
+        }
+    }
+
+    /// Handle the `<socket>,<error>` of a `+QIOPEN` URC.
+    fn open_result(&self, socket: Option<usize>, error: Option<usize>) {
+        match self.command.get() {
+            Some(Command::Open(open)) | Some(Command::OpenResult(open)) if socket == Some(open) => {
+                if error == Some(0) {
+                    self.current.map(|appid| self.sockets[open].set(*appid));
+                    self.finish(Ok(open));
+                } else {
+                    self.finish(Err(ErrorCode::FAIL));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> uart::TransmitClient for Cellular<'a, A> {
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        if self.data_in_flight.replace(false) {
+            self.data.replace(buffer);
+        } else {
+            self.tx_buffer.replace(buffer);
+        }
+        if let Err(e) = rval {
+            if self.command.get().is_some() {
+                self.finish(Err(e));
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> uart::ReceiveClient for Cellular<'a, A> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        if rval.is_ok() && rx_len > 0 {
+            self.receive_byte(buffer[0]);
+        }
+        if let Err((_, buffer)) = self.uart.receive_buffer(buffer, 1) {
+            self.rx_buffer.replace(buffer);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Cellular<'a, A> {
+    fn alarm(&self) {
+        match self.power.get() {
+            Power::PressingKey => {
+                self.pwrkey.clear();
+                self.power.set(Power::Booting);
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(BOOT_TIMEOUT_MS));
+            }
+            Power::Booting if self.command.get().is_none() => {
+                // No `RDY`, so the modem did not start.
+                self.power.set(Power::Off);
+                self.finish(Err(ErrorCode::NOACK));
+            }
+            _ => {
+                if self.command.get().is_some() {
+                    self.finish(Err(ErrorCode::NOACK));
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for Cellular<'a, A> {
+    /// Share the buffer received socket data is copied to.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Receive buffer
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.rx_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Share the APN or host name, and the data to send.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: APN or host name
+    /// - `1`: Data to send
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.text_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            1 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.tx_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Operation done callback
+    /// - `1`: Event callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.done_callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            1 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.event_callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Control the modem and its sockets.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Power on
+    /// - `2`: Power off
+    /// - `3`: Attach
+    /// - `4`: Detach
+    /// - `5`: Open a socket
+    /// - `6`: Send on a socket
+    /// - `7`: Read from a socket
+    /// - `8`: Close a socket
+    /// - `9`: Get the registration state
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
+        if (cmd_num == 2 || cmd_num == 4)
+            && !self.kernel.command_permitted(appid, DRIVER_NUM, cmd_num)
+        {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+
+        let result = match cmd_num {
+            0 => Ok(()),
+            1 => self.power_on(appid),
+            2 => self.check_ready().and_then(|()| {
+                self.send(
+                    appid,
+                    Command::PowerDown,
+                    COMMAND_TIMEOUT_MS,
+                    format_args!("+QPOWD=1"),
+                )
+            }),
+            3 => self.attach(appid),
+            4 => self.check_ready().and_then(|()| {
+                if !self.attached.get() {
+                    return Err(ErrorCode::ALREADY);
+                }
+                self.send(
+                    appid,
+                    Command::Deactivate,
+                    NETWORK_TIMEOUT_MS,
+                    format_args!("+QIDEACT={}", CONTEXT),
+                )
+            }),
+            5 => self.open(appid, arg1, arg2),
+            6 => self.send_data(appid, arg1, arg2),
+            7 => self.read(appid, arg1),
+            8 => self.check_ready().and_then(|()| {
+                self.check_owner(appid, arg1)?;
+                self.send(
+                    appid,
+                    Command::Close(arg1),
+                    NETWORK_TIMEOUT_MS,
+                    format_args!("+QICLOSE={}", arg1),
+                )
+            }),
+            9 => return CommandReturn::success_u32(self.registration.get() as u32),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match result {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_line, parse_number, quotable, Response};
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number(b"0"), Some(0));
+        assert_eq!(parse_number(b"42"), Some(42));
+        assert_eq!(parse_number(b""), None);
+        assert_eq!(parse_number(b"4x"), None);
+        assert_eq!(parse_number(b"-1"), None);
+        assert_eq!(parse_number(b"99999999999999999999999"), None);
+    }
+
+    #[test]
+    fn test_quotable() {
+        assert_eq!(quotable(b"internet\0junk"), Some("internet"));
+        assert_eq!(quotable(b"example.com"), Some("example.com"));
+        assert_eq!(quotable(b""), None);
+        assert_eq!(quotable(b"\0"), None);
+        assert_eq!(quotable(b"a\"b"), None);
+        assert_eq!(quotable(b"a\rb"), None);
+        assert_eq!(quotable(&[b'a', 0x80]), None);
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line(b"RDY"), Some(Response::Ready));
+        assert_eq!(parse_line(b"OK"), Some(Response::Ok));
+        assert_eq!(parse_line(b"SEND OK"), Some(Response::SendOk));
+        assert_eq!(parse_line(b"ERROR"), Some(Response::Error));
+        assert_eq!(parse_line(b"SEND FAIL"), Some(Response::Error));
+        assert_eq!(parse_line(b"+CME ERROR: 550"), Some(Response::Error));
+        assert_eq!(parse_line(b"+CEREG: 5"), Some(Response::Registration(5)));
+        assert_eq!(parse_line(b"+CEREG: x"), None);
+        assert_eq!(
+            parse_line(b"+QIURC: \"recv\",2"),
+            Some(Response::DataReceived(2))
+        );
+        assert_eq!(
+            parse_line(b"+QIURC: \"closed\",3"),
+            Some(Response::Closed(3))
+        );
+        assert_eq!(
+            parse_line(b"+QIURC: \"pdpdeact\",1"),
+            Some(Response::Deactivated)
+        );
+        assert_eq!(
+            parse_line(b"+QIOPEN: 1,0"),
+            Some(Response::Opened(Some(1), Some(0)))
+        );
+        assert_eq!(
+            parse_line(b"+QIOPEN: 1,565"),
+            Some(Response::Opened(Some(1), Some(565)))
+        );
+        assert_eq!(
+            parse_line(b"+QIOPEN: x"),
+            Some(Response::Opened(None, None))
+        );
+        assert_eq!(parse_line(b"+QIRD: 12"), Some(Response::ReadLength(12)));
+        // UDP responses add the remote address.
+        assert_eq!(
+            parse_line(b"+QIRD: 7,\"10.0.0.1\",5683"),
+            Some(Response::ReadLength(7))
+        );
+        assert_eq!(parse_line(b"+QIRD: "), Some(Response::ReadLength(0)));
+        // Echoed commands and unknown URCs are ignored.
+        assert_eq!(parse_line(b"AT+QIRD=0,512"), None);
+        assert_eq!(parse_line(b"+QIURC: \"incoming full\""), None);
+        assert_eq!(parse_line(b"OKAY"), None);
+    }
+}
@@ -0,0 +1,252 @@
+//! Runs long computations in bounded chunks across kernel loop iterations.
+//!
+//! Software implementations of expensive operations, such as hashing a large
+//! buffer, would otherwise run to completion inside a single syscall or
+//! callback, delaying every other process and interrupt handler until they
+//! finish. Instead, they implement `ChunkedTask` and do their work one chunk
+//! at a time. The executor runs one chunk per kernel loop iteration from a
+//! deferred call, so the latency the computation adds to the kernel loop is
+//! bounded by the chunk budget rather than by the size of the computation.
+//!
+//! The budget is in estimated CPU cycles. Each task converts it into its own
+//! units of work (blocks, rounds, ...) from an estimate of their cost, and
+//! always makes some progress, even if a single unit costs more than the
+//! budget. Tasks that are scheduled together take turns.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let executor = static_init!(
+//!     capsules::chunked_executor::ChunkedExecutor<'static>,
+//!     capsules::chunked_executor::ChunkedExecutor::new(dynamic_deferred_caller, 20_000)
+//! );
+//! executor.initialize_callback_handle(dynamic_deferred_caller.register(executor).unwrap());
+//!
+//! let sha_task = static_init!(
+//!     capsules::chunked_executor::ExecutorTask<'static>,
+//!     capsules::chunked_executor::ExecutorTask::new(executor)
+//! );
+//! sha_task.setup();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::{List, ListLink, ListNode};
+
+/// A computation that can be run a chunk at a time.
+pub trait ChunkedTask {
+    /// Run the next chunk of the computation, taking about `budget` cycles
+    /// or less. Returns `true` once the computation has finished.
+    fn run_chunk(&self, budget: u32) -> bool;
+}
+
+/// A slot for one task in a `ChunkedExecutor`.
+pub struct ExecutorTask<'a> {
+    executor: &'a ChunkedExecutor<'a>,
+    next: ListLink<'a, ExecutorTask<'a>>,
+    client: OptionalCell<&'a dyn ChunkedTask>,
+    scheduled: Cell<bool>,
+    /// Set by `cancel`, so a chunk that is running is not rescheduled.
+    cancelled: Cell<bool>,
+}
+
+impl<'a> ListNode<'a, ExecutorTask<'a>> for ExecutorTask<'a> {
+    fn next(&self) -> &'a ListLink<ExecutorTask<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> ExecutorTask<'a> {
+    pub fn new(executor: &'a ChunkedExecutor<'a>) -> ExecutorTask<'a> {
+        ExecutorTask {
+            executor: executor,
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+            scheduled: Cell::new(false),
+            cancelled: Cell::new(false),
+        }
+    }
+
+    /// Must be called right after `static_init!()`.
+    pub fn setup(&'a self) {
+        self.executor.tasks.push_tail(self);
+    }
+
+    pub fn set_client(&self, client: &'a dyn ChunkedTask) {
+        self.client.set(client);
+    }
+
+    /// Run the task's computation, until its `run_chunk` returns `true`.
+    pub fn schedule(&self) {
+        self.cancelled.set(false);
+        self.scheduled.set(true);
+        self.executor.schedule();
+    }
+
+    /// Stop running the task's computation. If called while a chunk of it
+    /// runs, no further chunks run.
+    pub fn cancel(&self) {
+        self.cancelled.set(true);
+        self.scheduled.set(false);
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.scheduled.get()
+    }
+}
+
+pub struct ChunkedExecutor<'a> {
+    tasks: List<'a, ExecutorTask<'a>>,
+    /// Cycles a task may take per kernel loop iteration.
+    budget: u32,
+    /// Position in `tasks` of the task to consider first next time.
+    next_task: Cell<usize>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> ChunkedExecutor<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall, budget: u32) -> ChunkedExecutor<'a> {
+        ChunkedExecutor {
+            tasks: List::new(),
+            budget: budget,
+            next_task: Cell::new(0),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn schedule(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+}
+
+impl DynamicDeferredCallClient for ChunkedExecutor<'_> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        // Take turns: pick the first scheduled task at or after the one
+        // following the task that ran last.
+        let start = self.next_task.get();
+        let task = self
+            .tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, task)| task.scheduled.get())
+            .find(|(i, _)| *i >= start)
+            .or_else(|| {
+                self.tasks
+                    .iter()
+                    .enumerate()
+                    .find(|(_, task)| task.scheduled.get())
+            });
+
+        if let Some((i, task)) = task {
+            self.next_task.set(i + 1);
+            // Unscheduled first, as a finishing task may start its next
+            // computation from a client callback.
+            task.scheduled.set(false);
+            task.cancelled.set(false);
+            let done = task
+                .client
+                .map_or(true, |client| client.run_chunk(self.budget));
+            if !done && !task.cancelled.get() {
+                task.scheduled.set(true);
+            }
+        }
+
+        if self.tasks.iter().any(|task| task.scheduled.get()) {
+            self.schedule();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
+
+    extern crate std;
+    use std::boxed::Box;
+
+    /// Takes `chunks` chunks, and cancels its task from the first one if
+    /// `cancel` is set.
+    struct Counter {
+        task: OptionalCell<&'static ExecutorTask<'static>>,
+        chunks: Cell<usize>,
+        ran: Cell<usize>,
+        cancel: bool,
+    }
+
+    impl ChunkedTask for Counter {
+        fn run_chunk(&self, _budget: u32) -> bool {
+            self.ran.set(self.ran.get() + 1);
+            if self.cancel {
+                self.task.map(|task| task.cancel());
+            }
+            self.ran.get() >= self.chunks.get()
+        }
+    }
+
+    fn setup(
+        chunks: usize,
+        cancel: bool,
+    ) -> (
+        &'static ChunkedExecutor<'static>,
+        DeferredCallHandle,
+        &'static ExecutorTask<'static>,
+        &'static Counter,
+    ) {
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let deferred_caller = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let executor = Box::leak(Box::new(ChunkedExecutor::new(deferred_caller, 1000)));
+        let handle = deferred_caller.register(executor).unwrap();
+        executor.initialize_callback_handle(handle);
+        let task = Box::leak(Box::new(ExecutorTask::new(executor)));
+        task.setup();
+        let counter = Box::leak(Box::new(Counter {
+            task: OptionalCell::new(task),
+            chunks: Cell::new(chunks),
+            ran: Cell::new(0),
+            cancel: cancel,
+        }));
+        task.set_client(counter);
+        (executor, handle, task, counter)
+    }
+
+    #[test]
+    fn test_runs_until_done() {
+        let (executor, handle, task, counter) = setup(3, false);
+        task.schedule();
+        for _ in 0..5 {
+            executor.call(handle);
+        }
+        assert_eq!(counter.ran.get(), 3);
+        assert!(!task.is_scheduled());
+    }
+
+    #[test]
+    fn test_cancel_from_chunk() {
+        let (executor, handle, task, counter) = setup(3, true);
+        task.schedule();
+        executor.call(handle);
+        assert!(!task.is_scheduled());
+        executor.call(handle);
+        assert_eq!(counter.ran.get(), 1);
+
+        // Scheduling again after the cancel runs it again.
+        task.schedule();
+        executor.call(handle);
+        assert_eq!(counter.ran.get(), 2);
+    }
+}
@@ -0,0 +1,399 @@
+//! Stores a small record of board configuration, such as the radio region,
+//! device EUIs and calibration values, so that it survives a failed write.
+//!
+//! The record is kept in two flash pages, or slots. Each write goes to the
+//! slot that does not hold the current record, with a generation number one
+//! higher than the current one and a CRC-32 over the whole slot. The current
+//! record is the valid slot with the newest generation, so a write that is
+//! cut short by a reset leaves the previous record in place. `rollback()`
+//! erases the current record to return to the previous one.
+//!
+//! Reading does not go through the flash HIL: `read_record()` checks the
+//! slots where they are mapped in memory, so the kernel can read the record
+//! while the board is set up, before any storage capsule is running.
+//!
+//! The contents of the record are up to the board. `find_item()` looks up an
+//! item in records made of tag-length-value items, one byte each for the tag
+//! and the length.
+//!
+//! Slot layout
+//! -----------
+//!
+//! | Offset | Size     | Field                           |
+//! |--------|----------|---------------------------------|
+//! | 0      | 4        | Magic, `TCFG`                   |
+//! | 4      | 4        | Generation                      |
+//! | 8      | 4        | Length of the data              |
+//! | 12     | length   | Data                            |
+//! | ...    | 4        | CRC-32 of everything before it  |
+//!
+//! All fields are little endian.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! // The two pages set aside for the record in the linker script, as they
+//! // are mapped in memory.
+//! let slots = unsafe {
+//!     [
+//!         core::slice::from_raw_parts(&_sconfig0 as *const u8, PAGE_SIZE),
+//!         core::slice::from_raw_parts(&_sconfig1 as *const u8, PAGE_SIZE),
+//!     ]
+//! };
+//! let region = capsules::config_record::read_record(slots)
+//!     .and_then(|record| capsules::config_record::find_item(record, TAG_REGION));
+//!
+//! let config_record = static_init!(
+//!     capsules::config_record::ConfigRecord<'static, nrf52840::nvmc::Nvmc>,
+//!     capsules::config_record::ConfigRecord::new(
+//!         &base_peripherals.nvmc,
+//!         &create_capability!(capabilities::RawFlashAccessCapability),
+//!         [CONFIG_PAGE0, CONFIG_PAGE1],
+//!         slots,
+//!         &mut CONFIG_PAGE_BUFFER
+//!     )
+//! );
+//! kernel::hil::flash::HasClient::set_client(&base_peripherals.nvmc, config_record);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+
+use kernel::capabilities::RawFlashAccessCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ErrorCode;
+
+use tickv::crc32::Crc;
+
+/// Marks a slot that holds a record, "TCFG".
+const MAGIC: u32 = 0x4746_4354;
+
+const HEADER_LEN: usize = 12;
+const CRC_LEN: usize = 4;
+
+/// Longest record that fits in slots of `slot_len` bytes.
+pub fn max_record_len(slot_len: usize) -> usize {
+    slot_len.saturating_sub(HEADER_LEN + CRC_LEN)
+}
+
+fn read_u32(slot: &[u8], offset: usize) -> u32 {
+    slot.get(offset..offset + 4)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map_or(0, u32::from_le_bytes)
+}
+
+/// The CRC-32 of `data`, the one TicKV uses.
+fn crc32(data: &[u8]) -> u32 {
+    let crc = Crc::new();
+    let mut digest = crc.digest();
+    digest.update(data);
+    digest.finalise()
+}
+
+/// The generation and data of the record in `slot`, if it holds a valid one.
+fn parse_slot(slot: &[u8]) -> Option<(u32, &[u8])> {
+    if read_u32(slot, 0) != MAGIC {
+        return None;
+    }
+    let len = read_u32(slot, 8) as usize;
+    if len > max_record_len(slot.len()) {
+        return None;
+    }
+    let crc_offset = HEADER_LEN + len;
+    let crc = crc32(&slot[..crc_offset]);
+    if crc != read_u32(slot, crc_offset) {
+        return None;
+    }
+    Some((read_u32(slot, 4), &slot[HEADER_LEN..crc_offset]))
+}
+
+/// Fill `buf` with a slot holding `data` as generation `generation`. `data`
+/// must be at most `max_record_len(buf.len())` long.
+fn encode_slot(buf: &mut [u8], generation: u32, data: &[u8]) {
+    let len = data.len();
+    for byte in buf.iter_mut() {
+        *byte = 0xff;
+    }
+    buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    buf[4..8].copy_from_slice(&generation.to_le_bytes());
+    buf[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+    buf[HEADER_LEN..HEADER_LEN + len].copy_from_slice(data);
+    let crc = crc32(&buf[..HEADER_LEN + len]);
+    buf[HEADER_LEN + len..HEADER_LEN + len + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Whether generation `a` is newer than `b`, allowing for wrap around.
+fn newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// The index, generation and data of the current record in `slots`.
+fn current(slots: [&[u8]; 2]) -> Option<(usize, u32, &[u8])> {
+    match (parse_slot(slots[0]), parse_slot(slots[1])) {
+        (Some((gen0, data0)), Some((gen1, data1))) => {
+            if newer(gen1, gen0) {
+                Some((1, gen1, data1))
+            } else {
+                Some((0, gen0, data0))
+            }
+        }
+        (Some((gen, data)), None) => Some((0, gen, data)),
+        (None, Some((gen, data))) => Some((1, gen, data)),
+        (None, None) => None,
+    }
+}
+
+/// The data of the current record in `slots`, the two slots as they are
+/// mapped in memory, or `None` if neither holds a valid record.
+pub fn read_record(slots: [&[u8]; 2]) -> Option<&[u8]> {
+    current(slots).map(|(_, _, data)| data)
+}
+
+/// The value of the first item with `tag` in a record of tag-length-value
+/// items.
+pub fn find_item(record: &[u8], tag: u8) -> Option<&[u8]> {
+    let mut offset = 0;
+    while offset + 2 <= record.len() {
+        let len = record[offset + 1] as usize;
+        let value = record.get(offset + 2..offset + 2 + len)?;
+        if record[offset] == tag {
+            return Some(value);
+        }
+        offset += 2 + len;
+    }
+    None
+}
+
+pub trait ConfigRecordClient {
+    /// Called when a `write()` or `rollback()` finished. The write failed if
+    /// the new record does not read back correctly, in which case the
+    /// previous one is still current.
+    fn done(&self, result: Result<(), ErrorCode>);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Writing(usize),
+    Erasing,
+}
+
+pub struct ConfigRecord<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    /// The flash pages of the two slots.
+    pages: [usize; 2],
+    /// The two slots, as they are mapped in memory.
+    slots: [&'a [u8]; 2],
+    buffer: TakeCell<'static, F::Page>,
+    state: Cell<State>,
+    client: OptionalCell<&'a dyn ConfigRecordClient>,
+}
+
+impl<'a, F: hil::flash::Flash> ConfigRecord<'a, F> {
+    /// `slots` must be where `pages` of `flash` are mapped in memory. Writing
+    /// the record changes how the board is set up, so it takes the
+    /// `RawFlashAccessCapability`.
+    pub fn new(
+        flash: &'a F,
+        _capability: &dyn RawFlashAccessCapability,
+        pages: [usize; 2],
+        slots: [&'a [u8]; 2],
+        buffer: &'static mut F::Page,
+    ) -> ConfigRecord<'a, F> {
+        ConfigRecord {
+            flash: flash,
+            pages: pages,
+            slots: slots,
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn ConfigRecordClient) {
+        self.client.set(client);
+    }
+
+    /// The data of the current record.
+    pub fn read(&self) -> Option<&'a [u8]> {
+        read_record(self.slots)
+    }
+
+    /// Generation of the current record.
+    pub fn generation(&self) -> Option<u32> {
+        current(self.slots).map(|(_, generation, _)| generation)
+    }
+
+    /// Make `data` the current record. Returns `SIZE` if it does not fit in a
+    /// slot and `BUSY` if a write or rollback is in progress.
+    pub fn write(&self, data: &[u8]) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let (slot, generation) = match current(self.slots) {
+            Some((slot, generation, _)) => (1 - slot, generation.wrapping_add(1)),
+            None => (0, 1),
+        };
+
+        let page = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        let buf = page.as_mut();
+        let len = data.len();
+        if len > max_record_len(cmp::min(buf.len(), self.slots[slot].len())) {
+            self.buffer.replace(page);
+            return Err(ErrorCode::SIZE);
+        }
+        encode_slot(buf, generation, data);
+
+        match self.flash.write_page(self.pages[slot], page) {
+            Ok(()) => {
+                self.state.set(State::Writing(slot));
+                Ok(())
+            }
+            Err((e, page)) => {
+                self.buffer.replace(page);
+                Err(e)
+            }
+        }
+    }
+
+    /// Erase the current record, so the previous one becomes current again.
+    /// Returns `INVAL` if there is no valid previous record.
+    pub fn rollback(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let slot = match current(self.slots) {
+            Some((slot, _, _)) if parse_slot(self.slots[1 - slot]).is_some() => slot,
+            _ => return Err(ErrorCode::INVAL),
+        };
+        self.flash.erase_page(self.pages[slot])?;
+        self.state.set(State::Erasing);
+        Ok(())
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for ConfigRecord<'_, F> {
+    fn read_complete(&self, _buffer: &'static mut F::Page, _error: hil::flash::Error) {}
+
+    fn write_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.buffer.replace(buffer);
+        if let State::Writing(slot) = self.state.get() {
+            self.state.set(State::Idle);
+            // Check what actually reached the flash.
+            let result = if error == hil::flash::Error::CommandComplete
+                && parse_slot(self.slots[slot]).is_some()
+            {
+                Ok(())
+            } else {
+                Err(ErrorCode::FAIL)
+            };
+            self.client.map(|client| client.done(result));
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        if self.state.get() == State::Erasing {
+            self.state.set(State::Idle);
+            let result = if error == hil::flash::Error::CommandComplete {
+                Ok(())
+            } else {
+                Err(ErrorCode::FAIL)
+            };
+            self.client.map(|client| client.done(result));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_slot, find_item, max_record_len, parse_slot, read_record};
+
+    const SLOT_LEN: usize = 64;
+    const ERASED: [u8; SLOT_LEN] = [0xff; SLOT_LEN];
+    const DATA: [u8; 7] = [1, 2, 0xaa, 0xbb, 2, 1, 0xcc];
+
+    fn slot(generation: u32, data: &[u8]) -> [u8; SLOT_LEN] {
+        let mut slot = ERASED;
+        encode_slot(&mut slot, generation, data);
+        slot
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let slot0 = slot(3, &DATA);
+        assert_eq!(parse_slot(&slot0), Some((3, &DATA[..])));
+        assert_eq!(read_record([&slot0, &ERASED]), Some(&DATA[..]));
+        assert_eq!(read_record([&ERASED, &slot0]), Some(&DATA[..]));
+        assert_eq!(read_record([&ERASED, &ERASED]), None);
+
+        let record = read_record([&slot0, &ERASED]).unwrap();
+        assert_eq!(find_item(record, 1), Some(&[0xaa, 0xbb][..]));
+        assert_eq!(find_item(record, 2), Some(&[0xcc][..]));
+        assert_eq!(find_item(record, 3), None);
+
+        // The largest record that fits still round-trips.
+        let full = [0x5a; SLOT_LEN];
+        let full = &full[..max_record_len(SLOT_LEN)];
+        assert_eq!(parse_slot(&slot(1, full)), Some((1, full)));
+    }
+
+    #[test]
+    fn test_bad_crc() {
+        let good = slot(3, &DATA);
+
+        let mut bad_data = good;
+        bad_data[12] ^= 0x01;
+        assert_eq!(parse_slot(&bad_data), None);
+
+        let mut bad_crc = good;
+        bad_crc[12 + DATA.len()] ^= 0x80;
+        assert_eq!(parse_slot(&bad_crc), None);
+
+        let mut bad_generation = good;
+        bad_generation[4] ^= 0x01;
+        assert_eq!(parse_slot(&bad_generation), None);
+
+        // A bad copy does not hide the good one.
+        assert_eq!(read_record([&bad_data, &slot(2, &[9])]), Some(&[9][..]));
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let good = slot(3, &DATA);
+        // A write cut short leaves the rest of the slot erased.
+        for written in 0..12 + DATA.len() + 4 {
+            let mut torn = ERASED;
+            torn[..written].copy_from_slice(&good[..written]);
+            assert_eq!(parse_slot(&torn), None, "{} bytes written", written);
+        }
+
+        // A length past the end of the slot is rejected rather than read.
+        let mut too_long = good;
+        too_long[8..12].copy_from_slice(&(SLOT_LEN as u32).to_le_bytes());
+        assert_eq!(parse_slot(&too_long), None);
+
+        // The previous record stays current when the newer write is torn.
+        let mut torn = ERASED;
+        torn[..20].copy_from_slice(&slot(4, &[7; 16])[..20]);
+        assert_eq!(read_record([&good, &torn]), Some(&DATA[..]));
+    }
+
+    #[test]
+    fn test_newer_copy() {
+        let old = slot(5, &[1]);
+        let new = slot(6, &[2]);
+        assert_eq!(read_record([&old, &new]), Some(&[2][..]));
+        assert_eq!(read_record([&new, &old]), Some(&[2][..]));
+
+        // Generations wrap around.
+        let old = slot(u32::MAX, &[1]);
+        let new = slot(0, &[2]);
+        assert_eq!(read_record([&old, &new]), Some(&[2][..]));
+        assert_eq!(read_record([&new, &old]), Some(&[2][..]));
+    }
+}
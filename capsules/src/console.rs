@@ -15,8 +15,10 @@
 //!                  115200,
 //!                  &mut console::WRITE_BUF,
 //!                  &mut console::READ_BUF,
+//!                  &mut console::READ_QUEUE,
 //!                  board_kernel.create_grant(&grant_cap)));
 //! hil::uart::UART::set_client(&usart::USART0, console);
+//! console.initialize();
 //! ```
 //!
 //! Usage
@@ -36,11 +38,47 @@
 //! When the buffer has been written successfully, the buffer is released from
 //! the driver. Successive writes must call `allow` each time a buffer is to be
 //! written.
+//!
+//! Only one process receives at a time. If a process asks to receive while
+//! another is receiving, its receive is queued and starts once the earlier
+//! ones complete, so it does not need to retry. Up to `READ_QUEUE.len() - 1`
+//! processes can be queued; past that the command fails with `NOMEM`.
+//!
+//! Receive errors
+//! --------------
+//!
+//! If the UART detects a line error while receiving, the receive ends early.
+//! The read callback is then called with a `FAIL` status, the number of bytes
+//! received before the error (which have been copied into the read buffer),
+//! and the kind of error as the third argument:
+//!
+//! - `1`: Parity error
+//! - `2`: Framing error
+//! - `3`: Overrun error, bytes after the reported ones were lost
+//! - `4`: Break condition
+//!
+//! For all other completions the third argument is `0`.
+//!
+//! Line mode
+//! ---------
+//!
+//! Command `4` receives a whole line into the read buffer, with one upcall
+//! for the line instead of one receive per chunk of bytes. The kernel removes
+//! the last byte of the line on a backspace (`0x08`) or delete (`0x7f`), and
+//! ends the line on a carriage return or a line feed, which is not stored. A
+//! line feed right after a carriage return is ignored, so `\r\n` ends one
+//! line. The line also ends once it fills the length passed to the command.
+//! The kernel does not echo what it receives.
+//!
+//! The upcall subscribed with number `3` gets the status and the length of
+//! the line. If the receive is cancelled or fails, it gets the part of the
+//! line received so far, with the error as for other receives.
 
 use core::convert::TryFrom;
 use core::{cmp, mem};
 
 use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::reservation::{BufferReservation, ReservationClient};
 use kernel::hil::uart;
 use kernel::{CommandReturn, Driver};
 use kernel::{ErrorCode, Grant, ProcessId, Upcall};
@@ -61,10 +99,48 @@ pub struct App {
     read_callback: Upcall,
     read_buffer: ReadWriteAppSlice,
     read_len: usize,
+    pending_read: bool,
+
+    line_callback: Upcall,
+    /// Whether the receive in progress or pending is of a line.
+    line_mode: bool,
+    /// Length of the line received so far.
+    line_len: usize,
+    /// Whether the last line ended with a carriage return, so a line feed
+    /// right after it is ignored.
+    line_cr: bool,
+}
+
+impl App {
+    /// The callback of the receive in progress or pending, which ends line
+    /// mode.
+    fn end_receive(&mut self) -> &mut Upcall {
+        if mem::replace(&mut self.line_mode, false) {
+            &mut self.line_callback
+        } else {
+            &mut self.read_callback
+        }
+    }
+}
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// The kind of line error passed to the read callback.
+fn line_error(error: uart::Error) -> usize {
+    match error {
+        uart::Error::ParityError => 1,
+        uart::Error::FramingError => 2,
+        uart::Error::OverrunError => 3,
+        uart::Error::BreakError => 4,
+        _ => 0,
+    }
 }
 
 pub static mut WRITE_BUF: [u8; 64] = [0; 64];
 pub static mut READ_BUF: [u8; 64] = [0; 64];
+/// Processes waiting for `READ_BUF`.
+pub static mut READ_QUEUE: [Option<ProcessId>; 8] = [None; 8];
 
 pub struct Console<'a> {
     uart: &'a dyn uart::UartData<'a>,
@@ -72,7 +148,7 @@ pub struct Console<'a> {
     tx_in_progress: OptionalCell<ProcessId>,
     tx_buffer: TakeCell<'static, [u8]>,
     rx_in_progress: OptionalCell<ProcessId>,
-    rx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: BufferReservation<'a>,
 }
 
 impl<'a> Console<'a> {
@@ -80,6 +156,7 @@ impl<'a> Console<'a> {
         uart: &'a dyn uart::UartData<'a>,
         tx_buffer: &'static mut [u8],
         rx_buffer: &'static mut [u8],
+        rx_queue: &'a mut [Option<ProcessId>],
         grant: Grant<App>,
     ) -> Console<'a> {
         Console {
@@ -88,10 +165,16 @@ impl<'a> Console<'a> {
             tx_in_progress: OptionalCell::empty(),
             tx_buffer: TakeCell::new(tx_buffer),
             rx_in_progress: OptionalCell::empty(),
-            rx_buffer: TakeCell::new(rx_buffer),
+            rx_buffer: BufferReservation::new(rx_buffer, rx_queue),
         }
     }
 
+    /// Must be called once the console is in its final place, so queued
+    /// receives can be started.
+    pub fn initialize(&'a self) {
+        self.rx_buffer.set_client(self);
+    }
+
     /// Internal helper function for setting up a new send transaction
     fn send_new(&self, app_id: ProcessId, app: &mut App, len: usize) -> Result<(), ErrorCode> {
         app.write_len = cmp::min(len, app.write_buffer.len());
@@ -148,29 +231,190 @@ impl<'a> Console<'a> {
         }
     }
 
-    /// Internal helper function for starting a receive operation
-    fn receive_new(&self, app_id: ProcessId, app: &mut App, len: usize) -> Result<(), ErrorCode> {
-        if self.rx_buffer.is_none() {
-            // For now, we tolerate only one concurrent receive operation on this console.
-            // Competing apps will have to retry until success.
+    /// Internal helper function for starting a receive operation, of a line
+    /// if `line` is set
+    fn receive_new(
+        &self,
+        app_id: ProcessId,
+        app: &mut App,
+        len: usize,
+        line: bool,
+    ) -> Result<(), ErrorCode> {
+        if self.rx_in_progress.contains(&app_id) {
             return Err(ErrorCode::BUSY);
         }
 
         let read_len = cmp::min(len, app.read_buffer.len());
-        if read_len > self.rx_buffer.map_or(0, |buf| buf.len()) {
+        if line {
+            // Lines are received a byte at a time straight into the read
+            // buffer, so are only limited by its length
+            if read_len == 0 {
+                return Err(ErrorCode::INVAL);
+            }
+        } else if read_len > self.rx_buffer.buffer_len() {
             // For simplicity, impose a small maximum receive length
             // instead of doing incremental reads
-            Err(ErrorCode::INVAL)
+            return Err(ErrorCode::INVAL);
+        }
+
+        // A process with a receive queued is still queued, so gets `BUSY`,
+        // unless the buffer was left for it to take here.
+        let reserved = self.rx_buffer.try_reserve(app_id).map_err(|e| match e {
+            ErrorCode::ALREADY => ErrorCode::BUSY,
+            e => e,
+        })?;
+        app.read_len = read_len;
+        app.line_mode = line;
+        app.line_len = 0;
+        app.pending_read = reserved.is_none();
+        match reserved {
+            Some(buffer) => self
+                .receive(app_id, Self::uart_read_len(app), buffer)
+                .map_err(|(e, buffer)| {
+                    self.rx_buffer.release(buffer);
+                    e
+                }),
+            None => {
+                // Another process is receiving. This receive starts once the
+                // buffer is handed to us in `reserved()`.
+                Ok(())
+            }
+        }
+    }
+
+    /// How many bytes to ask the UART for at a time.
+    fn uart_read_len(app: &App) -> usize {
+        if app.line_mode {
+            1
         } else {
-            // Note: We have ensured above that rx_buffer is present
-            app.read_len = read_len;
-            self.rx_buffer.take().map(|buffer| {
-                self.rx_in_progress.set(app_id);
-                let _ = self.uart.receive_buffer(buffer, app.read_len);
-            });
-            Ok(())
+            app.read_len
         }
     }
+
+    /// Add `byte` to the line being received into the read buffer. Returns
+    /// whether the line is complete.
+    fn line_byte(app: &mut App, byte: u8) -> bool {
+        let after_cr = mem::replace(&mut app.line_cr, byte == b'\r');
+        match byte {
+            b'\r' => true,
+            b'\n' => !(after_cr && app.line_len == 0),
+            BACKSPACE | DELETE => {
+                app.line_len = app.line_len.saturating_sub(1);
+                false
+            }
+            _ => {
+                let len = app.line_len;
+                app.read_buffer.mut_map_or((), |data| {
+                    if let Some(slot) = data.get_mut(len) {
+                        *slot = byte;
+                    }
+                });
+                app.line_len += 1;
+                app.line_len >= app.read_len
+            }
+        }
+    }
+
+    /// Handle the end of a one byte receive of `appid` in line mode: add the
+    /// byte to the line and receive the next one, or call the line callback
+    /// if the line is complete or the receive failed.
+    ///
+    /// Returns `buffer` if the receive is not in line mode.
+    fn line_received(
+        &self,
+        appid: ProcessId,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rcode: Result<(), ErrorCode>,
+        error: uart::Error,
+    ) -> Option<&'static mut [u8]> {
+        let mut buffer = Some(buffer);
+        let mut release = None;
+        let _ = self.apps.enter(appid, |app| {
+            if !app.line_mode {
+                return;
+            }
+            let rx_buffer = match buffer.take() {
+                Some(rx_buffer) => rx_buffer,
+                None => return,
+            };
+
+            let result = if error != uart::Error::None || rx_len == 0 {
+                if error == uart::Error::Aborted {
+                    Some(rcode)
+                } else {
+                    Some(Err(ErrorCode::FAIL))
+                }
+            } else if Self::line_byte(app, rx_buffer[0]) {
+                Some(Ok(()))
+            } else {
+                None
+            };
+
+            let result = match result {
+                Some(result) => {
+                    release = Some(rx_buffer);
+                    result
+                }
+                None => match self.uart.receive_buffer(rx_buffer, 1) {
+                    Ok(()) => return,
+                    Err((e, rx_buffer)) => {
+                        release = Some(rx_buffer);
+                        Err(e)
+                    }
+                },
+            };
+            self.rx_in_progress.clear();
+            let line_len = app.line_len;
+            app.end_receive().schedule(
+                kernel::into_statuscode(result),
+                line_len,
+                line_error(error),
+            );
+        });
+        // Start the next queued receive, if any, once out of this grant.
+        release.map(|rx_buffer| self.rx_buffer.release(rx_buffer));
+        buffer
+    }
+
+    /// Internal helper function for receiving into the reserved buffer.
+    fn receive(
+        &self,
+        app_id: ProcessId,
+        read_len: usize,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.rx_in_progress.set(app_id);
+        self.uart.receive_buffer(buffer, read_len).map_err(|e| {
+            self.rx_in_progress.clear();
+            e
+        })
+    }
+}
+
+impl ReservationClient for Console<'_> {
+    fn reserved(
+        &self,
+        processid: ProcessId,
+        buffer: &'static mut [u8],
+    ) -> Option<&'static mut [u8]> {
+        let mut buffer = Some(buffer);
+        let _ = self.apps.enter(processid, |app| {
+            if !app.pending_read {
+                return;
+            }
+            app.pending_read = false;
+            if let Some(rx_buffer) = buffer.take() {
+                let read_len = Self::uart_read_len(app);
+                if let Err((e, rx_buffer)) = self.receive(processid, read_len, rx_buffer) {
+                    buffer = Some(rx_buffer);
+                    app.end_receive()
+                        .schedule(kernel::into_statuscode(Err(e)), 0, 0);
+                }
+            }
+        });
+        buffer
+    }
 }
 
 impl Driver for Console<'_> {
@@ -234,6 +478,8 @@ impl Driver for Console<'_> {
     /// ### `subscribe_num`
     ///
     /// - `1`: Write buffer completed callback
+    /// - `2`: Read buffer completed callback
+    /// - `3`: Line received callback
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -257,6 +503,14 @@ impl Driver for Console<'_> {
                     })
                     .map_err(ErrorCode::from)
             }
+            3 => {
+                // getline done
+                self.apps
+                    .enter(app_id, |app| {
+                        mem::swap(&mut app.line_callback, &mut callback);
+                    })
+                    .map_err(ErrorCode::from)
+            }
             _ => Err(ErrorCode::NOSUPPORT),
         };
 
@@ -275,9 +529,14 @@ impl Driver for Console<'_> {
     /// - `1`: Transmits a buffer passed via `allow`, up to the length
     ///        passed in `arg1`
     /// - `2`: Receives into a buffer passed via `allow`, up to the length
-    ///        passed in `arg1`
+    ///        passed in `arg1`. If another process is receiving, the receive
+    ///        is queued behind it.
     /// - `3`: Cancel any in progress receives and return (via callback)
-    ///        what has been received so far.
+    ///        what has been received so far. A queued receive is removed
+    ///        from the queue and its callback called with `CANCEL`.
+    /// - `4`: Receives a line into a buffer passed via `allow`, up to the
+    ///        length passed in `arg1`. If another process is receiving, the
+    ///        receive is queued behind it.
     fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
         let res = match cmd_num {
             0 => Ok(Ok(())),
@@ -292,13 +551,34 @@ impl Driver for Console<'_> {
                 // getnstr
                 let len = arg1;
                 self.apps
-                    .enter(appid, |app| self.receive_new(appid, app, len))
+                    .enter(appid, |app| self.receive_new(appid, app, len, false))
                     .map_err(ErrorCode::from)
             }
             3 => {
                 // Abort RX
-                let _ = self.uart.receive_abort();
-                Ok(Ok(()))
+                if self.rx_buffer.cancel(appid) {
+                    self.apps
+                        .enter(appid, |app| {
+                            app.pending_read = false;
+                            app.end_receive().schedule(
+                                kernel::into_statuscode(Err(ErrorCode::CANCEL)),
+                                0,
+                                0,
+                            );
+                            Ok(())
+                        })
+                        .map_err(ErrorCode::from)
+                } else {
+                    let _ = self.uart.receive_abort();
+                    Ok(Ok(()))
+                }
+            }
+            4 => {
+                // getline
+                let len = arg1;
+                self.apps
+                    .enter(appid, |app| self.receive_new(appid, app, len, true))
+                    .map_err(ErrorCode::from)
             }
             _ => Err(ErrorCode::NOSUPPORT),
         };
@@ -391,6 +671,14 @@ impl uart::ReceiveClient for Console<'_> {
         rcode: Result<(), ErrorCode>,
         error: uart::Error,
     ) {
+        let buffer = match self.rx_in_progress.extract() {
+            Some(appid) => match self.line_received(appid, buffer, rx_len, rcode, error) {
+                Some(buffer) => buffer,
+                None => return,
+            },
+            None => buffer,
+        };
+
         self.rx_in_progress
             .take()
             .map(|appid| {
@@ -399,72 +687,80 @@ impl uart::ReceiveClient for Console<'_> {
                         // An iterator over the returned buffer yielding only the first `rx_len`
                         // bytes
                         let rx_buffer = buffer.iter().take(rx_len);
-                        match error {
-                            uart::Error::None | uart::Error::Aborted => {
-                                // Receive some bytes, signal error type and return bytes to process buffer
-                                let count = app.read_buffer.mut_map_or(-1, |data| {
-                                    let mut c = 0;
-                                    for (a, b) in data.iter_mut().zip(rx_buffer) {
-                                        c = c + 1;
-                                        *a = *b;
-                                    }
-                                    c
-                                });
-
-                                // Make sure we report the same number
-                                // of bytes that we actually copied into
-                                // the app's buffer. This is defensive:
-                                // we shouldn't ever receive more bytes
-                                // than will fit in the app buffer since
-                                // we use the app_buffer's length when
-                                // calling `receive()`. However, a buggy
-                                // lower layer could return more bytes
-                                // than we asked for, and we don't want
-                                // to propagate that length error to
-                                // userspace. However, we do return an
-                                // error code so that userspace knows
-                                // something went wrong.
-                                //
-                                // If count < 0 this means the buffer
-                                // disappeared: return NOMEM.
-                                let (ret, received_length) = if count < 0 {
-                                    (Err(ErrorCode::NOMEM), 0)
-                                } else if rx_len > app.read_buffer.len() {
-                                    // Return `SIZE` indicating that
-                                    // some received bytes were dropped.
-                                    // We report the length that we
-                                    // actually copied into the buffer,
-                                    // but also indicate that there was
-                                    // an issue in the kernel with the
-                                    // receive.
-                                    (Err(ErrorCode::SIZE), app.read_buffer.len())
-                                } else {
-                                    // This is the normal and expected
-                                    // case.
-                                    (rcode, rx_len)
-                                };
-
-                                app.read_callback.schedule(
-                                    kernel::into_statuscode(ret),
-                                    received_length,
-                                    0,
-                                );
-                            }
-                            _ => {
-                                // Some UART error occurred
-                                app.read_callback.schedule(
-                                    kernel::into_statuscode(Err(ErrorCode::FAIL)),
-                                    0,
-                                    0,
-                                );
-                            }
+                        // Line errors end the receive early, but the bytes
+                        // received before the error are still valid and are
+                        // passed up along with the kind of error.
+                        let line_error = line_error(error);
+                        if line_error == 0
+                            && error != uart::Error::None
+                            && error != uart::Error::Aborted
+                        {
+                            // Some other UART error occurred
+                            app.read_callback.schedule(
+                                kernel::into_statuscode(Err(ErrorCode::FAIL)),
+                                0,
+                                0,
+                            );
+                            return;
                         }
+
+                        // Receive some bytes, signal error type and return bytes to process buffer
+                        let count = app.read_buffer.mut_map_or(-1, |data| {
+                            let mut c = 0;
+                            for (a, b) in data.iter_mut().zip(rx_buffer) {
+                                c = c + 1;
+                                *a = *b;
+                            }
+                            c
+                        });
+
+                        // Make sure we report the same number
+                        // of bytes that we actually copied into
+                        // the app's buffer. This is defensive:
+                        // we shouldn't ever receive more bytes
+                        // than will fit in the app buffer since
+                        // we use the app_buffer's length when
+                        // calling `receive()`. However, a buggy
+                        // lower layer could return more bytes
+                        // than we asked for, and we don't want
+                        // to propagate that length error to
+                        // userspace. However, we do return an
+                        // error code so that userspace knows
+                        // something went wrong.
+                        //
+                        // If count < 0 this means the buffer
+                        // disappeared: return NOMEM.
+                        let (ret, received_length) = if count < 0 {
+                            (Err(ErrorCode::NOMEM), 0)
+                        } else if rx_len > app.read_buffer.len() {
+                            // Return `SIZE` indicating that
+                            // some received bytes were dropped.
+                            // We report the length that we
+                            // actually copied into the buffer,
+                            // but also indicate that there was
+                            // an issue in the kernel with the
+                            // receive.
+                            (Err(ErrorCode::SIZE), app.read_buffer.len())
+                        } else if line_error != 0 {
+                            (Err(ErrorCode::FAIL), rx_len)
+                        } else {
+                            // This is the normal and expected
+                            // case.
+                            (rcode, rx_len)
+                        };
+
+                        app.read_callback.schedule(
+                            kernel::into_statuscode(ret),
+                            received_length,
+                            line_error,
+                        );
                     })
                     .unwrap_or_default();
             })
             .unwrap_or_default();
 
-        // Whatever happens, we want to make sure to replace the rx_buffer for future transactions
-        self.rx_buffer.replace(buffer);
+        // Whatever happens, we want to make sure to release the rx_buffer
+        // for future transactions, starting the next queued receive if any
+        self.rx_buffer.release(buffer);
     }
 }
@@ -0,0 +1,306 @@
+//! Runs inference on the CPU, for boards without an ML accelerator.
+//!
+//! Implements `hil::inference::Inference` for models in the descriptor
+//! format documented in that module. Layers are run one per deferred call,
+//! so that a large model does not keep the kernel from handling interrupts
+//! for the whole run.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let cpu_inference = static_init!(
+//!     capsules::cpu_inference::CpuInference<'static>,
+//!     capsules::cpu_inference::CpuInference::new(
+//!         &mut capsules::cpu_inference::TENSOR_BUF,
+//!         dynamic_deferred_caller
+//!     )
+//! );
+//! cpu_inference.initialize_callback_handle(
+//!     dynamic_deferred_caller.register(cpu_inference).unwrap()
+//! );
+//! ```
+
+use core::cell::Cell;
+use core::convert::TryInto;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::inference::{self, InferenceClient, ModelInfo};
+use kernel::ErrorCode;
+
+/// Largest tensor, input, output or between layers, in bytes.
+pub const MAX_TENSOR_LEN: usize = 256;
+
+/// Holds the input and output of the layer being run.
+pub static mut TENSOR_BUF: [u8; 2 * MAX_TENSOR_LEN] = [0; 2 * MAX_TENSOR_LEN];
+
+const HEADER_LEN: usize = 8;
+const LAYER_HEADER_LEN: usize = 8;
+
+/// A fully connected layer of a model.
+struct Layer {
+    activation: u8,
+    output_len: usize,
+    shift: u8,
+    /// Offset of the weights in the model.
+    weights: usize,
+    /// Offset of the bias in the model.
+    bias: usize,
+    /// Offset of the next layer in the model.
+    next: usize,
+}
+
+/// Parse the layer at `offset` of `model`, which takes `input_len` inputs.
+fn parse_layer(model: &[u8], offset: usize, input_len: usize) -> Result<Layer, ErrorCode> {
+    let header = model
+        .get(offset..offset + LAYER_HEADER_LEN)
+        .ok_or(ErrorCode::INVAL)?;
+    if header[0] != inference::LAYER_FULLY_CONNECTED {
+        return Err(ErrorCode::NOSUPPORT);
+    }
+    let activation = header[1];
+    if activation != inference::ACTIVATION_NONE && activation != inference::ACTIVATION_RELU {
+        return Err(ErrorCode::NOSUPPORT);
+    }
+    let output_len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    if output_len == 0 {
+        return Err(ErrorCode::INVAL);
+    }
+    if output_len > MAX_TENSOR_LEN {
+        return Err(ErrorCode::SIZE);
+    }
+    let weights = offset + LAYER_HEADER_LEN;
+    let bias = weights + input_len * output_len;
+    let next = bias + 4 * output_len;
+    if next > model.len() {
+        return Err(ErrorCode::INVAL);
+    }
+    Ok(Layer {
+        activation,
+        output_len,
+        shift: header[4],
+        weights,
+        bias,
+        next,
+    })
+}
+
+pub struct CpuInference<'a> {
+    model: OptionalCell<&'static [u8]>,
+    info: OptionalCell<ModelInfo>,
+    /// Number of layers of the model.
+    layers: Cell<usize>,
+    tensors: TakeCell<'static, [u8]>,
+    /// Layer to run next, and its offset in the model.
+    layer: Cell<usize>,
+    offset: Cell<usize>,
+    /// Length of the tensor in the current half of `tensors`.
+    tensor_len: Cell<usize>,
+    /// Whether the current tensor is in the second half of `tensors`.
+    second_half: Cell<bool>,
+    running: Cell<bool>,
+    output_ready: Cell<bool>,
+    client: OptionalCell<&'a dyn InferenceClient>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> CpuInference<'a> {
+    pub fn new(
+        tensors: &'static mut [u8],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> CpuInference<'a> {
+        CpuInference {
+            model: OptionalCell::empty(),
+            info: OptionalCell::empty(),
+            layers: Cell::new(0),
+            tensors: TakeCell::new(tensors),
+            layer: Cell::new(0),
+            offset: Cell::new(0),
+            tensor_len: Cell::new(0),
+            second_half: Cell::new(false),
+            running: Cell::new(false),
+            output_ready: Cell::new(false),
+            client: OptionalCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn schedule_layer(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    /// Run the next layer of the model, returning whether it was the last.
+    fn run_layer(&self, model: &[u8], tensors: &mut [u8]) -> Result<bool, ErrorCode> {
+        let input_len = self.tensor_len.get();
+        let layer = parse_layer(model, self.offset.get(), input_len)?;
+        let (first, second) = tensors.split_at_mut(MAX_TENSOR_LEN);
+        let (input, output) = if self.second_half.get() {
+            (second, first)
+        } else {
+            (first, second)
+        };
+
+        let weights = &model[layer.weights..layer.bias];
+        for (o, out) in output[..layer.output_len].iter_mut().enumerate() {
+            let start = layer.bias + 4 * o;
+            let bias = i32::from_le_bytes(model[start..start + 4].try_into().unwrap_or([0; 4]));
+            let row = &weights[o * input_len..(o + 1) * input_len];
+            let mut acc = bias;
+            for (w, x) in row.iter().zip(input[..input_len].iter()) {
+                acc = acc.saturating_add(*w as i8 as i32 * *x as i8 as i32);
+            }
+            acc >>= layer.shift.min(31);
+            if layer.activation == inference::ACTIVATION_RELU && acc < 0 {
+                acc = 0;
+            }
+            *out = acc.max(i8::MIN as i32).min(i8::MAX as i32) as i8 as u8;
+        }
+
+        self.second_half.set(!self.second_half.get());
+        self.tensor_len.set(layer.output_len);
+        self.offset.set(layer.next);
+        self.layer.set(self.layer.get() + 1);
+        Ok(self.layer.get() == self.layers.get())
+    }
+
+    fn current_tensor<'b>(&self, tensors: &'b [u8]) -> &'b [u8] {
+        let start = if self.second_half.get() {
+            MAX_TENSOR_LEN
+        } else {
+            0
+        };
+        &tensors[start..start + self.tensor_len.get()]
+    }
+}
+
+impl<'a> inference::Inference<'a> for CpuInference<'a> {
+    fn set_client(&self, client: &'a dyn InferenceClient) {
+        self.client.set(client);
+    }
+
+    fn load_model(&self, model: &'static [u8]) -> Result<ModelInfo, ErrorCode> {
+        if self.running.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        let header = model.get(..HEADER_LEN).ok_or(ErrorCode::INVAL)?;
+        if header[..4] != inference::MODEL_MAGIC {
+            return Err(ErrorCode::INVAL);
+        }
+        if header[4] != inference::MODEL_VERSION {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        let layers = header[5] as usize;
+        let input_len = u16::from_le_bytes([header[6], header[7]]) as usize;
+        if layers == 0 || input_len == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        if input_len > MAX_TENSOR_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+
+        // Check every layer now, so that runs cannot fail.
+        let mut offset = HEADER_LEN;
+        let mut len = input_len;
+        for _ in 0..layers {
+            let layer = parse_layer(model, offset, len)?;
+            offset = layer.next;
+            len = layer.output_len;
+        }
+        if offset != model.len() {
+            return Err(ErrorCode::INVAL);
+        }
+
+        let info = ModelInfo {
+            input_len: input_len,
+            output_len: len,
+        };
+        self.model.set(model);
+        self.info.set(info);
+        self.layers.set(layers);
+        self.output_ready.set(false);
+        Ok(info)
+    }
+
+    fn set_input(&self, input: &[u8]) -> Result<(), ErrorCode> {
+        if self.running.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        let info = self.info.extract().ok_or(ErrorCode::OFF)?;
+        if input.len() != info.input_len {
+            return Err(ErrorCode::SIZE);
+        }
+        self.tensors.map_or(Err(ErrorCode::FAIL), |tensors| {
+            tensors[..input.len()].copy_from_slice(input);
+            self.second_half.set(false);
+            self.tensor_len.set(input.len());
+            self.output_ready.set(false);
+            Ok(())
+        })
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        if self.running.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        let info = self.info.extract().ok_or(ErrorCode::OFF)?;
+        if self.second_half.get() || self.tensor_len.get() != info.input_len {
+            // No input was set since the last run.
+            return Err(ErrorCode::INVAL);
+        }
+        self.layer.set(0);
+        self.offset.set(HEADER_LEN);
+        self.running.set(true);
+        self.schedule_layer();
+        Ok(())
+    }
+
+    fn get_output(&self, output: &mut [u8]) -> Result<usize, ErrorCode> {
+        if !self.output_ready.get() {
+            return Err(ErrorCode::OFF);
+        }
+        self.tensors.map_or(Err(ErrorCode::FAIL), |tensors| {
+            let tensor = self.current_tensor(tensors);
+            if output.len() < tensor.len() {
+                return Err(ErrorCode::SIZE);
+            }
+            output[..tensor.len()].copy_from_slice(tensor);
+            Ok(tensor.len())
+        })
+    }
+}
+
+impl DynamicDeferredCallClient for CpuInference<'_> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if !self.running.get() {
+            return;
+        }
+        let result = self.model.map_or(Err(ErrorCode::FAIL), |model| {
+            self.tensors.map_or(Err(ErrorCode::FAIL), |tensors| {
+                self.run_layer(model, tensors)
+            })
+        });
+        match result {
+            Ok(false) => self.schedule_layer(),
+            Ok(true) => {
+                self.running.set(false);
+                self.output_ready.set(true);
+                self.client.map(|client| client.inference_done(Ok(())));
+            }
+            Err(e) => {
+                self.running.set(false);
+                self.client.map(|client| client.inference_done(Err(e)));
+            }
+        }
+    }
+}
@@ -0,0 +1,1998 @@
+//! CTAP2 authenticator over the CTAPHID transport.
+//!
+//! Reassembles CTAPHID messages from the 64-byte HID reports, answers the
+//! CTAPHID `INIT`, `PING` and `CANCEL` commands, and runs the CTAP2
+//! `authenticatorMakeCredential`, `authenticatorGetAssertion`,
+//! `authenticatorGetInfo` and `authenticatorReset` commands sent with
+//! `CBOR`.
+//!
+//! Each credential has its own key pair, in a key slot of the signer. The
+//! credential ID is the key slot and the start of the hash of the relying
+//! party ID, which the authenticator checks against the credentials it
+//! created. Credentials are not discoverable, so `GetAssertion` needs an
+//! allow list. Once every key slot holds a credential, new ones are refused
+//! with `CTAP2_ERR_KEY_STORE_FULL` until the authenticator is reset.
+//!
+//! With `set_storage()`, the table of credentials and the signature counter
+//! are kept in nonvolatile storage, and read back by `start()`. A new
+//! credential is only returned once the table with it is written. The
+//! counter is reserved in storage `SIGN_COUNT_RESERVE` signatures at a
+//! time, so it is only written once in that many assertions and still never
+//! goes back after a reset. The keys themselves are kept by the signer,
+//! which needs its own storage to keep them.
+//!
+//! Creating a credential, signing an assertion and resetting all wait for
+//! the user to press a button, for up to 30 seconds, and keep the host
+//! informed with keepalives in the meantime. A press only counts if the
+//! button was released when the wait started, so a button that is stuck or
+//! held down cannot confirm requests. A host that only asks for an
+//! assertion without user presence does not wait, and the assertion says
+//! the user was not present.
+//!
+//! A message split over several packets must arrive with no more than
+//! `TRANSACTION_TIMEOUT_MS` between them, or it is dropped with
+//! `ERR_MSG_TIMEOUT`, so a host that stops halfway does not lock out the
+//! others.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::hil::gpio;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::signature::{self, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use kernel::hil::time;
+use kernel::hil::usb_hid;
+use kernel::ErrorCode;
+
+use super::cbor::{self, Reader, Writer};
+
+const PACKET_LEN: usize = 64;
+/// Data in an initialization packet, after the channel, command and length.
+const INIT_DATA_LEN: usize = PACKET_LEN - 7;
+/// Data in a continuation packet, after the channel and sequence number.
+const CONT_DATA_LEN: usize = PACKET_LEN - 5;
+
+const BROADCAST_CHANNEL: u32 = 0xffff_ffff;
+
+// CTAPHID commands
+const CTAPHID_PING: u8 = 0x81;
+const CTAPHID_INIT: u8 = 0x86;
+const CTAPHID_CBOR: u8 = 0x90;
+const CTAPHID_CANCEL: u8 = 0x91;
+const CTAPHID_KEEPALIVE: u8 = 0xbb;
+const CTAPHID_ERROR: u8 = 0xbf;
+
+/// Keepalive status while waiting for the user.
+const STATUS_UPNEEDED: u8 = 0x02;
+
+// CTAPHID errors
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_INVALID_SEQ: u8 = 0x04;
+const ERR_MSG_TIMEOUT: u8 = 0x05;
+const ERR_CHANNEL_BUSY: u8 = 0x06;
+const ERR_INVALID_CHANNEL: u8 = 0x0b;
+
+const CAPABILITY_CBOR: u8 = 0x04;
+/// The authenticator does not implement the CTAP1 `MSG` command.
+const CAPABILITY_NMSG: u8 = 0x08;
+
+// Authenticator commands
+const CMD_MAKE_CREDENTIAL: u8 = 0x01;
+const CMD_GET_ASSERTION: u8 = 0x02;
+const CMD_GET_INFO: u8 = 0x04;
+const CMD_RESET: u8 = 0x07;
+
+// Status codes
+const CTAP2_OK: u8 = 0x00;
+const CTAP1_ERR_INVALID_COMMAND: u8 = 0x01;
+const CTAP1_ERR_INVALID_LENGTH: u8 = 0x03;
+const CTAP2_ERR_CBOR_UNEXPECTED_TYPE: u8 = 0x11;
+const CTAP2_ERR_INVALID_CBOR: u8 = 0x12;
+const CTAP2_ERR_MISSING_PARAMETER: u8 = 0x14;
+const CTAP2_ERR_CREDENTIAL_EXCLUDED: u8 = 0x19;
+const CTAP2_ERR_UNSUPPORTED_ALGORITHM: u8 = 0x26;
+const CTAP2_ERR_OPERATION_DENIED: u8 = 0x27;
+const CTAP2_ERR_KEY_STORE_FULL: u8 = 0x28;
+const CTAP2_ERR_UNSUPPORTED_OPTION: u8 = 0x2b;
+const CTAP2_ERR_INVALID_OPTION: u8 = 0x2c;
+const CTAP2_ERR_KEEPALIVE_CANCEL: u8 = 0x2d;
+const CTAP2_ERR_NO_CREDENTIALS: u8 = 0x2e;
+const CTAP2_ERR_USER_ACTION_TIMEOUT: u8 = 0x2f;
+const CTAP2_ERR_PIN_AUTH_INVALID: u8 = 0x33;
+const CTAP2_ERR_PIN_NOT_SET: u8 = 0x35;
+const CTAP1_ERR_OTHER: u8 = 0x7f;
+
+/// COSE algorithm identifier of ES256: ECDSA over P-256 with SHA-256.
+const COSE_ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// The most credentials, one per key slot.
+pub const MAX_CREDENTIALS: usize = 8;
+
+const CREDENTIAL_ID_LEN: usize = 16;
+/// Authenticator data without attested credential data: the relying party
+/// ID hash, the flags and the signature counter.
+const AUTH_DATA_LEN: usize = 37;
+/// A P-256 public key as a COSE key.
+const COSE_KEY_LEN: usize = 77;
+/// Authenticator data with the AAGUID, credential ID and public key.
+const ATTESTED_AUTH_DATA_LEN: usize = AUTH_DATA_LEN + 16 + 2 + CREDENTIAL_ID_LEN + COSE_KEY_LEN;
+/// Longest DER encoding of a P-256 signature.
+const DER_SIGNATURE_LEN: usize = 72;
+
+/// How long to wait for the user to press the button.
+const USER_PRESENCE_TIMEOUT_MS: u32 = 30_000;
+/// How often to send a keepalive while waiting for the user.
+const KEEPALIVE_MS: u32 = 100;
+/// How long to wait for the next packet of a message.
+pub const TRANSACTION_TIMEOUT_MS: u32 = 500;
+
+/// Marks the table of credentials in storage, so that erased or zeroed
+/// storage reads back as an empty table.
+const TABLE_MAGIC: [u8; 4] = *b"CTAP";
+/// The table of credentials in storage: the marker, the signature counter
+/// reserved so far, and the relying party ID hash of the credential in each
+/// key slot, or zeros for a free slot.
+pub const TABLE_LEN: usize = 8 + 32 * MAX_CREDENTIALS;
+/// How many signatures the counter in storage is ahead of the counter in
+/// use.
+pub const SIGN_COUNT_RESERVE: u32 = 256;
+
+pub static mut TABLE_BUFFER: [u8; TABLE_LEN] = [0; TABLE_LEN];
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Receiving the continuation packets of a message.
+    Receiving,
+    /// Handling a message. No packets are received until it is answered.
+    Processing,
+    /// Waiting for the user to press the button. Only a `CANCEL` is
+    /// handled.
+    WaitingForUser,
+    /// Sending the response to a message.
+    Sending,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Request {
+    MakeCredential,
+    GetAssertion,
+    Reset,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    HashRpId,
+    HashAuthData,
+}
+
+fn cbor_status(error: cbor::Error) -> u8 {
+    match error {
+        cbor::Error::UnexpectedType => CTAP2_ERR_CBOR_UNEXPECTED_TYPE,
+        cbor::Error::Truncated | cbor::Error::Unsupported => CTAP2_ERR_INVALID_CBOR,
+    }
+}
+
+/// Read a `PublicKeyCredentialParameters` map, and return whether it is for
+/// ES256.
+fn read_credential_params(reader: &mut Reader) -> Result<bool, u8> {
+    let mut alg = None;
+    let mut public_key = false;
+    for _ in 0..reader.read_map().map_err(cbor_status)? {
+        match reader.read_text().map_err(cbor_status)? {
+            b"alg" => alg = Some(reader.read_int().map_err(cbor_status)?),
+            b"type" => public_key = reader.read_text().map_err(cbor_status)? == b"public-key",
+            _ => reader.skip().map_err(cbor_status)?,
+        }
+    }
+    Ok(public_key && alg == Some(COSE_ES256))
+}
+
+/// Read a `PublicKeyCredentialDescriptor` map, and return its credential ID
+/// if it is for a public key credential.
+fn read_credential_descriptor<'b>(reader: &mut Reader<'b>) -> Result<Option<&'b [u8]>, u8> {
+    let mut id = None;
+    let mut public_key = false;
+    for _ in 0..reader.read_map().map_err(cbor_status)? {
+        match reader.read_text().map_err(cbor_status)? {
+            b"id" => id = Some(reader.read_bytes().map_err(cbor_status)?),
+            b"type" => public_key = reader.read_text().map_err(cbor_status)? == b"public-key",
+            _ => reader.skip().map_err(cbor_status)?,
+        }
+    }
+    Ok(id.filter(|_| public_key))
+}
+
+/// Read a client data hash.
+fn read_client_data_hash(reader: &mut Reader) -> Result<[u8; 32], u8> {
+    let bytes = reader.read_bytes().map_err(cbor_status)?;
+    if bytes.len() != 32 {
+        return Err(CTAP1_ERR_INVALID_LENGTH);
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(bytes);
+    Ok(hash)
+}
+
+/// The error for a request with a PIN, which the authenticator does not
+/// support.
+fn pin_auth_status(reader: &mut Reader) -> u8 {
+    match reader.read_bytes() {
+        Ok(pin_auth) if pin_auth.is_empty() => CTAP2_ERR_PIN_NOT_SET,
+        Ok(_) => CTAP2_ERR_PIN_AUTH_INVALID,
+        Err(e) => cbor_status(e),
+    }
+}
+
+/// Encode a signature, R and S, as a DER `Ecdsa-Sig-Value`. Returns the
+/// length of the encoding.
+fn encode_der_signature(
+    signature: &[u8; SIGNATURE_LEN],
+    der: &mut [u8; DER_SIGNATURE_LEN],
+) -> usize {
+    let mut len = 2;
+    for value in [&signature[..32], &signature[32..]].iter() {
+        // Minimal encoding, as a positive integer
+        let first = value.iter().position(|b| *b != 0).unwrap_or(31);
+        let value = &value[first..];
+        let pad = value[0] & 0x80 != 0;
+        der[len] = 0x02;
+        der[len + 1] = (value.len() + pad as usize) as u8;
+        len += 2;
+        if pad {
+            der[len] = 0;
+            len += 1;
+        }
+        der[len..len + value.len()].copy_from_slice(value);
+        len += value.len();
+    }
+    der[0] = 0x30;
+    der[1] = (len - 2) as u8;
+    len
+}
+
+pub struct Ctap2Authenticator<'a, U, D, S, A>
+where
+    U: usb_hid::UsbHid<'a, [u8; 64]>,
+    D: digest::Digest<'a, [u8; 32]>,
+    S: signature::KeyGenerate<'a> + signature::SignatureSign<'a>,
+    A: time::Alarm<'a>,
+{
+    usb: &'a U,
+    digest: &'a D,
+    signer: &'a S,
+    alarm: &'a A,
+    button: &'a dyn gpio::InterruptPin<'a>,
+    button_mode: gpio::ActivationMode,
+    aaguid: [u8; 16],
+
+    send_packet: TakeCell<'static, [u8; 64]>,
+    recv_packet: TakeCell<'static, [u8; 64]>,
+    receiving: Cell<bool>,
+
+    // The CTAPHID transaction
+    state: Cell<State>,
+    channel: Cell<u32>,
+    command: Cell<u8>,
+    /// The message received, then the response to it.
+    message: TakeCell<'static, [u8]>,
+    message_len: Cell<usize>,
+    /// Bytes of the message received or sent so far.
+    offset: Cell<usize>,
+    seq: Cell<u8>,
+    init_sent: Cell<bool>,
+    next_channel: Cell<u32>,
+
+    // The authenticator request
+    request: Cell<Request>,
+    step: Cell<Step>,
+    client_data_hash: Cell<[u8; 32]>,
+    rp_id_hash: Cell<[u8; 32]>,
+    /// Where the relying party ID is in the message.
+    rp_id: Cell<(usize, usize)>,
+    /// Whether the request needs the user to be present.
+    check_presence: Cell<bool>,
+    /// Whether the user confirmed the request.
+    user_present: Cell<bool>,
+    /// Whether the button was pressed when the wait for the user started,
+    /// and has not been released since.
+    button_held: Cell<bool>,
+    /// How much longer to wait for the user, in milliseconds.
+    wait_left_ms: Cell<u32>,
+    /// Slots of the credentials in the allow or exclude list, as a bitmask.
+    listed: Cell<u32>,
+    slot: Cell<usize>,
+    /// Where the authenticator data is in the response.
+    auth_data: Cell<(usize, usize)>,
+
+    /// The relying party ID hash of the credential in each key slot.
+    credentials: [Cell<Option<[u8; 32]>>; MAX_CREDENTIALS],
+    sign_count: Cell<u32>,
+    /// The signature counter in storage, which `sign_count` must not pass.
+    sign_count_reserved: Cell<u32>,
+
+    storage: OptionalCell<&'a dyn NonvolatileStorage<'static>>,
+    storage_address: Cell<usize>,
+    table: TakeCell<'static, [u8]>,
+
+    hash: TakeCell<'static, [u8; 32]>,
+    signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+    public_key: TakeCell<'static, [u8; PUBLIC_KEY_LEN]>,
+}
+
+impl<'a, U, D, S, A> Ctap2Authenticator<'a, U, D, S, A>
+where
+    U: usb_hid::UsbHid<'a, [u8; 64]>,
+    D: digest::Digest<'a, [u8; 32]>,
+    S: signature::KeyGenerate<'a> + signature::SignatureSign<'a>,
+    A: time::Alarm<'a>,
+{
+    pub fn new(
+        usb: &'a U,
+        digest: &'a D,
+        signer: &'a S,
+        alarm: &'a A,
+        button: &'a dyn gpio::InterruptPin<'a>,
+        button_mode: gpio::ActivationMode,
+        button_floating_state: gpio::FloatingState,
+        aaguid: [u8; 16],
+        send_packet: &'static mut [u8; 64],
+        recv_packet: &'static mut [u8; 64],
+        message: &'static mut [u8],
+        hash: &'static mut [u8; 32],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    ) -> Ctap2Authenticator<'a, U, D, S, A> {
+        button.make_input();
+        button.set_floating_state(button_floating_state);
+        Ctap2Authenticator {
+            usb: usb,
+            digest: digest,
+            signer: signer,
+            alarm: alarm,
+            button: button,
+            button_mode: button_mode,
+            aaguid: aaguid,
+            send_packet: TakeCell::new(send_packet),
+            recv_packet: TakeCell::new(recv_packet),
+            receiving: Cell::new(false),
+            state: Cell::new(State::Idle),
+            channel: Cell::new(0),
+            command: Cell::new(0),
+            message: TakeCell::new(message),
+            message_len: Cell::new(0),
+            offset: Cell::new(0),
+            seq: Cell::new(0),
+            init_sent: Cell::new(false),
+            next_channel: Cell::new(1),
+            request: Cell::new(Request::MakeCredential),
+            step: Cell::new(Step::HashRpId),
+            client_data_hash: Cell::new([0; 32]),
+            rp_id_hash: Cell::new([0; 32]),
+            rp_id: Cell::new((0, 0)),
+            check_presence: Cell::new(true),
+            user_present: Cell::new(false),
+            button_held: Cell::new(false),
+            wait_left_ms: Cell::new(0),
+            listed: Cell::new(0),
+            slot: Cell::new(0),
+            auth_data: Cell::new((0, 0)),
+            credentials: Default::default(),
+            sign_count: Cell::new(0),
+            sign_count_reserved: Cell::new(0),
+            storage: OptionalCell::empty(),
+            storage_address: Cell::new(0),
+            table: TakeCell::empty(),
+            hash: TakeCell::new(hash),
+            signature: TakeCell::new(signature),
+            public_key: TakeCell::new(public_key),
+        }
+    }
+
+    /// Keep the table of credentials in `TABLE_LEN` bytes of `storage` from
+    /// `address`, which must have this as its client. `table` must be at
+    /// least `TABLE_LEN` bytes long.
+    pub fn set_storage(
+        &self,
+        storage: &'a dyn NonvolatileStorage<'static>,
+        address: usize,
+        table: &'static mut [u8],
+    ) {
+        self.storage.set(storage);
+        self.storage_address.set(address);
+        self.table.replace(table);
+    }
+
+    /// Start receiving requests, once the table of credentials is read from
+    /// storage. Call once the USB device is attached.
+    pub fn start(&self) {
+        let read = self.storage.extract().map_or(false, |storage| {
+            self.table.take().map_or(false, |table| {
+                // If the read fails, the table is lost with the buffer, and
+                // new credentials cannot be stored.
+                storage
+                    .read(table, self.storage_address.get(), TABLE_LEN)
+                    .is_ok()
+            })
+        });
+        if !read {
+            self.receive();
+        }
+    }
+
+    fn receive(&self) {
+        if self.receiving.get() {
+            return;
+        }
+        self.recv_packet.take().map(|packet| {
+            // Set first, as a pending packet is passed back right away.
+            self.receiving.set(true);
+            if let Err((_, packet)) = self.usb.receive_buffer(packet) {
+                self.receiving.set(false);
+                self.recv_packet.replace(packet);
+            }
+        });
+    }
+
+    fn slots(&self) -> usize {
+        cmp::min(self.signer.key_slots(), MAX_CREDENTIALS)
+    }
+
+    // CTAPHID transport
+
+    fn handle_packet(&self, packet: &[u8; PACKET_LEN]) {
+        let channel = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        match self.state.get() {
+            State::Processing | State::WaitingForUser | State::Sending => {
+                self.handle_busy_packet(channel, packet[4]);
+                return;
+            }
+            State::Idle | State::Receiving => (),
+        }
+        if packet[4] & 0x80 == 0 {
+            self.handle_continuation(channel, packet[4], &packet[5..]);
+            return;
+        }
+
+        let command = packet[4];
+        let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+        if self.state.get() == State::Receiving {
+            if channel != self.channel.get() {
+                self.send_error(channel, ERR_CHANNEL_BUSY);
+                return;
+            }
+            self.state.set(State::Idle);
+            // Only an INIT can interrupt a message, to resynchronize
+            if command != CTAPHID_INIT {
+                self.send_error(channel, ERR_INVALID_SEQ);
+                return;
+            }
+        }
+        if channel == 0 || (channel == BROADCAST_CHANNEL && command != CTAPHID_INIT) {
+            self.send_error(channel, ERR_INVALID_CHANNEL);
+            return;
+        }
+        if len > self.message.map_or(0, |message| message.len()) {
+            self.send_error(channel, ERR_INVALID_LEN);
+            return;
+        }
+
+        let count = cmp::min(len, INIT_DATA_LEN);
+        self.message
+            .map(|message| message[..count].copy_from_slice(&packet[7..7 + count]));
+        self.channel.set(channel);
+        self.command.set(command);
+        self.message_len.set(len);
+        self.offset.set(count);
+        self.seq.set(0);
+        if count == len {
+            self.dispatch();
+        } else {
+            self.state.set(State::Receiving);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TRANSACTION_TIMEOUT_MS));
+        }
+    }
+
+    fn handle_continuation(&self, channel: u32, seq: u8, data: &[u8]) {
+        if self.state.get() != State::Receiving || channel != self.channel.get() {
+            // Spurious continuation packets are ignored
+            return;
+        }
+        if seq != self.seq.get() {
+            self.state.set(State::Idle);
+            self.send_error(channel, ERR_INVALID_SEQ);
+            return;
+        }
+        let offset = self.offset.get();
+        let count = cmp::min(self.message_len.get() - offset, CONT_DATA_LEN);
+        self.message
+            .map(|message| message[offset..offset + count].copy_from_slice(&data[..count]));
+        self.offset.set(offset + count);
+        self.seq.set(seq + 1);
+        if offset + count == self.message_len.get() {
+            self.dispatch();
+        } else {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TRANSACTION_TIMEOUT_MS));
+        }
+    }
+
+    /// Handle a packet that arrives while a message is handled. Only the
+    /// wait for the user can be cancelled.
+    fn handle_busy_packet(&self, channel: u32, command: u8) {
+        if command & 0x80 == 0 {
+            // Continuation packets are ignored
+        } else if channel != self.channel.get() {
+            self.send_error(channel, ERR_CHANNEL_BUSY);
+        } else if command == CTAPHID_CANCEL && self.state.get() == State::WaitingForUser {
+            self.stop_waiting();
+            self.respond_status(CTAP2_ERR_KEEPALIVE_CANCEL);
+        }
+    }
+
+    /// Handle a complete message.
+    fn dispatch(&self) {
+        self.state.set(State::Processing);
+        let len = self.message_len.get();
+        match self.command.get() {
+            CTAPHID_INIT => self.init(len),
+            CTAPHID_PING => self.respond(CTAPHID_PING, len),
+            CTAPHID_CBOR if len > 0 => self.process_cbor(len),
+            // Nothing is waiting for the user, so there is nothing to cancel
+            CTAPHID_CANCEL => self.state.set(State::Idle),
+            CTAPHID_CBOR => {
+                self.state.set(State::Idle);
+                self.send_error(self.channel.get(), ERR_INVALID_LEN);
+            }
+            _ => {
+                self.state.set(State::Idle);
+                self.send_error(self.channel.get(), ERR_INVALID_CMD);
+            }
+        }
+    }
+
+    fn init(&self, len: usize) {
+        if len != 8 {
+            self.state.set(State::Idle);
+            self.send_error(self.channel.get(), ERR_INVALID_LEN);
+            return;
+        }
+        let channel = if self.channel.get() == BROADCAST_CHANNEL {
+            let channel = self.next_channel.get();
+            self.next_channel.set(if channel >= BROADCAST_CHANNEL - 1 {
+                1
+            } else {
+                channel + 1
+            });
+            channel
+        } else {
+            self.channel.get()
+        };
+        self.message.map(|message| {
+            // The nonce stays at the start
+            message[8..12].copy_from_slice(&channel.to_be_bytes());
+            // CTAPHID protocol version, then the device version
+            message[12..16].copy_from_slice(&[2, 0, 1, 0]);
+            message[16] = CAPABILITY_CBOR | CAPABILITY_NMSG;
+        });
+        self.respond(CTAPHID_INIT, 17);
+    }
+
+    /// Send the first `len` bytes of the message buffer as the response.
+    fn respond(&self, command: u8, len: usize) {
+        self.command.set(command);
+        self.message_len.set(len);
+        self.offset.set(0);
+        self.seq.set(0);
+        self.init_sent.set(false);
+        self.state.set(State::Sending);
+        self.send_next();
+    }
+
+    fn send_next(&self) {
+        let channel = self.channel.get();
+        let offset = self.offset.get();
+        let len = self.message_len.get();
+        // Without the buffer, an error is being sent, and the response
+        // continues once it is.
+        self.send_packet.take().map(|packet| {
+            for byte in packet.iter_mut() {
+                *byte = 0;
+            }
+            packet[..4].copy_from_slice(&channel.to_be_bytes());
+            let (header_len, data_len) = if !self.init_sent.get() {
+                self.init_sent.set(true);
+                packet[4] = self.command.get();
+                packet[5..7].copy_from_slice(&(len as u16).to_be_bytes());
+                (7, INIT_DATA_LEN)
+            } else {
+                packet[4] = self.seq.get();
+                self.seq.set(self.seq.get() + 1);
+                (5, CONT_DATA_LEN)
+            };
+            let count = cmp::min(len - offset, data_len);
+            self.message.map(|message| {
+                packet[header_len..header_len + count]
+                    .copy_from_slice(&message[offset..offset + count])
+            });
+            self.offset.set(offset + count);
+            if let Err((_, packet)) = self.usb.send_buffer(packet) {
+                self.send_packet.replace(packet);
+                self.state.set(State::Idle);
+                self.receive();
+            }
+        });
+    }
+
+    fn send_error(&self, channel: u32, error: u8) {
+        self.send_byte(channel, CTAPHID_ERROR, error);
+    }
+
+    /// Send a message of one byte that fits in a packet, such as an error
+    /// or a keepalive, unless a packet is being sent already.
+    fn send_byte(&self, channel: u32, command: u8, value: u8) {
+        self.send_packet.take().map(|packet| {
+            for byte in packet.iter_mut() {
+                *byte = 0;
+            }
+            packet[..4].copy_from_slice(&channel.to_be_bytes());
+            packet[4] = command;
+            packet[6] = 1;
+            packet[7] = value;
+            if let Err((_, packet)) = self.usb.send_buffer(packet) {
+                self.send_packet.replace(packet);
+            }
+        });
+    }
+
+    // CTAP2 commands
+
+    fn process_cbor(&self, len: usize) {
+        let command = self.message.map_or(0, |message| message[0]);
+        let result = match command {
+            CMD_MAKE_CREDENTIAL => self
+                .parse_make_credential(len)
+                .and_then(|()| self.hash_rp_id(Request::MakeCredential)),
+            CMD_GET_ASSERTION => self
+                .parse_get_assertion(len)
+                .and_then(|()| self.hash_rp_id(Request::GetAssertion)),
+            CMD_GET_INFO => self.get_info(),
+            CMD_RESET => {
+                self.request.set(Request::Reset);
+                self.check_presence.set(true);
+                self.user_present.set(false);
+                self.confirm_request()
+            }
+            _ => Err(CTAP1_ERR_INVALID_COMMAND),
+        };
+        if let Err(status) = result {
+            self.respond_status(status);
+        }
+    }
+
+    fn respond_status(&self, status: u8) {
+        self.message.map(|message| message[0] = status);
+        self.respond(CTAPHID_CBOR, 1);
+    }
+
+    fn get_info(&self) -> Result<(), u8> {
+        let len = self.message.map_or(None, |message| {
+            let max_message_len = message.len();
+            message[0] = CTAP2_OK;
+            let mut writer = Writer::new(message, 1);
+            writer.map(4);
+            writer.int(1);
+            writer.array(1);
+            writer.text("FIDO_2_0");
+            writer.int(3);
+            writer.bytes(&self.aaguid);
+            writer.int(4);
+            writer.map(3);
+            writer.text("rk");
+            writer.bool(false);
+            writer.text("up");
+            writer.bool(true);
+            writer.text("plat");
+            writer.bool(false);
+            writer.int(5);
+            writer.int(max_message_len as i64);
+            writer.position()
+        });
+        let len = len.ok_or(CTAP1_ERR_OTHER)?;
+        self.respond(CTAPHID_CBOR, len);
+        Ok(())
+    }
+
+    /// Record the credential in an allow or exclude list, if it is one of
+    /// ours.
+    fn list_credential(&self, id: &[u8]) {
+        if id.len() != CREDENTIAL_ID_LEN {
+            return;
+        }
+        let slot = id[0] as usize;
+        if slot >= self.slots() {
+            return;
+        }
+        if let Some(rp_id_hash) = self.credentials[slot].get() {
+            if rp_id_hash[..CREDENTIAL_ID_LEN - 1] == id[1..] {
+                self.listed.set(self.listed.get() | 1 << slot);
+            }
+        }
+    }
+
+    /// The first listed credential of the relying party of the request.
+    fn listed_credential(&self) -> Option<usize> {
+        let rp_id_hash = self.rp_id_hash.get();
+        (0..self.slots()).find(|slot| {
+            self.listed.get() & 1 << slot != 0 && self.credentials[*slot].get() == Some(rp_id_hash)
+        })
+    }
+
+    fn credential_id(&self, slot: usize) -> [u8; CREDENTIAL_ID_LEN] {
+        let mut id = [0; CREDENTIAL_ID_LEN];
+        id[0] = slot as u8;
+        id[1..].copy_from_slice(&self.rp_id_hash.get()[..CREDENTIAL_ID_LEN - 1]);
+        id
+    }
+
+    /// Read the options map of a request.
+    fn read_options(&self, reader: &mut Reader, request: Request) -> Result<(), u8> {
+        for _ in 0..reader.read_map().map_err(cbor_status)? {
+            let key = reader.read_text().map_err(cbor_status)?;
+            let value = reader.read_bool().map_err(cbor_status)?;
+            match (key, request) {
+                (b"rk", Request::MakeCredential) | (b"uv", _) if value => {
+                    return Err(CTAP2_ERR_UNSUPPORTED_OPTION)
+                }
+                (b"rk", Request::GetAssertion) => return Err(CTAP2_ERR_INVALID_OPTION),
+                (b"up", Request::GetAssertion) => self.check_presence.set(value),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the relying party ID, and record where it is in the message.
+    fn read_rp_id(&self, reader: &mut Reader) -> Result<(), u8> {
+        let rp_id = reader.read_text().map_err(cbor_status)?;
+        if rp_id.is_empty() {
+            return Err(CTAP2_ERR_MISSING_PARAMETER);
+        }
+        // The reader starts after the command byte
+        let end = reader.position() + 1;
+        self.rp_id.set((end - rp_id.len(), end));
+        Ok(())
+    }
+
+    fn parse_make_credential(&self, len: usize) -> Result<(), u8> {
+        self.listed.set(0);
+        self.check_presence.set(true);
+        self.user_present.set(false);
+        self.message.map_or(Err(CTAP1_ERR_OTHER), |message| {
+            let mut reader = Reader::new(&message[1..len]);
+            let mut client_data_hash = false;
+            let mut rp_id = false;
+            let mut user = false;
+            let mut params = false;
+            let mut es256 = false;
+            for _ in 0..reader.read_map().map_err(cbor_status)? {
+                match reader.read_int().map_err(cbor_status)? {
+                    1 => {
+                        self.client_data_hash
+                            .set(read_client_data_hash(&mut reader)?);
+                        client_data_hash = true;
+                    }
+                    2 => {
+                        for _ in 0..reader.read_map().map_err(cbor_status)? {
+                            match reader.read_text().map_err(cbor_status)? {
+                                b"id" => {
+                                    self.read_rp_id(&mut reader)?;
+                                    rp_id = true;
+                                }
+                                _ => reader.skip().map_err(cbor_status)?,
+                            }
+                        }
+                    }
+                    3 => {
+                        // The user is not stored, as credentials are not
+                        // discoverable
+                        for _ in 0..reader.read_map().map_err(cbor_status)? {
+                            reader.skip().map_err(cbor_status)?;
+                            reader.skip().map_err(cbor_status)?;
+                        }
+                        user = true;
+                    }
+                    4 => {
+                        for _ in 0..reader.read_array().map_err(cbor_status)? {
+                            es256 |= read_credential_params(&mut reader)?;
+                        }
+                        params = true;
+                    }
+                    5 => {
+                        for _ in 0..reader.read_array().map_err(cbor_status)? {
+                            if let Some(id) = read_credential_descriptor(&mut reader)? {
+                                self.list_credential(id);
+                            }
+                        }
+                    }
+                    7 => self.read_options(&mut reader, Request::MakeCredential)?,
+                    8 => return Err(pin_auth_status(&mut reader)),
+                    _ => reader.skip().map_err(cbor_status)?,
+                }
+            }
+            if !(client_data_hash && rp_id && user && params) {
+                Err(CTAP2_ERR_MISSING_PARAMETER)
+            } else if !es256 {
+                Err(CTAP2_ERR_UNSUPPORTED_ALGORITHM)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn parse_get_assertion(&self, len: usize) -> Result<(), u8> {
+        self.listed.set(0);
+        self.check_presence.set(true);
+        self.user_present.set(false);
+        self.message.map_or(Err(CTAP1_ERR_OTHER), |message| {
+            let mut reader = Reader::new(&message[1..len]);
+            let mut client_data_hash = false;
+            let mut rp_id = false;
+            for _ in 0..reader.read_map().map_err(cbor_status)? {
+                match reader.read_int().map_err(cbor_status)? {
+                    1 => {
+                        self.read_rp_id(&mut reader)?;
+                        rp_id = true;
+                    }
+                    2 => {
+                        self.client_data_hash
+                            .set(read_client_data_hash(&mut reader)?);
+                        client_data_hash = true;
+                    }
+                    3 => {
+                        for _ in 0..reader.read_array().map_err(cbor_status)? {
+                            if let Some(id) = read_credential_descriptor(&mut reader)? {
+                                self.list_credential(id);
+                            }
+                        }
+                    }
+                    5 => self.read_options(&mut reader, Request::GetAssertion)?,
+                    6 => return Err(pin_auth_status(&mut reader)),
+                    _ => reader.skip().map_err(cbor_status)?,
+                }
+            }
+            if client_data_hash && rp_id {
+                Ok(())
+            } else {
+                Err(CTAP2_ERR_MISSING_PARAMETER)
+            }
+        })
+    }
+
+    /// Hash bytes `start` to `end` of the message buffer.
+    fn hash_message(&self, start: usize, end: usize) -> Result<(), u8> {
+        self.message.take().map_or(Err(CTAP1_ERR_OTHER), |message| {
+            let mut data = LeasableBuffer::new(message);
+            data.slice(start..end);
+            self.digest
+                .add_data(data)
+                .map(|_| ())
+                .map_err(|(_, message)| {
+                    self.message.replace(message);
+                    CTAP1_ERR_OTHER
+                })
+        })
+    }
+
+    fn hash_rp_id(&self, request: Request) -> Result<(), u8> {
+        self.request.set(request);
+        self.step.set(Step::HashRpId);
+        let (start, end) = self.rp_id.get();
+        self.hash_message(start, end)
+    }
+
+    /// Hash the authenticator data of the response followed by the client
+    /// data hash, which is what gets signed.
+    fn hash_auth_data(&self, start: usize, end: usize) -> Result<(), u8> {
+        self.auth_data.set((start, end));
+        self.step.set(Step::HashAuthData);
+        self.hash_message(start, end + 32)
+    }
+
+    /// Write the authenticator data that all responses start with.
+    fn write_auth_data(&self, writer: &mut Writer, flags: u8) {
+        let flags = if self.user_present.get() {
+            flags | FLAG_USER_PRESENT
+        } else {
+            flags
+        };
+        let sign_count = self.sign_count.get().saturating_add(1);
+        self.sign_count.set(sign_count);
+        writer.raw(&self.rp_id_hash.get());
+        writer.raw(&[flags]);
+        writer.raw(&sign_count.to_be_bytes());
+    }
+
+    /// Check what does not need the user, and then wait for the user to
+    /// confirm the request if it needs it.
+    fn confirm_request(&self) -> Result<(), u8> {
+        match self.request.get() {
+            Request::MakeCredential if self.listed_credential().is_some() => {
+                return Err(CTAP2_ERR_CREDENTIAL_EXCLUDED)
+            }
+            Request::GetAssertion if self.listed_credential().is_none() => {
+                return Err(CTAP2_ERR_NO_CREDENTIALS)
+            }
+            Request::MakeCredential if self.free_slot().is_none() => {
+                return Err(CTAP2_ERR_KEY_STORE_FULL)
+            }
+            _ => (),
+        }
+        if !self.check_presence.get() {
+            return self.run_request();
+        }
+        self.state.set(State::WaitingForUser);
+        self.button_held
+            .set(self.button.read_activation(self.button_mode) == gpio::ActivationState::Active);
+        self.button
+            .enable_interrupts(gpio::InterruptEdge::EitherEdge);
+        self.wait_left_ms.set(USER_PRESENCE_TIMEOUT_MS);
+        self.send_byte(self.channel.get(), CTAPHID_KEEPALIVE, STATUS_UPNEEDED);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(KEEPALIVE_MS));
+        // A CANCEL can end the wait
+        self.receive();
+        Ok(())
+    }
+
+    fn stop_waiting(&self) {
+        self.button.disable_interrupts();
+        let _ = self.alarm.disarm();
+        self.state.set(State::Processing);
+    }
+
+    /// Carry out the request, once the user confirmed it if needed.
+    fn run_request(&self) -> Result<(), u8> {
+        match self.request.get() {
+            Request::MakeCredential => self.create_credential(),
+            Request::GetAssertion if self.reserve_sign_count() => self.store_table(),
+            Request::GetAssertion => self.assert_credential(),
+            Request::Reset => {
+                for credential in self.credentials.iter() {
+                    credential.set(None);
+                }
+                self.store_table()
+            }
+        }
+    }
+
+    /// A key slot without a credential.
+    fn free_slot(&self) -> Option<usize> {
+        (0..self.slots()).find(|slot| self.credentials[*slot].get().is_none())
+    }
+
+    /// Make sure that the signature counter in storage is ahead of the next
+    /// signature. Returns whether it has to be written.
+    fn reserve_sign_count(&self) -> bool {
+        let next = self.sign_count.get().saturating_add(1);
+        if self.storage.is_none() || next <= self.sign_count_reserved.get() {
+            return false;
+        }
+        self.sign_count_reserved
+            .set(next.saturating_add(SIGN_COUNT_RESERVE));
+        true
+    }
+
+    /// Write the table of credentials to storage, if there is any, and then
+    /// go on with the request.
+    fn store_table(&self) -> Result<(), u8> {
+        let storage = match self.storage.extract() {
+            Some(storage) => storage,
+            None => return self.table_stored(),
+        };
+        let table = self.table.take().ok_or(CTAP1_ERR_OTHER)?;
+        table[..4].copy_from_slice(&TABLE_MAGIC);
+        table[4..8].copy_from_slice(&self.sign_count_reserved.get().to_be_bytes());
+        for (credential, entry) in self.credentials.iter().zip(table[8..].chunks_mut(32)) {
+            entry.copy_from_slice(&credential.get().unwrap_or([0; 32]));
+        }
+        // The buffer is lost if the write fails, and with it the storage.
+        storage
+            .write(table, self.storage_address.get(), TABLE_LEN)
+            .map_err(|_| CTAP1_ERR_OTHER)
+    }
+
+    /// Go on with the request once the table is stored.
+    fn table_stored(&self) -> Result<(), u8> {
+        match self.request.get() {
+            Request::MakeCredential => {
+                let key = self.public_key.map(|public_key| *public_key);
+                key.map_or(Err(CTAP1_ERR_OTHER), |key| self.attest_credential(&key))
+            }
+            Request::GetAssertion => self.assert_credential(),
+            Request::Reset => {
+                self.respond_status(CTAP2_OK);
+                Ok(())
+            }
+        }
+    }
+
+    /// The table could not be stored: forget the new credential, which
+    /// would be lost on reset.
+    fn store_failed(&self) {
+        if self.request.get() == Request::MakeCredential {
+            self.credentials[self.slot.get()].set(None);
+        }
+    }
+
+    /// Pick a free key slot for a new credential, and generate its key.
+    fn create_credential(&self) -> Result<(), u8> {
+        let slot = self.free_slot().ok_or(CTAP2_ERR_KEY_STORE_FULL)?;
+        self.slot.set(slot);
+        self.public_key
+            .take()
+            .map_or(Err(CTAP1_ERR_OTHER), |public_key| {
+                self.signer
+                    .generate_key(slot, public_key)
+                    .map_err(|(_, public_key)| {
+                        self.public_key.replace(public_key);
+                        CTAP1_ERR_OTHER
+                    })
+            })
+    }
+
+    /// Write the response to `MakeCredential` up to the attestation
+    /// statement, and hash it for the attestation signature.
+    fn attest_credential(&self, public_key: &[u8; PUBLIC_KEY_LEN]) -> Result<(), u8> {
+        let id = self.credential_id(self.slot.get());
+        let auth_data = self.message.map_or(None, |message| {
+            message[0] = CTAP2_OK;
+            let mut writer = Writer::new(message, 1);
+            writer.map(3);
+            writer.int(1);
+            writer.text("packed");
+            writer.int(2);
+            writer.bytes_header(ATTESTED_AUTH_DATA_LEN);
+            let start = writer.position()?;
+            self.write_auth_data(&mut writer, FLAG_ATTESTED_CREDENTIAL);
+            writer.raw(&self.aaguid);
+            writer.raw(&(CREDENTIAL_ID_LEN as u16).to_be_bytes());
+            writer.raw(&id);
+            writer.map(5);
+            writer.int(1); // kty: EC2
+            writer.int(2);
+            writer.int(3); // alg
+            writer.int(COSE_ES256);
+            writer.int(-1); // crv: P-256
+            writer.int(1);
+            writer.int(-2); // x
+            writer.bytes(&public_key[..32]);
+            writer.int(-3); // y
+            writer.bytes(&public_key[32..]);
+            let end = writer.position()?;
+            // Overwritten by the attestation statement once signed
+            writer.raw(&self.client_data_hash.get());
+            writer.position().map(|_| (start, end))
+        });
+        let (start, end) = auth_data.ok_or(CTAP1_ERR_OTHER)?;
+        self.hash_auth_data(start, end)
+    }
+
+    /// Write the response to `GetAssertion` up to the signature, and hash it
+    /// for the signature.
+    fn assert_credential(&self) -> Result<(), u8> {
+        let slot = self.listed_credential().ok_or(CTAP2_ERR_NO_CREDENTIALS)?;
+        self.slot.set(slot);
+        let id = self.credential_id(slot);
+        let auth_data = self.message.map_or(None, |message| {
+            message[0] = CTAP2_OK;
+            let mut writer = Writer::new(message, 1);
+            writer.map(3);
+            writer.int(1);
+            writer.map(2);
+            writer.text("id");
+            writer.bytes(&id);
+            writer.text("type");
+            writer.text("public-key");
+            writer.int(2);
+            writer.bytes_header(AUTH_DATA_LEN);
+            let start = writer.position()?;
+            self.write_auth_data(&mut writer, 0);
+            let end = writer.position()?;
+            // Overwritten by the signature once signed
+            writer.raw(&self.client_data_hash.get());
+            writer.position().map(|_| (start, end))
+        });
+        let (start, end) = auth_data.ok_or(CTAP1_ERR_OTHER)?;
+        self.hash_auth_data(start, end)
+    }
+
+    /// Finish the response with `signature`, and send it.
+    fn finish_response(&self, signature: &[u8; SIGNATURE_LEN]) -> Result<(), u8> {
+        let mut der = [0; DER_SIGNATURE_LEN];
+        let der_len = encode_der_signature(signature, &mut der);
+        let (_, end) = self.auth_data.get();
+        let len = self.message.map_or(None, |message| {
+            let mut writer = Writer::new(message, end);
+            writer.int(3);
+            if self.request.get() == Request::MakeCredential {
+                // Self attestation, with the credential's own key
+                writer.map(2);
+                writer.text("alg");
+                writer.int(COSE_ES256);
+                writer.text("sig");
+            }
+            writer.bytes(&der[..der_len]);
+            writer.position()
+        });
+        let len = len.ok_or(CTAP1_ERR_OTHER)?;
+        self.respond(CTAPHID_CBOR, len);
+        Ok(())
+    }
+}
+
+impl<'a, U, D, S, A> usb_hid::Client<'a, [u8; 64]> for Ctap2Authenticator<'a, U, D, S, A>
+where
+    U: usb_hid::UsbHid<'a, [u8; 64]>,
+    D: digest::Digest<'a, [u8; 32]>,
+    S: signature::KeyGenerate<'a> + signature::SignatureSign<'a>,
+    A: time::Alarm<'a>,
+{
+    fn packet_received(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 64],
+        _endpoint: usize,
+    ) {
+        self.receiving.set(false);
+        let packet = *buffer;
+        self.recv_packet.replace(buffer);
+        if result.is_ok() {
+            self.handle_packet(&packet);
+        }
+        match self.state.get() {
+            State::Idle | State::Receiving | State::WaitingForUser => self.receive(),
+            State::Processing | State::Sending => (),
+        }
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        _result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 64],
+        _endpoint: usize,
+    ) {
+        self.send_packet.replace(buffer);
+        if self.state.get() == State::Sending {
+            if !self.init_sent.get() || self.offset.get() < self.message_len.get() {
+                self.send_next();
+            } else {
+                self.state.set(State::Idle);
+                self.receive();
+            }
+        }
+    }
+
+    fn can_receive(&'a self) -> bool {
+        self.receiving.get()
+    }
+}
+
+impl<'a, U, D, S, A> digest::Client<'a, [u8; 32]> for Ctap2Authenticator<'a, U, D, S, A>
+where
+    U: usb_hid::UsbHid<'a, [u8; 64]>,
+    D: digest::Digest<'a, [u8; 32]>,
+    S: signature::KeyGenerate<'a> + signature::SignatureSign<'a>,
+    A: time::Alarm<'a>,
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        self.message.replace(data);
+        let result = result.map_err(|_| CTAP1_ERR_OTHER).and_then(|()| {
+            self.hash.take().map_or(Err(CTAP1_ERR_OTHER), |hash| {
+                self.digest.run(hash).map_err(|(_, hash)| {
+                    self.hash.replace(hash);
+                    CTAP1_ERR_OTHER
+                })
+            })
+        });
+        if let Err(status) = result {
+            self.respond_status(status);
+        }
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; 32]) {
+        if result.is_err() {
+            self.hash.replace(digest);
+            self.respond_status(CTAP1_ERR_OTHER);
+            return;
+        }
+        let result = match self.step.get() {
+            Step::HashRpId => {
+                self.rp_id_hash.set(*digest);
+                self.hash.replace(digest);
+                self.confirm_request()
+            }
+            Step::HashAuthData => match self.signature.take() {
+                Some(signature) => self
+                    .signer
+                    .sign(self.slot.get(), digest, signature)
+                    .map_err(|(_, hash, signature)| {
+                        self.hash.replace(hash);
+                        self.signature.replace(signature);
+                        CTAP1_ERR_OTHER
+                    }),
+                None => {
+                    self.hash.replace(digest);
+                    Err(CTAP1_ERR_OTHER)
+                }
+            },
+        };
+        if let Err(status) = result {
+            self.respond_status(status);
+        }
+    }
+}
+
+impl<'a, U, D, S, A> signature::KeyGenerateClient for Ctap2Authenticator<'a, U, D, S, A>
+where
+    U: usb_hid::UsbHid<'a, [u8; 64]>,
+    D: digest::Digest<'a, [u8; 32]>,
+    S: signature::KeyGenerate<'a> + signature::SignatureSign<'a>,
+    A: time::Alarm<'a>,
+{
+    fn key_generated(
+        &self,
+        result: Result<(), ErrorCode>,
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    ) {
+        self.public_key.replace(public_key);
+        let result = result.map_err(|_| CTAP1_ERR_OTHER).and_then(|()| {
+            self.credentials[self.slot.get()].set(Some(self.rp_id_hash.get()));
+            self.reserve_sign_count();
+            self.store_table().map_err(|status| {
+                self.store_failed();
+                status
+            })
+        });
+        if let Err(status) = result {
+            self.respond_status(status);
+        }
+    }
+}
+
+impl<'a, U, D, S, A> signature::SignClient for Ctap2Authenticator<'a, U, D, S, A>
+where
+    U: usb_hid::UsbHid<'a, [u8; 64]>,
+    D: digest::Digest<'a, [u8; 32]>,
+    S: signature::KeyGenerate<'a> + signature::SignatureSign<'a>,
+    A: time::Alarm<'a>,
+{
+    fn signing_done(
+        &self,
+        result: Result<(), ErrorCode>,
+        hash: &'static mut [u8; 32],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) {
+        let value = *signature;
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+        let result = result
+            .map_err(|_| CTAP1_ERR_OTHER)
+            .and_then(|()| self.finish_response(&value));
+        if let Err(status) = result {
+            self.respond_status(status);
+        }
+    }
+}
+
+impl<'a, U, D, S, A> gpio::Client for Ctap2Authenticator<'a, U, D, S, A>
+where
+    U: usb_hid::UsbHid<'a, [u8; 64]>,
+    D: digest::Digest<'a, [u8; 32]>,
+    S: signature::KeyGenerate<'a> + signature::SignatureSign<'a>,
+    A: time::Alarm<'a>,
+{
+    fn fired(&self) {
+        if self.state.get() != State::WaitingForUser {
+            return;
+        }
+        if self.button.read_activation(self.button_mode) != gpio::ActivationState::Active {
+            self.button_held.set(false);
+            return;
+        }
+        if self.button_held.get() {
+            return;
+        }
+        self.stop_waiting();
+        self.user_present.set(true);
+        if let Err(status) = self.run_request() {
+            self.respond_status(status);
+        }
+    }
+}
+
+impl<'a, U, D, S, A> time::AlarmClient for Ctap2Authenticator<'a, U, D, S, A>
+where
+    U: usb_hid::UsbHid<'a, [u8; 64]>,
+    D: digest::Digest<'a, [u8; 32]>,
+    S: signature::KeyGenerate<'a> + signature::SignatureSign<'a>,
+    A: time::Alarm<'a>,
+{
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Receiving => {
+                // The host stopped sending the message
+                self.state.set(State::Idle);
+                self.send_error(self.channel.get(), ERR_MSG_TIMEOUT);
+                return;
+            }
+            State::WaitingForUser => (),
+            State::Idle | State::Processing | State::Sending => return,
+        }
+        let left = self.wait_left_ms.get().saturating_sub(KEEPALIVE_MS);
+        if left == 0 {
+            // A button held down the whole time is not a confirmation
+            let status = if self.button_held.get() {
+                CTAP2_ERR_OPERATION_DENIED
+            } else {
+                CTAP2_ERR_USER_ACTION_TIMEOUT
+            };
+            self.stop_waiting();
+            self.respond_status(status);
+            return;
+        }
+        self.wait_left_ms.set(left);
+        self.send_byte(self.channel.get(), CTAPHID_KEEPALIVE, STATUS_UPNEEDED);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(KEEPALIVE_MS));
+    }
+}
+
+impl<'a, U, D, S, A> NonvolatileStorageClient<'static> for Ctap2Authenticator<'a, U, D, S, A>
+where
+    U: usb_hid::UsbHid<'a, [u8; 64]>,
+    D: digest::Digest<'a, [u8; 32]>,
+    S: signature::KeyGenerate<'a> + signature::SignatureSign<'a>,
+    A: time::Alarm<'a>,
+{
+    fn read_done(&self, table: &'static mut [u8], length: usize) {
+        if length >= TABLE_LEN && table[..4] == TABLE_MAGIC {
+            let reserved = u32::from_be_bytes([table[4], table[5], table[6], table[7]]);
+            // Counting starts from what may have been used before the reset
+            self.sign_count.set(reserved);
+            self.sign_count_reserved.set(reserved);
+            for (credential, entry) in self.credentials.iter().zip(table[8..].chunks(32)) {
+                let mut rp_id_hash = [0; 32];
+                rp_id_hash.copy_from_slice(entry);
+                credential.set(Some(rp_id_hash).filter(|hash| *hash != [0; 32]));
+            }
+        }
+        self.table.replace(table);
+        self.receive();
+    }
+
+    fn write_done(&self, table: &'static mut [u8], length: usize) {
+        self.table.replace(table);
+        let result = if length == TABLE_LEN {
+            self.table_stored()
+        } else {
+            self.store_failed();
+            Err(CTAP1_ERR_OTHER)
+        };
+        if let Err(status) = result {
+            self.respond_status(status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use core::cell::RefCell;
+    use kernel::hil::digest::Client as _;
+    use kernel::hil::signature::{KeyGenerateClient as _, SignClient as _};
+    use kernel::hil::time::{Alarm as _, AlarmClient as _, Freq1KHz, Ticks32};
+    use kernel::hil::usb_hid::Client as _;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const CHANNEL: u32 = 0x0102_0304;
+
+    struct MockUsb {
+        sent: RefCell<Vec<[u8; 64]>>,
+        send: TakeCell<'static, [u8; 64]>,
+        recv: TakeCell<'static, [u8; 64]>,
+    }
+
+    impl<'a> usb_hid::UsbHid<'a, [u8; 64]> for MockUsb {
+        fn send_buffer(
+            &'a self,
+            send: &'static mut [u8; 64],
+        ) -> Result<usize, (ErrorCode, &'static mut [u8; 64])> {
+            self.sent.borrow_mut().push(*send);
+            self.send.replace(send);
+            Ok(64)
+        }
+
+        fn send_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
+            Err(ErrorCode::FAIL)
+        }
+
+        fn receive_buffer(
+            &'a self,
+            recv: &'static mut [u8; 64],
+        ) -> Result<(), (ErrorCode, &'static mut [u8; 64])> {
+            self.recv.replace(recv);
+            Ok(())
+        }
+
+        fn receive_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
+            Err(ErrorCode::FAIL)
+        }
+    }
+
+    /// Not SHA-256, but a different value for different data is all the
+    /// authenticator needs.
+    struct MockDigest {
+        data: TakeCell<'static, [u8]>,
+        state: Cell<[u8; 32]>,
+        hash: TakeCell<'static, [u8; 32]>,
+    }
+
+    impl<'a> digest::Digest<'a, [u8; 32]> for MockDigest {
+        fn set_client(&'a self, _client: &'a dyn digest::Client<'a, [u8; 32]>) {}
+
+        fn add_data(
+            &self,
+            data: LeasableBuffer<'static, u8>,
+        ) -> Result<usize, (ErrorCode, &'static mut [u8])> {
+            let mut state = self.state.get();
+            for (i, byte) in data[..].iter().enumerate() {
+                state[i % 32] = state[i % 32].rotate_left(3) ^ byte;
+            }
+            self.state.set(state);
+            let len = data.len();
+            self.data.replace(data.take());
+            Ok(len)
+        }
+
+        fn run(
+            &'a self,
+            digest: &'static mut [u8; 32],
+        ) -> Result<(), (ErrorCode, &'static mut [u8; 32])> {
+            *digest = self.state.get();
+            self.state.set([0; 32]);
+            self.hash.replace(digest);
+            Ok(())
+        }
+
+        fn clear_data(&self) {}
+    }
+
+    struct MockSigner {
+        slots: usize,
+        public_key: TakeCell<'static, [u8; PUBLIC_KEY_LEN]>,
+        signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+        hash: TakeCell<'static, [u8; 32]>,
+    }
+
+    impl<'a> signature::KeyGenerate<'a> for MockSigner {
+        fn set_key_client(&'a self, _client: &'a dyn signature::KeyGenerateClient) {}
+
+        fn key_slots(&self) -> usize {
+            self.slots
+        }
+
+        fn generate_key(
+            &self,
+            slot: usize,
+            public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+        ) -> Result<(), (ErrorCode, &'static mut [u8; PUBLIC_KEY_LEN])> {
+            *public_key = [slot as u8 + 1; PUBLIC_KEY_LEN];
+            self.public_key.replace(public_key);
+            Ok(())
+        }
+    }
+
+    impl<'a> signature::SignatureSign<'a> for MockSigner {
+        fn set_sign_client(&'a self, _client: &'a dyn signature::SignClient) {}
+
+        fn sign(
+            &self,
+            _slot: usize,
+            hash: &'static mut [u8; 32],
+            signature: &'static mut [u8; SIGNATURE_LEN],
+        ) -> Result<
+            (),
+            (
+                ErrorCode,
+                &'static mut [u8; 32],
+                &'static mut [u8; SIGNATURE_LEN],
+            ),
+        > {
+            *signature = [0x11; SIGNATURE_LEN];
+            self.hash.replace(hash);
+            self.signature.replace(signature);
+            Ok(())
+        }
+    }
+
+    struct MockAlarm {
+        armed: Cell<bool>,
+    }
+
+    impl time::Time for MockAlarm {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+    }
+
+    impl<'a> time::Alarm<'a> for MockAlarm {
+        fn set_alarm_client(&'a self, _client: &'a dyn time::AlarmClient) {}
+
+        fn set_alarm(&self, _reference: Ticks32, _dt: Ticks32) {
+            self.armed.set(true);
+        }
+
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            self.armed.set(false);
+            Ok(())
+        }
+
+        fn is_armed(&self) -> bool {
+            self.armed.get()
+        }
+
+        fn minimum_dt(&self) -> Ticks32 {
+            Ticks32::from(1)
+        }
+    }
+
+    struct MockButton {
+        pressed: Cell<bool>,
+    }
+
+    impl gpio::Input for MockButton {
+        fn read(&self) -> bool {
+            self.pressed.get()
+        }
+    }
+
+    impl gpio::Output for MockButton {
+        fn set(&self) {}
+
+        fn clear(&self) {}
+
+        fn toggle(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Configure for MockButton {
+        fn configuration(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn make_output(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn disable_output(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn make_input(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn disable_input(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: gpio::FloatingState) {}
+
+        fn floating_state(&self) -> gpio::FloatingState {
+            gpio::FloatingState::PullNone
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for MockButton {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+
+        fn enable_interrupts(&self, _mode: gpio::InterruptEdge) {}
+
+        fn disable_interrupts(&self) {}
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Pin for MockButton {}
+    impl<'a> gpio::InterruptPin<'a> for MockButton {}
+
+    struct MockStorage {
+        memory: RefCell<[u8; TABLE_LEN]>,
+        writes: Cell<usize>,
+        /// The buffer of the access in progress, and whether it is a write.
+        pending: TakeCell<'static, [u8]>,
+        write: Cell<bool>,
+    }
+
+    impl NonvolatileStorage<'static> for MockStorage {
+        fn set_client(&self, _client: &'static dyn NonvolatileStorageClient<'static>) {}
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            assert_eq!((address, length), (0, TABLE_LEN));
+            self.pending.replace(buffer);
+            self.write.set(false);
+            Ok(())
+        }
+
+        fn write(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            assert_eq!((address, length), (0, TABLE_LEN));
+            self.pending.replace(buffer);
+            self.write.set(true);
+            Ok(())
+        }
+    }
+
+    type Authenticator = Ctap2Authenticator<'static, MockUsb, MockDigest, MockSigner, MockAlarm>;
+
+    struct Harness {
+        auth: &'static Authenticator,
+        usb: &'static MockUsb,
+        digest: &'static MockDigest,
+        signer: &'static MockSigner,
+        alarm: &'static MockAlarm,
+        button: &'static MockButton,
+        storage: Option<&'static MockStorage>,
+    }
+
+    fn harness(slots: usize, storage: Option<&'static MockStorage>) -> Harness {
+        let usb = Box::leak(Box::new(MockUsb {
+            sent: RefCell::new(Vec::new()),
+            send: TakeCell::empty(),
+            recv: TakeCell::empty(),
+        }));
+        let digest = Box::leak(Box::new(MockDigest {
+            data: TakeCell::empty(),
+            state: Cell::new([0; 32]),
+            hash: TakeCell::empty(),
+        }));
+        let signer = Box::leak(Box::new(MockSigner {
+            slots: slots,
+            public_key: TakeCell::empty(),
+            signature: TakeCell::empty(),
+            hash: TakeCell::empty(),
+        }));
+        let alarm = Box::leak(Box::new(MockAlarm {
+            armed: Cell::new(false),
+        }));
+        let button = Box::leak(Box::new(MockButton {
+            pressed: Cell::new(false),
+        }));
+        let auth: &'static Authenticator = Box::leak(Box::new(Ctap2Authenticator::new(
+            usb,
+            digest,
+            signer,
+            alarm,
+            button,
+            gpio::ActivationMode::ActiveHigh,
+            gpio::FloatingState::PullNone,
+            [0; 16],
+            Box::leak(Box::new([0; 64])),
+            Box::leak(Box::new([0; 64])),
+            Box::leak(Box::new([0; 1024])),
+            Box::leak(Box::new([0; 32])),
+            Box::leak(Box::new([0; SIGNATURE_LEN])),
+            Box::leak(Box::new([0; PUBLIC_KEY_LEN])),
+        )));
+        if let Some(storage) = storage {
+            auth.set_storage(storage, 0, Box::leak(Box::new([0; TABLE_LEN])));
+        }
+        let harness = Harness {
+            auth,
+            usb,
+            digest,
+            signer,
+            alarm,
+            button,
+            storage,
+        };
+        auth.start();
+        harness.pump();
+        harness
+    }
+
+    fn new_storage() -> &'static MockStorage {
+        Box::leak(Box::new(MockStorage {
+            memory: RefCell::new([0xff; TABLE_LEN]),
+            writes: Cell::new(0),
+            pending: TakeCell::empty(),
+            write: Cell::new(false),
+        }))
+    }
+
+    impl Harness {
+        /// Complete every operation in progress, until nothing is left.
+        fn pump(&self) {
+            loop {
+                if let Some(packet) = self.usb.send.take() {
+                    self.auth.packet_transmitted(Ok(()), packet, 0);
+                } else if let Some(data) = self.digest.data.take() {
+                    self.auth.add_data_done(Ok(()), data);
+                } else if let Some(hash) = self.digest.hash.take() {
+                    self.auth.hash_done(Ok(()), hash);
+                } else if let Some(public_key) = self.signer.public_key.take() {
+                    self.auth.key_generated(Ok(()), public_key);
+                } else if let Some(signature) = self.signer.signature.take() {
+                    let hash = self.signer.hash.take().unwrap();
+                    self.auth.signing_done(Ok(()), hash, signature);
+                } else if let Some(buffer) = self.storage.and_then(|s| s.pending.take()) {
+                    let storage = self.storage.unwrap();
+                    let mut memory = storage.memory.borrow_mut();
+                    if storage.write.get() {
+                        memory.copy_from_slice(&buffer[..TABLE_LEN]);
+                        drop(memory);
+                        storage.writes.set(storage.writes.get() + 1);
+                        self.auth.write_done(buffer, TABLE_LEN);
+                    } else {
+                        buffer[..TABLE_LEN].copy_from_slice(&*memory);
+                        drop(memory);
+                        self.auth.read_done(buffer, TABLE_LEN);
+                    }
+                } else {
+                    return;
+                }
+            }
+        }
+
+        fn deliver(&self, packet: &[u8; 64]) {
+            let buffer = self.usb.recv.take().expect("not receiving");
+            *buffer = *packet;
+            self.auth.packet_received(Ok(()), buffer, 0);
+            self.pump();
+        }
+
+        /// Send a CTAPHID message, split into packets.
+        fn send(&self, channel: u32, command: u8, data: &[u8]) {
+            let mut packet = [0; 64];
+            packet[..4].copy_from_slice(&channel.to_be_bytes());
+            packet[4] = command;
+            packet[5..7].copy_from_slice(&(data.len() as u16).to_be_bytes());
+            let count = cmp::min(data.len(), INIT_DATA_LEN);
+            packet[7..7 + count].copy_from_slice(&data[..count]);
+            self.deliver(&packet);
+            for (seq, chunk) in data[count..].chunks(CONT_DATA_LEN).enumerate() {
+                let mut packet = [0; 64];
+                packet[..4].copy_from_slice(&channel.to_be_bytes());
+                packet[4] = seq as u8;
+                packet[5..5 + chunk.len()].copy_from_slice(chunk);
+                self.deliver(&packet);
+            }
+        }
+
+        /// Take the messages sent so far, reassembled, without keepalives.
+        fn responses(&self) -> Vec<(u32, u8, Vec<u8>)> {
+            let mut responses: Vec<(u32, u8, Vec<u8>)> = Vec::new();
+            let mut left = 0;
+            for packet in self.usb.sent.borrow_mut().drain(..) {
+                let channel = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+                if packet[4] & 0x80 != 0 {
+                    let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+                    let count = cmp::min(len, INIT_DATA_LEN);
+                    responses.push((channel, packet[4], packet[7..7 + count].to_vec()));
+                    left = len - count;
+                } else {
+                    let count = cmp::min(left, CONT_DATA_LEN);
+                    responses
+                        .last_mut()
+                        .unwrap()
+                        .2
+                        .extend(&packet[5..5 + count]);
+                    left -= count;
+                }
+            }
+            responses.retain(|(_, command, _)| *command != CTAPHID_KEEPALIVE);
+            responses
+        }
+
+        /// Send a CTAP2 request, press the button if it waits for the user,
+        /// and return the response.
+        fn request(&self, request: &[u8]) -> Vec<u8> {
+            self.send(CHANNEL, CTAPHID_CBOR, request);
+            if self.auth.state.get() == State::WaitingForUser {
+                self.button.pressed.set(true);
+                gpio::Client::fired(self.auth);
+                self.pump();
+                self.button.pressed.set(false);
+                gpio::Client::fired(self.auth);
+            }
+            let mut responses = self.responses();
+            assert_eq!(responses.len(), 1);
+            let (channel, command, data) = responses.pop().unwrap();
+            assert_eq!((channel, command), (CHANNEL, CTAPHID_CBOR));
+            data
+        }
+    }
+
+    fn make_credential(rp_id: &str) -> Vec<u8> {
+        let mut message = [0; 256];
+        message[0] = CMD_MAKE_CREDENTIAL;
+        let mut writer = Writer::new(&mut message, 1);
+        writer.map(4);
+        writer.int(1);
+        writer.bytes(&[0x42; 32]);
+        writer.int(2);
+        writer.map(1);
+        writer.text("id");
+        writer.text(rp_id);
+        writer.int(3);
+        writer.map(1);
+        writer.text("id");
+        writer.bytes(&[1]);
+        writer.int(4);
+        writer.array(1);
+        writer.map(2);
+        writer.text("alg");
+        writer.int(COSE_ES256);
+        writer.text("type");
+        writer.text("public-key");
+        let len = writer.position().unwrap();
+        message[..len].to_vec()
+    }
+
+    fn get_assertion(rp_id: &str, credential_id: &[u8]) -> Vec<u8> {
+        let mut message = [0; 256];
+        message[0] = CMD_GET_ASSERTION;
+        let mut writer = Writer::new(&mut message, 1);
+        writer.map(3);
+        writer.int(1);
+        writer.text(rp_id);
+        writer.int(2);
+        writer.bytes(&[0x42; 32]);
+        writer.int(3);
+        writer.array(1);
+        writer.map(2);
+        writer.text("id");
+        writer.bytes(credential_id);
+        writer.text("type");
+        writer.text("public-key");
+        let len = writer.position().unwrap();
+        message[..len].to_vec()
+    }
+
+    /// The authenticator data of a successful response.
+    fn auth_data(response: &[u8]) -> Vec<u8> {
+        assert_eq!(response[0], CTAP2_OK);
+        let mut reader = Reader::new(&response[1..]);
+        for _ in 0..reader.read_map().unwrap() {
+            match reader.read_int().unwrap() {
+                2 => return reader.read_bytes().unwrap().to_vec(),
+                _ => reader.skip().unwrap(),
+            }
+        }
+        panic!("no authenticator data");
+    }
+
+    fn sign_count(auth_data: &[u8]) -> u32 {
+        u32::from_be_bytes([auth_data[33], auth_data[34], auth_data[35], auth_data[36]])
+    }
+
+    /// The credential ID in the authenticator data of a new credential.
+    fn credential_id(auth_data: &[u8]) -> Vec<u8> {
+        auth_data[AUTH_DATA_LEN + 18..AUTH_DATA_LEN + 18 + CREDENTIAL_ID_LEN].to_vec()
+    }
+
+    #[test]
+    fn test_key_store_full() {
+        let h = harness(2, None);
+        let first = credential_id(&auth_data(&h.request(&make_credential("a.example"))));
+        let second = credential_id(&auth_data(&h.request(&make_credential("b.example"))));
+        assert_ne!(first, second);
+
+        // No slot is reused, and the user is not asked.
+        assert_eq!(
+            h.request(&make_credential("c.example")),
+            [CTAP2_ERR_KEY_STORE_FULL]
+        );
+        let response = h.request(&get_assertion("a.example", &first));
+        assert_eq!(sign_count(&auth_data(&response)), 3);
+
+        // A reset frees the slots.
+        assert_eq!(h.request(&[CMD_RESET]), [CTAP2_OK]);
+        assert_eq!(
+            h.request(&get_assertion("a.example", &first)),
+            [CTAP2_ERR_NO_CREDENTIALS]
+        );
+        assert_eq!(h.request(&make_credential("c.example"))[0], CTAP2_OK);
+    }
+
+    #[test]
+    fn test_storage() {
+        let storage = new_storage();
+        let h = harness(MAX_CREDENTIALS, Some(storage));
+        let created = auth_data(&h.request(&make_credential("a.example")));
+        let id = credential_id(&created);
+        assert_eq!(sign_count(&created), 1);
+        assert_eq!(storage.writes.get(), 1);
+
+        // Assertions within the reserve do not write to storage.
+        let response = h.request(&get_assertion("a.example", &id));
+        assert_eq!(sign_count(&auth_data(&response)), 2);
+        assert_eq!(storage.writes.get(), 1);
+
+        // After a reset, the credential is still there, and the counter
+        // carries on from beyond what was used.
+        let h = harness(MAX_CREDENTIALS, Some(storage));
+        let response = h.request(&get_assertion("a.example", &id));
+        let count = sign_count(&auth_data(&response));
+        assert_eq!(count, 1 + SIGN_COUNT_RESERVE + 1);
+        assert_eq!(storage.writes.get(), 2);
+        assert_eq!(
+            h.request(&get_assertion("b.example", &id)),
+            [CTAP2_ERR_NO_CREDENTIALS]
+        );
+    }
+
+    #[test]
+    fn test_transaction_timeout() {
+        let h = harness(MAX_CREDENTIALS, None);
+        // A message that needs a continuation packet, which never comes.
+        let mut packet = [0; 64];
+        packet[..4].copy_from_slice(&CHANNEL.to_be_bytes());
+        packet[4] = CTAPHID_PING;
+        packet[6] = 100;
+        h.deliver(&packet);
+        assert!(h.alarm.is_armed());
+        assert!(h.responses().is_empty());
+
+        // Other channels are turned away until the timeout.
+        h.send(CHANNEL + 1, CTAPHID_PING, &[1, 2, 3]);
+        assert_eq!(
+            h.responses(),
+            [(CHANNEL + 1, CTAPHID_ERROR, [ERR_CHANNEL_BUSY].to_vec())]
+        );
+        h.auth.alarm();
+        h.pump();
+        assert_eq!(
+            h.responses(),
+            [(CHANNEL, CTAPHID_ERROR, [ERR_MSG_TIMEOUT].to_vec())]
+        );
+        h.send(CHANNEL + 1, CTAPHID_PING, &[1, 2, 3]);
+        assert_eq!(
+            h.responses(),
+            [(CHANNEL + 1, CTAPHID_PING, [1, 2, 3].to_vec())]
+        );
+    }
+}
@@ -0,0 +1,418 @@
+//! Minimal CBOR encoding and decoding for CTAP2 messages.
+//!
+//! Supports the subset of CBOR that CTAP2 uses: integers, byte and text
+//! strings, arrays, maps and the `true` and `false` simple values, all with
+//! definite lengths. The writer does not sort map keys; callers write them
+//! in the canonical CTAP2 order.
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+
+const SIMPLE_FALSE: u64 = 20;
+const SIMPLE_TRUE: u64 = 21;
+
+/// How deeply nested items skipped over can be.
+const MAX_DEPTH: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The data ends in the middle of an item.
+    Truncated,
+    /// The item is valid CBOR that is not supported, such as an indefinite
+    /// length or a deep nesting.
+    Unsupported,
+    /// The item is not of the type expected.
+    UnexpectedType,
+}
+
+/// Reads CBOR items from a buffer, one after the other.
+pub struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    pub fn new(buf: &'b [u8]) -> Reader<'b> {
+        Reader { buf: buf, pos: 0 }
+    }
+
+    /// The offset of the next item in the buffer.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'b [u8], Error> {
+        let end = self.pos.checked_add(len).ok_or(Error::Truncated)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(Error::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Read the major type and argument of the next item.
+    fn header(&mut self) -> Result<(u8, u64), Error> {
+        let initial = self.take(1)?[0];
+        let value = match initial & 0x1f {
+            info @ 0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => self.take(2)?.iter().fold(0, |v, b| v << 8 | *b as u64),
+            26 => self.take(4)?.iter().fold(0, |v, b| v << 8 | *b as u64),
+            27 => self.take(8)?.iter().fold(0, |v, b| v << 8 | *b as u64),
+            _ => return Err(Error::Unsupported),
+        };
+        Ok((initial >> 5, value))
+    }
+
+    fn expect(&mut self, major: u8) -> Result<u64, Error> {
+        match self.header()? {
+            (m, value) if m == major => Ok(value),
+            _ => Err(Error::UnexpectedType),
+        }
+    }
+
+    fn length(value: u64) -> Result<usize, Error> {
+        if value > usize::MAX as u64 {
+            Err(Error::Truncated)
+        } else {
+            Ok(value as usize)
+        }
+    }
+
+    pub fn read_int(&mut self) -> Result<i64, Error> {
+        match self.header()? {
+            (_, value) if value > i64::MAX as u64 => Err(Error::Unsupported),
+            (MAJOR_UNSIGNED, value) => Ok(value as i64),
+            (MAJOR_NEGATIVE, value) => Ok(-1 - value as i64),
+            _ => Err(Error::UnexpectedType),
+        }
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'b [u8], Error> {
+        let len = Self::length(self.expect(MAJOR_BYTES)?)?;
+        self.take(len)
+    }
+
+    /// Read a text string, as its UTF-8 encoding.
+    pub fn read_text(&mut self) -> Result<&'b [u8], Error> {
+        let len = Self::length(self.expect(MAJOR_TEXT)?)?;
+        self.take(len)
+    }
+
+    /// Read the header of an array, and return its number of items.
+    pub fn read_array(&mut self) -> Result<usize, Error> {
+        Self::length(self.expect(MAJOR_ARRAY)?)
+    }
+
+    /// Read the header of a map, and return its number of entries.
+    pub fn read_map(&mut self) -> Result<usize, Error> {
+        Self::length(self.expect(MAJOR_MAP)?)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        match self.expect(MAJOR_SIMPLE)? {
+            SIMPLE_FALSE => Ok(false),
+            SIMPLE_TRUE => Ok(true),
+            _ => Err(Error::UnexpectedType),
+        }
+    }
+
+    /// Skip over the next item, with everything nested in it.
+    pub fn skip(&mut self) -> Result<(), Error> {
+        self.skip_nested(MAX_DEPTH)
+    }
+
+    fn skip_nested(&mut self, depth: usize) -> Result<(), Error> {
+        if depth == 0 {
+            return Err(Error::Unsupported);
+        }
+        let (major, value) = self.header()?;
+        match major {
+            MAJOR_BYTES | MAJOR_TEXT => {
+                self.take(Self::length(value)?)?;
+            }
+            MAJOR_ARRAY => {
+                for _ in 0..value {
+                    self.skip_nested(depth - 1)?;
+                }
+            }
+            MAJOR_MAP => {
+                for _ in 0..value {
+                    self.skip_nested(depth - 1)?;
+                    self.skip_nested(depth - 1)?;
+                }
+            }
+            MAJOR_TAG => self.skip_nested(depth - 1)?,
+            // Integers and simple values are all in the header
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Writes CBOR items to a buffer, one after the other. Writing past the end
+/// of the buffer is only reported by `position()`.
+pub struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+    overflow: bool,
+}
+
+impl<'b> Writer<'b> {
+    /// A writer adding items to `buf` from offset `pos`.
+    pub fn new(buf: &'b mut [u8], pos: usize) -> Writer<'b> {
+        Writer {
+            buf: buf,
+            pos: pos,
+            overflow: false,
+        }
+    }
+
+    /// The offset after the last item written, or `None` if the items did
+    /// not fit in the buffer.
+    pub fn position(&self) -> Option<usize> {
+        if self.overflow {
+            None
+        } else {
+            Some(self.pos)
+        }
+    }
+
+    /// Write `bytes` as they are, for example an item encoded beforehand.
+    pub fn raw(&mut self, bytes: &[u8]) {
+        if self.overflow {
+            return;
+        }
+        match self.buf.get_mut(self.pos..self.pos + bytes.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(bytes);
+                self.pos += bytes.len();
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    fn header(&mut self, major: u8, value: u64) {
+        let major = major << 5;
+        if value < 24 {
+            self.raw(&[major | value as u8]);
+        } else if value <= 0xff {
+            self.raw(&[major | 24, value as u8]);
+        } else if value <= 0xffff {
+            self.raw(&[major | 25]);
+            self.raw(&(value as u16).to_be_bytes());
+        } else if value <= 0xffff_ffff {
+            self.raw(&[major | 26]);
+            self.raw(&(value as u32).to_be_bytes());
+        } else {
+            self.raw(&[major | 27]);
+            self.raw(&value.to_be_bytes());
+        }
+    }
+
+    pub fn int(&mut self, value: i64) {
+        if value >= 0 {
+            self.header(MAJOR_UNSIGNED, value as u64);
+        } else {
+            self.header(MAJOR_NEGATIVE, (-1 - value) as u64);
+        }
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes_header(bytes.len());
+        self.raw(bytes);
+    }
+
+    /// Write the header of a byte string of `len` bytes, which the caller
+    /// then writes with `raw()`.
+    pub fn bytes_header(&mut self, len: usize) {
+        self.header(MAJOR_BYTES, len as u64);
+    }
+
+    pub fn text(&mut self, text: &str) {
+        self.header(MAJOR_TEXT, text.len() as u64);
+        self.raw(text.as_bytes());
+    }
+
+    /// Write the header of an array of `len` items, which follow.
+    pub fn array(&mut self, len: usize) {
+        self.header(MAJOR_ARRAY, len as u64);
+    }
+
+    /// Write the header of a map of `len` entries, which follow as keys and
+    /// values.
+    pub fn map(&mut self, len: usize) {
+        self.header(MAJOR_MAP, len as u64);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.header(MAJOR_SIMPLE, if value { SIMPLE_TRUE } else { SIMPLE_FALSE });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, Reader, Writer};
+
+    #[test]
+    fn test_int() {
+        // From the examples in RFC 8949, appendix A.
+        let vectors: [(i64, &[u8]); 14] = [
+            (0, &[0x00]),
+            (1, &[0x01]),
+            (23, &[0x17]),
+            (24, &[0x18, 0x18]),
+            (100, &[0x18, 0x64]),
+            (1000, &[0x19, 0x03, 0xe8]),
+            (1000000, &[0x1a, 0x00, 0x0f, 0x42, 0x40]),
+            (
+                1000000000000,
+                &[0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00],
+            ),
+            (-1, &[0x20]),
+            (-10, &[0x29]),
+            (-100, &[0x38, 0x63]),
+            (-1000, &[0x39, 0x03, 0xe7]),
+            (
+                i64::MAX,
+                &[0x1b, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+            (
+                i64::MIN,
+                &[0x3b, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+        ];
+        for (value, encoding) in vectors.iter() {
+            let mut buf = [0; 9];
+            let mut writer = Writer::new(&mut buf, 0);
+            writer.int(*value);
+            assert_eq!(writer.position(), Some(encoding.len()));
+            assert_eq!(&buf[..encoding.len()], *encoding);
+
+            let mut reader = Reader::new(encoding);
+            assert_eq!(reader.read_int(), Ok(*value));
+            assert_eq!(reader.position(), encoding.len());
+        }
+
+        // 2^64 - 1 does not fit.
+        let mut reader = Reader::new(&[0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(reader.read_int(), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut buf = [0; 64];
+        let mut writer = Writer::new(&mut buf, 0);
+        writer.map(3);
+        writer.int(1);
+        writer.bytes(&[1, 2, 3, 4]);
+        writer.int(2);
+        writer.array(2);
+        writer.text("IETF");
+        writer.bool(true);
+        writer.int(-7);
+        writer.bytes_header(2);
+        writer.raw(&[0xaa, 0xbb]);
+        let len = writer.position().unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[
+                0xa3, 0x01, 0x44, 0x01, 0x02, 0x03, 0x04, 0x02, 0x82, 0x64, 0x49, 0x45, 0x54, 0x46,
+                0xf5, 0x26, 0x42, 0xaa, 0xbb
+            ]
+        );
+
+        let mut reader = Reader::new(&buf[..len]);
+        assert_eq!(reader.read_map(), Ok(3));
+        assert_eq!(reader.read_int(), Ok(1));
+        assert_eq!(reader.read_bytes(), Ok(&[1, 2, 3, 4][..]));
+        assert_eq!(reader.read_int(), Ok(2));
+        assert_eq!(reader.read_array(), Ok(2));
+        assert_eq!(reader.read_text(), Ok(&b"IETF"[..]));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_int(), Ok(-7));
+        assert_eq!(reader.read_bytes(), Ok(&[0xaa, 0xbb][..]));
+        assert_eq!(reader.position(), len);
+        assert_eq!(reader.read_int(), Err(Error::Truncated));
+
+        // The whole map can be skipped.
+        let mut reader = Reader::new(&buf[..len]);
+        assert_eq!(reader.skip(), Ok(()));
+        assert_eq!(reader.position(), len);
+    }
+
+    #[test]
+    fn test_truncated() {
+        // {1: h'0102', 2: [false, "a"], 3: 1000}
+        let encoding = [
+            0xa3, 0x01, 0x42, 0x01, 0x02, 0x02, 0x82, 0xf4, 0x61, 0x61, 0x03, 0x19, 0x03, 0xe8,
+        ];
+        assert_eq!(Reader::new(&encoding).skip(), Ok(()));
+        for len in 0..encoding.len() {
+            assert_eq!(Reader::new(&encoding[..len]).skip(), Err(Error::Truncated));
+        }
+
+        // A string longer than the data left.
+        assert_eq!(
+            Reader::new(&[0x45, 0x01, 0x02]).read_bytes(),
+            Err(Error::Truncated)
+        );
+        assert_eq!(
+            Reader::new(&[0x7b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]).read_text(),
+            Err(Error::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        // Indefinite lengths and reserved additional information.
+        for initial in [0x5f, 0x7f, 0x9f, 0xbf, 0x1c, 0x1d, 0x1e].iter() {
+            assert_eq!(
+                Reader::new(&[*initial, 0xff]).skip(),
+                Err(Error::Unsupported)
+            );
+        }
+
+        // Nested deeper than the reader follows.
+        assert_eq!(
+            Reader::new(&[0x81, 0x81, 0x81, 0x81, 0x00]).skip(),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(Reader::new(&[0x81, 0x81, 0x81, 0x00]).skip(), Ok(()));
+
+        // Items of another type than the one asked for.
+        assert_eq!(
+            Reader::new(&[0x41, 0x00]).read_int(),
+            Err(Error::UnexpectedType)
+        );
+        assert_eq!(
+            Reader::new(&[0x61, 0x61]).read_bytes(),
+            Err(Error::UnexpectedType)
+        );
+        assert_eq!(Reader::new(&[0x80]).read_map(), Err(Error::UnexpectedType));
+        assert_eq!(
+            Reader::new(&[0xa0]).read_array(),
+            Err(Error::UnexpectedType)
+        );
+        assert_eq!(Reader::new(&[0xf6]).read_bool(), Err(Error::UnexpectedType));
+        assert_eq!(Reader::new(&[0x01]).read_bool(), Err(Error::UnexpectedType));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut buf = [0; 4];
+        let mut writer = Writer::new(&mut buf, 1);
+        writer.bytes(&[1, 2]);
+        assert_eq!(writer.position(), Some(4));
+        writer.int(0);
+        assert_eq!(writer.position(), None);
+        // Nothing more is written once an item did not fit.
+        writer.bytes(&[]);
+        assert_eq!(writer.position(), None);
+        assert_eq!(buf, [0, 0x42, 1, 2]);
+    }
+}
@@ -0,0 +1,71 @@
+//! CTAP2 (FIDO2) authenticator in the kernel.
+//!
+//! Where `capsules::ctap` passes CTAP HID packets to an application, this
+//! authenticator handles them in the kernel. It speaks CTAPHID over a
+//! `hil::usb_hid::UsbHid`, such as `capsules::usb::ctap::CtapHid`, hashes
+//! with a `hil::digest::Digest`, and keeps one key pair per credential in the
+//! key slots of a `hil::signature` signer, such as
+//! `capsules::p256::P256Software`.
+//!
+//! It implements the part of CTAP 2.0 needed to register and to log in with
+//! a security key:
+//!
+//! - `authenticatorMakeCredential`, for ES256 credentials, with a packed
+//!   self attestation,
+//! - `authenticatorGetAssertion`, for credentials in the allow list,
+//! - `authenticatorGetInfo` and `authenticatorReset`.
+//!
+//! The user confirms that they are present by pressing a button, for each
+//! new credential, assertion and reset.
+//!
+//! Limitations:
+//!
+//! - There is no PIN and no user verification.
+//! - Credentials are not discoverable (resident keys are not supported).
+//! - There are only as many credentials as key slots. Once they are all
+//!   used, new credentials are refused until the authenticator is reset.
+//! - Without nonvolatile storage, for the authenticator and for the signer,
+//!   credentials are lost on reset.
+//! - Only the wait for the user can be cancelled, and keepalives are only
+//!   sent during that wait.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let (ctap, authenticator) = components::ctap2::Ctap2Component::new(
+//!     &nrf52840_peripherals.usbd,
+//!     0x1915, // Nordic Semiconductor
+//!     0x503a, // lowRISC generic FS USB
+//!     strings,
+//!     sha,
+//!     p256,
+//!     mux_alarm,
+//!     &nrf52840_peripherals.gpio_port[BUTTON1_PIN],
+//!     kernel::hil::gpio::ActivationMode::ActiveLow,
+//!     kernel::hil::gpio::FloatingState::PullUp,
+//!     [0; 16], // AAGUID
+//! )
+//! .finalize(components::ctap2_component_helper!(
+//!     nrf52840::usbd::Usbd,
+//!     capsules::sha256::Sha256Software<'static>,
+//!     capsules::p256::P256Software<'static>,
+//!     nrf52840::rtc::Rtc<'static>,
+//! ));
+//!
+//! // Optionally, keep the credentials across resets. The signer needs
+//! // storage of its own for the keys.
+//! authenticator.set_storage(
+//!     storage,
+//!     0x0,
+//!     &mut capsules::ctap2::authenticator::TABLE_BUFFER,
+//! );
+//! storage.set_client(authenticator);
+//!
+//! ctap.enable();
+//! ctap.attach();
+//! authenticator.start();
+//! ```
+
+pub mod authenticator;
+pub mod cbor;
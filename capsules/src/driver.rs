@@ -19,6 +19,17 @@ pub enum NUM {
 
     // Kernel
     Ipc                   = 0x10000,
+    ProcessEvents         = 0x10001,
+    ProcessManager        = 0x10002,
+    IpcRpc                = 0x10003,
+    EnergyEstimator       = 0x10004,
+    InstalledApps         = 0x10005,
+    AppUpdate             = 0x10006,
+    HealthMonitor         = 0x10007,
+    NetworkTime           = 0x10008,
+    MeasuredBoot          = 0x10009,
+    SecureBoot            = 0x1000A,
+    WallClockAlarm        = 0x1000B,
 
     // HW Buses
     Spi                   = 0x20001,
@@ -26,22 +37,30 @@ pub enum NUM {
     I2cMaster             = 0x20003,
     UsbUser               = 0x20005,
     I2cMasterSlave        = 0x20006,
+    ModbusRtu             = 0x20007,
 
     // Radio
     BleAdvertising        = 0x30000,
     Ieee802154            = 0x30001,
     Udp                   = 0x30002,
+    Socket                = 0x30003,
+    Tcp                   = 0x30004,
+    Cellular              = 0x30005,
+    RadioLimits           = 0x30006,
 
     // Cryptography
     Rng                   = 0x40001,
     Crc                   = 0x40002,
     Hmac                  = 0x40003,
     CtapHid               = 0x40004,
+    Totp                  = 0x40005,
 
     // Storage
     AppFlash              = 0x50000,
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
+    Log                   = 0x50003,
+    Kv                    = 0x50004,
 
     // Sensors
     Temperature           = 0x60000,
@@ -50,6 +69,11 @@ pub enum NUM {
     NINEDOF               = 0x60004,
     Proximity             = 0x60005,
     SoundPressure         = 0x60006,
+    SensorTrigger         = 0x60007,
+    ImuFusion             = 0x60008,
+    Gnss                  = 0x60009,
+    Geofence              = 0x6000A,
+    Odometer              = 0x6000B,
 
     // Sensor ICs
     Tsl2561               = 0x70000,
@@ -71,5 +95,10 @@ pub enum NUM {
     Screen                = 0x90001,
     Touch                 = 0x90002,
     TextScreen            = 0x90003,
+    Audio                 = 0x90004,
+    CapSense              = 0x90005,
+    Inference             = 0x90006,
+    Servo                 = 0x90007,
+    Qdec                  = 0x90008,
 }
 }
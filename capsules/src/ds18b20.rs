@@ -0,0 +1,263 @@
+//! Driver for the DS18B20 1-Wire temperature sensor.
+//!
+//! Implements the `TemperatureDriver` HIL, so it can be exposed to userspace
+//! through the `temperature` capsule. Readings use the sensor's default
+//! 12-bit resolution, which takes up to 750 ms per conversion.
+//!
+//! If the sensor is the only device on the bus no ROM code is needed;
+//! otherwise pass the ROM code of the sensor, for example one found with
+//! `OneWireMaster::search_next()`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ds18b20_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let ds18b20 = static_init!(
+//!     capsules::ds18b20::Ds18b20<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::ds18b20::Ds18b20::new(
+//!         onewire,
+//!         ds18b20_alarm,
+//!         None,
+//!         &mut capsules::ds18b20::BUFFER
+//!     )
+//! );
+//! onewire.set_client(ds18b20);
+//! ds18b20_alarm.set_alarm_client(ds18b20);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::onewire::{OneWireClient, OneWireMaster, MATCH_ROM, SKIP_ROM};
+use kernel::hil::sensors;
+use kernel::hil::time::{self, Alarm};
+use kernel::ErrorCode;
+
+/// Large enough for a Match ROM command and the scratchpad.
+pub static mut BUFFER: [u8; 10] = [0; 10];
+
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+const SCRATCHPAD_LEN: usize = 9;
+
+/// Maximum conversion time at 12-bit resolution.
+const CONVERSION_MS: u32 = 750;
+
+/// Dallas/Maxim CRC-8 used for ROM codes and the scratchpad.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0;
+    for byte in data.iter() {
+        crc ^= *byte;
+        for _ in 0..8 {
+            if crc & 0x01 != 0 {
+                crc = (crc >> 1) ^ 0x8C;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    ResetConvert,
+    StartConvert,
+    Converting,
+    ResetRead,
+    StartRead,
+    ReadScratchpad,
+}
+
+pub struct Ds18b20<'a, A: Alarm<'a>> {
+    bus: &'a dyn OneWireMaster<'a>,
+    alarm: &'a A,
+    rom: Option<u64>,
+    client: OptionalCell<&'a dyn sensors::TemperatureClient>,
+    state: Cell<State>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, A: Alarm<'a>> Ds18b20<'a, A> {
+    pub fn new(
+        bus: &'a dyn OneWireMaster<'a>,
+        alarm: &'a A,
+        rom: Option<u64>,
+        buffer: &'static mut [u8],
+    ) -> Ds18b20<'a, A> {
+        Ds18b20 {
+            bus: bus,
+            alarm: alarm,
+            rom: rom,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    /// Select the sensor and send it `command`.
+    fn send_command(&self, command: u8) {
+        self.buffer.take().map(|buffer| {
+            let len = match self.rom {
+                Some(rom) => {
+                    buffer[0] = MATCH_ROM;
+                    buffer[1..9].copy_from_slice(&rom.to_le_bytes());
+                    buffer[9] = command;
+                    10
+                }
+                None => {
+                    buffer[0] = SKIP_ROM;
+                    buffer[1] = command;
+                    2
+                }
+            };
+            if let Err((e, buffer)) = self.bus.write(buffer, len) {
+                self.buffer.replace(buffer);
+                self.done(Err(e));
+            }
+        });
+    }
+
+    /// Report a reading, or why it failed.
+    fn done(&self, value: Result<usize, ErrorCode>) {
+        self.state.set(State::Idle);
+        self.client.map(|client| match value {
+            Ok(value) => client.callback(value),
+            Err(e) => client.error(e),
+        });
+    }
+}
+
+/// The temperature in a scratchpad read from the sensor, in hundredths of
+/// degrees.
+///
+/// A scratchpad of zeros has a valid CRC, but is what is read when no sensor
+/// answers on a bus held low, so it is rejected.
+fn parse_scratchpad(scratchpad: &[u8]) -> Result<usize, ErrorCode> {
+    let scratchpad = scratchpad.get(..SCRATCHPAD_LEN).ok_or(ErrorCode::SIZE)?;
+    if scratchpad.iter().all(|byte| *byte == 0) {
+        return Err(ErrorCode::NODEVICE);
+    }
+    if crc8(&scratchpad[..SCRATCHPAD_LEN - 1]) != scratchpad[SCRATCHPAD_LEN - 1] {
+        return Err(ErrorCode::FAIL);
+    }
+    // The temperature is in 1/16 degrees, convert to hundredths.
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]) as i32;
+    Ok((raw * 25 / 4) as usize)
+}
+
+impl<'a, A: Alarm<'a>> sensors::TemperatureDriver<'a> for Ds18b20<'a, A> {
+    fn set_client(&self, client: &'a dyn sensors::TemperatureClient) {
+        self.client.set(client);
+    }
+
+    fn read_temperature(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.bus.reset()?;
+        self.state.set(State::ResetConvert);
+        Ok(())
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Ds18b20<'a, A> {
+    fn alarm(&self) {
+        if self.state.get() == State::Converting {
+            match self.bus.reset() {
+                Ok(()) => self.state.set(State::ResetRead),
+                Err(e) => self.done(Err(e)),
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> OneWireClient for Ds18b20<'a, A> {
+    fn reset_done(&self, result: Result<(), ErrorCode>) {
+        if result.is_err() {
+            // No sensor on the bus.
+            self.done(Err(ErrorCode::NODEVICE));
+            return;
+        }
+        match self.state.get() {
+            State::ResetConvert => {
+                self.state.set(State::StartConvert);
+                self.send_command(CONVERT_T);
+            }
+            State::ResetRead => {
+                self.state.set(State::StartRead);
+                self.send_command(READ_SCRATCHPAD);
+            }
+            _ => {}
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        if let Err(e) = result {
+            self.buffer.replace(buffer);
+            self.done(Err(e));
+            return;
+        }
+        match self.state.get() {
+            State::StartConvert => {
+                self.buffer.replace(buffer);
+                self.state.set(State::Converting);
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(CONVERSION_MS));
+            }
+            State::StartRead => {
+                self.state.set(State::ReadScratchpad);
+                if let Err((e, buffer)) = self.bus.read(buffer, SCRATCHPAD_LEN) {
+                    self.buffer.replace(buffer);
+                    self.done(Err(e));
+                }
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn read_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        let value = result.and_then(|()| parse_scratchpad(buffer));
+        self.buffer.replace(buffer);
+        self.done(value);
+    }
+
+    fn search_done(&self, _result: Result<Option<u64>, ErrorCode>) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_scratchpad() {
+        // 25.0625 degrees
+        let scratchpad = [0x91, 0x01, 0x4b, 0x46, 0x7f, 0xff, 0x0f, 0x10, 0x25];
+        assert_eq!(parse_scratchpad(&scratchpad), Ok(2506));
+
+        // -10.125 degrees
+        let scratchpad = [0x5e, 0xff, 0x4b, 0x46, 0x7f, 0xff, 0x02, 0x10, 0xb6];
+        assert_eq!(parse_scratchpad(&scratchpad).map(|t| t as isize), Ok(-1012));
+    }
+
+    #[test]
+    fn test_parse_scratchpad_errors() {
+        let mut scratchpad = [0x91, 0x01, 0x4b, 0x46, 0x7f, 0xff, 0x0f, 0x10, 0x25];
+        scratchpad[0] ^= 1;
+        assert_eq!(parse_scratchpad(&scratchpad), Err(ErrorCode::FAIL));
+        assert_eq!(
+            parse_scratchpad(&[0; SCRATCHPAD_LEN]),
+            Err(ErrorCode::NODEVICE)
+        );
+        assert_eq!(parse_scratchpad(&[0x91, 0x01]), Err(ErrorCode::SIZE));
+    }
+}
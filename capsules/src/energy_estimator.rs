@@ -0,0 +1,289 @@
+//! Estimates how much energy each process has used.
+//!
+//! This helps developers attribute battery drain to apps. The estimate
+//! combines three sources, each weighted by coefficients the board measures
+//! for its hardware:
+//!
+//! - the CPU time each process has executed for, as accounted by the kernel
+//!   (this needs a scheduler that uses timeslices);
+//! - the bytes the radio has transmitted and received for each process, from
+//!   a radio driver implementing `RadioUsage`;
+//! - the time power domains from a `kernel::power::PowerRegistry` are on.
+//!   Every sampling interval, the energy of the powered domains is split
+//!   between the processes in proportion to the CPU time they used in that
+//!   interval. Energy from intervals in which no process ran is reported as
+//!   unattributed.
+//!
+//! The estimator keeps a small grant in every process to track its share of
+//! the power domain energy.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! static DOMAIN_COEFFICIENTS: [(usize, u32); 2] =
+//!     [(apollo3::pwrctrl::IOM2, 120), (apollo3::pwrctrl::UART0, 90)];
+//!
+//! let energy_estimator = static_init!(
+//!     capsules::energy_estimator::EnergyEstimator<
+//!         'static,
+//!         VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!         ProcessMgmtCap,
+//!     >,
+//!     capsules::energy_estimator::EnergyEstimator::new(
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         energy_alarm,
+//!         capsules::energy_estimator::EnergyModel {
+//!             cpu_nj_per_ms: 9_000,
+//!             radio_tx_nj_per_byte: 1_500,
+//!             radio_rx_nj_per_byte: 1_200,
+//!             domain_nj_per_ms: &DOMAIN_COEFFICIENTS,
+//!         },
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! energy_alarm.set_alarm_client(energy_estimator);
+//! energy_estimator.set_power_registry(power_registry);
+//! energy_estimator.set_radio(radio_driver);
+//! energy_estimator.start();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Processes are identified by the unique identifier the kernel gives them,
+//! as returned by command `1` of the process events driver. Energy is in
+//! microjoules.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the energy process `arg1` has used.
+//! - `2`: Get the energy process `arg1` has used for the CPU, the radio and
+//!   the power domains.
+//! - `3`: Get the energy of the power domains not attributed to a process.
+//!
+//! Commands `1` and `2` return `INVAL` if no process has the identifier.
+
+use core::cell::Cell;
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm};
+use kernel::power::PowerRegistry;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::EnergyEstimator as usize;
+
+/// How often the power domains are sampled, in milliseconds.
+const SAMPLE_INTERVAL_MS: u32 = 100;
+
+/// Implemented by radio drivers that count their traffic per process.
+pub trait RadioUsage {
+    /// Returns the payload bytes transmitted and received for `appid`.
+    fn radio_bytes(&self, appid: ProcessId) -> (usize, usize);
+}
+
+/// Energy coefficients of the board, in nanojoules.
+pub struct EnergyModel<'a> {
+    /// Energy per millisecond the CPU runs a process.
+    pub cpu_nj_per_ms: u32,
+    /// Energy per byte the radio transmits.
+    pub radio_tx_nj_per_byte: u32,
+    /// Energy per byte the radio receives.
+    pub radio_rx_nj_per_byte: u32,
+    /// Energy per millisecond a power domain is on, as (domain, energy)
+    /// pairs. Domains that are not listed are not counted.
+    pub domain_nj_per_ms: &'a [(usize, u32)],
+}
+
+#[derive(Default)]
+pub struct App {
+    /// CPU time of the process at the last sample.
+    sampled_cpu_time_us: u64,
+    /// Power domain energy attributed to the process.
+    domain_nj: u64,
+}
+
+/// Energy a process has used, in nanojoules.
+#[derive(Clone, Copy)]
+struct Estimate {
+    cpu_nj: u64,
+    radio_nj: u64,
+    domain_nj: u64,
+}
+
+pub struct EnergyEstimator<'a, A: Alarm<'a>, C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+    alarm: &'a A,
+    model: EnergyModel<'a>,
+    power: OptionalCell<&'a PowerRegistry<'a>>,
+    radio: OptionalCell<&'a dyn RadioUsage>,
+    apps: Grant<App>,
+    unattributed_nj: Cell<u64>,
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> EnergyEstimator<'a, A, C> {
+    pub fn new(
+        kernel: &'static Kernel,
+        capability: C,
+        alarm: &'a A,
+        model: EnergyModel<'a>,
+        grant: Grant<App>,
+    ) -> EnergyEstimator<'a, A, C> {
+        EnergyEstimator {
+            kernel: kernel,
+            capability: capability,
+            alarm: alarm,
+            model: model,
+            power: OptionalCell::empty(),
+            radio: OptionalCell::empty(),
+            apps: grant,
+            unattributed_nj: Cell::new(0),
+        }
+    }
+
+    pub fn set_power_registry(&self, power: &'a PowerRegistry<'a>) {
+        self.power.set(power);
+    }
+
+    pub fn set_radio(&self, radio: &'a dyn RadioUsage) {
+        self.radio.set(radio);
+    }
+
+    /// Start sampling the power domains.
+    pub fn start(&self) {
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(SAMPLE_INTERVAL_MS));
+    }
+
+    /// Energy the powered domains used over the last sampling interval.
+    fn domain_energy(&self) -> u64 {
+        self.power.map_or(0, |power| {
+            let mut energy = 0;
+            power.each_domain(|domain, powered| {
+                if powered {
+                    let coefficient = self
+                        .model
+                        .domain_nj_per_ms
+                        .iter()
+                        .find(|(id, _)| *id == domain)
+                        .map_or(0, |(_, nj_per_ms)| *nj_per_ms);
+                    energy += coefficient as u64 * SAMPLE_INTERVAL_MS as u64;
+                }
+            });
+            energy
+        })
+    }
+
+    /// Split the energy of the powered domains between the processes that
+    /// ran since the last sample.
+    fn sample(&self) {
+        let energy = self.domain_energy();
+
+        let total_cpu_time_us = Cell::new(0);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                let cpu_time_us = process.debug_cpu_time_us();
+                let _ = self.apps.enter(process.processid(), |app| {
+                    total_cpu_time_us.set(
+                        total_cpu_time_us.get()
+                            + cpu_time_us.saturating_sub(app.sampled_cpu_time_us),
+                    );
+                });
+            });
+        let total_cpu_time_us = total_cpu_time_us.get();
+        if total_cpu_time_us == 0 {
+            self.unattributed_nj
+                .set(self.unattributed_nj.get() + energy);
+        }
+
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                let cpu_time_us = process.debug_cpu_time_us();
+                let _ = self.apps.enter(process.processid(), |app| {
+                    if total_cpu_time_us > 0 {
+                        let used = cpu_time_us.saturating_sub(app.sampled_cpu_time_us);
+                        app.domain_nj += energy * used / total_cpu_time_us;
+                    }
+                    app.sampled_cpu_time_us = cpu_time_us;
+                });
+            });
+    }
+
+    /// Estimate the energy used by the process with identifier `id`.
+    fn estimate(&self, id: usize) -> Option<Estimate> {
+        let estimate = Cell::new(None);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                let appid = process.processid();
+                if appid.id() != id {
+                    return;
+                }
+                let cpu_nj = process.debug_cpu_time_us() * self.model.cpu_nj_per_ms as u64 / 1000;
+                let radio_nj = self.radio.map_or(0, |radio| {
+                    let (tx_bytes, rx_bytes) = radio.radio_bytes(appid);
+                    tx_bytes as u64 * self.model.radio_tx_nj_per_byte as u64
+                        + rx_bytes as u64 * self.model.radio_rx_nj_per_byte as u64
+                });
+                let domain_nj = self.apps.enter(appid, |app| app.domain_nj).unwrap_or(0);
+                estimate.set(Some(Estimate {
+                    cpu_nj,
+                    radio_nj,
+                    domain_nj,
+                }));
+            });
+        estimate.get()
+    }
+}
+
+/// Convert nanojoules to microjoules, saturating at the largest `u32`.
+fn to_uj(nj: u64) -> u32 {
+    (nj / 1000).min(u32::MAX as u64) as u32
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> time::AlarmClient
+    for EnergyEstimator<'a, A, C>
+{
+    fn alarm(&self) {
+        self.sample();
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(SAMPLE_INTERVAL_MS));
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> Driver for EnergyEstimator<'a, A, C> {
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the energy a process has used
+    /// - `2`: Get the energy a process has used, by source
+    /// - `3`: Get the energy not attributed to a process
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, _: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success(),
+            1 => match self.estimate(arg1) {
+                Some(estimate) => CommandReturn::success_u32(to_uj(
+                    estimate.cpu_nj + estimate.radio_nj + estimate.domain_nj,
+                )),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            2 => match self.estimate(arg1) {
+                Some(estimate) => CommandReturn::success_u32_u32_u32(
+                    to_uj(estimate.cpu_nj),
+                    to_uj(estimate.radio_nj),
+                    to_uj(estimate.domain_nj),
+                ),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            3 => CommandReturn::success_u32(to_uj(self.unattributed_nj.get())),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
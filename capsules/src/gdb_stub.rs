@@ -0,0 +1,884 @@
+//! GDB remote serial protocol stub for debugging processes over a UART.
+//!
+//! The stub lets GDB debug processes at the source level on boards where no
+//! debug probe can be attached, for example because the SWD pins are not
+//! reachable. It speaks the GDB remote serial protocol on a UART dedicated to
+//! it, and presents each process as a thread, with thread id `ProcessId::id()`
+//! plus one.
+//!
+//! Once GDB connects, faults of any process stop that process where it
+//! faulted, instead of applying the fault policy, and are reported to GDB.
+//! Other processes keep running while one is stopped. GDB can read memory
+//! the process can access, and write its RAM and registers. Breakpoints use
+//! the debug hardware of the chip, through the `Breakpoints` HIL, so a
+//! breakpoint hit is a fault of the process. Breakpoints can only be set in
+//! the code of the selected thread, as a breakpoint hit in the kernel would
+//! be a kernel fault. Without the HIL, breakpoints are not supported.
+//!
+//! Limitations:
+//!
+//! - Single-stepping is not supported, so GDB cannot step over a breakpoint.
+//!   To continue from a breakpoint, delete it first.
+//! - Registers are those of the process when it entered the kernel. The stack
+//!   pointer of Cortex-M processes cannot be changed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let gdb_stub = static_init!(
+//!     capsules::gdb_stub::GdbStub<
+//!         'static,
+//!         VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!         ProcessMgmtCap,
+//!     >,
+//!     capsules::gdb_stub::GdbStub::new(
+//!         &nrf52840::uart::UARTE0,
+//!         gdb_alarm,
+//!         Some(&cortexm4::fpb::FPB),
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         cortexm4::syscall::GDB_TARGET_XML,
+//!         &mut capsules::gdb_stub::RX_BUF,
+//!         &mut capsules::gdb_stub::PACKET_BUF,
+//!         &mut capsules::gdb_stub::TX_BUF,
+//!     )
+//! );
+//! hil::uart::Transmit::set_transmit_client(&nrf52840::uart::UARTE0, gdb_stub);
+//! hil::uart::Receive::set_receive_client(&nrf52840::uart::UARTE0, gdb_stub);
+//! gdb_alarm.set_alarm_client(gdb_stub);
+//! cortexm4::fpb::FPB.enable();
+//! gdb_stub.start();
+//! ```
+//!
+//! The chip must route the DebugMonitor exception to the hard fault handler
+//! for breakpoints to work. Then connect GDB to the UART:
+//!
+//! ```text
+//! (gdb) target remote /dev/ttyUSB0
+//! ```
+//!
+//! Protocol Support
+//! ----------------
+//!
+//! - `?`, `g`, `p`, `P`, `m`, `M`, `H`, `T`, `c`, `D`, `k`, and Ctrl-C.
+//! - `Z0`, `Z1`, `z0` and `z1`, all as hardware breakpoints.
+//! - `qSupported`, `qAttached`, `qC`, `qfThreadInfo`, `qsThreadInfo`,
+//!   `qThreadExtraInfo` and `qXfer:features:read`, to give GDB the target
+//!   description of the architecture.
+//!
+//! Other packets, including `s`, get the empty reply of unsupported packets.
+
+use core::cell::Cell;
+use core::{cmp, mem};
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::TakeCell;
+use kernel::hil::breakpoint::Breakpoints;
+use kernel::hil::time::{Alarm, AlarmClient};
+use kernel::hil::uart;
+use kernel::procs::Process;
+use kernel::{ErrorCode, Kernel};
+
+pub static mut RX_BUF: [u8; 1] = [0; 1];
+pub static mut PACKET_BUF: [u8; 256] = [0; 256];
+pub static mut TX_BUF: [u8; 300] = [0; 300];
+
+/// The largest packet GDB may send, as told in the `qSupported` reply. Kept
+/// below the size of `PACKET_BUF`.
+const PACKET_SIZE: usize = 0xf0;
+
+/// How often to check whether a process GDB resumed has stopped.
+const POLL_INTERVAL_MS: u32 = 10;
+
+/// The most breakpoints the stub keeps track of.
+const MAX_BREAKPOINTS: usize = 8;
+
+/// Sent by GDB outside a packet to stop the target.
+const INTERRUPT: u8 = 0x03;
+
+/// Signals reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+#[derive(Clone, Copy, PartialEq)]
+enum RxState {
+    /// Between packets.
+    Idle,
+    /// Reading the data of a packet, after `$`.
+    Data,
+    /// Reading the first checksum digit, after `#`.
+    Checksum,
+    /// Reading the second checksum digit, after the first with this value.
+    ChecksumLow(u8),
+}
+
+/// A reply being written into the transmit buffer.
+struct Reply<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+}
+
+impl Reply<'_> {
+    /// Room left in the buffer, keeping space for the end of the packet.
+    fn remaining(&self) -> usize {
+        self.buffer.len().saturating_sub(self.len + 3)
+    }
+
+    /// Add a byte. Bytes that do not fit are dropped, so callers limit the
+    /// length of replies that could be long.
+    fn push(&mut self, byte: u8) {
+        if self.remaining() > 0 {
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|byte| self.push(byte));
+    }
+
+    fn push_hex_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.push(HEX_DIGITS[(byte >> 4) as usize]);
+            self.push(HEX_DIGITS[(byte & 0xf) as usize]);
+        }
+    }
+
+    /// Add `value` in hex, without leading zeros.
+    fn push_hex(&mut self, value: usize) {
+        let mut digits = 1;
+        while digits < 2 * mem::size_of::<usize>() && value >> (digits * 4) != 0 {
+            digits += 1;
+        }
+        for digit in (0..digits).rev() {
+            self.push(HEX_DIGITS[(value >> (digit * 4)) & 0xf]);
+        }
+    }
+
+    /// Add a register value, in the byte order of the target.
+    fn push_register(&mut self, value: Option<usize>) {
+        match value {
+            Some(value) => self.push_hex_bytes(&(value as u32).to_le_bytes()),
+            None => self.push_str("xxxxxxxx"),
+        }
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a hex number, which must not be empty.
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() {
+        return None;
+    }
+    s.iter().try_fold(0usize, |value, &c| {
+        value.checked_mul(16)?.checked_add(hex_digit(c)? as usize)
+    })
+}
+
+/// Split `s` at the first `separator`.
+fn split(s: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = s.iter().position(|&c| c == separator)?;
+    Some((&s[..index], &s[index + 1..]))
+}
+
+/// Parse `address,length`.
+fn parse_range(s: &[u8]) -> Option<(usize, usize)> {
+    let (address, length) = split(s, b',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+pub struct GdbStub<'a, A: Alarm<'a>, C: ProcessManagementCapability> {
+    uart: &'a dyn uart::UartData<'a>,
+    alarm: &'a A,
+    breakpoints: Option<&'a dyn Breakpoints>,
+    kernel: &'static Kernel,
+    capability: C,
+    target_xml: &'static str,
+    register_count: usize,
+    pc_register: Option<usize>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_state: Cell<RxState>,
+    packet: TakeCell<'static, [u8]>,
+    packet_len: Cell<usize>,
+    packet_checksum: Cell<u8>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    /// Stop reply to send once the transmit buffer is back.
+    pending_stop: Cell<Option<u8>>,
+    /// A received packet waits for the transmit buffer to be handled, and
+    /// reception is paused until then.
+    pending_packet: Cell<bool>,
+    /// A packet failed its checksum and the request to resend it waits for
+    /// the transmit buffer.
+    pending_nak: Cell<bool>,
+    /// Whether faults of processes stop them for GDB.
+    attached: Cell<bool>,
+    /// Whether GDB waits for a stop reply.
+    running: Cell<bool>,
+    /// The thread GDB selected, or 0 for none.
+    thread: Cell<usize>,
+    /// The thread stopped for GDB, or 0 for none.
+    halted: Cell<usize>,
+    breakpoint_addresses: [Cell<Option<usize>>; MAX_BREAKPOINTS],
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> GdbStub<'a, A, C> {
+    /// Create the stub. `target_xml` is the GDB target description of the
+    /// architecture, which numbers the registers as the architecture reads
+    /// them for a debugger.
+    pub fn new(
+        uart: &'a dyn uart::UartData<'a>,
+        alarm: &'a A,
+        breakpoints: Option<&'a dyn Breakpoints>,
+        kernel: &'static Kernel,
+        capability: C,
+        target_xml: &'static str,
+        rx_buffer: &'static mut [u8],
+        packet: &'static mut [u8],
+        tx_buffer: &'static mut [u8],
+    ) -> GdbStub<'a, A, C> {
+        GdbStub {
+            uart: uart,
+            alarm: alarm,
+            breakpoints: breakpoints,
+            kernel: kernel,
+            capability: capability,
+            target_xml: target_xml,
+            register_count: target_xml.matches("<reg ").count(),
+            pc_register: target_xml
+                .split("<reg ")
+                .skip(1)
+                .position(|reg| reg.starts_with("name=\"pc\"")),
+            rx_buffer: TakeCell::new(rx_buffer),
+            rx_state: Cell::new(RxState::Idle),
+            packet: TakeCell::new(packet),
+            packet_len: Cell::new(0),
+            packet_checksum: Cell::new(0),
+            tx_buffer: TakeCell::new(tx_buffer),
+            pending_stop: Cell::new(None),
+            pending_packet: Cell::new(false),
+            pending_nak: Cell::new(false),
+            attached: Cell::new(false),
+            running: Cell::new(false),
+            thread: Cell::new(0),
+            halted: Cell::new(0),
+            breakpoint_addresses: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+        }
+    }
+
+    /// Start listening for GDB.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        self.receive()
+    }
+
+    fn receive(&self) -> Result<(), ErrorCode> {
+        self.rx_buffer
+            .take()
+            .map_or(Err(ErrorCode::BUSY), |buffer| {
+                self.uart
+                    .receive_buffer(buffer, 1)
+                    .map_err(|(err, buffer)| {
+                        self.rx_buffer.replace(buffer);
+                        err
+                    })
+            })
+    }
+
+    /// Run `operation` on the process of `thread`, if it exists.
+    fn with_thread<R>(&self, thread: usize, operation: impl Fn(&dyn Process) -> R) -> Option<R> {
+        let result = Cell::new(None);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if process.processid().id() + 1 == thread {
+                    result.set(Some(operation(process)));
+                }
+            });
+        result.into_inner()
+    }
+
+    /// The thread GDB selected, or the first process if it selected none or
+    /// its process no longer exists.
+    fn current_thread(&self) -> usize {
+        if self.with_thread(self.thread.get(), |_| ()).is_none() {
+            let first = Cell::new(0);
+            self.kernel
+                .process_each_capability(&self.capability, |process| {
+                    if first.get() == 0 {
+                        first.set(process.processid().id() + 1);
+                    }
+                });
+            self.thread.set(first.get());
+        }
+        self.thread.get()
+    }
+
+    fn attach(&self) {
+        if !self.attached.get() {
+            self.attached.set(true);
+            self.kernel
+                .process_each_capability(&self.capability, |process| {
+                    process.set_debugger_attached(true);
+                });
+        }
+    }
+
+    /// Let every process run again without the debugger.
+    fn detach(&self) {
+        if let Some(breakpoints) = self.breakpoints {
+            for (index, address) in self.breakpoint_addresses.iter().enumerate() {
+                if address.take().is_some() {
+                    breakpoints.clear(index);
+                }
+            }
+        }
+        self.with_thread(self.halted.get(), |process| process.resume());
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                process.set_debugger_attached(false);
+                if process.debug_faulted() {
+                    process.resume();
+                }
+            });
+        self.attached.set(false);
+        self.running.set(false);
+        self.halted.set(0);
+        self.alarm.disarm().ok();
+    }
+
+    /// Stop the current thread, and return the signal to report for it.
+    fn halt(&self) -> u8 {
+        let thread = self.current_thread();
+        let faulted = self
+            .with_thread(thread, |process| {
+                process.stop();
+                process.debug_faulted()
+            })
+            .unwrap_or(false);
+        self.halted.set(thread);
+        if faulted {
+            self.fault_signal(thread)
+        } else {
+            SIGINT
+        }
+    }
+
+    /// The signal for a fault of `thread`: `SIGTRAP` at a breakpoint,
+    /// otherwise `SIGSEGV`.
+    fn fault_signal(&self, thread: usize) -> u8 {
+        let pc = self.pc_register.and_then(|pc_register| {
+            self.with_thread(thread, |process| process.debug_read_register(pc_register))
+                .flatten()
+        });
+        if pc.map_or(false, |pc| {
+            self.breakpoint_addresses
+                .iter()
+                .any(|address| address.get() == Some(pc))
+        }) {
+            SIGTRAP
+        } else {
+            SIGSEGV
+        }
+    }
+
+    /// Resume the halted thread, and wait for a process to stop.
+    fn resume(&self) {
+        self.with_thread(self.halted.get(), |process| process.resume());
+        self.halted.set(0);
+        self.running.set(true);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(POLL_INTERVAL_MS));
+    }
+
+    /// Tell GDB the halted thread stopped with `signal`.
+    fn report_stop(&self, signal: u8) {
+        self.running.set(false);
+        if self.tx_buffer.is_none() {
+            self.pending_stop.set(Some(signal));
+            return;
+        }
+        self.send(false, |reply| {
+            self.stop_reply(reply, signal);
+            true
+        });
+    }
+
+    fn stop_reply(&self, reply: &mut Reply, signal: u8) {
+        reply.push(b'T');
+        reply.push_hex_bytes(&[signal]);
+        reply.push_str("thread:");
+        reply.push_hex(self.halted.get());
+        reply.push(b';');
+    }
+
+    /// Send a packet, preceded by an acknowledgement of the packet received if
+    /// `ack`. `build` writes the data of the packet, and returns whether there
+    /// is one to send.
+    fn send(&self, ack: bool, build: impl FnOnce(&mut Reply) -> bool) {
+        self.tx_buffer.take().map(|buffer| {
+            let mut len = 0;
+            if ack {
+                buffer[0] = b'+';
+                len = 1;
+            }
+            let mut reply = Reply {
+                buffer: &mut buffer[len + 1..],
+                len: 0,
+            };
+            if build(&mut reply) {
+                let data_len = reply.len;
+                let checksum = reply.buffer[..data_len]
+                    .iter()
+                    .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+                buffer[len] = b'$';
+                len += 1 + data_len;
+                buffer[len] = b'#';
+                buffer[len + 1] = HEX_DIGITS[(checksum >> 4) as usize];
+                buffer[len + 2] = HEX_DIGITS[(checksum & 0xf) as usize];
+                len += 3;
+            }
+            if len == 0 {
+                self.tx_buffer.replace(buffer);
+            } else if let Err((_, buffer)) = self.uart.transmit_buffer(buffer, len) {
+                self.tx_buffer.replace(buffer);
+            }
+        });
+    }
+
+    fn receive_byte(&self, byte: u8) {
+        match self.rx_state.get() {
+            RxState::Idle => {
+                if byte == b'$' {
+                    self.rx_state.set(RxState::Data);
+                    self.packet_len.set(0);
+                    self.packet_checksum.set(0);
+                } else if byte == INTERRUPT && self.running.get() {
+                    let signal = self.halt();
+                    self.report_stop(signal);
+                }
+                // Acknowledgements are ignored, as replies are not resent.
+            }
+            RxState::Data => {
+                if byte == b'#' {
+                    self.rx_state.set(RxState::Checksum);
+                } else {
+                    self.packet_checksum
+                        .set(self.packet_checksum.get().wrapping_add(byte));
+                    // Packets that do not fit are kept too long, so they fail
+                    // when handled.
+                    let len = self.packet_len.get();
+                    self.packet.map(|packet| {
+                        if len < packet.len() {
+                            packet[len] = byte;
+                        }
+                    });
+                    self.packet_len.set(len + 1);
+                }
+            }
+            RxState::Checksum => {
+                self.rx_state
+                    .set(RxState::ChecksumLow(hex_digit(byte).unwrap_or(0xff)));
+            }
+            RxState::ChecksumLow(high) => {
+                self.rx_state.set(RxState::Idle);
+                let checksum = hex_digit(byte).map(|low| (high << 4) | low);
+                if high <= 0xf && checksum == Some(self.packet_checksum.get()) {
+                    self.handle_packet();
+                } else {
+                    self.send_nak();
+                }
+            }
+        }
+    }
+
+    /// Ask GDB to send the packet again, once the transmit buffer is back if
+    /// it is in use.
+    fn send_nak(&self) {
+        match self.tx_buffer.take() {
+            Some(buffer) => {
+                buffer[0] = b'-';
+                if let Err((_, buffer)) = self.uart.transmit_buffer(buffer, 1) {
+                    self.tx_buffer.replace(buffer);
+                }
+            }
+            None => self.pending_nak.set(true),
+        }
+    }
+
+    /// Handle the received packet. If the transmit buffer is in use, the
+    /// packet is kept until it is back, so its reply is not lost. GDB sends
+    /// no other packet before it receives the acknowledgement.
+    fn handle_packet(&self) {
+        if self.tx_buffer.is_none() {
+            self.pending_packet.set(true);
+            return;
+        }
+        self.packet.take().map(|packet| {
+            let len = self.packet_len.get();
+            self.send(true, |reply| {
+                if len > PACKET_SIZE {
+                    reply.push_str("E01");
+                    true
+                } else {
+                    self.command(&packet[..len], reply)
+                }
+            });
+            self.packet.replace(packet);
+        });
+    }
+
+    /// Handle the packet `packet`, writing the reply into `reply`. Returns
+    /// whether to send the reply; some packets have none.
+    fn command(&self, packet: &[u8], reply: &mut Reply) -> bool {
+        let (&kind, args) = match packet.split_first() {
+            Some(split) => split,
+            None => return true,
+        };
+        let result = match kind {
+            b'?' => {
+                self.attach();
+                self.running.set(false);
+                let signal = self.halt();
+                self.stop_reply(reply, signal);
+                return true;
+            }
+            b'q' => return self.query(args, reply),
+            b'H' => {
+                // Thread ids 0 and -1 mean any thread, so keep the current
+                // one.
+                if args.len() > 1 && &args[1..] != b"0" && &args[1..] != b"-1" {
+                    match parse_hex(&args[1..]) {
+                        Some(thread) if self.with_thread(thread, |_| ()).is_some() => {
+                            self.thread.set(thread);
+                        }
+                        _ => return Self::error(reply),
+                    }
+                }
+                Ok(())
+            }
+            b'T' => match parse_hex(args) {
+                Some(thread) if self.with_thread(thread, |_| ()).is_some() => Ok(()),
+                _ => Err(ErrorCode::INVAL),
+            },
+            b'g' => {
+                let thread = self.current_thread();
+                for index in 0..self.register_count {
+                    reply.push_register(
+                        self.with_thread(thread, |process| process.debug_read_register(index))
+                            .flatten(),
+                    );
+                }
+                return true;
+            }
+            b'p' => {
+                let thread = self.current_thread();
+                let value = parse_hex(args).and_then(|index| {
+                    self.with_thread(thread, |process| process.debug_read_register(index))
+                        .flatten()
+                });
+                reply.push_register(value);
+                return true;
+            }
+            b'P' => self.write_register(args),
+            b'm' => return self.read_memory(args, reply),
+            b'M' => self.write_memory(args),
+            b'c' => {
+                if !args.is_empty() {
+                    let set_pc = match (parse_hex(args), self.pc_register) {
+                        (Some(address), Some(pc_register)) => self
+                            .with_thread(self.halted.get(), |process| {
+                                process.debug_write_register(pc_register, address)
+                            })
+                            .unwrap_or(Err(ErrorCode::INVAL)),
+                        _ => Err(ErrorCode::INVAL),
+                    };
+                    if set_pc.is_err() {
+                        return Self::error(reply);
+                    }
+                }
+                self.resume();
+                return false;
+            }
+            // Only breakpoints on instructions are supported, not watchpoints.
+            b'Z' | b'z' if args.starts_with(b"0,") || args.starts_with(b"1,") => {
+                match self.breakpoints {
+                    Some(breakpoints) => self.breakpoint(kind == b'Z', &args[2..], breakpoints),
+                    None => return true,
+                }
+            }
+            b'D' => {
+                self.detach();
+                Ok(())
+            }
+            b'k' => {
+                self.detach();
+                return false;
+            }
+            _ => return true,
+        };
+        match result {
+            Ok(()) => reply.push_str("OK"),
+            Err(_) => reply.push_str("E01"),
+        }
+        true
+    }
+
+    fn error(reply: &mut Reply) -> bool {
+        reply.push_str("E01");
+        true
+    }
+
+    fn query(&self, args: &[u8], reply: &mut Reply) -> bool {
+        if args.starts_with(b"Supported") {
+            reply.push_str("PacketSize=");
+            reply.push_hex(PACKET_SIZE);
+            reply.push_str(";qXfer:features:read+");
+        } else if let Some(range) = args.strip_prefix(b"Xfer:features:read:target.xml:") {
+            let (offset, length) = match parse_range(range) {
+                Some(range) => range,
+                None => return Self::error(reply),
+            };
+            let xml = self.target_xml.as_bytes();
+            let start = cmp::min(offset, xml.len());
+            let end = cmp::min(
+                xml.len(),
+                start + cmp::min(length, reply.remaining().saturating_sub(1)),
+            );
+            reply.push(if end == xml.len() { b'l' } else { b'm' });
+            xml[start..end].iter().for_each(|&byte| reply.push(byte));
+        } else if args == b"Attached" {
+            reply.push(b'1');
+        } else if args == b"C" {
+            reply.push_str("QC");
+            reply.push_hex(self.current_thread());
+        } else if args == b"fThreadInfo" {
+            reply.push(b'm');
+            let first = Cell::new(true);
+            // The closure cannot borrow the reply mutably, so it takes it.
+            let taken = Cell::new(Some(reply));
+            self.kernel
+                .process_each_capability(&self.capability, |process| {
+                    if let Some(reply) = taken.take() {
+                        if !first.replace(false) {
+                            reply.push(b',');
+                        }
+                        reply.push_hex(process.processid().id() + 1);
+                        taken.set(Some(reply));
+                    }
+                });
+            return true;
+        } else if args == b"sThreadInfo" {
+            reply.push(b'l');
+        } else if let Some(thread) = args.strip_prefix(b"ThreadExtraInfo,") {
+            let name = parse_hex(thread)
+                .and_then(|thread| self.with_thread(thread, |process| process.get_process_name()));
+            match name {
+                Some(name) => reply.push_hex_bytes(name.as_bytes()),
+                None => return Self::error(reply),
+            }
+        }
+        true
+    }
+
+    /// Handle `P`, `index=value`.
+    fn write_register(&self, args: &[u8]) -> Result<(), ErrorCode> {
+        let (index, value) = split(args, b'=').ok_or(ErrorCode::INVAL)?;
+        let index = parse_hex(index).ok_or(ErrorCode::INVAL)?;
+        // The value is in the byte order of the target.
+        let mut bytes = [0; 4];
+        if value.len() != 8 {
+            return Err(ErrorCode::INVAL);
+        }
+        for (byte, digits) in bytes.iter_mut().zip(value.chunks(2)) {
+            *byte = parse_hex(digits).ok_or(ErrorCode::INVAL)? as u8;
+        }
+        let value = u32::from_le_bytes(bytes) as usize;
+        self.with_thread(self.current_thread(), |process| {
+            process.debug_write_register(index, value)
+        })
+        .unwrap_or(Err(ErrorCode::INVAL))
+    }
+
+    /// Handle `m`, `address,length`.
+    fn read_memory(&self, args: &[u8], reply: &mut Reply) -> bool {
+        let (address, length) = match parse_range(args) {
+            Some(range) => range,
+            None => return Self::error(reply),
+        };
+        let length = cmp::min(length, reply.remaining() / 2);
+        let thread = self.current_thread();
+        let mut offset = 0;
+        while offset < length {
+            let len = cmp::min(16, length - offset);
+            let read = self
+                .with_thread(thread, |process| {
+                    let mut chunk = [0; 16];
+                    process
+                        .debug_read_memory(address.wrapping_add(offset), &mut chunk[..len])
+                        .map(|()| chunk)
+                })
+                .unwrap_or(Err(ErrorCode::INVAL));
+            match read {
+                Ok(chunk) => reply.push_hex_bytes(&chunk[..len]),
+                // A partial read is returned as is, unless nothing was read.
+                Err(_) if offset == 0 => return Self::error(reply),
+                Err(_) => break,
+            }
+            offset += len;
+        }
+        true
+    }
+
+    /// Handle `M`, `address,length:data`.
+    fn write_memory(&self, args: &[u8]) -> Result<(), ErrorCode> {
+        let (range, data) = split(args, b':').ok_or(ErrorCode::INVAL)?;
+        let (address, length) = parse_range(range).ok_or(ErrorCode::INVAL)?;
+        if data.len() != length * 2 {
+            return Err(ErrorCode::INVAL);
+        }
+        let thread = self.current_thread();
+        let mut chunk = [0; 16];
+        for (index, digits) in data.chunks(chunk.len() * 2).enumerate() {
+            let len = digits.len() / 2;
+            for (byte, pair) in chunk.iter_mut().zip(digits.chunks(2)) {
+                *byte = parse_hex(pair).ok_or(ErrorCode::INVAL)? as u8;
+            }
+            let offset = index * chunk.len();
+            self.with_thread(thread, |process| {
+                process.debug_write_memory(address.wrapping_add(offset), &chunk[..len])
+            })
+            .unwrap_or(Err(ErrorCode::INVAL))?;
+        }
+        Ok(())
+    }
+
+    /// Handle `Z0`, `Z1`, `z0` and `z1`, `address,kind` after the type.
+    /// Software and hardware breakpoints are both hardware breakpoints.
+    fn breakpoint(
+        &self,
+        insert: bool,
+        args: &[u8],
+        breakpoints: &dyn Breakpoints,
+    ) -> Result<(), ErrorCode> {
+        let (address, _) = split(args, b',').ok_or(ErrorCode::INVAL)?;
+        let address = parse_hex(address).ok_or(ErrorCode::INVAL)?;
+
+        let slots = &self.breakpoint_addresses[..cmp::min(MAX_BREAKPOINTS, breakpoints.count())];
+        if insert {
+            // A breakpoint hit in the kernel or another process would fault
+            // outside the process, so only allow addresses in the code of the
+            // selected process.
+            let in_process = self
+                .with_thread(self.current_thread(), |process| {
+                    address >= process.flash_non_protected_start() as usize
+                        && address < process.flash_end() as usize
+                })
+                .unwrap_or(false);
+            if !in_process {
+                return Err(ErrorCode::INVAL);
+            }
+            if slots.iter().any(|slot| slot.get() == Some(address)) {
+                return Ok(());
+            }
+            let index = slots
+                .iter()
+                .position(|slot| slot.get().is_none())
+                .ok_or(ErrorCode::NOMEM)?;
+            breakpoints.set(index, address)?;
+            slots[index].set(Some(address));
+        } else if let Some(index) = slots.iter().position(|slot| slot.get() == Some(address)) {
+            breakpoints.clear(index);
+            slots[index].set(None);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> AlarmClient for GdbStub<'a, A, C> {
+    fn alarm(&self) {
+        if !self.running.get() {
+            return;
+        }
+        let faulted = Cell::new(0);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if faulted.get() == 0 && process.debug_faulted() {
+                    faulted.set(process.processid().id() + 1);
+                }
+            });
+        match faulted.get() {
+            0 => self
+                .alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(POLL_INTERVAL_MS)),
+            thread => {
+                self.thread.set(thread);
+                self.halted.set(thread);
+                self.report_stop(self.fault_signal(thread));
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> uart::TransmitClient for GdbStub<'a, A, C> {
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        _rcode: Result<(), ErrorCode>,
+    ) {
+        self.tx_buffer.replace(buffer);
+        if self.pending_nak.take() {
+            self.send_nak();
+        } else if self.pending_packet.take() {
+            self.handle_packet();
+            if !self.pending_packet.get() {
+                let _ = self.receive();
+            }
+        } else if let Some(signal) = self.pending_stop.take() {
+            self.report_stop(signal);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> uart::ReceiveClient for GdbStub<'a, A, C> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        _rcode: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        // Bytes received with line errors are handled anyway, as a corrupted
+        // packet fails its checksum.
+        for &byte in &buffer[..cmp::min(rx_len, buffer.len())] {
+            self.receive_byte(byte);
+        }
+        self.rx_buffer.replace(buffer);
+        // The packet buffer holds a packet waiting for its reply, so wait for
+        // it to be handled before receiving more.
+        if !self.pending_packet.get() {
+            let _ = self.receive();
+        }
+    }
+}
@@ -0,0 +1,353 @@
+//! Evaluates geofences in the kernel, so tracking processes can sleep until
+//! the board enters or leaves one of them.
+//!
+//! Each process sets up to `MAX_FENCES` fences, each either a circle or a
+//! polygon of up to `MAX_VERTICES` vertices. The capsule checks every fix of
+//! the GNSS receiver against them, and only schedules the process's callback
+//! when the fix is on the other side of a fence than the one before.
+//!
+//! Circles use an equirectangular approximation of distances, and polygons
+//! treat latitude and longitude as plane coordinates, which is accurate for
+//! fences up to tens of kilometers across away from the poles. Polygons must
+//! not cross the 180th meridian. To keep GNSS noise from reporting a string
+//! of transitions at the edge of a circle, the board has to move
+//! `HYSTERESIS_M` beyond it to leave it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let geofence = static_init!(
+//!     capsules::geofence::Geofence,
+//!     capsules::geofence::Geofence::new(board_kernel.create_grant(&grant_cap))
+//! );
+//! kernel::hil::gnss::Gnss::set_fix_client(gnss, geofence);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: the fence to set, as little endian 32-bit words.
+//!   - Circle: `0`, then the latitude and longitude of its center in 10^-7
+//!     degrees, and its radius in meters.
+//!   - Polygon: `1`, then the number of vertices, then the latitude and
+//!     longitude of each vertex in 10^-7 degrees.
+//!
+//! ### Subscribe
+//!
+//! - `0`: fence crossed. The callback gets the index of the fence, `1` if the
+//!   board entered it or `0` if it left it, and the bitmask of the fences the
+//!   board is in.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns `MAX_FENCES`.
+//! - `1`: Set fence `arg1` from the allowed buffer. Whether the board is in
+//!   it is decided at the next fix, which reports entering it if it is.
+//! - `2`: Remove fence `arg1`.
+//! - `3`: Get the bitmask of the fences the board is in.
+
+use core::convert::TryInto;
+use core::mem;
+
+use kernel::hil::gnss::{Fix, FixClient};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Geofence as usize;
+
+/// Fences each process can set.
+pub const MAX_FENCES: usize = 8;
+
+/// Vertices of the largest polygon.
+pub const MAX_VERTICES: usize = 8;
+
+/// Distance beyond the edge of a circle the board has to be to leave it.
+pub const HYSTERESIS_M: u32 = 10;
+
+/// Meters per degree of latitude.
+const METERS_PER_DEGREE: f32 = 111_319.5;
+
+const KIND_CIRCLE: u32 = 0;
+const KIND_POLYGON: u32 = 1;
+
+#[derive(Clone, Copy)]
+enum Fence {
+    None,
+    Circle {
+        latitude: i32,
+        longitude: i32,
+        radius_m: u32,
+    },
+    Polygon {
+        vertices: [(i32, i32); MAX_VERTICES],
+        len: usize,
+    },
+}
+
+impl Default for Fence {
+    fn default() -> Fence {
+        Fence::None
+    }
+}
+
+impl Fence {
+    /// Parse a fence from its syscall layout.
+    fn from_words(data: &[u8]) -> Option<Fence> {
+        let word = |i: usize| -> Option<u32> {
+            data.get(4 * i..4 * i + 4)
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u32::from_le_bytes)
+        };
+        match word(0)? {
+            KIND_CIRCLE => Some(Fence::Circle {
+                latitude: word(1)? as i32,
+                longitude: word(2)? as i32,
+                radius_m: word(3)?,
+            }),
+            KIND_POLYGON => {
+                let len = word(1)? as usize;
+                if !(3..=MAX_VERTICES).contains(&len) {
+                    return None;
+                }
+                let mut vertices = [(0, 0); MAX_VERTICES];
+                for (i, vertex) in vertices[..len].iter_mut().enumerate() {
+                    *vertex = (word(2 + 2 * i)? as i32, word(3 + 2 * i)? as i32);
+                }
+                Some(Fence::Polygon { vertices, len })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `fix` is in the fence, given whether the previous one was.
+    fn contains(&self, fix: &Fix, was_inside: bool) -> bool {
+        match *self {
+            Fence::None => false,
+            Fence::Circle {
+                latitude,
+                longitude,
+                radius_m,
+            } => {
+                let radius = if was_inside {
+                    radius_m.saturating_add(HYSTERESIS_M)
+                } else {
+                    radius_m
+                } as f32;
+                let distance_sq = distance_sq_m(fix.latitude, fix.longitude, latitude, longitude);
+                distance_sq <= radius * radius
+            }
+            Fence::Polygon { vertices, len } => {
+                in_polygon(fix.latitude, fix.longitude, &vertices[..len])
+            }
+        }
+    }
+}
+
+/// Cosine of `x` radians, for `x` within ±π/2.
+fn cos(x: f32) -> f32 {
+    let x2 = x * x;
+    1.0 - x2 / 2.0 * (1.0 - x2 / 12.0 * (1.0 - x2 / 30.0 * (1.0 - x2 / 56.0)))
+}
+
+/// Square of the distance in meters between two points, in 10^-7 degrees.
+pub(crate) fn distance_sq_m(lat_a: i32, lon_a: i32, lat_b: i32, lon_b: i32) -> f32 {
+    let mut dlon = lon_a as i64 - lon_b as i64;
+    // Take the short way around the globe.
+    if dlon > 1_800_000_000 {
+        dlon -= 3_600_000_000;
+    } else if dlon < -1_800_000_000 {
+        dlon += 3_600_000_000;
+    }
+    let dlat = lat_a as i64 - lat_b as i64;
+    let mean_lat = ((lat_a as i64 + lat_b as i64) / 2) as f32 * 1e-7;
+    let x = dlon as f32 * 1e-7 * METERS_PER_DEGREE * cos(mean_lat.to_radians());
+    let y = dlat as f32 * 1e-7 * METERS_PER_DEGREE;
+    x * x + y * y
+}
+
+/// Whether a point is in a polygon, by counting the edges a ray from it
+/// crosses.
+fn in_polygon(lat: i32, lon: i32, vertices: &[(i32, i32)]) -> bool {
+    let (y, x) = (lat as i128, lon as i128);
+    let mut inside = false;
+    let mut j = vertices.len() - 1;
+    for i in 0..vertices.len() {
+        let (yi, xi) = (vertices[i].0 as i128, vertices[i].1 as i128);
+        let (yj, xj) = (vertices[j].0 as i128, vertices[j].1 as i128);
+        if (yi > y) != (yj > y) {
+            // Whether the point is west of where the edge crosses its
+            // latitude, without dividing.
+            let lhs = (x - xi) * (yj - yi);
+            let rhs = (xj - xi) * (y - yi);
+            if (yj > yi && lhs < rhs) || (yj < yi && lhs > rhs) {
+                inside = !inside;
+            }
+        }
+        j = i;
+    }
+    inside
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    fence_buffer: ReadOnlyAppSlice,
+    fences: [Fence; MAX_FENCES],
+    /// Fences the board was in at the last fix.
+    inside: u32,
+}
+
+pub struct Geofence {
+    apps: Grant<App>,
+}
+
+impl Geofence {
+    pub fn new(grant: Grant<App>) -> Geofence {
+        Geofence { apps: grant }
+    }
+
+    fn set_fence(&self, appid: ProcessId, index: usize) -> Result<(), ErrorCode> {
+        if index >= MAX_FENCES {
+            return Err(ErrorCode::INVAL);
+        }
+        self.apps
+            .enter(appid, |app| {
+                let fence = app
+                    .fence_buffer
+                    .map_or(None, |data| Fence::from_words(data))
+                    .ok_or(ErrorCode::INVAL)?;
+                app.fences[index] = fence;
+                app.inside &= !(1 << index);
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn remove_fence(&self, appid: ProcessId, index: usize) -> Result<(), ErrorCode> {
+        if index >= MAX_FENCES {
+            return Err(ErrorCode::INVAL);
+        }
+        self.apps
+            .enter(appid, |app| {
+                app.fences[index] = Fence::None;
+                app.inside &= !(1 << index);
+            })
+            .map_err(ErrorCode::from)
+    }
+}
+
+impl FixClient for Geofence {
+    fn fix(&self, fix: Fix) {
+        self.apps.each(|_, app| {
+            let was_inside = app.inside;
+            let mut inside = 0;
+            for (i, fence) in app.fences.iter().enumerate() {
+                if fence.contains(&fix, was_inside & (1 << i) != 0) {
+                    inside |= 1 << i;
+                }
+            }
+            app.inside = inside;
+
+            let mut changed = was_inside ^ inside;
+            while changed != 0 {
+                let i = changed.trailing_zeros();
+                changed &= !(1 << i);
+                let entered = inside & (1 << i) != 0;
+                app.callback
+                    .schedule(i as usize, entered as usize, inside as usize);
+            }
+        });
+    }
+}
+
+impl Driver for Geofence {
+    /// Share the fence to set.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Fence buffer
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.fence_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Fence crossed callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Manage fences.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the number of fences.
+    /// - `1`: Set a fence
+    /// - `2`: Remove a fence
+    /// - `3`: Get the fences the board is in
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        let result = match cmd_num {
+            0 => return CommandReturn::success_u32(MAX_FENCES as u32),
+            1 => self.set_fence(appid, arg1),
+            2 => self.remove_fence(appid, arg1),
+            3 => {
+                return self
+                    .apps
+                    .enter(appid, |app| CommandReturn::success_u32(app.inside))
+                    .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+            }
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match result {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
@@ -0,0 +1,722 @@
+//! Driver for GNSS receivers that speak NMEA and the Airoha PAIR protocol
+//! over a UART, such as the AG3335 in the Seeed T1000-E.
+//!
+//! Every valid NMEA sentence the receiver sends is copied to the processes
+//! that shared a buffer for it, without the trailing `\r\n`, so they can
+//! parse the fields they need. The driver also parses RMC sentences into
+//! position fixes for kernel clients, through `hil::gnss`.
+//!
+//! Processes can also shorten the time to first fix by giving the receiver
+//! assistance data: the current time, an approximate position, or an
+//! ephemeris blob, such as Airoha EPO data downloaded over LoRaWAN or by a
+//! companion phone. Time and position are turned into PAIR commands by the
+//! driver; ephemeris blobs are sent to the receiver as they are.
+//!
+//! Between fixes, the receiver can be put in standby, where it stops
+//! tracking but keeps its ephemeris and can hot start, or in backup, where
+//! only its RTC domain is powered. The receiver can only leave backup through
+//! a reset, so the board must give the driver the reset pin to use it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let gnss_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let gnss = static_init!(
+//!     capsules::gnss::Gnss<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     capsules::gnss::Gnss::new(
+//!         gnss_uart,
+//!         Some(&gpio_port[GNSS_RESET_PIN]),
+//!         gnss_alarm,
+//!         &mut capsules::gnss::TX_BUF,
+//!         &mut capsules::gnss::RX_BUF,
+//!         &mut capsules::gnss::LINE_BUF,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hil::uart::Transmit::set_transmit_client(gnss_uart, gnss);
+//! hil::uart::Receive::set_receive_client(gnss_uart, gnss);
+//! gnss_alarm.set_alarm_client(gnss);
+//! gnss.start();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-write `0`: buffer NMEA sentences are copied to.
+//! - Read-only `0`: assistance data, laid out as below. All fields are
+//!   little endian.
+//!   - Time: the UTC time in seconds since 1970, as a `u64`.
+//!   - Position: the latitude and longitude in 10^-7 degrees, as `i32`s, the
+//!     height above the ellipsoid in centimeters, as an `i32`, and the
+//!     accuracy of the position in centimeters, as a `u32`.
+//!   - Ephemeris: the blob, in the receiver's own format.
+//!
+//! ### Subscribe
+//!
+//! - `0`: sentence received. The callback gets the length of the sentence,
+//!   which is cut short if it does not fit in the buffer, and, if the board
+//!   has set an event clock, the tick count when the end of the sentence was
+//!   received (otherwise 0).
+//! - `1`: assistance data sent or power mode changed. The callback gets the
+//!   result.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Send assistance data. The first argument is its kind, `0` for
+//!   time, `1` for position or `2` for ephemeris, and the second the length
+//!   of an ephemeris blob. Returns `OFF` in backup and `BUSY` while another
+//!   command is being sent.
+//! - `2`: Set the power mode: `0` for full power, `1` for standby or `2` for
+//!   backup. From backup, the receiver can only go to full power, and only
+//!   if the driver has its reset pin.
+//! - `3`: Get the power mode.
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::fmt::{self, Write};
+use core::mem;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gnss::{self, Fix, FixClient};
+use kernel::hil::gpio;
+use kernel::hil::time::{self, Alarm};
+use kernel::hil::uart;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Gnss as usize;
+
+pub static mut TX_BUF: [u8; 256] = [0; 256];
+pub static mut RX_BUF: [u8; 1] = [0; 1];
+/// NMEA sentences are at most 82 characters long, but PAIR responses can be
+/// longer.
+pub static mut LINE_BUF: [u8; 128] = [0; 128];
+
+/// Time the reset pin is held low to wake the receiver from backup.
+const RESET_MS: u32 = 10;
+
+const ASSIST_TIME: usize = 0;
+const ASSIST_POSITION: usize = 1;
+const ASSIST_EPHEMERIS: usize = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum PowerMode {
+    Full = 0,
+    Standby = 1,
+    Backup = 2,
+}
+
+#[derive(Default)]
+pub struct App {
+    sentence_callback: Upcall,
+    done_callback: Upcall,
+    sentence_buffer: ReadWriteAppSlice,
+    assist_buffer: ReadOnlyAppSlice,
+}
+
+/// Writes formatted text into a buffer, dropping what does not fit.
+struct WriteAdapter<'b> {
+    buffer: &'b mut [u8],
+    used: usize,
+}
+
+impl Write for WriteAdapter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(slice) = self.buffer.get_mut(self.used..self.used + s.len()) {
+            slice.copy_from_slice(s.as_bytes());
+            self.used += s.len();
+        }
+        Ok(())
+    }
+}
+
+/// A fixed point number and its number of decimal digits.
+struct Fixed(i64, u32);
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scale = 10i64.pow(self.1);
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let value = self.0.abs();
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            value / scale,
+            value % scale,
+            width = self.1 as usize
+        )
+    }
+}
+
+/// XOR of the characters between `$` and `*`.
+fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |sum, byte| sum ^ byte)
+}
+
+/// Write `$body*checksum\r\n` into `buffer`, returning its length.
+fn write_sentence(buffer: &mut [u8], body: fmt::Arguments) -> usize {
+    let mut writer = WriteAdapter {
+        buffer: buffer,
+        used: 0,
+    };
+    let _ = writer.write_str("$");
+    let _ = writer.write_fmt(body);
+    let sum = checksum(&writer.buffer[1..writer.used]);
+    let _ = write!(writer, "*{:02X}\r\n", sum);
+    writer.used
+}
+
+/// Whether `line`, without `\r\n`, is a sentence with a valid checksum.
+fn is_valid(line: &[u8]) -> bool {
+    let len = line.len();
+    if len < 4 || line[0] != b'$' || line[len - 3] != b'*' {
+        return false;
+    }
+    core::str::from_utf8(&line[len - 2..])
+        .ok()
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        .map_or(false, |sum| sum == checksum(&line[1..len - 3]))
+}
+
+/// The year, month and day of a number of days since 1970.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March.
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Parse a decimal number, such as `12.345`, as an integer scaled by
+/// 10^`decimals`. Further digits are ignored.
+fn parse_fixed(text: &[u8], decimals: u32) -> Option<i64> {
+    let mut value: i64 = 0;
+    let mut fraction = None;
+    for &c in text {
+        match c {
+            b'.' if fraction.is_none() => fraction = Some(0),
+            b'0'..=b'9' => {
+                if fraction == Some(decimals) {
+                    continue;
+                }
+                value = value.checked_mul(10)?.checked_add((c - b'0') as i64)?;
+                fraction = fraction.map(|digits| digits + 1);
+            }
+            _ => return None,
+        }
+    }
+    let digits = fraction.unwrap_or(0);
+    value.checked_mul(10i64.pow(decimals - digits))
+}
+
+/// Parse a `(d)ddmm.mmmm` coordinate into 10^-7 degrees.
+fn parse_coordinate(text: &[u8], degree_digits: usize, hemisphere: &[u8]) -> Option<i32> {
+    let degrees = parse_fixed(text.get(..degree_digits)?, 0)?;
+    let minutes = parse_fixed(text.get(degree_digits..)?, 5)?;
+    let value = degrees * 10_000_000 + minutes * 100 / 60;
+    match hemisphere {
+        b"N" | b"E" => Some(value as i32),
+        b"S" | b"W" => Some(-value as i32),
+        _ => None,
+    }
+}
+
+/// The fix in a valid `RMC` sentence, if the receiver has one.
+fn parse_rmc(sentence: &[u8]) -> Option<Fix> {
+    // Drop the checksum.
+    let body = &sentence[..sentence.len() - 3];
+    if body.get(3..6) != Some(b"RMC") {
+        return None;
+    }
+    let mut fields: [&[u8]; 8] = [&[]; 8];
+    for (i, field) in body.split(|&c| c == b',').take(8).enumerate() {
+        fields[i] = field;
+    }
+    if fields[2] != b"A" {
+        return None;
+    }
+    let knots = parse_fixed(fields[7], 3).unwrap_or(0);
+    Some(Fix {
+        latitude: parse_coordinate(fields[3], 2, fields[4])?,
+        longitude: parse_coordinate(fields[5], 3, fields[6])?,
+        speed_mm_s: (knots * 514_444 / 1_000_000) as u32,
+    })
+}
+
+fn field<const N: usize>(data: &[u8], offset: usize) -> [u8; N] {
+    data[offset..offset + N].try_into().unwrap_or([0; N])
+}
+
+pub struct Gnss<'a, A: Alarm<'a>> {
+    uart: &'a dyn uart::UartData<'a>,
+    reset: Option<&'a dyn gpio::Pin>,
+    alarm: &'a A,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    line: TakeCell<'static, [u8]>,
+    /// Length of the sentence being received, or `usize::MAX` while waiting
+    /// for the start of one.
+    line_len: Cell<usize>,
+    mode: Cell<PowerMode>,
+    /// Mode the receiver enters once its command is sent.
+    next_mode: Cell<Option<PowerMode>>,
+    /// Process whose command is being sent.
+    current: OptionalCell<ProcessId>,
+    /// Bytes of an ephemeris blob sent so far, and its length.
+    blob_sent: Cell<usize>,
+    blob_len: Cell<usize>,
+    fix_client: OptionalCell<&'a dyn FixClient>,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> Gnss<'a, A> {
+    pub fn new(
+        uart: &'a dyn uart::UartData<'a>,
+        reset: Option<&'a dyn gpio::Pin>,
+        alarm: &'a A,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        line: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> Gnss<'a, A> {
+        Gnss {
+            uart: uart,
+            reset: reset,
+            alarm: alarm,
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            line: TakeCell::new(line),
+            line_len: Cell::new(usize::MAX),
+            mode: Cell::new(PowerMode::Full),
+            next_mode: Cell::new(None),
+            current: OptionalCell::empty(),
+            blob_sent: Cell::new(0),
+            blob_len: Cell::new(0),
+            fix_client: OptionalCell::empty(),
+            apps: grant,
+        }
+    }
+
+    /// Start receiving sentences.
+    pub fn start(&self) {
+        self.reset.map(|pin| {
+            pin.make_output();
+            pin.set();
+        });
+        self.rx_buffer.take().map(|buffer| {
+            let _ = self.uart.receive_buffer(buffer, 1);
+        });
+    }
+
+    fn receive_byte(&self, byte: u8) {
+        self.line.map(|line| {
+            let len = self.line_len.get();
+            match byte {
+                b'$' => {
+                    line[0] = byte;
+                    self.line_len.set(1);
+                }
+                b'\r' => {}
+                b'\n' => {
+                    if len <= line.len() && is_valid(&line[..len]) {
+                        self.deliver(&line[..len]);
+                        if let Some(fix) = parse_rmc(&line[..len]) {
+                            self.fix_client.map(|client| client.fix(fix));
+                        }
+                    }
+                    self.line_len.set(usize::MAX);
+                }
+                _ if len < line.len() => {
+                    line[len] = byte;
+                    self.line_len.set(len + 1);
+                }
+                _ => self.line_len.set(usize::MAX),
+            }
+        });
+    }
+
+    fn deliver(&self, sentence: &[u8]) {
+        self.apps.each(|_, app| {
+            let len = app.sentence_buffer.mut_map_or(0, |buffer| {
+                let len = cmp::min(buffer.len(), sentence.len());
+                buffer[..len].copy_from_slice(&sentence[..len]);
+                len
+            });
+            if len > 0 {
+                let timestamp = app.sentence_callback.event_timestamp().unwrap_or(0);
+                app.sentence_callback.schedule(len, timestamp as usize, 0);
+            }
+        });
+    }
+
+    fn inject(&self, appid: ProcessId, kind: usize, len: usize) -> Result<(), ErrorCode> {
+        if self.mode.get() == PowerMode::Backup {
+            return Err(ErrorCode::OFF);
+        }
+        if self.current.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let buffer = self.tx_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let result = self
+            .apps
+            .enter(appid, |app| {
+                app.assist_buffer
+                    .map_or(Err(ErrorCode::INVAL), |data| match kind {
+                        ASSIST_TIME if data.len() >= 8 => {
+                            let seconds = u64::from_le_bytes(field(data, 0));
+                            let (year, month, day) = civil_from_days(seconds / 86400);
+                            let time = seconds % 86400;
+                            Ok(write_sentence(
+                                buffer,
+                                format_args!(
+                                    "PAIR590,{},{:02},{:02},{:02},{:02},{:02}",
+                                    year,
+                                    month,
+                                    day,
+                                    time / 3600,
+                                    time / 60 % 60,
+                                    time % 60
+                                ),
+                            ))
+                        }
+                        ASSIST_POSITION if data.len() >= 16 => {
+                            let latitude = i32::from_le_bytes(field(data, 0));
+                            let longitude = i32::from_le_bytes(field(data, 4));
+                            let height_cm = i32::from_le_bytes(field(data, 8));
+                            let accuracy_cm = u32::from_le_bytes(field(data, 12));
+                            let accuracy = Fixed(accuracy_cm as i64, 2);
+                            Ok(write_sentence(
+                                buffer,
+                                format_args!(
+                                    "PAIR600,{},{},{},{},{},0.0,{}",
+                                    Fixed(latitude as i64, 7),
+                                    Fixed(longitude as i64, 7),
+                                    Fixed(height_cm as i64, 2),
+                                    accuracy,
+                                    accuracy,
+                                    accuracy
+                                ),
+                            ))
+                        }
+                        ASSIST_EPHEMERIS if len > 0 => {
+                            let len = cmp::min(len, data.len());
+                            let chunk = cmp::min(len, buffer.len());
+                            buffer[..chunk].copy_from_slice(&data[..chunk]);
+                            self.blob_len.set(len);
+                            self.blob_sent.set(chunk);
+                            Ok(chunk)
+                        }
+                        _ => Err(ErrorCode::INVAL),
+                    })
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match result {
+            Ok(tx_len) => self.transmit(appid, buffer, tx_len),
+            Err(e) => {
+                self.tx_buffer.replace(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    fn set_power_mode(&self, appid: ProcessId, mode: usize) -> Result<(), ErrorCode> {
+        let mode = match mode {
+            0 => PowerMode::Full,
+            1 => PowerMode::Standby,
+            2 => PowerMode::Backup,
+            _ => return Err(ErrorCode::INVAL),
+        };
+        if self.current.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if mode == self.mode.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+
+        if self.mode.get() == PowerMode::Backup {
+            // Only a reset wakes the receiver from backup.
+            let reset = match (mode, self.reset) {
+                (PowerMode::Full, Some(reset)) => reset,
+                _ => return Err(ErrorCode::NOSUPPORT),
+            };
+            reset.clear();
+            self.current.set(appid);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(RESET_MS));
+            return Ok(());
+        }
+
+        let buffer = self.tx_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let len = match mode {
+            PowerMode::Full => write_sentence(buffer, format_args!("PAIR002")),
+            PowerMode::Standby => write_sentence(buffer, format_args!("PAIR003")),
+            // Stay in backup until reset.
+            PowerMode::Backup => write_sentence(buffer, format_args!("PAIR650,0")),
+        };
+        self.next_mode.set(Some(mode));
+        self.blob_len.set(0);
+        self.blob_sent.set(0);
+        self.transmit(appid, buffer, len)
+    }
+
+    fn transmit(
+        &self,
+        appid: ProcessId,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), ErrorCode> {
+        match self.uart.transmit_buffer(buffer, len) {
+            Ok(()) => {
+                self.current.set(appid);
+                Ok(())
+            }
+            Err((e, buffer)) => {
+                self.tx_buffer.replace(buffer);
+                self.next_mode.set(None);
+                Err(e)
+            }
+        }
+    }
+
+    /// Send the next part of an ephemeris blob, returning whether there was
+    /// one.
+    fn send_more(&self, appid: ProcessId, buffer: &'static mut [u8]) -> bool {
+        let sent = self.blob_sent.get();
+        let blob_len = self.blob_len.get();
+        if sent >= blob_len {
+            self.tx_buffer.replace(buffer);
+            return false;
+        }
+        let chunk = self
+            .apps
+            .enter(appid, |app| {
+                app.assist_buffer.map_or(0, |data| {
+                    let end = cmp::min(cmp::min(blob_len, sent + buffer.len()), data.len());
+                    let chunk = end.saturating_sub(sent);
+                    buffer[..chunk].copy_from_slice(&data[sent..end]);
+                    chunk
+                })
+            })
+            .unwrap_or(0);
+        if chunk == 0 {
+            self.tx_buffer.replace(buffer);
+            return false;
+        }
+        self.blob_sent.set(sent + chunk);
+        match self.uart.transmit_buffer(buffer, chunk) {
+            Ok(()) => true,
+            Err((_, buffer)) => {
+                self.tx_buffer.replace(buffer);
+                false
+            }
+        }
+    }
+
+    fn done(&self, appid: ProcessId, result: Result<(), ErrorCode>) {
+        let _ = self.apps.enter(appid, |app| {
+            app.done_callback
+                .schedule(kernel::into_statuscode(result), 0, 0);
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> uart::TransmitClient for Gnss<'a, A> {
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        let appid = match self.current.extract() {
+            Some(appid) => appid,
+            None => {
+                self.tx_buffer.replace(buffer);
+                return;
+            }
+        };
+        if rval.is_ok() && self.send_more(appid, buffer) {
+            return;
+        }
+        // The blob is incomplete if the process went away.
+        let result = if self.blob_sent.get() < self.blob_len.get() {
+            Err(ErrorCode::FAIL)
+        } else {
+            rval
+        };
+        if let Some(mode) = self.next_mode.take() {
+            if result.is_ok() {
+                self.mode.set(mode);
+            }
+        }
+        self.current.clear();
+        self.done(appid, result);
+    }
+}
+
+impl<'a, A: Alarm<'a>> uart::ReceiveClient for Gnss<'a, A> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        if rval.is_ok() && rx_len > 0 {
+            self.receive_byte(buffer[0]);
+        }
+        if let Err((_, buffer)) = self.uart.receive_buffer(buffer, 1) {
+            self.rx_buffer.replace(buffer);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> gnss::Gnss<'a> for Gnss<'a, A> {
+    fn set_fix_client(&self, client: &'a dyn FixClient) {
+        self.fix_client.set(client);
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Gnss<'a, A> {
+    fn alarm(&self) {
+        // The reset pulse that wakes the receiver from backup is over.
+        self.reset.map(|pin| pin.set());
+        self.mode.set(PowerMode::Full);
+        self.current.take().map(|appid| self.done(appid, Ok(())));
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for Gnss<'a, A> {
+    /// Share the buffer sentences are copied to.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Sentence buffer
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.sentence_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Share assistance data.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Assistance data
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.assist_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Sentence received callback
+    /// - `1`: Command done callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.sentence_callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            1 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.done_callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Assist and control the receiver.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Send assistance data
+    /// - `2`: Set the power mode
+    /// - `3`: Get the power mode
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
+        let result = match cmd_num {
+            0 => Ok(()),
+            1 => self.inject(appid, arg1, arg2),
+            2 => self.set_power_mode(appid, arg1),
+            3 => return CommandReturn::success_u32(self.mode.get() as u32),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match result {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
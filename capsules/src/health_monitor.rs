@@ -0,0 +1,317 @@
+//! Periodically runs driver self-tests, for boards deployed without anyone
+//! watching them.
+//!
+//! Every period, the monitor runs each of its self-tests in order, such as
+//! the ones in `capsules::self_test`, and records which of them failed. Failed
+//! tests are printed with `debug!`. Processes can read the results, run the
+//! tests early, and be told when a round of tests finishes, for example to
+//! report the board's health to a server.
+//!
+//! If the board gives the monitor an LED, it blinks it once after a round in
+//! which every test passed, and once for every failed test otherwise.
+//!
+//! The period can be longer than the longest delay the alarm can be set for,
+//! which is only a few minutes for a 24-bit counter at 32 kHz, so the monitor
+//! waits for it in steps of at most `WAIT_STEP_S` seconds.
+//!
+//! A test that has not finished `TEST_TIMEOUT_MS` after it started counts as
+//! failed, and ends the round: the tests after it are not run, since a late
+//! result of the hung test could not be told apart from theirs.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let tests = static_init!(
+//!     [&'static dyn kernel::hil::self_test::SelfTest<'static>; 2],
+//!     [flash_crc_test, ram_pattern_test]
+//! );
+//! let health_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let health = static_init!(
+//!     capsules::health_monitor::HealthMonitor<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     capsules::health_monitor::HealthMonitor::new(
+//!         board_kernel,
+//!         tests,
+//!         health_alarm,
+//!         3600,
+//!         Some(&led[0]),
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! health_alarm.set_alarm_client(health);
+//! health.start();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: round done. The callback gets the number of rounds run and a
+//!   bitmask of the tests that failed in this one, bit `n` for test `n`.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the number of tests.
+//! - `1`: Get the number of rounds run and the bitmask of the tests that
+//!   failed in the last one.
+//! - `2`: Get the number of rounds in which any test failed.
+//! - `3`: Run the tests now, rather than at the end of the period. Returns
+//!   `BUSY` if they are running, and `NOSUPPORT` if the caller does not have
+//!   permission (see the `Permissions` TLV in `doc/TockBinaryFormat.md`), as
+//!   the tests take the devices they check away from other users.
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+
+use kernel::debug;
+use kernel::hil::led::Led;
+use kernel::hil::self_test::{SelfTest, SelfTestClient};
+use kernel::hil::time::{self, Alarm};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::HealthMonitor as usize;
+
+/// Most tests the monitor runs, one for every bit of the result bitmask.
+pub const MAX_TESTS: usize = 32;
+
+/// Time the LED is on or off while blinking.
+const BLINK_MS: u32 = 200;
+
+/// Longest single wait for the end of the period.
+pub const WAIT_STEP_S: u32 = 60;
+
+/// Time a test has to finish before it counts as failed.
+pub const TEST_TIMEOUT_MS: u32 = 10_000;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// Waiting for the end of the period, this many seconds after the wait
+    /// that is running.
+    Waiting(u32),
+    /// Running the test with this index.
+    Testing(usize),
+    /// Blinking the result, with this many LED toggles left.
+    Blinking(u32),
+}
+
+pub struct HealthMonitor<'a, A: Alarm<'a>> {
+    kernel: &'static Kernel,
+    tests: &'a [&'a dyn SelfTest<'a>],
+    alarm: &'a A,
+    period_s: u32,
+    led: Option<&'a dyn Led>,
+    state: Cell<State>,
+    /// Tests that failed in the round that is running.
+    failed: Cell<u32>,
+    /// Tests that failed in the last round.
+    last_failed: Cell<u32>,
+    rounds: Cell<u32>,
+    failed_rounds: Cell<u32>,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> HealthMonitor<'a, A> {
+    /// Run `tests` every `period_s` seconds, and show the results on `led`.
+    pub fn new(
+        kernel: &'static Kernel,
+        tests: &'a [&'a dyn SelfTest<'a>],
+        alarm: &'a A,
+        period_s: u32,
+        led: Option<&'a dyn Led>,
+        grant: Grant<App>,
+    ) -> HealthMonitor<'a, A> {
+        HealthMonitor {
+            kernel: kernel,
+            tests: tests,
+            alarm: alarm,
+            period_s: period_s,
+            led: led,
+            state: Cell::new(State::Waiting(0)),
+            failed: Cell::new(0),
+            last_failed: Cell::new(0),
+            rounds: Cell::new(0),
+            failed_rounds: Cell::new(0),
+            apps: grant,
+        }
+    }
+
+    /// Run the first round of tests. Must be called once.
+    pub fn start(&'a self) {
+        for test in self.tests() {
+            test.set_client(self);
+        }
+        self.led.map(|led| led.init());
+        self.start_round();
+    }
+
+    fn tests(&self) -> &'a [&'a dyn SelfTest<'a>] {
+        &self.tests[..cmp::min(self.tests.len(), MAX_TESTS)]
+    }
+
+    fn start_round(&self) {
+        self.failed.set(0);
+        self.run_from(0);
+    }
+
+    /// Run the tests from `index` on, until one is in progress or the round
+    /// is done.
+    fn run_from(&self, mut index: usize) {
+        while let Some(test) = self.tests().get(index) {
+            match test.run() {
+                Ok(()) => {
+                    self.state.set(State::Testing(index));
+                    self.alarm
+                        .set_alarm(self.alarm.now(), A::ticks_from_ms(TEST_TIMEOUT_MS));
+                    return;
+                }
+                Err(e) => self.record(index, Err(e)),
+            }
+            index += 1;
+        }
+        self.round_done();
+    }
+
+    fn record(&self, index: usize, result: Result<(), ErrorCode>) {
+        if let Err(e) = result {
+            debug!("health: {} failed ({:?})", self.tests[index].name(), e);
+            self.failed.set(self.failed.get() | 1 << index);
+        }
+    }
+
+    fn round_done(&self) {
+        let failed = self.failed.get();
+        self.last_failed.set(failed);
+        self.rounds.set(self.rounds.get().wrapping_add(1));
+        if failed != 0 {
+            self.failed_rounds
+                .set(self.failed_rounds.get().wrapping_add(1));
+        }
+
+        let rounds = self.rounds.get();
+        self.apps.each(|_, app| {
+            app.callback.schedule(rounds as usize, failed as usize, 0);
+        });
+
+        match self.led {
+            Some(led) => {
+                let blinks = cmp::max(failed.count_ones(), 1);
+                led.on();
+                self.state.set(State::Blinking(2 * blinks - 1));
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(BLINK_MS));
+            }
+            None => self.wait(self.period_s),
+        }
+    }
+
+    /// Wait `seconds` more until the next round.
+    fn wait(&self, seconds: u32) {
+        let step = cmp::min(seconds, WAIT_STEP_S);
+        self.state.set(State::Waiting(seconds - step));
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_seconds(step));
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for HealthMonitor<'a, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Waiting(0) => self.start_round(),
+            State::Waiting(seconds) => self.wait(seconds),
+            State::Blinking(0) => self.wait(self.period_s),
+            State::Blinking(toggles) => {
+                self.led.map(|led| led.toggle());
+                self.state.set(State::Blinking(toggles - 1));
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(BLINK_MS));
+            }
+            State::Testing(index) => {
+                debug!("health: {} timed out", self.tests[index].name());
+                self.failed.set(self.failed.get() | 1 << index);
+                self.round_done();
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> SelfTestClient for HealthMonitor<'a, A> {
+    fn self_test_done(&self, result: Result<(), ErrorCode>) {
+        if let State::Testing(index) = self.state.get() {
+            let _ = self.alarm.disarm();
+            self.record(index, result);
+            self.run_from(index + 1);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for HealthMonitor<'a, A> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Round done callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Read the health of the board.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the number of tests.
+    /// - `1`: Get the number of rounds and the tests that failed in the last
+    /// - `2`: Get the number of failed rounds
+    /// - `3`: Run the tests now
+    fn command(&self, cmd_num: usize, _: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success_u32(self.tests().len() as u32),
+            1 => CommandReturn::success_u32_u32(self.rounds.get(), self.last_failed.get()),
+            2 => CommandReturn::success_u32(self.failed_rounds.get()),
+            3 if !self.kernel.command_permitted(appid, DRIVER_NUM, cmd_num) => {
+                CommandReturn::failure(ErrorCode::NOSUPPORT)
+            }
+            3 => match self.state.get() {
+                State::Waiting(_) => {
+                    let _ = self.alarm.disarm();
+                    self.start_round();
+                    CommandReturn::success()
+                }
+                _ => CommandReturn::failure(ErrorCode::BUSY),
+            },
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Self-tests that check a HIL implementation keeps the contract its trait
+//! documents.
+//!
+//! Capsules rely on details of the HIL traits beyond their signatures: that an
+//! alarm set in the past fires right away, that an aborted UART receive
+//! returns its buffer with `CANCEL`, and so on. A chip driver that gets one of
+//! these wrong usually only shows up as a capsule misbehaving much later. The
+//! tests here are generic over the HIL trait, so any chip can instantiate them
+//! in its board's test harness, and they are `SelfTest`s, so they run under
+//! `capsules::self_test::SelfTestRunner` like the other self-tests.
+//!
+//! Each test exercises the peripheral it is given, so it needs exclusive use
+//! of it while it runs. The tests are:
+//!
+//! - `AlarmContractTest`: `disarm()` of an unarmed alarm succeeds, an alarm
+//!   set in the past fires promptly, and an alarm is not armed once it fired.
+//! - `UartAbortContractTest`: a second receive while one is outstanding gets
+//!   `BUSY` and its buffer back, and aborting a receive returns the buffer
+//!   with `CANCEL` if the abort reported `BUSY`.
+//! - `SpiLengthContractTest`: a transfer with a read buffer shorter than the
+//!   write buffer is limited to the read buffer, and both buffers come back.
+//! - `I2cNakContractTest`: a read from an address no device answers completes
+//!   with `AddressNak` and returns the buffer.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let alarm_contract = static_init!(
+//!     capsules::hil_contract::AlarmContractTest<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::hil_contract::AlarmContractTest::new(virtual_alarm_contract)
+//! );
+//! virtual_alarm_contract.set_alarm_client(alarm_contract);
+//! let tests = static_init!([&'static dyn SelfTest<'static>; 1], [alarm_contract]);
+//! let runner = static_init!(
+//!     capsules::self_test::SelfTestRunner<'static>,
+//!     capsules::self_test::SelfTestRunner::new(tests, &io::SIM_EXIT)
+//! );
+//! runner.start();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::i2c;
+use kernel::hil::self_test::{SelfTest, SelfTestClient};
+use kernel::hil::spi;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::hil::uart;
+use kernel::ErrorCode;
+
+/// Checks the `Alarm` contract.
+pub struct AlarmContractTest<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    set_at: Cell<A::Ticks>,
+    client: OptionalCell<&'a dyn SelfTestClient>,
+}
+
+impl<'a, A: Alarm<'a>> AlarmContractTest<'a, A> {
+    /// How late an alarm set in the past may fire.
+    const LATENESS_MS: u32 = 5;
+
+    pub fn new(alarm: &'a A) -> Self {
+        AlarmContractTest {
+            alarm: alarm,
+            set_at: Cell::new(A::Ticks::from(0)),
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> SelfTest<'a> for AlarmContractTest<'a, A> {
+    fn name(&self) -> &'static str {
+        "alarm contract"
+    }
+
+    fn set_client(&self, client: &'a dyn SelfTestClient) {
+        self.client.set(client);
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        if self.alarm.is_armed() {
+            return Err(ErrorCode::BUSY);
+        }
+        // Disarming an alarm that is not armed is not an error.
+        if self.alarm.disarm().is_err() || self.alarm.is_armed() {
+            return Err(ErrorCode::FAIL);
+        }
+
+        // An alarm whose reference and interval are both in the past must
+        // fire as soon as possible.
+        let dt = self.alarm.minimum_dt();
+        let now = self.alarm.now();
+        let reference = now.wrapping_sub(dt).wrapping_sub(dt);
+        self.set_at.set(now);
+        self.alarm.set_alarm(reference, dt);
+        if !self.alarm.is_armed() {
+            let _ = self.alarm.disarm();
+            return Err(ErrorCode::FAIL);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmContractTest<'a, A> {
+    fn alarm(&self) {
+        let late = self.alarm.now().wrapping_sub(self.set_at.get());
+        let result = if late > A::ticks_from_ms(Self::LATENESS_MS) || self.alarm.is_armed() {
+            Err(ErrorCode::FAIL)
+        } else {
+            Ok(())
+        };
+        self.client.map(|client| client.self_test_done(result));
+    }
+}
+
+/// Checks that a UART receive can be refused while busy and aborted.
+pub struct UartAbortContractTest<'a, U: uart::Receive<'a>> {
+    uart: &'a U,
+    buffer: TakeCell<'static, [u8]>,
+    second_buffer: TakeCell<'static, [u8]>,
+    refused: Cell<bool>,
+    abort_result: Cell<Result<(), ErrorCode>>,
+    client: OptionalCell<&'a dyn SelfTestClient>,
+}
+
+impl<'a, U: uart::Receive<'a>> UartAbortContractTest<'a, U> {
+    /// Both buffers must hold at least one byte. Nothing may be received on
+    /// the UART while the test runs.
+    pub fn new(uart: &'a U, buffer: &'static mut [u8], second_buffer: &'static mut [u8]) -> Self {
+        UartAbortContractTest {
+            uart: uart,
+            buffer: TakeCell::new(buffer),
+            second_buffer: TakeCell::new(second_buffer),
+            refused: Cell::new(false),
+            abort_result: Cell::new(Ok(())),
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a, U: uart::Receive<'a>> SelfTest<'a> for UartAbortContractTest<'a, U> {
+    fn name(&self) -> &'static str {
+        "uart abort contract"
+    }
+
+    fn set_client(&self, client: &'a dyn SelfTestClient) {
+        self.client.set(client);
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        let len = buffer.len();
+        if let Err((e, buffer)) = self.uart.receive_buffer(buffer, len) {
+            self.buffer.replace(buffer);
+            return Err(e);
+        }
+
+        // A second receive must be refused with its buffer returned.
+        let refused = self.second_buffer.take().map_or(false, |second| {
+            let len = second.len();
+            match self.uart.receive_buffer(second, len) {
+                Err((ErrorCode::BUSY, second)) => {
+                    self.second_buffer.replace(second);
+                    true
+                }
+                Err((_, second)) => {
+                    self.second_buffer.replace(second);
+                    false
+                }
+                // The second buffer is now owned by the UART, and is lost to
+                // the test. The abort below returns the first one.
+                Ok(()) => false,
+            }
+        });
+
+        // With a receive outstanding, the abort must either cancel it now
+        // (BUSY) or later (FAIL). Either way there is a callback.
+        let abort = self.uart.receive_abort();
+        self.refused.set(refused);
+        self.abort_result.set(abort);
+        if abort == Ok(()) {
+            // There is no callback to report through, so fail now.
+            return Err(ErrorCode::FAIL);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, U: uart::Receive<'a>> uart::ReceiveClient for UartAbortContractTest<'a, U> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        let len = buffer.len();
+        self.buffer.replace(buffer);
+        let cancelled = rval == Err(ErrorCode::CANCEL);
+        let result = match self.abort_result.get() {
+            _ if !self.refused.get() => Err(ErrorCode::BUSY),
+            Err(ErrorCode::BUSY) if cancelled && rx_len < len => Ok(()),
+            // A late cancel may also have completed the receive.
+            Err(ErrorCode::FAIL) if rx_len <= len => Ok(()),
+            _ => Err(ErrorCode::FAIL),
+        };
+        self.client.map(|client| client.self_test_done(result));
+    }
+}
+
+/// Checks that a SPI transfer is limited to the shorter of its buffers.
+pub struct SpiLengthContractTest<'a, S: spi::SpiMasterDevice> {
+    spi: &'a S,
+    write_buffer: TakeCell<'static, [u8]>,
+    read_buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn SelfTestClient>,
+}
+
+impl<'a, S: spi::SpiMasterDevice> SpiLengthContractTest<'a, S> {
+    /// `read_buffer` must be shorter than `write_buffer`. The transfer is
+    /// sent to the device `spi` selects, so it must be one that ignores it.
+    pub fn new(
+        spi: &'a S,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+    ) -> Self {
+        SpiLengthContractTest {
+            spi: spi,
+            write_buffer: TakeCell::new(write_buffer),
+            read_buffer: TakeCell::new(read_buffer),
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> SelfTest<'a> for SpiLengthContractTest<'a, S> {
+    fn name(&self) -> &'static str {
+        "spi length contract"
+    }
+
+    fn set_client(&self, client: &'a dyn SelfTestClient) {
+        self.client.set(client);
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        let write = self.write_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let read = match self.read_buffer.take() {
+            Some(read) if read.len() < write.len() => read,
+            read => {
+                self.write_buffer.replace(write);
+                read.map(|read| self.read_buffer.replace(read));
+                return Err(ErrorCode::FAIL);
+            }
+        };
+        let len = write.len();
+        self.spi.read_write_bytes(write, Some(read), len)
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for SpiLengthContractTest<'a, S> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        len: usize,
+    ) {
+        let result = match read_buffer {
+            Some(read) => {
+                let expected = read.len();
+                self.read_buffer.replace(read);
+                if len == expected {
+                    Ok(())
+                } else {
+                    Err(ErrorCode::SIZE)
+                }
+            }
+            None => Err(ErrorCode::FAIL),
+        };
+        self.write_buffer.replace(write_buffer);
+        self.client.map(|client| client.self_test_done(result));
+    }
+}
+
+/// Checks that an I2C read from an address no device answers fails with
+/// `AddressNak` and returns its buffer.
+pub struct I2cNakContractTest<'a> {
+    i2c: &'a dyn i2c::I2CDevice,
+    buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn SelfTestClient>,
+}
+
+impl<'a> I2cNakContractTest<'a> {
+    /// `i2c` must be a device at an address nothing on the bus answers, and
+    /// `buffer` must hold at least one byte.
+    pub fn new(i2c: &'a dyn i2c::I2CDevice, buffer: &'static mut [u8]) -> Self {
+        I2cNakContractTest {
+            i2c: i2c,
+            buffer: TakeCell::new(buffer),
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a> SelfTest<'a> for I2cNakContractTest<'a> {
+    fn name(&self) -> &'static str {
+        "i2c nak contract"
+    }
+
+    fn set_client(&self, client: &'a dyn SelfTestClient) {
+        self.client.set(client);
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        if buffer.is_empty() {
+            self.buffer.replace(buffer);
+            return Err(ErrorCode::FAIL);
+        }
+        self.i2c.enable();
+        self.i2c.read(buffer, 1);
+        Ok(())
+    }
+}
+
+impl i2c::I2CClient for I2cNakContractTest<'_> {
+    fn command_complete(&self, buffer: &'static mut [u8], error: i2c::Error) {
+        self.i2c.disable();
+        self.buffer.replace(buffer);
+        let result = match error {
+            i2c::Error::AddressNak => Ok(()),
+            _ => Err(ErrorCode::FAIL),
+        };
+        self.client.map(|client| client.self_test_done(result));
+    }
+}
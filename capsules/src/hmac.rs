@@ -28,6 +28,7 @@ use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Hmac as usize;
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryInto;
 use core::marker::PhantomData;
 use core::mem;
@@ -79,33 +80,27 @@ where
         self.appid.map_or(Err(ErrorCode::RESERVE), |appid| {
             self.apps
                 .enter(*appid, |app| {
-                    app.key.map_or((), |k| {
-                        self.hmac
-                            .set_mode_hmacsha256(k.as_ref().try_into().unwrap())
-                            .unwrap();
-                    });
+                    // The key must be exactly as long as the HMAC-SHA256 key
+                    app.key.map_or(Ok(()), |k| {
+                        k.as_ref()
+                            .try_into()
+                            .map_err(|_| ErrorCode::INVAL)
+                            .and_then(|key| self.hmac.set_mode_hmacsha256(key))
+                    })?;
 
                     app.data.map_or(Err(ErrorCode::RESERVE), |d| {
-                        self.data_buffer.map(|buf| {
-                            let data = d.as_ref();
-
-                            // Determine the size of the static buffer we have
-                            let static_buffer_len = buf.len();
+                        let mut lease_buf =
+                            LeasableBuffer::new(self.data_buffer.take().ok_or(ErrorCode::BUSY)?);
+                        let data = d.as_ref();
 
-                            // If we have more data then the static buffer we set how much data we are going to copy
-                            if data.len() > static_buffer_len {
-                                self.data_copied.set(static_buffer_len);
-                            }
-
-                            // Copy the data into the static buffer
-                            buf.copy_from_slice(&data[..static_buffer_len]);
-                        });
+                        // Copy as much data as fits into the static buffer
+                        let copied = cmp::min(data.len(), lease_buf.len());
+                        lease_buf[..copied].copy_from_slice(&data[..copied]);
+                        lease_buf.slice(..copied);
+                        self.data_copied.set(copied);
 
                         // Add the data from the static buffer to the HMAC
-                        if let Err(e) = self
-                            .hmac
-                            .add_data(LeasableBuffer::new(self.data_buffer.take().unwrap()))
-                        {
+                        if let Err(e) = self.hmac.add_data(lease_buf) {
                             self.data_buffer.replace(e.1);
                             return Err(e.0);
                         }
@@ -190,8 +185,10 @@ impl<'a, H: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> digest::C
                             // Update the amount of data copied
                             self.data_copied.set(copied_data + static_buffer_len);
 
-                            let mut lease_buf =
-                                LeasableBuffer::new(self.data_buffer.take().unwrap());
+                            let mut lease_buf = match self.data_buffer.take() {
+                                Some(buf) => LeasableBuffer::new(buf),
+                                None => return,
+                            };
 
                             // Add the data from the static buffer to the HMAC
                             if data_len < (copied_data + static_buffer_len) {
@@ -214,13 +211,22 @@ impl<'a, H: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> digest::C
                     // If we get here we are ready to run the digest, reset the copied data
                     self.data_copied.set(0);
 
-                    if let Err(e) = self.hmac.run(self.dest_buffer.take().unwrap()) {
+                    let result = self
+                        .dest_buffer
+                        .take()
+                        .map_or(Err(ErrorCode::BUSY), |dest| {
+                            self.hmac.run(dest).map_err(|(e, dest)| {
+                                self.dest_buffer.replace(dest);
+                                e
+                            })
+                        });
+                    if let Err(e) = result {
                         // Error, clear the appid and data
                         self.hmac.clear_data();
                         self.appid.clear();
 
                         app.callback
-                            .schedule(kernel::into_statuscode(e.0.into()), 0, 0);
+                            .schedule(kernel::into_statuscode(e.into()), 0, 0);
 
                         self.check_queue();
                         return;
@@ -52,38 +52,38 @@ impl<'a, I: 'a + i2c::I2CMaster> I2CMasterDriver<'a, I> {
         addr: u8,
         wlen: u8,
         rlen: u8,
-    ) {
-        // TODO(alevy) this function used to try and return Result<(), ErrorCode>s, but would always return
-        // ENOSUPPORT and all call-sites simply ignore the return value. Nonetheless, some error
-        // handling is probably useful. Comments inline where there used to be non-success results.
-        self.apps
-            .enter(app_id, |_| {
-                // TODO(alevy): if app.slice.map doesn't have a slice, we would have returned
-                // INVAL here. I.e., the driver is attempting an operation without sharing memory.
-                app.slice.map_or((), |app_buffer| {
-                    self.buf.take().map(|buffer| {
-                        buffer[..(wlen as usize)].copy_from_slice(&app_buffer[..(wlen as usize)]);
-
-                        let read_len: OptionalCell<usize>;
-                        if rlen == 0 {
-                            read_len = OptionalCell::empty();
-                        } else {
-                            read_len = OptionalCell::new(rlen as usize);
-                        }
-                        self.tx.put(Transaction { app_id, read_len });
-
-                        match command {
-                            Cmd::Ping => (), // Unexpected, shouldn't get here (was Err(ErrorCode::INVAL))
-                            Cmd::Write => self.i2c.write(addr, buffer, wlen),
-                            Cmd::Read => self.i2c.read(addr, buffer, rlen),
-                            Cmd::WriteRead => self.i2c.write_read(addr, buffer, wlen, rlen),
-                        }
-                    });
-                    // TODO(alevy): if buf.take() returned None, the I2C hadn't returned the
-                    // buffer. This shouldn't happen and previous this returned NOMEM
-                })
+    ) -> Result<(), ErrorCode> {
+        let (wlen, rlen) = (wlen as usize, rlen as usize);
+        // The driver needs memory shared with the process to operate on.
+        app.slice.map_or(Err(ErrorCode::INVAL), |app_buffer| {
+            if wlen > app_buffer.len() || rlen > app_buffer.len() {
+                return Err(ErrorCode::SIZE);
+            }
+            // The I2C has not returned the buffer of the last operation.
+            self.buf.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+                if wlen > buffer.len() || rlen > buffer.len() {
+                    self.buf.replace(buffer);
+                    return Err(ErrorCode::SIZE);
+                }
+                buffer[..wlen].copy_from_slice(&app_buffer[..wlen]);
+
+                let read_len: OptionalCell<usize>;
+                if rlen == 0 {
+                    read_len = OptionalCell::empty();
+                } else {
+                    read_len = OptionalCell::new(rlen);
+                }
+                self.tx.put(Transaction { app_id, read_len });
+
+                match command {
+                    Cmd::Ping => (), // Unexpected, shouldn't get here (was Err(ErrorCode::INVAL))
+                    Cmd::Write => self.i2c.write(addr, buffer, wlen as u8),
+                    Cmd::Read => self.i2c.read(addr, buffer, rlen as u8),
+                    Cmd::WriteRead => self.i2c.write_read(addr, buffer, wlen as u8, rlen as u8),
+                }
+                Ok(())
             })
-            .expect("Appid does not map to app");
+        })
     }
 }
 
@@ -163,8 +163,8 @@ impl<'a, I: 'a + i2c::I2CMaster> Driver for I2CMasterDriver<'a, I> {
                     .enter(appid, |app| {
                         let addr = arg1 as u8;
                         let write_len = arg2;
-                        self.operation(appid, app, Cmd::Write, addr, write_len as u8, 0);
-                        CommandReturn::success()
+                        self.operation(appid, app, Cmd::Write, addr, write_len as u8, 0)
+                            .into()
                     })
                     .unwrap_or_else(|err| err.into()),
                 Cmd::Read => self
@@ -172,8 +172,8 @@ impl<'a, I: 'a + i2c::I2CMaster> Driver for I2CMasterDriver<'a, I> {
                     .enter(appid, |app| {
                         let addr = arg1 as u8;
                         let read_len = arg2;
-                        self.operation(appid, app, Cmd::Read, addr, 0, read_len as u8);
-                        CommandReturn::success()
+                        self.operation(appid, app, Cmd::Read, addr, 0, read_len as u8)
+                            .into()
                     })
                     .unwrap_or_else(|err| err.into()),
                 Cmd::WriteRead => {
@@ -189,8 +189,8 @@ impl<'a, I: 'a + i2c::I2CMaster> Driver for I2CMasterDriver<'a, I> {
                                 addr,
                                 write_len as u8,
                                 read_len as u8,
-                            );
-                            CommandReturn::success()
+                            )
+                            .into()
                         })
                         .unwrap_or_else(|err| err.into())
                 }
@@ -207,6 +207,8 @@ impl<'a, I: 'a + i2c::I2CMaster> i2c::I2CHwMasterClient for I2CMasterDriver<'a,
             self.apps.enter(tx.app_id, |app| {
                 if let Some(read_len) = tx.read_len.take() {
                     app.slice.mut_map_or((), |app_buffer| {
+                        // The process may have swapped in a smaller buffer.
+                        let read_len = core::cmp::min(read_len, app_buffer.len());
                         app_buffer[..read_len].copy_from_slice(&buffer[..read_len]);
                     });
                 }
@@ -3,17 +3,32 @@
 //! Implements a userspace interface for sending and receiving IEEE 802.15.4
 //! frames. Also provides a minimal list-based interface for managing keys and
 //! known link neighbors, which is needed for 802.15.4 security.
+//!
+//! If the radio supports it, processes can also scan the energy on channels to
+//! pick a quiet one. Scans use the radio below the MAC, so the board has to
+//! give the driver the `RawRadioAccessCapability`:
+//!
+//! ```rust
+//! radio_driver.set_energy_detect(
+//!     &base_peripherals.ieee802154_radio,
+//!     &create_capability!(capabilities::RawRadioAccessCapability),
+//! );
+//! base_peripherals.ieee802154_radio.set_energy_detect_client(radio_driver);
+//! ```
 
+use crate::energy_estimator::RadioUsage;
 use crate::ieee802154::{device, framer};
 use crate::net::ieee802154::{AddressMode, Header, KeyId, MacAddress, PanID, SecurityLevel};
 use crate::net::stream::{decode_bytes, decode_u8, encode_bytes, encode_u8, SResult};
 use core::cell::Cell;
 use core::cmp::min;
 use core::mem;
+use kernel::capabilities::RawRadioAccessCapability;
 use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::common::dynamic_deferred_call::{
     DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
 };
+use kernel::hil::radio;
 use kernel::{
     CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
     ReadWriteAppSlice, Upcall,
@@ -22,6 +37,11 @@ use kernel::{
 const MAX_NEIGHBORS: usize = 4;
 const MAX_KEYS: usize = 4;
 
+/// Channels of the 2.4 GHz band, 11 to 26, as a bitmask.
+const CHANNEL_MASK: u32 = 0x07ff_f800;
+const FIRST_CHANNEL: u8 = 11;
+const NUM_CHANNELS: usize = 16;
+
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Ieee802154 as usize;
 
@@ -155,10 +175,16 @@ impl KeyDescriptor {
 pub struct App {
     rx_callback: Upcall,
     tx_callback: Upcall,
+    scan_callback: Upcall,
     app_read: ReadWriteAppSlice,
     app_write: ReadOnlyAppSlice,
     app_cfg: ReadWriteAppSlice,
     pending_tx: Option<(u16, Option<(SecurityLevel, KeyId)>)>,
+    /// Event clock tick count when the last frame was received.
+    rx_timestamp: Option<u32>,
+    /// Payload bytes transmitted and received for this app.
+    tx_bytes: usize,
+    rx_bytes: usize,
 }
 
 pub struct RadioDriver<'a> {
@@ -196,6 +222,16 @@ pub struct RadioDriver<'a> {
 
     /// Used to save result for passing a callback from a deferred call.
     saved_result: OptionalCell<Result<(), ErrorCode>>,
+
+    /// Radio that measures the energy on channels, if it can.
+    energy_detect: OptionalCell<&'a dyn radio::RadioEnergyDetect>,
+    /// ID of app whose energy scan is running.
+    scan_app: OptionalCell<ProcessId>,
+    /// Channels being scanned, and those left to measure, as bitmasks.
+    scan_channels: Cell<u32>,
+    scan_remaining: Cell<u32>,
+    /// Time to measure each channel for, in microseconds.
+    scan_duration: Cell<u32>,
 }
 
 impl<'a> RadioDriver<'a> {
@@ -218,6 +254,11 @@ impl<'a> RadioDriver<'a> {
             saved_appid: OptionalCell::empty(),
             saved_result: OptionalCell::empty(),
             handle: OptionalCell::empty(),
+            energy_detect: OptionalCell::empty(),
+            scan_app: OptionalCell::empty(),
+            scan_channels: Cell::new(0),
+            scan_remaining: Cell::new(0),
+            scan_duration: Cell::new(0),
         }
     }
 
@@ -225,6 +266,96 @@ impl<'a> RadioDriver<'a> {
         self.handle.replace(handle);
     }
 
+    /// Set the radio energy scans use. Without one they are NOSUPPORT.
+    pub fn set_energy_detect(
+        &self,
+        energy_detect: &'a dyn radio::RadioEnergyDetect,
+        _capability: &dyn RawRadioAccessCapability,
+    ) {
+        self.energy_detect.set(energy_detect);
+    }
+
+    // Energy scan functions
+
+    /// Start measuring the energy on `channels`, a bitmask of channels 11 to
+    /// 26, for `duration_us` each. The results go in the config buffer of
+    /// the app, which must hold one byte per channel.
+    fn start_scan(
+        &self,
+        appid: ProcessId,
+        channels: u32,
+        duration_us: u32,
+    ) -> Result<(), ErrorCode> {
+        if self.energy_detect.is_none() {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        if channels == 0 || channels & !CHANNEL_MASK != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.scan_app.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.apps
+            .enter(appid, |app| {
+                app.app_cfg.mut_map_or(Err(ErrorCode::INVAL), |cfg| {
+                    if cfg.len() != NUM_CHANNELS {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    for level in cfg.iter_mut() {
+                        *level = 0;
+                    }
+                    Ok(())
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+
+        self.scan_app.set(appid);
+        self.scan_channels.set(channels);
+        self.scan_remaining.set(channels);
+        self.scan_duration.set(duration_us);
+        self.measure_next().map_err(|e| {
+            self.scan_app.clear();
+            e
+        })
+    }
+
+    /// Measure the energy on the next channel of the scan.
+    fn measure_next(&self) -> Result<(), ErrorCode> {
+        let remaining = self.scan_remaining.get();
+        let channel = remaining.trailing_zeros();
+        self.scan_remaining.set(remaining & !(1 << channel));
+        self.energy_detect
+            .map_or(Err(ErrorCode::NOSUPPORT), |energy_detect| {
+                energy_detect.energy_detect(channel as u8, self.scan_duration.get())
+            })
+    }
+
+    /// Tell the app the scan is done and, if it succeeded, which channel was
+    /// the quietest.
+    fn scan_done(&self, result: Result<(), ErrorCode>) {
+        let channels = self.scan_channels.get();
+        self.scan_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                let (channel, level) = match result {
+                    Ok(()) => app.app_cfg.map_or((0, 0), |cfg| {
+                        cfg.iter()
+                            .enumerate()
+                            .map(|(i, level)| (FIRST_CHANNEL + i as u8, *level))
+                            .filter(|(channel, _)| channels & 1 << channel != 0)
+                            .min_by_key(|(_, level)| *level)
+                            .unwrap_or((0, 0))
+                    }),
+                    Err(_) => (0, 0),
+                };
+                app.scan_callback.schedule(
+                    kernel::into_statuscode(result),
+                    channel as usize,
+                    level as usize,
+                );
+            });
+        });
+    }
+
     // Neighbor management functions
 
     /// Add a new neighbor to the end of the list if there is still space
@@ -423,7 +554,10 @@ impl<'a> RadioDriver<'a> {
 
                 // Finally, transmit the frame
                 match self.mac.transmit(frame) {
-                    Ok(()) => Ok(()),
+                    Ok(()) => {
+                        app.tx_bytes += app.app_write.len();
+                        Ok(())
+                    }
                     Err((ecode, buf)) => {
                         self.kernel_tx.put(Some(buf));
                         Err(ecode)
@@ -465,15 +599,15 @@ impl<'a> RadioDriver<'a> {
 
 impl DynamicDeferredCallClient for RadioDriver<'_> {
     fn call(&self, _handle: DeferredCallHandle) {
-        let _ = self
-            .apps
-            .enter(self.saved_appid.expect("missing appid"), |app| {
+        self.saved_appid.map(|appid| {
+            let _ = self.apps.enter(*appid, |app| {
                 app.tx_callback.schedule(
-                    kernel::into_statuscode(self.saved_result.expect("missing result")),
+                    kernel::into_statuscode(self.saved_result.unwrap_or(Err(ErrorCode::FAIL))),
                     0,
                     0,
                 );
             });
+        });
     }
 }
 
@@ -565,6 +699,8 @@ impl Driver for RadioDriver<'_> {
     ///
     /// - `0`: Setup callback for when frame is received.
     /// - `1`: Setup callback for when frame is transmitted.
+    /// - `2`: Setup callback for when an energy scan is done. It gets the
+    ///        result, the quietest channel scanned and its energy.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -581,6 +717,10 @@ impl Driver for RadioDriver<'_> {
                     mem::swap(&mut app.tx_callback, &mut callback);
                     Ok(callback)
                 }
+                2 => {
+                    mem::swap(&mut app.scan_callback, &mut callback);
+                    Ok(callback)
+                }
                 _ => Err((callback, ErrorCode::NOSUPPORT)),
             })
             .unwrap_or_else(|err| Err((callback, err.into())))
@@ -635,11 +775,22 @@ impl Driver for RadioDriver<'_> {
     ///                      9 bytes: the key ID (might not use all bytes) +
     ///                      16 bytes: the key.
     /// - `25`: Remove the key at an index.
+    /// - `26`: Transmit the frame in the write buffer to the given short
+    ///        address, with the security settings in app_cfg.
+    /// - `27`: Get the event clock tick count when the last frame was
+    ///        received. Returns NOSUPPORT if the board does not timestamp
+    ///        events.
+    /// - `28`: Scan the energy on the channels in the bitmask `arg1`, where
+    ///        bit n is channel n, measuring each for `arg2` microseconds.
+    ///        No frames are sent or received during the scan. Returns
+    ///        NOSUPPORT if the radio cannot measure energy.
+    ///        app_cfg (out): 16 bytes: the IEEE 802.15.4 energy detection
+    ///                       value of channels 11 to 26, 0 if not scanned.
     fn command(
         &self,
         command_number: usize,
         arg1: usize,
-        _: usize,
+        arg2: usize,
         appid: ProcessId,
     ) -> CommandReturn {
         match command_number {
@@ -873,6 +1024,14 @@ impl Driver for RadioDriver<'_> {
                         },
                     )
             }
+            27 => self
+                .apps
+                .enter(appid, |app| match app.rx_timestamp {
+                    Some(timestamp) => CommandReturn::success_u32(timestamp),
+                    None => CommandReturn::failure(ErrorCode::NOSUPPORT),
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+            28 => self.start_scan(appid, arg1 as u32, arg2 as u32).into(),
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -891,6 +1050,32 @@ impl device::TxClient for RadioDriver<'_> {
     }
 }
 
+impl radio::EnergyDetectClient for RadioDriver<'_> {
+    fn energy_detect_done(&self, channel: u8, result: Result<u8, ErrorCode>) {
+        if let Ok(level) = result {
+            self.scan_app.map(|appid| {
+                let _ = self.apps.enter(*appid, |app| {
+                    app.app_cfg.mut_map_or((), |cfg| {
+                        let index = channel.wrapping_sub(FIRST_CHANNEL) as usize;
+                        if let Some(entry) = cfg.get_mut(index) {
+                            *entry = level;
+                        }
+                    });
+                });
+            });
+        }
+        let result = match result {
+            Ok(_) if self.scan_remaining.get() != 0 => match self.measure_next() {
+                Ok(()) => return,
+                Err(e) => Err(e),
+            },
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        self.scan_done(result);
+    }
+}
+
 /// Encode two PAN IDs into a single usize.
 #[inline]
 fn encode_pans(dst_pan: &Option<PanID>, src_pan: &Option<PanID>) -> usize {
@@ -907,6 +1092,14 @@ fn encode_address(addr: &Option<MacAddress>) -> usize {
     ((AddressMode::from(addr) as usize) << 16) | short_addr_only
 }
 
+impl RadioUsage for RadioDriver<'_> {
+    fn radio_bytes(&self, appid: ProcessId) -> (usize, usize) {
+        self.apps
+            .enter(appid, |app| (app.tx_bytes, app.rx_bytes))
+            .unwrap_or((0, 0))
+    }
+}
+
 impl device::RxClient for RadioDriver<'_> {
     fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
         self.apps.each(|_, app| {
@@ -925,6 +1118,8 @@ impl device::RxClient for RadioDriver<'_> {
                 let pans = encode_pans(&header.dst_pan, &header.src_pan);
                 let dst_addr = encode_address(&header.dst_addr);
                 let src_addr = encode_address(&header.src_addr);
+                app.rx_timestamp = app.rx_callback.event_timestamp();
+                app.rx_bytes += data_len;
                 app.rx_callback.schedule(pans, dst_addr, src_addr);
             }
         });
@@ -10,6 +10,7 @@
 //! through each frame for transmission.
 
 use crate::net::ieee802154::{Header, MacAddress};
+use crate::radio_limits::RadioLimits;
 use kernel::common::cells::OptionalCell;
 use kernel::debug;
 use kernel::hil::radio;
@@ -69,6 +70,7 @@ pub trait Mac {
 ///
 pub struct AwakeMac<'a, R: radio::Radio> {
     radio: &'a R,
+    limits: OptionalCell<&'a RadioLimits>,
 
     tx_client: OptionalCell<&'static dyn radio::TxClient>,
     rx_client: OptionalCell<&'static dyn radio::RxClient>,
@@ -78,10 +80,17 @@ impl<'a, R: radio::Radio> AwakeMac<'a, R> {
     pub fn new(radio: &'a R) -> AwakeMac<'a, R> {
         AwakeMac {
             radio: radio,
+            limits: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
             rx_client: OptionalCell::empty(),
         }
     }
+
+    /// Refuse to transmit while the channel or TX power of the radio is
+    /// outside `limits`.
+    pub fn set_limits(&self, limits: &'a RadioLimits) {
+        self.limits.set(limits);
+    }
 }
 
 impl<R: radio::Radio> Mac for AwakeMac<'_, R> {
@@ -143,6 +152,12 @@ impl<R: radio::Radio> Mac for AwakeMac<'_, R> {
         full_mac_frame: &'static mut [u8],
         frame_len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let allowed = self.limits.map_or(Ok(()), |limits| {
+            limits.check_ieee802154(self.radio.get_channel(), self.radio.get_tx_power())
+        });
+        if let Err(e) = allowed {
+            return Err((e, full_mac_frame));
+        }
         self.radio.transmit(full_mac_frame, frame_len)
     }
 }
@@ -186,3 +201,140 @@ impl<R: radio::Radio> radio::RxClient for AwakeMac<'_, R> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::radio_limits::Radio;
+    use core::cell::Cell;
+
+    extern crate std;
+    use std::boxed::Box;
+
+    /// Counts the frames it is asked to send.
+    struct MockRadio {
+        channel: u8,
+        tx_power: i8,
+        sent: Cell<usize>,
+    }
+
+    impl radio::RadioConfig for MockRadio {
+        fn initialize(
+            &self,
+            _spi_buf: &'static mut [u8],
+            _reg_write: &'static mut [u8],
+            _reg_read: &'static mut [u8],
+        ) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn reset(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn start(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn stop(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn is_on(&self) -> bool {
+            true
+        }
+        fn busy(&self) -> bool {
+            false
+        }
+        fn set_power_client(&self, _client: &'static dyn radio::PowerClient) {}
+        fn config_commit(&self) {}
+        fn set_config_client(&self, _client: &'static dyn radio::ConfigClient) {}
+        fn get_address(&self) -> u16 {
+            0
+        }
+        fn get_address_long(&self) -> [u8; 8] {
+            [0; 8]
+        }
+        fn get_pan(&self) -> u16 {
+            0
+        }
+        fn get_tx_power(&self) -> i8 {
+            self.tx_power
+        }
+        fn get_channel(&self) -> u8 {
+            self.channel
+        }
+        fn set_address(&self, _addr: u16) {}
+        fn set_address_long(&self, _addr: [u8; 8]) {}
+        fn set_pan(&self, _id: u16) {}
+        fn set_tx_power(&self, _power: i8) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn set_channel(&self, _chan: u8) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    impl radio::RadioData for MockRadio {
+        fn set_transmit_client(&self, _client: &'static dyn radio::TxClient) {}
+        fn set_receive_client(
+            &self,
+            _client: &'static dyn radio::RxClient,
+            _receive_buffer: &'static mut [u8],
+        ) {
+        }
+        fn set_receive_buffer(&self, _receive_buffer: &'static mut [u8]) {}
+        fn transmit(
+            &self,
+            _spi_buf: &'static mut [u8],
+            _frame_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.sent.set(self.sent.get() + 1);
+            Ok(())
+        }
+    }
+
+    impl radio::Radio for MockRadio {}
+
+    fn transmit(mac: &AwakeMac<MockRadio>) -> Result<(), ErrorCode> {
+        let frame = Box::leak(Box::new([0; radio::MAX_BUF_SIZE]));
+        mac.transmit(frame, radio::MIN_FRAME_SIZE)
+            .map_err(|(e, _)| e)
+    }
+
+    #[test]
+    fn test_transmit_within_limits() {
+        let radio = MockRadio {
+            channel: 26,
+            tx_power: 0,
+            sent: Cell::new(0),
+        };
+        let limits = RadioLimits::new();
+        let mac = AwakeMac::new(&radio);
+        assert_eq!(transmit(&mac), Ok(()));
+
+        mac.set_limits(&limits);
+        limits.set_max_tx_power_dbm(Radio::Ieee802154, 0);
+        assert_eq!(transmit(&mac), Ok(()));
+        assert_eq!(radio.sent.get(), 2);
+    }
+
+    #[test]
+    fn test_transmit_outside_limits() {
+        let radio = MockRadio {
+            channel: 26,
+            tx_power: 4,
+            sent: Cell::new(0),
+        };
+        let limits = RadioLimits::new();
+        let mac = AwakeMac::new(&radio);
+        mac.set_limits(&limits);
+
+        // Limits lowered after the radio was configured
+        limits.set_max_tx_power_dbm(Radio::Ieee802154, 0);
+        assert_eq!(transmit(&mac), Err(ErrorCode::INVAL));
+
+        limits.set_max_tx_power_dbm(Radio::Ieee802154, 4);
+        limits
+            .set_channel_allowed(Radio::Ieee802154, 26, false)
+            .unwrap();
+        assert_eq!(transmit(&mac), Err(ErrorCode::INVAL));
+        assert_eq!(radio.sent.get(), 0);
+    }
+}
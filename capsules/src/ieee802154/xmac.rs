@@ -80,6 +80,7 @@
 
 use crate::ieee802154::mac::Mac;
 use crate::net::ieee802154::{FrameType, FrameVersion, Header, MacAddress, PanID};
+use crate::radio_limits::RadioLimits;
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil::radio;
@@ -148,6 +149,7 @@ pub struct XMac<'a, R: radio::Radio, A: Alarm<'a>> {
     radio: &'a R,
     alarm: &'a A,
     rng: &'a dyn Rng<'a>,
+    limits: OptionalCell<&'a RadioLimits>,
     tx_client: OptionalCell<&'static dyn radio::TxClient>,
     rx_client: OptionalCell<&'static dyn radio::RxClient>,
     state: Cell<XMacState>,
@@ -170,6 +172,7 @@ impl<'a, R: radio::Radio, A: Alarm<'a>> XMac<'a, R, A> {
             radio: radio,
             alarm: alarm,
             rng: rng,
+            limits: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
             rx_client: OptionalCell::empty(),
             state: Cell::new(XMacState::STARTUP),
@@ -184,6 +187,12 @@ impl<'a, R: radio::Radio, A: Alarm<'a>> XMac<'a, R, A> {
         }
     }
 
+    /// Refuse to transmit while the channel or TX power of the radio is
+    /// outside `limits`.
+    pub fn set_limits(&self, limits: &'a RadioLimits) {
+        self.limits.set(limits);
+    }
+
     fn sleep_time(&self) -> u32 {
         // TODO (ongoing) modify based on traffic load to efficiently schedule
         // sleep. Currently sleeps for a constant amount of time.
@@ -418,6 +427,12 @@ impl<'a, R: radio::Radio, A: Alarm<'a>> Mac for XMac<'a, R, A> {
         } else if radio::PSDU_OFFSET + frame_len >= full_mac_frame.len() {
             return Err((ErrorCode::SIZE, full_mac_frame));
         }
+        let allowed = self.limits.map_or(Ok(()), |limits| {
+            limits.check_ieee802154(self.radio.get_channel(), self.radio.get_tx_power())
+        });
+        if let Err(e) = allowed {
+            return Err((e, full_mac_frame));
+        }
 
         match Header::decode(&full_mac_frame[radio::PSDU_OFFSET..], false).done() {
             Some((_, (header, _))) => {
@@ -0,0 +1,451 @@
+//! Provides userspace with the orientation of the board, fused from its
+//! motion sensors.
+//!
+//! At a fixed rate, the capsule reads the gyroscope, accelerometer and, if
+//! the board has one, magnetometer through the `NineDof` HIL, and updates an
+//! orientation quaternion with a Mahony filter. The gyroscope rates are
+//! integrated to follow fast rotations, while the directions of gravity and
+//! of the magnetic field slowly correct the drift this accumulates. Without a
+//! magnetometer, the heading drifts freely but pitch and roll stay correct.
+//!
+//! Drivers report the rotation rate in different units, so boards pass the
+//! rate in milli-degrees per second of one unit of their gyroscope. The other
+//! sensors only give directions, so their units do not matter, but the axes of
+//! all three must be aligned.
+//!
+//! The filter runs while at least one process has enabled orientation
+//! events. The capsule must be the `NineDof` client of its drivers, so it can
+//! not share them with the `ninedof` capsule.
+//!
+//! Quaternions are passed to userspace in Q14 fixed point, where `16384` is
+//! one, with two components packed in each argument: `w` in the upper and `x`
+//! in the lower 16 bits of the first, and `y` and `z` likewise in the second.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let imu_drivers = static_init!(
+//!     [&'static dyn kernel::hil::sensors::NineDof; 2],
+//!     [l3gd20, lsm303dlhc]
+//! );
+//! let imu_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, stm32f303xc::tim2::Tim2>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let imu_fusion = static_init!(
+//!     capsules::imu_fusion::ImuFusion<'static, VirtualMuxAlarm<'static, stm32f303xc::tim2::Tim2>>,
+//!     capsules::imu_fusion::ImuFusion::new(
+//!         imu_drivers,
+//!         imu_alarm,
+//!         1000, // The L3GD20 driver reports degrees per second.
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! kernel::hil::sensors::NineDof::set_client(l3gd20, imu_fusion);
+//! kernel::hil::sensors::NineDof::set_client(lsm303dlhc, imu_fusion);
+//! imu_alarm.set_alarm_client(imu_fusion);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: orientation events. The callback gets the packed quaternion.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the update rate in Hz.
+//! - `1`: Enable orientation events for this process, after every `arg1`-th
+//!   update. `0` is the same as `1`.
+//! - `2`: Disable orientation events for this process.
+//! - `3`: Get the packed quaternion.
+//! - `4`: Reset the orientation to the identity.
+
+use core::cell::Cell;
+use core::f32::consts::PI;
+use core::mem;
+
+use kernel::hil::sensors::{NineDof, NineDofClient};
+use kernel::hil::time::{self, Alarm};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ImuFusion as usize;
+
+/// Time between updates of the orientation.
+const UPDATE_INTERVAL_MS: u32 = 20;
+const UPDATE_RATE_HZ: u32 = 1000 / UPDATE_INTERVAL_MS;
+
+/// Proportional and integral gains of the filter, doubled. The proportional
+/// gain sets how fast gravity and the magnetic field correct the orientation;
+/// the integral gain learns the gyroscope's bias.
+const TWO_KP: f32 = 2.0 * 0.5;
+const TWO_KI: f32 = 2.0 * 0.0;
+
+/// One in the Q14 fixed point passed to userspace.
+const Q14_ONE: f32 = 16384.0;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    enabled: bool,
+    /// Number of updates between events.
+    interval: usize,
+    /// Updates since the last event.
+    count: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Gyroscope,
+    Accelerometer,
+    Magnetometer,
+}
+
+/// An orientation quaternion.
+#[derive(Clone, Copy)]
+struct Quaternion {
+    w: f32,
+    x: f32,
+    y: f32,
+    z: f32,
+}
+
+impl Quaternion {
+    const IDENTITY: Quaternion = Quaternion {
+        w: 1.0,
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    /// The quaternion packed into two words of Q14 components.
+    fn pack(&self) -> (u32, u32) {
+        let q14 = |v: f32| (v * Q14_ONE) as i16 as u16 as u32;
+        (
+            q14(self.w) << 16 | q14(self.x),
+            q14(self.y) << 16 | q14(self.z),
+        )
+    }
+}
+
+/// `1 / sqrt(x)` for positive `x`, which `core` does not provide.
+pub(crate) fn inv_sqrt(x: f32) -> f32 {
+    let y = f32::from_bits(0x5f37_59df - (x.to_bits() >> 1));
+    // Two Newton iterations bring the estimate to full precision.
+    let y = y * (1.5 - 0.5 * x * y * y);
+    y * (1.5 - 0.5 * x * y * y)
+}
+
+/// `v` scaled to unit length, or `None` if it is zero.
+fn normalize(v: [f32; 3]) -> Option<[f32; 3]> {
+    let norm = v[0] * v[0] + v[1] * v[1] + v[2] * v[2];
+    if norm > 0.0 {
+        let recip = inv_sqrt(norm);
+        Some([v[0] * recip, v[1] * recip, v[2] * recip])
+    } else {
+        None
+    }
+}
+
+pub struct ImuFusion<'a, A: Alarm<'a>> {
+    drivers: &'a [&'a dyn NineDof<'a>],
+    alarm: &'a A,
+    /// Rotation rate of one gyroscope unit, in radians per second.
+    gyro_scale: f32,
+    state: Cell<State>,
+    running: Cell<bool>,
+    gyro: Cell<[f32; 3]>,
+    accel: Cell<[f32; 3]>,
+    orientation: Cell<Quaternion>,
+    /// Integral of the error, scaled by the integral gain.
+    integral: Cell<[f32; 3]>,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> ImuFusion<'a, A> {
+    /// Fuse the sensors of `drivers`, whose gyroscope reports rates in units
+    /// of `gyro_mdps` milli-degrees per second.
+    pub fn new(
+        drivers: &'a [&'a dyn NineDof<'a>],
+        alarm: &'a A,
+        gyro_mdps: u32,
+        grant: Grant<App>,
+    ) -> ImuFusion<'a, A> {
+        ImuFusion {
+            drivers: drivers,
+            alarm: alarm,
+            gyro_scale: gyro_mdps as f32 / 1000.0 * PI / 180.0,
+            state: Cell::new(State::Idle),
+            running: Cell::new(false),
+            gyro: Cell::new([0.0; 3]),
+            accel: Cell::new([0.0; 3]),
+            orientation: Cell::new(Quaternion::IDENTITY),
+            integral: Cell::new([0.0; 3]),
+            apps: grant,
+        }
+    }
+
+    /// Run the filter while any process wants orientation events.
+    fn update_running(&self) {
+        let enabled = Cell::new(false);
+        self.apps.each(|_, app| {
+            if app.enabled {
+                enabled.set(true);
+            }
+        });
+        if enabled.get() && !self.running.get() {
+            self.running.set(true);
+            self.integral.set([0.0; 3]);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(UPDATE_INTERVAL_MS));
+        } else if !enabled.get() && self.running.get() {
+            self.running.set(false);
+            let _ = self.alarm.disarm();
+        }
+    }
+
+    /// Start reading the sensor of `state` from the first driver that has
+    /// one. Returns whether a read started.
+    fn read(&self, state: State) -> bool {
+        let started = self.drivers.iter().any(|driver| {
+            match state {
+                State::Gyroscope => driver.read_gyroscope(),
+                State::Accelerometer => driver.read_accelerometer(),
+                State::Magnetometer => driver.read_magnetometer(),
+                State::Idle => Err(ErrorCode::INVAL),
+            }
+            .is_ok()
+        });
+        if started {
+            self.state.set(state);
+        }
+        started
+    }
+
+    /// Read the sensors after the one of `state`, or update the orientation
+    /// once they are all read.
+    fn read_after(&self, state: State, mag: Option<[f32; 3]>) {
+        let started = match state {
+            State::Gyroscope => self.read(State::Accelerometer) || self.read(State::Magnetometer),
+            State::Accelerometer => self.read(State::Magnetometer),
+            _ => false,
+        };
+        if !started {
+            self.state.set(State::Idle);
+            self.update(mag);
+            self.notify();
+        }
+    }
+
+    /// One step of the Mahony filter.
+    fn update(&self, mag: Option<[f32; 3]>) {
+        let Quaternion {
+            w: q0,
+            x: q1,
+            y: q2,
+            z: q3,
+        } = self.orientation.get();
+        let [mut gx, mut gy, mut gz] = self.gyro.get();
+
+        // Without gravity to correct against, only integrate the gyroscope.
+        if let Some([ax, ay, az]) = normalize(self.accel.get()) {
+            // Gravity as the current orientation expects it.
+            let vx = q1 * q3 - q0 * q2;
+            let vy = q0 * q1 + q2 * q3;
+            let vz = q0 * q0 - 0.5 + q3 * q3;
+            let mut ex = ay * vz - az * vy;
+            let mut ey = az * vx - ax * vz;
+            let mut ez = ax * vy - ay * vx;
+
+            if let Some([mx, my, mz]) = mag.and_then(normalize) {
+                // The direction of the magnetic field in the earth frame,
+                // with its horizontal part turned onto the x axis.
+                let hx = 2.0
+                    * (mx * (0.5 - q2 * q2 - q3 * q3)
+                        + my * (q1 * q2 - q0 * q3)
+                        + mz * (q1 * q3 + q0 * q2));
+                let hy = 2.0
+                    * (mx * (q1 * q2 + q0 * q3)
+                        + my * (0.5 - q1 * q1 - q3 * q3)
+                        + mz * (q2 * q3 - q0 * q1));
+                let h2 = hx * hx + hy * hy;
+                let bx = h2 * inv_sqrt(h2);
+                let bz = 2.0
+                    * (mx * (q1 * q3 - q0 * q2)
+                        + my * (q2 * q3 + q0 * q1)
+                        + mz * (0.5 - q1 * q1 - q2 * q2));
+
+                // The magnetic field as the current orientation expects it.
+                let wx = bx * (0.5 - q2 * q2 - q3 * q3) + bz * (q1 * q3 - q0 * q2);
+                let wy = bx * (q1 * q2 - q0 * q3) + bz * (q0 * q1 + q2 * q3);
+                let wz = bx * (q0 * q2 + q1 * q3) + bz * (0.5 - q1 * q1 - q2 * q2);
+                ex += my * wz - mz * wy;
+                ey += mz * wx - mx * wz;
+                ez += mx * wy - my * wx;
+            }
+
+            let dt = UPDATE_INTERVAL_MS as f32 / 1000.0;
+            let [mut ix, mut iy, mut iz] = self.integral.get();
+            if TWO_KI > 0.0 {
+                ix += TWO_KI * ex * dt;
+                iy += TWO_KI * ey * dt;
+                iz += TWO_KI * ez * dt;
+                self.integral.set([ix, iy, iz]);
+            }
+            gx += ix + TWO_KP * ex;
+            gy += iy + TWO_KP * ey;
+            gz += iz + TWO_KP * ez;
+        }
+
+        // Integrate the rate of change of the quaternion.
+        let half_dt = 0.5 * UPDATE_INTERVAL_MS as f32 / 1000.0;
+        gx *= half_dt;
+        gy *= half_dt;
+        gz *= half_dt;
+        let w = q0 - q1 * gx - q2 * gy - q3 * gz;
+        let x = q1 + q0 * gx + q2 * gz - q3 * gy;
+        let y = q2 + q0 * gy - q1 * gz + q3 * gx;
+        let z = q3 + q0 * gz + q1 * gy - q2 * gx;
+
+        let recip = inv_sqrt(w * w + x * x + y * y + z * z);
+        self.orientation.set(Quaternion {
+            w: w * recip,
+            x: x * recip,
+            y: y * recip,
+            z: z * recip,
+        });
+    }
+
+    fn notify(&self) {
+        let (wx, yz) = self.orientation.get().pack();
+        self.apps.each(|_, app| {
+            if app.enabled {
+                app.count += 1;
+                if app.count >= app.interval {
+                    app.count = 0;
+                    app.callback.schedule(wx as usize, yz as usize, 0);
+                }
+            }
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for ImuFusion<'a, A> {
+    fn alarm(&self) {
+        if !self.running.get() {
+            return;
+        }
+        // Keep the rate fixed, however long the reads take.
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(UPDATE_INTERVAL_MS));
+
+        // Skip this update if the last one is still reading.
+        if self.state.get() == State::Idle {
+            self.gyro.set([0.0; 3]);
+            self.accel.set([0.0; 3]);
+            if !self.read(State::Gyroscope) {
+                self.read_after(State::Gyroscope, None);
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> NineDofClient for ImuFusion<'a, A> {
+    fn callback(&self, x: usize, y: usize, z: usize) {
+        // Drivers pass signed values.
+        let v = [x as isize as f32, y as isize as f32, z as isize as f32];
+        let state = self.state.get();
+        match state {
+            State::Gyroscope => {
+                let scale = self.gyro_scale;
+                self.gyro.set([v[0] * scale, v[1] * scale, v[2] * scale]);
+                self.read_after(state, None);
+            }
+            State::Accelerometer => {
+                self.accel.set(v);
+                self.read_after(state, None);
+            }
+            State::Magnetometer => self.read_after(state, Some(v)),
+            State::Idle => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for ImuFusion<'a, A> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Orientation event callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Control orientation events.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the update rate.
+    /// - `1`: Enable orientation events
+    /// - `2`: Disable orientation events
+    /// - `3`: Get the orientation
+    /// - `4`: Reset the orientation
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success_u32(UPDATE_RATE_HZ),
+            1 | 2 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app| {
+                        app.enabled = cmd_num == 1;
+                        app.interval = arg1;
+                        app.count = 0;
+                    })
+                    .map_err(ErrorCode::from);
+                match res {
+                    Ok(()) => {
+                        self.update_running();
+                        CommandReturn::success()
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+            3 => {
+                let (wx, yz) = self.orientation.get().pack();
+                CommandReturn::success_u32_u32(wx, yz)
+            }
+            4 => {
+                self.orientation.set(Quaternion::IDENTITY);
+                self.integral.set([0.0; 3]);
+                CommandReturn::success()
+            }
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Provides userspace with access to an inference engine.
+//!
+//! The board provides the models processes can run, as model descriptors in
+//! the format documented in `kernel::hil::inference`, and an engine to run
+//! them on: a hardware accelerator or `CpuInference`. Processes select a
+//! model by its index in the board's list. The engine runs one model at a
+//! time; runs started while it is busy fail with `BUSY`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! static MODELS: [&[u8]; 1] = [include_bytes!("gesture.tmdl")];
+//!
+//! let inference = static_init!(
+//!     capsules::inference::InferenceDriver<
+//!         'static,
+//!         capsules::cpu_inference::CpuInference<'static>,
+//!     >,
+//!     capsules::inference::InferenceDriver::new(
+//!         cpu_inference,
+//!         &MODELS,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hil::inference::Inference::set_client(cpu_inference, inference);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: input tensor.
+//! - Read-write `0`: buffer the output tensor is copied to.
+//!
+//! ### Subscribe
+//!
+//! - `0`: run done. The callback gets the status and the length of the
+//!   output tensor.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the number of models.
+//! - `1`: Select model `arg1`. Returns the lengths of its input and output
+//!   tensors.
+//! - `2`: Run the selected model on the input tensor.
+
+use core::mem;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::inference::{Inference, InferenceClient};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Inference as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    input: ReadOnlyAppSlice,
+    output: ReadWriteAppSlice,
+    model: Option<usize>,
+}
+
+pub struct InferenceDriver<'a, I: Inference<'a>> {
+    engine: &'a I,
+    models: &'a [&'static [u8]],
+    apps: Grant<App>,
+    /// Model loaded on the engine.
+    loaded: OptionalCell<usize>,
+    /// Process whose run is in progress.
+    current_app: OptionalCell<ProcessId>,
+}
+
+impl<'a, I: Inference<'a>> InferenceDriver<'a, I> {
+    pub fn new(
+        engine: &'a I,
+        models: &'a [&'static [u8]],
+        grant: Grant<App>,
+    ) -> InferenceDriver<'a, I> {
+        InferenceDriver {
+            engine: engine,
+            models: models,
+            apps: grant,
+            loaded: OptionalCell::empty(),
+            current_app: OptionalCell::empty(),
+        }
+    }
+
+    fn load(&self, model: usize) -> Result<(usize, usize), ErrorCode> {
+        let descriptor = self.models.get(model).ok_or(ErrorCode::INVAL)?;
+        let info = self.engine.load_model(descriptor).map_err(|e| {
+            self.loaded.clear();
+            e
+        })?;
+        self.loaded.set(model);
+        Ok((info.input_len, info.output_len))
+    }
+
+    fn select(&self, appid: ProcessId, model: usize) -> Result<(usize, usize), ErrorCode> {
+        if self.current_app.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let lengths = self.load(model)?;
+        self.apps
+            .enter(appid, |app| {
+                app.model = Some(model);
+            })
+            .map_err(ErrorCode::from)?;
+        Ok(lengths)
+    }
+
+    fn run(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        if self.current_app.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.apps
+            .enter(appid, |app| {
+                let model = app.model.ok_or(ErrorCode::RESERVE)?;
+                // Another process may have loaded a different model since.
+                if self.loaded.extract() != Some(model) {
+                    self.load(model)?;
+                }
+                app.input.map_or(Err(ErrorCode::RESERVE), |input| {
+                    self.engine.set_input(input)
+                })?;
+                self.engine.run()?;
+                self.current_app.set(appid);
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+}
+
+impl<'a, I: Inference<'a>> InferenceClient for InferenceDriver<'a, I> {
+    fn inference_done(&self, result: Result<(), ErrorCode>) {
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                let result = result.and_then(|()| {
+                    app.output.mut_map_or(Err(ErrorCode::RESERVE), |output| {
+                        self.engine.get_output(output)
+                    })
+                });
+                match result {
+                    Ok(len) => app.callback.schedule(0, len, 0),
+                    Err(e) => app.callback.schedule(kernel::into_statuscode(Err(e)), 0, 0),
+                }
+            });
+        });
+    }
+}
+
+impl<'a, I: Inference<'a>> Driver for InferenceDriver<'a, I> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Input tensor
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.input, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Output tensor
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.output, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Run done callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the number of models.
+    /// - `1`: Select a model
+    /// - `2`: Run the selected model
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success_u32(self.models.len() as u32),
+            1 => match self.select(appid, arg1) {
+                Ok((input_len, output_len)) => {
+                    CommandReturn::success_u32_u32(input_len as u32, output_len as u32)
+                }
+                Err(e) => CommandReturn::failure(e),
+            },
+            2 => match self.run(appid) {
+                Ok(()) => CommandReturn::success(),
+                Err(e) => CommandReturn::failure(e),
+            },
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! Lets a privileged process read the TBF headers of the installed apps.
+//!
+//! This gives a userspace updater what it needs to decide what to download:
+//! which TBF objects are in the app flash, where, how large they are, which
+//! version of the TBF format they use, and whether they are signed. Every
+//! object in the app flash is listed, including padding and apps the kernel
+//! did not load because they are disabled or their header is invalid.
+//!
+//! What is installed reveals which apps run on the board, so the driver only
+//! accepts commands from processes whose TBF header grants them (see the
+//! `Permissions` TLV in `doc/TockBinaryFormat.md`), and the board has to give
+//! it the `ProcessManagementCapability`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
+//! let installed_apps = static_init!(
+//!     capsules::installed_apps::InstalledApps<ProcessMgmtCap>,
+//!     capsules::installed_apps::InstalledApps::new(
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         core::slice::from_raw_parts(
+//!             &_sapps as *const u8,
+//!             &_eapps as *const u8 as usize - &_sapps as *const u8 as usize,
+//!         ),
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-write `0`: buffer the description of an object is written to.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Always allowed.
+//! - `1`: Return the number of TBF objects in the app flash.
+//! - `2`: Write the description of object `arg1` to the buffer. Returns the
+//!   length of the description, which is larger than the buffer if it was cut
+//!   short.
+//!
+//! Commands return `INVAL` if there is no object `arg1`, `RESERVE` if no
+//! buffer was allowed, and `NOSUPPORT` if the caller does not have
+//! permission.
+//!
+//! The description is little-endian:
+//!
+//! ```text
+//! 0          4           8           10          12     13      14     15
+//! +----------+-----------+-----------+-----------+------+-------+------+------+
+//! | offset   | size      | version   | hdr size  | kind | flags | cred | name |
+//! +----------+-----------+-----------+-----------+------+-------+------+------+
+//! | ShortID  | min. RAM  | credentials format    | name ...
+//! +----------+-----------+-----------------------+-----------
+//! ```
+//!
+//! - `offset`, `size`: where the object starts, from the start of the app
+//!   flash, and its length in bytes.
+//! - `version`, `hdr size`: the TBF version and the length of the header.
+//! - `kind`: `0` padding, `1` app, `2` invalid header.
+//! - `flags`: bit `0` the app is enabled, bit `1` it is loaded as a process,
+//!   bit `2` the process has a `ShortID`.
+//! - `cred`: `0` no credentials, `1` credentials the kernel did not verify,
+//!   `2` credentials verified by the kernel's `AppIdPolicy`.
+//! - `name`: length of the package name that ends the description.
+//! - `ShortID`: the `ShortID` of the process, if it has one.
+//! - `min. RAM`: the RAM the app asks for.
+//! - `credentials format`: the format of the credentials, if any.
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::mem;
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, ShortID};
+use kernel::{ReadWrite, ReadWriteAppSlice};
+use tock_tbf::parse::{parse_tbf_header, parse_tbf_header_lengths};
+use tock_tbf::types::{InitialTbfParseError, TbfHeader};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::InstalledApps as usize;
+
+/// Length of the description before the package name.
+const DESCRIPTION_LEN: usize = 28;
+
+const KIND_PADDING: u8 = 0;
+const KIND_APP: u8 = 1;
+const KIND_INVALID: u8 = 2;
+
+const FLAG_ENABLED: u8 = 1 << 0;
+const FLAG_LOADED: u8 = 1 << 1;
+const FLAG_SHORT_ID: u8 = 1 << 2;
+
+const CREDENTIALS_NONE: u8 = 0;
+const CREDENTIALS_UNVERIFIED: u8 = 1;
+const CREDENTIALS_VERIFIED: u8 = 2;
+
+/// A TBF object in the app flash.
+struct TbfObject {
+    offset: usize,
+    flash: &'static [u8],
+    /// The header, or `None` if it is invalid.
+    header: Option<(u16, u16, TbfHeader)>,
+}
+
+/// Walks the linked list of TBF objects in the app flash, the same way the
+/// kernel does when it loads processes.
+struct TbfObjects {
+    remaining: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for TbfObjects {
+    type Item = TbfObject;
+
+    fn next(&mut self) -> Option<TbfObject> {
+        let lengths: &'static [u8; 8] = self.remaining.get(0..8)?.try_into().ok()?;
+        let (lengths, total_size) = match parse_tbf_header_lengths(lengths) {
+            Ok((version, header_size, total_size)) => {
+                (Some((version, header_size)), total_size as usize)
+            }
+            Err(InitialTbfParseError::InvalidHeader(total_size)) => (None, total_size as usize),
+            Err(InitialTbfParseError::UnableToParse) => return None,
+        };
+        if total_size == 0 {
+            return None;
+        }
+        let flash = self.remaining.get(0..total_size)?;
+
+        let header = lengths.and_then(|(version, header_size)| {
+            flash
+                .get(0..header_size as usize)
+                .and_then(|header| parse_tbf_header(header, version).ok())
+                .map(|header| (version, header_size, header))
+        });
+        let object = TbfObject {
+            offset: self.offset,
+            flash,
+            header,
+        };
+
+        self.remaining = self.remaining.get(total_size..).unwrap_or(&[]);
+        self.offset += total_size;
+        Some(object)
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    buffer: ReadWriteAppSlice,
+}
+
+pub struct InstalledApps<C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+    app_flash: &'static [u8],
+    apps: Grant<App>,
+}
+
+impl<C: ProcessManagementCapability> InstalledApps<C> {
+    pub fn new(
+        kernel: &'static Kernel,
+        capability: C,
+        app_flash: &'static [u8],
+        grant: Grant<App>,
+    ) -> InstalledApps<C> {
+        InstalledApps {
+            kernel,
+            capability,
+            app_flash,
+            apps: grant,
+        }
+    }
+
+    fn objects(&self) -> TbfObjects {
+        TbfObjects {
+            remaining: self.app_flash,
+            offset: 0,
+        }
+    }
+
+    /// The `ShortID` of the process loaded from `object`, or `Some(None)` if
+    /// the process has none, or `None` if no process was loaded from it.
+    fn loaded_short_id(&self, object: &TbfObject) -> Option<Option<ShortID>> {
+        let short_id = Cell::new(None);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if process.flash_start() == object.flash.as_ptr() {
+                    short_id.set(Some(process.get_short_id()));
+                }
+            });
+        short_id.get()
+    }
+
+    /// Write the description of `object` to `buffer`, returning its length.
+    fn describe(&self, object: &TbfObject, buffer: &mut [u8]) -> usize {
+        let mut description = [0; DESCRIPTION_LEN];
+        description[0..4].copy_from_slice(&(object.offset as u32).to_le_bytes());
+        description[4..8].copy_from_slice(&(object.flash.len() as u32).to_le_bytes());
+
+        let mut name: &[u8] = &[];
+        match &object.header {
+            None => description[12] = KIND_INVALID,
+            Some((version, header_size, header)) => {
+                description[8..10].copy_from_slice(&version.to_le_bytes());
+                description[10..12].copy_from_slice(&header_size.to_le_bytes());
+                description[12] = if header.is_app() {
+                    KIND_APP
+                } else {
+                    KIND_PADDING
+                };
+
+                let mut flags = 0;
+                if header.enabled() {
+                    flags |= FLAG_ENABLED;
+                }
+                let loaded = self.loaded_short_id(object);
+                if loaded.is_some() {
+                    flags |= FLAG_LOADED;
+                }
+                let short_id = loaded.flatten();
+                if let Some(short_id) = short_id {
+                    flags |= FLAG_SHORT_ID;
+                    description[16..20].copy_from_slice(&short_id.id().to_le_bytes());
+                }
+                description[13] = flags;
+
+                description[14] = match header.get_credentials() {
+                    None => CREDENTIALS_NONE,
+                    Some(credentials) => {
+                        description[24..28].copy_from_slice(&credentials.format().to_le_bytes());
+                        if short_id.map_or(false, |short_id| short_id.is_signed()) {
+                            CREDENTIALS_VERIFIED
+                        } else {
+                            CREDENTIALS_UNVERIFIED
+                        }
+                    }
+                };
+
+                description[20..24]
+                    .copy_from_slice(&header.get_minimum_app_ram_size().to_le_bytes());
+                name = header.get_package_name().unwrap_or("").as_bytes();
+                name = &name[..cmp::min(name.len(), u8::MAX as usize)];
+                description[15] = name.len() as u8;
+            }
+        }
+
+        for (dst, src) in buffer.iter_mut().zip(description.iter().chain(name.iter())) {
+            *dst = *src;
+        }
+        DESCRIPTION_LEN + name.len()
+    }
+}
+
+impl<C: ProcessManagementCapability> Driver for InstalledApps<C> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer for the description of an object
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Read the installed TBF objects.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Number of TBF objects
+    /// - `2`: Describe object `arg1`
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        if cmd_num == 0 {
+            return CommandReturn::success();
+        }
+        if !self.kernel.command_permitted(appid, DRIVER_NUM, cmd_num) {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+
+        match cmd_num {
+            1 => CommandReturn::success_u32(self.objects().count() as u32),
+            2 => {
+                let object = match self.objects().nth(arg1) {
+                    Some(object) => object,
+                    None => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                let res = self
+                    .apps
+                    .enter(appid, |app| {
+                        app.buffer.mut_map_or(Err(ErrorCode::RESERVE), |buffer| {
+                            Ok(self.describe(&object, buffer))
+                        })
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                match res {
+                    Ok(length) => CommandReturn::success_u32(length as u32),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
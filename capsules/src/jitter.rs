@@ -0,0 +1,111 @@
+//! Random delays backed by a true random number generator.
+//!
+//! Implements `hil::jitter::Jitter` with an alarm, drawing the length of
+//! each delay from an `Rng`. The resolution is a tick of the alarm.
+//!
+//! An RNG has a single client, and the RNG driver and the P-256 signer
+//! usually want the TRNG too, so the jitter source takes a virtual device of
+//! a `MuxRngMaster` over it rather than the TRNG itself.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let rng_mux = static_init!(
+//!     capsules::virtual_rng::MuxRngMaster<'static>,
+//!     capsules::virtual_rng::MuxRngMaster::new(entropy_to_random)
+//! );
+//! let jitter_rng = static_init!(
+//!     capsules::virtual_rng::VirtualRngMasterDevice<'static>,
+//!     capsules::virtual_rng::VirtualRngMasterDevice::new(rng_mux)
+//! );
+//! let jitter_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let jitter = static_init!(
+//!     capsules::jitter::EntropyJitter<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::jitter::EntropyJitter::new(jitter_alarm, jitter_rng)
+//! );
+//! jitter_alarm.set_alarm_client(jitter);
+//! jitter_rng.set_client(jitter);
+//! p256.set_jitter(jitter, 2000);
+//! jitter.set_client(p256);
+//! ```
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::jitter::{Jitter, JitterClient};
+use kernel::hil::rng::{self, Rng};
+use kernel::hil::time::{self, Frequency};
+use kernel::ErrorCode;
+
+pub struct EntropyJitter<'a, A: time::Alarm<'a>> {
+    alarm: &'a A,
+    rng: &'a dyn Rng<'a>,
+    client: OptionalCell<&'a dyn JitterClient>,
+    /// The longest delay of the pending request, while one is.
+    max_us: OptionalCell<u32>,
+}
+
+impl<'a, A: time::Alarm<'a>> EntropyJitter<'a, A> {
+    pub fn new(alarm: &'a A, rng: &'a dyn Rng<'a>) -> EntropyJitter<'a, A> {
+        EntropyJitter {
+            alarm: alarm,
+            rng: rng,
+            client: OptionalCell::empty(),
+            max_us: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> Jitter<'a> for EntropyJitter<'a, A> {
+    fn set_client(&'a self, client: &'a dyn JitterClient) {
+        self.client.set(client);
+    }
+
+    fn delay(&self, max_us: u32) -> Result<(), ErrorCode> {
+        if self.max_us.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.rng.get()?;
+        self.max_us.set(max_us);
+        Ok(())
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> rng::Client for EntropyJitter<'a, A> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        let max_us = match self.max_us.extract() {
+            Some(max_us) => max_us,
+            None => return rng::Continue::Done,
+        };
+        // If the source fails, fall back to the longest delay rather than
+        // none at all
+        let random = match error {
+            Ok(()) => match randomness.next() {
+                Some(random) => random,
+                None => return rng::Continue::More,
+            },
+            Err(_) => u32::MAX,
+        };
+        let us = (random as u64 * (max_us as u64 + 1)) >> 32;
+        let ticks = us * A::Frequency::frequency() as u64 / 1_000_000;
+        self.alarm
+            .set_alarm(self.alarm.now(), A::Ticks::from(ticks as u32));
+        rng::Continue::Done
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> time::AlarmClient for EntropyJitter<'a, A> {
+    fn alarm(&self) {
+        if self.max_us.take().is_some() {
+            self.client.map(|client| client.delay_done());
+        }
+    }
+}
@@ -0,0 +1,830 @@
+//! Provides userspace with access to a key-value store, with a namespace and
+//! a storage quota for each application.
+//!
+//! The store is shared by all applications, but each application only sees
+//! its own keys. Keys are hashed together with the `ShortID` of the
+//! application, and each value is stored behind a header that records the
+//! `ShortID` it belongs to. An application that asks for a key of another
+//! application, or for a key whose hash happens to collide with one, is told
+//! the key does not exist.
+//!
+//! Each application may store up to `quota` bytes, counting the values and
+//! their headers. How much an application stores is kept in a usage record in
+//! the store, so the quota holds across reboots and restarts of the
+//! application. The record alternates between two keys and carries a
+//! sequence number: an update stores the new record before it removes the
+//! old one, so an update that fails leaves the previous record in place.
+//! An application that has values but no usage record has lost it, and its
+//! sets and deletes fail with `FAIL`. Applications that share a `ShortID`, such as two versions of
+//! one application, share a namespace and a quota. Which applications share
+//! a `ShortID` depends on the kernel's `AppIdPolicy`: with a policy based on
+//! signing keys, an application keeps its namespace across updates and no
+//! other application can take it over. Applications without a `ShortID`
+//! cannot use the store; their operations fail with `FAIL`.
+//!
+//! ```text
+//! +-----------------------+
+//! |       userspace       |
+//! +-----------------------+
+//!        kernel::Driver
+//! +-----------------------+
+//! |  KVStoreDriver (this) |
+//! +-----------------------+
+//!     hil::kv_system
+//! +-----------------------+
+//! |  capsules::tickv      |
+//! +-----------------------+
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let kv_key = static_init!([u8; 8], [0; 8]);
+//! let kv_store = static_init!(
+//!     capsules::kv_driver::KVStoreDriver<
+//!         'static,
+//!         TicKVStore<'static, FlashUser<'static, F>>,
+//!         TicKVKeyType,
+//!     >,
+//!     capsules::kv_driver::KVStoreDriver::new(
+//!         tickv,
+//!         board_kernel.create_grant(&grant_cap),
+//!         1024, // Bytes each application may store.
+//!         kv_key,
+//!         &mut capsules::kv_driver::BUFFER,
+//!     )
+//! );
+//! tickv.set_client(kv_store);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Initial
+//!
+//! ### Allow
+//!
+//! - read-only `0`: The key.
+//! - read-only `1`: The value to store.
+//! - read-write `0`: The buffer a value is read into.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Operation done. The first argument is the status. For a get, the
+//!   second argument is the length of the value, which is larger than the
+//!   buffer if the value was cut short. For a usage request, the second and
+//!   third arguments are the bytes stored and the quota.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the value of the key.
+//! - `2`: Set the key to the value, replacing any value it had.
+//! - `3`: Delete the key.
+//! - `4`: Request how many bytes the application stores.
+//!
+//! One operation runs at a time; others fail with `BUSY`. A set that would
+//! take the application over its quota fails with `NOMEM`, and a get or
+//! delete of a key that doesn't exist fails with `NOSUPPORT`. A set removes
+//! the old value of the key before it stores the new one, so a set that
+//! fails to store the new value deletes the key, and returns the error of
+//! the store.
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::math;
+use kernel::hil::kv_system::{self, KVSystem, KeyType};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Kv as usize;
+
+pub static mut BUFFER: [u8; 256] = [0; 256];
+
+/// Length of the header stored in front of each value.
+const HEADER_LEN: usize = 8;
+/// Length of the usage record, header included: the bytes stored, then the
+/// sequence number of the record.
+const USAGE_LEN: usize = HEADER_LEN + 8;
+
+/// Kinds of record, stored in the header and hashed into the key.
+const KIND_USAGE: u8 = 0;
+const KIND_VALUE: u8 = 1;
+
+/// Hash a key into the namespace of an application.
+fn hash(kind: u8, short_id: u32, key: &[u8]) -> u64 {
+    let hash = math::fnv1a_64(math::FNV1A_64_OFFSET, &[kind]);
+    let hash = math::fnv1a_64(hash, &short_id.to_le_bytes());
+    math::fnv1a_64(hash, key)
+}
+
+fn write_header(buffer: &mut [u8], kind: u8, short_id: u32, length: usize) {
+    buffer[0..4].copy_from_slice(&short_id.to_le_bytes());
+    buffer[4..6].copy_from_slice(&(length as u16).to_le_bytes());
+    buffer[6] = kind;
+    buffer[7] = 0;
+}
+
+/// Return the length of the data behind the header, if the header is of the
+/// right kind and belongs to `short_id`.
+fn read_header(buffer: &[u8], kind: u8, short_id: u32) -> Option<usize> {
+    let mut id = [0; 4];
+    id.copy_from_slice(&buffer[0..4]);
+    let length = u16::from_le_bytes([buffer[4], buffer[5]]) as usize;
+    if u32::from_le_bytes(id) == short_id
+        && buffer[6] == kind
+        && HEADER_LEN + length <= buffer.len()
+    {
+        Some(length)
+    } else {
+        None
+    }
+}
+
+/// Hash the key of usage record slot `slot` of an application.
+fn usage_hash(short_id: u32, slot: usize) -> u64 {
+    hash(KIND_USAGE, short_id, &[slot as u8])
+}
+
+/// Return the bytes stored and the sequence number from a usage record.
+fn read_usage(buffer: &[u8], short_id: u32) -> Option<(usize, u32)> {
+    read_header(buffer, KIND_USAGE, short_id)
+        .filter(|length| *length == USAGE_LEN - HEADER_LEN)
+        .map(|_| {
+            let mut used = [0; 4];
+            let mut sequence = [0; 4];
+            used.copy_from_slice(&buffer[HEADER_LEN..HEADER_LEN + 4]);
+            sequence.copy_from_slice(&buffer[HEADER_LEN + 4..USAGE_LEN]);
+            (
+                u32::from_le_bytes(used) as usize,
+                u32::from_le_bytes(sequence),
+            )
+        })
+}
+
+fn error(result: Result<(), ErrorCode>) -> ErrorCode {
+    result.err().unwrap_or(ErrorCode::FAIL)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    None,
+    Get,
+    Set,
+    Delete,
+    Usage,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    /// Reading a usage record slot of the application.
+    LoadUsage(usize),
+    /// Reading the value of the key, to return it or to learn how much of
+    /// the quota it takes.
+    ReadValue,
+    /// Invalidating the old value of the key.
+    RemoveValue,
+    /// Appending the new value of the key.
+    StoreValue,
+    /// Invalidating a record left in the free usage slot by an update that
+    /// failed to remove it.
+    RemoveStaleUsage,
+    /// Appending the updated usage record to the free slot.
+    StoreUsage,
+    /// Invalidating the old usage record, once the new one is stored.
+    RemoveUsage,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    key: ReadOnlyAppSlice,
+    value: ReadOnlyAppSlice,
+    read: ReadWriteAppSlice,
+}
+
+pub struct KVStoreDriver<'a, S: KVSystem<'a, K = T>, T: 'static + KeyType> {
+    kv: &'a S,
+    apps: Grant<App>,
+    /// Bytes each application may store.
+    quota: usize,
+    key: TakeCell<'static, T>,
+    buffer: TakeCell<'static, [u8]>,
+    current_app: OptionalCell<ProcessId>,
+    operation: Cell<Operation>,
+    step: Cell<Step>,
+    short_id: Cell<u32>,
+    key_hash: Cell<u64>,
+    /// Bytes the application stores, from its usage record.
+    used: Cell<usize>,
+    /// Sequence numbers of the records in the usage slots of the
+    /// application, `None` for an empty slot.
+    usage_slots: Cell<[Option<u32>; 2]>,
+    /// Error of a set that removed the old value but failed to store the new
+    /// one, returned once the usage record is updated.
+    set_error: OptionalCell<ErrorCode>,
+    /// Bytes the old value of the key takes, or 0 if it has none.
+    old_length: Cell<usize>,
+    /// Bytes the new value of the key takes.
+    new_length: Cell<usize>,
+}
+
+impl<'a, S: KVSystem<'a, K = T>, T: 'static + KeyType> KVStoreDriver<'a, S, T> {
+    pub fn new(
+        kv: &'a S,
+        grant: Grant<App>,
+        quota: usize,
+        key: &'static mut T,
+        buffer: &'static mut [u8],
+    ) -> KVStoreDriver<'a, S, T> {
+        KVStoreDriver {
+            kv,
+            apps: grant,
+            quota,
+            key: TakeCell::new(key),
+            buffer: TakeCell::new(buffer),
+            current_app: OptionalCell::empty(),
+            operation: Cell::new(Operation::None),
+            step: Cell::new(Step::LoadUsage(0)),
+            short_id: Cell::new(0),
+            key_hash: Cell::new(0),
+            used: Cell::new(0),
+            usage_slots: Cell::new([None, None]),
+            set_error: OptionalCell::empty(),
+            old_length: Cell::new(0),
+            new_length: Cell::new(0),
+        }
+    }
+
+    fn start(&self, appid: ProcessId, operation: Operation) -> Result<(), ErrorCode> {
+        if self.current_app.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let short_id = appid.short_id().ok_or(ErrorCode::FAIL)?.id();
+        let buffer_len = self.buffer.map_or(0, |buffer| buffer.len());
+
+        let key_hash = self
+            .apps
+            .enter(appid, |app| {
+                if operation == Operation::Usage {
+                    return Ok(0);
+                }
+                if operation == Operation::Set {
+                    let length = app.value.len();
+                    if length == 0 {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    if HEADER_LEN + length > buffer_len {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    self.new_length.set(HEADER_LEN + length);
+                }
+                app.key.map_or(Err(ErrorCode::INVAL), |key| {
+                    if key.is_empty() {
+                        Err(ErrorCode::INVAL)
+                    } else {
+                        Ok(hash(KIND_VALUE, short_id, key))
+                    }
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+
+        self.current_app.set(appid);
+        self.operation.set(operation);
+        self.short_id.set(short_id);
+        self.key_hash.set(key_hash);
+        self.old_length.set(0);
+        if operation == Operation::Delete {
+            self.new_length.set(0);
+        }
+
+        let res = if operation == Operation::Get {
+            self.read(Step::ReadValue, key_hash)
+        } else {
+            self.read(Step::LoadUsage(0), usage_hash(short_id, 0))
+        };
+        if res.is_err() {
+            self.current_app.clear();
+            self.operation.set(Operation::None);
+        }
+        res
+    }
+
+    fn set_key(&self, key: &mut T, hash: u64) {
+        for (dst, src) in key.as_mut().iter_mut().zip(hash.to_le_bytes().iter()) {
+            *dst = *src;
+        }
+    }
+
+    fn read(&self, step: Step, hash: u64) -> Result<(), ErrorCode> {
+        let key = self.key.take().ok_or(ErrorCode::RESERVE)?;
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => {
+                self.key.replace(key);
+                return Err(ErrorCode::RESERVE);
+            }
+        };
+        self.set_key(key, hash);
+        self.step.set(step);
+        self.kv.get_value(key, buffer).map_err(|(key, buffer, e)| {
+            self.key.replace(key);
+            self.buffer.replace(buffer);
+            error(e)
+        })
+    }
+
+    /// Append the first `length` bytes of the buffer.
+    fn append(&self, step: Step, hash: u64, length: usize) -> Result<(), ErrorCode> {
+        let key = self.key.take().ok_or(ErrorCode::RESERVE)?;
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => {
+                self.key.replace(key);
+                return Err(ErrorCode::RESERVE);
+            }
+        };
+        self.set_key(key, hash);
+        self.step.set(step);
+        self.kv
+            .append_key(key, buffer, length)
+            .map_err(|(key, buffer, e)| {
+                self.key.replace(key);
+                self.buffer.replace(buffer);
+                error(e)
+            })
+    }
+
+    fn remove(&self, step: Step, hash: u64) -> Result<(), ErrorCode> {
+        let key = self.key.take().ok_or(ErrorCode::RESERVE)?;
+        self.set_key(key, hash);
+        self.step.set(step);
+        self.kv.invalidate_key(key).map_err(|(key, e)| {
+            self.key.replace(key);
+            error(e)
+        })
+    }
+
+    /// Copy the value the application allowed into the buffer, behind its
+    /// header.
+    fn copy_value(&self) -> Result<(), ErrorCode> {
+        let appid = self.current_app.extract().ok_or(ErrorCode::FAIL)?;
+        let short_id = self.short_id.get();
+        let length = self.new_length.get() - HEADER_LEN;
+        self.buffer.map_or(Err(ErrorCode::RESERVE), |buffer| {
+            self.apps
+                .enter(appid, |app| {
+                    app.value.map_or(Err(ErrorCode::INVAL), |value| {
+                        // The application may have changed its buffer since
+                        // the quota was checked.
+                        if value.len() != length {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        write_header(buffer, KIND_VALUE, short_id, length);
+                        buffer[HEADER_LEN..HEADER_LEN + length].copy_from_slice(value);
+                        Ok(())
+                    })
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+
+    /// The usage slot holding the newest record, if any.
+    fn usage_slot(&self) -> Option<usize> {
+        match self.usage_slots.get() {
+            [None, None] => None,
+            [Some(_), None] => Some(0),
+            [None, Some(_)] => Some(1),
+            // Both records are left when an update fails to remove the old
+            // one. Sequence numbers wrap, so compare their difference.
+            [Some(first), Some(second)] => {
+                if (second.wrapping_sub(first) as i32) > 0 {
+                    Some(1)
+                } else {
+                    Some(0)
+                }
+            }
+        }
+    }
+
+    /// The usage slot the next record is stored in.
+    fn free_usage_slot(&self) -> usize {
+        self.usage_slot().map_or(0, |slot| 1 - slot)
+    }
+
+    /// The sequence number of the next usage record.
+    fn next_sequence(&self) -> u32 {
+        self.usage_slot()
+            .and_then(|slot| self.usage_slots.get()[slot])
+            .map_or(0, |sequence| sequence.wrapping_add(1))
+    }
+
+    /// Record the change in how much the application stores.
+    fn update_usage(&self) -> Result<(), ErrorCode> {
+        self.used
+            .set(self.used.get().saturating_sub(self.old_length.get()) + self.new_length.get());
+        let free = self.free_usage_slot();
+        if self.usage_slots.get()[free].is_some() {
+            self.remove(
+                Step::RemoveStaleUsage,
+                usage_hash(self.short_id.get(), free),
+            )
+        } else {
+            self.store_usage()
+        }
+    }
+
+    fn store_usage(&self) -> Result<(), ErrorCode> {
+        let short_id = self.short_id.get();
+        let used = self.used.get();
+        let sequence = self.next_sequence();
+        self.buffer.map_or(Err(ErrorCode::RESERVE), |buffer| {
+            write_header(buffer, KIND_USAGE, short_id, USAGE_LEN - HEADER_LEN);
+            buffer[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&(used as u32).to_le_bytes());
+            buffer[HEADER_LEN + 4..USAGE_LEN].copy_from_slice(&sequence.to_le_bytes());
+            Ok(())
+        })?;
+        self.append(
+            Step::StoreUsage,
+            usage_hash(short_id, self.free_usage_slot()),
+            USAGE_LEN,
+        )
+    }
+
+    /// Handle a set that removed the old value of the key but failed to
+    /// store the new one. The key is now deleted, so the usage record must
+    /// count it as such before the set fails with `error`.
+    fn value_not_stored(&self, error: ErrorCode) {
+        self.new_length.set(0);
+        self.set_error.set(error);
+        let res = self.update_usage();
+        if res.is_err() {
+            self.usage_updated(res);
+        }
+    }
+
+    /// Finish an operation that changed the usage record.
+    fn usage_updated(&self, result: Result<(), ErrorCode>) {
+        let result = match self.set_error.take() {
+            Some(e) => Err(e),
+            None => result,
+        };
+        self.finish(result, 0, 0);
+    }
+
+    fn usage_loaded(&self) -> Result<(), ErrorCode> {
+        match self.operation.get() {
+            Operation::Usage => {
+                self.finish(Ok(()), self.used.get(), self.quota);
+                Ok(())
+            }
+            _ => self.read(Step::ReadValue, self.key_hash.get()),
+        }
+    }
+
+    fn value_loaded(&self, result: Result<(), ErrorCode>) -> Result<(), ErrorCode> {
+        let short_id = self.short_id.get();
+        let length = match result {
+            Ok(()) => self
+                .buffer
+                .map_or(None, |buffer| read_header(buffer, KIND_VALUE, short_id)),
+            Err(ErrorCode::NOSUPPORT) => None,
+            Err(e) => return Err(e),
+        };
+
+        // Every value is counted in the usage record, so an application with
+        // a value but no record has lost it. Its usage is unknown, and
+        // starting again from 0 would let it exceed its quota.
+        if length.is_some() && self.operation.get() != Operation::Get && self.usage_slot().is_none()
+        {
+            return Err(ErrorCode::FAIL);
+        }
+
+        match self.operation.get() {
+            Operation::Get => {
+                let length = length.ok_or(ErrorCode::NOSUPPORT)?;
+                let appid = self.current_app.extract().ok_or(ErrorCode::FAIL)?;
+                self.buffer.map(|buffer| {
+                    let _ = self.apps.enter(appid, |app| {
+                        app.read.mut_map_or((), |read| {
+                            let copy = cmp::min(length, read.len());
+                            read[..copy].copy_from_slice(&buffer[HEADER_LEN..HEADER_LEN + copy]);
+                        });
+                    });
+                });
+                self.finish(Ok(()), length, 0);
+                Ok(())
+            }
+            Operation::Set => {
+                match length {
+                    Some(length) => self.old_length.set(HEADER_LEN + length),
+                    // The key is in the store but isn't the application's,
+                    // so its hash collides with a key of another
+                    // application.
+                    None if result.is_ok() => return Err(ErrorCode::FAIL),
+                    None => {}
+                }
+                let used = self.used.get().saturating_sub(self.old_length.get());
+                if used + self.new_length.get() > self.quota {
+                    return Err(ErrorCode::NOMEM);
+                }
+                self.copy_value()?;
+                if self.old_length.get() > 0 {
+                    self.remove(Step::RemoveValue, self.key_hash.get())
+                } else {
+                    self.append(Step::StoreValue, self.key_hash.get(), self.new_length.get())
+                }
+            }
+            Operation::Delete => {
+                let length = length.ok_or(ErrorCode::NOSUPPORT)?;
+                self.old_length.set(HEADER_LEN + length);
+                self.remove(Step::RemoveValue, self.key_hash.get())
+            }
+            Operation::Usage | Operation::None => Err(ErrorCode::FAIL),
+        }
+    }
+
+    fn finish(&self, result: Result<(), ErrorCode>, data0: usize, data1: usize) {
+        self.operation.set(Operation::None);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), data0, data1);
+            });
+        });
+    }
+
+    /// Finish the operation if a step failed.
+    fn check(&self, result: Result<(), ErrorCode>) {
+        if let Err(e) = result {
+            self.finish(Err(e), 0, 0);
+        }
+    }
+}
+
+impl<'a, S: KVSystem<'a, K = T>, T: 'static + KeyType> kv_system::Client<T>
+    for KVStoreDriver<'a, S, T>
+{
+    fn generate_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _unhashed_key: &'static [u8],
+        _key_buf: &'static T,
+    ) {
+        // Keys are hashed by this driver, it never generates them.
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut T,
+        value: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.buffer.replace(value);
+
+        match self.step.get() {
+            Step::StoreValue => match result {
+                Ok(()) => self.check(self.update_usage()),
+                Err(e) if self.old_length.get() > 0 => self.value_not_stored(e),
+                Err(e) => self.finish(Err(e), 0, 0),
+            },
+            Step::StoreUsage => {
+                if result.is_err() {
+                    // The old record is still in place.
+                    self.usage_updated(result);
+                    return;
+                }
+                let old = self.usage_slot();
+                let mut slots = self.usage_slots.get();
+                slots[self.free_usage_slot()] = Some(self.next_sequence());
+                self.usage_slots.set(slots);
+                match old {
+                    Some(slot) => {
+                        let res =
+                            self.remove(Step::RemoveUsage, usage_hash(self.short_id.get(), slot));
+                        if res.is_err() {
+                            // The new record is newer, the old one is
+                            // removed by the next update.
+                            self.usage_updated(Ok(()));
+                        }
+                    }
+                    None => self.usage_updated(Ok(())),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut T,
+        ret_buf: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.buffer.replace(ret_buf);
+
+        match self.step.get() {
+            Step::LoadUsage(slot) => {
+                let short_id = self.short_id.get();
+                let record = match result {
+                    Ok(()) => self
+                        .buffer
+                        .map_or(None, |buffer| read_usage(buffer, short_id))
+                        .ok_or(ErrorCode::FAIL)
+                        .map(Some),
+                    // The slot is empty.
+                    Err(ErrorCode::NOSUPPORT) => Ok(None),
+                    Err(e) => Err(e),
+                };
+                let res = record.and_then(|record| {
+                    let mut slots = self.usage_slots.get();
+                    slots[slot] = record.map(|(_, sequence)| sequence);
+                    self.usage_slots.set(slots);
+                    if slot == 0 {
+                        // Without a record, the application hasn't stored
+                        // anything yet.
+                        self.used.set(record.map_or(0, |(used, _)| used));
+                        return self.read(Step::LoadUsage(1), usage_hash(short_id, 1));
+                    }
+                    if let (Some(1), Some((used, _))) = (self.usage_slot(), record) {
+                        self.used.set(used);
+                    }
+                    self.usage_loaded()
+                });
+                self.check(res);
+            }
+            Step::ReadValue => self.check(self.value_loaded(result)),
+            _ => {}
+        }
+    }
+
+    fn invalidate_key_complete(&self, result: Result<(), ErrorCode>, key: &'static mut T) {
+        self.key.replace(key);
+
+        match self.step.get() {
+            Step::RemoveValue => match result {
+                Ok(()) if self.operation.get() == Operation::Set => {
+                    let res =
+                        self.append(Step::StoreValue, self.key_hash.get(), self.new_length.get());
+                    if let Err(e) = res {
+                        self.value_not_stored(e);
+                    }
+                }
+                _ => self.check(result.and_then(|()| self.update_usage())),
+            },
+            Step::RemoveStaleUsage => match result {
+                Ok(()) => {
+                    let mut slots = self.usage_slots.get();
+                    slots[self.free_usage_slot()] = None;
+                    self.usage_slots.set(slots);
+                    let res = self.store_usage();
+                    if res.is_err() {
+                        self.usage_updated(res);
+                    }
+                }
+                Err(e) => self.usage_updated(Err(e)),
+            },
+            Step::RemoveUsage => {
+                // The new record is stored, so the operation is done even if
+                // the old one couldn't be removed: the new record is newer,
+                // and the next update removes the old one.
+                if result.is_ok() {
+                    let mut slots = self.usage_slots.get();
+                    slots[self.free_usage_slot()] = None;
+                    self.usage_slots.set(slots);
+                }
+                self.usage_updated(Ok(()));
+            }
+            _ => {}
+        }
+    }
+
+    fn garbage_collect_complete(&self, _result: Result<(), ErrorCode>) {}
+}
+
+impl<'a, S: KVSystem<'a, K = T>, T: 'static + KeyType> Driver for KVStoreDriver<'a, S, T> {
+    /// Setup shared kernel-writable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Setup a buffer to read values into.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut slice, &mut app.read);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup shared kernel-readable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Setup the key buffer.
+    /// - `1`: Setup the buffer of the value to store.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.key);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut slice, &mut app.value);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup an operation done callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Get the value of the key.
+    /// - `2`: Set the key to the value.
+    /// - `3`: Delete the key.
+    /// - `4`: Request how many bytes the application stores, and its quota.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        let operation = match command_num {
+            0 => return CommandReturn::success(),
+            1 => Operation::Get,
+            2 => Operation::Set,
+            3 => Operation::Delete,
+            4 => Operation::Usage,
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+
+        match self.start(appid, operation) {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
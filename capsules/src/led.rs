@@ -131,6 +131,6 @@ impl<L: led::Led> Driver for LedDriver<'_, L> {
                     _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
                 }
             })
-            .expect("LEDs slice taken")
+            .unwrap_or_else(|| CommandReturn::failure(ErrorCode::FAIL))
     }
 }
@@ -15,32 +15,55 @@ pub mod analog_comparator;
 pub mod analog_sensor;
 pub mod apds9960;
 pub mod app_flash_driver;
+pub mod app_update;
+pub mod audio;
 pub mod ble_advertising_driver;
+pub mod ble_beacon;
 pub mod bus;
 pub mod button;
 pub mod buzzer_driver;
+pub mod capsense;
+pub mod cellular;
+pub mod chunked_executor;
+pub mod config_record;
 pub mod console;
+pub mod cpu_inference;
 pub mod crc;
 pub mod ctap;
+pub mod ctap2;
 pub mod dac;
 pub mod debug_process_restart;
 pub mod driver;
+pub mod ds18b20;
+pub mod energy_estimator;
 pub mod fm25cl;
 pub mod ft6x06;
 pub mod fxos8700cq;
+pub mod gdb_stub;
+pub mod geofence;
+pub mod gnss;
 pub mod gpio;
 pub mod gpio_async;
 pub mod hd44780;
+pub mod health_monitor;
+pub mod hil_contract;
 pub mod hmac;
 pub mod humidity;
 pub mod i2c_master;
 pub mod i2c_master_slave_driver;
 pub mod ieee802154;
+pub mod imu_fusion;
+pub mod inference;
+pub mod installed_apps;
 pub mod isl29035;
+pub mod jitter;
+pub mod kv_driver;
 pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
 pub mod log;
+pub mod log_driver;
+pub mod lora_region;
 pub mod low_level_debug;
 pub mod lps25hb;
 pub mod lsm303agr;
@@ -49,44 +72,67 @@ pub mod lsm303xx;
 pub mod ltc294x;
 pub mod max17205;
 pub mod mcp230xx;
+pub mod measured_boot;
 pub mod mlx90614;
+pub mod modbus_rtu;
 pub mod mx25r6435f;
+pub mod network_time;
 pub mod ninedof;
 pub mod nonvolatile_storage_driver;
 pub mod nonvolatile_to_pages;
 pub mod nrf51822_serialization;
+pub mod odometer;
+pub mod onewire_gpio;
+pub mod p256;
 pub mod panic_button;
 pub mod pca9544a;
 pub mod process_console;
+pub mod process_events;
+pub mod process_manager;
 pub mod proximity;
+pub mod qdec;
+pub mod radio_limits;
 pub mod rf233;
 pub mod rf233_const;
 pub mod rng;
+pub mod sampled_trigger;
 pub mod screen;
 pub mod sdcard;
+pub mod secure_boot;
 pub mod segger_rtt;
+pub mod self_test;
+pub mod sensor_trigger;
+pub mod servo;
+pub mod sha256;
 pub mod sht3x;
 pub mod si7021;
 pub mod sound_pressure;
 pub mod spi_controller;
 pub mod spi_peripheral;
 pub mod st77xx;
+pub mod sx126x;
 pub mod temperature;
 pub mod temperature_stm;
 pub mod text_screen;
 pub mod tickv;
+pub mod totp;
 pub mod touch;
 pub mod tsl2561;
 pub mod usb;
 pub mod virtual_adc;
 pub mod virtual_aes_ccm;
 pub mod virtual_alarm;
+pub mod virtual_ble;
 pub mod virtual_digest;
 pub mod virtual_flash;
+pub mod virtual_gnss;
 pub mod virtual_hmac;
 pub mod virtual_i2c;
 pub mod virtual_pwm;
+pub mod virtual_rf_switch;
 pub mod virtual_rng;
 pub mod virtual_spi;
 pub mod virtual_timer;
 pub mod virtual_uart;
+pub mod wall_clock_alarm;
+pub mod wear_leveling_flash;
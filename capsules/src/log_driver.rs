@@ -0,0 +1,651 @@
+//! Provides userspace with access to a persistent log.
+//!
+//! Processes append entries to a log in flash and read them back, so data
+//! such as sensor samples survives reboots. The log is `capsules::log::Log`,
+//! which writes pages in order and wraps around once the flash region is
+//! full, so pages wear evenly and the oldest entries are dropped first.
+//!
+//! All processes share the log and its read position. The log runs one
+//! operation at a time; operations started while another is in progress fail
+//! with `BUSY`.
+//!
+//! The log can also be dumped over a UART, either by a process or from the
+//! process console's `log` command. The dump is framed by markers so host
+//! tools (see `tools/tock_log_extract.py`) can pick it out of the rest of the
+//! console output, including when it is captured with `tockloader listen`:
+//!
+//! ```text
+//! TOCKLOG BEGIN <log start> <log end>
+//! TOCKLOG <entry id> <entry data>
+//! ...
+//! TOCKLOG END <number of entries>
+//! ```
+//!
+//! Entry IDs and data are in hexadecimal, the number of entries in decimal.
+//! If reading the log fails part way, the last line is `TOCKLOG ERROR
+//! <number of entries>` instead. The read position is restored after a dump.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let log_driver = static_init!(
+//!     capsules::log_driver::LogDriver<'static, Log>,
+//!     capsules::log_driver::LogDriver::new(
+//!         log,
+//!         &mut capsules::log_driver::BUFFER,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! log.set_read_client(log_driver);
+//! log.set_append_client(log_driver);
+//!
+//! let log_uart = static_init!(UartDevice, UartDevice::new(uart_mux, true));
+//! log_uart.setup();
+//! log_driver.set_uart(log_uart, &mut capsules::log_driver::DUMP_BUFFER);
+//! hil::uart::Transmit::set_transmit_client(log_uart, log_driver);
+//! process_console.set_log_dump(log_driver);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: entry to append.
+//! - Read-write `0`: buffer entries are read into.
+//!
+//! ### Subscribe
+//!
+//! - `0`: append done. The callback gets the status, the length of the entry
+//!   and whether old entries were overwritten to make room for it.
+//! - `1`: read done. The callback gets the status and the length of the
+//!   entry.
+//! - `2`: seek done. The callback gets the status.
+//! - `3`: sync done. The callback gets the status.
+//! - `4`: erase done. The callback gets the status.
+//! - `5`: dump done. The callback gets the status and the number of entries
+//!   dumped.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the approximate capacity of the log in bytes.
+//! - `1`: Append the first `arg1` bytes of the read-only buffer as an entry.
+//! - `2`: Read the next entry into the read-write buffer.
+//! - `3`: Seek to entry `arg1`, which must be an ID returned by command `6`.
+//! - `4`: Sync the log to flash.
+//! - `5`: Erase the log.
+//! - `6`: Returns the ID of the oldest entry, the ID the next entry will be
+//!   appended at, and the ID of the next entry to read.
+//! - `7`: Dump the log over the UART.
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::hil::uart;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Log as usize;
+
+/// Buffer entries are copied through. Its length limits the size of entries.
+pub static mut BUFFER: [u8; 256] = [0; 256];
+
+/// Buffer dump lines are formatted in. Fits an entry of `BUFFER` in hex.
+pub static mut DUMP_BUFFER: [u8; 544] = [0; 544];
+
+const UPCALL_APPEND: usize = 0;
+const UPCALL_READ: usize = 1;
+const UPCALL_SEEK: usize = 2;
+const UPCALL_SYNC: usize = 3;
+const UPCALL_ERASE: usize = 4;
+const UPCALL_DUMP: usize = 5;
+const NUM_UPCALLS: usize = 6;
+
+/// Implemented by logs that can be dumped for host tools to extract.
+pub trait LogDump {
+    /// Start writing the entries of the log out.
+    fn dump(&self) -> Result<(), ErrorCode>;
+}
+
+#[derive(Default)]
+pub struct App {
+    callbacks: [Upcall; NUM_UPCALLS],
+    append_buffer: ReadOnlyAppSlice,
+    read_buffer: ReadWriteAppSlice,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    Append,
+    Read,
+    Seek,
+    Sync,
+    Erase,
+    Dump(DumpState),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DumpState {
+    /// Seeking to the start of the log.
+    Start,
+    /// Sending the `BEGIN` line or an entry.
+    Entry,
+    /// Sending the `END` or `ERROR` line.
+    End,
+    /// Seeking back to the read position from before the dump.
+    Restore,
+}
+
+/// Who started the operation in progress.
+#[derive(Clone, Copy)]
+enum User {
+    App(ProcessId),
+    Kernel,
+}
+
+pub struct LogDriver<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> {
+    log: &'a L,
+    apps: Grant<App>,
+    buffer: TakeCell<'static, [u8]>,
+    operation: Cell<Operation>,
+    user: OptionalCell<User>,
+
+    uart: OptionalCell<&'a dyn uart::Transmit<'a>>,
+    dump_buffer: TakeCell<'static, [u8]>,
+    /// Read position to restore once the dump is done.
+    dump_read_position: Cell<usize>,
+    /// ID of the entry being read for the dump.
+    dump_entry_id: Cell<usize>,
+    dump_entries: Cell<usize>,
+    dump_result: Cell<Result<(), ErrorCode>>,
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogDriver<'a, L> {
+    pub fn new(log: &'a L, buffer: &'static mut [u8], grant: Grant<App>) -> LogDriver<'a, L> {
+        LogDriver {
+            log: log,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            operation: Cell::new(Operation::Idle),
+            user: OptionalCell::empty(),
+            uart: OptionalCell::empty(),
+            dump_buffer: TakeCell::empty(),
+            dump_read_position: Cell::new(0),
+            dump_entry_id: Cell::new(0),
+            dump_entries: Cell::new(0),
+            dump_result: Cell::new(Ok(())),
+        }
+    }
+
+    /// Set the UART the log is dumped over.
+    pub fn set_uart(&self, uart: &'a dyn uart::Transmit<'a>, dump_buffer: &'static mut [u8]) {
+        self.uart.set(uart);
+        self.dump_buffer.replace(dump_buffer);
+    }
+
+    fn start(&self, appid: ProcessId, command: usize, arg1: usize) -> Result<(), ErrorCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let operation = match command {
+            1 => {
+                self.append(appid, arg1)?;
+                Operation::Append
+            }
+            2 => {
+                self.read(appid)?;
+                Operation::Read
+            }
+            3 => {
+                self.log.seek(arg1)?;
+                Operation::Seek
+            }
+            4 => {
+                self.log.sync()?;
+                Operation::Sync
+            }
+            5 => {
+                self.log.erase()?;
+                Operation::Erase
+            }
+            7 => {
+                self.start_dump()?;
+                Operation::Dump(DumpState::Start)
+            }
+            _ => return Err(ErrorCode::NOSUPPORT),
+        };
+        self.operation.set(operation);
+        self.user.set(User::App(appid));
+        Ok(())
+    }
+
+    fn append(&self, appid: ProcessId, len: usize) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::NOMEM)?;
+        let len = self
+            .apps
+            .enter(appid, |app| {
+                app.append_buffer.map_or(0, |data| {
+                    let len = cmp::min(len, cmp::min(data.len(), buffer.len()));
+                    buffer[..len].copy_from_slice(&data[..len]);
+                    len
+                })
+            })
+            .unwrap_or(0);
+        if len == 0 {
+            self.buffer.replace(buffer);
+            return Err(ErrorCode::INVAL);
+        }
+        self.log.append(buffer, len).map_err(|(e, buffer)| {
+            self.buffer.replace(buffer);
+            e
+        })
+    }
+
+    fn read(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::NOMEM)?;
+        let len = self
+            .apps
+            .enter(appid, |app| app.read_buffer.len())
+            .unwrap_or(0);
+        let len = cmp::min(len, buffer.len());
+        if len == 0 {
+            self.buffer.replace(buffer);
+            return Err(ErrorCode::RESERVE);
+        }
+        self.log.read(buffer, len).map_err(|(e, buffer)| {
+            self.buffer.replace(buffer);
+            e
+        })
+    }
+
+    fn start_dump(&self) -> Result<(), ErrorCode> {
+        if self.uart.is_none() || self.dump_buffer.is_none() {
+            return Err(ErrorCode::NODEVICE);
+        }
+        self.dump_read_position.set(self.log.next_read_entry_id());
+        self.dump_entries.set(0);
+        self.dump_result.set(Ok(()));
+        self.log.seek(self.log.log_start())
+    }
+
+    /// Send the line `format` writes to the dump buffer.
+    fn send_line<F: FnOnce(&mut LineWriter)>(&self, format: F) -> Result<(), ErrorCode> {
+        let buffer = self.dump_buffer.take().ok_or(ErrorCode::NOMEM)?;
+        let mut line = LineWriter {
+            buffer: buffer,
+            len: 0,
+        };
+        line.push_str("TOCKLOG ");
+        format(&mut line);
+        line.push_str("\r\n");
+        let LineWriter { buffer, len } = line;
+        self.uart.map_or(Err(ErrorCode::NODEVICE), move |uart| {
+            uart.transmit_buffer(buffer, len).map_err(|(e, buffer)| {
+                self.dump_buffer.replace(buffer);
+                e
+            })
+        })
+    }
+
+    /// Read the next entry to dump, or end the dump once there are none.
+    fn dump_next(&self) {
+        self.dump_entry_id.set(self.log.next_read_entry_id());
+        let result = self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
+            let len = buffer.len();
+            self.log.read(buffer, len).map_err(|(e, buffer)| {
+                self.buffer.replace(buffer);
+                e
+            })
+        });
+        match result {
+            Ok(()) => {}
+            // The end of the log.
+            Err(ErrorCode::FAIL) => self.dump_end(Ok(())),
+            Err(e) => self.dump_end(Err(e)),
+        }
+    }
+
+    fn dump_end(&self, result: Result<(), ErrorCode>) {
+        self.dump_result.set(result);
+        self.operation.set(Operation::Dump(DumpState::End));
+        let entries = self.dump_entries.get();
+        let sent = self.send_line(|line| {
+            line.push_str(if result.is_ok() { "END " } else { "ERROR " });
+            line.push_decimal(entries);
+        });
+        if sent.is_err() {
+            self.dump_restore();
+        }
+    }
+
+    fn dump_restore(&self) {
+        self.operation.set(Operation::Dump(DumpState::Restore));
+        // The entry may have been overwritten since.
+        if self.log.seek(self.dump_read_position.get()).is_err() {
+            self.done(
+                UPCALL_DUMP,
+                self.dump_result.get(),
+                self.dump_entries.get(),
+                0,
+            );
+        }
+    }
+
+    /// Finish the operation in progress and notify whoever started it.
+    fn done(&self, upcall: usize, result: Result<(), ErrorCode>, arg1: usize, arg2: usize) {
+        self.operation.set(Operation::Idle);
+        if let Some(User::App(appid)) = self.user.take() {
+            let _ = self.apps.enter(appid, |app| {
+                app.callbacks[upcall].schedule(kernel::into_statuscode(result), arg1, arg2);
+            });
+        }
+    }
+}
+
+/// Formats a dump line into a buffer, dropping what does not fit.
+struct LineWriter {
+    buffer: &'static mut [u8],
+    len: usize,
+}
+
+impl LineWriter {
+    fn push(&mut self, byte: u8) {
+        if self.len < self.buffer.len() {
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex(&mut self, value: usize) {
+        for shift in (0..8).rev() {
+            self.push(b"0123456789abcdef"[(value >> (shift * 4)) & 0xf]);
+        }
+    }
+
+    fn push_decimal(&mut self, value: usize) {
+        let mut digits = [0; 20];
+        let mut count = 0;
+        let mut value = value;
+        loop {
+            digits[count] = b'0' + (value % 10) as u8;
+            count += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        for digit in digits[..count].iter().rev() {
+            self.push(*digit);
+        }
+    }
+
+    fn push_bytes_hex(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.push(b"0123456789abcdef"[(byte >> 4) as usize]);
+            self.push(b"0123456789abcdef"[(byte & 0xf) as usize]);
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogDump for LogDriver<'a, L> {
+    fn dump(&self) -> Result<(), ErrorCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.start_dump()?;
+        self.operation.set(Operation::Dump(DumpState::Start));
+        self.user.set(User::Kernel);
+        Ok(())
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogReadClient for LogDriver<'a, L> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: Result<(), ErrorCode>) {
+        match self.operation.get() {
+            Operation::Read => {
+                let result = error.and_then(|()| match self.user.extract() {
+                    Some(User::App(appid)) => self
+                        .apps
+                        .enter(appid, |app| {
+                            app.read_buffer.mut_map_or(Err(ErrorCode::RESERVE), |data| {
+                                // The app may have allowed a smaller buffer
+                                // since the read started.
+                                let length = cmp::min(length, data.len());
+                                data[..length].copy_from_slice(&buffer[..length]);
+                                Ok(())
+                            })
+                        })
+                        .unwrap_or_else(|err| Err(err.into())),
+                    _ => Err(ErrorCode::FAIL),
+                });
+                self.buffer.replace(buffer);
+                self.done(UPCALL_READ, result, length, 0);
+            }
+            Operation::Dump(DumpState::Entry) => {
+                if let Err(e) = error {
+                    self.buffer.replace(buffer);
+                    self.dump_end(Err(e));
+                    return;
+                }
+                let id = self.dump_entry_id.get();
+                let sent = self.send_line(|line| {
+                    line.push_hex(id);
+                    line.push(b' ');
+                    line.push_bytes_hex(&buffer[..length]);
+                });
+                self.buffer.replace(buffer);
+                match sent {
+                    Ok(()) => self.dump_entries.set(self.dump_entries.get() + 1),
+                    Err(e) => self.dump_end(Err(e)),
+                }
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn seek_done(&self, error: Result<(), ErrorCode>) {
+        match self.operation.get() {
+            Operation::Seek => self.done(UPCALL_SEEK, error, 0, 0),
+            Operation::Dump(DumpState::Start) => {
+                let result = error.and_then(|()| {
+                    self.operation.set(Operation::Dump(DumpState::Entry));
+                    let (start, end) = (self.log.log_start(), self.log.log_end());
+                    self.send_line(|line| {
+                        line.push_str("BEGIN ");
+                        line.push_hex(start);
+                        line.push(b' ');
+                        line.push_hex(end);
+                    })
+                });
+                if let Err(e) = result {
+                    self.dump_result.set(Err(e));
+                    self.dump_restore();
+                }
+            }
+            Operation::Dump(DumpState::Restore) => self.done(
+                UPCALL_DUMP,
+                self.dump_result.get(),
+                self.dump_entries.get(),
+                0,
+            ),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogWriteClient for LogDriver<'a, L> {
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+        if self.operation.get() == Operation::Append {
+            self.done(UPCALL_APPEND, error, length, records_lost as usize);
+        }
+    }
+
+    fn sync_done(&self, error: Result<(), ErrorCode>) {
+        if self.operation.get() == Operation::Sync {
+            self.done(UPCALL_SYNC, error, 0, 0);
+        }
+    }
+
+    fn erase_done(&self, error: Result<(), ErrorCode>) {
+        if self.operation.get() == Operation::Erase {
+            self.done(UPCALL_ERASE, error, 0, 0);
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> uart::TransmitClient for LogDriver<'a, L> {
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        _rcode: Result<(), ErrorCode>,
+    ) {
+        self.dump_buffer.replace(buffer);
+        match self.operation.get() {
+            Operation::Dump(DumpState::Entry) => self.dump_next(),
+            Operation::Dump(DumpState::End) => self.dump_restore(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> Driver for LogDriver<'a, L> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Entry to append
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.append_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer to read entries into
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.read_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Append done callback
+    /// - `1`: Read done callback
+    /// - `2`: Seek done callback
+    /// - `3`: Sync done callback
+    /// - `4`: Erase done callback
+    /// - `5`: Dump done callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = if subscribe_num < NUM_UPCALLS {
+            self.apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callbacks[subscribe_num], &mut callback);
+                })
+                .map_err(ErrorCode::from)
+        } else {
+            Err(ErrorCode::NOSUPPORT)
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the capacity of the log.
+    /// - `1`: Append an entry
+    /// - `2`: Read the next entry
+    /// - `3`: Seek to an entry
+    /// - `4`: Sync the log
+    /// - `5`: Erase the log
+    /// - `6`: Get the log start, end and read position
+    /// - `7`: Dump the log over the UART
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success_u32(self.log.get_size() as u32),
+            1..=5 | 7 => match self.start(appid, cmd_num, arg1) {
+                Ok(()) => CommandReturn::success(),
+                Err(e) => CommandReturn::failure(e),
+            },
+            6 => CommandReturn::success_u32_u32_u32(
+                self.log.log_start() as u32,
+                self.log.log_end() as u32,
+                self.log.next_read_entry_id() as u32,
+            ),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
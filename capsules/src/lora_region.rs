@@ -0,0 +1,372 @@
+//! LoRaWAN regional parameters and enforcement of their regulatory limits.
+//!
+//! Holds the channel plans of the EU868, US915 and AU915 regions, and a
+//! limiter that a LoRa radio driver checks before each uplink. The limiter
+//! rejects transmissions outside the region's bands, longer than its dwell
+//! time, or in a sub-band whose duty cycle has been used up, so processes
+//! cannot make the board break the rules of the region it is in, however
+//! they use the radio.
+//!
+//! In EU868 each sub-band has a duty cycle, from 0.1% to 10%. After
+//! transmitting for `t`, a sub-band with duty cycle `d` stays closed for
+//! `t * (1 / d - 1)`. US915 and AU915 have no duty cycle, but limit each
+//! uplink to 400 ms on air.
+//!
+//! The region of a board is best kept in its config record (see
+//! `capsules::config_record`), under the `CONFIG_TAG_REGION` tag, so that
+//! one image works in every region.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let region = capsules::config_record::read_record(config_slots)
+//!     .and_then(capsules::lora_region::Region::from_config)
+//!     .unwrap_or(capsules::lora_region::Region::Eu868);
+//! let limiter = static_init!(
+//!     capsules::lora_region::DutyCycleLimiter,
+//!     capsules::lora_region::DutyCycleLimiter::new(region)
+//! );
+//! ```
+//!
+//! and in the radio driver, with `now_ms` a millisecond clock:
+//!
+//! ```rust
+//! let airtime_us = lora_region::time_on_air_us(sf, bandwidth_hz, 1, 8, len, true, true);
+//! limiter.check(frequency_hz, bandwidth_hz, airtime_us, now_ms)?;
+//! // Transmit, then:
+//! limiter.record(frequency_hz, bandwidth_hz, airtime_us, now_ms);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::ErrorCode;
+
+use crate::config_record;
+
+/// Tag of the region ID in a config record.
+pub const CONFIG_TAG_REGION: u8 = 0x01;
+
+/// Most sub-bands of any region.
+const MAX_BANDS: usize = 6;
+
+/// Longest uplink the limiter allows in any region. It also bounds the time a
+/// sub-band can stay closed.
+const MAX_AIRTIME_US: u32 = 10_000_000;
+
+/// A part of the spectrum with a common duty cycle.
+struct Band {
+    start_hz: u32,
+    end_hz: u32,
+    /// The duty cycle is one over this.
+    duty_cycle_divisor: u32,
+}
+
+const EU868_BANDS: [Band; MAX_BANDS] = [
+    Band {
+        start_hz: 863_000_000,
+        end_hz: 865_000_000,
+        duty_cycle_divisor: 1000,
+    },
+    Band {
+        start_hz: 865_000_000,
+        end_hz: 868_000_000,
+        duty_cycle_divisor: 100,
+    },
+    Band {
+        start_hz: 868_000_000,
+        end_hz: 868_600_000,
+        duty_cycle_divisor: 100,
+    },
+    Band {
+        start_hz: 868_700_000,
+        end_hz: 869_200_000,
+        duty_cycle_divisor: 1000,
+    },
+    Band {
+        start_hz: 869_400_000,
+        end_hz: 869_650_000,
+        duty_cycle_divisor: 10,
+    },
+    Band {
+        start_hz: 869_700_000,
+        end_hz: 870_000_000,
+        duty_cycle_divisor: 100,
+    },
+];
+
+const US915_BANDS: [Band; 1] = [Band {
+    start_hz: 902_000_000,
+    end_hz: 928_000_000,
+    duty_cycle_divisor: 1,
+}];
+
+const AU915_BANDS: [Band; 1] = [Band {
+    start_hz: 915_000_000,
+    end_hz: 928_000_000,
+    duty_cycle_divisor: 1,
+}];
+
+/// A radio channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Channel {
+    pub frequency_hz: u32,
+    pub bandwidth_hz: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Eu868,
+    Us915,
+    Au915,
+}
+
+impl Region {
+    /// The region with ID `id`, as stored in config records.
+    pub fn from_id(id: u8) -> Option<Region> {
+        match id {
+            0 => Some(Region::Eu868),
+            1 => Some(Region::Us915),
+            2 => Some(Region::Au915),
+            _ => None,
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            Region::Eu868 => 0,
+            Region::Us915 => 1,
+            Region::Au915 => 2,
+        }
+    }
+
+    /// The region stored in a config record.
+    pub fn from_config(record: &[u8]) -> Option<Region> {
+        config_record::find_item(record, CONFIG_TAG_REGION)
+            .and_then(|value| value.first())
+            .and_then(|&id| Region::from_id(id))
+    }
+
+    fn bands(self) -> &'static [Band] {
+        match self {
+            Region::Eu868 => &EU868_BANDS,
+            Region::Us915 => &US915_BANDS,
+            Region::Au915 => &AU915_BANDS,
+        }
+    }
+
+    /// Index of the band that holds all of a channel.
+    fn band(self, frequency_hz: u32, bandwidth_hz: u32) -> Option<usize> {
+        let low = frequency_hz.checked_sub(bandwidth_hz / 2)?;
+        let high = frequency_hz.checked_add(bandwidth_hz / 2)?;
+        self.bands()
+            .iter()
+            .position(|band| band.start_hz <= low && high <= band.end_hz)
+    }
+
+    /// Whether `channel` lies within the bands of the region.
+    pub fn allows(self, channel: Channel) -> bool {
+        self.band(channel.frequency_hz, channel.bandwidth_hz)
+            .is_some()
+    }
+
+    /// Number of uplink channels every device of the region has. Networks
+    /// can add channels in EU868.
+    pub fn uplink_channels(self) -> usize {
+        match self {
+            Region::Eu868 => 3,
+            Region::Us915 | Region::Au915 => 72,
+        }
+    }
+
+    /// Uplink channel `index`. US915 and AU915 have 64 125 kHz channels,
+    /// followed by 8 500 kHz channels.
+    pub fn uplink_channel(self, index: usize) -> Option<Channel> {
+        let (first_hz, first_wide_hz) = match self {
+            Region::Eu868 => {
+                return if index < 3 {
+                    Some(Channel {
+                        frequency_hz: 868_100_000 + 200_000 * index as u32,
+                        bandwidth_hz: 125_000,
+                    })
+                } else {
+                    None
+                };
+            }
+            Region::Us915 => (902_300_000, 903_000_000),
+            Region::Au915 => (915_200_000, 915_900_000),
+        };
+        match index {
+            0..=63 => Some(Channel {
+                frequency_hz: first_hz + 200_000 * index as u32,
+                bandwidth_hz: 125_000,
+            }),
+            64..=71 => Some(Channel {
+                frequency_hz: first_wide_hz + 1_600_000 * (index as u32 - 64),
+                bandwidth_hz: 500_000,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Downlink channel `index`. In EU868, downlinks in the first receive
+    /// window use the uplink channel.
+    pub fn downlink_channel(self, index: usize) -> Option<Channel> {
+        match self {
+            Region::Eu868 => self.uplink_channel(index),
+            Region::Us915 | Region::Au915 if index < 8 => Some(Channel {
+                frequency_hz: 923_300_000 + 600_000 * index as u32,
+                bandwidth_hz: 500_000,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Channel of the second receive window.
+    pub fn rx2_channel(self) -> Channel {
+        match self {
+            Region::Eu868 => Channel {
+                frequency_hz: 869_525_000,
+                bandwidth_hz: 125_000,
+            },
+            Region::Us915 | Region::Au915 => Channel {
+                frequency_hz: 923_300_000,
+                bandwidth_hz: 500_000,
+            },
+        }
+    }
+
+    /// The highest EIRP devices of the region may transmit at.
+    pub fn max_eirp_dbm(self) -> i8 {
+        match self {
+            Region::Eu868 => 16,
+            Region::Us915 | Region::Au915 => 30,
+        }
+    }
+
+    /// The longest a single uplink may be on air, if the region limits it.
+    pub fn dwell_time_us(self) -> Option<u32> {
+        match self {
+            Region::Eu868 => None,
+            Region::Us915 | Region::Au915 => Some(400_000),
+        }
+    }
+}
+
+/// The time a LoRa packet of `payload_len` bytes is on air, in microseconds.
+///
+/// `coding_rate` is 1 to 4, for 4/5 to 4/8. LoRaWAN uses a coding rate of
+/// 4/5, a preamble of 8 symbols, and an explicit header, with a CRC on
+/// uplinks only.
+pub fn time_on_air_us(
+    spreading_factor: u8,
+    bandwidth_hz: u32,
+    coding_rate: u8,
+    preamble_len: u16,
+    payload_len: usize,
+    explicit_header: bool,
+    crc: bool,
+) -> u32 {
+    if bandwidth_hz == 0 || !(6..=12).contains(&spreading_factor) {
+        return u32::MAX;
+    }
+    let sf = spreading_factor as i64;
+    // The radio optimizes for low data rates when a symbol takes 16 ms or
+    // more.
+    let symbol_us = (1u64 << sf) * 1_000_000 / bandwidth_hz as u64;
+    let low_data_rate = if symbol_us >= 16_000 { 1 } else { 0 };
+
+    let bits = 8 * payload_len as i64 - 4 * sf + 28 + if crc { 16 } else { 0 }
+        - if explicit_header { 0 } else { 20 };
+    let bits_per_block = 4 * (sf - 2 * low_data_rate);
+    let blocks = if bits > 0 {
+        (bits + bits_per_block - 1) / bits_per_block
+    } else {
+        0
+    };
+    let payload_symbols = 8 + blocks * (coding_rate as i64 + 4);
+
+    // The preamble is 4.25 symbols longer than configured, so count in
+    // quarter symbols.
+    let quarter_symbols = 4 * preamble_len as u64 + 17 + 4 * payload_symbols as u64;
+    let us = quarter_symbols * (1u64 << sf) * 1_000_000 / (4 * bandwidth_hz as u64);
+    if us > u32::MAX as u64 {
+        u32::MAX
+    } else {
+        us as u32
+    }
+}
+
+/// Keeps uplinks within the limits of a region.
+pub struct DutyCycleLimiter {
+    region: Cell<Region>,
+    /// Time, in milliseconds, from which each sub-band may be used again.
+    open_at_ms: [Cell<u64>; MAX_BANDS],
+}
+
+impl DutyCycleLimiter {
+    pub fn new(region: Region) -> DutyCycleLimiter {
+        DutyCycleLimiter {
+            region: Cell::new(region),
+            open_at_ms: Default::default(),
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region.get()
+    }
+
+    /// Change the region. The duty cycles used so far are forgotten.
+    pub fn set_region(&self, region: Region) {
+        self.region.set(region);
+        for open_at in self.open_at_ms.iter() {
+            open_at.set(0);
+        }
+    }
+
+    /// Check that an uplink of `airtime_us` on a channel is allowed at
+    /// `now_ms`. Returns `INVAL` if the channel is outside the region's
+    /// bands, `SIZE` if the uplink is longer than the region's dwell time,
+    /// and `BUSY` while the duty cycle of the channel's sub-band is used up.
+    pub fn check(
+        &self,
+        frequency_hz: u32,
+        bandwidth_hz: u32,
+        airtime_us: u32,
+        now_ms: u64,
+    ) -> Result<(), ErrorCode> {
+        let region = self.region.get();
+        let band = region
+            .band(frequency_hz, bandwidth_hz)
+            .ok_or(ErrorCode::INVAL)?;
+        let dwell_time_us = region.dwell_time_us().unwrap_or(MAX_AIRTIME_US);
+        if airtime_us > dwell_time_us {
+            return Err(ErrorCode::SIZE);
+        }
+        if now_ms < self.open_at_ms[band].get() {
+            return Err(ErrorCode::BUSY);
+        }
+        Ok(())
+    }
+
+    /// The milliseconds from `now_ms` until a channel's sub-band may be used
+    /// again, or `None` if the channel is outside the region's bands.
+    pub fn wait_ms(&self, frequency_hz: u32, bandwidth_hz: u32, now_ms: u64) -> Option<u64> {
+        self.region
+            .get()
+            .band(frequency_hz, bandwidth_hz)
+            .map(|band| self.open_at_ms[band].get().saturating_sub(now_ms))
+    }
+
+    /// Account for an uplink of `airtime_us` that started at `now_ms`.
+    pub fn record(&self, frequency_hz: u32, bandwidth_hz: u32, airtime_us: u32, now_ms: u64) {
+        let region = self.region.get();
+        if let Some(band) = region.band(frequency_hz, bandwidth_hz) {
+            let divisor = region.bands()[band].duty_cycle_divisor as u64;
+            let airtime_ms = (airtime_us as u64 + 999) / 1000;
+            self.open_at_ms[band].set(now_ms + airtime_ms * divisor);
+        }
+    }
+}
@@ -0,0 +1,973 @@
+//! Measures the kernel and the apps at boot, and signs quotes of the
+//! measurements for remote attestation.
+//!
+//! When the board calls `start()`, after it has loaded the processes, the
+//! capsule hashes the kernel image and every TBF object in the app flash,
+//! headers included, with a SHA-256 `hil::digest::Digest`. Apps that were not
+//! loaded, such as those refused by the credentials policy, are measured too,
+//! as they are still code on the device. Each hash, a measurement, extends a
+//! platform configuration register (PCR) held in RAM, the way a TPM does:
+//!
+//! ```text
+//! PCR = SHA-256(PCR || measurement)
+//! ```
+//!
+//! PCRs start as zeros. PCR `0` holds the measurement of the kernel and PCR
+//! `1` those of the apps, in the order they lie in flash. PCRs can only
+//! be extended, and only by the capsule, so their values depend on
+//! everything that was measured and in which order.
+//!
+//! The capsule also keeps a log of the measurements: the kernel first, then
+//! each app with the `ShortID` of its process, or `0` if it was not loaded. A
+//! verifier replays the log to check it
+//! against the PCRs, and then checks each measurement against the images it
+//! trusts. Apps past the capacity of the log still extend PCR `1`, so a log
+//! that was cut short does not replay and the verifier rejects it.
+//!
+//! A quote is a signature, made with the attestation key in the
+//! `hil::signature::SignatureSign` slot the board chooses, of
+//!
+//! ```text
+//! SHA-256(nonce || PCR 0 || PCR 1)
+//! ```
+//!
+//! where the 32 byte nonce comes from the verifier, so that an old quote
+//! cannot be replayed. Once the images are measured, the capsule reads the
+//! public attestation key back from the region of nonvolatile storage the
+//! board gives it with `set_storage()`. If there is none, it generates the
+//! key in the slot with `hil::signature::KeyGenerate` and saves its public
+//! key there. Processes can read the public key, so that it can be recorded
+//! when the device is provisioned. The signer must keep its private keys
+//! across resets too, as `P256Software` does with storage, or the key would
+//! change at every boot. Without storage, a new key is generated at every
+//! boot.
+//!
+//! ```text
+//! +-----------------------+
+//! |       userspace       |
+//! +-----------------------+
+//!        kernel::Driver
+//! +-----------------------+
+//! |  MeasuredBoot (this)  |
+//! +-----------------------+
+//!   hil::digest  hil::signature  hil::nonvolatile_storage
+//! ```
+//!
+//! The digest may be shared through a `VirtualMuxDigest`; the capsule clears
+//! it after each hash to release it. The board has to give the capsule the
+//! `ProcessManagementCapability` to find the flash of the processes.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let measured_boot_digest = static_init!([u8; 32], [0; 32]);
+//! let measured_boot_signature = static_init!([u8; 64], [0; 64]);
+//! let measured_boot_public_key = static_init!([u8; 64], [0; 64]);
+//! let measured_boot = static_init!(
+//!     capsules::measured_boot::MeasuredBoot<
+//!         'static,
+//!         capsules::sha256::Sha256Software<'static>,
+//!         capsules::p256::P256Software<'static>,
+//!         ProcessMgmtCap,
+//!     >,
+//!     capsules::measured_boot::MeasuredBoot::new(
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         sha,
+//!         p256,
+//!         ATTESTATION_KEY_SLOT,
+//!         core::slice::from_raw_parts(
+//!             &_stext as *const u8,
+//!             &_etext as *const u8 as usize - &_stext as *const u8 as usize,
+//!         ),
+//!         core::slice::from_raw_parts(
+//!             &_sapps as *const u8,
+//!             &_eapps as *const u8 as usize - &_sapps as *const u8 as usize,
+//!         ),
+//!         board_kernel.create_grant(&grant_cap),
+//!         &mut capsules::measured_boot::DATA,
+//!         measured_boot_digest,
+//!         measured_boot_signature,
+//!         measured_boot_public_key,
+//!     )
+//! );
+//! digest::Digest::set_client(sha, measured_boot);
+//! signature::SignatureSign::set_sign_client(p256, measured_boot);
+//! signature::KeyGenerate::set_key_client(p256, measured_boot);
+//! measured_boot.set_storage(storage, 0x0, &mut capsules::measured_boot::KEY_RECORD);
+//! storage.set_client(measured_boot);
+//!
+//! // After `kernel::procs::load_processes()` and `p256.load()`.
+//! measured_boot.start();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Initial
+//!
+//! ### Allow
+//!
+//! - read-only `0`: The nonce of a quote, 32 bytes.
+//! - read-write `0`: The buffer PCRs, log entries and signatures are written
+//!   to.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Quote done. The first argument is the status.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Write PCR `arg1`, 32 bytes, to the buffer.
+//! - `2`: Return the number of entries in the measurement log.
+//! - `3`: Write entry `arg1` of the log to the buffer: the little-endian
+//!   `ShortID` of the app, `0` for the kernel or an app without one, then
+//!   the 32 byte measurement.
+//! - `4`: Sign a quote of the PCRs for the nonce, and write the 64 byte
+//!   signature to the buffer.
+//! - `5`: Write the 64 byte public attestation key to the buffer, the X and
+//!   Y coordinates big endian.
+//!
+//! Commands fail with `BUSY` until all images are measured and the key is
+//! ready, `FAIL` if measuring failed, `INVAL` if there is no such PCR or
+//! entry, `RESERVE` if there is no attestation key, and `SIZE` if the buffer
+//! is too short or the nonce is not 32 bytes. One quote is signed at a time;
+//! others fail with `BUSY`.
+
+use core::cell::Cell;
+use core::convert::TryInto;
+use core::mem;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::signature::{self, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::MeasuredBoot as usize;
+
+pub static mut DATA: [u8; 256] = [0; 256];
+pub static mut KEY_RECORD: [u8; KEY_RECORD_LEN] = [0; KEY_RECORD_LEN];
+
+/// The number of PCRs.
+pub const NUM_PCRS: usize = 2;
+/// PCR extended with the measurement of the kernel.
+pub const PCR_KERNEL: usize = 0;
+/// PCR extended with the measurements of the apps.
+pub const PCR_APPS: usize = 1;
+
+/// The number of measurements the log holds, the kernel's included.
+pub const LOG_LEN: usize = 16;
+
+const NONCE_LEN: usize = 32;
+/// Length of a log entry written to userspace.
+const ENTRY_LEN: usize = 4 + 32;
+
+/// The record of the public attestation key in storage: a magic number, then
+/// the key.
+pub const KEY_RECORD_LEN: usize = 4 + PUBLIC_KEY_LEN;
+const KEY_MAGIC: [u8; 4] = *b"ATTK";
+
+/// The TBF object that starts `offset` bytes into `app_flash`, or `None` at
+/// the end of the apps. Objects with an invalid header are returned too, as
+/// the kernel skips them by their length.
+fn app_at(app_flash: &'static [u8], offset: usize) -> Option<&'static [u8]> {
+    let header: &'static [u8; 8] = app_flash.get(offset..offset + 8)?.try_into().ok()?;
+    let length = match tock_tbf::parse::parse_tbf_header_lengths(header) {
+        Ok((_, _, length)) => length,
+        Err(tock_tbf::types::InitialTbfParseError::InvalidHeader(length)) => length,
+        Err(tock_tbf::types::InitialTbfParseError::UnableToParse) => return None,
+    } as usize;
+    if length == 0 {
+        return None;
+    }
+    // An object past the end of the app flash is measured up to the end.
+    let end = core::cmp::min(offset.saturating_add(length), app_flash.len());
+    Some(&app_flash[offset..end])
+}
+
+/// The public key in the record `record`, if it holds one.
+fn parse_key_record(record: &[u8]) -> Option<[u8; PUBLIC_KEY_LEN]> {
+    if record.len() < KEY_RECORD_LEN || record[..4] != KEY_MAGIC {
+        return None;
+    }
+    record[4..KEY_RECORD_LEN].try_into().ok()
+}
+
+/// Write the record of `public_key` into `record`.
+fn write_key_record(record: &mut [u8], public_key: &[u8; PUBLIC_KEY_LEN]) {
+    record[..4].copy_from_slice(&KEY_MAGIC);
+    record[4..KEY_RECORD_LEN].copy_from_slice(public_key);
+}
+
+/// The log entry of `measurement`, as written to userspace.
+fn log_entry(measurement: &Measurement) -> [u8; ENTRY_LEN] {
+    let mut entry = [0; ENTRY_LEN];
+    entry[..4].copy_from_slice(&measurement.short_id.to_le_bytes());
+    entry[4..].copy_from_slice(&measurement.digest);
+    entry
+}
+
+#[derive(Clone, Copy, Default)]
+struct Measurement {
+    short_id: u32,
+    digest: [u8; 32],
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    /// Nothing measured yet.
+    Idle,
+    /// Hashing an image.
+    Measure,
+    /// Hashing a PCR and a measurement into the new PCR.
+    Extend,
+    /// Reading the public attestation key from storage.
+    LoadKey,
+    /// Generating the attestation key.
+    GenerateKey,
+    /// Writing the public attestation key to storage.
+    StoreKey,
+    /// All images are measured, and no quote is being signed.
+    Measured,
+    /// Hashing the nonce and the PCRs.
+    HashQuote,
+    /// Signing the hash of a quote.
+    SignQuote,
+    /// Measuring failed.
+    Failed,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    nonce: ReadOnlyAppSlice,
+    buffer: ReadWriteAppSlice,
+}
+
+pub struct MeasuredBoot<
+    'a,
+    H: digest::Digest<'a, [u8; 32]>,
+    S: signature::SignatureSign<'a> + signature::KeyGenerate<'a>,
+    C: ProcessManagementCapability,
+> {
+    kernel: &'static Kernel,
+    capability: C,
+    hasher: &'a H,
+    signer: &'a S,
+    /// Key slot of the signer that quotes are signed with.
+    key_slot: usize,
+    kernel_image: &'static [u8],
+    app_flash: &'static [u8],
+    apps: Grant<App>,
+    data: TakeCell<'static, [u8]>,
+    digest: TakeCell<'static, [u8; 32]>,
+    signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+    public_key: TakeCell<'static, [u8; PUBLIC_KEY_LEN]>,
+    /// Whether `public_key` holds the attestation key.
+    key_ready: Cell<bool>,
+    storage: OptionalCell<&'a dyn NonvolatileStorage<'static>>,
+    storage_address: Cell<usize>,
+    key_record: TakeCell<'static, [u8]>,
+    step: Cell<Step>,
+    pcrs: [Cell<[u8; 32]>; NUM_PCRS],
+    log: [Cell<Measurement>; LOG_LEN],
+    log_len: Cell<usize>,
+    /// The image being measured, the PCR it extends and its `ShortID`.
+    image: Cell<&'static [u8]>,
+    image_pcr: Cell<usize>,
+    image_id: Cell<u32>,
+    /// How much of the image was added to the digest.
+    image_offset: Cell<usize>,
+    /// Offset in the app flash of the next app to measure.
+    next_app: Cell<usize>,
+    current_app: OptionalCell<ProcessId>,
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]>,
+        S: signature::SignatureSign<'a> + signature::KeyGenerate<'a>,
+        C: ProcessManagementCapability,
+    > MeasuredBoot<'a, H, S, C>
+{
+    pub fn new(
+        kernel: &'static Kernel,
+        capability: C,
+        hasher: &'a H,
+        signer: &'a S,
+        key_slot: usize,
+        kernel_image: &'static [u8],
+        app_flash: &'static [u8],
+        grant: Grant<App>,
+        data: &'static mut [u8],
+        digest: &'static mut [u8; 32],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    ) -> MeasuredBoot<'a, H, S, C> {
+        MeasuredBoot {
+            kernel,
+            capability,
+            hasher,
+            signer,
+            key_slot,
+            kernel_image,
+            app_flash,
+            apps: grant,
+            data: TakeCell::new(data),
+            digest: TakeCell::new(digest),
+            signature: TakeCell::new(signature),
+            public_key: TakeCell::new(public_key),
+            key_ready: Cell::new(false),
+            storage: OptionalCell::empty(),
+            storage_address: Cell::new(0),
+            key_record: TakeCell::empty(),
+            step: Cell::new(Step::Idle),
+            pcrs: Default::default(),
+            log: Default::default(),
+            log_len: Cell::new(0),
+            image: Cell::new(&[]),
+            image_pcr: Cell::new(PCR_KERNEL),
+            image_id: Cell::new(0),
+            image_offset: Cell::new(0),
+            next_app: Cell::new(0),
+            current_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Keep the public attestation key in `KEY_RECORD_LEN` bytes of `storage`
+    /// from `address`, which must have this as its client. `buffer` must be
+    /// at least `KEY_RECORD_LEN` bytes long.
+    pub fn set_storage(
+        &self,
+        storage: &'a dyn NonvolatileStorage<'static>,
+        address: usize,
+        buffer: &'static mut [u8],
+    ) {
+        self.storage.set(storage);
+        self.storage_address.set(address);
+        self.key_record.replace(buffer);
+    }
+
+    /// Measure the kernel and the apps, then set up the attestation key. Call
+    /// once, after the processes are loaded.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if self.step.get() != Step::Idle {
+            return Err(ErrorCode::ALREADY);
+        }
+        let res = self.measure(self.kernel_image, PCR_KERNEL, 0);
+        if res.is_err() {
+            self.step.set(Step::Idle);
+        }
+        res
+    }
+
+    /// Start hashing `image`, to extend `pcr` with.
+    fn measure(&self, image: &'static [u8], pcr: usize, short_id: u32) -> Result<(), ErrorCode> {
+        self.step.set(Step::Measure);
+        self.image.set(image);
+        self.image_pcr.set(pcr);
+        self.image_id.set(short_id);
+        self.image_offset.set(0);
+        self.hash_image()
+    }
+
+    /// Add the next chunk of the image to the digest, or compute the digest
+    /// once all of it was added.
+    fn hash_image(&self) -> Result<(), ErrorCode> {
+        let image = self.image.get();
+        let offset = self.image_offset.get();
+        if offset == image.len() {
+            return self.run();
+        }
+
+        let data = self.data.take().ok_or(ErrorCode::RESERVE)?;
+        let length = core::cmp::min(data.len(), image.len() - offset);
+        data[..length].copy_from_slice(&image[offset..offset + length]);
+        self.image_offset.set(offset + length);
+        self.add_data(data, length)
+    }
+
+    /// Add the first `length` bytes of the data buffer to the digest.
+    fn add_data(&self, data: &'static mut [u8], length: usize) -> Result<(), ErrorCode> {
+        let mut lease = LeasableBuffer::new(data);
+        lease.slice(..length);
+        self.hasher
+            .add_data(lease)
+            .map(|_| ())
+            .map_err(|(e, data)| {
+                self.hasher.clear_data();
+                self.data.replace(data);
+                e
+            })
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        let digest = self.digest.take().ok_or(ErrorCode::RESERVE)?;
+        self.hasher.run(digest).map_err(|(e, digest)| {
+            self.hasher.clear_data();
+            self.digest.replace(digest);
+            e
+        })
+    }
+
+    /// Hash `first` and `second` into a new digest.
+    fn hash_concatenation(&self, step: Step, first: &[u8], second: &[u8]) -> Result<(), ErrorCode> {
+        let data = self.data.take().ok_or(ErrorCode::RESERVE)?;
+        let length = first.len() + second.len();
+        data[..first.len()].copy_from_slice(first);
+        data[first.len()..length].copy_from_slice(second);
+        self.step.set(step);
+        self.add_data(data, length)
+    }
+
+    /// Record the measurement of the current image, and extend its PCR with
+    /// it.
+    fn measured(&self, digest: &[u8; 32]) -> Result<(), ErrorCode> {
+        let index = self.log_len.get();
+        if index < LOG_LEN {
+            self.log[index].set(Measurement {
+                short_id: self.image_id.get(),
+                digest: *digest,
+            });
+            self.log_len.set(index + 1);
+        }
+
+        let pcr = self.pcrs[self.image_pcr.get()].get();
+        self.hash_concatenation(Step::Extend, &pcr, digest)
+    }
+
+    /// Measure the next app in flash, or set up the attestation key once all
+    /// of them are.
+    fn measure_next_app(&self) -> Result<(), ErrorCode> {
+        let image = match app_at(self.app_flash, self.next_app.get()) {
+            Some(image) => image,
+            None => return self.load_key(),
+        };
+        self.next_app.set(self.next_app.get() + image.len());
+
+        let start = image.as_ptr();
+        let short_id = Cell::new(0);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if process.flash_start() == start {
+                    short_id.set(process.processid().short_id().map_or(0, |id| id.id()));
+                }
+            });
+        self.measure(image, PCR_APPS, short_id.get())
+    }
+
+    /// Read the public attestation key from storage, or generate the key if
+    /// there is no storage.
+    fn load_key(&self) -> Result<(), ErrorCode> {
+        match (self.storage.extract(), self.key_record.take()) {
+            (Some(storage), Some(record)) => {
+                self.step.set(Step::LoadKey);
+                storage.read(record, self.storage_address.get(), KEY_RECORD_LEN)
+            }
+            (_, record) => {
+                record.map(|record| self.key_record.replace(record));
+                self.generate_key()
+            }
+        }
+    }
+
+    fn generate_key(&self) -> Result<(), ErrorCode> {
+        let public_key = self.public_key.take().ok_or(ErrorCode::RESERVE)?;
+        self.step.set(Step::GenerateKey);
+        self.signer
+            .generate_key(self.key_slot, public_key)
+            .map_err(|(e, public_key)| {
+                self.public_key.replace(public_key);
+                e
+            })
+    }
+
+    /// Write the public attestation key to storage, if there is any.
+    fn store_key(&self) -> Result<(), ErrorCode> {
+        let storage = match self.storage.extract() {
+            Some(storage) => storage,
+            None => {
+                self.step.set(Step::Measured);
+                return Ok(());
+            }
+        };
+        let record = self.key_record.take().ok_or(ErrorCode::RESERVE)?;
+        self.public_key
+            .map(|public_key| write_key_record(record, public_key));
+        self.step.set(Step::StoreKey);
+        storage.write(record, self.storage_address.get(), KEY_RECORD_LEN)
+    }
+
+    /// Hash the nonce of `appid` and the PCRs, the data a quote signs.
+    fn quote(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let mut nonce = [0; NONCE_LEN];
+        self.apps
+            .enter(appid, |app| {
+                if app.buffer.len() < SIGNATURE_LEN {
+                    return Err(ErrorCode::SIZE);
+                }
+                app.nonce.map_or(Err(ErrorCode::SIZE), |slice| {
+                    nonce.copy_from_slice(slice.try_into().map_err(|_| ErrorCode::SIZE)?);
+                    Ok(())
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+
+        let mut pcrs = [0; NUM_PCRS * 32];
+        for (pcr, value) in pcrs.chunks_mut(32).zip(self.pcrs.iter()) {
+            pcr.copy_from_slice(&value.get());
+        }
+        self.hash_concatenation(Step::HashQuote, &nonce, &pcrs)
+            .map(|()| self.current_app.set(appid))
+            .map_err(|e| {
+                self.step.set(Step::Measured);
+                e
+            })
+    }
+
+    /// The hash of the quote is ready: sign it.
+    fn sign_quote(&self, hash: &'static mut [u8; 32]) -> Result<(), ErrorCode> {
+        let signature = match self.signature.take() {
+            Some(signature) => signature,
+            None => {
+                self.digest.replace(hash);
+                return Err(ErrorCode::RESERVE);
+            }
+        };
+        self.step.set(Step::SignQuote);
+        self.signer
+            .sign(self.key_slot, hash, signature)
+            .map_err(|(e, hash, signature)| {
+                self.digest.replace(hash);
+                self.signature.replace(signature);
+                e
+            })
+    }
+
+    /// Handle the result of a step: if it failed, stop measuring, or report
+    /// the error to the process that asked for a quote.
+    fn check(&self, result: Result<(), ErrorCode>) {
+        if let Err(e) = result {
+            match self.step.get() {
+                Step::Measure | Step::Extend => {
+                    // A failed measurement leaves the PCRs unable to match
+                    // any log, so don't go on.
+                    self.step.set(Step::Failed);
+                }
+                Step::LoadKey => {
+                    let res = self.generate_key();
+                    self.check(res);
+                }
+                // Without a key the measurements can still be read, and
+                // quotes fail with `RESERVE`. A key that could not be stored
+                // is still used until the next boot.
+                Step::GenerateKey | Step::StoreKey => self.step.set(Step::Measured),
+                Step::HashQuote | Step::SignQuote => self.quote_done(Err(e)),
+                Step::Idle | Step::Measured | Step::Failed => {}
+            }
+        }
+    }
+
+    fn quote_done(&self, result: Result<(), ErrorCode>) {
+        self.step.set(Step::Measured);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback.schedule(kernel::into_statuscode(result), 0, 0);
+            });
+        });
+    }
+
+    /// Write `bytes` to the buffer of `appid`.
+    fn write(&self, appid: ProcessId, bytes: &[u8]) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(appid, |app| {
+                app.buffer.mut_map_or(Err(ErrorCode::SIZE), |buffer| {
+                    buffer
+                        .get_mut(..bytes.len())
+                        .ok_or(ErrorCode::SIZE)?
+                        .copy_from_slice(bytes);
+                    Ok(())
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]>,
+        S: signature::SignatureSign<'a> + signature::KeyGenerate<'a>,
+        C: ProcessManagementCapability,
+    > digest::Client<'a, [u8; 32]> for MeasuredBoot<'a, H, S, C>
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        self.data.replace(data);
+        let res = result.and_then(|()| match self.step.get() {
+            Step::Measure => self.hash_image(),
+            _ => self.run(),
+        });
+        self.check(res);
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; 32]) {
+        self.hasher.clear_data();
+        if let Err(e) = result {
+            self.digest.replace(digest);
+            self.check(Err(e));
+            return;
+        }
+
+        let res = match self.step.get() {
+            Step::Measure => {
+                let measurement = *digest;
+                self.digest.replace(digest);
+                self.measured(&measurement)
+            }
+            Step::Extend => {
+                self.pcrs[self.image_pcr.get()].set(*digest);
+                self.digest.replace(digest);
+                self.measure_next_app()
+            }
+            Step::HashQuote => self.sign_quote(digest),
+            _ => {
+                self.digest.replace(digest);
+                Ok(())
+            }
+        };
+        self.check(res);
+    }
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]>,
+        S: signature::SignatureSign<'a> + signature::KeyGenerate<'a>,
+        C: ProcessManagementCapability,
+    > signature::SignClient for MeasuredBoot<'a, H, S, C>
+{
+    fn signing_done(
+        &self,
+        result: Result<(), ErrorCode>,
+        hash: &'static mut [u8; 32],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) {
+        self.digest.replace(hash);
+        let res = result.and_then(|()| {
+            self.current_app.map_or(Err(ErrorCode::FAIL), |appid| {
+                self.write(*appid, &signature[..])
+            })
+        });
+        self.signature.replace(signature);
+        self.quote_done(res);
+    }
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]>,
+        S: signature::SignatureSign<'a> + signature::KeyGenerate<'a>,
+        C: ProcessManagementCapability,
+    > signature::KeyGenerateClient for MeasuredBoot<'a, H, S, C>
+{
+    fn key_generated(
+        &self,
+        result: Result<(), ErrorCode>,
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    ) {
+        self.public_key.replace(public_key);
+        let res = result.and_then(|()| {
+            self.key_ready.set(true);
+            self.store_key()
+        });
+        self.check(res);
+    }
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]>,
+        S: signature::SignatureSign<'a> + signature::KeyGenerate<'a>,
+        C: ProcessManagementCapability,
+    > NonvolatileStorageClient<'static> for MeasuredBoot<'a, H, S, C>
+{
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        let stored = parse_key_record(&buffer[..length]);
+        self.key_record.replace(buffer);
+        if self.step.get() != Step::LoadKey {
+            return;
+        }
+        match (stored, self.public_key.take()) {
+            (Some(stored), Some(public_key)) => {
+                *public_key = stored;
+                self.public_key.replace(public_key);
+                self.key_ready.set(true);
+                self.step.set(Step::Measured);
+            }
+            (_, public_key) => {
+                public_key.map(|public_key| self.public_key.replace(public_key));
+                let res = self.generate_key();
+                self.check(res);
+            }
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.key_record.replace(buffer);
+        if self.step.get() == Step::StoreKey {
+            self.step.set(Step::Measured);
+        }
+    }
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]>,
+        S: signature::SignatureSign<'a> + signature::KeyGenerate<'a>,
+        C: ProcessManagementCapability,
+    > Driver for MeasuredBoot<'a, H, S, C>
+{
+    /// Setup shared kernel-readable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The nonce of a quote.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut slice, &mut app.nonce);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup shared kernel-writable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The buffer PCRs, log entries and signatures are written to.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut slice, &mut app.buffer);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup a quote done callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Write PCR `arg1` to the buffer.
+    /// - `2`: Return the number of entries in the measurement log.
+    /// - `3`: Write entry `arg1` of the log to the buffer.
+    /// - `4`: Sign a quote of the PCRs for the nonce.
+    /// - `5`: Write the public attestation key to the buffer.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        _arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        if command_num == 0 {
+            return CommandReturn::success();
+        }
+        match self.step.get() {
+            Step::Measured | Step::HashQuote | Step::SignQuote => {}
+            Step::Failed => return CommandReturn::failure(ErrorCode::FAIL),
+            _ => return CommandReturn::failure(ErrorCode::BUSY),
+        }
+
+        let res = match command_num {
+            1 => match self.pcrs.get(arg1) {
+                Some(pcr) => self.write(appid, &pcr.get()),
+                None => Err(ErrorCode::INVAL),
+            },
+            2 => return CommandReturn::success_u32(self.log_len.get() as u32),
+            3 => {
+                if arg1 < self.log_len.get() {
+                    self.write(appid, &log_entry(&self.log[arg1].get()))
+                } else {
+                    Err(ErrorCode::INVAL)
+                }
+            }
+            4 => {
+                if !self.key_ready.get() {
+                    Err(ErrorCode::RESERVE)
+                } else if self.step.get() == Step::Measured {
+                    self.quote(appid)
+                } else {
+                    Err(ErrorCode::BUSY)
+                }
+            }
+            5 => {
+                let public_key = self.public_key.map(|public_key| *public_key);
+                match public_key {
+                    Some(public_key) if self.key_ready.get() => self.write(appid, &public_key),
+                    _ => Err(ErrorCode::RESERVE),
+                }
+            }
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// The first 8 bytes of a version 2 TBF header, padded to `total` bytes.
+    fn app(header_size: u16, total: u32, fill: u8) -> Vec<u8> {
+        let mut app = Vec::new();
+        app.extend_from_slice(&2u16.to_le_bytes());
+        app.extend_from_slice(&header_size.to_le_bytes());
+        app.extend_from_slice(&total.to_le_bytes());
+        app.resize(total as usize, fill);
+        app
+    }
+
+    fn flash(apps: &[Vec<u8>], padding: usize) -> &'static [u8] {
+        let mut flash: Vec<u8> = apps.concat();
+        flash.resize(flash.len() + padding, 0xff);
+        Box::leak(flash.into_boxed_slice())
+    }
+
+    #[test]
+    fn test_app_walk() {
+        let flash = flash(&[app(16, 32, 1), app(24, 64, 2)], 16);
+        let first = app_at(flash, 0).unwrap();
+        assert_eq!(first.as_ptr(), flash.as_ptr());
+        assert_eq!(first.len(), 32);
+        let second = app_at(flash, 32).unwrap();
+        assert_eq!(second.len(), 64);
+        // The whole object is measured, header included.
+        assert_eq!(second[..2], 2u16.to_le_bytes());
+        assert_eq!(second[63], 2);
+        // Erased flash ends the apps.
+        assert!(app_at(flash, 96).is_none());
+        assert!(app_at(flash, flash.len()).is_none());
+    }
+
+    #[test]
+    fn test_app_walk_invalid_header() {
+        // A header shorter than the required one is still measured, as the
+        // kernel skips it by its length and goes on to the next app.
+        let flash = flash(&[app(8, 32, 1), app(16, 32, 2)], 16);
+        assert_eq!(app_at(flash, 0).unwrap().len(), 32);
+        assert_eq!(app_at(flash, 32).unwrap().len(), 32);
+        assert!(app_at(flash, 64).is_none());
+    }
+
+    #[test]
+    fn test_app_walk_truncated() {
+        let mut long = app(16, 48, 1);
+        long[4..8].copy_from_slice(&4096u32.to_le_bytes());
+        assert_eq!(app_at(flash(&[long], 0), 0).unwrap().len(), 48);
+
+        let mut zero = app(16, 16, 1);
+        zero[4..8].copy_from_slice(&0u32.to_le_bytes());
+        assert!(app_at(flash(&[zero], 0), 0).is_none());
+    }
+
+    #[test]
+    fn test_key_record() {
+        let mut public_key = [0; PUBLIC_KEY_LEN];
+        for (i, byte) in public_key.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut record = [0xff; KEY_RECORD_LEN];
+        assert_eq!(parse_key_record(&record), None);
+        write_key_record(&mut record, &public_key);
+        assert_eq!(parse_key_record(&record), Some(public_key));
+        // A short read holds no key.
+        assert_eq!(parse_key_record(&record[..KEY_RECORD_LEN - 1]), None);
+        record[0] ^= 1;
+        assert_eq!(parse_key_record(&record), None);
+    }
+
+    #[test]
+    fn test_log_entry() {
+        let entry = log_entry(&Measurement {
+            short_id: 0x1234_5678,
+            digest: [0xab; 32],
+        });
+        assert_eq!(entry[..4], [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(entry[4..], [0xab; 32]);
+    }
+}
@@ -78,29 +78,37 @@ impl<'a> Mlx90614SMBus<'_> {
         }
     }
 
-    fn is_present(&self) {
-        self.state.set(State::IsPresent);
-        self.buffer.take().map(|buf| {
-            // turn on i2c to send commands
-            buf[0] = Mlx90614Registers::RAW1 as u8;
-            self.smbus_temp.smbus_write_read(buf, 1, 1).unwrap();
-        });
+    fn is_present(&self) -> Result<(), ErrorCode> {
+        self.start_read(State::IsPresent, Mlx90614Registers::RAW1, 1)
     }
 
-    fn read_ambient_temperature(&self) {
-        self.state.set(State::ReadAmbientTemp);
-        self.buffer.take().map(|buf| {
-            buf[0] = Mlx90614Registers::TA as u8;
-            self.smbus_temp.smbus_write_read(buf, 1, 1).unwrap();
-        });
+    fn read_ambient_temperature(&self) -> Result<(), ErrorCode> {
+        self.start_read(State::ReadAmbientTemp, Mlx90614Registers::TA, 1)
     }
 
-    fn read_object_temperature(&self) {
-        self.state.set(State::ReadObjTemp);
-        self.buffer.take().map(|buf| {
-            buf[0] = Mlx90614Registers::TOBJ1 as u8;
-            self.smbus_temp.smbus_write_read(buf, 1, 2).unwrap();
-        });
+    fn read_object_temperature(&self) -> Result<(), ErrorCode> {
+        self.start_read(State::ReadObjTemp, Mlx90614Registers::TOBJ1, 2)
+    }
+
+    fn start_read(
+        &self,
+        state: State,
+        register: Mlx90614Registers,
+        read_len: u8,
+    ) -> Result<(), ErrorCode> {
+        self.buffer.take().map_or(Err(ErrorCode::BUSY), |buf| {
+            buf[0] = register as u8;
+            match self.smbus_temp.smbus_write_read(buf, 1, read_len) {
+                Ok(()) => {
+                    self.state.set(state);
+                    Ok(())
+                }
+                Err((_error, buf)) => {
+                    self.buffer.replace(buf);
+                    Err(ErrorCode::FAIL)
+                }
+            }
+        })
     }
 }
 
@@ -164,8 +172,7 @@ impl<'a> Driver for Mlx90614SMBus<'a> {
             // Check is sensor is correctly connected
             1 => {
                 if self.state.get() == State::Idle {
-                    self.is_present();
-                    CommandReturn::success()
+                    self.is_present().into()
                 } else {
                     CommandReturn::failure(ErrorCode::BUSY)
                 }
@@ -173,8 +180,7 @@ impl<'a> Driver for Mlx90614SMBus<'a> {
             // Read Ambient Temperature
             2 => {
                 if self.state.get() == State::Idle {
-                    self.read_ambient_temperature();
-                    CommandReturn::success()
+                    self.read_ambient_temperature().into()
                 } else {
                     CommandReturn::failure(ErrorCode::BUSY)
                 }
@@ -182,8 +188,7 @@ impl<'a> Driver for Mlx90614SMBus<'a> {
             // Read Object Temperature
             3 => {
                 if self.state.get() == State::Idle {
-                    self.read_object_temperature();
-                    CommandReturn::success()
+                    self.read_object_temperature().into()
                 } else {
                     CommandReturn::failure(ErrorCode::BUSY)
                 }
@@ -215,7 +220,6 @@ impl<'a> sensors::TemperatureDriver<'a> for Mlx90614SMBus<'a> {
     }
 
     fn read_temperature(&self) -> Result<(), ErrorCode> {
-        self.read_object_temperature();
-        Ok(())
+        self.read_object_temperature()
     }
 }
@@ -0,0 +1,600 @@
+//! Modbus RTU master over a UART.
+//!
+//! Lets userspace read and write registers on Modbus RTU slaves without
+//! driving the UART directly. The capsule builds the request frames,
+//! appends and checks the CRC, keeps the 3.5 character silent interval
+//! between frames and times out requests that get no response.
+//!
+//! Only one request is in flight at a time; other processes get `BUSY` until
+//! it completes.
+//!
+//! The UART HIL has no inter-character timeout, so the capsule relies on the
+//! function code and byte count of a response to know its length rather
+//! than on the 1.5 character gap.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let modbus_uart = static_init!(UartDevice, UartDevice::new(uart_mux, true));
+//! modbus_uart.setup();
+//! let modbus_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let modbus = static_init!(
+//!     capsules::modbus_rtu::ModbusRtu<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::modbus_rtu::ModbusRtu::new(
+//!         modbus_uart,
+//!         modbus_alarm,
+//!         19200,
+//!         &mut capsules::modbus_rtu::BUF,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hil::uart::Transmit::set_transmit_client(modbus_uart, modbus);
+//! hil::uart::Receive::set_receive_client(modbus_uart, modbus);
+//! modbus_alarm.set_alarm_client(modbus);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Register values are exchanged with userspace as big-endian 16-bit words,
+//! as they appear on the wire.
+//!
+//! ### Allow
+//!
+//! - Read-write `0`: buffer the values of read registers are written to.
+//! - Read-only `0`: values for a write multiple registers request.
+//!
+//! ### Subscribe
+//!
+//! - `0`: request complete. The callback gets the status, the number of
+//!   registers read or written and the Modbus exception code returned by the
+//!   slave (or `0`). The status is `NOACK` if the slave did not respond,
+//!   `FAIL` for an exception response or a corrupt frame and `SIZE` if the
+//!   read buffer no longer fits the values read.
+//!
+//! ### Command
+//!
+//! For all commands `arg1` is the slave address. Address `0` broadcasts a
+//! write to all slaves, in which case no response is expected.
+//!
+//! - `0`: Driver check.
+//! - `1`: Read holding registers. `arg2` is the first register in the low
+//!   16 bits and the number of registers in the high 16 bits.
+//! - `2`: Read input registers. `arg2` is as for command `1`.
+//! - `3`: Write single register. `arg2` is the register in the low 16 bits
+//!   and the value in the high 16 bits.
+//! - `4`: Write multiple registers. `arg2` is as for command `1`, the values
+//!   are taken from the read-only allow buffer.
+
+use core::cell::Cell;
+use core::mem;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::time::{self, Alarm};
+use kernel::hil::uart;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ModbusRtu as usize;
+
+/// Longest RTU frame allowed by the Modbus specification.
+pub const MAX_FRAME_LEN: usize = 256;
+
+pub static mut BUF: [u8; MAX_FRAME_LEN] = [0; MAX_FRAME_LEN];
+
+/// How long to wait for a slave to respond.
+const RESPONSE_TIMEOUT_MS: u32 = 200;
+
+/// Most registers a single read request may ask for.
+const MAX_READ_REGISTERS: u16 = 125;
+/// Most registers a single write multiple request may carry.
+const MAX_WRITE_REGISTERS: u16 = 123;
+
+/// Address, function code and byte count (or first data byte).
+const HEADER_LEN: usize = 3;
+/// Address, function code, exception code and CRC.
+const EXCEPTION_LEN: usize = 5;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+const EXCEPTION_FLAG: u8 = 0x80;
+
+/// CRC-16/MODBUS over `data`, continuing from `crc` (`0xFFFF` for a new
+/// frame).
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data.iter() {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 0x0001 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for the line to be silent before sending the request.
+    FrameDelay,
+    Transmitting,
+    ReceivingHeader,
+    ReceivingBody,
+    /// The response timed out and the receive is being cancelled.
+    Aborting,
+    /// A broadcast was sent, waiting for the slaves to process it.
+    Turnaround,
+}
+
+#[derive(Clone, Copy)]
+struct Request {
+    function: u8,
+    slave: u8,
+    count: u16,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    read_buffer: ReadWriteAppSlice,
+    write_buffer: ReadOnlyAppSlice,
+}
+
+pub struct ModbusRtu<'a, A: Alarm<'a>> {
+    uart: &'a dyn uart::UartData<'a>,
+    alarm: &'a A,
+    baud_rate: u32,
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    request: Cell<Request>,
+    tx_len: Cell<usize>,
+    header: Cell<[u8; HEADER_LEN]>,
+    rx_len: Cell<usize>,
+    current_app: OptionalCell<ProcessId>,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> ModbusRtu<'a, A> {
+    pub fn new(
+        uart: &'a dyn uart::UartData<'a>,
+        alarm: &'a A,
+        baud_rate: u32,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> ModbusRtu<'a, A> {
+        ModbusRtu {
+            uart: uart,
+            alarm: alarm,
+            baud_rate: baud_rate,
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            request: Cell::new(Request {
+                function: 0,
+                slave: 0,
+                count: 0,
+            }),
+            tx_len: Cell::new(0),
+            header: Cell::new([0; HEADER_LEN]),
+            rx_len: Cell::new(0),
+            current_app: OptionalCell::empty(),
+            apps: grant,
+        }
+    }
+
+    /// The silent interval between frames: 3.5 characters of 11 bits, or a
+    /// fixed 1750 us above 19200 baud as recommended by the specification.
+    fn frame_delay_us(&self) -> u32 {
+        if self.baud_rate > 19200 {
+            1750
+        } else {
+            38_500_000 / self.baud_rate
+        }
+    }
+
+    /// Build the request frame for `app` and wait for the frame delay before
+    /// sending it.
+    fn start_request(
+        &self,
+        appid: ProcessId,
+        app: &mut App,
+        function: u8,
+        slave: u8,
+        arg: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.current_app.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if slave > 247 {
+            return Err(ErrorCode::INVAL);
+        }
+
+        let low = (arg & 0xFFFF) as u16;
+        let high = ((arg >> 16) & 0xFFFF) as u16;
+        let count = match function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                if slave == 0 {
+                    // Reads can't be broadcast.
+                    return Err(ErrorCode::INVAL);
+                }
+                if high == 0 || high > MAX_READ_REGISTERS {
+                    return Err(ErrorCode::INVAL);
+                }
+                if app.read_buffer.len() < high as usize * 2 {
+                    return Err(ErrorCode::SIZE);
+                }
+                high
+            }
+            WRITE_SINGLE_REGISTER => 1,
+            _ => {
+                if high == 0 || high > MAX_WRITE_REGISTERS {
+                    return Err(ErrorCode::INVAL);
+                }
+                if app.write_buffer.len() < high as usize * 2 {
+                    return Err(ErrorCode::SIZE);
+                }
+                high
+            }
+        };
+
+        self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
+            buffer[0] = slave;
+            buffer[1] = function;
+            buffer[2..4].copy_from_slice(&low.to_be_bytes());
+            buffer[4..6].copy_from_slice(&high.to_be_bytes());
+            let mut len = 6;
+            if function == WRITE_MULTIPLE_REGISTERS {
+                let data_len = count as usize * 2;
+                buffer[6] = data_len as u8;
+                app.write_buffer.map_or((), |data| {
+                    buffer[7..7 + data_len].copy_from_slice(&data[..data_len]);
+                });
+                len = 7 + data_len;
+            }
+            let crc = crc16(0xFFFF, &buffer[..len]);
+            buffer[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+            self.tx_len.set(len + 2);
+            self.buffer.replace(buffer);
+
+            self.request.set(Request {
+                function: function,
+                slave: slave,
+                count: count,
+            });
+            self.current_app.set(appid);
+            self.state.set(State::FrameDelay);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_us(self.frame_delay_us()));
+            Ok(())
+        })
+    }
+
+    /// Receive the next part of the response into the frame buffer.
+    fn receive(&self, buffer: &'static mut [u8], len: usize) {
+        if let Err((e, buffer)) = self.uart.receive_buffer(buffer, len) {
+            self.buffer.replace(buffer);
+            let _ = self.alarm.disarm();
+            self.complete(Err(e), 0);
+        }
+    }
+
+    /// Length of the whole response, given its first three bytes.
+    fn response_len(&self, header: &[u8]) -> Option<usize> {
+        let request = self.request.get();
+        if header[0] != request.slave || header[1] & !EXCEPTION_FLAG != request.function {
+            return None;
+        }
+        if header[1] & EXCEPTION_FLAG != 0 {
+            return Some(EXCEPTION_LEN);
+        }
+        match request.function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                let data_len = request.count as usize * 2;
+                if header[2] as usize == data_len {
+                    Some(HEADER_LEN + data_len + 2)
+                } else {
+                    None
+                }
+            }
+            // Writes echo the address and count or value of the request.
+            _ => Some(8),
+        }
+    }
+
+    /// Check the CRC of a full response and pass the result to the process.
+    fn handle_response(&self, frame: &[u8]) {
+        let len = frame.len();
+        let crc = crc16(0xFFFF, &frame[..len - 2]);
+        if crc.to_le_bytes() != [frame[len - 2], frame[len - 1]] {
+            self.complete(Err(ErrorCode::FAIL), 0);
+            return;
+        }
+        if frame[1] & EXCEPTION_FLAG != 0 {
+            self.complete(Err(ErrorCode::FAIL), frame[2] as usize);
+            return;
+        }
+
+        let request = self.request.get();
+        let result = match request.function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                let data = &frame[HEADER_LEN..len - 2];
+                // The process may have allowed a smaller buffer since the
+                // request was started.
+                self.current_app.map_or(Err(ErrorCode::FAIL), |appid| {
+                    self.apps
+                        .enter(*appid, |app| {
+                            app.read_buffer.mut_map_or(Err(ErrorCode::SIZE), |buffer| {
+                                buffer
+                                    .get_mut(..data.len())
+                                    .ok_or(ErrorCode::SIZE)
+                                    .map(|buffer| buffer.copy_from_slice(data))
+                            })
+                        })
+                        .unwrap_or_else(|err| Err(err.into()))
+                })
+            }
+            _ => Ok(()),
+        };
+        self.complete(result, 0);
+    }
+
+    /// Finish the current request and notify the process that made it.
+    fn complete(&self, result: Result<(), ErrorCode>, exception: usize) {
+        self.state.set(State::Idle);
+        let count = self.request.get().count as usize;
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                let count = if result.is_ok() { count } else { 0 };
+                app.callback
+                    .schedule(kernel::into_statuscode(result), count, exception);
+            });
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for ModbusRtu<'a, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::FrameDelay => {
+                self.buffer.take().map(|buffer| {
+                    self.state.set(State::Transmitting);
+                    if let Err((e, buffer)) = self.uart.transmit_buffer(buffer, self.tx_len.get()) {
+                        self.buffer.replace(buffer);
+                        self.complete(Err(e), 0);
+                    }
+                });
+            }
+            State::ReceivingHeader | State::ReceivingBody => {
+                // No (complete) response in time. If there is no receive to
+                // cancel we can finish now, otherwise wait for the UART to
+                // return the buffer.
+                match self.uart.receive_abort() {
+                    Ok(()) => self.complete(Err(ErrorCode::NOACK), 0),
+                    Err(_) => self.state.set(State::Aborting),
+                }
+            }
+            State::Turnaround => self.complete(Ok(()), 0),
+            State::Idle | State::Transmitting | State::Aborting => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> uart::TransmitClient for ModbusRtu<'a, A> {
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        if let Err(e) = rval {
+            self.buffer.replace(buffer);
+            self.complete(Err(e), 0);
+            return;
+        }
+
+        if self.request.get().slave == 0 {
+            // Slaves don't respond to broadcasts, give them the frame delay
+            // to act on it before the next request.
+            self.buffer.replace(buffer);
+            self.state.set(State::Turnaround);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_us(self.frame_delay_us()));
+        } else {
+            self.state.set(State::ReceivingHeader);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(RESPONSE_TIMEOUT_MS));
+            self.receive(buffer, HEADER_LEN);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> uart::ReceiveClient for ModbusRtu<'a, A> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        match self.state.get() {
+            State::Aborting => {
+                self.buffer.replace(buffer);
+                self.complete(Err(ErrorCode::NOACK), 0);
+            }
+            State::ReceivingHeader => {
+                if rval.is_err() || rx_len < HEADER_LEN {
+                    self.buffer.replace(buffer);
+                    let _ = self.alarm.disarm();
+                    self.complete(Err(ErrorCode::FAIL), 0);
+                    return;
+                }
+                match self.response_len(&buffer[..HEADER_LEN]) {
+                    Some(len) => {
+                        let mut header = [0; HEADER_LEN];
+                        header.copy_from_slice(&buffer[..HEADER_LEN]);
+                        self.header.set(header);
+                        self.rx_len.set(len);
+                        self.state.set(State::ReceivingBody);
+                        self.receive(buffer, len - HEADER_LEN);
+                    }
+                    None => {
+                        self.buffer.replace(buffer);
+                        let _ = self.alarm.disarm();
+                        self.complete(Err(ErrorCode::FAIL), 0);
+                    }
+                }
+            }
+            State::ReceivingBody => {
+                let _ = self.alarm.disarm();
+                let len = self.rx_len.get();
+                if rval.is_err() || rx_len < len - HEADER_LEN {
+                    self.buffer.replace(buffer);
+                    self.complete(Err(ErrorCode::FAIL), 0);
+                    return;
+                }
+                // The body was received at the start of the buffer, put the
+                // header back in front of it to get the whole frame.
+                buffer.copy_within(0..len - HEADER_LEN, HEADER_LEN);
+                buffer[..HEADER_LEN].copy_from_slice(&self.header.get());
+                self.handle_response(&buffer[..len]);
+                self.buffer.replace(buffer);
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for ModbusRtu<'a, A> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer read register values are written to
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.read_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Register values for write multiple registers
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.write_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Request complete callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Issue Modbus requests.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Read holding registers
+    /// - `2`: Read input registers
+    /// - `3`: Write single register
+    /// - `4`: Write multiple registers
+    fn command(&self, cmd_num: usize, slave: usize, arg: usize, appid: ProcessId) -> CommandReturn {
+        let function = match cmd_num {
+            0 => return CommandReturn::success(),
+            1 => READ_HOLDING_REGISTERS,
+            2 => READ_INPUT_REGISTERS,
+            3 => WRITE_SINGLE_REGISTER,
+            4 => WRITE_MULTIPLE_REGISTERS,
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+        if slave > u8::MAX as usize {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        }
+
+        let res = self
+            .apps
+            .enter(appid, |app| {
+                self.start_request(appid, app, function, slave as u8, arg)
+            })
+            .map_err(ErrorCode::from)
+            .and_then(|r| r);
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
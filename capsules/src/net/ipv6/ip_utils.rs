@@ -224,6 +224,45 @@ pub fn compute_icmp_checksum(
     sum as u16
 }
 
+/// Computes the checksum of a TCP segment, from its encoded header and its
+/// payload. The checksum of a received segment, header and payload passed
+/// together as `header`, is 0 if it is valid.
+pub fn compute_tcp_checksum(ip6_header: &IP6Header, header: &[u8], payload: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+
+    // add ipv6 pseudo-header, with the length of the segment as 32 bits
+    let src_addr = ip6_header.get_src_addr();
+    let dst_addr = ip6_header.get_dst_addr();
+    sum += sum_bytes(&src_addr.0);
+    sum += sum_bytes(&dst_addr.0);
+    let len = (header.len() + payload.len()) as u32;
+    sum += (len >> 16) + (len & 0xffff);
+    sum += ip6_nh::TCP as u32;
+
+    // add the header, then the payload, which may start at an odd offset
+    sum += sum_bytes(header);
+    if header.len() % 2 == 0 {
+        sum += sum_bytes(payload);
+    } else if let Some((first, rest)) = payload.split_first() {
+        sum += *first as u32;
+        sum += sum_bytes(rest);
+    }
+
+    // carry overflow
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+
+    !sum as u16
+}
+
+/// Sums the 16 bit big endian words of `buf`, padding an odd byte with zero.
+fn sum_bytes(buf: &[u8]) -> u32 {
+    buf.chunks(2)
+        .map(|word| (word[0] as u32) << 8 | *word.get(1).unwrap_or(&0) as u32)
+        .sum()
+}
+
 pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
     let mut sum: u32 = 0;
 
@@ -42,6 +42,9 @@ pub trait IP6Receiver<'a> {
 
 pub struct IP6RecvStruct<'a> {
     client: OptionalCell<&'a dyn IP6RecvClient>,
+    /// Client of the packets of one other transport protocol, and the next
+    /// header value of that protocol.
+    protocol_client: OptionalCell<(u8, &'a dyn IP6RecvClient)>,
 }
 
 impl<'a> IP6Receiver<'a> for IP6RecvStruct<'a> {
@@ -54,8 +57,15 @@ impl<'a> IP6RecvStruct<'a> {
     pub fn new() -> IP6RecvStruct<'a> {
         IP6RecvStruct {
             client: OptionalCell::empty(),
+            protocol_client: OptionalCell::empty(),
         }
     }
+
+    /// Pass the packets whose next header is `next_header`, such as
+    /// `ip6_nh::TCP`, to `client` rather than to the main client.
+    pub fn set_protocol_client(&self, next_header: u8, client: &'a dyn IP6RecvClient) {
+        self.protocol_client.set((next_header, client));
+    }
 }
 
 impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
@@ -71,11 +81,16 @@ impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
                     debug!("cksum fail!: {:?}", checksum_result);
                     return; //Dropped.
                 }
-                // Note: Protocols for which checksum verification is not implemented (TCP, etc.)
+                // Note: Protocols for which checksum verification is not implemented
                 // are automatically assumed as fine, rather than dropped
 
-                self.client
-                    .map(|client| client.receive(ip6_header, &buf[offset..len]));
+                let client = match self.protocol_client.extract() {
+                    Some((next_header, client)) if next_header == ip6_header.next_header => {
+                        Some(client)
+                    }
+                    _ => self.client.extract(),
+                };
+                client.map(|client| client.receive(ip6_header, &buf[offset..len]));
             }
             None => {
                 debug!("failed to decode ipv6 header");
@@ -0,0 +1,764 @@
+//! Multicast DNS (mDNS) and DNS Service Discovery (DNS-SD) responder.
+//!
+//! Makes the board and the services it offers discoverable on the link by
+//! standard tools such as `avahi-browse` or `dns-sd`, without a DNS server.
+//! The responder answers queries for:
+//!
+//! - `<hostname>.local`, with the AAAA records of the interface addresses,
+//! - `<service>.local`, such as `_coap._udp.local`, with a PTR record to each
+//!   instance of the service,
+//! - `<instance>.<service>.local`, with the SRV and TXT records of the
+//!   instance, and
+//! - `_services._dns-sd._udp.local`, with a PTR record to each service type.
+//!
+//! When started, the responder probes the link for other responders that use
+//! the host or instance names, and then announces its records. Should one
+//! answer, the responder stops and tells its client, which can pick other
+//! names and start it again. The simultaneous probe tiebreak of RFC 6762 is
+//! not implemented, and neither are known answer suppression and one-shot
+//! queries from ports other than 5353.
+//!
+//! Answers that do not fit in the buffer are sent in the packets that follow,
+//! and all responses are sent to the mDNS multicast group.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! static SERVICES: [capsules::net::mdns::Service; 1] = [capsules::net::mdns::Service {
+//!     instance: "Kitchen sensor",
+//!     service: "_coap._udp",
+//!     port: 5683,
+//!     txt: &["rt=temperature"],
+//! }];
+//!
+//! let mdns = static_init!(
+//!     capsules::net::mdns::Mdns<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::net::mdns::Mdns::new(
+//!         mdns_send,
+//!         mdns_recv,
+//!         udp_port_table,
+//!         net_cap,
+//!         mdns_alarm,
+//!         "imix",
+//!         local_ip_ifaces,
+//!         LeasableBuffer::new(&mut MDNS_BUF),
+//!     )
+//! );
+//! mdns_send.set_client(mdns);
+//! mdns_recv.set_client(mdns);
+//! mdns_alarm.set_alarm_client(mdns);
+//! mdns.set_services(&SERVICES).unwrap();
+//! mdns.start().unwrap();
+//! ```
+
+use core::cell::Cell;
+use core::iter;
+
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ErrorCode;
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+
+/// The mDNS port.
+pub const MDNS_PORT: u16 = 5353;
+
+/// The link-local mDNS multicast group, `ff02::fb`.
+pub const MDNS_ADDR: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfb]);
+
+/// Services the responder can announce.
+pub const MAX_SERVICES: usize = 7;
+
+const PROBES: u8 = 3;
+const PROBE_INTERVAL_MS: u32 = 250;
+const ANNOUNCEMENTS: u8 = 2;
+const ANNOUNCE_INTERVAL_MS: u32 = 1000;
+/// Responses to multicast queries are delayed by 20 to 120 ms, so that the
+/// responses of several responders are spread out.
+const RESPONSE_DELAY_MS: u32 = 20;
+const RESPONSE_JITTER_MS: u32 = 100;
+
+/// TTLs recommended by RFC 6762 for records with and without host names.
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// In questions, asks for a unicast response.
+const CLASS_UNICAST: u16 = 0x8000;
+/// In answers, marks the record as the only one of its name and type.
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+
+const SERVICES_NAME: &str = "_services._dns-sd._udp";
+
+/// A service to announce.
+pub struct Service<'a> {
+    /// Name of the instance, a single label that may contain dots and spaces,
+    /// such as `Kitchen sensor`.
+    pub instance: &'a str,
+    /// Type and protocol of the service, such as `_coap._udp`.
+    pub service: &'a str,
+    /// Port the service listens on.
+    pub port: u16,
+    /// `key=value` strings of the TXT record.
+    pub txt: &'a [&'a str],
+}
+
+pub trait MdnsClient {
+    /// Another responder on the link uses the host name or the name of an
+    /// instance, so the responder stopped.
+    fn conflict(&self);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Probing(u8),
+    Announcing(u8),
+    Running,
+    Conflict,
+}
+
+/// The records of the responder, as bits of a mask: the AAAA records of the
+/// host, then for each service its PTR, SRV and TXT records and its PTR
+/// record in the service type enumeration.
+const RECORD_HOST: u32 = 1;
+
+fn record_ptr(service: usize) -> u32 {
+    1 << (1 + 4 * service)
+}
+
+fn record_srv(service: usize) -> u32 {
+    1 << (2 + 4 * service)
+}
+
+fn record_txt(service: usize) -> u32 {
+    1 << (3 + 4 * service)
+}
+
+fn record_enum(service: usize) -> u32 {
+    1 << (4 + 4 * service)
+}
+
+/// A domain name in `.local`, made of an optional single label followed by
+/// dot separated labels.
+#[derive(Clone, Copy)]
+struct Name<'n> {
+    label: Option<&'n str>,
+    labels: &'n str,
+}
+
+impl<'n> Name<'n> {
+    fn new(label: Option<&'n str>, labels: &'n str) -> Name<'n> {
+        Name { label, labels }
+    }
+
+    fn labels(&self) -> impl Iterator<Item = &'n str> {
+        self.label
+            .into_iter()
+            .chain(self.labels.split('.'))
+            .chain(iter::once("local"))
+    }
+}
+
+/// Reads a DNS message.
+struct Reader<'b> {
+    msg: &'b [u8],
+    offset: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.msg.get(self.offset..self.offset + 2)?;
+        self.offset += 2;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.offset = self.offset.checked_add(len)?;
+        if self.offset <= self.msg.len() {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Skip the name at the offset, returning where it started.
+    fn name(&mut self) -> Option<usize> {
+        let start = self.offset;
+        loop {
+            let len = *self.msg.get(self.offset)? as usize;
+            if len & 0xc0 == 0xc0 {
+                self.skip(2)?;
+                break;
+            }
+            self.skip(1 + len)?;
+            if len == 0 {
+                break;
+            }
+        }
+        Some(start)
+    }
+
+    /// Whether the name at `offset` is `name`, ignoring case and following
+    /// compression pointers.
+    fn name_is(&self, mut offset: usize, name: &Name) -> bool {
+        let mut labels = name.labels();
+        // Bound the pointers followed, to stop on loops.
+        let mut pointers = 0;
+        loop {
+            let len = match self.msg.get(offset) {
+                Some(&len) => len as usize,
+                None => return false,
+            };
+            if len & 0xc0 == 0xc0 {
+                let low = match self.msg.get(offset + 1) {
+                    Some(&low) => low as usize,
+                    None => return false,
+                };
+                pointers += 1;
+                if pointers > 16 {
+                    return false;
+                }
+                offset = (len & 0x3f) << 8 | low;
+                continue;
+            }
+            if len == 0 {
+                return labels.next().is_none();
+            }
+            let label = match (labels.next(), self.msg.get(offset + 1..offset + 1 + len)) {
+                (Some(expected), Some(label)) => expected.as_bytes().eq_ignore_ascii_case(label),
+                _ => false,
+            };
+            if !label {
+                return false;
+            }
+            offset += 1 + len;
+        }
+    }
+}
+
+/// Writes a DNS message, failing once the buffer is full.
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.len + bytes.len();
+        self.buf.get_mut(self.len..end)?.copy_from_slice(bytes);
+        self.len = end;
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Write a length prefixed string, such as a label.
+    fn string(&mut self, string: &str) -> Option<()> {
+        if string.len() > 63 {
+            return None;
+        }
+        self.bytes(&[string.len() as u8])?;
+        self.bytes(string.as_bytes())
+    }
+
+    fn name(&mut self, name: &Name) -> Option<()> {
+        for label in name.labels() {
+            self.string(label)?;
+        }
+        self.bytes(&[0])
+    }
+
+    /// Write the header of a record, then its data with `rdata`, and patch in
+    /// the length of the data.
+    fn record<F>(&mut self, name: &Name, rtype: u16, class: u16, ttl: u32, rdata: F) -> Option<()>
+    where
+        F: FnOnce(&mut Writer) -> Option<()>,
+    {
+        self.name(name)?;
+        self.u16(rtype)?;
+        self.u16(class)?;
+        self.u32(ttl)?;
+        let length = self.len;
+        self.u16(0)?;
+        rdata(self)?;
+        let rdlength = (self.len - length - 2) as u16;
+        self.buf[length..length + 2].copy_from_slice(&rdlength.to_be_bytes());
+        Some(())
+    }
+
+    fn set_count(&mut self, index: usize, count: u16) {
+        self.buf[4 + 2 * index..6 + 2 * index].copy_from_slice(&count.to_be_bytes());
+    }
+}
+
+pub struct Mdns<'a, A: Alarm<'a>> {
+    sender: &'a dyn UDPSender<'a>,
+    receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    net_cap: &'static NetworkCapability,
+    alarm: &'a A,
+    hostname: &'a str,
+    addresses: &'a [IPAddr],
+    services: Cell<&'a [Service<'a>]>,
+    buffer: MapCell<LeasableBuffer<'static, u8>>,
+    client: OptionalCell<&'a dyn MdnsClient>,
+    state: Cell<State>,
+    /// A probe query waits for the buffer.
+    probe: Cell<bool>,
+    /// Records waiting for the buffer, and records to add to them if they
+    /// fit.
+    answers: Cell<u32>,
+    additionals: Cell<u32>,
+}
+
+impl<'a, A: Alarm<'a>> Mdns<'a, A> {
+    pub fn new(
+        sender: &'a dyn UDPSender<'a>,
+        receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        net_cap: &'static NetworkCapability,
+        alarm: &'a A,
+        hostname: &'a str,
+        addresses: &'a [IPAddr],
+        buffer: LeasableBuffer<'static, u8>,
+    ) -> Mdns<'a, A> {
+        Mdns {
+            sender: sender,
+            receiver: receiver,
+            port_table: port_table,
+            net_cap: net_cap,
+            alarm: alarm,
+            hostname: hostname,
+            addresses: addresses,
+            services: Cell::new(&[]),
+            buffer: MapCell::new(buffer),
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            probe: Cell::new(false),
+            answers: Cell::new(0),
+            additionals: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn MdnsClient) {
+        self.client.set(client);
+    }
+
+    /// Set the services to announce. If the responder is running, it probes
+    /// and announces its names again.
+    pub fn set_services(&self, services: &'a [Service<'a>]) -> Result<(), ErrorCode> {
+        if services.len() > MAX_SERVICES {
+            return Err(ErrorCode::SIZE);
+        }
+        self.services.set(services);
+        if self.state.get() != State::Idle {
+            self.restart();
+        }
+        Ok(())
+    }
+
+    /// Bind to the mDNS port and start probing.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if !self.receiver.is_bound() {
+            let socket = self
+                .port_table
+                .create_socket()
+                .map_err(|_| ErrorCode::NOMEM)?;
+            let (send_binding, receive_binding) = self
+                .port_table
+                .bind(socket, MDNS_PORT, self.net_cap)
+                .map_err(|_| ErrorCode::BUSY)?;
+            self.sender.set_binding(send_binding);
+            self.receiver.set_binding(receive_binding);
+        }
+        self.restart();
+        Ok(())
+    }
+
+    fn restart(&self) {
+        self.answers.set(0);
+        self.additionals.set(0);
+        self.state.set(State::Probing(0));
+        // Wait up to a probe interval first, so that boards powered up
+        // together do not probe at once.
+        let delay = self.alarm.now().into_u32() % PROBE_INTERVAL_MS;
+        self.set_alarm_ms(delay);
+    }
+
+    fn set_alarm_ms(&self, ms: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    fn host_name(&self) -> Name<'a> {
+        Name::new(None, self.hostname)
+    }
+
+    fn all_records(&self) -> u32 {
+        (0..self.services.get().len()).fold(RECORD_HOST, |records, i| {
+            records | record_ptr(i) | record_srv(i) | record_txt(i) | record_enum(i)
+        })
+    }
+
+    /// Send all the records, for the `announcements`th time.
+    fn announce(&self, announcements: u8) {
+        self.state.set(State::Announcing(announcements));
+        self.set_alarm_ms(ANNOUNCE_INTERVAL_MS);
+        self.answers.set(self.answers.get() | self.all_records());
+        self.send();
+    }
+
+    /// Send the probe or records waiting, if the buffer is free.
+    fn send(&self) {
+        if !self.probe.get() && self.answers.get() == 0 {
+            return;
+        }
+        self.buffer.take().map(|mut buffer| {
+            buffer.reset();
+            let len = if self.probe.get() {
+                self.probe.set(false);
+                self.write_probe(&mut buffer[..])
+            } else {
+                self.write_response(&mut buffer[..])
+            };
+            buffer.slice(0..len);
+            if let Err(mut buffer) = self
+                .sender
+                .send_to(MDNS_ADDR, MDNS_PORT, buffer, self.net_cap)
+            {
+                buffer.reset();
+                self.buffer.replace(buffer);
+            }
+        });
+    }
+
+    /// Write a query for the host and instance names, with the records the
+    /// responder would answer with in the authority section.
+    fn write_probe(&self, buf: &mut [u8]) -> usize {
+        let services = self.services.get();
+        let mut writer = Writer { buf, len: 0 };
+        let mut questions = 0;
+        let mut authorities = 0;
+        let _ = writer.bytes(&[0; 12]).and_then(|_| {
+            writer.name(&self.host_name())?;
+            writer.u16(TYPE_ANY)?;
+            writer.u16(CLASS_IN | CLASS_UNICAST)?;
+            questions += 1;
+            for service in services {
+                writer.name(&Name::new(Some(service.instance), service.service))?;
+                writer.u16(TYPE_ANY)?;
+                writer.u16(CLASS_IN | CLASS_UNICAST)?;
+                questions += 1;
+            }
+            authorities += self.write_record(&mut writer, RECORD_HOST)?;
+            for i in 0..services.len() {
+                authorities += self.write_record(&mut writer, record_srv(i))?;
+            }
+            Some(())
+        });
+        writer.set_count(0, questions);
+        writer.set_count(2, authorities);
+        writer.len
+    }
+
+    /// Write the answers waiting and the additional records that fit, and
+    /// leave the answers that do not for the next response.
+    fn write_response(&self, buf: &mut [u8]) -> usize {
+        let mut writer = Writer { buf, len: 0 };
+        if writer.bytes(&[0; 12]).is_none() {
+            self.answers.set(0);
+            return 0;
+        }
+        writer.buf[2..4].copy_from_slice(&(FLAG_RESPONSE | FLAG_AUTHORITATIVE).to_be_bytes());
+
+        let mut answers = self.answers.get();
+        let mut additionals = self.additionals.get() & !answers;
+        self.additionals.set(0);
+        let mut counts = [0; 2];
+        while answers != 0 {
+            let record = 1 << answers.trailing_zeros();
+            let len = writer.len;
+            match self.write_record(&mut writer, record) {
+                Some(count) => counts[0] += count,
+                None => {
+                    writer.len = len;
+                    // Drop a record too large for any response, rather than
+                    // try to send it forever.
+                    if counts[0] != 0 {
+                        break;
+                    }
+                }
+            }
+            answers &= !record;
+        }
+        self.answers.set(answers);
+        while answers == 0 && additionals != 0 {
+            let record = 1 << additionals.trailing_zeros();
+            let len = writer.len;
+            match self.write_record(&mut writer, record) {
+                Some(count) => counts[1] += count,
+                None => {
+                    writer.len = len;
+                    break;
+                }
+            }
+            additionals &= !record;
+        }
+        writer.set_count(1, counts[0]);
+        writer.set_count(3, counts[1]);
+        writer.len
+    }
+
+    /// Write a record of the responder, returning the number of resource
+    /// records written.
+    fn write_record(&self, writer: &mut Writer, record: u32) -> Option<u16> {
+        let host = self.host_name();
+        if record == RECORD_HOST {
+            let mut count = 0;
+            for address in self.addresses.iter().filter(|a| !a.is_multicast()) {
+                writer.record(
+                    &host,
+                    TYPE_AAAA,
+                    CLASS_IN | CLASS_CACHE_FLUSH,
+                    HOST_TTL,
+                    |w| w.bytes(&address.0),
+                )?;
+                count += 1;
+            }
+            return Some(count);
+        }
+
+        let index = (record.trailing_zeros() as usize - 1) / 4;
+        let service = self.services.get().get(index)?;
+        let service_name = Name::new(None, service.service);
+        let instance_name = Name::new(Some(service.instance), service.service);
+        if record == record_ptr(index) {
+            writer.record(&service_name, TYPE_PTR, CLASS_IN, OTHER_TTL, |w| {
+                w.name(&instance_name)
+            })?;
+        } else if record == record_srv(index) {
+            writer.record(
+                &instance_name,
+                TYPE_SRV,
+                CLASS_IN | CLASS_CACHE_FLUSH,
+                HOST_TTL,
+                |w| {
+                    // Priority and weight.
+                    w.u16(0)?;
+                    w.u16(0)?;
+                    w.u16(service.port)?;
+                    w.name(&host)
+                },
+            )?;
+        } else if record == record_txt(index) {
+            writer.record(
+                &instance_name,
+                TYPE_TXT,
+                CLASS_IN | CLASS_CACHE_FLUSH,
+                OTHER_TTL,
+                |w| {
+                    // A TXT record with no strings holds one empty string.
+                    if service.txt.is_empty() {
+                        return w.bytes(&[0]);
+                    }
+                    service.txt.iter().try_for_each(|txt| w.string(txt))
+                },
+            )?;
+        } else {
+            writer.record(
+                &Name::new(None, SERVICES_NAME),
+                TYPE_PTR,
+                CLASS_IN,
+                OTHER_TTL,
+                |w| w.name(&service_name),
+            )?;
+        }
+        Some(1)
+    }
+
+    /// The records answering a question, and the records to add to them.
+    fn answer(&self, reader: &Reader, name: usize, qtype: u16) -> (u32, u32) {
+        let any = qtype == TYPE_ANY;
+        let mut answers = 0;
+        let mut additionals = 0;
+        if (any || qtype == TYPE_AAAA) && reader.name_is(name, &self.host_name()) {
+            answers |= RECORD_HOST;
+        }
+        let enumeration =
+            (any || qtype == TYPE_PTR) && reader.name_is(name, &Name::new(None, SERVICES_NAME));
+        for (i, service) in self.services.get().iter().enumerate() {
+            if enumeration {
+                answers |= record_enum(i);
+            }
+            if (any || qtype == TYPE_PTR) && reader.name_is(name, &Name::new(None, service.service))
+            {
+                answers |= record_ptr(i);
+                additionals |= record_srv(i) | record_txt(i) | RECORD_HOST;
+            }
+            if reader.name_is(name, &Name::new(Some(service.instance), service.service)) {
+                if any || qtype == TYPE_SRV {
+                    answers |= record_srv(i);
+                    additionals |= RECORD_HOST;
+                }
+                if any || qtype == TYPE_TXT {
+                    answers |= record_txt(i);
+                }
+            }
+        }
+        (answers, additionals)
+    }
+
+    /// Whether a record another responder sent uses one of our names.
+    fn conflicts(&self, reader: &Reader, name: usize, rtype: u16) -> bool {
+        if (rtype == TYPE_AAAA || rtype == TYPE_A) && reader.name_is(name, &self.host_name()) {
+            return true;
+        }
+        (rtype == TYPE_SRV || rtype == TYPE_TXT)
+            && self.services.get().iter().any(|service| {
+                reader.name_is(name, &Name::new(Some(service.instance), service.service))
+            })
+    }
+
+    fn receive_message(&self, reader: &mut Reader, state: State) -> Option<()> {
+        let _id = reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let answers = reader.u16()?;
+        let authorities = reader.u16()?;
+        let additionals = reader.u16()?;
+        if flags & FLAG_RESPONSE != 0 {
+            let records = answers
+                .saturating_add(authorities)
+                .saturating_add(additionals);
+            self.receive_response(reader, questions, records)
+        } else if state == State::Running {
+            self.receive_query(reader, questions)
+        } else {
+            Some(())
+        }
+    }
+
+    fn receive_query(&self, reader: &mut Reader, questions: u16) -> Option<()> {
+        let mut answers = 0;
+        let mut additionals = 0;
+        for _ in 0..questions {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let qclass = reader.u16()?;
+            if qclass & !CLASS_UNICAST == CLASS_IN {
+                let (a, b) = self.answer(reader, name, qtype);
+                answers |= a;
+                additionals |= b;
+            }
+        }
+        if answers != 0 {
+            self.answers.set(self.answers.get() | answers);
+            self.additionals.set(self.additionals.get() | additionals);
+            if !self.alarm.is_armed() {
+                let jitter = self.alarm.now().into_u32() % RESPONSE_JITTER_MS;
+                self.set_alarm_ms(RESPONSE_DELAY_MS + jitter);
+            }
+        }
+        Some(())
+    }
+
+    fn receive_response(&self, reader: &mut Reader, questions: u16, records: u16) -> Option<()> {
+        for _ in 0..questions {
+            reader.name()?;
+            reader.skip(4)?;
+        }
+        for _ in 0..records {
+            let name = reader.name()?;
+            let rtype = reader.u16()?;
+            reader.skip(6)?;
+            let rdlength = reader.u16()?;
+            reader.skip(rdlength as usize)?;
+            if self.conflicts(reader, name, rtype) {
+                self.state.set(State::Conflict);
+                let _ = self.alarm.disarm();
+                self.probe.set(false);
+                self.answers.set(0);
+                self.additionals.set(0);
+                self.client.map(|client| client.conflict());
+                break;
+            }
+        }
+        Some(())
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Mdns<'a, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Probing(probes) if probes < PROBES => {
+                self.state.set(State::Probing(probes + 1));
+                self.set_alarm_ms(PROBE_INTERVAL_MS);
+                self.probe.set(true);
+                self.send();
+            }
+            State::Probing(_) => self.announce(1),
+            State::Announcing(announcements) if announcements < ANNOUNCEMENTS => {
+                self.announce(announcements + 1)
+            }
+            State::Announcing(_) => self.state.set(State::Running),
+            State::Running => self.send(),
+            State::Idle | State::Conflict => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for Mdns<'a, A> {
+    fn send_done(&self, _result: Result<(), ErrorCode>, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.buffer.replace(dgram);
+        // Delayed answers wait for their alarm.
+        if self.probe.get() || !self.alarm.is_armed() || self.state.get() != State::Running {
+            self.send();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for Mdns<'a, A> {
+    fn receive(
+        &self,
+        _src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        let state = self.state.get();
+        if src_port != MDNS_PORT || state == State::Idle || state == State::Conflict {
+            return;
+        }
+        let mut reader = Reader {
+            msg: payload,
+            offset: 0,
+        };
+        let _ = self.receive_message(&mut reader, state);
+    }
+}
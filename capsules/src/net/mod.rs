@@ -8,7 +8,10 @@ pub mod stream;
 pub mod icmpv6;
 pub mod ieee802154;
 pub mod ipv6;
+pub mod mdns;
 pub mod network_capabilities;
+pub mod slip;
+pub mod socket;
 pub mod tcp;
 pub mod thread;
 pub mod udp;
@@ -0,0 +1,273 @@
+//! IPv6 over a serial line, framed with SLIP (RFC 1055).
+//!
+//! `SlipIP6` is an `IP6Sender` that sends packets over a UART instead of
+//! over 6LoWPAN and an 802.15.4 radio, and hands the packets it receives to
+//! an `IP6RecvStruct` like the 6LoWPAN receive path does. With it, the UDP
+//! and TCP capsules can talk to a host computer over the console UART (for
+//! example with `slattach` on Linux) or to a modem that speaks SLIP, on any
+//! board with a serial port and without a border router.
+//!
+//! A serial line has exactly one peer, so the gateway MAC address is
+//! ignored. Only SLIP framing is supported; there is no PPP negotiation.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let slip_uart = static_init!(UartDevice, UartDevice::new(uart_mux, true));
+//! slip_uart.setup();
+//! let ip6_recv = static_init!(IP6RecvStruct<'static>, IP6RecvStruct::new());
+//! ip6_recv.set_client(udp_recv_mux);
+//! let slip = static_init!(
+//!     capsules::net::slip::SlipIP6<'static>,
+//!     capsules::net::slip::SlipIP6::new(
+//!         slip_uart,
+//!         ip6_packet,
+//!         &mut capsules::net::slip::TX_BUF,
+//!         &mut capsules::net::slip::RX_BUF,
+//!         &mut capsules::net::slip::FRAME_BUF,
+//!         ip_vis,
+//!     )
+//! );
+//! hil::uart::Transmit::set_transmit_client(slip_uart, slip);
+//! hil::uart::Receive::set_receive_client(slip_uart, slip);
+//! slip.set_receive_client(ip6_recv);
+//! slip.start();
+//! let udp_send_mux = static_init!(
+//!     MuxUdpSender<'static, capsules::net::slip::SlipIP6<'static>>,
+//!     MuxUdpSender::new(slip)
+//! );
+//! slip.set_client(udp_send_mux);
+//! ```
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, IP6Packet, TransportHeader};
+use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
+use crate::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::uart;
+use kernel::ErrorCode;
+
+/// The minimum IPv6 MTU, escaped in the worst case, plus the frame ends.
+pub static mut TX_BUF: [u8; 2 * 1280 + 2] = [0; 2 * 1280 + 2];
+pub static mut RX_BUF: [u8; 1] = [0; 1];
+/// Received packets longer than this buffer are dropped.
+pub static mut FRAME_BUF: [u8; 1280] = [0; 1280];
+
+const END: u8 = 0xc0;
+const ESC: u8 = 0xdb;
+const ESC_END: u8 = 0xdc;
+const ESC_ESC: u8 = 0xdd;
+
+/// Escape the `len` bytes at the start of `buf` in place and add the frame
+/// ends. Returns the length of the frame, or `None` if it does not fit.
+fn encode_frame(buf: &mut [u8], len: usize) -> Option<usize> {
+    let escapes = buf[..len].iter().filter(|&&c| c == END || c == ESC).count();
+    let frame_len = len + escapes + 2;
+    if frame_len > buf.len() {
+        return None;
+    }
+
+    // Working back from the end, no byte is overwritten before it is read.
+    let mut out = frame_len - 1;
+    buf[out] = END;
+    for i in (0..len).rev() {
+        let (first, second) = match buf[i] {
+            END => (ESC, Some(ESC_END)),
+            ESC => (ESC, Some(ESC_ESC)),
+            c => (c, None),
+        };
+        if let Some(second) = second {
+            out -= 1;
+            buf[out] = second;
+        }
+        out -= 1;
+        buf[out] = first;
+    }
+    buf[0] = END;
+    Some(frame_len)
+}
+
+pub struct SlipIP6<'a> {
+    uart: &'a dyn uart::UartData<'a>,
+    ip6_packet: TakeCell<'static, IP6Packet<'static>>,
+    tx_buf: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+    frame: TakeCell<'static, [u8]>,
+    /// Length of the frame being received, or `usize::MAX` while dropping a
+    /// frame too long for the buffer.
+    frame_len: Cell<usize>,
+    /// Whether the last byte received was `ESC`.
+    escaped: Cell<bool>,
+    src_addr: Cell<IPAddr>,
+    client: OptionalCell<&'a dyn IP6SendClient>,
+    rx_client: OptionalCell<&'a dyn SixlowpanRxClient>,
+    ip_vis: &'static IpVisibilityCapability,
+}
+
+impl<'a> SlipIP6<'a> {
+    pub fn new(
+        uart: &'a dyn uart::UartData<'a>,
+        ip6_packet: &'static mut IP6Packet<'static>,
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        frame: &'static mut [u8],
+        ip_vis: &'static IpVisibilityCapability,
+    ) -> SlipIP6<'a> {
+        SlipIP6 {
+            uart: uart,
+            ip6_packet: TakeCell::new(ip6_packet),
+            tx_buf: TakeCell::new(tx_buf),
+            rx_buf: TakeCell::new(rx_buf),
+            frame: TakeCell::new(frame),
+            frame_len: Cell::new(0),
+            escaped: Cell::new(false),
+            src_addr: Cell::new(IPAddr::new()),
+            client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            ip_vis: ip_vis,
+        }
+    }
+
+    /// Set the client received packets are passed to, usually an
+    /// `IP6RecvStruct`.
+    pub fn set_receive_client(&self, client: &'a dyn SixlowpanRxClient) {
+        self.rx_client.set(client);
+    }
+
+    /// Start receiving from the UART.
+    pub fn start(&self) {
+        self.rx_buf.take().map(|buffer| {
+            let _ = self.uart.receive_buffer(buffer, 1);
+        });
+    }
+
+    fn receive_byte(&self, byte: u8) {
+        let len = self.frame_len.get();
+        let byte = match (self.escaped.replace(false), byte) {
+            (_, END) => {
+                if len > 0 && len != usize::MAX {
+                    self.frame.map(|frame| {
+                        self.rx_client
+                            .map(|client| client.receive(&frame[..len], len, Ok(())));
+                    });
+                }
+                self.frame_len.set(0);
+                return;
+            }
+            (false, ESC) => {
+                self.escaped.set(true);
+                return;
+            }
+            (true, ESC_END) => END,
+            (true, ESC_ESC) => ESC,
+            // RFC 1055 leaves other escaped bytes as they are.
+            (_, c) => c,
+        };
+        if len == usize::MAX {
+            return;
+        }
+        self.frame.map(|frame| match frame.get_mut(len) {
+            Some(slot) => {
+                *slot = byte;
+                self.frame_len.set(len + 1);
+            }
+            None => self.frame_len.set(usize::MAX),
+        });
+    }
+}
+
+impl<'a> IP6Sender<'a> for SlipIP6<'a> {
+    fn set_client(&self, client: &'a dyn IP6SendClient) {
+        self.client.set(client);
+    }
+
+    fn set_addr(&self, src_addr: IPAddr) {
+        self.src_addr.set(src_addr);
+    }
+
+    fn set_gateway(&self, _gateway: MacAddress) {}
+
+    fn set_header(&mut self, ip6_header: IP6Header) {
+        self.ip6_packet
+            .map(|ip6_packet| ip6_packet.header = ip6_header);
+    }
+
+    fn send_to(
+        &self,
+        dst: IPAddr,
+        transport_header: TransportHeader,
+        payload: &LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), ErrorCode> {
+        if !net_cap.remote_addr_valid(dst, self.ip_vis) {
+            return Err(ErrorCode::FAIL);
+        }
+        let tx_buf = self.tx_buf.take().ok_or(ErrorCode::BUSY)?;
+        let frame_len = self.ip6_packet.map_or(Err(ErrorCode::NOMEM), |ip6_packet| {
+            ip6_packet.header = IP6Header::default();
+            ip6_packet.header.src_addr = self.src_addr.get();
+            ip6_packet.header.dst_addr = dst;
+            ip6_packet.set_payload(transport_header, payload);
+            ip6_packet.set_transport_checksum();
+
+            if ip6_packet.get_total_len() as usize > tx_buf.len() {
+                return Err(ErrorCode::SIZE);
+            }
+            ip6_packet
+                .encode(tx_buf)
+                .done()
+                .and_then(|(len, _)| encode_frame(tx_buf, len))
+                .ok_or(ErrorCode::SIZE)
+        });
+
+        match frame_len {
+            Ok(frame_len) => self
+                .uart
+                .transmit_buffer(tx_buf, frame_len)
+                .map_err(|(e, tx_buf)| {
+                    self.tx_buf.replace(tx_buf);
+                    e
+                }),
+            Err(e) => {
+                self.tx_buf.replace(tx_buf);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'a> uart::TransmitClient for SlipIP6<'a> {
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        self.tx_buf.replace(buffer);
+        self.client.map(|client| client.send_done(rval));
+    }
+}
+
+impl<'a> uart::ReceiveClient for SlipIP6<'a> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        if rval.is_ok() && rx_len > 0 {
+            self.receive_byte(buffer[0]);
+        }
+        if let Err((_, buffer)) = self.uart.receive_buffer(buffer, 1) {
+            self.rx_buf.replace(buffer);
+        }
+    }
+}
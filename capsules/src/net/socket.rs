@@ -0,0 +1,602 @@
+//! Socket-style userspace interface to the network transports.
+//!
+//! Processes open sockets on a transport, bind them to a local endpoint,
+//! optionally connect them to a remote endpoint, and then send and receive
+//! datagrams, with the same system calls whatever the transport. Portable
+//! libraries therefore do not need to know each radio's own driver.
+//!
+//! Each transport implements [`Transport`](trait.Transport.html) and is
+//! selected by its [`Protocol`](enum.Protocol.html) number. Endpoints are an
+//! address of up to 16 bytes and a port, which each transport interprets:
+//!
+//! - UDP: an IPv6 address and a UDP port.
+//! - BLE L2CAP: a 6 byte device address, with the rest zero, and a PSM.
+//!
+//! Sockets are datagram sockets: one send is one UDP datagram or one L2CAP
+//! SDU. The driver sends one datagram at a time, for all processes and
+//! transports.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let socket_driver = static_init!(
+//!     capsules::net::socket::SocketDriver<'static>,
+//!     capsules::net::socket::SocketDriver::new(
+//!         &[udp_socket_transport],
+//!         LeasableBuffer::new(&mut SOCKET_BUF),
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! udp_socket_transport.set_client(socket_driver);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Endpoints are passed as their 16 address bytes, followed by the port as
+//! a little endian `u16`.
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: the payload to send.
+//! - Read-only `1`: the endpoint to bind to, connect to or send to.
+//! - Read-write `0`: the buffer received payloads are copied to.
+//! - Read-write `1`: the buffer the endpoint that sent the last received
+//!   payload is written to.
+//!
+//! ### Subscribe
+//!
+//! - `0`: received. The callback gets the socket and the length of the
+//!   payload, which is truncated to the buffer.
+//! - `1`: sent. The callback gets the socket and the result of the send.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the bitmask of the protocols of the
+//!   transports.
+//! - `1`: Open a socket on protocol `arg1`. Returns the socket.
+//! - `2`: Bind socket `arg1` to the endpoint allowed. Returns `BUSY` if
+//!   another socket, process or capsule uses it.
+//! - `3`: Connect socket `arg1` to the endpoint allowed, which sends go to.
+//! - `4`: Send the payload allowed on socket `arg1`, to the endpoint it is
+//!   connected to.
+//! - `5`: Send the payload allowed on socket `arg1`, to the endpoint allowed.
+//! - `6`: Close socket `arg1`.
+//! - `7`: Get the largest payload that can be sent.
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::mem;
+
+use kernel::common::cells::MapCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Socket as usize;
+
+/// Sockets each process can open.
+pub const MAX_SOCKETS: usize = 4;
+
+/// Length of an endpoint in the allowed buffers.
+pub const ENDPOINT_LEN: usize = 18;
+
+/// Transport protocols, which processes select transports by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Udp = 0,
+    BleL2cap = 1,
+}
+
+/// A local or remote endpoint of a transport.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Endpoint {
+    pub addr: [u8; 16],
+    pub port: u16,
+}
+
+impl Endpoint {
+    fn decode(data: &[u8]) -> Option<Endpoint> {
+        let data = data.get(..ENDPOINT_LEN)?;
+        let mut addr = [0; 16];
+        addr.copy_from_slice(&data[..16]);
+        Some(Endpoint {
+            addr: addr,
+            port: u16::from_le_bytes(data[16..].try_into().ok()?),
+        })
+    }
+
+    fn encode(&self, data: &mut [u8]) {
+        if let Some(data) = data.get_mut(..ENDPOINT_LEN) {
+            data[..16].copy_from_slice(&self.addr);
+            data[16..].copy_from_slice(&self.port.to_le_bytes());
+        }
+    }
+}
+
+/// A datagram transport the socket driver can use.
+pub trait Transport<'a> {
+    fn protocol(&self) -> Protocol;
+
+    fn set_client(&self, client: &'a dyn TransportClient);
+
+    /// Receive the datagrams sent to `local`.
+    fn bind(&self, local: Endpoint) -> Result<(), ErrorCode>;
+
+    fn unbind(&self, local: Endpoint) -> Result<(), ErrorCode>;
+
+    /// Unbind the endpoints `in_use` says are no longer used, such as those
+    /// of processes that exited.
+    fn unbind_unused(&self, in_use: &dyn Fn(Endpoint) -> bool);
+
+    /// Largest payload `send` takes.
+    fn max_payload(&self) -> usize;
+
+    /// Send `payload` from bound endpoint `local` to `remote`.
+    fn send(
+        &self,
+        local: Endpoint,
+        remote: Endpoint,
+        payload: LeasableBuffer<'static, u8>,
+    ) -> Result<(), (ErrorCode, LeasableBuffer<'static, u8>)>;
+}
+
+pub trait TransportClient {
+    fn send_done(&self, result: Result<(), ErrorCode>, payload: LeasableBuffer<'static, u8>);
+
+    /// A datagram from `remote` to the bound endpoint `local` arrived.
+    fn receive(&self, protocol: Protocol, local: Endpoint, remote: Endpoint, payload: &[u8]);
+}
+
+#[derive(Clone, Copy, Default)]
+struct Socket {
+    protocol: Option<Protocol>,
+    local: Option<Endpoint>,
+    remote: Option<Endpoint>,
+}
+
+#[derive(Default)]
+pub struct App {
+    rx_callback: Upcall,
+    tx_callback: Upcall,
+    tx_buffer: ReadOnlyAppSlice,
+    endpoint_buffer: ReadOnlyAppSlice,
+    rx_buffer: ReadWriteAppSlice,
+    source_buffer: ReadWriteAppSlice,
+    sockets: [Socket; MAX_SOCKETS],
+    /// Socket and destination of the send waiting for the kernel buffer.
+    pending_tx: Option<(usize, Endpoint)>,
+}
+
+pub struct SocketDriver<'a> {
+    transports: &'a [&'a dyn Transport<'a>],
+    buffer: MapCell<LeasableBuffer<'static, u8>>,
+    buffer_len: usize,
+    /// Process and socket of the send in progress.
+    current: Cell<Option<(ProcessId, usize)>>,
+    apps: Grant<App>,
+}
+
+impl<'a> SocketDriver<'a> {
+    pub fn new(
+        transports: &'a [&'a dyn Transport<'a>],
+        buffer: LeasableBuffer<'static, u8>,
+        grant: Grant<App>,
+    ) -> SocketDriver<'a> {
+        SocketDriver {
+            transports: transports,
+            buffer_len: buffer.len(),
+            buffer: MapCell::new(buffer),
+            current: Cell::new(None),
+            apps: grant,
+        }
+    }
+
+    fn transport(&self, protocol: Protocol) -> Option<&'a dyn Transport<'a>> {
+        self.transports
+            .iter()
+            .find(|transport| transport.protocol() == protocol)
+            .copied()
+    }
+
+    fn max_payload(&self) -> usize {
+        self.transports
+            .iter()
+            .map(|transport| transport.max_payload())
+            .fold(self.buffer_len, cmp::min)
+    }
+
+    /// Whether a process has a socket on `protocol` bound to `local`.
+    fn bound(&self, protocol: Protocol, local: Endpoint) -> bool {
+        self.apps.iter().any(|app| {
+            app.enter(|app| {
+                app.sockets
+                    .iter()
+                    .any(|socket| socket.protocol == Some(protocol) && socket.local == Some(local))
+            })
+        })
+    }
+
+    fn open(&self, appid: ProcessId, protocol: usize) -> Result<u32, ErrorCode> {
+        let protocol = self
+            .transports
+            .iter()
+            .map(|transport| transport.protocol())
+            .find(|p| *p as usize == protocol)
+            .ok_or(ErrorCode::NOSUPPORT)?;
+        self.apps
+            .enter(appid, |app| {
+                let index = app
+                    .sockets
+                    .iter()
+                    .position(|socket| socket.protocol.is_none())
+                    .ok_or(ErrorCode::NOMEM)?;
+                app.sockets[index] = Socket {
+                    protocol: Some(protocol),
+                    local: None,
+                    remote: None,
+                };
+                Ok(index as u32)
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    /// The protocol of an open socket, and the endpoint allowed.
+    fn socket_endpoint(
+        &self,
+        appid: ProcessId,
+        index: usize,
+    ) -> Result<(Protocol, Endpoint), ErrorCode> {
+        self.apps
+            .enter(appid, |app| {
+                let protocol = app
+                    .sockets
+                    .get(index)
+                    .and_then(|socket| socket.protocol)
+                    .ok_or(ErrorCode::INVAL)?;
+                let endpoint = app
+                    .endpoint_buffer
+                    .map_or(None, |data| Endpoint::decode(data))
+                    .ok_or(ErrorCode::INVAL)?;
+                Ok((protocol, endpoint))
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn bind(&self, appid: ProcessId, index: usize) -> Result<(), ErrorCode> {
+        let (protocol, local) = self.socket_endpoint(appid, index)?;
+        let transport = self.transport(protocol).ok_or(ErrorCode::NOSUPPORT)?;
+        if self.bound(protocol, local) {
+            return Err(ErrorCode::BUSY);
+        }
+        transport.unbind_unused(&|local| self.bound(protocol, local));
+        self.unbind(appid, index)?;
+        transport.bind(local)?;
+        self.apps
+            .enter(appid, |app| app.sockets[index].local = Some(local))
+            .map_err(ErrorCode::from)
+    }
+
+    /// Unbind the socket if it is bound.
+    fn unbind(&self, appid: ProcessId, index: usize) -> Result<(), ErrorCode> {
+        let socket = self
+            .apps
+            .enter(appid, |app| {
+                let socket = app.sockets[index];
+                app.sockets[index].local = None;
+                socket
+            })
+            .map_err(ErrorCode::from)?;
+        match (
+            socket.protocol.and_then(|p| self.transport(p)),
+            socket.local,
+        ) {
+            (Some(transport), Some(local)) => transport.unbind(local),
+            _ => Ok(()),
+        }
+    }
+
+    fn connect(&self, appid: ProcessId, index: usize) -> Result<(), ErrorCode> {
+        let (_, remote) = self.socket_endpoint(appid, index)?;
+        self.apps
+            .enter(appid, |app| app.sockets[index].remote = Some(remote))
+            .map_err(ErrorCode::from)
+    }
+
+    fn close(&self, appid: ProcessId, index: usize) -> Result<(), ErrorCode> {
+        if index >= MAX_SOCKETS {
+            return Err(ErrorCode::INVAL);
+        }
+        let result = self.unbind(appid, index);
+        self.apps
+            .enter(appid, |app| {
+                app.sockets[index] = Socket::default();
+                if app.pending_tx.map_or(false, |(socket, _)| socket == index) {
+                    app.pending_tx = None;
+                }
+            })
+            .map_err(ErrorCode::from)?;
+        result
+    }
+
+    /// Queue a send on a socket, to `remote` or else to where it is
+    /// connected.
+    fn queue_send(
+        &self,
+        appid: ProcessId,
+        index: usize,
+        remote: Option<Endpoint>,
+    ) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(appid, |app| {
+                if app.pending_tx.is_some() {
+                    return Err(ErrorCode::BUSY);
+                }
+                let socket = app.sockets.get(index).ok_or(ErrorCode::INVAL)?;
+                if socket.protocol.is_none() {
+                    return Err(ErrorCode::INVAL);
+                }
+                if socket.local.is_none() {
+                    return Err(ErrorCode::RESERVE);
+                }
+                let remote = remote.or(socket.remote).ok_or(ErrorCode::INVAL)?;
+                app.pending_tx = Some((index, remote));
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+        self.send_next();
+        Ok(())
+    }
+
+    /// Start the next send waiting, if the driver is idle.
+    fn send_next(&self) {
+        while self.current.get().is_none() {
+            let next = self.apps.iter().find_map(|app| {
+                let appid = app.processid();
+                app.enter(|app| app.pending_tx.take().map(|tx| (appid, tx)))
+            });
+            let (appid, (index, remote)) = match next {
+                Some(next) => next,
+                None => return,
+            };
+            if let Err(e) = self.send(appid, index, remote) {
+                let _ = self.apps.enter(appid, |app| {
+                    app.tx_callback
+                        .schedule(index, kernel::into_statuscode(Err(e)), 0);
+                });
+            }
+        }
+    }
+
+    fn send(&self, appid: ProcessId, index: usize, remote: Endpoint) -> Result<(), ErrorCode> {
+        let (transport, local) = self
+            .apps
+            .enter(appid, |app| {
+                let socket = app.sockets[index];
+                let protocol = socket.protocol.ok_or(ErrorCode::INVAL)?;
+                let local = socket.local.ok_or(ErrorCode::RESERVE)?;
+                let transport = self.transport(protocol).ok_or(ErrorCode::NOSUPPORT)?;
+                Ok::<_, ErrorCode>((transport, local))
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+
+        let mut buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        let copied = self
+            .apps
+            .enter(appid, |app| {
+                app.tx_buffer.map_or(Err(ErrorCode::RESERVE), |payload| {
+                    let len = payload.len();
+                    if len > buffer.len() {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    buffer[..len].copy_from_slice(payload);
+                    Ok(len)
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        match copied {
+            Ok(len) => buffer.slice(0..len),
+            Err(e) => {
+                self.buffer.replace(buffer);
+                return Err(e);
+            }
+        }
+
+        match transport.send(local, remote, buffer) {
+            Ok(()) => {
+                self.current.set(Some((appid, index)));
+                Ok(())
+            }
+            Err((e, mut buffer)) => {
+                buffer.reset();
+                self.buffer.replace(buffer);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl TransportClient for SocketDriver<'_> {
+    fn send_done(&self, result: Result<(), ErrorCode>, mut payload: LeasableBuffer<'static, u8>) {
+        payload.reset();
+        self.buffer.replace(payload);
+        if let Some((appid, index)) = self.current.take() {
+            let _ = self.apps.enter(appid, |app| {
+                app.tx_callback
+                    .schedule(index, kernel::into_statuscode(result), 0);
+            });
+        }
+        self.send_next();
+    }
+
+    fn receive(&self, protocol: Protocol, local: Endpoint, remote: Endpoint, payload: &[u8]) {
+        self.apps.each(|_, app| {
+            let index = app.sockets.iter().position(|socket| {
+                socket.protocol == Some(protocol) && socket.local == Some(local)
+            });
+            if let Some(index) = index {
+                let len = app.rx_buffer.mut_map_or(0, |buffer| {
+                    let len = cmp::min(payload.len(), buffer.len());
+                    buffer[..len].copy_from_slice(&payload[..len]);
+                    len
+                });
+                app.source_buffer
+                    .mut_map_or((), |buffer| remote.encode(buffer));
+                app.rx_callback.schedule(index, len, 0);
+            }
+        });
+    }
+}
+
+impl Driver for SocketDriver<'_> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Payload to send
+    /// - `1`: Endpoint to bind, connect or send to
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.tx_buffer, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.endpoint_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup buffers to receive into.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Received payload
+    /// - `1`: Endpoint the payload came from
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.rx_buffer, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.source_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Received callback
+    /// - `1`: Sent callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.rx_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.tx_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Socket control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the protocols supported.
+    /// - `1`: Open a socket
+    /// - `2`: Bind a socket
+    /// - `3`: Connect a socket
+    /// - `4`: Send on a connected socket
+    /// - `5`: Send to an endpoint
+    /// - `6`: Close a socket
+    /// - `7`: Get the largest payload
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        let result = match cmd_num {
+            0 => {
+                let protocols = self
+                    .transports
+                    .iter()
+                    .fold(0, |mask, transport| mask | 1 << transport.protocol() as u32);
+                return CommandReturn::success_u32(protocols);
+            }
+            1 => {
+                return match self.open(appid, arg1) {
+                    Ok(index) => CommandReturn::success_u32(index),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+            2 => self.bind(appid, arg1),
+            3 => self.connect(appid, arg1),
+            4 => self.queue_send(appid, arg1, None),
+            5 => self
+                .socket_endpoint(appid, arg1)
+                .and_then(|(_, remote)| self.queue_send(appid, arg1, Some(remote))),
+            6 => self.close(appid, arg1),
+            7 => return CommandReturn::success_u32(self.max_payload() as u32),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match result {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
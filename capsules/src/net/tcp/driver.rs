@@ -0,0 +1,838 @@
+//! Minimal TCP userspace interface, for talking to TCP-only services such
+//! as MQTT brokers over 6LoWPAN networks.
+//!
+//! Each process can have one connection, which it opens actively; there is
+//! no listening. The driver is built for low-rate links rather than for
+//! throughput:
+//!
+//! - One segment is in flight per connection, and is retransmitted after a
+//!   timeout that doubles each time, until the connection is given up.
+//! - The receive window is the free space of the process's buffer, and only
+//!   in-order data is accepted.
+//! - No options are sent, so there is no SACK or window scaling, and those
+//!   received are ignored.
+//!
+//! Segments are at most as long as the buffer the driver is given, which
+//! should fit in an IPv6 packet over 6LoWPAN. Segments for no connection
+//! are dropped without a reset.
+//!
+//! Usage
+//! -----
+//!
+//! The driver sends with its own `IP6SendStruct`, and receives the TCP
+//! packets from the IPv6 receiver:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let tcp_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let tcp_driver = static_init!(
+//!     capsules::net::tcp::TCPDriver<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     capsules::net::tcp::TCPDriver::new(
+//!         tcp_send,
+//!         tcp_alarm,
+//!         LeasableBuffer::new(&mut TCP_BUF),
+//!         net_cap,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! tcp_send.set_client(tcp_driver);
+//! tcp_alarm.set_alarm_client(tcp_driver);
+//! ip_receive.set_protocol_client(ip6_nh::TCP, tcp_driver);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: the data to send.
+//! - Read-only `1`: the remote endpoint: its 16 byte IPv6 address, followed
+//!   by its port as a little endian `u16`.
+//! - Read-write `0`: the buffer received data is appended to.
+//!
+//! ### Subscribe
+//!
+//! - `0`: connection state changed. The callback gets the new state and,
+//!   when the connection closed, whether it closed normally: `CANCEL` if it
+//!   was reset and `NOACK` if the remote stopped answering.
+//! - `1`: data received. The callback gets the number of bytes in the
+//!   receive buffer.
+//! - `2`: data sent. The callback gets the number of bytes sent, once all
+//!   of them are acknowledged.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Connect to the remote endpoint allowed, from local port `arg1`,
+//!   or from an ephemeral port if `arg1` is `0`.
+//! - `2`: Send the first `arg1` bytes of the data allowed. Returns `BUSY`
+//!   until the previous send is acknowledged.
+//! - `3`: Consume the first `arg1` bytes of the receive buffer, which moves
+//!   the rest to its start and opens the window.
+//! - `4`: Close the connection, once the data sent is acknowledged.
+//! - `5`: Abort the connection, which resets it.
+//! - `6`: Get the connection state.
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::mem;
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, TransportHeader};
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::tcp::{tcp_flags, TCPHeader};
+use kernel::common::cells::MapCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Tcp as usize;
+
+/// Period of the connection timers.
+const TICK_MS: u32 = 500;
+/// First retransmission timeout, in ticks.
+const INITIAL_RTO: u8 = 6;
+/// Longest retransmission timeout, in ticks.
+const MAX_RTO: u8 = 120;
+/// Retransmissions of a segment before the connection is given up.
+const MAX_RETRANSMISSIONS: u8 = 5;
+/// Ticks a closed connection stays in TIME-WAIT.
+const TIME_WAIT: u8 = 8;
+/// First port of the ephemeral range.
+const EPHEMERAL_PORT_MIN: u16 = 49152;
+
+/// Length of the remote endpoint in the allowed buffer.
+const ENDPOINT_LEN: usize = 18;
+
+/// Connection states, from RFC 793, without those of passive opens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Closed = 0,
+    SynSent = 1,
+    Established = 2,
+    FinWait1 = 3,
+    FinWait2 = 4,
+    CloseWait = 5,
+    Closing = 6,
+    LastAck = 7,
+    TimeWait = 8,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State::Closed
+    }
+}
+
+/// Whether sequence number `a` is before `b`.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+#[derive(Default)]
+pub struct App {
+    state_callback: Upcall,
+    rx_callback: Upcall,
+    tx_callback: Upcall,
+    tx_buffer: ReadOnlyAppSlice,
+    endpoint_buffer: ReadOnlyAppSlice,
+    rx_buffer: ReadWriteAppSlice,
+    state: State,
+    local_port: u16,
+    remote_addr: [u8; 16],
+    remote_port: u16,
+    /// Oldest unacknowledged sequence number.
+    snd_una: u32,
+    /// Next sequence number to send.
+    snd_nxt: u32,
+    /// Window the remote last advertised.
+    snd_wnd: u16,
+    /// Next sequence number expected.
+    rcv_nxt: u32,
+    /// Bytes of the allowed data to send.
+    tx_len: usize,
+    /// Bytes of those acknowledged.
+    tx_acked: usize,
+    /// Bytes received and not yet consumed.
+    rx_len: usize,
+    /// The process closed the connection, so a FIN follows the data.
+    fin_queued: bool,
+    /// The last sequence number sent is a FIN.
+    fin_sent: bool,
+    ack_pending: bool,
+    reset_pending: bool,
+    /// The segment in flight is to be sent again.
+    retransmit: bool,
+    /// Ticks until the segment in flight is retransmitted or, in
+    /// TIME-WAIT, until the connection closes. `0` when stopped.
+    timer: u8,
+    rto: u8,
+    retransmissions: u8,
+}
+
+impl App {
+    fn in_flight(&self) -> bool {
+        self.snd_una != self.snd_nxt
+    }
+
+    fn window(&self) -> u16 {
+        let free = self.rx_buffer.len().saturating_sub(self.rx_len);
+        cmp::min(free, u16::MAX as usize) as u16
+    }
+
+    fn set_state(&mut self, state: State, result: Result<(), ErrorCode>) {
+        self.state = state;
+        self.state_callback
+            .schedule(state as usize, kernel::into_statuscode(result), 0);
+    }
+
+    fn close(&mut self, result: Result<(), ErrorCode>) {
+        self.timer = 0;
+        self.fin_queued = false;
+        self.ack_pending = false;
+        self.retransmit = false;
+        self.tx_len = 0;
+        self.set_state(State::Closed, result);
+    }
+
+    fn start_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.rto;
+        }
+    }
+
+    /// The next segment to send, with its payload copied to `buffer`.
+    fn next_segment(&mut self, buffer: &mut [u8]) -> Option<(TCPHeader, usize)> {
+        let mut header = TCPHeader::new();
+        header.set_src_port(self.local_port);
+        header.set_dst_port(self.remote_port);
+        header.set_ack_num(self.rcv_nxt);
+        header.set_window(self.window());
+
+        if self.reset_pending {
+            self.reset_pending = false;
+            header.set_seq_num(self.snd_nxt);
+            header.set_flags(tcp_flags::RST | tcp_flags::ACK);
+            return Some((header, 0));
+        }
+
+        let sending = match self.state {
+            State::SynSent => {
+                if !self.in_flight() || self.retransmit {
+                    self.retransmit = false;
+                    self.snd_nxt = self.snd_una.wrapping_add(1);
+                    header.set_seq_num(self.snd_una);
+                    header.set_ack_num(0);
+                    header.set_flags(tcp_flags::SYN);
+                    self.start_timer();
+                    return Some((header, 0));
+                }
+                false
+            }
+            State::Established | State::CloseWait => true,
+            State::FinWait1 | State::Closing | State::LastAck => true,
+            _ => false,
+        };
+
+        if sending && (!self.in_flight() || self.retransmit) {
+            self.retransmit = false;
+            // An empty remote window is probed with one byte, which the
+            // retransmission timer repeats.
+            let window = cmp::max(self.snd_wnd, 1) as usize;
+            let remaining = self.tx_len - self.tx_acked;
+            let len = cmp::min(cmp::min(remaining, buffer.len()), window);
+            if len > 0 {
+                let offset = self.tx_acked;
+                let copied = self.tx_buffer.map_or(false, |data| {
+                    data.get(offset..offset + len).map_or(false, |data| {
+                        buffer[..len].copy_from_slice(data);
+                        true
+                    })
+                });
+                if copied {
+                    self.ack_pending = false;
+                    self.fin_sent = false;
+                    self.snd_nxt = self.snd_una.wrapping_add(len as u32);
+                    header.set_seq_num(self.snd_una);
+                    header.set_flags(tcp_flags::ACK | tcp_flags::PSH);
+                    self.start_timer();
+                    return Some((header, len));
+                }
+            } else if self.fin_queued {
+                self.ack_pending = false;
+                self.fin_sent = true;
+                self.snd_nxt = self.snd_una.wrapping_add(1);
+                header.set_seq_num(self.snd_una);
+                header.set_flags(tcp_flags::FIN | tcp_flags::ACK);
+                self.start_timer();
+                return Some((header, 0));
+            }
+        }
+
+        if self.ack_pending {
+            self.ack_pending = false;
+            header.set_seq_num(self.snd_nxt);
+            header.set_flags(tcp_flags::ACK);
+            return Some((header, 0));
+        }
+        None
+    }
+
+    /// Process a segment of the connection.
+    fn receive(&mut self, header: &TCPHeader, payload: &[u8]) {
+        if header.has_flags(tcp_flags::RST) {
+            // Only resets for the segment in flight or in the window are
+            // accepted, so they cannot be spoofed blindly.
+            let valid = if self.state == State::SynSent {
+                header.has_flags(tcp_flags::ACK) && header.get_ack_num() == self.snd_nxt
+            } else {
+                header.get_seq_num() == self.rcv_nxt
+            };
+            if valid {
+                self.close(Err(ErrorCode::CANCEL));
+            }
+            return;
+        }
+
+        if self.state == State::SynSent {
+            if header.has_flags(tcp_flags::SYN | tcp_flags::ACK)
+                && header.get_ack_num() == self.snd_nxt
+            {
+                self.snd_una = self.snd_nxt;
+                self.snd_wnd = header.get_window();
+                self.rcv_nxt = header.get_seq_num().wrapping_add(1);
+                self.timer = 0;
+                self.retransmissions = 0;
+                self.rto = INITIAL_RTO;
+                self.ack_pending = true;
+                self.set_state(State::Established, Ok(()));
+            }
+            return;
+        }
+
+        if !header.has_flags(tcp_flags::ACK) {
+            return;
+        }
+        let ack = header.get_ack_num();
+        if seq_lt(self.snd_una, ack) && seq_le(ack, self.snd_nxt) {
+            let mut acked = ack.wrapping_sub(self.snd_una) as usize;
+            let fin_acked = self.fin_sent && ack == self.snd_nxt;
+            if fin_acked {
+                acked -= 1;
+            }
+            self.snd_una = ack;
+            self.tx_acked = cmp::min(self.tx_acked + acked, self.tx_len);
+            self.retransmit = false;
+            self.retransmissions = 0;
+            self.rto = INITIAL_RTO;
+            self.timer = 0;
+            if self.tx_len > 0 && self.tx_acked == self.tx_len {
+                self.tx_callback.schedule(self.tx_len, 0, 0);
+                self.tx_len = 0;
+                self.tx_acked = 0;
+            }
+            if fin_acked {
+                self.fin_queued = false;
+                match self.state {
+                    State::FinWait1 => self.set_state(State::FinWait2, Ok(())),
+                    State::Closing => {
+                        self.timer = TIME_WAIT;
+                        self.set_state(State::TimeWait, Ok(()));
+                    }
+                    State::LastAck => {
+                        self.close(Ok(()));
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.snd_wnd = header.get_window();
+
+        let receiving = match self.state {
+            State::Established | State::FinWait1 | State::FinWait2 => true,
+            _ => false,
+        };
+        let seq = header.get_seq_num();
+        if !payload.is_empty() {
+            // Out of order data is dropped, and the duplicate ACK tells the
+            // remote what is expected.
+            self.ack_pending = true;
+            if receiving && seq == self.rcv_nxt {
+                let offset = self.rx_len;
+                let len = self.rx_buffer.mut_map_or(0, |buffer| {
+                    let len = cmp::min(payload.len(), buffer.len().saturating_sub(offset));
+                    buffer[offset..offset + len].copy_from_slice(&payload[..len]);
+                    len
+                });
+                if len > 0 {
+                    self.rx_len += len;
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+                    self.rx_callback.schedule(self.rx_len, 0, 0);
+                }
+            }
+        }
+
+        if header.has_flags(tcp_flags::FIN)
+            && receiving
+            && seq.wrapping_add(payload.len() as u32) == self.rcv_nxt
+        {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.ack_pending = true;
+            match self.state {
+                State::Established => self.set_state(State::CloseWait, Ok(())),
+                State::FinWait1 => self.set_state(State::Closing, Ok(())),
+                _ => {
+                    self.timer = TIME_WAIT;
+                    self.set_state(State::TimeWait, Ok(()));
+                }
+            }
+        } else if header.has_flags(tcp_flags::FIN) {
+            // A retransmitted FIN, whose ACK was lost.
+            self.ack_pending = true;
+        }
+    }
+
+    /// Advance the connection timer by one tick.
+    fn tick(&mut self) {
+        if self.timer == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        if self.state == State::TimeWait {
+            self.close(Ok(()));
+        } else if self.in_flight() {
+            if self.retransmissions == MAX_RETRANSMISSIONS {
+                self.reset_pending = true;
+                self.close(Err(ErrorCode::NOACK));
+            } else {
+                self.retransmissions += 1;
+                self.rto = cmp::min(self.rto.saturating_mul(2), MAX_RTO);
+                self.retransmit = true;
+            }
+        }
+    }
+}
+
+pub struct TCPDriver<'a, A: Alarm<'a>> {
+    sender: &'a dyn IP6Sender<'a>,
+    alarm: &'a A,
+    buffer: MapCell<LeasableBuffer<'static, u8>>,
+    /// A segment is being sent.
+    sending: Cell<bool>,
+    net_cap: &'static NetworkCapability,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> TCPDriver<'a, A> {
+    pub fn new(
+        sender: &'a dyn IP6Sender<'a>,
+        alarm: &'a A,
+        buffer: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+        grant: Grant<App>,
+    ) -> TCPDriver<'a, A> {
+        TCPDriver {
+            sender: sender,
+            alarm: alarm,
+            buffer: MapCell::new(buffer),
+            sending: Cell::new(false),
+            net_cap: net_cap,
+            apps: grant,
+        }
+    }
+
+    /// Whether a connection other than that of `appid` uses `port`.
+    fn port_used(&self, appid: ProcessId, port: u16) -> bool {
+        self.apps.iter().any(|app| {
+            app.processid() != appid
+                && app.enter(|app| app.state != State::Closed && app.local_port == port)
+        })
+    }
+
+    fn ephemeral_port(&self, appid: ProcessId) -> Option<u16> {
+        let range = u16::MAX - EPHEMERAL_PORT_MIN + 1;
+        let start = (self.alarm.now().into_u32() % range as u32) as u16;
+        (0..range)
+            .map(|i| EPHEMERAL_PORT_MIN + (start + i) % range)
+            .find(|port| !self.port_used(appid, *port))
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    /// Send the next segment of any connection, unless one is being sent.
+    fn send_next(&self) {
+        if self.sending.get() {
+            return;
+        }
+        self.buffer.take().map(|mut buffer| {
+            let segment = self.apps.iter().find_map(|app| {
+                app.enter(|app| {
+                    let remote = IPAddr(app.remote_addr);
+                    app.next_segment(&mut buffer[..])
+                        .map(|(header, len)| (remote, header, len, app.timer > 0))
+                })
+            });
+            if let Some((remote, header, len, timed)) = segment {
+                buffer.slice(0..len);
+                let result = self.sender.send_to(
+                    remote,
+                    TransportHeader::TCP(header),
+                    &buffer,
+                    self.net_cap,
+                );
+                buffer.reset();
+                // A segment that fails to send is retransmitted, or is an
+                // ACK that the next segment received causes again.
+                self.sending.set(result.is_ok());
+                if timed {
+                    self.start_timer();
+                }
+            }
+            self.buffer.replace(buffer);
+        });
+    }
+
+    fn connect(&self, appid: ProcessId, port: usize) -> Result<(), ErrorCode> {
+        if port > u16::MAX as usize {
+            return Err(ErrorCode::INVAL);
+        }
+        let port = match port {
+            0 => self.ephemeral_port(appid).ok_or(ErrorCode::NOMEM)?,
+            port => port as u16,
+        };
+        if self.port_used(appid, port) {
+            return Err(ErrorCode::BUSY);
+        }
+        let iss = self.alarm.now().into_u32().wrapping_mul(2654435761);
+        self.apps
+            .enter(appid, |app| {
+                if app.state != State::Closed {
+                    return Err(ErrorCode::ALREADY);
+                }
+                let endpoint = app
+                    .endpoint_buffer
+                    .map_or(None, |data| {
+                        data.get(..ENDPOINT_LEN).map(|data| {
+                            let mut addr = [0; 16];
+                            addr.copy_from_slice(&data[..16]);
+                            (
+                                addr,
+                                u16::from_le_bytes(data[16..].try_into().unwrap_or([0; 2])),
+                            )
+                        })
+                    })
+                    .ok_or(ErrorCode::INVAL)?;
+                if endpoint.1 == 0 {
+                    return Err(ErrorCode::INVAL);
+                }
+                app.local_port = port;
+                app.remote_addr = endpoint.0;
+                app.remote_port = endpoint.1;
+                app.snd_una = iss;
+                app.snd_nxt = iss;
+                app.snd_wnd = 0;
+                app.rcv_nxt = 0;
+                app.tx_len = 0;
+                app.tx_acked = 0;
+                app.rx_len = 0;
+                app.fin_queued = false;
+                app.fin_sent = false;
+                app.ack_pending = false;
+                app.reset_pending = false;
+                app.retransmit = false;
+                app.timer = 0;
+                app.rto = INITIAL_RTO;
+                app.retransmissions = 0;
+                app.set_state(State::SynSent, Ok(()));
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn send(&self, appid: ProcessId, len: usize) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(appid, |app| {
+                match app.state {
+                    State::Established | State::CloseWait => {}
+                    _ => return Err(ErrorCode::OFF),
+                }
+                if app.tx_len > 0 {
+                    return Err(ErrorCode::BUSY);
+                }
+                if len == 0 || len > app.tx_buffer.len() {
+                    return Err(ErrorCode::SIZE);
+                }
+                app.tx_len = len;
+                app.tx_acked = 0;
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn consume(&self, appid: ProcessId, len: usize) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(appid, |app| {
+                if len > app.rx_len {
+                    return Err(ErrorCode::SIZE);
+                }
+                let rx_len = app.rx_len;
+                app.rx_buffer
+                    .mut_map_or((), |buffer| buffer.copy_within(len..rx_len, 0));
+                app.rx_len -= len;
+                // Tell the remote the window opened.
+                if len > 0 && app.state != State::Closed {
+                    app.ack_pending = true;
+                }
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn close(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(appid, |app| {
+                match app.state {
+                    State::SynSent => app.close(Ok(())),
+                    State::Established => {
+                        app.fin_queued = true;
+                        app.set_state(State::FinWait1, Ok(()));
+                    }
+                    State::CloseWait => {
+                        app.fin_queued = true;
+                        app.set_state(State::LastAck, Ok(()));
+                    }
+                    _ => return Err(ErrorCode::ALREADY),
+                }
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn abort(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(appid, |app| {
+                match app.state {
+                    State::Closed => return Err(ErrorCode::ALREADY),
+                    State::SynSent | State::TimeWait => {}
+                    _ => app.reset_pending = true,
+                }
+                app.close(Err(ErrorCode::CANCEL));
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for TCPDriver<'a, A> {
+    fn alarm(&self) {
+        let running = Cell::new(false);
+        self.apps.each(|_, app| {
+            app.tick();
+            if app.timer > 0 {
+                running.set(true);
+            }
+        });
+        if running.get() {
+            self.start_timer();
+        }
+        self.send_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6SendClient for TCPDriver<'a, A> {
+    fn send_done(&self, _result: Result<(), ErrorCode>) {
+        self.sending.set(false);
+        self.send_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6RecvClient for TCPDriver<'a, A> {
+    fn receive(&self, ip6_header: IP6Header, payload: &[u8]) {
+        let (offset, header) = match TCPHeader::decode(payload).done() {
+            Some(decoded) => decoded,
+            None => return,
+        };
+        let remote = ip6_header.get_src_addr();
+        self.apps.each(|_, app| {
+            if app.state != State::Closed
+                && app.local_port == header.get_dst_port()
+                && app.remote_port == header.get_src_port()
+                && IPAddr(app.remote_addr) == remote
+            {
+                app.receive(&header, &payload[offset..]);
+            }
+        });
+        self.send_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for TCPDriver<'a, A> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Data to send
+    /// - `1`: Remote endpoint
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    // The data of a send must stay until it is acknowledged.
+                    if app.tx_len > 0 {
+                        return Err(ErrorCode::BUSY);
+                    }
+                    mem::swap(&mut app.tx_buffer, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.endpoint_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup the receive buffer.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer received data is appended to
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    // The data buffered would be lost with the buffer.
+                    if app.rx_len > 0 {
+                        return Err(ErrorCode::BUSY);
+                    }
+                    mem::swap(&mut app.rx_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Connection state changed
+    /// - `1`: Data received
+    /// - `2`: Data sent
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.state_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.rx_callback, &mut callback);
+                    Ok(())
+                }
+                2 => {
+                    mem::swap(&mut app.tx_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Connection control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Connect from local port `arg1`, or an ephemeral port if `0`.
+    /// - `2`: Send `arg1` bytes.
+    /// - `3`: Consume `arg1` received bytes.
+    /// - `4`: Close.
+    /// - `5`: Abort.
+    /// - `6`: Get the connection state.
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        let result = match cmd_num {
+            0 => Ok(()),
+            1 => self.connect(appid, arg1),
+            2 => self.send(appid, arg1),
+            3 => self.consume(appid, arg1),
+            4 => self.close(appid),
+            5 => self.abort(appid),
+            6 => {
+                return self
+                    .apps
+                    .enter(appid, |app| CommandReturn::success_u32(app.state as u32))
+                    .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+            }
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        if result.is_ok() {
+            self.send_next();
+        }
+        match result {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
@@ -16,7 +16,6 @@ use crate::net::udp::udp_recv::UDPRecvClient;
 use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
 use crate::net::util::host_slice_to_u16;
 use core::cell::Cell;
-use core::convert::TryFrom;
 use core::convert::TryInto;
 use core::mem::size_of;
 use core::{cmp, mem};
@@ -245,12 +244,7 @@ impl<'a> UDPDriver<'a> {
     fn do_next_tx_immediate(&self, new_appid: ProcessId) -> Result<u32, ErrorCode> {
         self.get_next_tx_if_idle().map_or(Ok(0), |appid| {
             if appid == new_appid {
-                let sync_result = self.perform_tx_sync(appid);
-                if sync_result == Ok(()) {
-                    Ok(1) //Indicates packet passed to radio
-                } else {
-                    Err(ErrorCode::try_from(sync_result).unwrap())
-                }
+                self.perform_tx_sync(appid).map(|()| 1) //1 indicates packet passed to radio
             } else {
                 self.perform_tx_async(appid);
                 Ok(0) //indicates async transmission
@@ -593,7 +587,9 @@ impl<'a> Driver for UDPDriver<'a> {
                             }
                         })
                     }
-                    Err(retcode) => CommandReturn::failure(retcode.try_into().unwrap()),
+                    Err(retcode) => {
+                        CommandReturn::failure(retcode.try_into().unwrap_or(ErrorCode::FAIL))
+                    }
                 }
             }
             4 => CommandReturn::success_u32(self.max_tx_pyld_len as u32),
@@ -673,9 +669,7 @@ impl<'a> PortQuery for UDPDriver<'a> {
         let mut port_bound = false;
         for app in self.apps.iter() {
             app.enter(|other_app| {
-                if other_app.bound_port.is_some() {
-                    let other_addr_opt = other_app.bound_port.clone();
-                    let other_addr = other_addr_opt.expect("Missing other_addr");
+                if let Some(other_addr) = &other_app.bound_port {
                     if other_addr.port == port {
                         port_bound = true;
                     }
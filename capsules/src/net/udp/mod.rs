@@ -2,6 +2,7 @@ pub mod driver;
 pub mod udp_port_table;
 pub mod udp_recv;
 pub mod udp_send;
+pub mod udp_socket;
 
 pub use self::driver::UDPDriver;
 pub use self::driver::DRIVER_NUM;
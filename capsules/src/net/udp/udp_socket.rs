@@ -0,0 +1,226 @@
+//! UDP transport of the socket driver.
+//!
+//! Binds the ports of the sockets in the kernel port table, so they are
+//! exclusive with those of capsules and of the UDP driver, and receives on
+//! each with its own `UDPReceiver`. Sockets bind to a port on all
+//! interfaces, so the address of the local endpoint must be `::`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let udp_socket_receivers = static_init!(
+//!     [UDPReceiver<'static>; 4],
+//!     [UDPReceiver::new(), UDPReceiver::new(), UDPReceiver::new(), UDPReceiver::new()]
+//! );
+//! let udp_socket_transport = static_init!(
+//!     capsules::net::udp::udp_socket::UDPSocketTransport<'static>,
+//!     capsules::net::udp::udp_socket::UDPSocketTransport::new(
+//!         udp_socket_send,
+//!         udp_socket_receivers,
+//!         udp_port_table,
+//!         MAX_PAYLOAD_LEN,
+//!         net_cap,
+//!     )
+//! );
+//! udp_socket_send.set_client(udp_socket_transport);
+//! for receiver in udp_socket_receivers.iter() {
+//!     receiver.set_client(udp_socket_transport);
+//!     udp_recv_mux.add_client(receiver);
+//! }
+//! ```
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::socket::{Endpoint, Protocol, Transport, TransportClient};
+use crate::net::udp::udp_port_table::{UdpPortBindingTx, UdpPortManager};
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ErrorCode;
+
+/// Ports the transport can bind at once, at most one per receiver.
+pub const MAX_BINDINGS: usize = 8;
+
+pub struct UDPSocketTransport<'a> {
+    sender: &'a dyn UDPSender<'a>,
+    receivers: &'a [UDPReceiver<'a>],
+    /// Send bindings of the ports bound, at the index of their receiver.
+    send_bindings: MapCell<[Option<UdpPortBindingTx>; MAX_BINDINGS]>,
+    port_table: &'static UdpPortManager,
+    max_payload: usize,
+    net_cap: &'static NetworkCapability,
+    client: OptionalCell<&'a dyn TransportClient>,
+}
+
+impl<'a> UDPSocketTransport<'a> {
+    pub fn new(
+        sender: &'a dyn UDPSender<'a>,
+        receivers: &'a [UDPReceiver<'a>],
+        port_table: &'static UdpPortManager,
+        max_payload: usize,
+        net_cap: &'static NetworkCapability,
+    ) -> UDPSocketTransport<'a> {
+        UDPSocketTransport {
+            sender: sender,
+            receivers: receivers,
+            send_bindings: MapCell::new(Default::default()),
+            port_table: port_table,
+            max_payload: max_payload,
+            net_cap: net_cap,
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Index of the binding of `port`.
+    fn find(&self, port: u16) -> Option<usize> {
+        self.send_bindings.map_or(None, |bindings| {
+            bindings.iter().position(|binding| {
+                binding
+                    .as_ref()
+                    .map_or(false, |binding| binding.get_port() == port)
+            })
+        })
+    }
+
+    fn endpoint(port: u16) -> Endpoint {
+        Endpoint {
+            addr: [0; 16],
+            port: port,
+        }
+    }
+}
+
+impl<'a> Transport<'a> for UDPSocketTransport<'a> {
+    fn protocol(&self) -> Protocol {
+        Protocol::Udp
+    }
+
+    fn set_client(&self, client: &'a dyn TransportClient) {
+        self.client.set(client);
+    }
+
+    fn bind(&self, local: Endpoint) -> Result<(), ErrorCode> {
+        if local.addr != [0; 16] || local.port == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.find(local.port).is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let count = core::cmp::min(self.receivers.len(), MAX_BINDINGS);
+        let index = (0..count)
+            .find(|i| !self.receivers[*i].is_bound())
+            .ok_or(ErrorCode::NOMEM)?;
+        let socket = self
+            .port_table
+            .create_socket()
+            .map_err(|_| ErrorCode::NOMEM)?;
+        let (send_binding, receive_binding) = self
+            .port_table
+            .bind(socket, local.port, self.net_cap)
+            .map_err(|_| ErrorCode::BUSY)?;
+        self.receivers[index].set_binding(receive_binding);
+        self.send_bindings
+            .map(|bindings| bindings[index] = Some(send_binding));
+        Ok(())
+    }
+
+    fn unbind(&self, local: Endpoint) -> Result<(), ErrorCode> {
+        let index = self.find(local.port).ok_or(ErrorCode::INVAL)?;
+        let send_binding = self
+            .send_bindings
+            .map_or(None, |bindings| bindings[index].take())
+            .ok_or(ErrorCode::FAIL)?;
+        let receive_binding = match self.receivers[index].get_binding() {
+            Some(binding) => binding,
+            None => {
+                self.send_bindings
+                    .map(|bindings| bindings[index] = Some(send_binding));
+                return Err(ErrorCode::FAIL);
+            }
+        };
+        // Dropping the socket frees its slot in the port table.
+        match self.port_table.unbind(send_binding, receive_binding) {
+            Ok(_socket) => Ok(()),
+            Err((send_binding, receive_binding)) => {
+                self.receivers[index].set_binding(receive_binding);
+                self.send_bindings
+                    .map(|bindings| bindings[index] = Some(send_binding));
+                Err(ErrorCode::FAIL)
+            }
+        }
+    }
+
+    fn unbind_unused(&self, in_use: &dyn Fn(Endpoint) -> bool) {
+        for index in 0..MAX_BINDINGS {
+            let port = self.send_bindings.map_or(None, |bindings| {
+                bindings[index].as_ref().map(|binding| binding.get_port())
+            });
+            if let Some(port) = port {
+                if !in_use(Self::endpoint(port)) {
+                    let _ = self.unbind(Self::endpoint(port));
+                }
+            }
+        }
+    }
+
+    fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    fn send(
+        &self,
+        local: Endpoint,
+        remote: Endpoint,
+        payload: LeasableBuffer<'static, u8>,
+    ) -> Result<(), (ErrorCode, LeasableBuffer<'static, u8>)> {
+        if payload.len() > self.max_payload {
+            return Err((ErrorCode::SIZE, payload));
+        }
+        let index = match self.find(local.port) {
+            Some(index) => index,
+            None => return Err((ErrorCode::RESERVE, payload)),
+        };
+        // The sender checks the binding when it queues the datagram, so it
+        // can go back straight away.
+        self.send_bindings.map(|bindings| {
+            bindings[index]
+                .take()
+                .map(|binding| self.sender.set_binding(binding))
+        });
+        let result = self
+            .sender
+            .send_to(IPAddr(remote.addr), remote.port, payload, self.net_cap);
+        let binding = self.sender.get_binding();
+        self.send_bindings.map(|bindings| bindings[index] = binding);
+        result.map_err(|payload| (ErrorCode::FAIL, payload))
+    }
+}
+
+impl<'a> UDPSendClient for UDPSocketTransport<'a> {
+    fn send_done(&self, result: Result<(), ErrorCode>, dgram: LeasableBuffer<'static, u8>) {
+        self.client
+            .map(move |client| client.send_done(result, dgram));
+    }
+}
+
+impl<'a> UDPRecvClient for UDPSocketTransport<'a> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        dst_port: u16,
+        payload: &[u8],
+    ) {
+        let remote = Endpoint {
+            addr: src_addr.0,
+            port: src_port,
+        };
+        self.client
+            .map(|client| client.receive(Protocol::Udp, Self::endpoint(dst_port), remote, payload));
+    }
+}
@@ -0,0 +1,477 @@
+//! Keeps the wall-clock time, set and disciplined from network time sources.
+//!
+//! The capsule counts the ticks of an alarm as a 64-bit number and maps them
+//! to the time of the last synchronization. Two sources are supported:
+//!
+//! - LoRaWAN `DeviceTimeAns` MAC commands, which give the GPS time at the end
+//!   of the uplink that carried the `DeviceTimeReq`. The MAC, in the kernel or
+//!   in a process, passes the answer along with the alarm ticks at which that
+//!   uplink ended.
+//! - IEEE 802.15.4 beacons from coordinators that put the time, in
+//!   microseconds since 1970 at the start of the beacon, in the last 8 bytes
+//!   of the beacon payload, little endian. Only beacons secured with a MIC
+//!   are used, so that the time cannot be set by anyone without the network
+//!   key. The capsule must be the receive client of a MAC user.
+//!
+//! When two synchronizations are far enough apart, the difference between
+//! the time the capsule predicted and the one received is the drift of the
+//! alarm's clock, which the capsule learns and corrects for from then on.
+//! The estimated error is the error of the last source plus the worst drift
+//! the clock may have accumulated since, which is far lower once it is
+//! calibrated.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let time_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let network_time = static_init!(
+//!     capsules::network_time::NetworkTime<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     capsules::network_time::NetworkTime::new(
+//!         board_kernel,
+//!         time_alarm,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! time_alarm.set_alarm_client(network_time);
+//! network_time.start();
+//! time_mac_user.set_receive_client(network_time);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: synchronized. The callback gets the source and the estimated error
+//!   in microseconds.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the source of the last synchronization, `0` if there was none,
+//!   `1` for LoRaWAN or `2` for 802.15.4, and its age in seconds.
+//! - `2`: Get the time, in seconds and microseconds since 1970. Returns `OFF`
+//!   before the first synchronization.
+//! - `3`: Get the estimated error in microseconds, and the learnt drift of the
+//!   clock in parts per billion, as an `i32`.
+//! - `4`: Synchronize from a LoRaWAN `DeviceTimeAns`: `arg1` is its GPS
+//!   seconds, and `arg2` the alarm ticks at which the uplink ended. Returns
+//!   `NOSUPPORT` if the caller does not have permission (see the
+//!   `Permissions` TLV in `doc/TockBinaryFormat.md`), as the time is trusted
+//!   by other capsules.
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::mem;
+
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, Upcall};
+
+use crate::ieee802154::device::RxClient;
+use crate::net::ieee802154::{FrameType, Header};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::NetworkTime as usize;
+
+/// The GPS epoch, 1980-01-06, in seconds since 1970.
+const GPS_EPOCH_S: u64 = 315_964_800;
+/// Leap seconds between GPS time and UTC, as of 2017.
+const GPS_LEAP_SECONDS: u64 = 18;
+
+/// LoRaWAN only requires network servers to answer within 100 ms of the
+/// true time.
+const DEVICE_TIME_ERROR_US: u32 = 100_000;
+/// The reception of a beacon is timed to within the processing of the
+/// frame.
+const BEACON_ERROR_US: u32 = 1000;
+/// An 802.15.4 byte takes 32 µs at 250 kbit/s, and a frame is preceded by 6
+/// bytes of preamble, start of frame delimiter and length.
+const BYTE_US: u32 = 32;
+const PHY_HEADER_LEN: u32 = 6;
+
+/// Shortest time between synchronizations to learn the drift from.
+const MIN_CALIBRATION_S: u64 = 600;
+/// Drift of an uncalibrated 32 kHz crystal, over temperature.
+const UNCALIBRATED_DRIFT_PPB: u64 = 50_000;
+/// Drift left once it is calibrated.
+const CALIBRATED_DRIFT_PPB: u64 = 5_000;
+/// Largest drift the capsule will learn.
+const MAX_DRIFT_PPB: i64 = 200_000;
+
+/// Longest time between updates of the 64-bit tick count.
+const MAX_UPDATE_S: u64 = 3600;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Source {
+    LoRaWan = 1,
+    Ieee802154 = 2,
+}
+
+/// `elapsed_us` measured by a clock that drifts by `drift_ppb`, corrected
+/// for the drift.
+fn corrected_us(elapsed_us: u64, drift_ppb: i64) -> i128 {
+    let elapsed_us = elapsed_us as i128;
+    elapsed_us + elapsed_us * drift_ppb as i128 / 1_000_000_000
+}
+
+/// The drift to correct for after a synchronization found the time predicted
+/// with `drift_ppb` off by `offset_us`, `elapsed_us` after the last one. The
+/// remaining offset is drift the correction missed. Move half way towards the
+/// drift it implies, to average out the error of the sources.
+fn learnt_drift_ppb(drift_ppb: i64, offset_us: i128, elapsed_us: u64) -> i64 {
+    let error_ppb = (offset_us * 1_000_000_000 / elapsed_us as i128) as i64;
+    let drift = drift_ppb.saturating_add(error_ppb / 2);
+    cmp::max(cmp::min(drift, MAX_DRIFT_PPB), -MAX_DRIFT_PPB)
+}
+
+/// The error of a synchronization to within `error_us`, `age_us` later.
+fn estimated_error_us(error_us: u32, age_us: u64, calibrated: bool) -> u32 {
+    let drift_ppb = if calibrated {
+        CALIBRATED_DRIFT_PPB
+    } else {
+        UNCALIBRATED_DRIFT_PPB
+    };
+    let drift_us = age_us.saturating_mul(drift_ppb) / 1_000_000_000;
+    (error_us as u64 + drift_us).try_into().unwrap_or(u32::MAX)
+}
+
+/// The time to receive an 802.15.4 frame of `len` bytes.
+fn frame_us(len: usize) -> u32 {
+    (len as u32 + PHY_HEADER_LEN) * BYTE_US
+}
+
+/// Whether a received frame carried a MIC, which the MAC checked against the
+/// network key before passing the frame on.
+fn authenticated(header: &Header) -> bool {
+    header
+        .security
+        .map_or(false, |security| security.level.mic_len() > 0)
+}
+
+#[derive(Clone, Copy)]
+struct Sync {
+    source: Source,
+    /// 64-bit alarm ticks and time, in microseconds since 1970, of the
+    /// synchronization.
+    ticks: u64,
+    time_us: u64,
+    error_us: u32,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+}
+
+pub struct NetworkTime<'a, A: Alarm<'a>> {
+    kernel: &'static Kernel,
+    alarm: &'a A,
+    /// Alarm ticks at the last update of `ticks`.
+    last_now: Cell<A::Ticks>,
+    /// 64-bit count of alarm ticks.
+    ticks: Cell<u64>,
+    sync: Cell<Option<Sync>>,
+    drift_ppb: Cell<i64>,
+    calibrated: Cell<bool>,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> NetworkTime<'a, A> {
+    pub fn new(kernel: &'static Kernel, alarm: &'a A, grant: Grant<App>) -> NetworkTime<'a, A> {
+        NetworkTime {
+            kernel: kernel,
+            alarm: alarm,
+            last_now: Cell::new(A::Ticks::from(0)),
+            ticks: Cell::new(0),
+            sync: Cell::new(None),
+            drift_ppb: Cell::new(0),
+            calibrated: Cell::new(false),
+            apps: grant,
+        }
+    }
+
+    /// Start counting ticks.
+    pub fn start(&self) {
+        self.last_now.set(self.alarm.now());
+        self.set_update_alarm();
+    }
+
+    /// Update the tick count often enough that the alarm's counter cannot
+    /// wrap around unnoticed.
+    fn set_update_alarm(&self) {
+        let frequency = <A::Frequency>::frequency() as u64;
+        let wrap_s = (A::Ticks::max_value().into_u32() as u64 + 1) / frequency;
+        let interval_s = cmp::max(cmp::min(wrap_s / 2, MAX_UPDATE_S), 1);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_seconds(interval_s as u32));
+    }
+
+    /// The 64-bit tick count now.
+    fn now_ticks(&self) -> u64 {
+        let now = self.alarm.now();
+        let elapsed = now.wrapping_sub(self.last_now.get()).into_u32() as u64;
+        self.last_now.set(now);
+        self.ticks.set(self.ticks.get() + elapsed);
+        self.ticks.get()
+    }
+
+    /// The 64-bit tick count at `at`, a moment in the recent past.
+    fn ticks_at(&self, at: A::Ticks) -> u64 {
+        let now = self.alarm.now();
+        let ago = now.wrapping_sub(at).into_u32() as u64;
+        self.now_ticks().saturating_sub(ago)
+    }
+
+    fn ticks_to_us(ticks: u64) -> u64 {
+        (ticks as u128 * 1_000_000 / <A::Frequency>::frequency() as u128) as u64
+    }
+
+    /// The time at 64-bit tick count `ticks`, corrected for the drift.
+    fn time_at(&self, sync: &Sync, ticks: u64) -> u64 {
+        let elapsed_us = Self::ticks_to_us(ticks.saturating_sub(sync.ticks));
+        (sync.time_us as i128 + corrected_us(elapsed_us, self.drift_ppb.get())) as u64
+    }
+
+    /// The time now, in microseconds since 1970.
+    pub fn now_us(&self) -> Option<u64> {
+        let ticks = self.now_ticks();
+        self.sync.get().map(|sync| self.time_at(&sync, ticks))
+    }
+
+    /// The estimated error of `now_us()`.
+    pub fn error_us(&self) -> Option<u32> {
+        let ticks = self.now_ticks();
+        self.sync.get().map(|sync| {
+            let age_us = Self::ticks_to_us(ticks.saturating_sub(sync.ticks));
+            estimated_error_us(sync.error_us, age_us, self.calibrated.get())
+        })
+    }
+
+    /// Set the time to `time_us`, in microseconds since 1970, as it was at
+    /// alarm ticks `at`, to within `error_us`.
+    pub fn synchronize(&self, source: Source, time_us: u64, at: A::Ticks, error_us: u32) {
+        let ticks = self.ticks_at(at);
+        if let Some(last) = self.sync.get() {
+            let elapsed_us = Self::ticks_to_us(ticks.saturating_sub(last.ticks));
+            if elapsed_us >= MIN_CALIBRATION_S * 1_000_000 {
+                let offset_us = time_us as i128 - self.time_at(&last, ticks) as i128;
+                self.drift_ppb.set(learnt_drift_ppb(
+                    self.drift_ppb.get(),
+                    offset_us,
+                    elapsed_us,
+                ));
+                self.calibrated.set(true);
+            }
+        }
+        self.sync.set(Some(Sync {
+            source: source,
+            ticks: ticks,
+            time_us: time_us,
+            error_us: error_us,
+        }));
+
+        let error_us = self.error_us().unwrap_or(error_us);
+        self.apps.each(|_, app| {
+            app.callback.schedule(source as usize, error_us as usize, 0);
+        });
+    }
+
+    /// Synchronize from the payload of a LoRaWAN `DeviceTimeAns`, received in
+    /// answer to the uplink that ended at alarm ticks `uplink_end`.
+    pub fn device_time_answer(
+        &self,
+        payload: &[u8],
+        uplink_end: A::Ticks,
+    ) -> Result<(), ErrorCode> {
+        let seconds: [u8; 4] = payload
+            .get(0..4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(ErrorCode::SIZE)?;
+        let fraction = *payload.get(4).ok_or(ErrorCode::SIZE)? as u64;
+        let gps_us = u32::from_le_bytes(seconds) as u64 * 1_000_000 + fraction * 1_000_000 / 256;
+        self.device_time(gps_us, uplink_end, DEVICE_TIME_ERROR_US);
+        Ok(())
+    }
+
+    fn device_time(&self, gps_us: u64, uplink_end: A::Ticks, error_us: u32) {
+        let time_us = gps_us + (GPS_EPOCH_S - GPS_LEAP_SECONDS) * 1_000_000;
+        self.synchronize(Source::LoRaWan, time_us, uplink_end, error_us);
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for NetworkTime<'a, A> {
+    fn alarm(&self) {
+        self.now_ticks();
+        self.set_update_alarm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> RxClient for NetworkTime<'a, A> {
+    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+        let now = self.alarm.now();
+        if header.frame_type != FrameType::Beacon || data_len < 8 || !authenticated(&header) {
+            return;
+        }
+        let end = data_offset + data_len;
+        let time: [u8; 8] = match buf.get(end - 8..end).and_then(|b| b.try_into().ok()) {
+            Some(time) => time,
+            None => return,
+        };
+        // The time is that of the start of the frame, which took until now
+        // to receive.
+        let start = now.wrapping_sub(A::ticks_from_us(frame_us(end)));
+        self.synchronize(
+            Source::Ieee802154,
+            u64::from_le_bytes(time),
+            start,
+            BEACON_ERROR_US,
+        );
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for NetworkTime<'a, A> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Synchronized callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Read the time and its synchronization.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the source and age of the last synchronization
+    /// - `2`: Get the time
+    /// - `3`: Get the estimated error and the drift
+    /// - `4`: Synchronize from a LoRaWAN `DeviceTimeAns`
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success(),
+            1 => {
+                let ticks = self.now_ticks();
+                self.sync
+                    .get()
+                    .map_or(CommandReturn::success_u32_u32(0, 0), |sync| {
+                        let age_s = Self::ticks_to_us(ticks.saturating_sub(sync.ticks)) / 1_000_000;
+                        CommandReturn::success_u32_u32(
+                            sync.source as u32,
+                            age_s.try_into().unwrap_or(u32::MAX),
+                        )
+                    })
+            }
+            2 => self
+                .now_us()
+                .map_or(CommandReturn::failure(ErrorCode::OFF), |time_us| {
+                    CommandReturn::success_u32_u32(
+                        (time_us / 1_000_000) as u32,
+                        (time_us % 1_000_000) as u32,
+                    )
+                }),
+            3 => self
+                .error_us()
+                .map_or(CommandReturn::failure(ErrorCode::OFF), |error_us| {
+                    CommandReturn::success_u32_u32(error_us, self.drift_ppb.get() as i32 as u32)
+                }),
+            4 => {
+                if !self.kernel.command_permitted(appid, DRIVER_NUM, cmd_num) {
+                    return CommandReturn::failure(ErrorCode::NOSUPPORT);
+                }
+                // Processes cannot pass the fraction of a second, which adds
+                // up to 1/256 s of error.
+                self.device_time(
+                    arg1 as u64 * 1_000_000,
+                    A::Ticks::from(arg2 as u32),
+                    DEVICE_TIME_ERROR_US + 1_000_000 / 256,
+                );
+                CommandReturn::success()
+            }
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_corrected_us() {
+        assert_eq!(corrected_us(1_000_000_000, 0), 1_000_000_000);
+        // 1000 ppb is 1 µs per second.
+        assert_eq!(corrected_us(1_000_000_000, 1000), 1_000_001_000);
+        assert_eq!(corrected_us(1_000_000_000, -1000), 999_999_000);
+        assert_eq!(corrected_us(0, MAX_DRIFT_PPB), 0);
+    }
+
+    #[test]
+    fn test_learnt_drift() {
+        // 60 ms early over 10 minutes is 100 ppm, of which half is learnt.
+        let elapsed_us = MIN_CALIBRATION_S * 1_000_000;
+        assert_eq!(learnt_drift_ppb(0, 60_000, elapsed_us), 50_000);
+        assert_eq!(learnt_drift_ppb(0, -60_000, elapsed_us), -50_000);
+        // The next synchronization moves half way again.
+        assert_eq!(learnt_drift_ppb(50_000, 30_000, elapsed_us), 75_000);
+        // No offset keeps the drift.
+        assert_eq!(learnt_drift_ppb(12_345, 0, elapsed_us), 12_345);
+    }
+
+    #[test]
+    fn test_learnt_drift_clamped() {
+        let elapsed_us = MIN_CALIBRATION_S * 1_000_000;
+        assert_eq!(learnt_drift_ppb(0, 600_000_000, elapsed_us), MAX_DRIFT_PPB);
+        assert_eq!(
+            learnt_drift_ppb(0, -600_000_000, elapsed_us),
+            -MAX_DRIFT_PPB
+        );
+        assert_eq!(
+            learnt_drift_ppb(MAX_DRIFT_PPB, 60_000, elapsed_us),
+            MAX_DRIFT_PPB
+        );
+    }
+
+    #[test]
+    fn test_estimated_error() {
+        assert_eq!(estimated_error_us(1000, 0, false), 1000);
+        // An hour uncalibrated at 50 ppm, then calibrated at 5 ppm.
+        assert_eq!(estimated_error_us(1000, 3_600_000_000, false), 181_000);
+        assert_eq!(estimated_error_us(1000, 3_600_000_000, true), 19_000);
+        assert_eq!(estimated_error_us(u32::MAX, u64::MAX, false), u32::MAX);
+    }
+
+    #[test]
+    fn test_frame_us() {
+        // A 20 byte beacon and its 6 bytes of PHY header at 32 µs per byte.
+        assert_eq!(frame_us(20), 832);
+    }
+}
@@ -53,6 +53,40 @@
 //!         &mut capsules::nonvolatile_storage_driver::BUFFER));
 //! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_storage);
 //! ```
+//!
+//! Transactions
+//! ------------
+//!
+//! A write interrupted by a reset can leave a record half old and half new.
+//! To update a range atomically, a process begins a transaction on it, writes
+//! the new contents, and commits the transaction. Readers then see either
+//! the contents from before the transaction or all of the new contents, even
+//! if the board resets part way through the commit.
+//!
+//! Transactions need a journal, a region of the storage outside of the
+//! userspace and kernel regions, set with `set_journal()`:
+//!
+//! ```rust
+//! nonvolatile_storage.set_journal(
+//!     5000,
+//!     1024,
+//!     &mut capsules::nonvolatile_storage_driver::JOURNAL_BUFFER,
+//! );
+//! ```
+//!
+//! The journal starts with a commit record, followed by a shadow copy of the
+//! range, so a transaction can cover up to the length of the journal less 16
+//! bytes. Beginning a transaction copies the range into the shadow, and
+//! writes by the owning process to the range go to the shadow instead.
+//! Committing writes the commit record, with a checksum of the shadow, copies
+//! the shadow over the range and then clears the record. If the board resets
+//! before the record is cleared, `set_journal()` finds the record and copies
+//! the shadow over the range again. Records with a wrong checksum were torn
+//! while being written, and are dropped along with the transaction.
+//!
+//! One transaction runs at a time. Until it is committed, reads of the range
+//! return the old contents, also to the owning process, and other processes
+//! cannot write to it.
 
 use core::cell::Cell;
 use core::cmp;
@@ -70,6 +104,17 @@ use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::NvmStorage as usize;
 
 pub static mut BUFFER: [u8; 512] = [0; 512];
+pub static mut JOURNAL_BUFFER: [u8; 64] = [0; 64];
+
+/// Length of the commit record at the start of the journal.
+const RECORD_LEN: usize = 16;
+const RECORD_MAGIC: u32 = 0x4e56_5458;
+
+fn journal_checksum(sum: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(sum, |sum, byte| {
+        sum.rotate_left(5).wrapping_add(*byte as u32)
+    })
+}
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
@@ -81,13 +126,420 @@ pub enum NonvolatileCommand {
 
 #[derive(Clone, Copy)]
 pub enum NonvolatileUser {
-    App { app_id: ProcessId },
+    App {
+        app_id: ProcessId,
+    },
     Kernel,
+    /// A step of a transaction.
+    Journal,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum JournalStep {
+    Idle,
+    /// Reading the commit record at boot.
+    Recover,
+    /// Copying the range of a new transaction into the shadow.
+    Shadow,
+    /// Computing the checksum of the shadow.
+    Checksum,
+    /// Writing the commit record.
+    Commit,
+    /// Copying the shadow over the range.
+    Apply,
+    /// Clearing the commit record.
+    Clear,
+}
+
+#[derive(Clone, Copy)]
+struct Transaction {
+    /// Process that began the transaction, or `None` for a transaction
+    /// found at boot.
+    owner: Option<ProcessId>,
+    /// Start of the range, relative to the userspace region.
+    offset: usize,
+    length: usize,
+}
+
+/// The journal and the transaction using it. It has a buffer of its own and
+/// no per-process state, so that a reset can be recovered from before any
+/// process runs.
+///
+/// Each step returns whether it is waiting for a read or write of the
+/// storage. Once it is not, the step is done, and `finish()` ends it.
+struct Journal<'a> {
+    driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    buffer: TakeCell<'static, [u8]>,
+    // Physical address and length of the journal, if transactions are
+    // supported.
+    region: OptionalCell<(usize, usize)>,
+    userspace_start_address: usize,
+    userspace_length: usize,
+    transaction: OptionalCell<Transaction>,
+    step: Cell<JournalStep>,
+    // How far the current step has got through the range.
+    position: Cell<usize>,
+    checksum: Cell<u32>,
+    // Checksum in the commit record found at boot.
+    recovered_checksum: OptionalCell<u32>,
+}
+
+impl<'a> Journal<'a> {
+    fn new(
+        driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        userspace_start_address: usize,
+        userspace_length: usize,
+    ) -> Journal<'a> {
+        Journal {
+            driver: driver,
+            buffer: TakeCell::empty(),
+            region: OptionalCell::empty(),
+            userspace_start_address: userspace_start_address,
+            userspace_length: userspace_length,
+            transaction: OptionalCell::empty(),
+            step: Cell::new(JournalStep::Idle),
+            position: Cell::new(0),
+            checksum: Cell::new(0),
+            recovered_checksum: OptionalCell::empty(),
+        }
+    }
+
+    fn set_region(&self, address: usize, length: usize, buffer: &'static mut [u8]) {
+        self.region.set((address, length));
+        self.buffer.replace(buffer);
+    }
+
+    // Read the commit record, to finish a transaction interrupted by a reset.
+    fn recover(&self) -> Result<bool, ErrorCode> {
+        let (address, _) = self.region.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        self.step.set(JournalStep::Recover);
+        self.read(address, RECORD_LEN).map_err(|e| {
+            self.step.set(JournalStep::Idle);
+            e
+        })
+    }
+
+    // Copy the range into the shadow.
+    fn begin(
+        &self,
+        owner: Option<ProcessId>,
+        offset: usize,
+        length: usize,
+    ) -> Result<bool, ErrorCode> {
+        self.transaction.set(Transaction {
+            owner: owner,
+            offset: offset,
+            length: length,
+        });
+        self.start_copy(JournalStep::Shadow).map_err(|e| {
+            self.step.set(JournalStep::Idle);
+            self.transaction.clear();
+            e
+        })
+    }
+
+    // Write the commit record and copy the shadow over the range.
+    fn commit(&self) -> Result<bool, ErrorCode> {
+        self.start_checksum().map_err(|e| {
+            self.step.set(JournalStep::Idle);
+            e
+        })
+    }
+
+    // End the current step, and return it along with the owner of the
+    // transaction.
+    fn finish(&self, result: Result<(), ErrorCode>) -> (JournalStep, Option<ProcessId>) {
+        let step = self.step.replace(JournalStep::Idle);
+        let owner = self
+            .transaction
+            .extract()
+            .and_then(|transaction| transaction.owner);
+        // A transaction stays open once its range has been copied to the
+        // shadow. Otherwise it is over, and any commit record left behind is
+        // dealt with at the next boot.
+        if step != JournalStep::Shadow || result.is_err() {
+            self.transaction.clear();
+        }
+        (step, owner)
+    }
+
+    fn read(&self, address: usize, length: usize) -> Result<bool, ErrorCode> {
+        self.buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                let active_len = cmp::min(length, buffer.len());
+                self.driver.read(buffer, address, active_len).map(|()| true)
+            })
+    }
+
+    fn write(&self, address: usize, length: usize) -> Result<bool, ErrorCode> {
+        self.buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                let active_len = cmp::min(length, buffer.len());
+                self.driver
+                    .write(buffer, address, active_len)
+                    .map(|()| true)
+            })
+    }
+
+    // Physical addresses of the range of the transaction and of its shadow.
+    fn transaction_addresses(&self) -> Result<(Transaction, usize, usize), ErrorCode> {
+        let transaction = self.transaction.extract().ok_or(ErrorCode::FAIL)?;
+        let (journal_address, _) = self.region.extract().ok_or(ErrorCode::FAIL)?;
+        Ok((
+            transaction,
+            self.userspace_start_address + transaction.offset,
+            journal_address + RECORD_LEN,
+        ))
+    }
+
+    fn start_copy(&self, step: JournalStep) -> Result<bool, ErrorCode> {
+        self.step.set(step);
+        self.position.set(0);
+        self.copy_next()
+    }
+
+    // Read the next chunk of a copy between the range and the shadow, or
+    // move on to the next step once it is done.
+    fn copy_next(&self) -> Result<bool, ErrorCode> {
+        let (transaction, range, shadow) = self.transaction_addresses()?;
+        let position = self.position.get();
+        let source = match self.step.get() {
+            JournalStep::Shadow => range,
+            _ => shadow,
+        };
+        if position < transaction.length {
+            self.read(source + position, transaction.length - position)
+        } else if self.step.get() == JournalStep::Shadow {
+            Ok(false)
+        } else {
+            self.clear_record()
+        }
+    }
+
+    fn start_checksum(&self) -> Result<bool, ErrorCode> {
+        let (transaction, _, shadow) = self.transaction_addresses()?;
+        self.step.set(JournalStep::Checksum);
+        self.position.set(0);
+        let sum = journal_checksum(0, &(transaction.offset as u32).to_le_bytes());
+        self.checksum.set(journal_checksum(
+            sum,
+            &(transaction.length as u32).to_le_bytes(),
+        ));
+        self.read(shadow, transaction.length)
+    }
+
+    fn write_record(&self) -> Result<bool, ErrorCode> {
+        let (transaction, _, _) = self.transaction_addresses()?;
+        let (journal_address, _) = self.region.extract().ok_or(ErrorCode::FAIL)?;
+        self.buffer.map(|buffer| {
+            buffer[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+            buffer[4..8].copy_from_slice(&(transaction.offset as u32).to_le_bytes());
+            buffer[8..12].copy_from_slice(&(transaction.length as u32).to_le_bytes());
+            buffer[12..16].copy_from_slice(&self.checksum.get().to_le_bytes());
+        });
+        self.step.set(JournalStep::Commit);
+        self.write(journal_address, RECORD_LEN)
+    }
+
+    fn clear_record(&self) -> Result<bool, ErrorCode> {
+        let (journal_address, _) = self.region.extract().ok_or(ErrorCode::FAIL)?;
+        self.buffer.map(|buffer| {
+            buffer[0..4].copy_from_slice(&[0; 4]);
+        });
+        self.step.set(JournalStep::Clear);
+        self.write(journal_address, 4)
+    }
+
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) -> Result<bool, ErrorCode> {
+        match self.step.get() {
+            JournalStep::Recover => {
+                let field = |i: usize| {
+                    let mut bytes = [0; 4];
+                    bytes.copy_from_slice(&buffer[i..i + 4]);
+                    u32::from_le_bytes(bytes) as usize
+                };
+                let (magic, offset, transaction_length, checksum) =
+                    (field(0), field(4), field(8), field(12));
+                self.buffer.replace(buffer);
+
+                let journal_length = self.region.map_or(0, |(_, length)| *length);
+                if magic as u32 != RECORD_MAGIC {
+                    return Ok(false);
+                }
+                // A record torn before its length was written is dropped
+                // like one with a wrong checksum.
+                if transaction_length == 0
+                    || offset >= self.userspace_length
+                    || transaction_length > self.userspace_length - offset
+                    || transaction_length > journal_length - RECORD_LEN
+                {
+                    return self.clear_record();
+                }
+                self.transaction.set(Transaction {
+                    owner: None,
+                    offset: offset,
+                    length: transaction_length,
+                });
+                self.recovered_checksum.set(checksum as u32);
+                self.start_checksum()
+            }
+            JournalStep::Shadow | JournalStep::Apply => {
+                let (_, range, shadow) = self.transaction_addresses()?;
+                let destination = match self.step.get() {
+                    JournalStep::Shadow => shadow,
+                    _ => range,
+                };
+                self.buffer.replace(buffer);
+                self.write(destination + self.position.get(), length)
+            }
+            JournalStep::Checksum => {
+                let (transaction, _, shadow) = self.transaction_addresses()?;
+                self.checksum
+                    .set(journal_checksum(self.checksum.get(), &buffer[..length]));
+                self.buffer.replace(buffer);
+                let position = self.position.get() + length;
+                self.position.set(position);
+                if position < transaction.length {
+                    return self.read(shadow + position, transaction.length - position);
+                }
+                match self.recovered_checksum.take() {
+                    Some(checksum) if checksum == self.checksum.get() => {
+                        self.start_copy(JournalStep::Apply)
+                    }
+                    // The record was torn while being written, so the range
+                    // still holds its old contents.
+                    Some(_) => self.clear_record(),
+                    None => self.write_record(),
+                }
+            }
+            _ => {
+                self.buffer.replace(buffer);
+                Ok(false)
+            }
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) -> Result<bool, ErrorCode> {
+        self.buffer.replace(buffer);
+        match self.step.get() {
+            JournalStep::Shadow | JournalStep::Apply => {
+                self.position.set(self.position.get() + length);
+                self.copy_next()
+            }
+            JournalStep::Commit => self.start_copy(JournalStep::Apply),
+            _ => Ok(false),
+        }
+    }
+}
+
+/// The underlying storage and the transactions on it, without any
+/// per-process state.
+struct Storage<'a> {
+    driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    // Internal buffer for copying appslices into.
+    buffer: TakeCell<'static, [u8]>,
+    // What issued the currently executing call. This can be an app or the kernel.
+    current_user: OptionalCell<NonvolatileUser>,
+    // The first byte that is accessible from userspace.
+    userspace_start_address: usize,
+    // Transactions, if the board set a journal.
+    journal: Journal<'a>,
+}
+
+impl<'a> Storage<'a> {
+    // Start a read or write of the storage. It is only marked as used by
+    // `user` once the driver has accepted the request, so that a refused
+    // request leaves it free for the next one.
+    fn start(
+        &self,
+        user: NonvolatileUser,
+        command: NonvolatileCommand,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        let result = match command {
+            NonvolatileCommand::UserspaceRead | NonvolatileCommand::KernelRead => {
+                self.driver.read(buffer, address, length)
+            }
+            NonvolatileCommand::UserspaceWrite | NonvolatileCommand::KernelWrite => {
+                self.driver.write(buffer, address, length)
+            }
+        };
+        if result.is_ok() {
+            self.current_user.set(user);
+        }
+        result
+    }
+
+    // Start a read or write for a process. `owner` is whether the process
+    // owns the current transaction, if there is one.
+    fn userspace_call_driver(
+        &self,
+        user: NonvolatileUser,
+        owner: bool,
+        command: NonvolatileCommand,
+        offset: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        self.buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                // Check that the internal buffer and the buffer that was
+                // allowed are long enough.
+                let active_len = cmp::min(length, buffer.len());
+
+                // Calculate where we want to actually read from in the physical
+                // storage.
+                match self.physical_address(owner, command, offset, active_len) {
+                    Ok(address) => self.start(user, command, buffer, address, active_len),
+                    Err(e) => {
+                        self.buffer.replace(buffer);
+                        Err(e)
+                    }
+                }
+            })
+    }
+
+    // Writes to the range of a transaction go to the shadow if they come from
+    // the process that owns it, and are refused otherwise.
+    fn physical_address(
+        &self,
+        owner: bool,
+        command: NonvolatileCommand,
+        offset: usize,
+        length: usize,
+    ) -> Result<usize, ErrorCode> {
+        let address = offset + self.userspace_start_address;
+        if command != NonvolatileCommand::UserspaceWrite {
+            return Ok(address);
+        }
+        match (
+            self.journal.transaction.extract(),
+            self.journal.region.extract(),
+        ) {
+            (Some(transaction), Some((journal_address, _))) => {
+                let end = transaction.offset + transaction.length;
+                if offset >= end || offset + length <= transaction.offset {
+                    Ok(address)
+                } else if owner && offset >= transaction.offset && offset + length <= end {
+                    Ok(journal_address + RECORD_LEN + offset - transaction.offset)
+                } else {
+                    Err(ErrorCode::BUSY)
+                }
+            }
+            _ => Ok(address),
+        }
+    }
 }
 
 pub struct App {
     callback_read: Upcall,
     callback_write: Upcall,
+    callback_begin: Upcall,
+    callback_commit: Upcall,
     pending_command: bool,
     command: NonvolatileCommand,
     offset: usize,
@@ -101,6 +553,8 @@ impl Default for App {
         App {
             callback_read: Upcall::default(),
             callback_write: Upcall::default(),
+            callback_begin: Upcall::default(),
+            callback_commit: Upcall::default(),
             pending_command: false,
             command: NonvolatileCommand::UserspaceRead,
             offset: 0,
@@ -113,17 +567,10 @@ impl Default for App {
 
 pub struct NonvolatileStorage<'a> {
     // The underlying physical storage device.
-    driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    storage: Storage<'a>,
     // Per-app state.
     apps: Grant<App>,
 
-    // Internal buffer for copying appslices into.
-    buffer: TakeCell<'static, [u8]>,
-    // What issued the currently executing call. This can be an app or the kernel.
-    current_user: OptionalCell<NonvolatileUser>,
-
-    // The first byte that is accessible from userspace.
-    userspace_start_address: usize,
     // How many bytes allocated to userspace.
     userspace_length: usize,
     // The first byte that is accessible from the kernel.
@@ -158,11 +605,14 @@ impl<'a> NonvolatileStorage<'a> {
         buffer: &'static mut [u8],
     ) -> NonvolatileStorage<'a> {
         NonvolatileStorage {
-            driver: driver,
+            storage: Storage {
+                driver: driver,
+                buffer: TakeCell::new(buffer),
+                current_user: OptionalCell::empty(),
+                userspace_start_address: userspace_start_address,
+                journal: Journal::new(driver, userspace_start_address, userspace_length),
+            },
             apps: grant,
-            buffer: TakeCell::new(buffer),
-            current_user: OptionalCell::empty(),
-            userspace_start_address: userspace_start_address,
             userspace_length: userspace_length,
             kernel_start_address: kernel_start_address,
             kernel_length: kernel_length,
@@ -175,6 +625,29 @@ impl<'a> NonvolatileStorage<'a> {
         }
     }
 
+    /// Use `length` bytes at physical address `address` as the journal for
+    /// transactions, and finish a transaction interrupted by a reset. The
+    /// journal must not overlap the userspace or kernel regions. Steps of
+    /// transactions use `buffer`, which must fit the 16 byte commit record.
+    pub fn set_journal(
+        &self,
+        address: usize,
+        length: usize,
+        buffer: &'static mut [u8],
+    ) -> Result<(), ErrorCode> {
+        if length <= RECORD_LEN || buffer.len() < RECORD_LEN {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.storage.current_user.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.storage.journal.set_region(address, length, buffer);
+        self.storage
+            .journal
+            .recover()
+            .map(|pending| self.journal_progress(Ok(pending)))
+    }
+
     // Check so see if we are doing something. If not, go ahead and do this
     // command. If so, this is queued and will be run when the pending
     // command completes.
@@ -225,7 +698,7 @@ impl<'a> NonvolatileStorage<'a> {
                             };
 
                             // Check that it exists.
-                            if allow_buf_len == 0 || self.buffer.is_none() {
+                            if allow_buf_len == 0 || self.storage.buffer.is_none() {
                                 return Err(ErrorCode::RESERVE);
                             }
 
@@ -235,16 +708,13 @@ impl<'a> NonvolatileStorage<'a> {
 
                             // First need to determine if we can execute this or must
                             // queue it.
-                            if self.current_user.is_none() {
-                                // No app is currently using the underlying storage.
-                                // Mark this app as active, and then execute the command.
-                                self.current_user
-                                    .set(NonvolatileUser::App { app_id: appid });
-
+                            if self.storage.current_user.is_none() {
+                                // No app is currently using the underlying storage,
+                                // so execute the command.
                                 // Need to copy bytes if this is a write!
                                 if command == NonvolatileCommand::UserspaceWrite {
                                     app.buffer_write.map_or((), |app_buffer| {
-                                        self.buffer.map(|kernel_buffer| {
+                                        self.storage.buffer.map(|kernel_buffer| {
                                             // Check that the internal buffer and the buffer that was
                                             // allowed are long enough.
                                             let write_len =
@@ -260,7 +730,7 @@ impl<'a> NonvolatileStorage<'a> {
                                     });
                                 }
 
-                                self.userspace_call_driver(command, offset, active_len)
+                                self.userspace_call_driver(appid, command, offset, active_len)
                             } else {
                                 // Some app is using the storage, we must wait.
                                 if app.pending_command == true {
@@ -287,19 +757,15 @@ impl<'a> NonvolatileStorage<'a> {
                         let active_len = cmp::min(length, kernel_buffer.len());
 
                         // Check if there is something going on.
-                        if self.current_user.is_none() {
+                        if self.storage.current_user.is_none() {
                             // Nothing is using this, lets go!
-                            self.current_user.set(NonvolatileUser::Kernel);
-
-                            match command {
-                                NonvolatileCommand::KernelRead => {
-                                    self.driver.read(kernel_buffer, offset, active_len)
-                                }
-                                NonvolatileCommand::KernelWrite => {
-                                    self.driver.write(kernel_buffer, offset, active_len)
-                                }
-                                _ => Err(ErrorCode::FAIL),
-                            }
+                            self.storage.start(
+                                NonvolatileUser::Kernel,
+                                command,
+                                kernel_buffer,
+                                offset,
+                                active_len,
+                            )
                         } else {
                             if self.kernel_pending_command.get() == true {
                                 Err(ErrorCode::NOMEM)
@@ -319,32 +785,126 @@ impl<'a> NonvolatileStorage<'a> {
 
     fn userspace_call_driver(
         &self,
+        appid: ProcessId,
         command: NonvolatileCommand,
         offset: usize,
         length: usize,
     ) -> Result<(), ErrorCode> {
-        // Calculate where we want to actually read from in the physical
-        // storage.
-        let physical_address = offset + self.userspace_start_address;
+        let owner = self
+            .storage
+            .journal
+            .transaction
+            .map_or(false, |transaction| transaction.owner == Some(appid));
+        self.storage.userspace_call_driver(
+            NonvolatileUser::App { app_id: appid },
+            owner,
+            command,
+            offset,
+            length,
+        )
+    }
 
-        self.buffer
-            .take()
-            .map_or(Err(ErrorCode::RESERVE), |buffer| {
-                // Check that the internal buffer and the buffer that was
-                // allowed are long enough.
-                let active_len = cmp::min(length, buffer.len());
+    fn begin_transaction(
+        &self,
+        appid: ProcessId,
+        offset: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        let (_, journal_length) = self
+            .storage
+            .journal
+            .region
+            .extract()
+            .ok_or(ErrorCode::NOSUPPORT)?;
+        if length == 0
+            || offset >= self.userspace_length
+            || length > self.userspace_length - offset
+            || length > journal_length - RECORD_LEN
+        {
+            return Err(ErrorCode::INVAL);
+        }
+        // A transaction whose process has exited can be taken over.
+        let owned = self
+            .storage
+            .journal
+            .transaction
+            .extract()
+            .map_or(false, |transaction| {
+                transaction
+                    .owner
+                    .map_or(true, |owner| self.apps.enter(owner, |_| ()).is_ok())
+            });
+        if owned || self.storage.current_user.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
 
-                // self.current_app.set(Some(appid));
-                match command {
-                    NonvolatileCommand::UserspaceRead => {
-                        self.driver.read(buffer, physical_address, active_len)
-                    }
-                    NonvolatileCommand::UserspaceWrite => {
-                        self.driver.write(buffer, physical_address, active_len)
-                    }
-                    _ => Err(ErrorCode::FAIL),
+        self.storage
+            .journal
+            .begin(Some(appid), offset, length)
+            .map(|pending| self.journal_progress(Ok(pending)))
+    }
+
+    fn commit_transaction(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let transaction = self
+            .storage
+            .journal
+            .transaction
+            .extract()
+            .ok_or(ErrorCode::INVAL)?;
+        if transaction.owner != Some(appid) {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.storage.current_user.is_some()
+            || self.storage.journal.step.get() != JournalStep::Idle
+        {
+            return Err(ErrorCode::BUSY);
+        }
+        self.storage
+            .journal
+            .commit()
+            .map(|pending| self.journal_progress(Ok(pending)))
+    }
+
+    fn abort_transaction(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let transaction = self
+            .storage
+            .journal
+            .transaction
+            .extract()
+            .ok_or(ErrorCode::INVAL)?;
+        if transaction.owner != Some(appid) {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.storage.journal.step.get() != JournalStep::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.storage.journal.transaction.clear();
+        Ok(())
+    }
+
+    // Keep the storage for the journal while it waits for a read or write,
+    // and notify the owner once the step is done.
+    fn journal_progress(&self, result: Result<bool, ErrorCode>) {
+        match result {
+            Ok(true) => self.storage.current_user.set(NonvolatileUser::Journal),
+            Ok(false) => self.journal_done(Ok(())),
+            Err(e) => self.journal_done(Err(e)),
+        }
+    }
+
+    // End the current transaction step and notify the owner.
+    fn journal_done(&self, result: Result<(), ErrorCode>) {
+        let (step, owner) = self.storage.journal.finish(result);
+        owner.map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                let status = kernel::into_statuscode(result);
+                if step == JournalStep::Shadow {
+                    app.callback_begin.schedule(status, 0, 0);
+                } else {
+                    app.callback_commit.schedule(status, 0, 0);
                 }
-            })
+            });
+        });
     }
 
     fn check_queue(&self) {
@@ -352,21 +912,13 @@ impl<'a> NonvolatileStorage<'a> {
         if self.kernel_pending_command.get() {
             self.kernel_buffer.take().map(|kernel_buffer| {
                 self.kernel_pending_command.set(false);
-                self.current_user.set(NonvolatileUser::Kernel);
-
-                match self.kernel_command.get() {
-                    NonvolatileCommand::KernelRead => self.driver.read(
-                        kernel_buffer,
-                        self.kernel_readwrite_address.get(),
-                        self.kernel_readwrite_length.get(),
-                    ),
-                    NonvolatileCommand::KernelWrite => self.driver.write(
-                        kernel_buffer,
-                        self.kernel_readwrite_address.get(),
-                        self.kernel_readwrite_length.get(),
-                    ),
-                    _ => Err(ErrorCode::FAIL),
-                }
+                self.storage.start(
+                    NonvolatileUser::Kernel,
+                    self.kernel_command.get(),
+                    kernel_buffer,
+                    self.kernel_readwrite_address.get(),
+                    self.kernel_readwrite_length.get(),
+                )
             });
         } else {
             // If the kernel is not requesting anything, check all of the apps.
@@ -375,10 +927,8 @@ impl<'a> NonvolatileStorage<'a> {
                 let started_command = cntr.enter(|app| {
                     if app.pending_command {
                         app.pending_command = false;
-                        self.current_user
-                            .set(NonvolatileUser::App { app_id: appid });
                         if let Ok(()) =
-                            self.userspace_call_driver(app.command, app.offset, app.length)
+                            self.userspace_call_driver(appid, app.command, app.offset, app.length)
                         {
                             true
                         } else {
@@ -400,7 +950,7 @@ impl<'a> NonvolatileStorage<'a> {
 impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for NonvolatileStorage<'_> {
     fn read_done(&self, buffer: &'static mut [u8], length: usize) {
         // Switch on which user of this capsule generated this callback.
-        self.current_user.take().map(|user| {
+        self.storage.current_user.take().map(|user| {
             match user {
                 NonvolatileUser::Kernel => {
                     self.kernel_client.map(move |client| {
@@ -420,21 +970,27 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                         });
 
                         // Replace the buffer we used to do this read.
-                        self.buffer.replace(buffer);
+                        self.storage.buffer.replace(buffer);
 
                         // And then signal the app.
                         app.callback_read.schedule(length, 0, 0);
                     });
                 }
+                NonvolatileUser::Journal => {
+                    self.journal_progress(self.storage.journal.read_done(buffer, length));
+                }
             }
         });
 
-        self.check_queue();
+        // Transactions take several reads and writes.
+        if self.storage.current_user.is_none() {
+            self.check_queue();
+        }
     }
 
     fn write_done(&self, buffer: &'static mut [u8], length: usize) {
         // Switch on which user of this capsule generated this callback.
-        self.current_user.take().map(|user| {
+        self.storage.current_user.take().map(|user| {
             match user {
                 NonvolatileUser::Kernel => {
                     self.kernel_client.map(move |client| {
@@ -444,16 +1000,21 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                 NonvolatileUser::App { app_id } => {
                     let _ = self.apps.enter(app_id, move |app| {
                         // Replace the buffer we used to do this write.
-                        self.buffer.replace(buffer);
+                        self.storage.buffer.replace(buffer);
 
                         // And then signal the app.
                         app.callback_write.schedule(length, 0, 0);
                     });
                 }
+                NonvolatileUser::Journal => {
+                    self.journal_progress(self.storage.journal.write_done(buffer, length));
+                }
             }
         });
 
-        self.check_queue();
+        if self.storage.current_user.is_none() {
+            self.check_queue();
+        }
     }
 }
 
@@ -549,6 +1110,8 @@ impl Driver for NonvolatileStorage<'_> {
     ///
     /// - `0`: Setup a read done callback.
     /// - `1`: Setup a write done callback.
+    /// - `2`: Setup a transaction begun callback.
+    /// - `3`: Setup a transaction committed callback.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -566,6 +1129,14 @@ impl Driver for NonvolatileStorage<'_> {
                     mem::swap(&mut app.callback_write, &mut callback);
                     Ok(())
                 }
+                2 => {
+                    mem::swap(&mut app.callback_begin, &mut callback);
+                    Ok(())
+                }
+                3 => {
+                    mem::swap(&mut app.callback_commit, &mut callback);
+                    Ok(())
+                }
                 _ => Err(ErrorCode::NOSUPPORT),
             })
             .unwrap_or_else(|err| Err(err.into()));
@@ -586,6 +1157,9 @@ impl Driver for NonvolatileStorage<'_> {
     /// - `1`: Return the number of bytes available to userspace.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
+    /// - `4`: Begin a transaction on `length` bytes at `offset`.
+    /// - `5`: Commit the transaction.
+    /// - `6`: Abort the transaction.
     fn command(
         &self,
         command_num: usize,
@@ -633,7 +1207,319 @@ impl Driver for NonvolatileStorage<'_> {
                 }
             }
 
+            4 /* Begin a transaction */ => {
+                match self.begin_transaction(appid, offset, length) {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            5 /* Commit the transaction */ => {
+                match self.commit_transaction(appid) {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            6 /* Abort the transaction */ => {
+                match self.abort_transaction(appid) {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{
+        Journal, JournalStep, NonvolatileCommand, NonvolatileUser, Storage, RECORD_LEN,
+        RECORD_MAGIC,
+    };
+    use core::cell::Cell;
+    use kernel::common::cells::{OptionalCell, TakeCell};
+    use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+    use kernel::ErrorCode;
+    use std::boxed::Box;
+
+    // Userspace gets the first 32 bytes, and the journal the last 48.
+    const MEMORY_LEN: usize = 80;
+    const USERSPACE_LEN: usize = 32;
+    const JOURNAL: usize = 32;
+    const JOURNAL_LEN: usize = 48;
+    const SHADOW: usize = JOURNAL + RECORD_LEN;
+    // The transaction takes two chunks of the journal buffer to copy.
+    const OFFSET: usize = 4;
+    const LENGTH: usize = 24;
+
+    const OLD: u8 = 0x11;
+    const NEW: u8 = 0x22;
+
+    /// Storage that holds on to each read or write until the test completes
+    /// it, so that the test can reset between any two of them.
+    struct MockStorage {
+        memory: Cell<[u8; MEMORY_LEN]>,
+        buffer: TakeCell<'static, [u8]>,
+        // Whether the outstanding operation is a write, its address and its
+        // length.
+        operation: OptionalCell<(bool, usize, usize)>,
+    }
+
+    impl MockStorage {
+        fn new() -> MockStorage {
+            let mut memory = [0; MEMORY_LEN];
+            memory[..USERSPACE_LEN].copy_from_slice(&[OLD; USERSPACE_LEN]);
+            MockStorage {
+                memory: Cell::new(memory),
+                buffer: TakeCell::empty(),
+                operation: OptionalCell::empty(),
+            }
+        }
+
+        /// Finish the outstanding read or write. A `torn` write only stores
+        /// its first half. Returns whether the journal is waiting again.
+        fn complete(&self, journal: &Journal, torn: bool) -> bool {
+            let (write, address, length) = self.operation.take().unwrap();
+            let buffer = self.buffer.take().unwrap();
+            let mut memory = self.memory.get();
+            let result = if write {
+                let stored = if torn { length / 2 } else { length };
+                memory[address..address + stored].copy_from_slice(&buffer[..stored]);
+                self.memory.set(memory);
+                journal.write_done(buffer, length)
+            } else {
+                buffer[..length].copy_from_slice(&memory[address..address + length]);
+                journal.read_done(buffer, length)
+            };
+            result.unwrap()
+        }
+
+        /// Lose the outstanding operation, as a reset would.
+        fn reset(&self) {
+            self.operation.clear();
+            self.buffer.take();
+        }
+
+        fn range(&self) -> [u8; LENGTH] {
+            let mut range = [0; LENGTH];
+            range.copy_from_slice(&self.memory.get()[OFFSET..OFFSET + LENGTH]);
+            range
+        }
+
+        fn record_magic(&self) -> u32 {
+            let mut magic = [0; 4];
+            magic.copy_from_slice(&self.memory.get()[JOURNAL..JOURNAL + 4]);
+            u32::from_le_bytes(magic)
+        }
+    }
+
+    impl NonvolatileStorage<'static> for MockStorage {
+        fn set_client(&self, _client: &'static dyn NonvolatileStorageClient<'static>) {}
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            self.buffer.replace(buffer);
+            self.operation.set((false, address, length));
+            Ok(())
+        }
+
+        fn write(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            self.buffer.replace(buffer);
+            self.operation.set((true, address, length));
+            Ok(())
+        }
+    }
+
+    /// The journal as set up at boot.
+    fn boot(storage: &MockStorage) -> Journal {
+        let journal = Journal::new(storage, 0, USERSPACE_LEN);
+        journal.set_region(JOURNAL, JOURNAL_LEN, Box::leak(Box::new([0; RECORD_LEN])));
+        journal
+    }
+
+    fn run(storage: &MockStorage, journal: &Journal, mut pending: bool) {
+        while pending {
+            pending = storage.complete(journal, false);
+        }
+        journal.finish(Ok(()));
+    }
+
+    fn recover(storage: &MockStorage) {
+        let journal = boot(storage);
+        let pending = journal.recover().unwrap();
+        run(storage, &journal, pending);
+    }
+
+    /// Begin a transaction, write the new contents to its shadow and start
+    /// committing it.
+    fn start_commit(storage: &MockStorage) -> Journal {
+        let journal = boot(storage);
+        let pending = journal.begin(None, OFFSET, LENGTH).unwrap();
+        run(storage, &journal, pending);
+        assert!(journal.transaction.is_some());
+
+        let mut memory = storage.memory.get();
+        memory[SHADOW..SHADOW + LENGTH].copy_from_slice(&[NEW; LENGTH]);
+        storage.memory.set(memory);
+        assert!(journal.commit().unwrap());
+        journal
+    }
+
+    #[test]
+    fn test_commit() {
+        let storage = MockStorage::new();
+        let journal = start_commit(&storage);
+        run(&storage, &journal, true);
+        assert_eq!(storage.range(), [NEW; LENGTH]);
+        assert_eq!(storage.record_magic(), 0);
+
+        // Nothing is left for the next boot to do.
+        recover(&storage);
+        assert_eq!(storage.range(), [NEW; LENGTH]);
+    }
+
+    #[test]
+    fn test_reset_while_beginning() {
+        for completed in 0..4 {
+            let storage = MockStorage::new();
+            let journal = boot(&storage);
+            assert!(journal.begin(None, OFFSET, LENGTH).unwrap());
+            for _ in 0..completed {
+                assert!(storage.complete(&journal, false));
+            }
+            assert!(journal.step.get() == JournalStep::Shadow);
+            storage.reset();
+
+            recover(&storage);
+            assert_eq!(storage.range(), [OLD; LENGTH]);
+        }
+    }
+
+    #[test]
+    fn test_reset_while_committing() {
+        let mut steps = [false; 4];
+        let mut completed = 0;
+        loop {
+            let storage = MockStorage::new();
+            let journal = start_commit(&storage);
+            let mut pending = true;
+            for _ in 0..completed {
+                pending = pending && storage.complete(&journal, false);
+            }
+            if !pending {
+                break;
+            }
+            let step = journal.step.get();
+            storage.reset();
+
+            // Once the record is written, the next boot finishes the
+            // commit. Before, the range keeps its old contents.
+            recover(&storage);
+            let (index, expected) = match step {
+                JournalStep::Checksum => (0, OLD),
+                JournalStep::Commit => (1, OLD),
+                JournalStep::Apply => (2, NEW),
+                JournalStep::Clear => (3, NEW),
+                _ => panic!("unexpected step"),
+            };
+            steps[index] = true;
+            assert_eq!(storage.range(), [expected; LENGTH]);
+            assert_eq!(storage.record_magic(), 0);
+            completed += 1;
+        }
+        assert_eq!(steps, [true; 4]);
+    }
+
+    #[test]
+    fn test_reset_while_recovering() {
+        // Reset after the record is written, and then again at each step of
+        // the recovery.
+        for completed in 0.. {
+            let storage = MockStorage::new();
+            let journal = start_commit(&storage);
+            while journal.step.get() != JournalStep::Apply {
+                assert!(storage.complete(&journal, false));
+            }
+            storage.reset();
+
+            let journal = boot(&storage);
+            let mut pending = journal.recover().unwrap();
+            for _ in 0..completed {
+                pending = pending && storage.complete(&journal, false);
+            }
+            if !pending {
+                assert_eq!(storage.range(), [NEW; LENGTH]);
+                break;
+            }
+            storage.reset();
+
+            recover(&storage);
+            assert_eq!(storage.range(), [NEW; LENGTH]);
+            assert_eq!(storage.record_magic(), 0);
+        }
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let storage = MockStorage::new();
+        let journal = start_commit(&storage);
+        while journal.step.get() != JournalStep::Commit {
+            assert!(storage.complete(&journal, false));
+        }
+        // Only the magic and the offset of the record make it to storage.
+        storage.complete(&journal, true);
+        storage.reset();
+        assert_eq!(storage.record_magic(), RECORD_MAGIC);
+
+        recover(&storage);
+        assert_eq!(storage.range(), [OLD; LENGTH]);
+        assert_eq!(storage.record_magic(), 0);
+    }
+
+    #[test]
+    fn test_refused_write() {
+        let mock = MockStorage::new();
+        let storage = Storage {
+            driver: &mock,
+            buffer: TakeCell::new(Box::leak(Box::new([0; 8]))),
+            current_user: OptionalCell::empty(),
+            userspace_start_address: 0,
+            journal: boot(&mock),
+        };
+        let pending = storage.journal.begin(None, OFFSET, LENGTH).unwrap();
+        run(&mock, &storage.journal, pending);
+
+        // A process that does not own the transaction cannot write to its
+        // range, and the storage is left free.
+        let user = NonvolatileUser::Kernel;
+        let write = NonvolatileCommand::UserspaceWrite;
+        assert_eq!(
+            storage.userspace_call_driver(user, false, write, OFFSET, 4),
+            Err(ErrorCode::BUSY)
+        );
+        assert!(storage.current_user.is_none());
+        assert!(storage.buffer.is_some());
+
+        let read = NonvolatileCommand::UserspaceRead;
+        assert_eq!(
+            storage.userspace_call_driver(user, false, read, OFFSET, 4),
+            Ok(())
+        );
+        assert!(storage.current_user.is_some());
+        assert_eq!(mock.operation.extract(), Some((false, OFFSET, 4)));
+    }
+}
@@ -0,0 +1,368 @@
+//! Counts steps and motion with the accelerometer, and estimates the distance
+//! travelled since the last GNSS fix.
+//!
+//! Tracking processes use the estimate to duty cycle the GNSS receiver: there
+//! is no point in a new fix while the board has not moved, and a walk of a
+//! few hundred meters calls for one sooner than planned. A process can ask
+//! to be woken once the estimate passes a distance, and otherwise sleep.
+//!
+//! The capsule samples the accelerometer at `SAMPLE_RATE_HZ`. It follows the
+//! slowly changing magnitude of the acceleration, which is gravity while the
+//! board is still, and counts a step at every peak more than
+//! `STEP_THRESHOLD_MG` above it, and a sample of motion for every sample more
+//! than `MOTION_THRESHOLD_MG` away from it. The distance is the steps since
+//! the last fix times the stride length. At each fix after at least
+//! `CALIBRATION_STEPS` steps, the capsule compares the distance between the
+//! two fixes with the steps taken, and moves the stride towards the result if
+//! it is plausible for walking, so the estimate adapts to whoever carries the
+//! board.
+//!
+//! Sampling runs while at least one process has enabled the odometer.
+//! Drivers report acceleration in different units, so boards pass the
+//! acceleration of one unit of their accelerometer in milli-g.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let odometer_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let odometer = static_init!(
+//!     capsules::odometer::Odometer<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     capsules::odometer::Odometer::new(
+//!         fxos8700,
+//!         odometer_alarm,
+//!         1,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! kernel::hil::sensors::NineDof::set_client(fxos8700, odometer);
+//! odometer_alarm.set_alarm_client(odometer);
+//! odometer_gnss.add_to_mux();
+//! kernel::hil::gnss::Gnss::set_fix_client(odometer_gnss, odometer);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: distance passed. The callback gets the estimated distance since the
+//!   last fix in millimeters and the steps since the last fix. It is
+//!   scheduled once per fix.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the sample rate in Hz.
+//! - `1`: Enable the odometer for this process, with a callback once the
+//!   distance since the last fix passes `arg1` millimeters, or none if it is
+//!   `0`.
+//! - `2`: Disable the odometer for this process.
+//! - `3`: Get the steps and the seconds of motion since the odometer started.
+//! - `4`: Get the estimated distance since the last fix in millimeters, and
+//!   the steps since the last fix.
+//! - `5`: Get the stride length in millimeters.
+//! - `6`: Set the stride length to `arg1` millimeters.
+
+use core::cell::Cell;
+use core::mem;
+
+use kernel::hil::gnss::{Fix, FixClient};
+use kernel::hil::sensors::{NineDof, NineDofClient};
+use kernel::hil::time::{self, Alarm};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+use crate::geofence::distance_sq_m;
+use crate::imu_fusion::inv_sqrt;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Odometer as usize;
+
+const SAMPLE_INTERVAL_MS: u32 = 40;
+pub const SAMPLE_RATE_HZ: u32 = 1000 / SAMPLE_INTERVAL_MS;
+
+/// Rise of the acceleration above gravity that counts as a step.
+pub const STEP_THRESHOLD_MG: f32 = 120.0;
+/// Change of the acceleration that counts as motion.
+pub const MOTION_THRESHOLD_MG: f32 = 50.0;
+/// Shortest time between two steps, for a fast run.
+const MIN_STEP_MS: u32 = 250;
+
+/// Steps between two fixes needed to calibrate the stride.
+pub const CALIBRATION_STEPS: u32 = 20;
+const DEFAULT_STRIDE_MM: u32 = 700;
+/// Strides outside this range are not walking, for example while driving.
+const MIN_STRIDE_MM: u32 = 300;
+const MAX_STRIDE_MM: u32 = 1500;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    enabled: bool,
+    /// Distance since the last fix to call back at, if any.
+    threshold_mm: u32,
+    /// Whether the callback was scheduled since the last fix.
+    notified: bool,
+}
+
+pub struct Odometer<'a, A: Alarm<'a>> {
+    accelerometer: &'a dyn NineDof<'a>,
+    alarm: &'a A,
+    /// Acceleration of one accelerometer unit, in milli-g.
+    accel_scale: f32,
+    running: Cell<bool>,
+    reading: Cell<bool>,
+    /// Slowly following magnitude of the acceleration, or `None` before the
+    /// first sample.
+    baseline: Cell<Option<f32>>,
+    /// Whether the acceleration dropped below the baseline since the last
+    /// step.
+    armed: Cell<bool>,
+    samples_since_step: Cell<u32>,
+    steps: Cell<u32>,
+    motion_samples: Cell<u32>,
+    steps_since_fix: Cell<u32>,
+    stride_mm: Cell<u32>,
+    last_fix: Cell<Option<Fix>>,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> Odometer<'a, A> {
+    /// Count steps with `accelerometer`, which reports acceleration in units
+    /// of `accel_mg` milli-g.
+    pub fn new(
+        accelerometer: &'a dyn NineDof<'a>,
+        alarm: &'a A,
+        accel_mg: u32,
+        grant: Grant<App>,
+    ) -> Odometer<'a, A> {
+        Odometer {
+            accelerometer: accelerometer,
+            alarm: alarm,
+            accel_scale: accel_mg as f32,
+            running: Cell::new(false),
+            reading: Cell::new(false),
+            baseline: Cell::new(None),
+            armed: Cell::new(false),
+            samples_since_step: Cell::new(0),
+            steps: Cell::new(0),
+            motion_samples: Cell::new(0),
+            steps_since_fix: Cell::new(0),
+            stride_mm: Cell::new(DEFAULT_STRIDE_MM),
+            last_fix: Cell::new(None),
+            apps: grant,
+        }
+    }
+
+    /// Sample the accelerometer while any process has enabled the odometer.
+    fn update_running(&self) {
+        let enabled = Cell::new(false);
+        self.apps.each(|_, app| {
+            if app.enabled {
+                enabled.set(true);
+            }
+        });
+        if enabled.get() && !self.running.get() {
+            self.running.set(true);
+            self.baseline.set(None);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(SAMPLE_INTERVAL_MS));
+        } else if !enabled.get() && self.running.get() {
+            self.running.set(false);
+            let _ = self.alarm.disarm();
+        }
+    }
+
+    fn distance_mm(&self) -> u32 {
+        self.steps_since_fix
+            .get()
+            .saturating_mul(self.stride_mm.get())
+    }
+
+    fn sample(&self, accel: [f32; 3]) {
+        let norm = accel[0] * accel[0] + accel[1] * accel[1] + accel[2] * accel[2];
+        let magnitude = if norm > 0.0 {
+            norm * inv_sqrt(norm)
+        } else {
+            0.0
+        };
+        let baseline = self.baseline.get().unwrap_or(magnitude);
+        self.baseline
+            .set(Some(baseline + (magnitude - baseline) / 16.0));
+        let dynamic = magnitude - baseline;
+
+        if dynamic > MOTION_THRESHOLD_MG || dynamic < -MOTION_THRESHOLD_MG {
+            self.motion_samples
+                .set(self.motion_samples.get().wrapping_add(1));
+        }
+
+        let samples = self.samples_since_step.get().saturating_add(1);
+        self.samples_since_step.set(samples);
+        if dynamic < 0.0 {
+            self.armed.set(true);
+        } else if dynamic > STEP_THRESHOLD_MG
+            && self.armed.get()
+            && samples.saturating_mul(SAMPLE_INTERVAL_MS) >= MIN_STEP_MS
+        {
+            self.armed.set(false);
+            self.samples_since_step.set(0);
+            self.steps.set(self.steps.get().wrapping_add(1));
+            self.steps_since_fix
+                .set(self.steps_since_fix.get().saturating_add(1));
+            self.notify();
+        }
+    }
+
+    /// Call back the processes whose distance the estimate passed.
+    fn notify(&self) {
+        let distance = self.distance_mm();
+        let steps = self.steps_since_fix.get();
+        self.apps.each(|_, app| {
+            if app.enabled && app.threshold_mm > 0 && !app.notified && distance >= app.threshold_mm
+            {
+                app.notified = true;
+                app.callback.schedule(distance as usize, steps as usize, 0);
+            }
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Odometer<'a, A> {
+    fn alarm(&self) {
+        if !self.running.get() {
+            return;
+        }
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(SAMPLE_INTERVAL_MS));
+        // Skip this sample if the last one is still reading.
+        if !self.reading.get() && self.accelerometer.read_accelerometer().is_ok() {
+            self.reading.set(true);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> NineDofClient for Odometer<'a, A> {
+    fn callback(&self, x: usize, y: usize, z: usize) {
+        if !self.reading.get() {
+            return;
+        }
+        self.reading.set(false);
+        // Drivers pass signed values.
+        let scale = self.accel_scale;
+        self.sample([
+            x as isize as f32 * scale,
+            y as isize as f32 * scale,
+            z as isize as f32 * scale,
+        ]);
+    }
+}
+
+impl<'a, A: Alarm<'a>> FixClient for Odometer<'a, A> {
+    fn fix(&self, fix: Fix) {
+        let steps = self.steps_since_fix.get();
+        if let Some(last) = self.last_fix.get() {
+            if steps >= CALIBRATION_STEPS {
+                let distance_sq =
+                    distance_sq_m(last.latitude, last.longitude, fix.latitude, fix.longitude);
+                let distance_mm = if distance_sq > 0.0 {
+                    distance_sq * inv_sqrt(distance_sq) * 1000.0
+                } else {
+                    0.0
+                };
+                let stride = (distance_mm / steps as f32) as u32;
+                if (MIN_STRIDE_MM..=MAX_STRIDE_MM).contains(&stride) {
+                    self.stride_mm.set((3 * self.stride_mm.get() + stride) / 4);
+                }
+            }
+        }
+        self.last_fix.set(Some(fix));
+        self.steps_since_fix.set(0);
+        self.apps.each(|_, app| app.notified = false);
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for Odometer<'a, A> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Distance passed callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Control the odometer and read its counts.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the sample rate.
+    /// - `1`: Enable, with a distance to call back at
+    /// - `2`: Disable
+    /// - `3`: Get the steps and seconds of motion
+    /// - `4`: Get the distance and steps since the last fix
+    /// - `5`: Get the stride length
+    /// - `6`: Set the stride length
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success_u32(SAMPLE_RATE_HZ),
+            1 | 2 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app| {
+                        app.enabled = cmd_num == 1;
+                        app.threshold_mm = arg1 as u32;
+                        app.notified = false;
+                    })
+                    .map_err(ErrorCode::from);
+                match res {
+                    Ok(()) => {
+                        self.update_running();
+                        self.notify();
+                        CommandReturn::success()
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+            3 => CommandReturn::success_u32_u32(
+                self.steps.get(),
+                self.motion_samples.get() / SAMPLE_RATE_HZ,
+            ),
+            4 => CommandReturn::success_u32_u32(self.distance_mm(), self.steps_since_fix.get()),
+            5 => CommandReturn::success_u32(self.stride_mm.get()),
+            6 => {
+                let stride = arg1 as u32;
+                if (MIN_STRIDE_MM..=MAX_STRIDE_MM).contains(&stride) {
+                    self.stride_mm.set(stride);
+                    CommandReturn::success()
+                } else {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                }
+            }
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! 1-Wire bus master bit-banged on a GPIO pin.
+//!
+//! The pin is driven low to pull the bus down and switched to an input to
+//! release it, so the bus needs an external pull-up resistor (typically
+//! 4.7 kOhm). Bit timings follow the standard speed timings from Maxim
+//! application note 126.
+//!
+//! The long parts of a transaction (the reset pulse and presence window, and
+//! the gaps between bytes) are waited out with an alarm. The slots of a
+//! single byte are timed by polling the alarm's counter, which needs to run
+//! at 1 MHz or faster to get the timing right. Each byte keeps the CPU busy
+//! for about 0.6 ms, during which interrupts stretching a slot by more than
+//! a few microseconds can corrupt a read.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let onewire_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let onewire = static_init!(
+//!     capsules::onewire_gpio::OneWireGpio<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::onewire_gpio::OneWireGpio::new(&sam4l::gpio::PA[16], onewire_alarm)
+//! );
+//! onewire_alarm.set_alarm_client(onewire);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::onewire::{OneWireClient, OneWireMaster, SEARCH_ROM};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ErrorCode;
+
+/// Length of the reset pulse.
+const RESET_LOW_US: u32 = 480;
+/// Time from releasing the bus to sampling the presence pulse.
+const PRESENCE_SAMPLE_US: u32 = 70;
+/// Rest of the presence detection window after sampling.
+const PRESENCE_WAIT_US: u32 = 410;
+/// Gap between bytes, the bus is idle (high) during it.
+const BYTE_GAP_US: u32 = 100;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// The bus is held low for the reset pulse.
+    ResetPulse,
+    /// Waiting out the presence detection window.
+    Presence,
+    Writing,
+    Reading,
+    /// Walking the ROM code tree, one byte of ROM code per alarm.
+    Searching,
+    /// Every device has already been found, report it.
+    SearchEnd,
+}
+
+pub struct OneWireGpio<'a, A: Alarm<'a>> {
+    pin: &'a dyn gpio::Pin,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn OneWireClient>,
+    state: Cell<State>,
+    buffer: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    index: Cell<usize>,
+    presence: Cell<bool>,
+    /// Whether the current reset is the start of a search.
+    searching: Cell<bool>,
+    rom: Cell<u64>,
+    /// Bit number (1-64) of the last branch where the previous search took
+    /// the 0 path, or 0 if there was none.
+    last_discrepancy: Cell<u8>,
+    last_zero: Cell<u8>,
+    last_device: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>> OneWireGpio<'a, A> {
+    pub fn new(pin: &'a dyn gpio::Pin, alarm: &'a A) -> OneWireGpio<'a, A> {
+        OneWireGpio {
+            pin: pin,
+            alarm: alarm,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            buffer: TakeCell::empty(),
+            len: Cell::new(0),
+            index: Cell::new(0),
+            presence: Cell::new(false),
+            searching: Cell::new(false),
+            rom: Cell::new(0),
+            last_discrepancy: Cell::new(0),
+            last_zero: Cell::new(0),
+            last_device: Cell::new(false),
+        }
+    }
+
+    fn set_alarm_us(&self, us: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_us(us));
+    }
+
+    fn delay_us(&self, us: u32) {
+        let start = self.alarm.now();
+        let delay = A::ticks_from_us(us);
+        while self.alarm.now().wrapping_sub(start) < delay {}
+    }
+
+    fn drive_low(&self) {
+        self.pin.make_output();
+        self.pin.clear();
+    }
+
+    fn release(&self) {
+        self.pin.make_input();
+    }
+
+    fn write_bit(&self, bit: bool) {
+        self.drive_low();
+        if bit {
+            self.delay_us(6);
+            self.release();
+            self.delay_us(64);
+        } else {
+            self.delay_us(60);
+            self.release();
+            self.delay_us(10);
+        }
+    }
+
+    fn read_bit(&self) -> bool {
+        self.drive_low();
+        self.delay_us(6);
+        self.release();
+        self.delay_us(9);
+        let bit = self.pin.read();
+        self.delay_us(55);
+        bit
+    }
+
+    fn write_byte(&self, byte: u8) {
+        for i in 0..8 {
+            self.write_bit(byte & (1 << i) != 0);
+        }
+    }
+
+    fn read_byte(&self) -> u8 {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit() {
+                byte |= 1 << i;
+            }
+        }
+        byte
+    }
+
+    fn start_reset(&self, searching: bool) {
+        self.searching.set(searching);
+        self.state.set(State::ResetPulse);
+        self.drive_low();
+        self.set_alarm_us(RESET_LOW_US);
+    }
+
+    /// Run the search for one byte of the ROM code, starting at bit number
+    /// `first` (1-64). Returns false if no device answered.
+    fn search_byte(&self, first: u8) -> bool {
+        let mut rom = self.rom.get();
+        for bit_number in first..first + 8 {
+            let mask = 1u64 << (bit_number - 1);
+            let id_bit = self.read_bit();
+            let cmp_id_bit = self.read_bit();
+            let direction = if id_bit && cmp_id_bit {
+                return false;
+            } else if id_bit != cmp_id_bit {
+                // All devices left have the same value for this bit.
+                id_bit
+            } else {
+                // Devices disagree: take the same path as last time before
+                // the last discrepancy, the 1 path at it and the 0 path
+                // after it.
+                let direction = if bit_number < self.last_discrepancy.get() {
+                    rom & mask != 0
+                } else {
+                    bit_number == self.last_discrepancy.get()
+                };
+                if !direction {
+                    self.last_zero.set(bit_number);
+                }
+                direction
+            };
+            if direction {
+                rom |= mask;
+            } else {
+                rom &= !mask;
+            }
+            self.write_bit(direction);
+        }
+        self.rom.set(rom);
+        true
+    }
+
+    fn search_complete(&self, result: Result<Option<u64>, ErrorCode>) {
+        self.state.set(State::Idle);
+        if result.is_err() || result == Ok(None) {
+            self.search_reset();
+        }
+        self.client.map(|client| client.search_done(result));
+    }
+}
+
+impl<'a, A: Alarm<'a>> OneWireMaster<'a> for OneWireGpio<'a, A> {
+    fn set_client(&self, client: &'a dyn OneWireClient) {
+        self.client.set(client);
+    }
+
+    fn reset(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.start_reset(false);
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != State::Idle {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len == 0 || len > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+        self.buffer.replace(buffer);
+        self.len.set(len);
+        self.index.set(0);
+        self.state.set(State::Writing);
+        self.set_alarm_us(BYTE_GAP_US);
+        Ok(())
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != State::Idle {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len == 0 || len > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+        self.buffer.replace(buffer);
+        self.len.set(len);
+        self.index.set(0);
+        self.state.set(State::Reading);
+        self.set_alarm_us(BYTE_GAP_US);
+        Ok(())
+    }
+
+    fn search_reset(&self) {
+        self.rom.set(0);
+        self.last_discrepancy.set(0);
+        self.last_device.set(false);
+    }
+
+    fn search_next(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        if self.last_device.get() {
+            self.state.set(State::SearchEnd);
+            self.set_alarm_us(BYTE_GAP_US);
+        } else {
+            self.start_reset(true);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for OneWireGpio<'a, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Idle => {}
+            State::ResetPulse => {
+                self.release();
+                self.delay_us(PRESENCE_SAMPLE_US);
+                // Devices answer by pulling the bus low.
+                self.presence.set(!self.pin.read());
+                self.state.set(State::Presence);
+                self.set_alarm_us(PRESENCE_WAIT_US);
+            }
+            State::Presence => {
+                if !self.searching.get() {
+                    self.state.set(State::Idle);
+                    let result = if self.presence.get() {
+                        Ok(())
+                    } else {
+                        Err(ErrorCode::NOACK)
+                    };
+                    self.client.map(|client| client.reset_done(result));
+                } else if !self.presence.get() {
+                    self.search_complete(Err(ErrorCode::NOACK));
+                } else {
+                    self.write_byte(SEARCH_ROM);
+                    self.index.set(0);
+                    self.last_zero.set(0);
+                    self.state.set(State::Searching);
+                    self.set_alarm_us(BYTE_GAP_US);
+                }
+            }
+            State::Writing => {
+                let index = self.index.get();
+                self.buffer.map(|buffer| self.write_byte(buffer[index]));
+                self.index.set(index + 1);
+                if index + 1 < self.len.get() {
+                    self.set_alarm_us(BYTE_GAP_US);
+                } else {
+                    self.state.set(State::Idle);
+                    self.buffer.take().map(|buffer| {
+                        self.client
+                            .map(move |client| client.write_done(buffer, Ok(())));
+                    });
+                }
+            }
+            State::Reading => {
+                let index = self.index.get();
+                let byte = self.read_byte();
+                self.buffer.map(|buffer| buffer[index] = byte);
+                self.index.set(index + 1);
+                if index + 1 < self.len.get() {
+                    self.set_alarm_us(BYTE_GAP_US);
+                } else {
+                    self.state.set(State::Idle);
+                    self.buffer.take().map(|buffer| {
+                        self.client
+                            .map(move |client| client.read_done(buffer, Ok(())));
+                    });
+                }
+            }
+            State::Searching => {
+                let index = self.index.get();
+                if !self.search_byte(index as u8 * 8 + 1) {
+                    // No device answered the search.
+                    self.search_complete(Ok(None));
+                } else if index + 1 < 8 {
+                    self.index.set(index + 1);
+                    self.set_alarm_us(BYTE_GAP_US);
+                } else {
+                    self.last_discrepancy.set(self.last_zero.get());
+                    if self.last_zero.get() == 0 {
+                        self.last_device.set(true);
+                    }
+                    self.search_complete(Ok(Some(self.rom.get())));
+                }
+            }
+            State::SearchEnd => self.search_complete(Ok(None)),
+        }
+    }
+}
@@ -0,0 +1,1280 @@
+//! Software implementation of ECDSA over the NIST P-256 curve.
+//!
+//! Implements `hil::signature::KeyGenerate` and
+//! `hil::signature::SignatureSign` on the CPU, for chips without a
+//! public-key accelerator or secure element. The private keys are kept in
+//! RAM, so they are lost on reset, unless the board gives the capsule a
+//! region of nonvolatile storage with `set_storage()`. The keys are then
+//! written there when they are generated and read back by `load()` at boot.
+//! They are stored in the clear, so the region must not be readable by
+//! processes or over a debug port.
+//!
+//! The arithmetic runs on a `ChunkedExecutor`, one bit of the scalar
+//! multiplication or of the modular inversion at a time, so generating a key
+//! or signing, which takes a few million cycles, does not hold up the rest of
+//! the system. Randomness for private keys and for the per-signature nonces
+//! comes from an `hil::rng::Rng`, usually a virtual device of a
+//! `MuxRngMaster`, so that the jitter source can share the TRNG.
+//!
+//! Scalar multiplication uses the complete addition formulas of Renes,
+//! Costello and Batina, and doubles and adds for every one of the 256 bits
+//! of the scalar, keeping or dropping each sum with a mask rather than a
+//! branch. The code has no branches or memory accesses that depend on the
+//! private key or the nonce, but it is not verified to run in constant time:
+//! that is up to the compiler and the CPU, for example how long a
+//! multiplication takes, and has not been measured on any chip. It is not
+//! hardened against other side channels, such as power analysis either.
+//!
+//! With `set_jitter()`, the arithmetic of each operation starts after a
+//! random delay, which blurs when signatures are computed. If the delay
+//! cannot be started, the operation fails rather than running without it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let p256 = static_init!(
+//!     capsules::p256::P256Software<'static>,
+//!     capsules::p256::P256Software::new(p256_task, rng)
+//! );
+//! p256_task.set_client(p256);
+//! rng.set_client(p256);
+//!
+//! // Optionally, keep the keys across resets.
+//! p256.set_storage(storage, 0x0, &mut capsules::p256::KEY_BUFFER);
+//! storage.set_client(p256);
+//! p256.load();
+//! ```
+
+use core::cell::Cell;
+use core::convert::TryInto;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::jitter::{Jitter, JitterClient};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::rng;
+use kernel::hil::signature::{self, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use kernel::ErrorCode;
+
+use crate::chunked_executor::{ChunkedTask, ExecutorTask};
+
+/// The number of key slots.
+pub const KEY_SLOTS: usize = 8;
+
+/// The bytes of storage for one key slot: `KEY_MAGIC` and the private key.
+const KEY_RECORD_LEN: usize = 36;
+/// The bytes of storage for all the key slots.
+pub const KEY_STORAGE_LEN: usize = KEY_SLOTS * KEY_RECORD_LEN;
+/// Marks a record that holds a key, so that erased or zeroed storage reads
+/// back as an empty slot.
+const KEY_MAGIC: [u8; 4] = *b"P256";
+
+pub static mut KEY_BUFFER: [u8; KEY_STORAGE_LEN] = [0; KEY_STORAGE_LEN];
+
+/// Estimated cost of one modular multiplication, in cycles.
+const MUL_CYCLES: u32 = 700;
+/// Estimated cost of one bit of a scalar multiplication: a point doubling
+/// and a point addition.
+const MULTIPLY_BIT_CYCLES: u32 = 26 * MUL_CYCLES;
+/// Estimated cost of one bit of a modular exponentiation.
+const INVERT_BIT_CYCLES: u32 = 2 * MUL_CYCLES;
+
+/// A 256-bit integer, least significant word first.
+type Limbs = [u32; 8];
+
+/// A point in homogeneous projective coordinates (X : Y : Z), which is the
+/// affine point (X / Z, Y / Z), in the Montgomery domain. The point at
+/// infinity is (0 : 1 : 0).
+type Point = [Limbs; 3];
+
+/// An odd modulus, with the constants for Montgomery multiplication.
+struct Modulus {
+    m: Limbs,
+    /// -m^-1 mod 2^32
+    m_inv: u32,
+    /// 2^512 mod m
+    r2: Limbs,
+}
+
+/// The field prime p = 2^256 - 2^224 + 2^192 + 2^96 - 1.
+const P: Modulus = Modulus {
+    m: [
+        0xffffffff, 0xffffffff, 0xffffffff, 0x00000000, 0x00000000, 0x00000000, 0x00000001,
+        0xffffffff,
+    ],
+    m_inv: 0x00000001,
+    r2: [
+        0x00000003, 0x00000000, 0xffffffff, 0xfffffffb, 0xfffffffe, 0xffffffff, 0xfffffffd,
+        0x00000004,
+    ],
+};
+
+/// The order n of the base point.
+const N: Modulus = Modulus {
+    m: [
+        0xfc632551, 0xf3b9cac2, 0xa7179e84, 0xbce6faad, 0xffffffff, 0xffffffff, 0x00000000,
+        0xffffffff,
+    ],
+    m_inv: 0xee00bc4f,
+    r2: [
+        0xbe79eea2, 0x83244c95, 0x49bd6fa6, 0x4699799c, 0x2b6bec59, 0x2845b239, 0xf3d95620,
+        0x66e12d94,
+    ],
+};
+
+/// The X coordinate of the base point G.
+const GX: Limbs = [
+    0xd898c296, 0xf4a13945, 0x2deb33a0, 0x77037d81, 0x63a440f2, 0xf8bce6e5, 0xe12c4247, 0x6b17d1f2,
+];
+/// The Y coordinate of the base point G.
+const GY: Limbs = [
+    0x37bf51f5, 0xcbb64068, 0x6b315ece, 0x2bce3357, 0x7c0f9e16, 0x8ee7eb4a, 0xfe1a7f9b, 0x4fe342e2,
+];
+
+/// The constant b of the curve equation y^2 = x^3 - 3x + b.
+const B: Limbs = [
+    0x27d2604b, 0x3bce3c3e, 0xcc53b0f6, 0x651d06b0, 0x769886bc, 0xb3ebbd55, 0xaa3a93e7, 0x5ac635d8,
+];
+
+const ONE: Limbs = [1, 0, 0, 0, 0, 0, 0, 0];
+const ZERO: Limbs = [0; 8];
+
+fn from_be_bytes(bytes: &[u8; 32]) -> Limbs {
+    let mut limbs = ZERO;
+    for (i, limb) in limbs.iter_mut().enumerate() {
+        let start = 28 - 4 * i;
+        *limb = u32::from_be_bytes(bytes[start..start + 4].try_into().unwrap_or([0; 4]));
+    }
+    limbs
+}
+
+fn to_be_bytes(limbs: &Limbs, bytes: &mut [u8]) {
+    for (i, limb) in limbs.iter().enumerate() {
+        let start = 28 - 4 * i;
+        bytes[start..start + 4].copy_from_slice(&limb.to_be_bytes());
+    }
+}
+
+fn is_zero(a: &Limbs) -> bool {
+    a.iter().fold(0, |acc, limb| acc | limb) == 0
+}
+
+/// `a - b`, and the borrow out.
+fn sub(a: &Limbs, b: &Limbs) -> (Limbs, u32) {
+    let mut r = ZERO;
+    let mut borrow = 0u64;
+    for i in 0..8 {
+        let d = (a[i] as u64).wrapping_sub(b[i] as u64 + borrow);
+        r[i] = d as u32;
+        borrow = (d >> 32) & 1;
+    }
+    (r, borrow as u32)
+}
+
+/// `a + b`, and the carry out.
+fn add(a: &Limbs, b: &Limbs) -> (Limbs, u32) {
+    let mut r = ZERO;
+    let mut carry = 0u64;
+    for i in 0..8 {
+        let s = a[i] as u64 + b[i] as u64 + carry;
+        r[i] = s as u32;
+        carry = s >> 32;
+    }
+    (r, carry as u32)
+}
+
+/// `a` if `mask` is all ones, `b` if it is zero.
+fn select(mask: u32, a: &Limbs, b: &Limbs) -> Limbs {
+    let mut r = ZERO;
+    for i in 0..8 {
+        r[i] = (a[i] & mask) | (b[i] & !mask);
+    }
+    r
+}
+
+/// Reduce `a + carry * 2^256`, which is less than `2m`, modulo `m`.
+fn reduce_once(a: &Limbs, carry: u32, md: &Modulus) -> Limbs {
+    let (d, borrow) = sub(a, &md.m);
+    // Keep the difference unless it went negative without a carry to absorb
+    // the borrow.
+    let keep_a = (borrow & !carry & 1).wrapping_neg();
+    select(keep_a, a, &d)
+}
+
+fn add_mod(a: &Limbs, b: &Limbs, md: &Modulus) -> Limbs {
+    let (s, carry) = add(a, b);
+    reduce_once(&s, carry, md)
+}
+
+fn sub_mod(a: &Limbs, b: &Limbs, md: &Modulus) -> Limbs {
+    let (d, borrow) = sub(a, b);
+    let (s, _) = add(&d, &md.m);
+    select(borrow.wrapping_neg(), &s, &d)
+}
+
+/// Montgomery multiplication: `a * b / 2^256 mod m`.
+fn mont_mul(a: &Limbs, b: &Limbs, md: &Modulus) -> Limbs {
+    let mut t = [0u32; 10];
+    for i in 0..8 {
+        let mut carry = 0u64;
+        for j in 0..8 {
+            let s = t[j] as u64 + a[j] as u64 * b[i] as u64 + carry;
+            t[j] = s as u32;
+            carry = s >> 32;
+        }
+        let s = t[8] as u64 + carry;
+        t[8] = s as u32;
+        t[9] = (s >> 32) as u32;
+
+        let u = t[0].wrapping_mul(md.m_inv);
+        let mut carry = (t[0] as u64 + u as u64 * md.m[0] as u64) >> 32;
+        for j in 1..8 {
+            let s = t[j] as u64 + u as u64 * md.m[j] as u64 + carry;
+            t[j - 1] = s as u32;
+            carry = s >> 32;
+        }
+        let s = t[8] as u64 + carry;
+        t[7] = s as u32;
+        t[8] = t[9] + (s >> 32) as u32;
+    }
+    let mut r = ZERO;
+    r.copy_from_slice(&t[..8]);
+    reduce_once(&r, t[8], md)
+}
+
+fn to_mont(a: &Limbs, md: &Modulus) -> Limbs {
+    mont_mul(a, &md.r2, md)
+}
+
+fn from_mont(a: &Limbs, md: &Modulus) -> Limbs {
+    mont_mul(a, &ONE, md)
+}
+
+/// Double a point, with the complete formulas for a = -3 (Renes, Costello
+/// and Batina, "Complete addition formulas for prime order elliptic curves",
+/// algorithm 6). `b` is in the Montgomery domain.
+fn point_double(p: &Point, b: &Limbs) -> Point {
+    let [x, y, z] = p;
+    let t0 = mont_mul(x, x, &P);
+    let t1 = mont_mul(y, y, &P);
+    let t2 = mont_mul(z, z, &P);
+    let t3 = mont_mul(x, y, &P);
+    let t3 = add_mod(&t3, &t3, &P);
+    let z3 = mont_mul(x, z, &P);
+    let z3 = add_mod(&z3, &z3, &P);
+    let y3 = mont_mul(b, &t2, &P);
+    let y3 = sub_mod(&y3, &z3, &P);
+    let x3 = add_mod(&y3, &y3, &P);
+    let y3 = add_mod(&x3, &y3, &P);
+    let x3 = sub_mod(&t1, &y3, &P);
+    let y3 = add_mod(&t1, &y3, &P);
+    let y3 = mont_mul(&x3, &y3, &P);
+    let x3 = mont_mul(&x3, &t3, &P);
+    let t3 = add_mod(&t2, &t2, &P);
+    let t2 = add_mod(&t2, &t3, &P);
+    let z3 = mont_mul(b, &z3, &P);
+    let z3 = sub_mod(&z3, &t2, &P);
+    let z3 = sub_mod(&z3, &t0, &P);
+    let t3 = add_mod(&z3, &z3, &P);
+    let z3 = add_mod(&z3, &t3, &P);
+    let t3 = add_mod(&t0, &t0, &P);
+    let t0 = add_mod(&t3, &t0, &P);
+    let t0 = sub_mod(&t0, &t2, &P);
+    let t0 = mont_mul(&t0, &z3, &P);
+    let y3 = add_mod(&y3, &t0, &P);
+    let t0 = mont_mul(y, z, &P);
+    let t0 = add_mod(&t0, &t0, &P);
+    let z3 = mont_mul(&t0, &z3, &P);
+    let x3 = sub_mod(&x3, &z3, &P);
+    let z3 = mont_mul(&t0, &t1, &P);
+    let z3 = add_mod(&z3, &z3, &P);
+    let z3 = add_mod(&z3, &z3, &P);
+    [x3, y3, z3]
+}
+
+/// Add two points, with the complete formulas for a = -3 (algorithm 4 of
+/// the same paper). They are correct for any two points, including equal
+/// points and the point at infinity, so the sum takes the same steps
+/// whatever the points are. `b` is in the Montgomery domain.
+fn point_add(p: &Point, q: &Point, b: &Limbs) -> Point {
+    let [x1, y1, z1] = p;
+    let [x2, y2, z2] = q;
+    let t0 = mont_mul(x1, x2, &P);
+    let t1 = mont_mul(y1, y2, &P);
+    let t2 = mont_mul(z1, z2, &P);
+    let t3 = add_mod(x1, y1, &P);
+    let t4 = add_mod(x2, y2, &P);
+    let t3 = mont_mul(&t3, &t4, &P);
+    let t4 = add_mod(&t0, &t1, &P);
+    let t3 = sub_mod(&t3, &t4, &P);
+    let t4 = add_mod(y1, z1, &P);
+    let x3 = add_mod(y2, z2, &P);
+    let t4 = mont_mul(&t4, &x3, &P);
+    let x3 = add_mod(&t1, &t2, &P);
+    let t4 = sub_mod(&t4, &x3, &P);
+    let x3 = add_mod(x1, z1, &P);
+    let y3 = add_mod(x2, z2, &P);
+    let x3 = mont_mul(&x3, &y3, &P);
+    let y3 = add_mod(&t0, &t2, &P);
+    let y3 = sub_mod(&x3, &y3, &P);
+    let z3 = mont_mul(b, &t2, &P);
+    let x3 = sub_mod(&y3, &z3, &P);
+    let z3 = add_mod(&x3, &x3, &P);
+    let x3 = add_mod(&x3, &z3, &P);
+    let z3 = sub_mod(&t1, &x3, &P);
+    let x3 = add_mod(&t1, &x3, &P);
+    let y3 = mont_mul(b, &y3, &P);
+    let t1 = add_mod(&t2, &t2, &P);
+    let t2 = add_mod(&t1, &t2, &P);
+    let y3 = sub_mod(&y3, &t2, &P);
+    let y3 = sub_mod(&y3, &t0, &P);
+    let t1 = add_mod(&y3, &y3, &P);
+    let y3 = add_mod(&t1, &y3, &P);
+    let t1 = add_mod(&t0, &t0, &P);
+    let t0 = add_mod(&t1, &t0, &P);
+    let t0 = sub_mod(&t0, &t2, &P);
+    let t1 = mont_mul(&t4, &y3, &P);
+    let t2 = mont_mul(&t0, &y3, &P);
+    let y3 = mont_mul(&x3, &z3, &P);
+    let y3 = add_mod(&y3, &t2, &P);
+    let x3 = mont_mul(&x3, &t3, &P);
+    let x3 = sub_mod(&x3, &t1, &P);
+    let z3 = mont_mul(&z3, &t4, &P);
+    let t1 = mont_mul(&t3, &t0, &P);
+    let z3 = add_mod(&z3, &t1, &P);
+    [x3, y3, z3]
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    /// Reading the keys from storage.
+    Load,
+    GenerateKey(usize),
+    Sign(usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    /// Waiting for the random scalar.
+    Random,
+    /// Multiplying the base point by the scalar, with the number of bits
+    /// left.
+    Multiply(usize),
+    /// Inverting the Z coordinate of the result, to make it affine.
+    InvertZ,
+    /// Inverting the nonce of a signature.
+    InvertNonce,
+    /// Writing a new private key to storage.
+    Store,
+}
+
+pub struct P256Software<'a> {
+    task: &'a ExecutorTask<'a>,
+    rng: &'a dyn rng::Rng<'a>,
+    key_client: OptionalCell<&'a dyn signature::KeyGenerateClient>,
+    sign_client: OptionalCell<&'a dyn signature::SignClient>,
+    keys: [Cell<Option<Limbs>>; KEY_SLOTS],
+    storage: OptionalCell<&'a dyn NonvolatileStorage<'static>>,
+    storage_address: Cell<usize>,
+    storage_buffer: TakeCell<'static, [u8]>,
+    jitter: OptionalCell<&'a dyn Jitter<'a>>,
+    /// The longest random delay before the arithmetic of an operation.
+    jitter_max_us: Cell<u32>,
+
+    operation: Cell<Operation>,
+    step: Cell<Step>,
+    /// The new private key, or the nonce of a signature.
+    scalar: Cell<Limbs>,
+    random_words: Cell<usize>,
+    point: Cell<Point>,
+    /// The hash to sign, as an integer modulo n.
+    hash_value: Cell<Limbs>,
+    /// R of the signature, in the Montgomery domain.
+    r: Cell<Limbs>,
+    // A modular exponentiation, which runs one bit at a time.
+    exp_base: Cell<Limbs>,
+    exp_acc: Cell<Limbs>,
+    exp_bit: Cell<usize>,
+
+    public_key: TakeCell<'static, [u8; PUBLIC_KEY_LEN]>,
+    hash: TakeCell<'static, [u8; 32]>,
+    signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+}
+
+impl<'a> P256Software<'a> {
+    pub fn new(task: &'a ExecutorTask<'a>, rng: &'a dyn rng::Rng<'a>) -> P256Software<'a> {
+        P256Software {
+            task: task,
+            rng: rng,
+            key_client: OptionalCell::empty(),
+            sign_client: OptionalCell::empty(),
+            keys: Default::default(),
+            storage: OptionalCell::empty(),
+            storage_address: Cell::new(0),
+            storage_buffer: TakeCell::empty(),
+            jitter: OptionalCell::empty(),
+            jitter_max_us: Cell::new(0),
+            operation: Cell::new(Operation::Idle),
+            step: Cell::new(Step::Random),
+            scalar: Cell::new(ZERO),
+            random_words: Cell::new(0),
+            point: Cell::new([ZERO, ZERO, ZERO]),
+            hash_value: Cell::new(ZERO),
+            r: Cell::new(ZERO),
+            exp_base: Cell::new(ZERO),
+            exp_acc: Cell::new(ZERO),
+            exp_bit: Cell::new(0),
+            public_key: TakeCell::empty(),
+            hash: TakeCell::empty(),
+            signature: TakeCell::empty(),
+        }
+    }
+
+    /// Delay the arithmetic of each operation by a random time of up to
+    /// `max_us` microseconds, with `jitter`, which must have this as its
+    /// client.
+    pub fn set_jitter(&self, jitter: &'a dyn Jitter<'a>, max_us: u32) {
+        self.jitter.set(jitter);
+        self.jitter_max_us.set(max_us);
+    }
+
+    /// Keep the keys in `KEY_STORAGE_LEN` bytes of `storage` from
+    /// `address`, which must have this as its client. `buffer` must be at
+    /// least `KEY_STORAGE_LEN` bytes long.
+    pub fn set_storage(
+        &self,
+        storage: &'a dyn NonvolatileStorage<'static>,
+        address: usize,
+        buffer: &'static mut [u8],
+    ) {
+        self.storage.set(storage);
+        self.storage_address.set(address);
+        self.storage_buffer.replace(buffer);
+    }
+
+    /// Read the keys back from storage. Until this finishes, generating keys
+    /// and signing return `BUSY`.
+    pub fn load(&self) -> Result<(), ErrorCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let storage = self.storage.extract().ok_or(ErrorCode::OFF)?;
+        let buffer = self.storage_buffer.take().ok_or(ErrorCode::NOMEM)?;
+        self.operation.set(Operation::Load);
+        storage
+            .read(buffer, self.storage_address.get(), KEY_STORAGE_LEN)
+            .map_err(|e| {
+                self.operation.set(Operation::Idle);
+                e
+            })
+    }
+
+    /// Write the new private key of `slot` to storage. The slot only takes
+    /// the key once it has been written.
+    fn store_key(&self, slot: usize) -> Result<(), ErrorCode> {
+        let storage = self.storage.extract().ok_or(ErrorCode::OFF)?;
+        let buffer = self.storage_buffer.take().ok_or(ErrorCode::NOMEM)?;
+        buffer[..4].copy_from_slice(&KEY_MAGIC);
+        to_be_bytes(&self.scalar.get(), &mut buffer[4..KEY_RECORD_LEN]);
+        let address = self.storage_address.get() + slot * KEY_RECORD_LEN;
+        self.step.set(Step::Store);
+        storage.write(buffer, address, KEY_RECORD_LEN)
+    }
+
+    /// Start the arithmetic, after a random delay if there is a jitter
+    /// source. Returns the error of the jitter source if the delay cannot be
+    /// started, as the board asked for the delay.
+    fn schedule_arithmetic(&self) -> Result<(), ErrorCode> {
+        match self.jitter.extract() {
+            Some(jitter) => jitter.delay(self.jitter_max_us.get()),
+            None => {
+                self.task.schedule();
+                Ok(())
+            }
+        }
+    }
+
+    /// Ask for a new random scalar.
+    fn start_random(&self) -> Result<(), ErrorCode> {
+        self.step.set(Step::Random);
+        self.random_words.set(0);
+        self.rng.get()
+    }
+
+    /// Start computing `base^(m - 2) mod m`, the inverse of `base`, which is
+    /// in the Montgomery domain.
+    fn start_invert(&self, base: &Limbs, md: &Modulus) {
+        self.exp_base.set(*base);
+        self.exp_acc.set(to_mont(&ONE, md));
+        self.exp_bit.set(256);
+    }
+
+    /// Run up to `bits` bits of the inversion started by `start_invert()`.
+    /// Returns whether it has finished.
+    fn run_invert(&self, bits: usize, md: &Modulus) -> bool {
+        let (exponent, _) = sub(&md.m, &[2, 0, 0, 0, 0, 0, 0, 0]);
+        let base = self.exp_base.get();
+        let mut acc = self.exp_acc.get();
+        let mut bit = self.exp_bit.get();
+        for _ in 0..bits {
+            if bit == 0 {
+                break;
+            }
+            bit -= 1;
+            acc = mont_mul(&acc, &acc, md);
+            // The exponent is public, so it is fine to branch on it.
+            if (exponent[bit / 32] >> (bit % 32)) & 1 == 1 {
+                acc = mont_mul(&acc, &base, md);
+            }
+        }
+        self.exp_acc.set(acc);
+        self.exp_bit.set(bit);
+        bit == 0
+    }
+
+    /// Run up to `bits` of the `left` bits of the scalar multiplication of
+    /// the base point. Returns the number of bits left.
+    ///
+    /// The multiplication goes through all 256 bits of the scalar, leading
+    /// zeros included, and doubles and adds for each, so the operations it
+    /// runs do not depend on the scalar.
+    fn run_multiply(&self, bits: usize, left: usize) -> usize {
+        let scalar = self.scalar.get();
+        let b = to_mont(&B, &P);
+        let g = [to_mont(&GX, &P), to_mont(&GY, &P), to_mont(&ONE, &P)];
+        let mut point = self.point.get();
+        let mut left = left;
+        for _ in 0..bits {
+            if left == 0 {
+                break;
+            }
+            left -= 1;
+            // Always add, and keep the sum only if the bit is set.
+            point = point_double(&point, &b);
+            let sum = point_add(&point, &g, &b);
+            let mask = ((scalar[left / 32] >> (left % 32)) & 1).wrapping_neg();
+            point = [
+                select(mask, &sum[0], &point[0]),
+                select(mask, &sum[1], &point[1]),
+                select(mask, &sum[2], &point[2]),
+            ];
+        }
+        self.point.set(point);
+        left
+    }
+
+    /// The operation is over: clear the secrets and return the buffers.
+    fn finish(&self, result: Result<(), ErrorCode>) {
+        let operation = self.operation.get();
+        self.operation.set(Operation::Idle);
+        self.scalar.set(ZERO);
+        self.point.set([ZERO, ZERO, ZERO]);
+        self.exp_base.set(ZERO);
+        self.exp_acc.set(ZERO);
+        match operation {
+            Operation::GenerateKey(_) => {
+                self.public_key.take().map(|public_key| {
+                    self.key_client
+                        .map(move |client| client.key_generated(result, public_key));
+                });
+            }
+            Operation::Sign(_) => {
+                self.hash.take().map(|hash| {
+                    self.signature.take().map(|signature| {
+                        self.sign_client
+                            .map(move |client| client.signing_done(result, hash, signature));
+                    });
+                });
+            }
+            Operation::Idle | Operation::Load => (),
+        }
+    }
+
+    /// The affine X coordinate of the result, and its Y coordinate, once
+    /// the inverse of Z has been computed.
+    fn affine_result(&self) -> (Limbs, Limbs) {
+        let [x, y, _] = self.point.get();
+        let z_inv = self.exp_acc.get();
+        (
+            from_mont(&mont_mul(&x, &z_inv, &P), &P),
+            from_mont(&mont_mul(&y, &z_inv, &P), &P),
+        )
+    }
+}
+
+impl<'a> signature::KeyGenerate<'a> for P256Software<'a> {
+    fn set_key_client(&'a self, client: &'a dyn signature::KeyGenerateClient) {
+        self.key_client.set(client);
+    }
+
+    fn key_slots(&self) -> usize {
+        KEY_SLOTS
+    }
+
+    fn generate_key(
+        &self,
+        slot: usize,
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; PUBLIC_KEY_LEN])> {
+        if slot >= KEY_SLOTS {
+            return Err((ErrorCode::INVAL, public_key));
+        }
+        if self.operation.get() != Operation::Idle {
+            return Err((ErrorCode::BUSY, public_key));
+        }
+        if let Err(e) = self.start_random() {
+            return Err((e, public_key));
+        }
+        self.public_key.replace(public_key);
+        self.operation.set(Operation::GenerateKey(slot));
+        Ok(())
+    }
+}
+
+impl<'a> signature::SignatureSign<'a> for P256Software<'a> {
+    fn set_sign_client(&'a self, client: &'a dyn signature::SignClient) {
+        self.sign_client.set(client);
+    }
+
+    fn sign(
+        &self,
+        slot: usize,
+        hash: &'static mut [u8; 32],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ErrorCode,
+            &'static mut [u8; 32],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if slot >= KEY_SLOTS {
+            return Err((ErrorCode::INVAL, hash, signature));
+        }
+        if self.operation.get() != Operation::Idle {
+            return Err((ErrorCode::BUSY, hash, signature));
+        }
+        if self.keys[slot].get().is_none() {
+            return Err((ErrorCode::RESERVE, hash, signature));
+        }
+        if let Err(e) = self.start_random() {
+            return Err((e, hash, signature));
+        }
+        // The hash is 256 bits long, so less than 2n.
+        self.hash_value
+            .set(reduce_once(&from_be_bytes(hash), 0, &N));
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+        self.operation.set(Operation::Sign(slot));
+        Ok(())
+    }
+}
+
+impl rng::Client for P256Software<'_> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        let operation = self.operation.get();
+        if operation == Operation::Idle
+            || operation == Operation::Load
+            || self.step.get() != Step::Random
+        {
+            return rng::Continue::Done;
+        }
+        if let Err(e) = error {
+            self.finish(Err(e));
+            return rng::Continue::Done;
+        }
+
+        let mut scalar = self.scalar.get();
+        let mut count = self.random_words.get();
+        for word in randomness.take(8 - count) {
+            scalar[count] = word;
+            count += 1;
+        }
+        self.scalar.set(scalar);
+        self.random_words.set(count);
+        if count < 8 {
+            return rng::Continue::More;
+        }
+
+        // The scalar must be in [1, n - 1], so draw again otherwise.
+        let (_, borrow) = sub(&scalar, &N.m);
+        if is_zero(&scalar) || borrow == 0 {
+            self.random_words.set(0);
+            return rng::Continue::More;
+        }
+
+        // Start from the point at infinity.
+        self.point.set([ZERO, to_mont(&ONE, &P), ZERO]);
+        self.step.set(Step::Multiply(256));
+        if let Err(e) = self.schedule_arithmetic() {
+            self.finish(Err(e));
+        }
+        rng::Continue::Done
+    }
+}
+
+impl NonvolatileStorageClient<'static> for P256Software<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        if self.operation.get() == Operation::Load {
+            let records = buffer[..length.min(KEY_STORAGE_LEN)].chunks_exact(KEY_RECORD_LEN);
+            for (slot, record) in records.enumerate() {
+                if record[..4] != KEY_MAGIC {
+                    continue;
+                }
+                // Only take a key that could have been generated here.
+                let key = from_be_bytes(&record[4..].try_into().unwrap());
+                let (_, borrow) = sub(&key, &N.m);
+                if !is_zero(&key) && borrow == 1 {
+                    self.keys[slot].set(Some(key));
+                }
+            }
+            self.operation.set(Operation::Idle);
+        }
+        buffer.iter_mut().for_each(|byte| *byte = 0);
+        self.storage_buffer.replace(buffer);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        buffer.iter_mut().for_each(|byte| *byte = 0);
+        self.storage_buffer.replace(buffer);
+        if let (Operation::GenerateKey(slot), Step::Store) = (self.operation.get(), self.step.get())
+        {
+            if length == KEY_RECORD_LEN {
+                self.keys[slot].set(Some(self.scalar.get()));
+                self.finish(Ok(()));
+            } else {
+                self.finish(Err(ErrorCode::FAIL));
+            }
+        }
+    }
+}
+
+impl JitterClient for P256Software<'_> {
+    fn delay_done(&self) {
+        if self.operation.get() != Operation::Idle {
+            self.task.schedule();
+        }
+    }
+}
+
+impl ChunkedTask for P256Software<'_> {
+    fn run_chunk(&self, budget: u32) -> bool {
+        match (self.operation.get(), self.step.get()) {
+            (Operation::Idle, _) | (Operation::Load, _) | (_, Step::Random) | (_, Step::Store) => {
+                true
+            }
+            (_, Step::Multiply(left)) => {
+                let bits = (budget / MULTIPLY_BIT_CYCLES).max(1) as usize;
+                let left = self.run_multiply(bits, left);
+                if left == 0 {
+                    // The scalar is in [1, n - 1], so the result is not the
+                    // point at infinity.
+                    let [_, _, z] = self.point.get();
+                    self.start_invert(&z, &P);
+                    self.step.set(Step::InvertZ);
+                } else {
+                    self.step.set(Step::Multiply(left));
+                }
+                false
+            }
+            (operation, Step::InvertZ) => {
+                let bits = (budget / INVERT_BIT_CYCLES).max(1) as usize;
+                if !self.run_invert(bits, &P) {
+                    return false;
+                }
+                let (x, y) = self.affine_result();
+                match operation {
+                    Operation::GenerateKey(slot) => {
+                        self.public_key.map(|public_key| {
+                            to_be_bytes(&x, &mut public_key[..32]);
+                            to_be_bytes(&y, &mut public_key[32..]);
+                        });
+                        if self.storage.is_none() {
+                            self.keys[slot].set(Some(self.scalar.get()));
+                            self.finish(Ok(()));
+                        } else if let Err(e) = self.store_key(slot) {
+                            self.finish(Err(e));
+                        }
+                        true
+                    }
+                    _ => {
+                        // r = x mod n, and x < p < 2n
+                        let r = reduce_once(&x, 0, &N);
+                        if is_zero(&r) {
+                            return self.start_random().map_or_else(
+                                |e| {
+                                    self.finish(Err(e));
+                                    true
+                                },
+                                |()| true,
+                            );
+                        }
+                        self.r.set(to_mont(&r, &N));
+                        self.start_invert(&to_mont(&self.scalar.get(), &N), &N);
+                        self.step.set(Step::InvertNonce);
+                        false
+                    }
+                }
+            }
+            (operation, Step::InvertNonce) => {
+                let bits = (budget / INVERT_BIT_CYCLES).max(1) as usize;
+                if !self.run_invert(bits, &N) {
+                    return false;
+                }
+                let slot = match operation {
+                    Operation::Sign(slot) => slot,
+                    _ => return true,
+                };
+                let key = match self.keys[slot].get() {
+                    Some(key) => key,
+                    None => {
+                        self.finish(Err(ErrorCode::RESERVE));
+                        return true;
+                    }
+                };
+                // s = k^-1 (e + r d) mod n
+                let r = self.r.get();
+                let rd = mont_mul(&r, &to_mont(&key, &N), &N);
+                let sum = add_mod(&to_mont(&self.hash_value.get(), &N), &rd, &N);
+                let s = from_mont(&mont_mul(&self.exp_acc.get(), &sum, &N), &N);
+                if is_zero(&s) {
+                    return self.start_random().map_or_else(
+                        |e| {
+                            self.finish(Err(e));
+                            true
+                        },
+                        |()| true,
+                    );
+                }
+                self.signature.map(|signature| {
+                    to_be_bytes(&from_mont(&r, &N), &mut signature[..32]);
+                    to_be_bytes(&s, &mut signature[32..]);
+                });
+                self.finish(Ok(()));
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{
+        from_be_bytes, is_zero, mont_mul, point_add, point_double, sub, sub_mod, to_mont, Limbs,
+        Modulus, P256Software, Point, B, GX, GY, KEY_STORAGE_LEN, N, ONE, P, ZERO,
+    };
+    use crate::chunked_executor::{ChunkedExecutor, ChunkedTask, ExecutorTask};
+    use core::cell::{Cell, RefCell};
+    use kernel::common::cells::TakeCell;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
+    use kernel::hil::jitter::{Jitter, JitterClient};
+    use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+    use kernel::hil::rng::{self, Client, Rng};
+    use kernel::hil::signature::{
+        KeyGenerate, KeyGenerateClient, SignClient, SignatureSign, PUBLIC_KEY_LEN, SIGNATURE_LEN,
+    };
+    use kernel::ErrorCode;
+    use std::boxed::Box;
+
+    struct MockRng;
+
+    impl<'a> Rng<'a> for MockRng {
+        fn get(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn cancel(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn set_client(&'a self, _client: &'a dyn rng::Client) {}
+    }
+
+    /// A jitter source whose random source has failed.
+    struct FailingJitter;
+
+    impl<'a> Jitter<'a> for FailingJitter {
+        fn set_client(&'a self, _client: &'a dyn JitterClient) {}
+
+        fn delay(&self, _max_us: u32) -> Result<(), ErrorCode> {
+            Err(ErrorCode::FAIL)
+        }
+    }
+
+    /// Storage that completes an access when the test calls `complete()`.
+    struct MockStorage {
+        memory: RefCell<[u8; 0x200]>,
+        /// The buffer, address and length of the access in progress, and
+        /// whether it is a write.
+        pending: TakeCell<'static, [u8]>,
+        access: Cell<(usize, usize, bool)>,
+    }
+
+    impl MockStorage {
+        fn new() -> MockStorage {
+            MockStorage {
+                memory: RefCell::new([0xff; 0x200]),
+                pending: TakeCell::empty(),
+                access: Cell::new((0, 0, false)),
+            }
+        }
+
+        fn complete(&self, client: &dyn NonvolatileStorageClient<'static>) {
+            let buffer = self.pending.take().unwrap();
+            let (address, length, write) = self.access.get();
+            let mut memory = self.memory.borrow_mut();
+            if write {
+                memory[address..address + length].copy_from_slice(&buffer[..length]);
+                drop(memory);
+                client.write_done(buffer, length);
+            } else {
+                buffer[..length].copy_from_slice(&memory[address..address + length]);
+                drop(memory);
+                client.read_done(buffer, length);
+            }
+        }
+    }
+
+    impl NonvolatileStorage<'static> for MockStorage {
+        fn set_client(&self, _client: &'static dyn NonvolatileStorageClient<'static>) {}
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            self.pending.replace(buffer);
+            self.access.set((address, length, false));
+            Ok(())
+        }
+
+        fn write(
+            &self,
+            buffer: &'static mut [u8],
+            address: usize,
+            length: usize,
+        ) -> Result<(), ErrorCode> {
+            self.pending.replace(buffer);
+            self.access.set((address, length, true));
+            Ok(())
+        }
+    }
+
+    struct MockClient {
+        public_key: TakeCell<'static, [u8; PUBLIC_KEY_LEN]>,
+        signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+    }
+
+    impl KeyGenerateClient for MockClient {
+        fn key_generated(
+            &self,
+            result: Result<(), ErrorCode>,
+            public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+        ) {
+            assert_eq!(result, Ok(()));
+            self.public_key.replace(public_key);
+        }
+    }
+
+    impl SignClient for MockClient {
+        fn signing_done(
+            &self,
+            result: Result<(), ErrorCode>,
+            _hash: &'static mut [u8; 32],
+            signature: &'static mut [u8; SIGNATURE_LEN],
+        ) {
+            assert_eq!(result, Ok(()));
+            self.signature.replace(signature);
+        }
+    }
+
+    /// Records the result of key generation.
+    struct ResultClient {
+        result: Cell<Option<Result<(), ErrorCode>>>,
+    }
+
+    impl KeyGenerateClient for ResultClient {
+        fn key_generated(
+            &self,
+            result: Result<(), ErrorCode>,
+            _public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+        ) {
+            self.result.set(Some(result));
+        }
+    }
+
+    fn hex(s: &str) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    /// Hand `p256` the scalar it asked the RNG for, and run the arithmetic
+    /// to the end.
+    fn run(p256: &P256Software, scalar: &[u8; 32]) {
+        let words = from_be_bytes(scalar);
+        assert_eq!(
+            p256.randomness_available(&mut words.iter().cloned(), Ok(())),
+            rng::Continue::Done
+        );
+        while !p256.run_chunk(u32::MAX) {}
+    }
+
+    /// The public key of the private key `scalar`, which is `scalar` times
+    /// the base point.
+    fn multiply_base(scalar: &str) -> ([u8; 32], [u8; 32]) {
+        let ddc = DynamicDeferredCall::new(&[]);
+        let executor = ChunkedExecutor::new(&ddc, 0);
+        let task = ExecutorTask::new(&executor);
+        let rng = MockRng;
+        let p256 = P256Software::new(&task, &rng);
+        let client = MockClient {
+            public_key: TakeCell::empty(),
+            signature: TakeCell::empty(),
+        };
+        p256.set_key_client(&client);
+
+        assert!(p256.generate_key(0, Box::leak(Box::new([0; 64]))).is_ok());
+        run(&p256, &hex(scalar));
+        let public_key = client.public_key.take().unwrap();
+        let (mut x, mut y) = ([0; 32], [0; 32]);
+        x.copy_from_slice(&public_key[..32]);
+        y.copy_from_slice(&public_key[32..]);
+        (x, y)
+    }
+
+    #[test]
+    fn test_multiply_base() {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+                "4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5",
+            ),
+            (
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "7cf27b188d034f7e8a52380304b51ac3c08969e277f21b35a60b48fc47669978",
+                "07775510db8ed040293d9ac69f7430dbba7dade63ce982299e04b79d227873d1",
+            ),
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "5ecbe4d1a6330a44c8f7ef951d4bf165e6c6b721efada985fb41661bc6e7fd6c",
+                "8734640c4998ff7e374b06ce1a64a2ecd82ab036384fb83d9a79b127a27d5032",
+            ),
+            // n - 1, so the result is -G.
+            (
+                "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632550",
+                "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+                "b01cbd1c01e58065711814b583f061e9d431cca994cea1313449bf97c840ae0a",
+            ),
+        ];
+        for (scalar, x, y) in vectors.iter() {
+            assert_eq!(multiply_base(scalar), (hex(x), hex(y)));
+        }
+    }
+
+    #[test]
+    fn test_jitter_fails_closed() {
+        let ddc = DynamicDeferredCall::new(&[]);
+        let executor = ChunkedExecutor::new(&ddc, 0);
+        let task = ExecutorTask::new(&executor);
+        let rng = MockRng;
+        let jitter = FailingJitter;
+        let p256 = P256Software::new(&task, &rng);
+        let client = ResultClient {
+            result: Cell::new(None),
+        };
+        p256.set_key_client(&client);
+        p256.set_jitter(&jitter, 2000);
+
+        assert!(p256.generate_key(0, Box::leak(Box::new([0; 64]))).is_ok());
+        let words = from_be_bytes(&hex(
+            "0000000000000000000000000000000000000000000000000000000000000001",
+        ));
+        p256.randomness_available(&mut words.iter().cloned(), Ok(()));
+        // The operation fails instead of running without the delay
+        assert_eq!(client.result.get(), Some(Err(ErrorCode::FAIL)));
+        assert!(p256.run_chunk(u32::MAX));
+        assert!(p256.keys[0].get().is_none());
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 6979, A.2.5: P-256 with SHA-256, message "sample".
+        let private_key = "c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721";
+        let nonce = "a6e3c57dd01abe90086538398355dd4c3b17aa873382b0f24d6129493d8aad60";
+        let hash = "af2bdbe1aa9b6ec1e2ade1d694f41fc71a831d0268e9891562113d8a62add1bf";
+
+        let ddc = DynamicDeferredCall::new(&[]);
+        let executor = ChunkedExecutor::new(&ddc, 0);
+        let task = ExecutorTask::new(&executor);
+        let rng = MockRng;
+        let p256 = P256Software::new(&task, &rng);
+        let client = MockClient {
+            public_key: TakeCell::empty(),
+            signature: TakeCell::empty(),
+        };
+        p256.set_key_client(&client);
+        p256.set_sign_client(&client);
+
+        assert!(p256.generate_key(3, Box::leak(Box::new([0; 64]))).is_ok());
+        run(&p256, &hex(private_key));
+        let public_key = client.public_key.take().unwrap();
+        assert_eq!(
+            public_key[..32],
+            hex("60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6")
+        );
+        assert_eq!(
+            public_key[32..],
+            hex("7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299")
+        );
+
+        assert!(p256
+            .sign(
+                3,
+                Box::leak(Box::new(hex(hash))),
+                Box::leak(Box::new([0; 64]))
+            )
+            .is_ok());
+        run(&p256, &hex(nonce));
+        let signature = client.signature.take().unwrap();
+        assert_eq!(
+            signature[..32],
+            hex("efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716")
+        );
+        assert_eq!(
+            signature[32..],
+            hex("f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8")
+        );
+    }
+
+    #[test]
+    fn test_invert() {
+        let ddc = DynamicDeferredCall::new(&[]);
+        let executor = ChunkedExecutor::new(&ddc, 0);
+        let task = ExecutorTask::new(&executor);
+        let rng = MockRng;
+        let p256 = P256Software::new(&task, &rng);
+
+        let invert = |a: &Limbs, md: &Modulus| {
+            p256.start_invert(&to_mont(a, md), md);
+            // A few bits at a time, as the executor would run it.
+            while !p256.run_invert(7, md) {}
+            p256.exp_acc.get()
+        };
+        let (p_minus_one, _) = sub(&P.m, &ONE);
+        let (n_minus_one, _) = sub(&N.m, &ONE);
+        let values = [
+            ONE,
+            [2, 0, 0, 0, 0, 0, 0, 0],
+            [0x1234_5678; 8],
+            p_minus_one,
+            n_minus_one,
+        ];
+        for md in [&P, &N].iter() {
+            let one = to_mont(&ONE, md);
+            for value in values.iter() {
+                let (_, borrow) = sub(value, &md.m);
+                if borrow == 0 {
+                    continue;
+                }
+                let inverse = invert(value, md);
+                assert_eq!(mont_mul(&to_mont(value, md), &inverse, md), one);
+            }
+        }
+        // The inverse of n - 1 is itself.
+        assert_eq!(invert(&n_minus_one, &N), to_mont(&n_minus_one, &N));
+    }
+
+    #[test]
+    fn test_complete_addition() {
+        let b = to_mont(&B, &P);
+        let one = to_mont(&ONE, &P);
+        let infinity: Point = [ZERO, one, ZERO];
+        let g: Point = [to_mont(&GX, &P), to_mont(&GY, &P), one];
+        let minus_g: Point = [g[0], sub_mod(&ZERO, &g[1], &P), one];
+        // Whether two points are the same, whatever their Z.
+        let same = |p: &Point, q: &Point| {
+            mont_mul(&p[0], &q[2], &P) == mont_mul(&q[0], &p[2], &P)
+                && mont_mul(&p[1], &q[2], &P) == mont_mul(&q[1], &p[2], &P)
+        };
+
+        assert!(same(&point_add(&infinity, &g, &b), &g));
+        assert!(same(&point_add(&g, &infinity, &b), &g));
+        assert!(same(&point_add(&g, &g, &b), &point_double(&g, &b)));
+        let sum = point_add(&g, &minus_g, &b);
+        assert!(is_zero(&sum[2]) && !is_zero(&sum[1]));
+        let double = point_double(&infinity, &b);
+        assert!(is_zero(&double[2]) && !is_zero(&double[1]));
+    }
+
+    #[test]
+    fn test_storage() {
+        let private_key = "c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721";
+        let nonce = "a6e3c57dd01abe90086538398355dd4c3b17aa873382b0f24d6129493d8aad60";
+        let hash = "af2bdbe1aa9b6ec1e2ade1d694f41fc71a831d0268e9891562113d8a62add1bf";
+
+        let ddc = DynamicDeferredCall::new(&[]);
+        let executor = ChunkedExecutor::new(&ddc, 0);
+        let task = ExecutorTask::new(&executor);
+        let rng = MockRng;
+        let storage = MockStorage::new();
+        let client = MockClient {
+            public_key: TakeCell::empty(),
+            signature: TakeCell::empty(),
+        };
+
+        // The key is only usable once it has been written.
+        let p256 = P256Software::new(&task, &rng);
+        p256.set_key_client(&client);
+        p256.set_storage(&storage, 0x10, Box::leak(Box::new([0; KEY_STORAGE_LEN])));
+        assert!(p256.load().is_ok());
+        storage.complete(&p256);
+        assert!(p256.generate_key(2, Box::leak(Box::new([0; 64]))).is_ok());
+        run(&p256, &hex(private_key));
+        assert!(client.public_key.is_none());
+        assert_eq!(storage.access.get(), (0x10 + 2 * 36, 36, true));
+        storage.complete(&p256);
+        assert!(client.public_key.take().is_some());
+
+        // After a reset, the key is read back, and signs as before.
+        let p256 = P256Software::new(&task, &rng);
+        p256.set_sign_client(&client);
+        p256.set_storage(&storage, 0x10, Box::leak(Box::new([0; KEY_STORAGE_LEN])));
+        assert!(p256.load().is_ok());
+        let hash = Box::leak(Box::new(hex(hash)));
+        let signature = Box::leak(Box::new([0; 64]));
+        let (hash, signature) = match p256.sign(2, hash, signature) {
+            Err((ErrorCode::BUSY, hash, signature)) => (hash, signature),
+            _ => panic!("signed before the keys were loaded"),
+        };
+        storage.complete(&p256);
+        assert!(p256
+            .sign(
+                0,
+                Box::leak(Box::new([0; 32])),
+                Box::leak(Box::new([0; 64]))
+            )
+            .is_err());
+        assert!(p256.sign(2, hash, signature).is_ok());
+        run(&p256, &hex(nonce));
+        let signature = client.signature.take().unwrap();
+        assert_eq!(
+            signature[32..],
+            hex("f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8")
+        );
+    }
+}
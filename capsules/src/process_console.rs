@@ -13,6 +13,14 @@
 //!  - 'start n' starts the stopped process with name n
 //!  - 'fault n' forces the process with name n into a fault state
 //!  - 'panic' causes the kernel to run the panic handler
+//!  - 'log' dumps the persistent log, if the board provides one (see
+//!    `capsules::log_driver`)
+//!  - 'memdiff' takes a checkpoint of the memory use of the processes and
+//!    prints how it changed since the previous checkpoint
+//!  - 'grants' prints, for each driver that failed to enter a grant, how
+//!    often that was because the process was gone, the process was not
+//!    running, the grant region was out of memory or the grant was already
+//!    entered. Failures outside system calls are listed under driver '-'.
 //!
 //! ### `list` Command Fields:
 //!
@@ -105,6 +113,19 @@
 //! Timeslice expirations: 0
 //! ```
 //!
+//! To find slow memory growth, take a checkpoint with `memdiff`, let the
+//! processes run, and use `memdiff` again. `App RAM` is the memory the
+//! process can access, up to its break, and `Grant RAM` the grant region the
+//! kernel allocated in its memory. A `-` marks a process that was not running
+//! at the previous checkpoint:
+//!
+//! ```text
+//! memdiff
+//!  PID    Name                 App RAM    Change  Grant RAM    Change
+//!   0     blink                   3072      +512       1124       +0
+//!   2     c_hello                 2048         -        864        -
+//! ```
+//!
 //! and you can control processes with the `start` and `stop` commands:
 //!
 //! ```text
@@ -116,12 +137,15 @@ use core::cell::Cell;
 use core::cmp;
 use core::str;
 use kernel::capabilities::ProcessManagementCapability;
-use kernel::common::cells::TakeCell;
+use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::debug;
 use kernel::hil::uart;
 use kernel::introspection::KernelInfo;
 use kernel::ErrorCode;
 use kernel::Kernel;
+use kernel::ProcessId;
+
+use crate::log_driver::LogDump;
 
 // Since writes are character echoes, we do not need more than 4 bytes:
 // the longest write is 3 bytes for a backspace (backspace, space, backspace).
@@ -133,6 +157,9 @@ pub static mut READ_BUF: [u8; 4] = [0; 4];
 // characters, limiting arguments to 25 bytes or so seems fine for now.
 pub static mut COMMAND_BUF: [u8; 32] = [0; 32];
 
+/// The number of processes a `memdiff` checkpoint holds.
+const MEMDIFF_PROCESSES: usize = 8;
+
 pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     uart: &'a dyn uart::UartData<'a>,
     tx_in_progress: Cell<bool>,
@@ -151,6 +178,11 @@ pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     execute: Cell<bool>,
     kernel: &'static Kernel,
     capability: C,
+    log: OptionalCell<&'a dyn LogDump>,
+
+    /// The memory use of the processes at the last `memdiff`, as their
+    /// ID, app RAM and grant RAM.
+    memdiff_checkpoint: Cell<[Option<(ProcessId, usize, usize)>; MEMDIFF_PROCESSES]>,
 }
 
 impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
@@ -174,9 +206,16 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             execute: Cell::new(false),
             kernel: kernel,
             capability: capability,
+            log: OptionalCell::empty(),
+            memdiff_checkpoint: Cell::new([None; MEMDIFF_PROCESSES]),
         }
     }
 
+    /// Set the log the `log` command dumps.
+    pub fn set_log_dump(&self, log: &'a dyn LogDump) {
+        self.log.set(log);
+    }
+
     pub fn start(&self) -> Result<(), ErrorCode> {
         if self.running.get() == false {
             self.rx_buffer.take().map(|buffer| {
@@ -189,6 +228,79 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
         Ok(())
     }
 
+    // Print the memory use of each process and how it changed since the last
+    // checkpoint, which this one replaces.
+    fn memdiff(&self) {
+        let info: KernelInfo = KernelInfo::new(self.kernel);
+        let previous = self.memdiff_checkpoint.get();
+        let checkpoint = Cell::new([None; MEMDIFF_PROCESSES]);
+        let index = Cell::new(0);
+
+        debug!(" PID    Name                 App RAM    Change  Grant RAM    Change");
+        self.kernel
+            .process_each_capability(&self.capability, |proc| {
+                let appid = proc.processid();
+                let (app, grant) = info.app_memory_usage(appid, &self.capability);
+                let before = previous.iter().flatten().find(|(id, _, _)| *id == appid);
+                match before {
+                    Some(&(_, app_before, grant_before)) => debug!(
+                        "  {:?}\t{:<20}{:8}{:+10}{:11}{:+10}",
+                        appid,
+                        proc.get_process_name(),
+                        app,
+                        app as isize - app_before as isize,
+                        grant,
+                        grant as isize - grant_before as isize
+                    ),
+                    None => debug!(
+                        "  {:?}\t{:<20}{:8}{:>10}{:11}{:>10}",
+                        appid,
+                        proc.get_process_name(),
+                        app,
+                        "-",
+                        grant,
+                        "-"
+                    ),
+                }
+                let mut entries = checkpoint.get();
+                if let Some(slot) = entries.get_mut(index.get()) {
+                    *slot = Some((appid, app, grant));
+                    checkpoint.set(entries);
+                    index.set(index.get() + 1);
+                }
+            });
+        self.memdiff_checkpoint.set(checkpoint.get());
+    }
+
+    fn grant_failures(&self) {
+        let info: KernelInfo = KernelInfo::new(self.kernel);
+        debug!("   Driver  No app  Inactive  No memory  Entered");
+        let mut index = 0;
+        while let Some((driver_num, failures)) = info.grant_failures(index, &self.capability) {
+            if !failures.is_empty() {
+                match driver_num {
+                    Some(driver_num) => debug!(
+                        " {:#8x}{:8}{:10}{:11}{:9}",
+                        driver_num,
+                        failures.no_such_app,
+                        failures.inactive_app,
+                        failures.out_of_memory,
+                        failures.already_entered
+                    ),
+                    None => debug!(
+                        " {:>8}{:8}{:10}{:11}{:9}",
+                        "-",
+                        failures.no_such_app,
+                        failures.inactive_app,
+                        failures.out_of_memory,
+                        failures.already_entered
+                    ),
+                }
+            }
+            index += 1;
+        }
+    }
+
     // Process the command in the command buffer and clear the buffer.
     fn read_command(&self) {
         self.command_buffer.map(|command| {
@@ -211,7 +323,7 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                         let clean_str = s.trim();
                         if clean_str.starts_with("help") {
                             debug!("Welcome to the process console.");
-                            debug!("Valid commands are: help status list stop start fault panic");
+                            debug!("Valid commands are: help status list stop start fault memdiff grants log panic");
                         } else if clean_str.starts_with("start") {
                             let argument = clean_str.split_whitespace().nth(1);
                             argument.map(|name| {
@@ -291,10 +403,19 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                 "Timeslice expirations: {}",
                                 info.timeslice_expirations(&self.capability)
                             );
+                        } else if clean_str.starts_with("memdiff") {
+                            self.memdiff();
+                        } else if clean_str.starts_with("grants") {
+                            self.grant_failures();
+                        } else if clean_str.starts_with("log") {
+                            let result = self.log.map_or(Err(ErrorCode::NODEVICE), |log| log.dump());
+                            if let Err(e) = result {
+                                debug!("Log dump failed: {:?}", e);
+                            }
                         } else if clean_str.starts_with("panic") {
                             panic!("ProcessConsole forced a kernel panic.");
                         } else {
-                            debug!("Valid commands are: help status list stop start fault");
+                            debug!("Valid commands are: help status list stop start fault memdiff grants log");
                         }
                     }
                     Err(_e) => debug!("Invalid command: {:?}", command),
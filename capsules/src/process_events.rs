@@ -0,0 +1,147 @@
+//! Notifies processes when other processes start, exit or fault.
+//!
+//! This lets a supervisor process watch over the other processes on the
+//! board, for example to log faults or to take the board to a safe state
+//! when a critical process stops. Processes are identified by the unique
+//! identifier the kernel gives them, which changes when a process restarts.
+//!
+//! The events tell a process what the others on the board do, so only
+//! processes whose TBF header grants them command `0` of this driver (see the
+//! `Permissions` TLV in `doc/TockBinaryFormat.md`) can subscribe to them.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let process_events = static_init!(
+//!     capsules::process_events::ProcessEvents,
+//!     capsules::process_events::ProcessEvents::new(
+//!         board_kernel,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! board_kernel.set_process_events_client(process_events, &process_management_capability);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: process events. The callback gets the event, the identifier of the
+//!   process and a reason code:
+//!   - `1`: the process started. The reason is `0`.
+//!   - `2`: the process exited. The reason is its completion code.
+//!   - `3`: the process exited and asked to be restarted. The reason is its
+//!     completion code.
+//!   - `4`: the process faulted. The reason is what the kernel does next: `0`
+//!     panic, `1` restart the process or `2` stop it.
+//!
+//!   Returns `NOSUPPORT` if the caller does not have permission.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the identifier of the calling process.
+
+use core::mem;
+
+use kernel::procs::{FaultAction, ProcessEvent, ProcessEventsClient};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ProcessEvents as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+}
+
+pub struct ProcessEvents {
+    kernel: &'static Kernel,
+    apps: Grant<App>,
+}
+
+impl ProcessEvents {
+    pub fn new(kernel: &'static Kernel, grant: Grant<App>) -> ProcessEvents {
+        ProcessEvents {
+            kernel: kernel,
+            apps: grant,
+        }
+    }
+}
+
+impl ProcessEventsClient for ProcessEvents {
+    fn process_event(&self, process: ProcessId, event: ProcessEvent) {
+        let (event, reason) = match event {
+            ProcessEvent::Started => (1, 0),
+            ProcessEvent::Exited {
+                completion_code,
+                restart: false,
+            } => (2, completion_code as usize),
+            ProcessEvent::Exited {
+                completion_code,
+                restart: true,
+            } => (3, completion_code as usize),
+            ProcessEvent::Faulted(action) => (
+                4,
+                match action {
+                    FaultAction::Panic => 0,
+                    FaultAction::Restart => 1,
+                    FaultAction::Stop => 2,
+                },
+            ),
+        };
+        self.apps.each(|appid, app| {
+            if appid != process {
+                app.callback.schedule(event, process.id(), reason);
+            }
+        });
+    }
+}
+
+impl Driver for ProcessEvents {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Process event callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 if !self.kernel.command_permitted(appid, DRIVER_NUM, 0) => Err(ErrorCode::NOSUPPORT),
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the identifier of the calling process.
+    fn command(&self, cmd_num: usize, _: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success(),
+            1 => CommandReturn::success_u32(appid.id() as u32),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Lets a manager process stop, start and configure other processes.
+//!
+//! This is meant for deployments where a manager app handles recovery of the
+//! other apps, for example when told to by a remote server. Processes are
+//! selected by their package name.
+//!
+//! Because it controls other processes, the driver only accepts commands
+//! from processes whose TBF header grants them (see the `Permissions` TLV
+//! in `doc/TockBinaryFormat.md`), and the board has to give it the
+//! `ProcessManagementCapability`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
+//! let process_manager = static_init!(
+//!     capsules::process_manager::ProcessManager<ProcessMgmtCap>,
+//!     capsules::process_manager::ProcessManager::new(
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-only `0`: name of the process the next command applies to.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Always allowed.
+//! - `1`: Stop the process.
+//! - `2`: Start the process. A stopped process is resumed, a process that has
+//!   exited or faulted is restarted.
+//! - `3`: Restart the process.
+//! - `4`: Set what happens when the process faults: `0` stop it, `1` restart
+//!   it.
+//!
+//! Commands return `INVAL` if no process has the given name, or if it names
+//! the calling process, and `NOSUPPORT` if the caller does not have
+//! permission.
+
+use core::cell::Cell;
+use core::mem;
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::procs::{Process, RestartFaultPolicy, State, StopFaultPolicy};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId};
+use kernel::{Read, ReadOnlyAppSlice};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ProcessManager as usize;
+
+static STOP_FAULT_POLICY: StopFaultPolicy = StopFaultPolicy {};
+static RESTART_FAULT_POLICY: RestartFaultPolicy = RestartFaultPolicy {};
+
+#[derive(Default)]
+pub struct App {
+    name: ReadOnlyAppSlice,
+}
+
+pub struct ProcessManager<C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+    apps: Grant<App>,
+}
+
+impl<C: ProcessManagementCapability> ProcessManager<C> {
+    pub fn new(kernel: &'static Kernel, capability: C, grant: Grant<App>) -> ProcessManager<C> {
+        ProcessManager {
+            kernel: kernel,
+            capability: capability,
+            apps: grant,
+        }
+    }
+
+    /// Run `operation` on the process named in the buffer `appid` allowed.
+    fn with_named_process(
+        &self,
+        appid: ProcessId,
+        operation: impl Fn(&dyn Process) -> Result<(), ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(appid, |app| {
+                app.name.map_or(Err(ErrorCode::RESERVE), |name| {
+                    let result = Cell::new(Err(ErrorCode::INVAL));
+                    self.kernel
+                        .process_each_capability(&self.capability, |process| {
+                            if process.get_process_name().as_bytes() == name.as_ref()
+                                && process.processid() != appid
+                            {
+                                result.set(operation(process));
+                            }
+                        });
+                    result.get()
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+}
+
+impl<C: ProcessManagementCapability> Driver for ProcessManager<C> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Name of the process to act on
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.name, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Control other processes.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Stop the process
+    /// - `2`: Start the process
+    /// - `3`: Restart the process
+    /// - `4`: Set the fault policy of the process
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        if cmd_num == 0 {
+            return CommandReturn::success();
+        }
+        if !self.kernel.command_permitted(appid, DRIVER_NUM, cmd_num) {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+
+        let res = match cmd_num {
+            1 => self.with_named_process(appid, |process| {
+                process.stop();
+                Ok(())
+            }),
+            2 => self.with_named_process(appid, |process| {
+                match process.get_state() {
+                    State::StoppedRunning | State::StoppedYielded => process.resume(),
+                    State::Faulted | State::Terminated => process.try_restart(0),
+                    _ => return Err(ErrorCode::ALREADY),
+                }
+                Ok(())
+            }),
+            3 => self.with_named_process(appid, |process| {
+                process.try_restart(0);
+                Ok(())
+            }),
+            4 => match arg1 {
+                0 => self.with_named_process(appid, |process| {
+                    process.set_fault_policy(&STOP_FAULT_POLICY);
+                    Ok(())
+                }),
+                1 => self.with_named_process(appid, |process| {
+                    process.set_fault_policy(&RESTART_FAULT_POLICY);
+                    Ok(())
+                }),
+                _ => Err(ErrorCode::INVAL),
+            },
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Provides userspace with the position and velocity of a quadrature encoder.
+//!
+//! The decoder counts the steps of the encoder in hardware, so a fast turning
+//! knob or wheel does not interrupt the kernel at every step. The capsule
+//! computes the velocity from the steps between the decoder's reports. The
+//! encoder counts as stopped once it has not moved for `STOP_MS`, and enabled
+//! processes are then told its velocity dropped to zero.
+//!
+//! The decoder runs while at least one process has enabled events. The
+//! position is shared by all processes, and any of them can set it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let qdec_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! base_peripherals.qdec.set_pins(
+//!     Pinmux::new(QDEC_A_PIN as u32),
+//!     Pinmux::new(QDEC_B_PIN as u32),
+//! );
+//! let qdec = static_init!(
+//!     capsules::qdec::Qdec<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     capsules::qdec::Qdec::new(
+//!         &base_peripherals.qdec,
+//!         qdec_alarm,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! kernel::hil::qdec::QuadratureDecoder::set_client(&base_peripherals.qdec, qdec);
+//! qdec_alarm.set_alarm_client(qdec);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: encoder events. The callback gets the event, the position and the
+//!   velocity in steps per second. The event is `0` when the encoder moved or
+//!   stopped, and `1` when its index signal pulsed. Position and velocity are
+//!   signed 32-bit values.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Enable encoder events for this process.
+//! - `2`: Disable encoder events for this process.
+//! - `3`: Get the position, in steps.
+//! - `4`: Get the velocity, in steps per second.
+//! - `5`: Set the position to `arg1`.
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+
+use kernel::hil::qdec::{QuadratureDecoder, QuadratureDecoderClient};
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Qdec as usize;
+
+/// Time without steps after which the encoder counts as stopped. Encoders
+/// turning slower than a step per `STOP_MS` report each step as a short move.
+pub const STOP_MS: u32 = 250;
+
+/// Events passed to the callback.
+const EVENT_MOVED: usize = 0;
+const EVENT_INDEX: usize = 1;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    enabled: bool,
+}
+
+pub struct Qdec<'a, A: Alarm<'a>> {
+    qdec: &'a dyn QuadratureDecoder<'a>,
+    alarm: &'a A,
+    /// The position and time of the last report.
+    last_position: Cell<i32>,
+    last_report: Cell<A::Ticks>,
+    velocity: Cell<i32>,
+    moving: Cell<bool>,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> Qdec<'a, A> {
+    pub fn new(
+        qdec: &'a dyn QuadratureDecoder<'a>,
+        alarm: &'a A,
+        grant: Grant<App>,
+    ) -> Qdec<'a, A> {
+        Qdec {
+            qdec: qdec,
+            alarm: alarm,
+            last_position: Cell::new(0),
+            last_report: Cell::new(A::Ticks::from(0)),
+            velocity: Cell::new(0),
+            moving: Cell::new(false),
+            apps: grant,
+        }
+    }
+
+    /// Run the decoder while any process wants encoder events.
+    fn update_enabled(&self) -> Result<(), ErrorCode> {
+        let enabled = Cell::new(false);
+        self.apps.each(|_, app| {
+            if app.enabled {
+                enabled.set(true);
+            }
+        });
+        if enabled.get() && !self.qdec.is_enabled() {
+            self.last_position.set(self.qdec.position());
+            self.qdec.enable()
+        } else if !enabled.get() && self.qdec.is_enabled() {
+            self.stop();
+            self.qdec.disable()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn stop(&self) {
+        self.velocity.set(0);
+        if self.moving.get() {
+            self.moving.set(false);
+            let _ = self.alarm.disarm();
+        }
+    }
+
+    fn notify(&self, event: usize, position: i32) {
+        let velocity = self.velocity.get();
+        self.apps.each(|_, app| {
+            if app.enabled {
+                app.callback
+                    .schedule(event, position as usize, velocity as usize);
+            }
+        });
+    }
+
+    /// Milliseconds since the last report.
+    fn ms_since_report(&self, now: A::Ticks) -> u32 {
+        let elapsed = now.wrapping_sub(self.last_report.get()).into_u32() as u64;
+        (elapsed * 1000 / A::Frequency::frequency() as u64) as u32
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Qdec<'a, A> {
+    fn alarm(&self) {
+        self.moving.set(false);
+        self.velocity.set(0);
+        self.notify(EVENT_MOVED, self.last_position.get());
+    }
+}
+
+impl<'a, A: Alarm<'a>> QuadratureDecoderClient for Qdec<'a, A> {
+    fn position_changed(&self, position: i32) {
+        let now = self.alarm.now();
+        let steps = position.wrapping_sub(self.last_position.get()) as i64;
+        // When the encoder starts moving there is no telling when the first
+        // steps happened, so assume they took the whole stop time.
+        let elapsed_ms = if self.moving.get() {
+            cmp::max(self.ms_since_report(now), 1)
+        } else {
+            STOP_MS
+        };
+        self.velocity.set((steps * 1000 / elapsed_ms as i64) as i32);
+        self.last_position.set(position);
+        self.last_report.set(now);
+
+        self.moving.set(true);
+        self.alarm.set_alarm(now, A::ticks_from_ms(STOP_MS));
+        self.notify(EVENT_MOVED, position);
+    }
+
+    fn index(&self, position: i32) {
+        self.notify(EVENT_INDEX, position);
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for Qdec<'a, A> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Encoder event callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Read the encoder.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Enable encoder events
+    /// - `2`: Disable encoder events
+    /// - `3`: Get the position
+    /// - `4`: Get the velocity
+    /// - `5`: Set the position
+    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success(),
+            1 | 2 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app| app.enabled = cmd_num == 1)
+                    .map_err(ErrorCode::from);
+                match res {
+                    Ok(()) => self.update_enabled().into(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+            3 => CommandReturn::success_u32(self.qdec.position() as u32),
+            4 => CommandReturn::success_u32(self.velocity.get() as u32),
+            5 => {
+                let position = arg1 as i32;
+                self.qdec.set_position(position);
+                self.last_position.set(position);
+                CommandReturn::success()
+            }
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! Regulatory limits on the radios of a board, set at run time and kept in
+//! the config record.
+//!
+//! `RadioLimits` holds a TX power cap for each radio, the channels BLE and
+//! IEEE 802.15.4 may use, and the LoRa region. Radio drivers check it when
+//! their TX power or channel is set: the BLE advertising driver, the RF233
+//! and the SX126x do, once the board gives it to them with `set_limits()`.
+//! Without limits, they allow whatever the radio supports.
+//!
+//! The IEEE 802.15.4 MAC layers (`AwakeMac` and `XMac`) also refuse to
+//! transmit while the channel or TX power of their radio is outside the
+//! limits. This covers radios that do not check the limits themselves, such
+//! as the nRF52's, and limits lowered after the radio was configured.
+//!
+//! The limits are loaded from the config record (see
+//! `capsules::config_record`) when the board starts, so a product can ship
+//! one image for every region and be provisioned for where it is sold. The
+//! `RadioLimitsDriver` lets a privileged process change them and save them
+//! back to the record.
+//!
+//! Config record items
+//! -------------------
+//!
+//! - `CONFIG_TAG_TX_POWER`: the TX power caps of BLE, 802.15.4 and LoRa, in
+//!   that order, in dBm as one signed byte each.
+//! - `CONFIG_TAG_CHANNELS`: the allowed channels of BLE and 802.15.4, in
+//!   that order, as a little endian `u64` each, bit n for channel n.
+//! - `lora_region::CONFIG_TAG_REGION`: the LoRa region ID.
+//!
+//! Items that are missing leave the radio unrestricted.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let radio_limits = static_init!(
+//!     capsules::radio_limits::RadioLimits,
+//!     capsules::radio_limits::RadioLimits::new()
+//! );
+//! if let Some(record) = capsules::config_record::read_record(config_slots) {
+//!     radio_limits.load(record);
+//! }
+//! ble_radio.set_limits(radio_limits);
+//! // For IEEE 802.15.4, pass `radio_limits` to
+//! // `components::ieee802154::Ieee802154Component::radio_limits()`.
+//!
+//! let radio_limits_driver = static_init!(
+//!     capsules::radio_limits::RadioLimitsDriver<'static, nrf52840::nvmc::Nvmc>,
+//!     capsules::radio_limits::RadioLimitsDriver::new(
+//!         board_kernel,
+//!         radio_limits,
+//!         config_record,
+//!         &mut capsules::radio_limits::BUFFER,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! config_record.set_client(radio_limits_driver);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Radios are numbered `0` for BLE, `1` for IEEE 802.15.4 and `2` for LoRa.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Save done. The first argument is the status.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Always allowed.
+//! - `1`: Get the TX power cap of radio `arg1`, in dBm.
+//! - `2`: Set the TX power cap of radio `arg1` to `arg2` dBm, a signed
+//!   byte.
+//! - `3`: Get the allowed channels of radio `arg1`, as the low and high
+//!   halves of a bitmask. Not for LoRa.
+//! - `4`: Allow radio `arg1` to use channel `arg2`. Not for LoRa.
+//! - `5`: Forbid radio `arg1` to use channel `arg2`. Not for LoRa.
+//! - `6`: Get the LoRa region ID. Returns `INVAL` if none is set.
+//! - `7`: Set the LoRa region to ID `arg1`, as in `lora_region::Region`.
+//! - `8`: Save the limits to the config record.
+//!
+//! Changes apply to the radios at once, but only last across a reboot once
+//! saved. Commands return `NOSUPPORT` if the caller does not have
+//! permission (see the `Permissions` TLV in `doc/TockBinaryFormat.md`).
+
+use core::cell::Cell;
+use core::convert::TryInto;
+use core::mem;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::flash;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, Upcall};
+
+use crate::config_record::{self, ConfigRecord, ConfigRecordClient};
+use crate::lora_region::{self, Region};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::RadioLimits as usize;
+
+/// Holds the config record while it is rebuilt with the limits.
+pub static mut BUFFER: [u8; 256] = [0; 256];
+
+/// Tag of the TX power caps in a config record.
+pub const CONFIG_TAG_TX_POWER: u8 = 0x02;
+/// Tag of the channel masks in a config record.
+pub const CONFIG_TAG_CHANNELS: u8 = 0x03;
+
+const NUM_RADIOS: usize = 3;
+/// Radios with channel masks.
+const NUM_CHANNEL_RADIOS: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Radio {
+    Ble = 0,
+    Ieee802154 = 1,
+    Lora = 2,
+}
+
+impl Radio {
+    pub fn from_index(index: usize) -> Option<Radio> {
+        match index {
+            0 => Some(Radio::Ble),
+            1 => Some(Radio::Ieee802154),
+            2 => Some(Radio::Lora),
+            _ => None,
+        }
+    }
+
+    /// The channels the radio has, or `None` for LoRa, whose channels are
+    /// set by the region.
+    fn channel_range(self) -> Option<(u8, u8)> {
+        match self {
+            Radio::Ble => Some((0, 39)),
+            Radio::Ieee802154 => Some((11, 26)),
+            Radio::Lora => None,
+        }
+    }
+}
+
+pub struct RadioLimits {
+    max_tx_power_dbm: [Cell<i8>; NUM_RADIOS],
+    /// Allowed channels of the radios that have a channel range.
+    channels: [Cell<u64>; NUM_CHANNEL_RADIOS],
+    lora_region: Cell<Option<Region>>,
+}
+
+impl RadioLimits {
+    /// Limits that allow everything.
+    pub const fn new() -> RadioLimits {
+        RadioLimits {
+            max_tx_power_dbm: [Cell::new(i8::MAX), Cell::new(i8::MAX), Cell::new(i8::MAX)],
+            channels: [Cell::new(u64::MAX), Cell::new(u64::MAX)],
+            lora_region: Cell::new(None),
+        }
+    }
+
+    /// Take the limits in the config record `record`.
+    pub fn load(&self, record: &[u8]) {
+        if let Some(caps) = config_record::find_item(record, CONFIG_TAG_TX_POWER) {
+            for (cap, &dbm) in self.max_tx_power_dbm.iter().zip(caps.iter()) {
+                cap.set(dbm as i8);
+            }
+        }
+        if let Some(masks) = config_record::find_item(record, CONFIG_TAG_CHANNELS) {
+            for (mask, bytes) in self.channels.iter().zip(masks.chunks_exact(8)) {
+                mask.set(u64::from_le_bytes(bytes.try_into().unwrap_or([0xff; 8])));
+            }
+        }
+        if let Some(region) = Region::from_config(record) {
+            self.lora_region.set(Some(region));
+        }
+    }
+
+    pub fn max_tx_power_dbm(&self, radio: Radio) -> i8 {
+        self.max_tx_power_dbm[radio as usize].get()
+    }
+
+    pub fn set_max_tx_power_dbm(&self, radio: Radio, dbm: i8) {
+        self.max_tx_power_dbm[radio as usize].set(dbm);
+    }
+
+    /// The allowed channels of `radio`, bit n for channel n, or `None` for
+    /// LoRa.
+    pub fn channels(&self, radio: Radio) -> Option<u64> {
+        radio
+            .channel_range()
+            .map(|_| self.channels[radio as usize].get())
+    }
+
+    /// Allow or forbid `radio` to use `channel`. Returns `INVAL` if the
+    /// radio has no such channel.
+    pub fn set_channel_allowed(
+        &self,
+        radio: Radio,
+        channel: u8,
+        allowed: bool,
+    ) -> Result<(), ErrorCode> {
+        match radio.channel_range() {
+            Some((first, last)) if (first..=last).contains(&channel) => {
+                let mask = &self.channels[radio as usize];
+                if allowed {
+                    mask.set(mask.get() | 1 << channel);
+                } else {
+                    mask.set(mask.get() & !(1 << channel));
+                }
+                Ok(())
+            }
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+
+    pub fn lora_region(&self) -> Option<Region> {
+        self.lora_region.get()
+    }
+
+    pub fn set_lora_region(&self, region: Region) {
+        self.lora_region.set(Some(region));
+    }
+
+    /// Returns `INVAL` if `radio` may not transmit at `dbm`.
+    pub fn check_tx_power(&self, radio: Radio, dbm: i8) -> Result<(), ErrorCode> {
+        if dbm > self.max_tx_power_dbm(radio) {
+            Err(ErrorCode::INVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns `INVAL` if `radio` may not use `channel`.
+    pub fn check_channel(&self, radio: Radio, channel: u8) -> Result<(), ErrorCode> {
+        match self.channels(radio) {
+            Some(mask) if channel < 64 && mask & (1 << channel) != 0 => Ok(()),
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+
+    /// Returns `INVAL` if an IEEE 802.15.4 radio on `channel` may not
+    /// transmit at `dbm`.
+    pub fn check_ieee802154(&self, channel: u8, dbm: i8) -> Result<(), ErrorCode> {
+        self.check_channel(Radio::Ieee802154, channel)?;
+        self.check_tx_power(Radio::Ieee802154, dbm)
+    }
+
+    /// Returns `INVAL` if LoRa may not use `channel`, because it is outside
+    /// the bands of the region.
+    pub fn check_lora_channel(&self, channel: lora_region::Channel) -> Result<(), ErrorCode> {
+        match self.lora_region.get() {
+            Some(region) if !region.allows(channel) => Err(ErrorCode::INVAL),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write `record` to `buf` with the items of the limits replaced.
+    /// Returns the length of the new record, or `None` if it does not fit.
+    fn encode(&self, record: &[u8], buf: &mut [u8]) -> Option<usize> {
+        let mut len = 0;
+        let mut push = |tag: u8, value: &[u8]| -> Option<()> {
+            let item = buf.get_mut(len..len + 2 + value.len())?;
+            item[0] = tag;
+            item[1] = value.len() as u8;
+            item[2..].copy_from_slice(value);
+            len += item.len();
+            Some(())
+        };
+
+        // Keep the items of the record that are not limits.
+        let mut offset = 0;
+        while offset + 2 <= record.len() {
+            let tag = record[offset];
+            let value = record.get(offset + 2..offset + 2 + record[offset + 1] as usize)?;
+            if tag != CONFIG_TAG_TX_POWER
+                && tag != CONFIG_TAG_CHANNELS
+                && tag != lora_region::CONFIG_TAG_REGION
+            {
+                push(tag, value)?;
+            }
+            offset += 2 + value.len();
+        }
+
+        let mut caps = [0; NUM_RADIOS];
+        for (byte, cap) in caps.iter_mut().zip(self.max_tx_power_dbm.iter()) {
+            *byte = cap.get() as u8;
+        }
+        push(CONFIG_TAG_TX_POWER, &caps)?;
+        let mut masks = [0; 8 * NUM_CHANNEL_RADIOS];
+        for (bytes, mask) in masks.chunks_exact_mut(8).zip(self.channels.iter()) {
+            bytes.copy_from_slice(&mask.get().to_le_bytes());
+        }
+        push(CONFIG_TAG_CHANNELS, &masks)?;
+        if let Some(region) = self.lora_region.get() {
+            push(lora_region::CONFIG_TAG_REGION, &[region.id()])?;
+        }
+        Some(len)
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+}
+
+pub struct RadioLimitsDriver<'a, F: flash::Flash + 'static> {
+    kernel: &'static Kernel,
+    limits: &'a RadioLimits,
+    config: &'a ConfigRecord<'a, F>,
+    buffer: TakeCell<'static, [u8]>,
+    /// Process whose save is in progress.
+    saving: OptionalCell<ProcessId>,
+    apps: Grant<App>,
+}
+
+impl<'a, F: flash::Flash> RadioLimitsDriver<'a, F> {
+    pub fn new(
+        kernel: &'static Kernel,
+        limits: &'a RadioLimits,
+        config: &'a ConfigRecord<'a, F>,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> RadioLimitsDriver<'a, F> {
+        RadioLimitsDriver {
+            kernel: kernel,
+            limits: limits,
+            config: config,
+            buffer: TakeCell::new(buffer),
+            saving: OptionalCell::empty(),
+            apps: grant,
+        }
+    }
+
+    fn save(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        if self.saving.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        let record = self.config.read().unwrap_or(&[]);
+        let result = match self.limits.encode(record, buffer) {
+            Some(len) => self.config.write(&buffer[..len]),
+            None => Err(ErrorCode::SIZE),
+        };
+        // The config record copies the data before writing it.
+        self.buffer.replace(buffer);
+        if result.is_ok() {
+            self.saving.set(appid);
+        }
+        result
+    }
+}
+
+impl<F: flash::Flash> ConfigRecordClient for RadioLimitsDriver<'_, F> {
+    fn done(&self, result: Result<(), ErrorCode>) {
+        self.saving.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback.schedule(kernel::into_statuscode(result), 0, 0);
+            });
+        });
+    }
+}
+
+impl<F: flash::Flash> Driver for RadioLimitsDriver<'_, F> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Save done callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Get and set the radio limits.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the TX power cap of a radio
+    /// - `2`: Set the TX power cap of a radio
+    /// - `3`: Get the allowed channels of a radio
+    /// - `4`: Allow a channel
+    /// - `5`: Forbid a channel
+    /// - `6`: Get the LoRa region
+    /// - `7`: Set the LoRa region
+    /// - `8`: Save the limits
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
+        if cmd_num == 0 {
+            return CommandReturn::success();
+        }
+        if !self.kernel.command_permitted(appid, DRIVER_NUM, cmd_num) {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+
+        let radio = Radio::from_index(arg1);
+        let result = match (cmd_num, radio) {
+            (1, Some(radio)) => {
+                let dbm = self.limits.max_tx_power_dbm(radio);
+                return CommandReturn::success_u32(dbm as i32 as u32);
+            }
+            (2, Some(radio)) => {
+                self.limits.set_max_tx_power_dbm(radio, arg2 as u8 as i8);
+                Ok(())
+            }
+            (3, Some(radio)) => match self.limits.channels(radio) {
+                Some(mask) => {
+                    return CommandReturn::success_u32_u32(mask as u32, (mask >> 32) as u32)
+                }
+                None => Err(ErrorCode::INVAL),
+            },
+            (4, Some(radio)) | (5, Some(radio)) => {
+                let channel = if arg2 > u8::MAX as usize {
+                    u8::MAX
+                } else {
+                    arg2 as u8
+                };
+                self.limits
+                    .set_channel_allowed(radio, channel, cmd_num == 4)
+            }
+            (1..=5, None) => Err(ErrorCode::INVAL),
+            (6, _) => match self.limits.lora_region() {
+                Some(region) => return CommandReturn::success_u32(region.id() as u32),
+                None => Err(ErrorCode::INVAL),
+            },
+            (7, _) => match Region::from_id(arg1 as u8).filter(|_| arg1 <= u8::MAX as usize) {
+                Some(region) => {
+                    self.limits.set_lora_region(region);
+                    Ok(())
+                }
+                None => Err(ErrorCode::INVAL),
+            },
+            (8, _) => self.save(appid),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match result {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_load() {
+        let limits = RadioLimits::new();
+        limits.set_max_tx_power_dbm(Radio::Ble, -4);
+        limits.set_max_tx_power_dbm(Radio::Lora, 14);
+        limits.set_channel_allowed(Radio::Ble, 38, false).unwrap();
+        limits
+            .set_channel_allowed(Radio::Ieee802154, 26, false)
+            .unwrap();
+        limits.set_lora_region(Region::Us915);
+
+        // An item that is not a limit, and stale limits that are replaced
+        let record = [0x10, 2, 0xaa, 0xbb, CONFIG_TAG_TX_POWER, 3, 1, 2, 3];
+        let mut buf = [0; 64];
+        let len = limits.encode(&record, &mut buf).unwrap();
+        let encoded = &buf[..len];
+        assert_eq!(&encoded[..4], &[0x10, 2, 0xaa, 0xbb]);
+        assert_eq!(
+            config_record::find_item(encoded, CONFIG_TAG_TX_POWER),
+            Some(&[0xfc, 0x7f, 14][..])
+        );
+
+        let loaded = RadioLimits::new();
+        loaded.load(encoded);
+        assert_eq!(loaded.max_tx_power_dbm(Radio::Ble), -4);
+        assert_eq!(loaded.max_tx_power_dbm(Radio::Ieee802154), i8::MAX);
+        assert_eq!(loaded.max_tx_power_dbm(Radio::Lora), 14);
+        assert_eq!(loaded.channels(Radio::Ble), Some(!(1 << 38)));
+        assert_eq!(loaded.channels(Radio::Ieee802154), Some(!(1 << 26)));
+        assert_eq!(loaded.channels(Radio::Lora), None);
+        assert_eq!(loaded.lora_region(), Some(Region::Us915));
+        assert!(loaded.check_tx_power(Radio::Ble, -4).is_ok());
+        assert!(loaded.check_tx_power(Radio::Ble, 0).is_err());
+        assert!(loaded.check_channel(Radio::Ble, 38).is_err());
+        assert!(loaded.check_channel(Radio::Ble, 37).is_ok());
+    }
+
+    #[test]
+    fn test_encode_too_small() {
+        let limits = RadioLimits::new();
+        let mut buf = [0; 20];
+        assert_eq!(limits.encode(&[], &mut buf), None);
+        let mut buf = [0; 23];
+        assert_eq!(limits.encode(&[], &mut buf), Some(23));
+    }
+
+    #[test]
+    fn test_encode_malformed_record() {
+        let limits = RadioLimits::new();
+        let mut buf = [0; 64];
+        // The item claims more bytes than the record has
+        assert_eq!(limits.encode(&[0x10, 4, 0xaa], &mut buf), None);
+    }
+
+    #[test]
+    fn test_load_missing_items() {
+        let limits = RadioLimits::new();
+        limits.load(&[0x10, 1, 0xaa]);
+        assert_eq!(limits.max_tx_power_dbm(Radio::Ble), i8::MAX);
+        assert_eq!(limits.channels(Radio::Ble), Some(u64::MAX));
+        assert_eq!(limits.lora_region(), None);
+    }
+}
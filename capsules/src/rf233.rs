@@ -11,6 +11,7 @@
 
 #![allow(unused_parens)]
 
+use crate::radio_limits::{Radio, RadioLimits};
 use crate::rf233_const::{
     ExternalState, InteruptFlags, RF233BusCommand, RF233Register, RF233TrxCmd,
 };
@@ -214,6 +215,7 @@ pub struct RF233<'a, S: spi::SpiMasterDevice> {
     pan: Cell<u16>,
     tx_power: Cell<i8>,
     channel: Cell<u8>,
+    limits: OptionalCell<&'a RadioLimits>,
     spi_rx: TakeCell<'static, [u8]>,
     spi_tx: TakeCell<'static, [u8]>,
     spi_buf: TakeCell<'static, [u8]>,
@@ -1060,12 +1062,18 @@ impl<'a, S: spi::SpiMasterDevice> RF233<'a, S> {
             pan: Cell::new(0),
             tx_power: Cell::new(setting_to_power(PHY_TX_PWR)),
             channel: Cell::new(channel),
+            limits: OptionalCell::empty(),
             spi_rx: TakeCell::empty(),
             spi_tx: TakeCell::empty(),
             spi_buf: TakeCell::empty(),
         }
     }
 
+    /// Refuse TX powers and channels outside `limits`.
+    pub fn set_limits(&self, limits: &'a RadioLimits) {
+        self.limits.set(limits);
+    }
+
     fn handle_interrupt(&self) {
         // In most cases, the first thing the driver does on handling an interrupt is
         // read the IRQ status; this pushes most logic to the SPI handler.
@@ -1271,6 +1279,9 @@ impl<S: spi::SpiMasterDevice> radio::RadioConfig for RF233<'_, S> {
         if (power > 4 || power < -17) {
             Err(ErrorCode::INVAL)
         } else {
+            self.limits.map_or(Ok(()), |limits| {
+                limits.check_tx_power(Radio::Ieee802154, power)
+            })?;
             self.tx_power.set(power);
             Ok(())
         }
@@ -1278,6 +1289,9 @@ impl<S: spi::SpiMasterDevice> radio::RadioConfig for RF233<'_, S> {
 
     fn set_channel(&self, chan: u8) -> Result<(), ErrorCode> {
         if chan >= 11 && chan <= 26 {
+            self.limits.map_or(Ok(()), |limits| {
+                limits.check_channel(Radio::Ieee802154, chan)
+            })?;
             self.channel.set(chan);
             Ok(())
         } else {
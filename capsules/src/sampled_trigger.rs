@@ -0,0 +1,381 @@
+//! Threshold triggers for sensors without hardware threshold detection.
+//!
+//! These adapters implement `hil::sensors::Trigger` for temperature sensors,
+//! ADC channels and accelerometers by sampling the sensor periodically with
+//! an alarm and comparing each reading against the threshold. Sampling only
+//! runs while the trigger is enabled.
+//!
+//! Each adapter takes the client slot of the sensor it wraps, so the sensor
+//! (or its virtualized instance) must be dedicated to the trigger.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let temp_trigger_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let temp_trigger = static_init!(
+//!     capsules::sampled_trigger::TemperatureTrigger<
+//!         'static,
+//!         VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     >,
+//!     capsules::sampled_trigger::TemperatureTrigger::new(
+//!         &base_peripherals.temp,
+//!         temp_trigger_alarm,
+//!         1000
+//!     )
+//! );
+//! kernel::hil::sensors::TemperatureDriver::set_client(&base_peripherals.temp, temp_trigger);
+//! temp_trigger_alarm.set_alarm_client(temp_trigger);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::adc;
+use kernel::hil::sensors::{self, TriggerDirection};
+use kernel::hil::time::{self, Alarm};
+use kernel::ErrorCode;
+
+/// Detects threshold crossings in a stream of readings.
+///
+/// The hysteresis band sits below the threshold for rising triggers, above
+/// it for falling triggers and is centered on it when triggering in both
+/// directions. The value has to leave the band on the other side before the
+/// trigger can fire again.
+pub struct ThresholdDetector {
+    threshold: Cell<i32>,
+    hysteresis: Cell<u32>,
+    direction: Cell<TriggerDirection>,
+    enabled: Cell<bool>,
+    /// Whether the value was last seen above the band, `None` until the
+    /// first reading after enabling.
+    above: OptionalCell<bool>,
+}
+
+impl ThresholdDetector {
+    pub const fn new() -> ThresholdDetector {
+        ThresholdDetector {
+            threshold: Cell::new(0),
+            hysteresis: Cell::new(0),
+            direction: Cell::new(TriggerDirection::Rising),
+            enabled: Cell::new(false),
+            above: OptionalCell::empty(),
+        }
+    }
+
+    pub fn enable(&self, threshold: i32, hysteresis: u32, direction: TriggerDirection) {
+        self.threshold.set(threshold);
+        self.hysteresis.set(hysteresis);
+        self.direction.set(direction);
+        self.above.clear();
+        self.enabled.set(true);
+    }
+
+    pub fn disable(&self) {
+        self.enabled.set(false);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    /// Process a reading, returning `true` if it crossed the threshold in
+    /// the configured direction. The first reading after enabling only
+    /// establishes which side of the threshold the value is on.
+    pub fn update(&self, value: i32) -> bool {
+        if !self.enabled.get() {
+            return false;
+        }
+        let threshold = self.threshold.get() as i64;
+        let hysteresis = self.hysteresis.get() as i64;
+        let direction = self.direction.get();
+        let (upper, lower) = match direction {
+            TriggerDirection::Rising => (threshold, threshold - hysteresis),
+            TriggerDirection::Falling => (threshold + hysteresis, threshold),
+            TriggerDirection::Both => (
+                threshold + hysteresis / 2,
+                threshold - (hysteresis - hysteresis / 2),
+            ),
+        };
+        let value = value as i64;
+
+        match self.above.extract() {
+            None => {
+                self.above.set(value > upper);
+                false
+            }
+            Some(false) if value > upper => {
+                self.above.set(true);
+                direction != TriggerDirection::Falling
+            }
+            Some(true) if value < lower => {
+                self.above.set(false);
+                direction != TriggerDirection::Rising
+            }
+            Some(_) => false,
+        }
+    }
+}
+
+/// Sampling state shared by the adapters.
+struct Sampler<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    interval_ms: u32,
+    detector: ThresholdDetector,
+    client: OptionalCell<&'a dyn sensors::TriggerClient>,
+}
+
+impl<'a, A: Alarm<'a>> Sampler<'a, A> {
+    fn new(alarm: &'a A, interval_ms: u32) -> Sampler<'a, A> {
+        Sampler {
+            alarm: alarm,
+            interval_ms: interval_ms,
+            detector: ThresholdDetector::new(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn enable(
+        &self,
+        threshold: i32,
+        hysteresis: u32,
+        direction: TriggerDirection,
+        start: impl FnOnce() -> Result<(), ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        let was_enabled = self.detector.is_enabled();
+        self.detector.enable(threshold, hysteresis, direction);
+        if !was_enabled {
+            // Take the first sample right away to learn which side of the
+            // threshold the value starts on. If the sensor is still busy
+            // with a reading from before, try again after an interval.
+            if start().is_err() {
+                self.sample_failed();
+            }
+        }
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        self.detector.disable();
+        if self.alarm.is_armed() {
+            self.alarm.disarm()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn sample_done(&self, value: i32) {
+        if !self.detector.is_enabled() {
+            return;
+        }
+        if self.detector.update(value) {
+            self.client.map(|client| client.triggered(value));
+        }
+        // The client may have disabled the trigger.
+        if self.detector.is_enabled() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(self.interval_ms));
+        }
+    }
+
+    /// Retry later when the sensor could not start a reading.
+    fn sample_failed(&self) {
+        if self.detector.is_enabled() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(self.interval_ms));
+        }
+    }
+}
+
+/// Trigger on temperature, in hundredths of degrees centigrade.
+pub struct TemperatureTrigger<'a, A: Alarm<'a>> {
+    sensor: &'a dyn sensors::TemperatureDriver<'a>,
+    sampler: Sampler<'a, A>,
+}
+
+impl<'a, A: Alarm<'a>> TemperatureTrigger<'a, A> {
+    pub fn new(
+        sensor: &'a dyn sensors::TemperatureDriver<'a>,
+        alarm: &'a A,
+        interval_ms: u32,
+    ) -> TemperatureTrigger<'a, A> {
+        TemperatureTrigger {
+            sensor: sensor,
+            sampler: Sampler::new(alarm, interval_ms),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> sensors::Trigger<'a> for TemperatureTrigger<'a, A> {
+    fn set_trigger_client(&self, client: &'a dyn sensors::TriggerClient) {
+        self.sampler.client.set(client);
+    }
+
+    fn enable_trigger(
+        &self,
+        threshold: i32,
+        hysteresis: u32,
+        direction: TriggerDirection,
+    ) -> Result<(), ErrorCode> {
+        self.sampler.enable(threshold, hysteresis, direction, || {
+            self.sensor.read_temperature()
+        })
+    }
+
+    fn disable_trigger(&self) -> Result<(), ErrorCode> {
+        self.sampler.disable()
+    }
+}
+
+impl<'a, A: Alarm<'a>> sensors::TemperatureClient for TemperatureTrigger<'a, A> {
+    fn callback(&self, value: usize) {
+        self.sampler.sample_done(value as i32);
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for TemperatureTrigger<'a, A> {
+    fn alarm(&self) {
+        if self.sensor.read_temperature().is_err() {
+            self.sampler.sample_failed();
+        }
+    }
+}
+
+/// Trigger on an ADC channel, in raw left-justified counts.
+pub struct AdcTrigger<'a, A: Alarm<'a>> {
+    channel: &'a dyn adc::AdcChannel,
+    sampler: Sampler<'a, A>,
+}
+
+impl<'a, A: Alarm<'a>> AdcTrigger<'a, A> {
+    pub fn new(
+        channel: &'a dyn adc::AdcChannel,
+        alarm: &'a A,
+        interval_ms: u32,
+    ) -> AdcTrigger<'a, A> {
+        AdcTrigger {
+            channel: channel,
+            sampler: Sampler::new(alarm, interval_ms),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> sensors::Trigger<'a> for AdcTrigger<'a, A> {
+    fn set_trigger_client(&self, client: &'a dyn sensors::TriggerClient) {
+        self.sampler.client.set(client);
+    }
+
+    fn enable_trigger(
+        &self,
+        threshold: i32,
+        hysteresis: u32,
+        direction: TriggerDirection,
+    ) -> Result<(), ErrorCode> {
+        self.sampler
+            .enable(threshold, hysteresis, direction, || self.channel.sample())
+    }
+
+    fn disable_trigger(&self) -> Result<(), ErrorCode> {
+        self.sampler.disable()
+    }
+}
+
+impl<'a, A: Alarm<'a>> adc::Client for AdcTrigger<'a, A> {
+    fn sample_ready(&self, sample: u16) {
+        self.sampler.sample_done(sample as i32);
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for AdcTrigger<'a, A> {
+    fn alarm(&self) {
+        if self.channel.sample().is_err() {
+            self.sampler.sample_failed();
+        }
+    }
+}
+
+/// Integer square root, rounded down.
+fn isqrt(value: u64) -> u64 {
+    let mut root = 0;
+    let mut bit = 1 << 62;
+    let mut rem = value;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+/// Trigger on the magnitude of acceleration, in milli-g.
+///
+/// At rest the magnitude is about 1000 mg. A rising trigger above that
+/// detects shocks and motion, a falling trigger well below it detects free
+/// fall.
+pub struct AccelerometerTrigger<'a, A: Alarm<'a>> {
+    sensor: &'a dyn sensors::NineDof<'a>,
+    sampler: Sampler<'a, A>,
+}
+
+impl<'a, A: Alarm<'a>> AccelerometerTrigger<'a, A> {
+    pub fn new(
+        sensor: &'a dyn sensors::NineDof<'a>,
+        alarm: &'a A,
+        interval_ms: u32,
+    ) -> AccelerometerTrigger<'a, A> {
+        AccelerometerTrigger {
+            sensor: sensor,
+            sampler: Sampler::new(alarm, interval_ms),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> sensors::Trigger<'a> for AccelerometerTrigger<'a, A> {
+    fn set_trigger_client(&self, client: &'a dyn sensors::TriggerClient) {
+        self.sampler.client.set(client);
+    }
+
+    fn enable_trigger(
+        &self,
+        threshold: i32,
+        hysteresis: u32,
+        direction: TriggerDirection,
+    ) -> Result<(), ErrorCode> {
+        self.sampler.enable(threshold, hysteresis, direction, || {
+            self.sensor.read_accelerometer()
+        })
+    }
+
+    fn disable_trigger(&self) -> Result<(), ErrorCode> {
+        self.sampler.disable()
+    }
+}
+
+impl<'a, A: Alarm<'a>> sensors::NineDofClient for AccelerometerTrigger<'a, A> {
+    fn callback(&self, x: usize, y: usize, z: usize) {
+        // Each axis is a signed value in milli-g.
+        let (x, y, z) = (x as i32 as i64, y as i32 as i64, z as i32 as i64);
+        let magnitude = isqrt((x * x + y * y + z * z) as u64);
+        self.sampler.sample_done(magnitude as i32);
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for AccelerometerTrigger<'a, A> {
+    fn alarm(&self) {
+        if self.sensor.read_accelerometer().is_err() {
+            self.sampler.sample_failed();
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Lets processes read how the boot stages verified the kernel.
+//!
+//! Exposes the state a `hil::secure_boot::SecureBootStatus` reports: how the
+//! image the kernel is part of was verified, the key it was verified with,
+//! and the anti-rollback counters. Processes can only read it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let boot_status = static_init!(
+//!     earlgrey::boot_log::BootLog,
+//!     earlgrey::boot_log::BootLog::read(earlgrey::boot_log::BOOT_LOG_BASE, &peripherals.hmac)
+//! );
+//! let secure_boot = static_init!(
+//!     capsules::secure_boot::SecureBoot<'static, earlgrey::boot_log::BootLog>,
+//!     capsules::secure_boot::SecureBoot::new(boot_status)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Initial
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Return how the image was verified: `0` unknown, `1` not verified,
+//!   `2` integrity checked, `3` signature verified.
+//! - `2`: Return the ID of the key the signature was verified with. Returns
+//!   `NOSUPPORT` unless the signature was verified and the boot stage
+//!   recorded the key.
+//! - `3`: Return the number of anti-rollback counters.
+//! - `4`: Return the value of anti-rollback counter `arg1`. Returns `INVAL`
+//!   if there is no such counter.
+
+use kernel::hil::secure_boot::SecureBootStatus;
+use kernel::{CommandReturn, Driver, ErrorCode, ProcessId};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SecureBoot as usize;
+
+pub struct SecureBoot<'a, S: SecureBootStatus> {
+    status: &'a S,
+}
+
+impl<'a, S: SecureBootStatus> SecureBoot<'a, S> {
+    pub fn new(status: &'a S) -> SecureBoot<'a, S> {
+        SecureBoot { status }
+    }
+}
+
+impl<'a, S: SecureBootStatus> Driver for SecureBoot<'a, S> {
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Return how the image was verified.
+    /// - `2`: Return the ID of the key the signature was verified with.
+    /// - `3`: Return the number of anti-rollback counters.
+    /// - `4`: Return the value of anti-rollback counter `arg1`.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        _arg2: usize,
+        _appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+            1 => CommandReturn::success_u32(self.status.verification() as u32),
+            2 => match self.status.key_id() {
+                Some(key_id) => CommandReturn::success_u32(key_id),
+                None => CommandReturn::failure(ErrorCode::NOSUPPORT),
+            },
+            3 => CommandReturn::success_u32(self.status.rollback_counters() as u32),
+            4 => match self.status.rollback_counter(arg1) {
+                Some(value) => CommandReturn::success_u32(value),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -78,6 +78,7 @@
 //!         rtt,
 //!         &mut capsules::console::WRITE_BUF,
 //!         &mut capsules::console::READ_BUF,
+//!         &mut capsules::console::READ_QUEUE,
 //!         board_kernel.create_grant(&grant_cap)
 //!     )
 //! );
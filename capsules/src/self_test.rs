@@ -0,0 +1,416 @@
+//! Runs a list of driver self-tests and reports the combined result.
+//!
+//! This is used by boards running in a simulator (QEMU, Verilator) to check
+//! that the kernel and its peripherals work without any process loaded. Each
+//! test is run in order, its result is printed with `debug!`, and once every
+//! test has finished the runner reports a pass/fail result through the
+//! board's `SimulationExit` implementation. Deployed boards can run the same
+//! tests periodically with `capsules::health_monitor::HealthMonitor`.
+//!
+//! Besides the runner, this module has self-tests that apply to any board:
+//! that an alarm fires, that an I2C device acknowledges its address, that the
+//! kernel image in flash is unchanged, and that unused RAM holds a pattern.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let alarm_test = static_init!(
+//!     capsules::self_test::AlarmSelfTest<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::self_test::AlarmSelfTest::new(virtual_alarm_test)
+//! );
+//! virtual_alarm_test.set_alarm_client(alarm_test);
+//! let tests = static_init!([&'static dyn SelfTest<'static>; 1], [alarm_test]);
+//! let runner = static_init!(
+//!     capsules::self_test::SelfTestRunner<'static>,
+//!     capsules::self_test::SelfTestRunner::new(tests, &io::SIM_EXIT)
+//! );
+//! runner.start();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell, VolatileCell};
+use kernel::debug;
+use kernel::hil::i2c;
+use kernel::hil::self_test::{SelfTest, SelfTestClient};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::simulation::{SimulationExit, SimulationResult};
+use kernel::ErrorCode;
+use tickv::crc32::{Crc, Digest};
+
+use crate::chunked_executor::{ChunkedTask, ExecutorTask};
+
+pub struct SelfTestRunner<'a> {
+    tests: &'a [&'a dyn SelfTest<'a>],
+    exit: &'a dyn SimulationExit,
+    current: Cell<usize>,
+    failures: Cell<u32>,
+}
+
+impl<'a> SelfTestRunner<'a> {
+    pub fn new(tests: &'a [&'a dyn SelfTest<'a>], exit: &'a dyn SimulationExit) -> Self {
+        SelfTestRunner {
+            tests: tests,
+            exit: exit,
+            current: Cell::new(0),
+            failures: Cell::new(0),
+        }
+    }
+
+    /// Start running the tests. The runner must not be started more than once.
+    pub fn start(&'a self) {
+        for test in self.tests.iter() {
+            test.set_client(self);
+        }
+        self.current.set(0);
+        self.failures.set(0);
+        self.run_next();
+    }
+
+    fn run_next(&self) {
+        // Tests that fail to start are counted as failures, so loop until a
+        // test is in progress or the list is exhausted.
+        while let Some(test) = self.tests.get(self.current.get()) {
+            match test.run() {
+                Ok(()) => return,
+                Err(e) => self.record(Err(e)),
+            }
+        }
+
+        let failures = self.failures.get();
+        debug!("self-test: {} run, {} failed", self.tests.len(), failures);
+        if failures == 0 {
+            self.exit.exit(SimulationResult::Pass);
+        } else {
+            self.exit.exit(SimulationResult::Fail(failures));
+        }
+    }
+
+    fn record(&self, result: Result<(), ErrorCode>) {
+        let index = self.current.get();
+        let name = self.tests.get(index).map_or("?", |test| test.name());
+        match result {
+            Ok(()) => debug!("self-test {}: pass", name),
+            Err(e) => {
+                debug!("self-test {}: FAIL ({:?})", name, e);
+                self.failures.set(self.failures.get() + 1);
+            }
+        }
+        self.current.set(index + 1);
+    }
+}
+
+impl SelfTestClient for SelfTestRunner<'_> {
+    fn self_test_done(&self, result: Result<(), ErrorCode>) {
+        self.record(result);
+        self.run_next();
+    }
+}
+
+/// Checks that an alarm fires and that time advances by at least the
+/// requested interval.
+pub struct AlarmSelfTest<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    start: Cell<A::Ticks>,
+    client: OptionalCell<&'a dyn SelfTestClient>,
+}
+
+impl<'a, A: Alarm<'a>> AlarmSelfTest<'a, A> {
+    /// Length of the interval the test waits for.
+    const INTERVAL_MS: u32 = 10;
+
+    pub fn new(alarm: &'a A) -> Self {
+        AlarmSelfTest {
+            alarm: alarm,
+            start: Cell::new(A::Ticks::from(0)),
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> SelfTest<'a> for AlarmSelfTest<'a, A> {
+    fn name(&self) -> &'static str {
+        "alarm"
+    }
+
+    fn set_client(&self, client: &'a dyn SelfTestClient) {
+        self.client.set(client);
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        if self.alarm.is_armed() {
+            return Err(ErrorCode::BUSY);
+        }
+        let now = self.alarm.now();
+        self.start.set(now);
+        self.alarm
+            .set_alarm(now, A::ticks_from_ms(Self::INTERVAL_MS));
+        Ok(())
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmSelfTest<'a, A> {
+    fn alarm(&self) {
+        let elapsed = self.alarm.now().wrapping_sub(self.start.get());
+        let result = if elapsed >= A::ticks_from_ms(Self::INTERVAL_MS) {
+            Ok(())
+        } else {
+            Err(ErrorCode::FAIL)
+        };
+        self.client.map(|client| client.self_test_done(result));
+    }
+}
+
+/// Checks that a device on an I2C bus acknowledges its address, by reading a
+/// byte from it.
+pub struct I2cPresenceSelfTest<'a> {
+    name: &'static str,
+    i2c: &'a dyn i2c::I2CDevice,
+    buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn SelfTestClient>,
+}
+
+impl<'a> I2cPresenceSelfTest<'a> {
+    /// `buffer` must hold at least one byte. The test is reported as `name`.
+    pub fn new(name: &'static str, i2c: &'a dyn i2c::I2CDevice, buffer: &'static mut [u8]) -> Self {
+        I2cPresenceSelfTest {
+            name: name,
+            i2c: i2c,
+            buffer: TakeCell::new(buffer),
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a> SelfTest<'a> for I2cPresenceSelfTest<'a> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn set_client(&self, client: &'a dyn SelfTestClient) {
+        self.client.set(client);
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        if buffer.is_empty() {
+            self.buffer.replace(buffer);
+            return Err(ErrorCode::FAIL);
+        }
+        self.i2c.enable();
+        self.i2c.read(buffer, 1);
+        Ok(())
+    }
+}
+
+impl i2c::I2CClient for I2cPresenceSelfTest<'_> {
+    fn command_complete(&self, buffer: &'static mut [u8], error: i2c::Error) {
+        self.i2c.disable();
+        self.buffer.replace(buffer);
+        let result = match error {
+            i2c::Error::CommandComplete => Ok(()),
+            i2c::Error::AddressNak => Err(ErrorCode::NODEVICE),
+            _ => Err(ErrorCode::FAIL),
+        };
+        self.client.map(|client| client.self_test_done(result));
+    }
+}
+
+/// Estimated cost of adding one byte to a CRC, in cycles.
+const CRC_BYTE_CYCLES: u32 = 20;
+
+/// The CRC-32 shared with TicKV, which the digests of the test refer to.
+static CRC32: Crc = Crc::new();
+
+/// Checks that an image in flash, usually the kernel's own, is unchanged, by
+/// comparing its CRC-32, as computed by `tickv::crc32`, with a known value.
+///
+/// If the board cannot know the CRC when it is built, the CRC of the first run
+/// becomes the known value, and the test catches flash that changes while the
+/// board runs.
+pub struct FlashCrcSelfTest<'a> {
+    task: &'a ExecutorTask<'a>,
+    image: &'a [u8],
+    expected: Cell<Option<u32>>,
+    offset: Cell<usize>,
+    crc: MapCell<Digest<'static>>,
+    client: OptionalCell<&'a dyn SelfTestClient>,
+}
+
+impl<'a> FlashCrcSelfTest<'a> {
+    pub fn new(task: &'a ExecutorTask<'a>, image: &'a [u8], expected: Option<u32>) -> Self {
+        FlashCrcSelfTest {
+            task: task,
+            image: image,
+            expected: Cell::new(expected),
+            offset: Cell::new(0),
+            crc: MapCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// The CRC-32 of the image, once the test has run.
+    pub fn crc(&self) -> Option<u32> {
+        self.expected.get()
+    }
+}
+
+impl<'a> SelfTest<'a> for FlashCrcSelfTest<'a> {
+    fn name(&self) -> &'static str {
+        "flash crc"
+    }
+
+    fn set_client(&self, client: &'a dyn SelfTestClient) {
+        self.client.set(client);
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        if self.task.is_scheduled() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.offset.set(0);
+        self.crc.put(CRC32.digest());
+        self.task.schedule();
+        Ok(())
+    }
+}
+
+impl ChunkedTask for FlashCrcSelfTest<'_> {
+    fn run_chunk(&self, budget: u32) -> bool {
+        let offset = self.offset.get();
+        let len = ((budget / CRC_BYTE_CYCLES).max(1) as usize).min(self.image.len() - offset);
+        self.crc
+            .map(|crc| crc.update(&self.image[offset..offset + len]));
+        self.offset.set(offset + len);
+        if offset + len < self.image.len() {
+            return false;
+        }
+
+        let crc = match self.crc.take() {
+            Some(crc) => crc.finalise(),
+            None => return true,
+        };
+        let result = match self.expected.get() {
+            Some(expected) if expected != crc => Err(ErrorCode::FAIL),
+            _ => Ok(()),
+        };
+        if self.expected.get().is_none() {
+            self.expected.set(Some(crc));
+        }
+        self.client.map(|client| client.self_test_done(result));
+        true
+    }
+}
+
+/// Estimated cost of writing or checking one word of RAM, in cycles.
+const RAM_WORD_CYCLES: u32 = 10;
+
+#[derive(Clone, Copy, PartialEq)]
+enum RamPass {
+    /// Write the pattern to every word.
+    Write,
+    /// Check the pattern and write its complement.
+    CheckInvert,
+    /// Check the complement and clear the word.
+    CheckClear,
+}
+
+/// Checks a region of RAM that nothing else uses, by filling it with a
+/// pattern that differs for every word and reading it back, first as it was
+/// written and then inverted. This finds stuck bits and faulty address
+/// lines. The region is left zeroed.
+pub struct RamPatternSelfTest<'a> {
+    task: &'a ExecutorTask<'a>,
+    region: &'a [VolatileCell<u32>],
+    pass: Cell<RamPass>,
+    offset: Cell<usize>,
+    failed: Cell<bool>,
+    client: OptionalCell<&'a dyn SelfTestClient>,
+}
+
+impl<'a> RamPatternSelfTest<'a> {
+    pub fn new(task: &'a ExecutorTask<'a>, region: &'a [VolatileCell<u32>]) -> Self {
+        RamPatternSelfTest {
+            task: task,
+            region: region,
+            pass: Cell::new(RamPass::Write),
+            offset: Cell::new(0),
+            failed: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn pattern(index: usize) -> u32 {
+        (index as u32).wrapping_mul(0x9e37_79b9) ^ 0x5555_5555
+    }
+}
+
+impl<'a> SelfTest<'a> for RamPatternSelfTest<'a> {
+    fn name(&self) -> &'static str {
+        "ram pattern"
+    }
+
+    fn set_client(&self, client: &'a dyn SelfTestClient) {
+        self.client.set(client);
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        if self.task.is_scheduled() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.pass.set(RamPass::Write);
+        self.offset.set(0);
+        self.failed.set(false);
+        self.task.schedule();
+        Ok(())
+    }
+}
+
+impl ChunkedTask for RamPatternSelfTest<'_> {
+    fn run_chunk(&self, budget: u32) -> bool {
+        let offset = self.offset.get();
+        let end = (offset + (budget / RAM_WORD_CYCLES).max(1) as usize).min(self.region.len());
+        let pass = self.pass.get();
+        for (index, word) in self.region[offset..end].iter().enumerate() {
+            let pattern = Self::pattern(offset + index);
+            match pass {
+                RamPass::Write => word.set(pattern),
+                RamPass::CheckInvert => {
+                    if word.get() != pattern {
+                        self.failed.set(true);
+                    }
+                    word.set(!pattern);
+                }
+                RamPass::CheckClear => {
+                    if word.get() != !pattern {
+                        self.failed.set(true);
+                    }
+                    word.set(0);
+                }
+            }
+        }
+        self.offset.set(end);
+        if end < self.region.len() {
+            return false;
+        }
+
+        self.offset.set(0);
+        match pass {
+            RamPass::Write => self.pass.set(RamPass::CheckInvert),
+            RamPass::CheckInvert => self.pass.set(RamPass::CheckClear),
+            RamPass::CheckClear => {
+                let result = if self.failed.get() {
+                    Err(ErrorCode::FAIL)
+                } else {
+                    Ok(())
+                };
+                self.client.map(|client| client.self_test_done(result));
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,203 @@
+//! Provides userspace with threshold events from sensors.
+//!
+//! Instead of polling a sensor, a process arms a trigger on one of the
+//! sources the board provides and waits for the callback. Any sensor that
+//! implements `hil::sensors::Trigger` can be a source; the adapters in
+//! `sampled_trigger` provide triggers for sensors without hardware support.
+//!
+//! A source can only be armed by one process at a time. It stays armed, and
+//! keeps delivering events, until the process disarms it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! use capsules::sensor_trigger::{SensorTrigger, TriggerSource};
+//!
+//! let sources = static_init!(
+//!     [TriggerSource<'static>; 2],
+//!     [TriggerSource::new(temp_trigger), TriggerSource::new(adc_trigger)]
+//! );
+//! let sensor_trigger = static_init!(
+//!     SensorTrigger<'static>,
+//!     SensorTrigger::new(sources, board_kernel.create_grant(&grant_cap))
+//! );
+//! sensor_trigger.initialize();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: trigger events. The callback gets the source and the value that
+//!   crossed the threshold, in the units of the source.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the number of sources.
+//! - `1`: Arm a source. `arg1` holds the source in bits 0-7, the direction
+//!   in bits 8-9 (`0` rising, `1` falling, `2` both) and the hysteresis in
+//!   bits 16-31. `arg2` is the threshold, as a signed 32-bit value.
+//! - `2`: Disarm source `arg1`.
+
+use core::mem;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::sensors::{Trigger, TriggerClient, TriggerDirection};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SensorTrigger as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+}
+
+/// A trigger offered to processes, and the process that has armed it.
+pub struct TriggerSource<'a> {
+    trigger: &'a dyn Trigger<'a>,
+    index: usize,
+    owner: OptionalCell<ProcessId>,
+    driver: OptionalCell<&'a SensorTrigger<'a>>,
+}
+
+impl<'a> TriggerSource<'a> {
+    pub fn new(trigger: &'a dyn Trigger<'a>) -> TriggerSource<'a> {
+        TriggerSource {
+            trigger: trigger,
+            index: 0,
+            owner: OptionalCell::empty(),
+            driver: OptionalCell::empty(),
+        }
+    }
+}
+
+impl TriggerClient for TriggerSource<'_> {
+    fn triggered(&self, value: i32) {
+        self.driver
+            .map(|driver| driver.triggered(self.index, self.owner.extract(), value));
+    }
+}
+
+pub struct SensorTrigger<'a> {
+    sources: &'a [TriggerSource<'a>],
+    apps: Grant<App>,
+}
+
+impl<'a> SensorTrigger<'a> {
+    pub fn new(sources: &'a mut [TriggerSource<'a>], grant: Grant<App>) -> SensorTrigger<'a> {
+        for (index, source) in sources.iter_mut().enumerate() {
+            source.index = index;
+        }
+        SensorTrigger {
+            sources: sources,
+            apps: grant,
+        }
+    }
+
+    /// Register as the client of every source.
+    pub fn initialize(&'a self) {
+        for source in self.sources.iter() {
+            source.driver.set(self);
+            source.trigger.set_trigger_client(source);
+        }
+    }
+
+    fn triggered(&self, index: usize, owner: Option<ProcessId>, value: i32) {
+        owner.map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback.schedule(index, value as usize, 0);
+            });
+        });
+    }
+
+    /// Whether `source` is armed by a process other than `appid` that still
+    /// exists.
+    fn owned_by_other(&self, source: &TriggerSource, appid: ProcessId) -> bool {
+        source.owner.map_or(false, |owner| {
+            *owner != appid && self.apps.enter(*owner, |_| ()).is_ok()
+        })
+    }
+
+    fn arm(&self, arg1: usize, threshold: i32, appid: ProcessId) -> Result<(), ErrorCode> {
+        let source = self.sources.get(arg1 & 0xff).ok_or(ErrorCode::INVAL)?;
+        let direction = match (arg1 >> 8) & 0x3 {
+            0 => TriggerDirection::Rising,
+            1 => TriggerDirection::Falling,
+            2 => TriggerDirection::Both,
+            _ => return Err(ErrorCode::INVAL),
+        };
+        let hysteresis = ((arg1 >> 16) & 0xffff) as u32;
+        if self.owned_by_other(source, appid) {
+            return Err(ErrorCode::BUSY);
+        }
+        source
+            .trigger
+            .enable_trigger(threshold, hysteresis, direction)?;
+        source.owner.set(appid);
+        Ok(())
+    }
+
+    fn disarm(&self, index: usize, appid: ProcessId) -> Result<(), ErrorCode> {
+        let source = self.sources.get(index).ok_or(ErrorCode::INVAL)?;
+        if !source.owner.contains(&appid) {
+            return Err(ErrorCode::ALREADY);
+        }
+        source.owner.clear();
+        source.trigger.disable_trigger()
+    }
+}
+
+impl Driver for SensorTrigger<'_> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Trigger event callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Control triggers.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the number of sources.
+    /// - `1`: Arm a source
+    /// - `2`: Disarm a source
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
+        let res = match cmd_num {
+            0 => return CommandReturn::success_u32(self.sources.len() as u32),
+            1 => self.arm(arg1, arg2 as i32, appid),
+            2 => self.disarm(arg1, appid),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
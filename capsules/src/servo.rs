@@ -0,0 +1,316 @@
+//! Provides userspace with control of hobby servo motors.
+//!
+//! Each channel is a PWM pin driving one servo with the usual 50 Hz frame,
+//! whose pulse width sets the position of the servo. Processes set the pulse
+//! width in microseconds, or an angle from 0 to 180 degrees that maps linearly
+//! onto the pulse widths the board allows. Without a speed limit a channel
+//! moves straight to its new position. With one, the capsule moves the pulse
+//! width a step each frame, so the servo turns at that speed. When a channel
+//! reaches its new position, the process that moved it is notified.
+//!
+//! `virtual_pwm` only runs one pin of a PWM peripheral at a time, so each
+//! channel needs a PWM peripheral of its own.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let servo_pins = static_init!(
+//!     [&'static dyn kernel::hil::pwm::PwmPin; 2],
+//!     [servo_pwm_pin0, servo_pwm_pin1]
+//! );
+//! let servo_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let servo = static_init!(
+//!     capsules::servo::Servo<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     capsules::servo::Servo::new(
+//!         servo_pins,
+//!         servo_alarm,
+//!         capsules::servo::DEFAULT_MIN_PULSE_US,
+//!         capsules::servo::DEFAULT_MAX_PULSE_US,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! servo_alarm.set_alarm_client(servo);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: move done. The callback gets the channel and its pulse width in
+//!   microseconds, and is only called for the process that last moved the
+//!   channel.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the number of channels.
+//! - `1`: Move channel `arg1` to a pulse width of `arg2` microseconds.
+//! - `2`: Move channel `arg1` to an angle of `arg2` degrees, from 0 to 180.
+//! - `3`: Limit the speed of channel `arg1` to `arg2` degrees per second. `0`
+//!   removes the limit.
+//! - `4`: Stop the pulses of channel `arg1`, so the servo no longer holds its
+//!   position.
+//! - `5`: Get the current and the target pulse width of channel `arg1`, in
+//!   microseconds, or `0` if the channel is stopped.
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+
+use kernel::hil::pwm::PwmPin;
+use kernel::hil::time::{self, Alarm};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Servo as usize;
+
+/// Most channels the capsule keeps state for.
+pub const MAX_CHANNELS: usize = 8;
+
+/// The pulse widths of 0 and 180 degrees that most servos accept.
+pub const DEFAULT_MIN_PULSE_US: usize = 500;
+pub const DEFAULT_MAX_PULSE_US: usize = 2500;
+
+/// The frame of the PWM signal: servos expect a pulse every 20 ms.
+const FRAME_HZ: usize = 50;
+const FRAME_MS: u32 = 20;
+const FRAME_US: usize = 20_000;
+
+/// The angle of the longest pulse.
+const MAX_ANGLE: usize = 180;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+}
+
+pub struct Servo<'a, A: Alarm<'a>> {
+    pins: &'a [&'a dyn PwmPin],
+    alarm: &'a A,
+    min_pulse_us: usize,
+    max_pulse_us: usize,
+    /// Pulse width each channel outputs, or 0 while it is stopped.
+    current: [Cell<usize>; MAX_CHANNELS],
+    /// Pulse width each channel is moving to.
+    target: [Cell<usize>; MAX_CHANNELS],
+    /// Speed limit of each channel, in microseconds of pulse width per second,
+    /// or 0 for none.
+    speed: [Cell<usize>; MAX_CHANNELS],
+    /// The process that last moved each channel.
+    owner: [Cell<Option<ProcessId>>; MAX_CHANNELS],
+    moving: Cell<bool>,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>> Servo<'a, A> {
+    /// Control a servo on each of `pins`. `min_pulse_us` and `max_pulse_us`
+    /// are the pulse widths of 0 and 180 degrees, and bound the pulse widths
+    /// processes can set.
+    pub fn new(
+        pins: &'a [&'a dyn PwmPin],
+        alarm: &'a A,
+        min_pulse_us: usize,
+        max_pulse_us: usize,
+        grant: Grant<App>,
+    ) -> Servo<'a, A> {
+        Servo {
+            pins: pins,
+            alarm: alarm,
+            min_pulse_us: min_pulse_us,
+            max_pulse_us: max_pulse_us,
+            current: Default::default(),
+            target: Default::default(),
+            speed: Default::default(),
+            owner: Default::default(),
+            moving: Cell::new(false),
+            apps: grant,
+        }
+    }
+
+    fn channels(&self) -> usize {
+        cmp::min(self.pins.len(), MAX_CHANNELS)
+    }
+
+    /// Output `pulse_us` on `channel`.
+    fn output(&self, channel: usize, pulse_us: usize) -> Result<(), ErrorCode> {
+        let pin = self.pins[channel];
+        let duty_cycle =
+            (pulse_us as u64 * pin.get_maximum_duty_cycle() as u64 / FRAME_US as u64) as usize;
+        pin.start(FRAME_HZ, duty_cycle)
+    }
+
+    fn notify(&self, channel: usize) {
+        self.owner[channel].get().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback
+                    .schedule(channel, self.current[channel].get(), 0);
+            });
+        });
+    }
+
+    fn move_to(&self, channel: usize, pulse_us: usize, appid: ProcessId) -> Result<(), ErrorCode> {
+        if channel >= self.channels()
+            || pulse_us < self.min_pulse_us
+            || pulse_us > self.max_pulse_us
+        {
+            return Err(ErrorCode::INVAL);
+        }
+        self.owner[channel].set(Some(appid));
+        self.target[channel].set(pulse_us);
+
+        // The position of a stopped servo is unknown, so there is nothing to
+        // move it gradually from.
+        if self.speed[channel].get() == 0 || self.current[channel].get() == 0 {
+            self.output(channel, pulse_us)?;
+            self.current[channel].set(pulse_us);
+            self.notify(channel);
+        } else if !self.moving.get() {
+            self.moving.set(true);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(FRAME_MS));
+        }
+        Ok(())
+    }
+
+    fn stop(&self, channel: usize) -> Result<(), ErrorCode> {
+        if channel >= self.channels() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.current[channel].set(0);
+        self.target[channel].set(0);
+        self.pins[channel].stop()
+    }
+
+    /// The pulse width of `angle` degrees.
+    fn angle_to_pulse_us(&self, angle: usize) -> Option<usize> {
+        if angle > MAX_ANGLE {
+            None
+        } else {
+            Some(self.min_pulse_us + angle * (self.max_pulse_us - self.min_pulse_us) / MAX_ANGLE)
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Servo<'a, A> {
+    fn alarm(&self) {
+        let mut moving = false;
+        for channel in 0..self.channels() {
+            let current = self.current[channel].get();
+            let target = self.target[channel].get();
+            if current == target {
+                continue;
+            }
+
+            let next = match self.speed[channel].get() {
+                // The limit was removed during the move.
+                0 => target,
+                speed => {
+                    let step = cmp::max(1, speed * FRAME_MS as usize / 1000);
+                    if current < target {
+                        cmp::min(current + step, target)
+                    } else {
+                        cmp::max(current.saturating_sub(step), target)
+                    }
+                }
+            };
+            if self.output(channel, next).is_err() {
+                // Give up on the move rather than retry every frame.
+                self.target[channel].set(current);
+                continue;
+            }
+            self.current[channel].set(next);
+            if next == target {
+                self.notify(channel);
+            } else {
+                moving = true;
+            }
+        }
+
+        self.moving.set(moving);
+        if moving {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(FRAME_MS));
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for Servo<'a, A> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Move done callback
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// Control servos.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the number of channels.
+    /// - `1`: Move a channel to a pulse width
+    /// - `2`: Move a channel to an angle
+    /// - `3`: Limit the speed of a channel
+    /// - `4`: Stop a channel
+    /// - `5`: Get the current and target pulse width of a channel
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: ProcessId) -> CommandReturn {
+        match cmd_num {
+            0 => CommandReturn::success_u32(self.channels() as u32),
+            1 => self.move_to(arg1, arg2, appid).into(),
+            2 => match self.angle_to_pulse_us(arg2) {
+                Some(pulse_us) => self.move_to(arg1, pulse_us, appid).into(),
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            3 => {
+                if arg1 >= self.channels() {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    // Degrees per second to microseconds of pulse width per
+                    // second.
+                    let speed = arg2 * (self.max_pulse_us - self.min_pulse_us) / MAX_ANGLE;
+                    self.speed[arg1].set(speed);
+                    CommandReturn::success()
+                }
+            }
+            4 => self.stop(arg1).into(),
+            5 => {
+                if arg1 >= self.channels() {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    CommandReturn::success_u32_u32(
+                        self.current[arg1].get() as u32,
+                        self.target[arg1].get() as u32,
+                    )
+                }
+            }
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
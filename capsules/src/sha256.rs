@@ -0,0 +1,353 @@
+//! Software implementation of SHA-256, for chips without a hash engine.
+//!
+//! Implements `hil::digest::Digest` on the CPU. Hashing runs on a
+//! `ChunkedExecutor`, a few blocks per kernel loop iteration, so hashing a
+//! large buffer does not hold up the rest of the system.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sha = static_init!(
+//!     capsules::sha256::Sha256Software<'static>,
+//!     capsules::sha256::Sha256Software::new(sha_task)
+//! );
+//! sha_task.set_client(sha);
+//! ```
+
+use core::cell::Cell;
+use core::convert::TryInto;
+
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::ErrorCode;
+
+use crate::chunked_executor::{ChunkedTask, ExecutorTask};
+
+/// Estimated cost of compressing one block, in cycles.
+const BLOCK_CYCLES: u32 = 4000;
+
+pub(crate) const BLOCK_LEN: usize = 64;
+
+pub(crate) const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Mix `block` into `state`.
+pub(crate) fn compress(state: &mut [u32; 8], block: &[u8; BLOCK_LEN]) {
+    let mut w = [0u32; 64];
+    for (i, word) in block.chunks(4).enumerate() {
+        w[i] = u32::from_be_bytes(word.try_into().unwrap_or([0; 4]));
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let mut v = *state;
+    for i in 0..64 {
+        let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+        let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+        let t1 = v[7]
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(ROUND_CONSTANTS[i])
+            .wrapping_add(w[i]);
+        let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+        let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+        let t2 = s0.wrapping_add(maj);
+        v = [
+            t1.wrapping_add(t2),
+            v[0],
+            v[1],
+            v[2],
+            v[3].wrapping_add(t1),
+            v[4],
+            v[5],
+            v[6],
+        ];
+    }
+    for (s, x) in state.iter_mut().zip(v.iter()) {
+        *s = s.wrapping_add(*x);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    AddData,
+    Finish,
+}
+
+pub struct Sha256Software<'a> {
+    task: &'a ExecutorTask<'a>,
+    client: OptionalCell<&'a dyn digest::Client<'a, [u8; 32]>>,
+    operation: Cell<Operation>,
+    state: Cell<[u32; 8]>,
+    /// Data not yet compressed, always less than a block.
+    block: Cell<[u8; BLOCK_LEN]>,
+    block_len: Cell<usize>,
+    /// Bytes hashed so far.
+    total_len: Cell<u64>,
+    data: MapCell<LeasableBuffer<'static, u8>>,
+    /// Bytes of `data` hashed so far.
+    data_index: Cell<usize>,
+    digest: TakeCell<'static, [u8; 32]>,
+}
+
+impl<'a> Sha256Software<'a> {
+    pub fn new(task: &'a ExecutorTask<'a>) -> Sha256Software<'a> {
+        Sha256Software {
+            task: task,
+            client: OptionalCell::empty(),
+            operation: Cell::new(Operation::Idle),
+            state: Cell::new(INITIAL_STATE),
+            block: Cell::new([0; BLOCK_LEN]),
+            block_len: Cell::new(0),
+            total_len: Cell::new(0),
+            data: MapCell::empty(),
+            data_index: Cell::new(0),
+            digest: TakeCell::empty(),
+        }
+    }
+
+    fn reset(&self) {
+        self.state.set(INITIAL_STATE);
+        self.block.set([0; BLOCK_LEN]);
+        self.block_len.set(0);
+        self.total_len.set(0);
+    }
+
+    /// Hash up to `blocks` blocks of the pending data. Returns whether all of
+    /// it has been hashed.
+    fn hash_data(&self, blocks: u32) -> bool {
+        let mut state = self.state.get();
+        let mut block = self.block.get();
+        let mut block_len = self.block_len.get();
+        let mut index = self.data_index.get();
+        let mut blocks = blocks;
+
+        let done = self.data.map_or(true, |data| {
+            while index < data.len() && blocks > 0 {
+                let count = (BLOCK_LEN - block_len).min(data.len() - index);
+                block[block_len..block_len + count].copy_from_slice(&data[index..index + count]);
+                block_len += count;
+                index += count;
+                if block_len == BLOCK_LEN {
+                    compress(&mut state, &block);
+                    block_len = 0;
+                    blocks -= 1;
+                }
+            }
+            index == data.len()
+        });
+
+        self.total_len
+            .set(self.total_len.get() + (index - self.data_index.get()) as u64);
+        self.state.set(state);
+        self.block.set(block);
+        self.block_len.set(block_len);
+        self.data_index.set(index);
+        done
+    }
+
+    /// Pad the message and write the final hash to `digest`.
+    fn finish(&self, digest: &mut [u8; 32]) {
+        let mut state = self.state.get();
+        let mut block = self.block.get();
+        let block_len = self.block_len.get();
+
+        block[block_len] = 0x80;
+        for byte in block[block_len + 1..].iter_mut() {
+            *byte = 0;
+        }
+        if block_len + 1 > BLOCK_LEN - 8 {
+            compress(&mut state, &block);
+            block = [0; BLOCK_LEN];
+        }
+        block[BLOCK_LEN - 8..].copy_from_slice(&(self.total_len.get() * 8).to_be_bytes());
+        compress(&mut state, &block);
+
+        for (out, word) in digest.chunks_mut(4).zip(state.iter()) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+}
+
+impl<'a> digest::Digest<'a, [u8; 32]> for Sha256Software<'a> {
+    fn set_client(&'a self, client: &'a dyn digest::Client<'a, [u8; 32]>) {
+        self.client.set(client);
+    }
+
+    fn add_data(
+        &self,
+        data: LeasableBuffer<'static, u8>,
+    ) -> Result<usize, (ErrorCode, &'static mut [u8])> {
+        if self.operation.get() != Operation::Idle {
+            return Err((ErrorCode::BUSY, data.take()));
+        }
+        let len = data.len();
+        self.data.replace(data);
+        self.data_index.set(0);
+        self.operation.set(Operation::AddData);
+        self.task.schedule();
+        Ok(len)
+    }
+
+    fn run(
+        &'a self,
+        digest: &'static mut [u8; 32],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; 32])> {
+        if self.operation.get() != Operation::Idle {
+            return Err((ErrorCode::BUSY, digest));
+        }
+        self.digest.replace(digest);
+        self.operation.set(Operation::Finish);
+        self.task.schedule();
+        Ok(())
+    }
+
+    fn clear_data(&self) {
+        // Buffers of an operation in progress are returned by its callback,
+        // so only clear between operations.
+        if self.operation.get() == Operation::Idle {
+            self.reset();
+        }
+    }
+}
+
+impl ChunkedTask for Sha256Software<'_> {
+    fn run_chunk(&self, budget: u32) -> bool {
+        match self.operation.get() {
+            Operation::Idle => true,
+            Operation::AddData => {
+                if !self.hash_data((budget / BLOCK_CYCLES).max(1)) {
+                    return false;
+                }
+                self.operation.set(Operation::Idle);
+                self.data.take().map(|data| {
+                    let data = data.take();
+                    self.client
+                        .map(move |client| client.add_data_done(Ok(()), data));
+                });
+                true
+            }
+            Operation::Finish => {
+                // At most two blocks, so done in one chunk.
+                self.operation.set(Operation::Idle);
+                self.digest.take().map(|digest| {
+                    self.finish(digest);
+                    self.reset();
+                    self.client
+                        .map(move |client| client.hash_done(Ok(()), digest));
+                });
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunked_executor::ChunkedExecutor;
+    use kernel::common::dynamic_deferred_call::{
+        DynamicDeferredCall, DynamicDeferredCallClientState,
+    };
+    use kernel::hil::digest::Digest;
+
+    extern crate std;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// The SHA-256 of `message`, added in parts of at most `part_len` bytes.
+    fn sha256(message: &[u8], part_len: usize) -> [u8; 32] {
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let deferred_caller = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let executor = Box::leak(Box::new(ChunkedExecutor::new(deferred_caller, 20_000)));
+        let task = Box::leak(Box::new(ExecutorTask::new(executor)));
+        let sha = Sha256Software::new(task);
+
+        for part in message.chunks(part_len) {
+            let buffer: &'static mut [u8] = Box::leak(part.to_vec().into_boxed_slice());
+            assert_eq!(sha.add_data(LeasableBuffer::new(buffer)), Ok(part.len()));
+            while !sha.run_chunk(20_000) {}
+        }
+        let mut digest = [0; 32];
+        sha.finish(&mut digest);
+        digest
+    }
+
+    fn from_hex(hex: &str) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    // The examples of FIPS 180-4, from the NIST cryptographic standards and
+    // guidelines example values.
+
+    #[test]
+    fn test_abc() {
+        let expected = from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(sha256(b"abc", 64), expected);
+        assert_eq!(sha256(b"abc", 1), expected);
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(
+            sha256(b"", 64),
+            from_hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+    }
+
+    #[test]
+    fn test_two_blocks() {
+        // 448 bits: the length no longer fits in the block of the padding.
+        let message = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        let expected = from_hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(sha256(message, 64), expected);
+        assert_eq!(sha256(message, 7), expected);
+    }
+
+    #[test]
+    fn test_896_bits() {
+        let message = b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                        hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu";
+        assert_eq!(
+            sha256(message, 64),
+            from_hex("cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1")
+        );
+    }
+
+    #[test]
+    fn test_million_a() {
+        let message: Vec<u8> = [b'a'; 1000].repeat(1000);
+        assert_eq!(
+            sha256(&message, 4096),
+            from_hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")
+        );
+    }
+}
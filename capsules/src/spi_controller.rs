@@ -65,10 +65,10 @@ impl<'a, S: SpiMasterDevice> Spi<'a, S> {
     }
 
     // Assumes checks for busy/etc. already done
-    // Updates app.index to be index + length of op
-    fn do_next_read_write(&self, app: &mut App) {
+    // Updates app.index to be index + length of op if the op started
+    fn do_next_read_write(&self, app: &mut App) -> Result<(), ErrorCode> {
+        let mut start = app.index;
         let write_len = self.kernel_write.map_or(0, |kwbuf| {
-            let mut start = app.index;
             let tmp_len = app.app_write.map_or(0, |src| {
                 let len = cmp::min(app.len - start, self.kernel_len.get());
                 let end = cmp::min(start + len, src.len());
@@ -79,14 +79,15 @@ impl<'a, S: SpiMasterDevice> Spi<'a, S> {
                 }
                 end - start
             });
-            app.index = start + tmp_len;
             tmp_len
         });
-        let _ = self.spi_master.read_write_bytes(
-            self.kernel_write.take().unwrap(),
-            self.kernel_read.take(),
-            write_len,
-        );
+        self.kernel_write
+            .take()
+            .map_or(Err(ErrorCode::NOMEM), |kwbuf| {
+                self.spi_master
+                    .read_write_bytes(kwbuf, self.kernel_read.take(), write_len)
+            })
+            .map(|()| app.index = start + write_len)
     }
 }
 
@@ -203,8 +204,11 @@ impl<'a, S: SpiMasterDevice> Driver for Spi<'a, S> {
                         app.len = arg1;
                         app.index = 0;
                         self.busy.set(true);
-                        self.do_next_read_write(app);
-                        CommandReturn::success()
+                        let result = self.do_next_read_write(app);
+                        if result.is_err() {
+                            self.busy.set(false);
+                        }
+                        result.into()
                     } else {
                         /* write buffer too small, or zero length write */
                         CommandReturn::failure(ErrorCode::INVAL)
@@ -300,8 +304,13 @@ impl<S: SpiMasterDevice> SpiMasterClient for Spi<'_, S> {
                 app.len = 0;
                 app.index = 0;
                 app.callback.schedule(len, 0, 0);
-            } else {
-                self.do_next_read_write(app);
+            } else if self.do_next_read_write(app).is_err() {
+                // Report how much was transferred before the error.
+                self.busy.set(false);
+                let len = app.index;
+                app.len = 0;
+                app.index = 0;
+                app.callback.schedule(len, 0, 0);
             }
         });
     }
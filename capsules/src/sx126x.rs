@@ -0,0 +1,1241 @@
+//! Driver for the Semtech SX1261 and SX1262 LoRa transceivers.
+//!
+//! Implements `hil::lora::LoraPhy` over SPI. The radio signals every event on
+//! its DIO1 pin, and holds its BUSY pin high while it cannot take a command.
+//!
+//! Listen before talk runs a channel activity detection (CAD) with the
+//! parameters Semtech recommends for each spreading factor, and only
+//! transmits if it finds no preamble. Duty-cycled reception uses the radio's
+//! own RX duty cycle mode, which alternates between receiving and sleeping
+//! without waking the MCU and cuts the receive current by the ratio of the
+//! two periods.
+//!
+//! On boards where the radio shares its antenna with other radios, the
+//! driver acquires its path through the RF switch (see
+//! `capsules::virtual_rf_switch`) for each operation and releases it when the
+//! operation finishes. A reception that another radio needs the antenna for
+//! is stopped, and returns its buffer with `CANCEL`.
+//!
+//! With `set_duty_cycle_limiter()`, each transmission is first checked
+//! against the regional limits of `capsules::lora_region`, and refused if the
+//! sub-band of its channel has used up its duty cycle. The time on air of
+//! each transmission that completes is then counted against the sub-band.
+//! The limiter keeps time with the ticks of `clock`, counted whenever the
+//! radio transmits. If the clock wraps around more than once between two
+//! transmissions, the time is undercounted, which only keeps the sub-bands
+//! closed longer.
+//!
+//! The board must reset the radio before calling `initialize()`. Boards whose
+//! RF switch is not driven by DIO2 must switch it themselves.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sx1262 = static_init!(
+//!     capsules::sx126x::Sx126x<'static, nrf52840::rtc::Rtc<'static>>,
+//!     capsules::sx126x::Sx126x::new(
+//!         sx1262_spi,
+//!         &gpio_port[RADIO_BUSY_PIN],
+//!         &gpio_port[RADIO_DIO1_PIN],
+//!         capsules::sx126x::Variant::Sx1262,
+//!         &mut capsules::sx126x::TX_BUF,
+//!         &mut capsules::sx126x::RX_BUF
+//!     )
+//! );
+//! sx1262_spi.set_client(sx1262);
+//! gpio_port[RADIO_DIO1_PIN].set_client(sx1262);
+//! sx1262.set_duty_cycle_limiter(limiter, &peripherals.nrf52.rtc);
+//! sx1262.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::lora::{Config, LoraPhy, LoraPhyClient};
+use kernel::hil::rf_switch::{RfSwitch, RfSwitchClient};
+use kernel::hil::spi;
+use kernel::hil::time::{Frequency, Ticks, Time};
+use kernel::ErrorCode;
+
+use crate::lora_region::{self, Channel, DutyCycleLimiter};
+use crate::radio_limits::{Radio, RadioLimits};
+
+/// Longest command: `ReadBuffer` of a whole packet.
+pub const BUFFER_LEN: usize = 258;
+
+pub static mut TX_BUF: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+pub static mut RX_BUF: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+
+/// Times BUSY is polled before a command is given up on. The radio leaves
+/// BUSY within a few hundred microseconds of any command.
+const BUSY_POLLS: usize = 100_000;
+
+/// Opcodes.
+const SET_STANDBY: u8 = 0x80;
+const SET_PACKET_TYPE: u8 = 0x8A;
+const SET_RF_FREQUENCY: u8 = 0x86;
+const SET_PA_CONFIG: u8 = 0x95;
+const SET_TX_PARAMS: u8 = 0x8E;
+const SET_BUFFER_BASE_ADDRESS: u8 = 0x8F;
+const SET_MODULATION_PARAMS: u8 = 0x8B;
+const SET_PACKET_PARAMS: u8 = 0x8C;
+const SET_DIO_IRQ_PARAMS: u8 = 0x08;
+const SET_DIO2_AS_RF_SWITCH_CTRL: u8 = 0x9D;
+const SET_CAD_PARAMS: u8 = 0x88;
+const SET_CAD: u8 = 0xC5;
+const SET_TX: u8 = 0x83;
+const SET_RX: u8 = 0x82;
+const SET_RX_DUTY_CYCLE: u8 = 0x94;
+const WRITE_REGISTER: u8 = 0x0D;
+const WRITE_BUFFER: u8 = 0x0E;
+const READ_BUFFER: u8 = 0x1E;
+const GET_IRQ_STATUS: u8 = 0x12;
+const CLEAR_IRQ_STATUS: u8 = 0x02;
+const GET_RX_BUFFER_STATUS: u8 = 0x13;
+const GET_PACKET_STATUS: u8 = 0x14;
+
+const PACKET_TYPE_LORA: u8 = 0x01;
+const STANDBY_RC: u8 = 0x00;
+const REG_LORA_SYNC_WORD: u16 = 0x0740;
+/// The sync word of public LoRaWAN networks.
+const LORA_SYNC_WORD_PUBLIC: u16 = 0x3444;
+
+/// IRQ flags.
+const IRQ_TX_DONE: u16 = 1 << 0;
+const IRQ_RX_DONE: u16 = 1 << 1;
+const IRQ_HEADER_ERR: u16 = 1 << 5;
+const IRQ_CRC_ERR: u16 = 1 << 6;
+const IRQ_CAD_DONE: u16 = 1 << 7;
+const IRQ_CAD_DETECTED: u16 = 1 << 8;
+const IRQ_TIMEOUT: u16 = 1 << 9;
+const IRQ_ALL: u16 = 0x03FF;
+const IRQ_USED: u16 = IRQ_TX_DONE
+    | IRQ_RX_DONE
+    | IRQ_HEADER_ERR
+    | IRQ_CRC_ERR
+    | IRQ_CAD_DONE
+    | IRQ_CAD_DETECTED
+    | IRQ_TIMEOUT;
+
+/// Times for the radio are in steps of 15.625 µs, 64 to the millisecond.
+const STEPS_PER_MS: u32 = 64;
+const MAX_STEPS: u32 = 0x00FF_FFFF;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Variant {
+    /// Up to +15 dBm.
+    Sx1261,
+    /// Up to +22 dBm.
+    Sx1262,
+}
+
+/// One SPI command.
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Standby,
+    PacketType,
+    BufferBase,
+    SyncWord,
+    DioIrq,
+    RfSwitch,
+    Frequency,
+    PaConfig,
+    TxParams,
+    Modulation,
+    CadParams,
+    Cad,
+    PacketParamsTx,
+    WriteBuffer,
+    Tx,
+    PacketParamsRx,
+    Rx,
+    RxDutyCycle,
+    GetIrq,
+    ClearIrq,
+    RxBufferStatus,
+    ReadBuffer,
+    PacketStatus,
+}
+
+/// A series of commands, after which the driver waits for DIO1 or reports
+/// the outcome of an operation.
+#[derive(Clone, Copy, PartialEq)]
+enum Sequence {
+    Init,
+    Transmit,
+    ListenBeforeTalk,
+    TransmitAfterListen,
+    ChannelActivity,
+    Receive,
+    ReceiveDutyCycled,
+    GetIrq,
+    ClearIrq,
+    ReadPacket,
+    Stop,
+}
+
+impl Sequence {
+    fn steps(self) -> &'static [Step] {
+        match self {
+            Sequence::Init => &[
+                Step::Standby,
+                Step::PacketType,
+                Step::BufferBase,
+                Step::SyncWord,
+                Step::DioIrq,
+                Step::RfSwitch,
+            ],
+            Sequence::Transmit => &[
+                Step::Standby,
+                Step::Frequency,
+                Step::PaConfig,
+                Step::TxParams,
+                Step::Modulation,
+                Step::PacketParamsTx,
+                Step::WriteBuffer,
+                Step::Tx,
+            ],
+            Sequence::ListenBeforeTalk => &[
+                Step::Standby,
+                Step::Frequency,
+                Step::PaConfig,
+                Step::TxParams,
+                Step::Modulation,
+                Step::CadParams,
+                Step::Cad,
+            ],
+            Sequence::TransmitAfterListen => &[
+                Step::ClearIrq,
+                Step::PacketParamsTx,
+                Step::WriteBuffer,
+                Step::Tx,
+            ],
+            Sequence::ChannelActivity => &[
+                Step::Standby,
+                Step::Frequency,
+                Step::Modulation,
+                Step::CadParams,
+                Step::Cad,
+            ],
+            Sequence::Receive => &[
+                Step::Standby,
+                Step::Frequency,
+                Step::Modulation,
+                Step::PacketParamsRx,
+                Step::Rx,
+            ],
+            Sequence::ReceiveDutyCycled => &[
+                Step::Standby,
+                Step::Frequency,
+                Step::Modulation,
+                Step::PacketParamsRx,
+                Step::RxDutyCycle,
+            ],
+            Sequence::GetIrq => &[Step::GetIrq],
+            Sequence::ClearIrq => &[Step::ClearIrq],
+            Sequence::ReadPacket => &[
+                Step::ClearIrq,
+                Step::RxBufferStatus,
+                Step::ReadBuffer,
+                Step::PacketStatus,
+            ],
+            Sequence::Stop => &[Step::Standby, Step::ClearIrq],
+        }
+    }
+}
+
+/// The operation the radio is doing for the client.
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    Transmit,
+    ChannelActivity,
+    Receive,
+}
+
+pub struct Sx126x<'a, T: Time> {
+    spi: &'a dyn spi::SpiMasterDevice,
+    busy: &'a dyn gpio::Pin,
+    dio1: &'a dyn gpio::InterruptPin<'a>,
+    variant: Variant,
+    config: Cell<Option<Config>>,
+    client: OptionalCell<&'a dyn LoraPhyClient>,
+    rf_switch: OptionalCell<&'a dyn RfSwitch<'a>>,
+    limits: OptionalCell<&'a RadioLimits>,
+    limiter: OptionalCell<&'a DutyCycleLimiter>,
+    clock: OptionalCell<&'a T>,
+    /// Clock ticks when the time was last counted, and the ticks counted.
+    last_now: Cell<T::Ticks>,
+    clock_ticks: Cell<u64>,
+    /// Time on air of the transmission in progress, and when it started.
+    airtime_us: Cell<u32>,
+    tx_start_ms: Cell<u64>,
+    /// Sequence that starts once the antenna is switched to the radio.
+    antenna_wait: Cell<Option<Sequence>>,
+    /// Another radio asked for the antenna while commands were running.
+    yield_antenna: Cell<bool>,
+    tx_buf: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+    /// The client's buffer of the operation in progress.
+    packet: TakeCell<'static, [u8]>,
+    packet_len: Cell<usize>,
+    operation: Cell<Operation>,
+    sequence: Cell<Sequence>,
+    step: Cell<usize>,
+    /// Whether a sequence of commands is in progress.
+    running: Cell<bool>,
+    /// DIO1 rose while commands were running.
+    irq_pending: Cell<bool>,
+    irq: Cell<u16>,
+    /// Reception timeout, or listen and sleep periods, in radio steps.
+    rx_period: Cell<u32>,
+    sleep_period: Cell<u32>,
+    rx_start: Cell<u8>,
+    rssi_dbm: Cell<i16>,
+    snr_db: Cell<i8>,
+    /// Outcome reported once the IRQ flags are cleared.
+    result: Cell<Result<(), ErrorCode>>,
+}
+
+impl<'a, T: Time> Sx126x<'a, T> {
+    pub fn new(
+        spi: &'a dyn spi::SpiMasterDevice,
+        busy: &'a dyn gpio::Pin,
+        dio1: &'a dyn gpio::InterruptPin<'a>,
+        variant: Variant,
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+    ) -> Sx126x<'a, T> {
+        Sx126x {
+            spi: spi,
+            busy: busy,
+            dio1: dio1,
+            variant: variant,
+            config: Cell::new(None),
+            client: OptionalCell::empty(),
+            rf_switch: OptionalCell::empty(),
+            limits: OptionalCell::empty(),
+            limiter: OptionalCell::empty(),
+            clock: OptionalCell::empty(),
+            last_now: Cell::new(T::Ticks::from(0)),
+            clock_ticks: Cell::new(0),
+            airtime_us: Cell::new(0),
+            tx_start_ms: Cell::new(0),
+            antenna_wait: Cell::new(None),
+            yield_antenna: Cell::new(false),
+            tx_buf: TakeCell::new(tx_buf),
+            rx_buf: TakeCell::new(rx_buf),
+            packet: TakeCell::empty(),
+            packet_len: Cell::new(0),
+            operation: Cell::new(Operation::Idle),
+            sequence: Cell::new(Sequence::Init),
+            step: Cell::new(0),
+            running: Cell::new(false),
+            irq_pending: Cell::new(false),
+            irq: Cell::new(0),
+            rx_period: Cell::new(0),
+            sleep_period: Cell::new(0),
+            rx_start: Cell::new(0),
+            rssi_dbm: Cell::new(0),
+            snr_db: Cell::new(0),
+            result: Cell::new(Ok(())),
+        }
+    }
+
+    /// Set the radio up for LoRa. The radio must have just been reset.
+    pub fn initialize(&self) -> Result<(), ErrorCode> {
+        self.spi.configure(
+            spi::ClockPolarity::IdleLow,
+            spi::ClockPhase::SampleLeading,
+            8_000_000,
+        );
+        self.busy.make_input();
+        self.dio1.make_input();
+        self.dio1.enable_interrupts(gpio::InterruptEdge::RisingEdge);
+        self.start(Sequence::Init)
+    }
+
+    /// Share the antenna with other radios through `rf_switch`.
+    pub fn set_rf_switch(&self, rf_switch: &'a dyn RfSwitch<'a>) {
+        self.rf_switch.set(rf_switch);
+    }
+
+    /// Refuse configurations outside `limits`.
+    pub fn set_limits(&self, limits: &'a RadioLimits) {
+        self.limits.set(limits);
+    }
+
+    /// Refuse transmissions over the duty cycle `limiter` allows, keeping
+    /// time with `clock`.
+    pub fn set_duty_cycle_limiter(&self, limiter: &'a DutyCycleLimiter, clock: &'a T) {
+        self.limiter.set(limiter);
+        self.clock.set(clock);
+        self.last_now.set(clock.now());
+    }
+
+    /// The time in milliseconds counted with the clock.
+    fn now_ms(&self) -> u64 {
+        self.clock.map_or(0, |clock| {
+            let now = clock.now();
+            let elapsed = now.wrapping_sub(self.last_now.get()).into_u32() as u64;
+            self.last_now.set(now);
+            self.clock_ticks.set(self.clock_ticks.get() + elapsed);
+            (self.clock_ticks.get() as u128 * 1000 / T::Frequency::frequency() as u128) as u64
+        })
+    }
+
+    fn start(&self, sequence: Sequence) -> Result<(), ErrorCode> {
+        if self.running.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.sequence.set(sequence);
+        self.step.set(0);
+        self.running.set(true);
+        self.run_step();
+        Ok(())
+    }
+
+    /// Start the step of the sequence the driver is at, or finish the
+    /// sequence.
+    fn run_step(&self) {
+        let step = match self.sequence.get().steps().get(self.step.get()) {
+            Some(step) => *step,
+            None => {
+                self.running.set(false);
+                self.sequence_done();
+                return;
+            }
+        };
+
+        // The radio ignores commands while it is busy.
+        let mut polls = 0;
+        while self.busy.read() {
+            polls += 1;
+            if polls == BUSY_POLLS {
+                self.running.set(false);
+                self.fail(ErrorCode::FAIL);
+                return;
+            }
+        }
+
+        let started = self.tx_buf.take().map(|buf| {
+            let len = self.encode(step, buf);
+            match self.spi.read_write_bytes(buf, self.rx_buf.take(), len) {
+                Ok(()) => true,
+                Err(_) => false,
+            }
+        });
+        if started != Some(true) {
+            self.running.set(false);
+            self.fail(ErrorCode::FAIL);
+        }
+    }
+
+    /// Write the command of `step` into `buf`, returning its length.
+    fn encode(&self, step: Step, buf: &mut [u8]) -> usize {
+        let config = self.config.get();
+        let modulation = config.map_or([0; 4], |config| self.modulation_params(&config));
+        let frequency = config.map_or(0, |config| {
+            ((config.frequency_hz as u64) << 25) / 32_000_000
+        }) as u32;
+        let preamble = config.map_or(8, |config| config.preamble_len);
+        let crc = config.map_or(true, |config| config.crc) as u8;
+        let invert_iq = config.map_or(false, |config| config.invert_iq) as u8;
+        let power = config.map_or(0, |config| config.tx_power_dbm);
+
+        let command: &[u8] = match step {
+            Step::Standby => &[SET_STANDBY, STANDBY_RC],
+            Step::PacketType => &[SET_PACKET_TYPE, PACKET_TYPE_LORA],
+            Step::BufferBase => &[SET_BUFFER_BASE_ADDRESS, 0, 0],
+            Step::SyncWord => {
+                let [reg_hi, reg_lo] = REG_LORA_SYNC_WORD.to_be_bytes();
+                let [sync_hi, sync_lo] = LORA_SYNC_WORD_PUBLIC.to_be_bytes();
+                buf[..5].copy_from_slice(&[WRITE_REGISTER, reg_hi, reg_lo, sync_hi, sync_lo]);
+                return 5;
+            }
+            Step::DioIrq => {
+                let [used_hi, used_lo] = IRQ_USED.to_be_bytes();
+                buf[..9].copy_from_slice(&[
+                    SET_DIO_IRQ_PARAMS,
+                    used_hi,
+                    used_lo,
+                    used_hi,
+                    used_lo,
+                    0,
+                    0,
+                    0,
+                    0,
+                ]);
+                return 9;
+            }
+            Step::RfSwitch => &[SET_DIO2_AS_RF_SWITCH_CTRL, 1],
+            Step::Frequency => {
+                buf[0] = SET_RF_FREQUENCY;
+                buf[1..5].copy_from_slice(&frequency.to_be_bytes());
+                return 5;
+            }
+            Step::PaConfig => match self.variant {
+                Variant::Sx1261 => &[SET_PA_CONFIG, 0x06, 0x00, 0x01, 0x01],
+                Variant::Sx1262 => &[SET_PA_CONFIG, 0x04, 0x07, 0x00, 0x01],
+            },
+            Step::TxParams => {
+                // Ramp up over 200 µs.
+                buf[..3].copy_from_slice(&[SET_TX_PARAMS, power as u8, 0x04]);
+                return 3;
+            }
+            Step::Modulation => {
+                buf[0] = SET_MODULATION_PARAMS;
+                buf[1..5].copy_from_slice(&modulation);
+                return 5;
+            }
+            Step::CadParams => {
+                // The number of symbols and detection peak Semtech
+                // recommends for each spreading factor.
+                let sf = modulation[0];
+                let (symbols, peak) = match sf {
+                    7 => (0x01, 22),
+                    8 => (0x01, 22),
+                    9 => (0x02, 23),
+                    10 => (0x02, 24),
+                    11 => (0x02, 25),
+                    _ => (0x02, 28),
+                };
+                buf[..8].copy_from_slice(&[SET_CAD_PARAMS, symbols, peak, 10, 0x00, 0, 0, 0]);
+                return 8;
+            }
+            Step::Cad => &[SET_CAD],
+            Step::PacketParamsTx | Step::PacketParamsRx => {
+                let len = if step == Step::PacketParamsTx {
+                    self.packet_len.get() as u8
+                } else {
+                    0xFF
+                };
+                let [preamble_hi, preamble_lo] = preamble.to_be_bytes();
+                buf[..7].copy_from_slice(&[
+                    SET_PACKET_PARAMS,
+                    preamble_hi,
+                    preamble_lo,
+                    0x00,
+                    len,
+                    crc,
+                    invert_iq,
+                ]);
+                return 7;
+            }
+            Step::WriteBuffer => {
+                let len = self.packet_len.get();
+                buf[0] = WRITE_BUFFER;
+                buf[1] = 0;
+                self.packet.map(|packet| {
+                    buf[2..2 + len].copy_from_slice(&packet[..len]);
+                });
+                return 2 + len;
+            }
+            Step::Tx => &[SET_TX, 0, 0, 0],
+            Step::Rx => {
+                buf[0] = SET_RX;
+                buf[1..4].copy_from_slice(&self.rx_period.get().to_be_bytes()[1..]);
+                return 4;
+            }
+            Step::RxDutyCycle => {
+                buf[0] = SET_RX_DUTY_CYCLE;
+                buf[1..4].copy_from_slice(&self.rx_period.get().to_be_bytes()[1..]);
+                buf[4..7].copy_from_slice(&self.sleep_period.get().to_be_bytes()[1..]);
+                return 7;
+            }
+            Step::GetIrq => &[GET_IRQ_STATUS, 0, 0, 0],
+            Step::ClearIrq => {
+                let [all_hi, all_lo] = IRQ_ALL.to_be_bytes();
+                buf[..3].copy_from_slice(&[CLEAR_IRQ_STATUS, all_hi, all_lo]);
+                return 3;
+            }
+            Step::RxBufferStatus => &[GET_RX_BUFFER_STATUS, 0, 0, 0],
+            Step::ReadBuffer => {
+                let len = self.packet_len.get();
+                buf[0] = READ_BUFFER;
+                buf[1] = self.rx_start.get();
+                for byte in buf[2..3 + len].iter_mut() {
+                    *byte = 0;
+                }
+                return 3 + len;
+            }
+            Step::PacketStatus => &[GET_PACKET_STATUS, 0, 0, 0, 0],
+        };
+        buf[..command.len()].copy_from_slice(command);
+        command.len()
+    }
+
+    fn modulation_params(&self, config: &Config) -> [u8; 4] {
+        let bandwidth = match config.bandwidth_hz {
+            62_500 => 0x03,
+            125_000 => 0x04,
+            250_000 => 0x05,
+            _ => 0x06,
+        };
+        // Low data rate optimization is needed once a symbol takes 16 ms.
+        let symbol_us = (1u64 << config.spreading_factor) * 1_000_000 / config.bandwidth_hz as u64;
+        let low_data_rate = (symbol_us >= 16_000) as u8;
+        [
+            config.spreading_factor,
+            bandwidth,
+            config.coding_rate,
+            low_data_rate,
+        ]
+    }
+
+    /// Record the response to `step`.
+    fn step_done(&self, step: Step, response: &[u8]) {
+        match step {
+            Step::GetIrq => self.irq.set(u16::from_be_bytes([response[2], response[3]])),
+            Step::RxBufferStatus => {
+                let capacity = self.packet.map_or(0, |packet| packet.len());
+                self.packet_len
+                    .set(cmp::min(response[2] as usize, capacity));
+                self.rx_start.set(response[3]);
+            }
+            Step::ReadBuffer => {
+                let len = self.packet_len.get();
+                self.packet.map(|packet| {
+                    packet[..len].copy_from_slice(&response[3..3 + len]);
+                });
+            }
+            Step::PacketStatus => {
+                self.rssi_dbm.set(-(response[2] as i16) / 2);
+                self.snr_db.set(response[3] as i8 / 4);
+            }
+            _ => {}
+        }
+    }
+
+    fn sequence_done(&self) {
+        match self.sequence.get() {
+            Sequence::Init => {}
+            Sequence::GetIrq => self.handle_irq(),
+            Sequence::ClearIrq | Sequence::ReadPacket | Sequence::Stop => self.finish(),
+            // Wait for DIO1.
+            _ => {
+                if self.irq_pending.get() {
+                    self.irq_pending.set(false);
+                    let _ = self.start(Sequence::GetIrq);
+                } else if self.yield_antenna.take() {
+                    self.yield_to_other_radio();
+                }
+            }
+        }
+    }
+
+    /// Decide what to do about the IRQ flags the radio raised.
+    fn handle_irq(&self) {
+        let irq = self.irq.get();
+        let next = match self.operation.get() {
+            Operation::Idle => Sequence::ClearIrq,
+            Operation::Transmit if irq & IRQ_CAD_DONE != 0 => {
+                if irq & IRQ_CAD_DETECTED != 0 {
+                    self.result.set(Err(ErrorCode::BUSY));
+                    Sequence::ClearIrq
+                } else {
+                    Sequence::TransmitAfterListen
+                }
+            }
+            Operation::Transmit => {
+                self.result.set(if irq & IRQ_TX_DONE != 0 {
+                    self.record_airtime();
+                    Ok(())
+                } else {
+                    Err(ErrorCode::FAIL)
+                });
+                Sequence::ClearIrq
+            }
+            Operation::ChannelActivity => {
+                self.result.set(if irq & IRQ_CAD_DETECTED != 0 {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    Ok(())
+                });
+                Sequence::ClearIrq
+            }
+            Operation::Receive => {
+                if irq & (IRQ_HEADER_ERR | IRQ_CRC_ERR) != 0 {
+                    self.packet_len.set(0);
+                    self.result.set(Err(ErrorCode::FAIL));
+                    Sequence::ClearIrq
+                } else if irq & IRQ_RX_DONE != 0 {
+                    self.result.set(Ok(()));
+                    Sequence::ReadPacket
+                } else {
+                    self.packet_len.set(0);
+                    self.result.set(Err(ErrorCode::NOACK));
+                    Sequence::ClearIrq
+                }
+            }
+        };
+        let _ = self.start(next);
+    }
+
+    /// Check that the duty cycle of the channel allows transmitting `len`
+    /// bytes now.
+    fn check_duty_cycle(&self, config: &Config, len: usize) -> Result<(), ErrorCode> {
+        let airtime_us = lora_region::time_on_air_us(
+            config.spreading_factor,
+            config.bandwidth_hz,
+            config.coding_rate,
+            config.preamble_len,
+            len,
+            true,
+            config.crc,
+        );
+        self.limiter.map_or(Ok(()), |limiter| {
+            let now_ms = self.now_ms();
+            limiter.check(config.frequency_hz, config.bandwidth_hz, airtime_us, now_ms)?;
+            self.airtime_us.set(airtime_us);
+            self.tx_start_ms.set(now_ms);
+            Ok(())
+        })
+    }
+
+    /// Count the transmission that just completed against its sub-band.
+    fn record_airtime(&self) {
+        if let (Some(limiter), Some(config)) = (self.limiter.extract(), self.config.get()) {
+            limiter.record(
+                config.frequency_hz,
+                config.bandwidth_hz,
+                self.airtime_us.get(),
+                self.tx_start_ms.get(),
+            );
+        }
+    }
+
+    /// Report the outcome of the operation to the client.
+    fn finish(&self) {
+        let operation = self.operation.get();
+        self.operation.set(Operation::Idle);
+        self.yield_antenna.set(false);
+        self.rf_switch.map(|rf_switch| rf_switch.release());
+        let result = self.result.get();
+        match operation {
+            Operation::Idle => {}
+            Operation::Transmit => {
+                if let Some(packet) = self.packet.take() {
+                    self.client
+                        .map(move |client| client.transmit_done(packet, result));
+                }
+            }
+            Operation::ChannelActivity => {
+                self.client
+                    .map(|client| client.channel_activity_done(result.is_err()));
+            }
+            Operation::Receive => {
+                if let Some(packet) = self.packet.take() {
+                    let len = self.packet_len.get();
+                    let rssi_dbm = self.rssi_dbm.get();
+                    let snr_db = self.snr_db.get();
+                    self.client.map(move |client| {
+                        client.receive_done(packet, len, rssi_dbm, snr_db, result)
+                    });
+                }
+            }
+        }
+    }
+
+    /// Give up on the operation after the radio stopped responding.
+    fn fail(&self, error: ErrorCode) {
+        self.result.set(Err(error));
+        self.packet_len.set(0);
+        self.finish();
+    }
+
+    /// Whether a new operation can start.
+    fn ready(&self) -> Result<(), ErrorCode> {
+        if self.config.get().is_none() {
+            Err(ErrorCode::OFF)
+        } else if self.running.get() || self.operation.get() != Operation::Idle {
+            Err(ErrorCode::BUSY)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn begin(&self, operation: Operation, sequence: Sequence, buf: &'static mut [u8]) {
+        self.packet.replace(buf);
+        self.operation.set(operation);
+        self.rssi_dbm.set(0);
+        self.snr_db.set(0);
+        self.connect(sequence);
+    }
+
+    /// Start `sequence` once the antenna is switched to the radio.
+    fn connect(&self, sequence: Sequence) {
+        let waiting = self.rf_switch.map_or(false, |rf_switch| {
+            rf_switch.acquire() == Err(ErrorCode::BUSY)
+        });
+        if waiting {
+            self.antenna_wait.set(Some(sequence));
+        } else {
+            let _ = self.start(sequence);
+        }
+    }
+
+    /// Stop a reception so another radio can use the antenna. Transmissions
+    /// and channel activity checks are short, and release it when they end.
+    fn yield_to_other_radio(&self) {
+        if self.operation.get() == Operation::Receive && self.antenna_wait.get().is_none() {
+            let _ = self.stop();
+        }
+    }
+}
+
+impl<'a, T: Time> LoraPhy<'a> for Sx126x<'a, T> {
+    fn set_client(&self, client: &'a dyn LoraPhyClient) {
+        self.client.set(client);
+    }
+
+    fn set_config(&self, config: Config) -> Result<(), ErrorCode> {
+        let (min_power, max_power) = match self.variant {
+            Variant::Sx1261 => (-17, 15),
+            Variant::Sx1262 => (-9, 22),
+        };
+        if !(7..=12).contains(&config.spreading_factor)
+            || !(1..=4).contains(&config.coding_rate)
+            || ![62_500, 125_000, 250_000, 500_000].contains(&config.bandwidth_hz)
+            || config.tx_power_dbm < min_power
+            || config.tx_power_dbm > max_power
+        {
+            return Err(ErrorCode::INVAL);
+        }
+        self.limits.map_or(Ok(()), |limits| {
+            limits.check_tx_power(Radio::Lora, config.tx_power_dbm)?;
+            limits.check_lora_channel(Channel {
+                frequency_hz: config.frequency_hz,
+                bandwidth_hz: config.bandwidth_hz,
+            })
+        })?;
+        self.config.set(Some(config));
+        Ok(())
+    }
+
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        listen_before_talk: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if len > buf.len() || len > 255 {
+            return Err((ErrorCode::SIZE, buf));
+        }
+        if let Err(e) = self.ready() {
+            return Err((e, buf));
+        }
+        if let Some(config) = self.config.get() {
+            if let Err(e) = self.check_duty_cycle(&config, len) {
+                return Err((e, buf));
+            }
+        }
+        self.packet_len.set(len);
+        let sequence = if listen_before_talk {
+            Sequence::ListenBeforeTalk
+        } else {
+            Sequence::Transmit
+        };
+        self.begin(Operation::Transmit, sequence, buf);
+        Ok(())
+    }
+
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        timeout_ms: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.ready() {
+            return Err((e, buf));
+        }
+        self.rx_period
+            .set(cmp::min(timeout_ms.saturating_mul(STEPS_PER_MS), MAX_STEPS));
+        self.begin(Operation::Receive, Sequence::Receive, buf);
+        Ok(())
+    }
+
+    fn receive_duty_cycled(
+        &self,
+        buf: &'static mut [u8],
+        listen_ms: u32,
+        sleep_ms: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let listen = listen_ms.saturating_mul(STEPS_PER_MS);
+        let sleep = sleep_ms.saturating_mul(STEPS_PER_MS);
+        if listen == 0 || listen > MAX_STEPS || sleep > MAX_STEPS {
+            return Err((ErrorCode::INVAL, buf));
+        }
+        if let Err(e) = self.ready() {
+            return Err((e, buf));
+        }
+        self.rx_period.set(listen);
+        self.sleep_period.set(sleep);
+        self.begin(Operation::Receive, Sequence::ReceiveDutyCycled, buf);
+        Ok(())
+    }
+
+    fn channel_activity(&self) -> Result<(), ErrorCode> {
+        self.ready()?;
+        self.operation.set(Operation::ChannelActivity);
+        self.connect(Sequence::ChannelActivity);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), ErrorCode> {
+        if self.operation.get() == Operation::Idle {
+            return Err(ErrorCode::ALREADY);
+        }
+        if self.running.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.antenna_wait.set(None);
+        self.result.set(Err(ErrorCode::CANCEL));
+        self.packet_len.set(0);
+        self.start(Sequence::Stop)
+    }
+}
+
+impl<T: Time> spi::SpiMasterClient for Sx126x<'_, T> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        _len: usize,
+    ) {
+        if let Some(step) = self.sequence.get().steps().get(self.step.get()) {
+            if let Some(response) = read_buffer.as_ref() {
+                self.step_done(*step, response);
+            }
+        }
+        self.tx_buf.replace(write_buffer);
+        if let Some(buf) = read_buffer {
+            self.rx_buf.replace(buf);
+        }
+        self.step.set(self.step.get() + 1);
+        self.run_step();
+    }
+}
+
+impl<T: Time> gpio::Client for Sx126x<'_, T> {
+    fn fired(&self) {
+        if self.start(Sequence::GetIrq).is_err() {
+            self.irq_pending.set(true);
+        }
+    }
+}
+
+impl<T: Time> RfSwitchClient for Sx126x<'_, T> {
+    fn granted(&self) {
+        match self.antenna_wait.take() {
+            Some(sequence) => {
+                let _ = self.start(sequence);
+            }
+            None => {
+                self.rf_switch.map(|rf_switch| rf_switch.release());
+            }
+        }
+    }
+
+    fn release_requested(&self) {
+        if self.running.get() {
+            self.yield_antenna.set(true);
+        } else {
+            self.yield_to_other_radio();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::lora_region::Region;
+    use core::cell::RefCell;
+    use kernel::hil::gpio::Client;
+    use kernel::hil::spi::SpiMasterClient;
+    use kernel::hil::time::{Freq1KHz, Ticks32};
+    use std::boxed::Box;
+
+    /// Holds each SPI transfer until `complete()`, and answers
+    /// `GetIrqStatus` with `irq`.
+    struct MockSpi {
+        transfer: RefCell<Option<(&'static mut [u8], Option<&'static mut [u8]>, usize)>>,
+        irq: Cell<u16>,
+    }
+
+    impl MockSpi {
+        /// Complete transfers until the driver waits for the radio.
+        fn complete<T: Time>(&self, radio: &Sx126x<'_, T>) {
+            loop {
+                let transfer = self.transfer.borrow_mut().take();
+                match transfer {
+                    Some((write, mut read, len)) => {
+                        if let Some(read) = read.as_mut() {
+                            if write[0] == GET_IRQ_STATUS {
+                                read[2..4].copy_from_slice(&self.irq.get().to_be_bytes());
+                            }
+                        }
+                        radio.read_write_done(write, read, len);
+                    }
+                    None => return,
+                }
+            }
+        }
+    }
+
+    impl spi::SpiMasterDevice for MockSpi {
+        fn configure(&self, _cpol: spi::ClockPolarity, _cpal: spi::ClockPhase, _rate: u32) {}
+
+        fn read_write_bytes(
+            &self,
+            write_buffer: &'static mut [u8],
+            read_buffer: Option<&'static mut [u8]>,
+            len: usize,
+        ) -> Result<(), ErrorCode> {
+            *self.transfer.borrow_mut() = Some((write_buffer, read_buffer, len));
+            Ok(())
+        }
+
+        fn set_polarity(&self, _cpol: spi::ClockPolarity) {}
+
+        fn set_phase(&self, _cpal: spi::ClockPhase) {}
+
+        fn set_rate(&self, _rate: u32) {}
+
+        fn get_polarity(&self) -> spi::ClockPolarity {
+            spi::ClockPolarity::IdleLow
+        }
+
+        fn get_phase(&self) -> spi::ClockPhase {
+            spi::ClockPhase::SampleLeading
+        }
+
+        fn get_rate(&self) -> u32 {
+            8_000_000
+        }
+    }
+
+    /// The BUSY and DIO1 pins, always low.
+    struct MockPin;
+
+    impl gpio::Input for MockPin {
+        fn read(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Output for MockPin {
+        fn set(&self) {}
+
+        fn clear(&self) {}
+
+        fn toggle(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Configure for MockPin {
+        fn configuration(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn make_output(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn disable_output(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn make_input(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn disable_input(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+
+        fn deactivate_to_low_power(&self) {}
+
+        fn set_floating_state(&self, _state: gpio::FloatingState) {}
+
+        fn floating_state(&self) -> gpio::FloatingState {
+            gpio::FloatingState::PullNone
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for MockPin {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+
+        fn enable_interrupts(&self, _mode: gpio::InterruptEdge) {}
+
+        fn disable_interrupts(&self) {}
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Pin for MockPin {}
+    impl<'a> gpio::InterruptPin<'a> for MockPin {}
+
+    struct MockClock {
+        now: Cell<u32>,
+    }
+
+    impl Time for MockClock {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(self.now.get())
+        }
+    }
+
+    struct MockClient {
+        buf: TakeCell<'static, [u8]>,
+        result: Cell<Option<Result<(), ErrorCode>>>,
+    }
+
+    impl LoraPhyClient for MockClient {
+        fn transmit_done(&self, buf: &'static mut [u8], result: Result<(), ErrorCode>) {
+            self.buf.replace(buf);
+            self.result.set(Some(result));
+        }
+
+        fn receive_done(
+            &self,
+            _buf: &'static mut [u8],
+            _len: usize,
+            _rssi_dbm: i16,
+            _snr_db: i8,
+            _result: Result<(), ErrorCode>,
+        ) {
+        }
+
+        fn channel_activity_done(&self, _detected: bool) {}
+    }
+
+    const CONFIG: Config = Config {
+        frequency_hz: 868_100_000,
+        spreading_factor: 12,
+        bandwidth_hz: 125_000,
+        coding_rate: 1,
+        preamble_len: 8,
+        crc: true,
+        invert_iq: false,
+        tx_power_dbm: 14,
+    };
+
+    type Setup = (
+        &'static Sx126x<'static, MockClock>,
+        &'static MockSpi,
+        &'static MockClock,
+        &'static DutyCycleLimiter,
+        &'static MockClient,
+    );
+
+    /// A configured radio limited to the EU868 duty cycles.
+    fn setup() -> Setup {
+        let spi: &'static MockSpi = Box::leak(Box::new(MockSpi {
+            transfer: RefCell::new(None),
+            irq: Cell::new(0),
+        }));
+        let pin: &'static MockPin = Box::leak(Box::new(MockPin));
+        let clock: &'static MockClock = Box::leak(Box::new(MockClock { now: Cell::new(0) }));
+        let limiter: &'static DutyCycleLimiter =
+            Box::leak(Box::new(DutyCycleLimiter::new(Region::Eu868)));
+        let client: &'static MockClient = Box::leak(Box::new(MockClient {
+            buf: TakeCell::new(Box::leak(Box::new([0; 64]))),
+            result: Cell::new(None),
+        }));
+        let radio: &'static Sx126x<'static, MockClock> = Box::leak(Box::new(Sx126x::new(
+            spi,
+            pin,
+            pin,
+            Variant::Sx1262,
+            Box::leak(Box::new([0; BUFFER_LEN])),
+            Box::leak(Box::new([0; BUFFER_LEN])),
+        )));
+        radio.set_client(client);
+        radio.set_duty_cycle_limiter(limiter, clock);
+        radio.initialize().unwrap();
+        spi.complete(radio);
+        radio.set_config(CONFIG).unwrap();
+        (radio, spi, clock, limiter, client)
+    }
+
+    /// Transmit 20 bytes from the client's buffer, and complete the
+    /// transmission if it starts.
+    fn transmit(
+        radio: &Sx126x<'static, MockClock>,
+        spi: &MockSpi,
+        client: &MockClient,
+    ) -> Result<(), ErrorCode> {
+        let buf = client.buf.take().unwrap();
+        radio.transmit(buf, 20, false).map_err(|(e, buf)| {
+            client.buf.replace(buf);
+            e
+        })?;
+        spi.complete(radio);
+        spi.irq.set(IRQ_TX_DONE);
+        radio.fired();
+        spi.complete(radio);
+        client.result.take().unwrap()
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        let (radio, spi, clock, limiter, client) = setup();
+
+        assert_eq!(transmit(radio, spi, client), Ok(()));
+        let wait_ms = limiter
+            .wait_ms(CONFIG.frequency_hz, CONFIG.bandwidth_hz, 0)
+            .unwrap();
+        // An SF12 packet takes over a second, and the sub-band has a 1% duty
+        // cycle.
+        assert!(wait_ms > 100_000);
+
+        // Over budget: refused before the radio is touched.
+        clock.now.set(wait_ms as u32 - 1);
+        assert_eq!(transmit(radio, spi, client), Err(ErrorCode::BUSY));
+        assert!(spi.transfer.borrow().is_none());
+
+        clock.now.set(wait_ms as u32);
+        assert_eq!(transmit(radio, spi, client), Ok(()));
+        assert_eq!(transmit(radio, spi, client), Err(ErrorCode::BUSY));
+    }
+
+    #[test]
+    fn test_failed_transmit_not_counted() {
+        let (radio, spi, _, limiter, client) = setup();
+
+        // A transmission that times out does not use up the duty cycle.
+        let buf = client.buf.take().unwrap();
+        assert!(radio.transmit(buf, 20, false).is_ok());
+        spi.complete(radio);
+        spi.irq.set(IRQ_TIMEOUT);
+        radio.fired();
+        spi.complete(radio);
+        assert_eq!(client.result.take(), Some(Err(ErrorCode::FAIL)));
+        assert_eq!(
+            limiter.wait_ms(CONFIG.frequency_hz, CONFIG.bandwidth_hz, 0),
+            Some(0)
+        );
+
+        // A channel outside the region is refused.
+        radio
+            .set_config(Config {
+                frequency_hz: 915_000_000,
+                ..CONFIG
+            })
+            .unwrap();
+        assert_eq!(transmit(radio, spi, client), Err(ErrorCode::INVAL));
+    }
+}
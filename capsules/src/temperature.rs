@@ -7,7 +7,9 @@
 //!
 //! The `subscribe` system call supports the single `subscribe_number` zero,
 //! which is used to provide a callback that will return back the result of
-//! a temperature sensor reading.
+//! a temperature sensor reading. The callback's second argument is `0` if the
+//! reading succeeded, and otherwise the error code of the failure, in which
+//! case the first argument is `0`.
 //! The `subscribe`call return codes indicate the following:
 //!
 //! * `Ok(())`: the callback been successfully been configured.
@@ -124,20 +126,31 @@ impl<'a> TemperatureSensor<'a> {
     }
 }
 
-impl hil::sensors::TemperatureClient for TemperatureSensor<'_> {
-    fn callback(&self, temp_val: usize) {
+impl TemperatureSensor<'_> {
+    /// Give the result of the reading to every process waiting for it.
+    fn deliver(&self, temp_val: usize, status: usize) {
         for cntr in self.apps.iter() {
             cntr.enter(|app| {
                 if app.subscribed {
                     self.busy.set(false);
                     app.subscribed = false;
-                    app.callback.schedule(temp_val, 0, 0);
+                    app.callback.schedule(temp_val, status, 0);
                 }
             });
         }
     }
 }
 
+impl hil::sensors::TemperatureClient for TemperatureSensor<'_> {
+    fn callback(&self, temp_val: usize) {
+        self.deliver(temp_val, 0);
+    }
+
+    fn error(&self, error: ErrorCode) {
+        self.deliver(0, kernel::into_statuscode(Err(error)));
+    }
+}
+
 impl Driver for TemperatureSensor<'_> {
     fn subscribe(
         &self,
@@ -0,0 +1,814 @@
+//! Generates time-based one-time passwords (TOTP, RFC 6238) in the kernel,
+//! from secrets that applications never read back.
+//!
+//! An application provisions a secret for a slot once. The capsule encrypts
+//! it and stores it in a key-value store, and from then on the application
+//! can only ask for the current code of the slot. The secret is never copied
+//! back into process memory, so a compromised application cannot leak it,
+//! and the application that provisioned it may clear its copy.
+//!
+//! Secrets are kept per application, keyed by its `ShortID` and the slot
+//! number. Each stored secret is encrypted with a keystream of
+//! HMAC-SHA-256, keyed with a device key the board provides, over the
+//! `ShortID`, the slot and the time the secret was provisioned. The flash
+//! backing the store therefore never holds a secret in the clear.
+//!
+//! Codes are the HMAC-SHA-256 of the number of 30 second periods since 1970,
+//! truncated as RFC 4226 describes. RFC 6238 allows SHA-256, but the
+//! verifier must be configured to use it. The time comes from the
+//! `network_time` capsule, so codes are only available once it has
+//! synchronized.
+//!
+//! Whoever sets the time can ask for the code of any period, and so replay
+//! it later. `network_time` only takes the time from beacons with a MIC, and
+//! from processes granted its command `4` by the `Permissions` TLV, so no
+//! other process can move the clock. Boards should grant that command to no
+//! process they don't trust with the secrets.
+//!
+//! ```text
+//! +-----------------------+
+//! |       userspace       |
+//! +-----------------------+
+//!        kernel::Driver
+//! +-----------------------+
+//! |      Totp (this)      |
+//! +-----------------------+
+//!   hil::digest  hil::kv_system  network_time
+//! ```
+//!
+//! The capsule must be the only client of its KV system, so it needs a store
+//! of its own rather than the one behind `kv_driver`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let totp_key = static_init!([u8; 8], [0; 8]);
+//! let totp_digest = static_init!([u8; 32], [0; 32]);
+//! let totp = static_init!(
+//!     capsules::totp::Totp<
+//!         'static,
+//!         VirtualMuxHmac<'static, lowrisc::hmac::Hmac<'static>, [u8; 32]>,
+//!         TicKVStore<'static, FlashUser<'static, F>>,
+//!         TicKVKeyType,
+//!         VirtualMuxAlarm<'static, A>,
+//!     >,
+//!     capsules::totp::Totp::new(
+//!         virtual_hmac_user,
+//!         tickv,
+//!         network_time,
+//!         board_kernel.create_grant(&grant_cap),
+//!         &DEVICE_KEY,
+//!         totp_key,
+//!         &mut capsules::totp::BUFFER,
+//!         &mut capsules::totp::DATA,
+//!         totp_digest,
+//!     )
+//! );
+//! digest::Digest::set_client(virtual_hmac_user, totp);
+//! tickv.set_client(totp);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Initial
+//!
+//! ### Allow
+//!
+//! - read-only `0`: The secret to provision, 1 to 32 bytes.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Operation done. The first argument is the status. For a code, the
+//!   second argument is the code and the third the seconds until it changes.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Provision the secret for slot `arg1`, replacing any secret it had,
+//!   for codes of `arg2` digits, 6 to 8.
+//! - `2`: Request the current code of slot `arg1`.
+//! - `3`: Delete the secret of slot `arg1`.
+//!
+//! One operation runs at a time; others fail with `BUSY`. Provisioning a
+//! secret or requesting a code fails with `OFF` before the time is known,
+//! and requesting the code of, or deleting, a slot without a secret fails
+//! with `NOSUPPORT`.
+
+use core::cell::Cell;
+use core::convert::TryInto;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::common::math;
+use kernel::hil::digest::{self, HMACSha256};
+use kernel::hil::kv_system::{self, KVSystem, KeyType};
+use kernel::hil::time::Alarm;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, Upcall};
+
+use crate::network_time::NetworkTime;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Totp as usize;
+
+pub static mut BUFFER: [u8; 64] = [0; 64];
+pub static mut DATA: [u8; 16] = [0; 16];
+
+/// Length of a code period, in seconds.
+const PERIOD_S: u64 = 30;
+
+/// Longest secret, which fills a HMAC-SHA-256 key. HMAC pads shorter keys
+/// with zeros, so storing them padded doesn't change the codes.
+const SECRET_LEN: usize = 32;
+/// Offset of the provisioning time in a record.
+const NONCE: usize = 8;
+/// Offset of the encrypted secret in a record.
+const SECRET: usize = 16;
+/// Length of a record.
+const RECORD_LEN: usize = SECRET + SECRET_LEN;
+/// Length of the data the keystream is the HMAC of.
+const KEYSTREAM_DATA_LEN: usize = 13;
+
+/// Hash the slot of an application into a key of the store.
+fn hash(short_id: u32, slot: u8) -> u64 {
+    let hash = math::fnv1a_64(math::FNV1A_64_OFFSET, &short_id.to_le_bytes());
+    math::fnv1a_64(hash, &[slot])
+}
+
+/// Return the digits and the provisioning time of the record, if it belongs
+/// to the slot of `short_id`.
+fn read_record(buffer: &[u8], short_id: u32, slot: u8) -> Option<(u8, u64)> {
+    let mut id = [0; 4];
+    id.copy_from_slice(&buffer[0..4]);
+    let mut nonce = [0; 8];
+    nonce.copy_from_slice(&buffer[NONCE..SECRET]);
+    if u32::from_le_bytes(id) == short_id && buffer[4] == slot {
+        Some((buffer[5], u64::from_le_bytes(nonce)))
+    } else {
+        None
+    }
+}
+
+/// The code of `digits` digits for a HMAC, truncated as RFC 4226 describes.
+fn truncate(hmac: &[u8; 32], digits: u8) -> u32 {
+    let offset = (hmac[31] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        hmac[offset] & 0x7f,
+        hmac[offset + 1],
+        hmac[offset + 2],
+        hmac[offset + 3],
+    ]);
+    binary % 10u32.pow(digits as u32)
+}
+
+/// The RFC 6238 counter of the period `now_s` falls in, and the seconds
+/// until the period ends.
+fn period(now_s: u64) -> (u64, u32) {
+    (now_s / PERIOD_S, (PERIOD_S - now_s % PERIOD_S) as u32)
+}
+
+fn error(result: Result<(), ErrorCode>) -> ErrorCode {
+    result.err().unwrap_or(ErrorCode::FAIL)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    None,
+    Provision,
+    Code,
+    Delete,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    /// Reading the record of the slot.
+    ReadRecord,
+    /// Computing the keystream the secret is encrypted with.
+    Keystream,
+    /// Computing the HMAC of the current period.
+    Code,
+    /// Invalidating the old record of the slot.
+    RemoveRecord,
+    /// Appending the new record of the slot.
+    StoreRecord,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    secret: ReadOnlyAppSlice,
+}
+
+pub struct Totp<
+    'a,
+    H: digest::Digest<'a, [u8; 32]> + HMACSha256,
+    S: KVSystem<'a, K = T>,
+    T: 'static + KeyType,
+    A: Alarm<'a>,
+> {
+    hmac: &'a H,
+    kv: &'a S,
+    time: &'a NetworkTime<'a, A>,
+    apps: Grant<App>,
+    /// Key of the keystream the secrets are encrypted with.
+    device_key: &'static [u8; 32],
+    key: TakeCell<'static, T>,
+    buffer: TakeCell<'static, [u8]>,
+    data: TakeCell<'static, [u8]>,
+    digest: TakeCell<'static, [u8; 32]>,
+    current_app: OptionalCell<ProcessId>,
+    operation: Cell<Operation>,
+    step: Cell<Step>,
+    short_id: Cell<u32>,
+    slot: Cell<u8>,
+    /// Digits of the code, from the command or the record.
+    digits: Cell<u8>,
+    /// Whether the slot has a record in the store.
+    record_stored: Cell<bool>,
+    /// Seconds until the code being computed changes.
+    remaining_s: Cell<u32>,
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]> + HMACSha256,
+        S: KVSystem<'a, K = T>,
+        T: 'static + KeyType,
+        A: Alarm<'a>,
+    > Totp<'a, H, S, T, A>
+{
+    pub fn new(
+        hmac: &'a H,
+        kv: &'a S,
+        time: &'a NetworkTime<'a, A>,
+        grant: Grant<App>,
+        device_key: &'static [u8; 32],
+        key: &'static mut T,
+        buffer: &'static mut [u8],
+        data: &'static mut [u8],
+        digest: &'static mut [u8; 32],
+    ) -> Totp<'a, H, S, T, A> {
+        Totp {
+            hmac,
+            kv,
+            time,
+            apps: grant,
+            device_key,
+            key: TakeCell::new(key),
+            buffer: TakeCell::new(buffer),
+            data: TakeCell::new(data),
+            digest: TakeCell::new(digest),
+            current_app: OptionalCell::empty(),
+            operation: Cell::new(Operation::None),
+            step: Cell::new(Step::ReadRecord),
+            short_id: Cell::new(0),
+            slot: Cell::new(0),
+            digits: Cell::new(0),
+            record_stored: Cell::new(false),
+            remaining_s: Cell::new(0),
+        }
+    }
+
+    fn start(
+        &self,
+        appid: ProcessId,
+        operation: Operation,
+        slot: usize,
+        digits: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.current_app.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let short_id = appid.short_id().ok_or(ErrorCode::FAIL)?.id();
+        let slot: u8 = slot.try_into().map_err(|_| ErrorCode::INVAL)?;
+        if operation != Operation::Delete && self.time.now_us().is_none() {
+            return Err(ErrorCode::OFF);
+        }
+        if operation == Operation::Provision {
+            if !(6..=8).contains(&digits) {
+                return Err(ErrorCode::INVAL);
+            }
+            self.apps
+                .enter(appid, |app| {
+                    if (1..=SECRET_LEN).contains(&app.secret.len()) {
+                        Ok(())
+                    } else {
+                        Err(ErrorCode::INVAL)
+                    }
+                })
+                .unwrap_or_else(|err| Err(err.into()))?;
+            self.digits.set(digits as u8);
+        }
+
+        self.current_app.set(appid);
+        self.operation.set(operation);
+        self.short_id.set(short_id);
+        self.slot.set(slot);
+
+        let res = self.read();
+        if res.is_err() {
+            self.current_app.clear();
+            self.operation.set(Operation::None);
+        }
+        res
+    }
+
+    fn set_key(&self, key: &mut T) {
+        let hash = hash(self.short_id.get(), self.slot.get());
+        for (dst, src) in key.as_mut().iter_mut().zip(hash.to_le_bytes().iter()) {
+            *dst = *src;
+        }
+    }
+
+    fn read(&self) -> Result<(), ErrorCode> {
+        let key = self.key.take().ok_or(ErrorCode::RESERVE)?;
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => {
+                self.key.replace(key);
+                return Err(ErrorCode::RESERVE);
+            }
+        };
+        self.set_key(key);
+        self.step.set(Step::ReadRecord);
+        self.kv.get_value(key, buffer).map_err(|(key, buffer, e)| {
+            self.key.replace(key);
+            self.buffer.replace(buffer);
+            error(e)
+        })
+    }
+
+    fn append(&self) -> Result<(), ErrorCode> {
+        let key = self.key.take().ok_or(ErrorCode::RESERVE)?;
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => {
+                self.key.replace(key);
+                return Err(ErrorCode::RESERVE);
+            }
+        };
+        self.set_key(key);
+        self.step.set(Step::StoreRecord);
+        self.kv
+            .append_key(key, buffer, RECORD_LEN)
+            .map_err(|(key, buffer, e)| {
+                self.key.replace(key);
+                self.buffer.replace(buffer);
+                error(e)
+            })
+    }
+
+    fn remove(&self) -> Result<(), ErrorCode> {
+        let key = self.key.take().ok_or(ErrorCode::RESERVE)?;
+        self.set_key(key);
+        self.step.set(Step::RemoveRecord);
+        self.kv.invalidate_key(key).map_err(|(key, e)| {
+            self.key.replace(key);
+            error(e)
+        })
+    }
+
+    /// Start the HMAC of the first `length` bytes of the data buffer.
+    fn hmac(&self, step: Step, key: &[u8; 32], length: usize) -> Result<(), ErrorCode> {
+        let data = self.data.take().ok_or(ErrorCode::RESERVE)?;
+        if let Err(e) = self.hmac.set_mode_hmacsha256(key) {
+            self.data.replace(data);
+            return Err(e);
+        }
+        self.step.set(step);
+        let mut lease = LeasableBuffer::new(data);
+        lease.slice(..length);
+        self.hmac.add_data(lease).map(|_| ()).map_err(|(e, data)| {
+            self.hmac.clear_data();
+            self.data.replace(data);
+            e
+        })
+    }
+
+    /// Compute the keystream of the record, from the time it was
+    /// provisioned.
+    fn keystream(&self, nonce: u64) -> Result<(), ErrorCode> {
+        let short_id = self.short_id.get();
+        let slot = self.slot.get();
+        self.data.map_or(Err(ErrorCode::RESERVE), |data| {
+            data[0..4].copy_from_slice(&short_id.to_le_bytes());
+            data[4] = slot;
+            data[5..KEYSTREAM_DATA_LEN].copy_from_slice(&nonce.to_le_bytes());
+            Ok(())
+        })?;
+        self.hmac(Step::Keystream, self.device_key, KEYSTREAM_DATA_LEN)
+    }
+
+    fn record_loaded(&self, result: Result<(), ErrorCode>) -> Result<(), ErrorCode> {
+        let short_id = self.short_id.get();
+        let slot = self.slot.get();
+        let record = match result {
+            Ok(()) => self
+                .buffer
+                .map_or(None, |buffer| read_record(buffer, short_id, slot)),
+            Err(ErrorCode::NOSUPPORT) => None,
+            Err(e) => return Err(e),
+        };
+
+        match self.operation.get() {
+            Operation::Provision => {
+                // The key is in the store but isn't the slot's, so its hash
+                // collides with a slot of another application.
+                if record.is_none() && result.is_ok() {
+                    return Err(ErrorCode::FAIL);
+                }
+                self.record_stored.set(record.is_some());
+                let nonce = self.time.now_us().ok_or(ErrorCode::OFF)?;
+                let digits = self.digits.get();
+                self.buffer.map_or(Err(ErrorCode::RESERVE), |buffer| {
+                    buffer[0..4].copy_from_slice(&short_id.to_le_bytes());
+                    buffer[4] = slot;
+                    buffer[5] = digits;
+                    buffer[6] = 0;
+                    buffer[7] = 0;
+                    buffer[NONCE..SECRET].copy_from_slice(&nonce.to_le_bytes());
+                    Ok(())
+                })?;
+                self.keystream(nonce)
+            }
+            Operation::Code => {
+                let (digits, nonce) = record.ok_or(ErrorCode::NOSUPPORT)?;
+                self.digits.set(digits);
+                self.keystream(nonce)
+            }
+            Operation::Delete => {
+                record.ok_or(ErrorCode::NOSUPPORT)?;
+                self.remove()
+            }
+            Operation::None => Err(ErrorCode::FAIL),
+        }
+    }
+
+    /// Encrypt the secret the application allowed into the record.
+    fn encrypt(&self, keystream: &[u8; 32]) -> Result<(), ErrorCode> {
+        let appid = self.current_app.extract().ok_or(ErrorCode::FAIL)?;
+        self.buffer.map_or(Err(ErrorCode::RESERVE), |buffer| {
+            self.apps
+                .enter(appid, |app| {
+                    app.secret.map_or(Err(ErrorCode::INVAL), |secret| {
+                        // The application may have changed its buffer since
+                        // the command checked it.
+                        if secret.is_empty() || secret.len() > SECRET_LEN {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        for (i, dst) in buffer[SECRET..RECORD_LEN].iter_mut().enumerate() {
+                            *dst = secret.get(i).copied().unwrap_or(0) ^ keystream[i];
+                        }
+                        Ok(())
+                    })
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+
+    /// Decrypt the secret of the record and start the HMAC of the current
+    /// period with it.
+    fn start_code(&self, keystream: &[u8; 32]) -> Result<(), ErrorCode> {
+        let now_s = self.time.now_us().ok_or(ErrorCode::OFF)? / 1_000_000;
+        let (counter, remaining_s) = period(now_s);
+        self.remaining_s.set(remaining_s);
+        self.data.map_or(Err(ErrorCode::RESERVE), |data| {
+            data[0..8].copy_from_slice(&counter.to_be_bytes());
+            Ok(())
+        })?;
+
+        let mut secret = [0; SECRET_LEN];
+        self.buffer.map(|buffer| {
+            for (i, dst) in secret.iter_mut().enumerate() {
+                *dst = buffer[SECRET + i] ^ keystream[i];
+            }
+        });
+        let res = self.hmac(Step::Code, &secret, 8);
+        for byte in secret.iter_mut() {
+            *byte = 0;
+        }
+        res
+    }
+
+    fn hashed(&self, digest: &mut [u8; 32]) -> Result<(), ErrorCode> {
+        match (self.step.get(), self.operation.get()) {
+            (Step::Keystream, Operation::Provision) => {
+                self.encrypt(digest)?;
+                if self.record_stored.get() {
+                    self.remove()
+                } else {
+                    self.append()
+                }
+            }
+            (Step::Keystream, Operation::Code) => self.start_code(digest),
+            (Step::Code, _) => {
+                let code = truncate(digest, self.digits.get());
+                self.finish(Ok(()), code as usize, self.remaining_s.get() as usize);
+                Ok(())
+            }
+            _ => Err(ErrorCode::FAIL),
+        }
+    }
+
+    fn finish(&self, result: Result<(), ErrorCode>, data0: usize, data1: usize) {
+        // Don't leave secrets behind in the buffers.
+        self.buffer.map(|buffer| {
+            for byte in buffer.iter_mut() {
+                *byte = 0;
+            }
+        });
+        self.digest.map(|digest| {
+            for byte in digest.iter_mut() {
+                *byte = 0;
+            }
+        });
+        self.operation.set(Operation::None);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                app.callback
+                    .schedule(kernel::into_statuscode(result), data0, data1);
+            });
+        });
+    }
+
+    /// Finish the operation if a step failed.
+    fn check(&self, result: Result<(), ErrorCode>) {
+        if let Err(e) = result {
+            self.finish(Err(e), 0, 0);
+        }
+    }
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]> + HMACSha256,
+        S: KVSystem<'a, K = T>,
+        T: 'static + KeyType,
+        A: Alarm<'a>,
+    > digest::Client<'a, [u8; 32]> for Totp<'a, H, S, T, A>
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        self.data.replace(data);
+        let res = result.and_then(|()| {
+            let digest = self.digest.take().ok_or(ErrorCode::RESERVE)?;
+            self.hmac.run(digest).map_err(|(e, digest)| {
+                self.digest.replace(digest);
+                e
+            })
+        });
+        if res.is_err() {
+            self.hmac.clear_data();
+        }
+        self.check(res);
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; 32]) {
+        self.hmac.clear_data();
+        let res = result.and_then(|()| self.hashed(digest));
+        for byte in digest.iter_mut() {
+            *byte = 0;
+        }
+        self.digest.replace(digest);
+        self.check(res);
+    }
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]> + HMACSha256,
+        S: KVSystem<'a, K = T>,
+        T: 'static + KeyType,
+        A: Alarm<'a>,
+    > kv_system::Client<T> for Totp<'a, H, S, T, A>
+{
+    fn generate_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _unhashed_key: &'static [u8],
+        _key_buf: &'static T,
+    ) {
+        // Keys are hashed by this capsule, it never generates them.
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut T,
+        value: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.buffer.replace(value);
+
+        if self.step.get() == Step::StoreRecord {
+            self.finish(result, 0, 0);
+        }
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut T,
+        ret_buf: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.buffer.replace(ret_buf);
+
+        if self.step.get() == Step::ReadRecord {
+            self.check(self.record_loaded(result));
+        }
+    }
+
+    fn invalidate_key_complete(&self, result: Result<(), ErrorCode>, key: &'static mut T) {
+        self.key.replace(key);
+
+        if self.step.get() == Step::RemoveRecord {
+            if self.operation.get() == Operation::Provision {
+                self.check(result.and_then(|()| self.append()));
+            } else {
+                self.finish(result, 0, 0);
+            }
+        }
+    }
+
+    fn garbage_collect_complete(&self, _result: Result<(), ErrorCode>) {}
+}
+
+impl<
+        'a,
+        H: digest::Digest<'a, [u8; 32]> + HMACSha256,
+        S: KVSystem<'a, K = T>,
+        T: 'static + KeyType,
+        A: Alarm<'a>,
+    > Driver for Totp<'a, H, S, T, A>
+{
+    /// Setup shared kernel-readable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Setup the buffer of the secret to provision.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut slice, &mut app.secret);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup an operation done callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app| {
+                    mem::swap(&mut app.callback, &mut callback);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Provision the secret for slot `arg1`, for codes of `arg2`
+    ///   digits.
+    /// - `2`: Request the current code of slot `arg1`.
+    /// - `3`: Delete the secret of slot `arg1`.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        let operation = match command_num {
+            0 => return CommandReturn::success(),
+            1 => Operation::Provision,
+            2 => Operation::Code,
+            3 => Operation::Delete,
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+
+        match self.start(appid, operation, arg1, arg2) {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::sha256::{compress, BLOCK_LEN, INITIAL_STATE};
+    use std::vec::Vec;
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        let mut message = Vec::from(data);
+        message.push(0x80);
+        while message.len() % BLOCK_LEN != BLOCK_LEN - 8 {
+            message.push(0);
+        }
+        message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+        let mut state = INITIAL_STATE;
+        for block in message.chunks(BLOCK_LEN) {
+            compress(&mut state, block.try_into().unwrap());
+        }
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_mut(4).zip(state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn hmac_sha256(key: &[u8; SECRET_LEN], data: &[u8]) -> [u8; 32] {
+        let mut inner: Vec<u8> = key.iter().map(|b| b ^ 0x36).collect();
+        inner.resize(BLOCK_LEN, 0x36);
+        inner.extend_from_slice(data);
+        let mut outer: Vec<u8> = key.iter().map(|b| b ^ 0x5c).collect();
+        outer.resize(BLOCK_LEN, 0x5c);
+        outer.extend_from_slice(&sha256(&inner));
+        sha256(&outer)
+    }
+
+    fn code(secret: &[u8; SECRET_LEN], now_s: u64, digits: u8) -> u32 {
+        let (counter, _) = period(now_s);
+        truncate(&hmac_sha256(secret, &counter.to_be_bytes()), digits)
+    }
+
+    /// The SHA-256 test vectors of RFC 6238, appendix B.
+    #[test]
+    fn test_rfc6238_vectors() {
+        let secret = b"12345678901234567890123456789012";
+        let vectors = [
+            (59, 46119246),
+            (1111111109, 68084774),
+            (1111111111, 67062674),
+            (1234567890, 91819424),
+            (2000000000, 90698825),
+            (20000000000, 77737706),
+        ];
+        for &(now_s, expected) in vectors.iter() {
+            assert_eq!(code(secret, now_s, 8), expected);
+            assert_eq!(code(secret, now_s, 6), expected % 1_000_000);
+        }
+    }
+
+    #[test]
+    fn test_period() {
+        assert_eq!(period(0), (0, 30));
+        assert_eq!(period(29), (0, 1));
+        assert_eq!(period(30), (1, 30));
+        assert_eq!(period(59), (1, 1));
+    }
+
+    #[test]
+    fn test_read_record() {
+        let mut buffer = [0; RECORD_LEN];
+        buffer[0..4].copy_from_slice(&7u32.to_le_bytes());
+        buffer[4] = 2;
+        buffer[5] = 6;
+        buffer[NONCE..SECRET].copy_from_slice(&1234u64.to_le_bytes());
+        assert_eq!(read_record(&buffer, 7, 2), Some((6, 1234)));
+        assert_eq!(read_record(&buffer, 7, 3), None);
+        assert_eq!(read_record(&buffer, 8, 2), None);
+    }
+}
@@ -0,0 +1,343 @@
+//! Virtualize the BLE advertising radio, so several capsules can use it.
+//!
+//! Each user of the radio, such as the BLE advertising driver and a kernel
+//! beacon, gets a `VirtualBleRadio`. The radio runs one operation at a time:
+//! an operation holds it from when it starts until its transmit or receive
+//! callback. An operation queued from the callback of the previous operation
+//! of the same user goes first, so the packets of an advertising event or the
+//! channels of a scan are not split by other users. Otherwise queued
+//! operations start in the order of the users in the list.
+//!
+//! The TX power is a setting of the radio, so each user's TX power is kept
+//! here and set on the radio before each of its operations.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ble_mux = static_init!(
+//!     capsules::virtual_ble::MuxBleRadio<'static, nrf52840::ble_radio::Radio>,
+//!     capsules::virtual_ble::MuxBleRadio::new(&base_peripherals.ble_radio)
+//! );
+//! let beacon_radio = static_init!(
+//!     capsules::virtual_ble::VirtualBleRadio<'static, nrf52840::ble_radio::Radio>,
+//!     capsules::virtual_ble::VirtualBleRadio::new(ble_mux)
+//! );
+//! beacon_radio.setup();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::ble_advertising::{
+    BleAdvertisementDriver, BleConfig, BleExtendedAdvertisementDriver, BleScanResponseDriver, Phy,
+    RadioChannel, RxClient, TxClient,
+};
+use kernel::ErrorCode;
+
+/// A BLE radio with all the features the virtual radios pass through.
+pub trait BleRadio<'a>:
+    BleAdvertisementDriver<'a>
+    + BleExtendedAdvertisementDriver<'a>
+    + BleScanResponseDriver<'a>
+    + BleConfig
+{
+}
+
+impl<
+        'a,
+        R: BleAdvertisementDriver<'a>
+            + BleExtendedAdvertisementDriver<'a>
+            + BleScanResponseDriver<'a>
+            + BleConfig,
+    > BleRadio<'a> for R
+{
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Op {
+    Idle,
+    Transmit {
+        len: usize,
+        channel: RadioChannel,
+    },
+    Receive(RadioChannel),
+    TransmitExtended {
+        primary_len: usize,
+        aux_len: usize,
+        channel: RadioChannel,
+        secondary: RadioChannel,
+        phy: Phy,
+    },
+    TransmitScannable {
+        adv_len: usize,
+        rsp_len: usize,
+        channel: RadioChannel,
+    },
+}
+
+pub struct MuxBleRadio<'a, R: BleRadio<'a>> {
+    radio: &'a R,
+    devices: List<'a, VirtualBleRadio<'a, R>>,
+    inflight: OptionalCell<&'a VirtualBleRadio<'a, R>>,
+}
+
+impl<'a, R: BleRadio<'a>> MuxBleRadio<'a, R> {
+    pub const fn new(radio: &'a R) -> MuxBleRadio<'a, R> {
+        MuxBleRadio {
+            radio: radio,
+            devices: List::new(),
+            inflight: OptionalCell::empty(),
+        }
+    }
+
+    /// Start the next queued operation, preferring one of `previous`, the
+    /// user of the operation that just ended.
+    fn do_next_op(&self, previous: Option<&'a VirtualBleRadio<'a, R>>) {
+        while self.inflight.is_none() {
+            let next = previous
+                .filter(|node| node.operation.get() != Op::Idle)
+                .or_else(|| {
+                    self.devices
+                        .iter()
+                        .find(|node| node.operation.get() != Op::Idle)
+                });
+            let node = match next {
+                Some(node) => node,
+                None => return,
+            };
+            let op = node.operation.replace(Op::Idle);
+            if let Some(power) = node.tx_power.get() {
+                let _ = self.radio.set_tx_power(power);
+            }
+            self.inflight.set(node);
+            let result = match op {
+                Op::Receive(channel) => {
+                    self.radio.receive_advertisement(channel);
+                    Ok(())
+                }
+                Op::Transmit { len, channel } => node.buffer.take().map_or(Ok(()), |buffer| {
+                    self.radio.transmit_advertisement(buffer, len, channel);
+                    Ok(())
+                }),
+                Op::TransmitExtended {
+                    primary_len,
+                    aux_len,
+                    channel,
+                    secondary,
+                    phy,
+                } => node.buffer.take().map_or(Ok(()), |buffer| {
+                    self.radio.transmit_extended_advertisement(
+                        buffer,
+                        primary_len,
+                        aux_len,
+                        channel,
+                        secondary,
+                        phy,
+                    )
+                }),
+                Op::TransmitScannable {
+                    adv_len,
+                    rsp_len,
+                    channel,
+                } => node.buffer.take().map_or(Ok(()), |buffer| {
+                    self.radio
+                        .transmit_scannable_advertisement(buffer, adv_len, rsp_len, channel)
+                }),
+                Op::Idle => Ok(()),
+            };
+            if let Err((e, buffer)) = result {
+                // The radio returned the buffer at once, so the operation is
+                // over. The user may queue another one from the callback.
+                self.inflight.clear();
+                node.tx_client
+                    .map(move |client| client.transmit_event(buffer, Err(e)));
+            }
+        }
+    }
+}
+
+impl<'a, R: BleRadio<'a>> TxClient for MuxBleRadio<'a, R> {
+    fn transmit_event(&self, buf: &'static mut [u8], result: Result<(), ErrorCode>) {
+        let device = self.inflight.take();
+        if let Some(device) = device {
+            device
+                .tx_client
+                .map(move |client| client.transmit_event(buf, result));
+        }
+        self.do_next_op(device);
+    }
+}
+
+impl<'a, R: BleRadio<'a>> RxClient for MuxBleRadio<'a, R> {
+    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: Result<(), ErrorCode>) {
+        let device = self.inflight.take();
+        if let Some(device) = device {
+            device
+                .rx_client
+                .map(move |client| client.receive_event(buf, len, result));
+        }
+        self.do_next_op(device);
+    }
+}
+
+pub struct VirtualBleRadio<'a, R: BleRadio<'a>> {
+    mux: &'a MuxBleRadio<'a, R>,
+    next: ListLink<'a, VirtualBleRadio<'a, R>>,
+    operation: Cell<Op>,
+    buffer: TakeCell<'static, [u8]>,
+    tx_power: Cell<Option<u8>>,
+    tx_client: OptionalCell<&'a dyn TxClient>,
+    rx_client: OptionalCell<&'a dyn RxClient>,
+}
+
+impl<'a, R: BleRadio<'a>> ListNode<'a, VirtualBleRadio<'a, R>> for VirtualBleRadio<'a, R> {
+    fn next(&self) -> &'a ListLink<VirtualBleRadio<'a, R>> {
+        &self.next
+    }
+}
+
+impl<'a, R: BleRadio<'a>> VirtualBleRadio<'a, R> {
+    pub const fn new(mux: &'a MuxBleRadio<'a, R>) -> VirtualBleRadio<'a, R> {
+        VirtualBleRadio {
+            mux: mux,
+            next: ListLink::empty(),
+            operation: Cell::new(Op::Idle),
+            buffer: TakeCell::empty(),
+            tx_power: Cell::new(None),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Add this radio to the mux, and make the mux the client of the radio.
+    pub fn setup(&'a self) {
+        self.mux.devices.push_head(self);
+        self.mux.radio.set_transmit_client(self.mux);
+        self.mux.radio.set_receive_client(self.mux);
+    }
+
+    /// Queue `op` with `buffer`, unless an operation is already queued, in
+    /// which case `buffer` is given back.
+    fn queue(
+        &self,
+        op: Op,
+        buffer: Option<&'static mut [u8]>,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.operation.get() != Op::Idle {
+            return match buffer {
+                Some(buffer) => Err((ErrorCode::BUSY, buffer)),
+                None => Ok(()),
+            };
+        }
+        if let Some(buffer) = buffer {
+            self.buffer.replace(buffer);
+        }
+        self.operation.set(op);
+        self.mux.do_next_op(None);
+        Ok(())
+    }
+}
+
+impl<'a, R: BleRadio<'a>> BleAdvertisementDriver<'a> for VirtualBleRadio<'a, R> {
+    fn transmit_advertisement(&self, buf: &'static mut [u8], len: usize, channel: RadioChannel) {
+        if let Err((e, buf)) = self.queue(Op::Transmit { len, channel }, Some(buf)) {
+            self.tx_client
+                .map(move |client| client.transmit_event(buf, Err(e)));
+        }
+    }
+
+    fn receive_advertisement(&self, channel: RadioChannel) {
+        let _ = self.queue(Op::Receive(channel), None);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_transmit_client(&self, client: &'a dyn TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn last_rssi(&self) -> Option<i8> {
+        self.mux.radio.last_rssi()
+    }
+}
+
+impl<'a, R: BleRadio<'a>> BleExtendedAdvertisementDriver<'a> for VirtualBleRadio<'a, R> {
+    fn supports_secondary_phy(&self, phy: Phy) -> bool {
+        self.mux.radio.supports_secondary_phy(phy)
+    }
+
+    fn aux_offset_us(&self) -> u32 {
+        self.mux.radio.aux_offset_us()
+    }
+
+    fn transmit_extended_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        primary_len: usize,
+        aux_len: usize,
+        channel: RadioChannel,
+        secondary: RadioChannel,
+        phy: Phy,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.mux.radio.supports_secondary_phy(phy) {
+            return Err((ErrorCode::NOSUPPORT, buf));
+        }
+        self.queue(
+            Op::TransmitExtended {
+                primary_len,
+                aux_len,
+                channel,
+                secondary,
+                phy,
+            },
+            Some(buf),
+        )
+    }
+}
+
+impl<'a, R: BleRadio<'a>> BleScanResponseDriver<'a> for VirtualBleRadio<'a, R> {
+    fn supports_scan_response(&self) -> bool {
+        self.mux.radio.supports_scan_response()
+    }
+
+    fn transmit_scannable_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        adv_len: usize,
+        rsp_len: usize,
+        channel: RadioChannel,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.mux.radio.supports_scan_response() {
+            return Err((ErrorCode::NOSUPPORT, buf));
+        }
+        self.queue(
+            Op::TransmitScannable {
+                adv_len,
+                rsp_len,
+                channel,
+            },
+            Some(buf),
+        )
+    }
+}
+
+impl<'a, R: BleRadio<'a>> BleConfig for VirtualBleRadio<'a, R> {
+    fn check_tx_power(&self, power: u8) -> Result<(), ErrorCode> {
+        self.mux.radio.check_tx_power(power)
+    }
+
+    fn set_tx_power(&self, power: u8) -> Result<(), ErrorCode> {
+        self.mux.radio.check_tx_power(power)?;
+        self.tx_power.set(Some(power));
+        Ok(())
+    }
+
+    fn check_channel_map(&self, channel_map: u8) -> Result<(), ErrorCode> {
+        self.mux.radio.check_channel_map(channel_map)
+    }
+}
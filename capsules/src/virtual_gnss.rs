@@ -0,0 +1,79 @@
+//! Virtualize the position fixes of a GNSS receiver.
+//!
+//! `MuxGnss` is the fix client of the receiver and passes every fix on to
+//! each `VirtualGnss`, so several kernel capsules, such as the geofence and
+//! odometer capsules, can follow the receiver.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let mux_gnss = static_init!(
+//!     capsules::virtual_gnss::MuxGnss<'static>,
+//!     capsules::virtual_gnss::MuxGnss::new()
+//! );
+//! kernel::hil::gnss::Gnss::set_fix_client(gnss, mux_gnss);
+//!
+//! let geofence_gnss = static_init!(
+//!     capsules::virtual_gnss::VirtualGnss<'static>,
+//!     capsules::virtual_gnss::VirtualGnss::new(mux_gnss)
+//! );
+//! geofence_gnss.add_to_mux();
+//! kernel::hil::gnss::Gnss::set_fix_client(geofence_gnss, geofence);
+//! ```
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::gnss::{Fix, FixClient, Gnss};
+
+pub struct MuxGnss<'a> {
+    users: List<'a, VirtualGnss<'a>>,
+}
+
+impl<'a> MuxGnss<'a> {
+    pub const fn new() -> MuxGnss<'a> {
+        MuxGnss { users: List::new() }
+    }
+}
+
+impl FixClient for MuxGnss<'_> {
+    fn fix(&self, fix: Fix) {
+        for user in self.users.iter() {
+            user.client.map(|client| client.fix(fix));
+        }
+    }
+}
+
+pub struct VirtualGnss<'a> {
+    mux: &'a MuxGnss<'a>,
+    next: ListLink<'a, VirtualGnss<'a>>,
+    client: OptionalCell<&'a dyn FixClient>,
+}
+
+impl<'a> VirtualGnss<'a> {
+    pub const fn new(mux: &'a MuxGnss<'a>) -> VirtualGnss<'a> {
+        VirtualGnss {
+            mux: mux,
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn add_to_mux(&'a self) {
+        self.mux.users.push_head(self);
+    }
+}
+
+impl<'a> ListNode<'a, VirtualGnss<'a>> for VirtualGnss<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, VirtualGnss<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> Gnss<'a> for VirtualGnss<'a> {
+    fn set_fix_client(&self, client: &'a dyn FixClient) {
+        self.client.set(client);
+    }
+}
@@ -2,6 +2,11 @@
 //!
 //! `MuxI2C` provides shared access to a single I2C Master Bus for multiple
 //! users. `I2CDevice` provides access to a specific I2C address.
+//!
+//! The mux enables the bus when a device first enables it, and keeps it
+//! enabled from then on, as the mux always has devices by then. Devices
+//! usually enable and disable the bus around each transfer, and controllers
+//! that power down while disabled would otherwise be power-cycled each time.
 
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
@@ -16,7 +21,7 @@ pub struct MuxI2C<'a> {
     smbus: Option<&'a dyn i2c::SMBusMaster>,
     i2c_devices: List<'a, I2CDevice<'a>>,
     smbus_devices: List<'a, SMBusDevice<'a>>,
-    enabled: Cell<usize>,
+    enabled: Cell<bool>,
     i2c_inflight: OptionalCell<&'a I2CDevice<'a>>,
     smbus_inflight: OptionalCell<&'a SMBusDevice<'a>>,
     deferred_caller: &'a DynamicDeferredCall,
@@ -49,7 +54,7 @@ impl<'a> MuxI2C<'a> {
             smbus,
             i2c_devices: List::new(),
             smbus_devices: List::new(),
-            enabled: Cell::new(0),
+            enabled: Cell::new(false),
             i2c_inflight: OptionalCell::empty(),
             smbus_inflight: OptionalCell::empty(),
             deferred_caller: deferred_caller,
@@ -62,21 +67,12 @@ impl<'a> MuxI2C<'a> {
     }
 
     fn enable(&self) {
-        let enabled = self.enabled.get();
-        self.enabled.set(enabled + 1);
-        if enabled == 0 {
+        if !self.enabled.get() {
+            self.enabled.set(true);
             self.i2c.enable();
         }
     }
 
-    fn disable(&self) {
-        let enabled = self.enabled.get();
-        self.enabled.set(enabled - 1);
-        if enabled == 1 {
-            self.i2c.disable();
-        }
-    }
-
     fn do_next_op(&self) {
         if self.i2c_inflight.is_none() && self.smbus_inflight.is_none() {
             // Nothing is currently in flight
@@ -189,7 +185,6 @@ enum Op {
 pub struct I2CDevice<'a> {
     mux: &'a MuxI2C<'a>,
     addr: u8,
-    enabled: Cell<bool>,
     buffer: TakeCell<'static, [u8]>,
     operation: Cell<Op>,
     next: ListLink<'a, I2CDevice<'a>>,
@@ -201,7 +196,6 @@ impl<'a> I2CDevice<'a> {
         I2CDevice {
             mux: mux,
             addr: addr,
-            enabled: Cell::new(false),
             buffer: TakeCell::empty(),
             operation: Cell::new(Op::Idle),
             next: ListLink::empty(),
@@ -231,17 +225,11 @@ impl<'a> ListNode<'a, I2CDevice<'a>> for I2CDevice<'a> {
 
 impl i2c::I2CDevice for I2CDevice<'_> {
     fn enable(&self) {
-        if !self.enabled.get() {
-            self.enabled.set(true);
-            self.mux.enable();
-        }
+        self.mux.enable();
     }
 
     fn disable(&self) {
-        if self.enabled.get() {
-            self.enabled.set(false);
-            self.mux.disable();
-        }
+        // The mux keeps the bus enabled for its other devices.
     }
 
     fn write_read(&self, data: &'static mut [u8], write_len: u8, read_len: u8) {
@@ -266,7 +254,6 @@ impl i2c::I2CDevice for I2CDevice<'_> {
 pub struct SMBusDevice<'a> {
     mux: &'a MuxI2C<'a>,
     addr: u8,
-    enabled: Cell<bool>,
     buffer: TakeCell<'static, [u8]>,
     operation: Cell<Op>,
     next: ListLink<'a, SMBusDevice<'a>>,
@@ -282,7 +269,6 @@ impl<'a> SMBusDevice<'a> {
         SMBusDevice {
             mux: mux,
             addr: addr,
-            enabled: Cell::new(false),
             buffer: TakeCell::empty(),
             operation: Cell::new(Op::Idle),
             next: ListLink::empty(),
@@ -312,17 +298,11 @@ impl<'a> ListNode<'a, SMBusDevice<'a>> for SMBusDevice<'a> {
 
 impl<'a> i2c::I2CDevice for SMBusDevice<'a> {
     fn enable(&self) {
-        if !self.enabled.get() {
-            self.enabled.set(true);
-            self.mux.enable();
-        }
+        self.mux.enable();
     }
 
     fn disable(&self) {
-        if self.enabled.get() {
-            self.enabled.set(false);
-            self.mux.disable();
-        }
+        // The mux keeps the bus enabled for its other devices.
     }
 
     fn write_read(&self, data: &'static mut [u8], write_len: u8, read_len: u8) {
@@ -0,0 +1,236 @@
+//! Virtualize an RF switch that shares one antenna between several radios.
+//!
+//! `MuxRfSwitch` drives the GPIOs of the switch. Each radio gets a
+//! `VirtualRfSwitch` with the levels of those GPIOs that connect the antenna
+//! to it, bit `n` for pin `n`. While no radio holds the antenna, the pins are
+//! left at the mux's idle levels, which should isolate every radio.
+//!
+//! Radios take turns. When a radio asks for the antenna while another holds
+//! it, the holder is asked to release it. On release, the antenna goes to the
+//! next waiting radio after the holder, and if yet more radios are waiting,
+//! the new holder is asked to release it in turn, so a long GNSS fix cannot
+//! keep a LoRa uplink waiting, nor a stream of uplinks starve the GNSS.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let rf_pins = static_init!(
+//!     [&'static dyn kernel::hil::gpio::Output; 2],
+//!     [&gpio_port[RF_SW_CTRL1], &gpio_port[RF_SW_CTRL2]]
+//! );
+//! let mux_rf_switch = static_init!(
+//!     capsules::virtual_rf_switch::MuxRfSwitch<'static>,
+//!     capsules::virtual_rf_switch::MuxRfSwitch::new(rf_pins, 0b00, dynamic_deferred_caller)
+//! );
+//! mux_rf_switch.initialize_callback_handle(
+//!     dynamic_deferred_caller.register(mux_rf_switch).unwrap(),
+//! );
+//! mux_rf_switch.initialize();
+//!
+//! let lora_rf_switch = static_init!(
+//!     capsules::virtual_rf_switch::VirtualRfSwitch<'static>,
+//!     capsules::virtual_rf_switch::VirtualRfSwitch::new(mux_rf_switch, 0b01)
+//! );
+//! lora_rf_switch.add_to_mux();
+//! lora_rf_switch.set_client(sx1262);
+//! sx1262.set_rf_switch(lora_rf_switch);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::gpio;
+use kernel::hil::rf_switch::{RfSwitch, RfSwitchClient};
+use kernel::ErrorCode;
+
+pub struct MuxRfSwitch<'a> {
+    pins: &'a [&'a dyn gpio::Output],
+    /// Pin levels while no radio holds the antenna.
+    idle: u32,
+    users: List<'a, VirtualRfSwitch<'a>>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> MuxRfSwitch<'a> {
+    pub const fn new(
+        pins: &'a [&'a dyn gpio::Output],
+        idle: u32,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> MuxRfSwitch<'a> {
+        MuxRfSwitch {
+            pins: pins,
+            idle: idle,
+            users: List::new(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Put the switch in its idle position.
+    pub fn initialize(&self) {
+        self.set_levels(self.idle);
+    }
+
+    fn set_levels(&self, levels: u32) {
+        for (i, pin) in self.pins.iter().enumerate() {
+            if levels & (1 << i) != 0 {
+                pin.set();
+            } else {
+                pin.clear();
+            }
+        }
+    }
+
+    fn owner(&self) -> Option<&'a VirtualRfSwitch<'a>> {
+        self.users.iter().find(|node| node.acquired.get())
+    }
+
+    fn acquire(&self, user: &VirtualRfSwitch<'a>) -> Result<(), ErrorCode> {
+        if user.acquired.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        match self.owner() {
+            Some(owner) => {
+                user.waiting.set(true);
+                owner.release_requested.set(true);
+                self.notify_async();
+                Err(ErrorCode::BUSY)
+            }
+            None => {
+                self.set_levels(user.levels);
+                user.acquired.set(true);
+                Ok(())
+            }
+        }
+    }
+
+    fn release(&self, user: &VirtualRfSwitch<'a>) {
+        user.waiting.set(false);
+        user.release_requested.set(false);
+        if !user.acquired.get() {
+            return;
+        }
+        user.acquired.set(false);
+
+        // Hand the antenna to the first waiting radio after the one that
+        // released it, wrapping around the list.
+        let mut after_user = false;
+        let mut first = None;
+        let mut next = None;
+        for node in self.users.iter() {
+            if core::ptr::eq(node, user) {
+                after_user = true;
+            } else if node.waiting.get() {
+                if first.is_none() {
+                    first = Some(node);
+                }
+                if after_user && next.is_none() {
+                    next = Some(node);
+                }
+            }
+        }
+
+        match next.or(first) {
+            Some(node) => {
+                node.waiting.set(false);
+                self.set_levels(node.levels);
+                node.acquired.set(true);
+                node.granted.set(true);
+                if self.users.iter().any(|other| other.waiting.get()) {
+                    node.release_requested.set(true);
+                }
+                self.notify_async();
+            }
+            None => self.set_levels(self.idle),
+        }
+    }
+
+    /// Tell radios about grants and release requests after the call that
+    /// caused them returns, so their callbacks are not reentrant.
+    fn notify_async(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for MuxRfSwitch<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        for node in self.users.iter() {
+            if node.granted.take() {
+                node.client.map(|client| client.granted());
+            }
+        }
+        // The radio may have released the antenna since it was asked to.
+        if let Some(owner) = self.owner() {
+            if owner.release_requested.take() {
+                owner.client.map(|client| client.release_requested());
+            }
+        }
+    }
+}
+
+pub struct VirtualRfSwitch<'a> {
+    mux: &'a MuxRfSwitch<'a>,
+    /// Pin levels that connect the antenna to this radio.
+    levels: u32,
+    acquired: Cell<bool>,
+    waiting: Cell<bool>,
+    granted: Cell<bool>,
+    release_requested: Cell<bool>,
+    next: ListLink<'a, VirtualRfSwitch<'a>>,
+    client: OptionalCell<&'a dyn RfSwitchClient>,
+}
+
+impl<'a> VirtualRfSwitch<'a> {
+    pub const fn new(mux: &'a MuxRfSwitch<'a>, levels: u32) -> VirtualRfSwitch<'a> {
+        VirtualRfSwitch {
+            mux: mux,
+            levels: levels,
+            acquired: Cell::new(false),
+            waiting: Cell::new(false),
+            granted: Cell::new(false),
+            release_requested: Cell::new(false),
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn add_to_mux(&'a self) {
+        self.mux.users.push_head(self);
+    }
+}
+
+impl<'a> ListNode<'a, VirtualRfSwitch<'a>> for VirtualRfSwitch<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, VirtualRfSwitch<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> RfSwitch<'a> for VirtualRfSwitch<'a> {
+    fn set_client(&self, client: &'a dyn RfSwitchClient) {
+        self.client.set(client);
+    }
+
+    fn acquire(&self) -> Result<(), ErrorCode> {
+        self.mux.acquire(self)
+    }
+
+    fn release(&self) {
+        self.mux.release(self);
+    }
+
+    fn is_acquired(&self) -> bool {
+        self.acquired.get()
+    }
+}
@@ -18,9 +18,16 @@
 //! # use capsules::virtual_uart::{MuxUart, UartDevice};
 //!
 //! // Create a shared UART channel for the console and for kernel debug.
+//! static mut RX_BUF: [u8; capsules::virtual_uart::RX_BUF_LEN] =
+//!     [0; capsules::virtual_uart::RX_BUF_LEN];
 //! let uart_mux = static_init!(
 //!     MuxUart<'static>,
-//!     MuxUart::new(&sam4l::usart::USART0, &mut capsules::virtual_uart::RX_BUF)
+//!     MuxUart::new(
+//!         &sam4l::usart::USART0,
+//!         &mut RX_BUF,
+//!         115200,
+//!         dynamic_deferred_caller,
+//!     )
 //! );
 //! hil::uart::UART::set_receive_client(&sam4l::usart::USART0, uart_mux);
 //! hil::uart::UART::set_transmit_client(&sam4l::usart::USART0, uart_mux);
@@ -34,11 +41,13 @@
 //!         console_uart,
 //!         &mut capsules::console::WRITE_BUF,
 //!         &mut capsules::console::READ_BUF,
+//!         &mut capsules::console::READ_QUEUE,
 //!         board_kernel.create_grant(&grant_cap)
 //!     )
 //! );
 //! hil::uart::UART::set_transmit_client(console_uart, console);
 //! hil::uart::UART::set_receive_client(console_uart, console);
+//! console.initialize();
 //! ```
 
 use core::cell::Cell;
@@ -52,8 +61,7 @@ use kernel::common::dynamic_deferred_call::{
 use kernel::common::{List, ListLink, ListNode};
 use kernel::hil::uart;
 
-const RX_BUF_LEN: usize = 64;
-pub static mut RX_BUF: [u8; RX_BUF_LEN] = [0; RX_BUF_LEN];
+pub const RX_BUF_LEN: usize = 64;
 
 pub struct MuxUart<'a> {
     uart: &'a dyn uart::Uart<'a>,
@@ -138,10 +146,13 @@ impl<'a> uart::ReceiveClient for MuxUart<'a> {
                     let state = device.state.get();
                     let position = device.rx_position.get();
                     let remaining = device.rx_len.get() - position;
-                    // If this finishes the read, signal to the caller,
-                    // otherwise update state so next read will fill in
-                    // more data.
-                    if remaining == 0 {
+                    // If this finishes the read, or the UART reported a
+                    // line error that ended reception early, signal to the
+                    // caller, otherwise update state so next read will fill
+                    // in more data.
+                    if remaining == 0
+                        || (error.is_line_error() && state == UartDeviceReceiveState::Receiving)
+                    {
                         device.state.set(UartDeviceReceiveState::Idle);
                         device.received_buffer(rxbuf, position, rcode, error);
                         // Need to check if receive was called in callback
@@ -0,0 +1,560 @@
+//! Alarms at a wall-clock time, which survive a reset.
+//!
+//! The wall-clock mode of the alarm driver: a process sets an alarm for a
+//! time in seconds since 1970, as the `network_time` capsule keeps it, rather
+//! than for a count of alarm ticks. Unlike a tick count, that time still
+//! means the same after the board resets, so the capsule keeps the pending
+//! alarm of each process in a KV store, keyed by the process's `ShortID`.
+//! After a reset, for example by the watchdog, the process asks for its alarm
+//! back and does not miss, say, its next scheduled uplink. An alarm whose
+//! time passed while the board was down fires as soon as it is restored.
+//!
+//! The time is read again at least every `MAX_WAIT_S` seconds, so alarms
+//! follow corrections of the time and of the clock drift. Alarms fire up to a
+//! second late, and not before `network_time` has synchronized. Processes
+//! without a `ShortID` can set alarms, but they are not saved.
+//!
+//! ```text
+//! +-----------------------+
+//! |       userspace       |
+//! +-----------------------+
+//!        kernel::Driver
+//! +-----------------------+
+//! | WallClockAlarm (this) |
+//! +-----------------------+
+//!   hil::time  hil::kv_system  network_time
+//! ```
+//!
+//! The capsule must be the only client of its KV system, so it needs a store
+//! of its own rather than the one behind `kv_driver`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let wall_clock_alarm_key = static_init!([u8; 8], [0; 8]);
+//! let wall_clock_alarm = static_init!(
+//!     capsules::wall_clock_alarm::WallClockAlarm<
+//!         'static,
+//!         VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!         TicKVStore<'static, FlashUser<'static, F>>,
+//!         TicKVKeyType,
+//!     >,
+//!     capsules::wall_clock_alarm::WallClockAlarm::new(
+//!         wall_clock_virtual_alarm,
+//!         network_time,
+//!         tickv,
+//!         board_kernel.create_grant(&grant_cap),
+//!         wall_clock_alarm_key,
+//!         &mut capsules::wall_clock_alarm::BUFFER,
+//!     )
+//! );
+//! wall_clock_virtual_alarm.set_alarm_client(wall_clock_alarm);
+//! tickv.set_client(wall_clock_alarm);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Initial
+//!
+//! ### Subscribe
+//!
+//! - `0`: Alarm fired. The first argument is the time it was set for, in
+//!   seconds since 1970.
+//! - `1`: Restore done. The first argument is the status, the second the
+//!   time of the restored alarm, or `0` if none was saved.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Set the alarm of the process for `arg1` seconds since 1970,
+//!   replacing any alarm it had. Returns `OFF` before the time is known, and
+//!   `INVAL` if the time has passed.
+//! - `2`: Cancel the alarm of the process. Returns `ALREADY` if it has none.
+//! - `3`: Restore the alarm saved before the last reset. Returns `NOSUPPORT`
+//!   if the process has no `ShortID`, and `BUSY` if it is already restoring.
+//!   A restored alarm does not replace one set since the reset.
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::math;
+use kernel::hil::kv_system::{self, KVSystem, KeyType};
+use kernel::hil::time::{self, Alarm};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, Upcall};
+
+use crate::network_time::NetworkTime;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::WallClockAlarm as usize;
+
+pub static mut BUFFER: [u8; 16] = [0; 16];
+
+/// Longest time between two readings of the time while alarms are pending.
+pub const MAX_WAIT_S: u64 = 60;
+
+/// Length of a saved alarm: the `ShortID` and the time.
+const RECORD_LEN: usize = 12;
+
+/// Hash the `ShortID` of an application into a key of the store.
+fn hash(short_id: u32) -> u64 {
+    let hash = math::fnv1a_64(math::FNV1A_64_OFFSET, b"alarm");
+    math::fnv1a_64(hash, &short_id.to_le_bytes())
+}
+
+fn write_record(buffer: &mut [u8], short_id: u32, target_s: u64) {
+    buffer[0..4].copy_from_slice(&short_id.to_le_bytes());
+    buffer[4..RECORD_LEN].copy_from_slice(&target_s.to_le_bytes());
+}
+
+/// Return the time of the saved alarm, if it belongs to `short_id`.
+fn read_record(buffer: &[u8], short_id: u32) -> Option<u64> {
+    let id = u32::from_le_bytes(buffer.get(0..4)?.try_into().ok()?);
+    let target_s = u64::from_le_bytes(buffer.get(4..RECORD_LEN)?.try_into().ok()?);
+    if id == short_id {
+        Some(target_s)
+    } else {
+        None
+    }
+}
+
+/// Seconds to wait before checking an alarm for `target_s` again, at
+/// `now_us`, or `None` if it is due.
+fn wait_s(now_us: u64, target_s: u64) -> Option<u64> {
+    let target_us = target_s.saturating_mul(1_000_000);
+    if now_us >= target_us {
+        None
+    } else {
+        let remaining_s = (target_us - now_us).saturating_add(999_999) / 1_000_000;
+        Some(cmp::min(remaining_s, MAX_WAIT_S))
+    }
+}
+
+fn error(result: Result<(), ErrorCode>) -> ErrorCode {
+    result.err().unwrap_or(ErrorCode::FAIL)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    /// Reading the saved alarm of the process.
+    Restore,
+    /// Invalidating the saved alarm of the process.
+    Remove,
+    /// Saving the alarm of the process.
+    Store,
+}
+
+#[derive(Default)]
+pub struct App {
+    fired_callback: Upcall,
+    restore_callback: Upcall,
+    /// Time the alarm fires at, in seconds since 1970.
+    target_s: Option<u64>,
+    /// The saved alarm of the process is out of date.
+    save_pending: bool,
+    restore_pending: bool,
+}
+
+pub struct WallClockAlarm<'a, A: Alarm<'a>, S: KVSystem<'a, K = T>, T: 'static + KeyType> {
+    alarm: &'a A,
+    time: &'a NetworkTime<'a, A>,
+    kv: &'a S,
+    apps: Grant<App>,
+    key: TakeCell<'static, T>,
+    buffer: TakeCell<'static, [u8]>,
+    /// The process whose saved alarm is being read or written.
+    current: OptionalCell<(ProcessId, Step)>,
+}
+
+impl<'a, A: Alarm<'a>, S: KVSystem<'a, K = T>, T: 'static + KeyType> WallClockAlarm<'a, A, S, T> {
+    pub fn new(
+        alarm: &'a A,
+        time: &'a NetworkTime<'a, A>,
+        kv: &'a S,
+        grant: Grant<App>,
+        key: &'static mut T,
+        buffer: &'static mut [u8],
+    ) -> WallClockAlarm<'a, A, S, T> {
+        WallClockAlarm {
+            alarm,
+            time,
+            kv,
+            apps: grant,
+            key: TakeCell::new(key),
+            buffer: TakeCell::new(buffer),
+            current: OptionalCell::empty(),
+        }
+    }
+
+    /// Fire the alarms that are due, and set the alarm to check the others
+    /// again.
+    fn check(&self) {
+        let now_us = self.time.now_us();
+        let pending = Cell::new(false);
+        let next_s = Cell::new(MAX_WAIT_S);
+        self.apps.each(|appid, app| {
+            if let Some(target_s) = app.target_s {
+                match now_us.map(|now_us| wait_s(now_us, target_s)) {
+                    Some(None) => {
+                        app.target_s = None;
+                        app.save_pending = appid.short_id().is_some();
+                        app.fired_callback.schedule(target_s as usize, 0, 0);
+                    }
+                    Some(Some(wait_s)) => {
+                        pending.set(true);
+                        next_s.set(cmp::min(next_s.get(), wait_s));
+                    }
+                    // Wait for the time to be known
+                    None => pending.set(true),
+                }
+            }
+        });
+
+        if pending.get() {
+            self.alarm.set_alarm(
+                self.alarm.now(),
+                A::ticks_from_seconds(cmp::max(next_s.get(), 1) as u32),
+            );
+        } else {
+            let _ = self.alarm.disarm();
+        }
+        self.next_operation();
+    }
+
+    fn set_key(&self, key: &mut T, short_id: u32) {
+        for (dst, src) in key
+            .as_mut()
+            .iter_mut()
+            .zip(hash(short_id).to_le_bytes().iter())
+        {
+            *dst = *src;
+        }
+    }
+
+    /// Start reading or writing the next saved alarm that needs it, if the
+    /// store is idle.
+    fn next_operation(&self) {
+        while self.current.is_none() {
+            let mut next = None;
+            for cntr in self.apps.iter() {
+                let appid = cntr.processid();
+                let step = cntr.enter(|app| {
+                    if app.restore_pending {
+                        app.restore_pending = false;
+                        Some(Step::Restore)
+                    } else if app.save_pending {
+                        app.save_pending = false;
+                        Some(Step::Remove)
+                    } else {
+                        None
+                    }
+                });
+                if let Some(step) = step {
+                    next = Some((appid, step));
+                    break;
+                }
+            }
+            let (appid, step) = match next {
+                Some(next) => next,
+                None => return,
+            };
+            if let Err(e) = self.start(appid, step) {
+                if step == Step::Restore {
+                    self.restored(appid, Err(e), 0);
+                }
+            }
+        }
+    }
+
+    fn start(&self, appid: ProcessId, step: Step) -> Result<(), ErrorCode> {
+        let short_id = appid.short_id().ok_or(ErrorCode::NOSUPPORT)?.id();
+        let key = self.key.take().ok_or(ErrorCode::RESERVE)?;
+        self.set_key(key, short_id);
+        self.current.set((appid, step));
+        let res = match step {
+            Step::Restore => match self.buffer.take() {
+                Some(buffer) => self.kv.get_value(key, buffer).map_err(|(key, buffer, e)| {
+                    self.key.replace(key);
+                    self.buffer.replace(buffer);
+                    error(e)
+                }),
+                None => {
+                    self.key.replace(key);
+                    Err(ErrorCode::RESERVE)
+                }
+            },
+            Step::Remove => self.kv.invalidate_key(key).map_err(|(key, e)| {
+                self.key.replace(key);
+                error(e)
+            }),
+            Step::Store => {
+                let target_s = self
+                    .apps
+                    .enter(appid, |app| app.target_s)
+                    .map_err(ErrorCode::from)
+                    .and_then(|target_s| target_s.ok_or(ErrorCode::FAIL));
+                match (target_s, self.buffer.take()) {
+                    (Ok(target_s), Some(buffer)) => {
+                        write_record(buffer, short_id, target_s);
+                        self.kv
+                            .append_key(key, buffer, RECORD_LEN)
+                            .map_err(|(key, buffer, e)| {
+                                self.key.replace(key);
+                                self.buffer.replace(buffer);
+                                error(e)
+                            })
+                    }
+                    (target_s, buffer) => {
+                        buffer.map(|buffer| self.buffer.replace(buffer));
+                        self.key.replace(key);
+                        Err(target_s.err().unwrap_or(ErrorCode::RESERVE))
+                    }
+                }
+            }
+        };
+        if res.is_err() {
+            self.current.clear();
+        }
+        res
+    }
+
+    fn restored(&self, appid: ProcessId, result: Result<(), ErrorCode>, target_s: u64) {
+        let _ = self.apps.enter(appid, |app| {
+            app.restore_callback
+                .schedule(kernel::into_statuscode(result), target_s as usize, 0);
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>, S: KVSystem<'a, K = T>, T: 'static + KeyType> time::AlarmClient
+    for WallClockAlarm<'a, A, S, T>
+{
+    fn alarm(&self) {
+        self.check();
+    }
+}
+
+impl<'a, A: Alarm<'a>, S: KVSystem<'a, K = T>, T: 'static + KeyType> kv_system::Client<T>
+    for WallClockAlarm<'a, A, S, T>
+{
+    fn generate_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _unhashed_key: &'static [u8],
+        _key_buf: &'static T,
+    ) {
+        // Keys are hashed by this capsule, it never generates them.
+    }
+
+    fn append_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        key: &'static mut T,
+        value: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.buffer.replace(value);
+        self.current.clear();
+        self.next_operation();
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut T,
+        ret_buf: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        let restored = self.current.take().map(|(appid, _)| {
+            let saved = appid
+                .short_id()
+                .and_then(|short_id| read_record(ret_buf, short_id.id()));
+            let result = match result {
+                // Nothing was saved
+                Err(ErrorCode::NOSUPPORT) => Ok(None),
+                Err(e) => Err(e),
+                Ok(()) => Ok(saved),
+            };
+            (appid, result)
+        });
+        self.buffer.replace(ret_buf);
+
+        if let Some((appid, result)) = restored {
+            let target_s = match result {
+                Ok(Some(target_s)) => {
+                    let _ = self.apps.enter(appid, |app| {
+                        if app.target_s.is_none() {
+                            app.target_s = Some(target_s);
+                        }
+                    });
+                    target_s
+                }
+                _ => 0,
+            };
+            self.restored(appid, result.map(|_| ()), target_s);
+            if target_s != 0 {
+                self.check();
+                return;
+            }
+        }
+        self.next_operation();
+    }
+
+    fn invalidate_key_complete(&self, _result: Result<(), ErrorCode>, key: &'static mut T) {
+        self.key.replace(key);
+        // There may have been nothing saved to invalidate
+        if let Some((appid, _)) = self.current.take() {
+            let stored = self
+                .apps
+                .enter(appid, |app| app.target_s.is_some())
+                .unwrap_or(false);
+            if stored && self.start(appid, Step::Store).is_ok() {
+                return;
+            }
+        }
+        self.next_operation();
+    }
+
+    fn garbage_collect_complete(&self, _result: Result<(), ErrorCode>) {}
+}
+
+impl<'a, A: Alarm<'a>, S: KVSystem<'a, K = T>, T: 'static + KeyType> Driver
+    for WallClockAlarm<'a, A, S, T>
+{
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup an alarm fired callback.
+    /// - `1`: Setup a restore done callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(app_id, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.fired_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.restore_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Set the alarm for `arg1` seconds since 1970.
+    /// - `2`: Cancel the alarm.
+    /// - `3`: Restore the alarm saved before the last reset.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        _arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        let persistent = appid.short_id().is_some();
+        let res = match command_num {
+            0 => return CommandReturn::success(),
+            1 => match self.time.now_us() {
+                None => Err(ErrorCode::OFF),
+                Some(now_us) if wait_s(now_us, arg1 as u64).is_none() => Err(ErrorCode::INVAL),
+                Some(_) => self
+                    .apps
+                    .enter(appid, |app| {
+                        app.target_s = Some(arg1 as u64);
+                        app.save_pending = persistent;
+                    })
+                    .map_err(ErrorCode::from),
+            },
+            2 => self
+                .apps
+                .enter(appid, |app| match app.target_s.take() {
+                    Some(_) => {
+                        app.save_pending = persistent;
+                        Ok(())
+                    }
+                    None => Err(ErrorCode::ALREADY),
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            3 if !persistent => Err(ErrorCode::NOSUPPORT),
+            3 => self
+                .apps
+                .enter(appid, |app| {
+                    let restoring = app.restore_pending
+                        || self.current.map_or(false, |&mut (id, step)| {
+                            id == appid && step == Step::Restore
+                        });
+                    if restoring {
+                        Err(ErrorCode::BUSY)
+                    } else {
+                        app.restore_pending = true;
+                        Ok(())
+                    }
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => {
+                // Must be called outside of the grant region
+                self.check();
+                CommandReturn::success()
+            }
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wait_s() {
+        assert_eq!(wait_s(10_000_000, 10), None);
+        assert_eq!(wait_s(10_000_001, 10), None);
+        assert_eq!(wait_s(9_000_000, 10), Some(1));
+        assert_eq!(wait_s(9_999_999, 10), Some(1));
+        assert_eq!(wait_s(8_500_000, 10), Some(2));
+        assert_eq!(wait_s(0, 3600), Some(MAX_WAIT_S));
+        assert_eq!(wait_s(0, u64::MAX), Some(MAX_WAIT_S));
+    }
+
+    #[test]
+    fn test_record() {
+        let mut buffer = [0; 16];
+        write_record(&mut buffer, 0x1234, 1_700_000_000);
+        assert_eq!(read_record(&buffer, 0x1234), Some(1_700_000_000));
+        assert_eq!(read_record(&buffer, 0x1235), None);
+        assert_eq!(read_record(&buffer[..8], 0x1234), None);
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_ne!(hash(1), hash(2));
+    }
+}
@@ -0,0 +1,911 @@
+//! Spreads erases over a flash region and retires pages that fail.
+//!
+//! `WearLevelingFlash` sits between a `hil::flash::Flash` implementation and
+//! a storage capsule such as TicKV, and implements `hil::flash::Flash`
+//! itself. The storage capsule addresses logical pages, which are mapped onto
+//! the physical pages of the region. The region has more physical pages than
+//! logical ones; the extra pages are spares.
+//!
+//! Every physical page has an erase count. When a logical page is erased and
+//! its physical page has been erased `remap_threshold` times more than the
+//! least worn spare, the logical page moves to that spare instead. Pages a
+//! storage capsule erases often thus move around the region rather than
+//! wearing out one physical page. Pages that fail to erase or write are marked
+//! bad and replaced by a spare, and the operation is retried there.
+//!
+//! The mapping and the erase counts are stored in the first two pages of the
+//! region, which are written alternately. They are written when the mapping
+//! changes, so at most once every `remap_threshold` erases of a page. Erases
+//! that do not move a page are counted in RAM until the mapping is next
+//! written, and are lost if the board resets before that.
+//!
+//! Finding the physical page of a logical page searches the table, so the
+//! cost of an operation grows with the size of the region. The table of a
+//! region must fit in one page: 16 bytes plus 8 bytes per physical page.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//! # use capsules::wear_leveling_flash::{PageEntry, WearLevelingFlash};
+//!
+//! // 64 physical pages, 56 of them exposed, after the two metadata pages.
+//! let table = static_init!([PageEntry; 64], [PageEntry::new(); 64]);
+//! let wear_leveling = static_init!(
+//!     WearLevelingFlash<'static, nrf52840::nvmc::Nvmc>,
+//!     WearLevelingFlash::new(
+//!         &base_peripherals.nvmc,
+//!         0xC0, // First page of the region.
+//!         56,   // Logical pages.
+//!         32,   // Remap threshold.
+//!         table,
+//!         static_init!(nrf52840::nvmc::NrfPage, nrf52840::nvmc::NrfPage::default()),
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(&base_peripherals.nvmc, wear_leveling);
+//! wear_leveling.initialize().unwrap();
+//! ```
+//!
+//! One operation requested before initialization finishes is run once it
+//! has. After that, operations are refused with `BUSY` while another one is
+//! in progress.
+
+use core::cell::Cell;
+use core::convert::TryInto;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::flash::Error;
+use kernel::ErrorCode;
+
+const MAGIC: u32 = 0x5746_4c31;
+const HEADER_LEN: usize = 16;
+const ENTRY_LEN: usize = 8;
+const METADATA_PAGES: usize = 2;
+
+/// `PageEntry::logical` of a spare page.
+const FREE: u16 = 0xffff;
+/// `PageEntry::logical` of a page that failed.
+const BAD: u16 = 0xfffe;
+
+/// What is known about a physical page.
+#[derive(Clone, Copy)]
+pub struct PageEntry {
+    /// Logical page stored in the page, `FREE` or `BAD`.
+    logical: u16,
+    erase_count: u32,
+}
+
+impl PageEntry {
+    pub const fn new() -> PageEntry {
+        PageEntry {
+            logical: FREE,
+            erase_count: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Uninitialized,
+    /// Reading the table from a metadata page.
+    ReadMetadata(usize),
+    Idle,
+    Read,
+    /// Writing a physical page.
+    Write(usize),
+    /// Erasing a physical page for a logical erase.
+    Erase(usize),
+    /// Erasing a spare to retry a failed write on.
+    EraseSpare(usize),
+    EraseMetadata(usize),
+    WriteMetadata(usize),
+}
+
+/// An operation requested before initialization finished.
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Idle,
+    Read(usize),
+    Write(usize),
+    Erase(usize),
+}
+
+/// What to tell the client once the table has been written.
+#[derive(Clone, Copy)]
+enum Report {
+    Initialized,
+    Write(Error),
+    Erase(Error),
+}
+
+fn checksum(header: &[u8], entries: &[u8]) -> u32 {
+    header
+        .iter()
+        .chain(entries.iter())
+        .fold(0, |sum: u32, byte| {
+            sum.rotate_left(5).wrapping_add(*byte as u32)
+        })
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or([0; 4]))
+}
+
+pub struct WearLevelingFlash<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    client: OptionalCell<&'a dyn hil::flash::Client<WearLevelingFlash<'a, F>>>,
+    /// First physical page of the region, holding the first metadata page.
+    start_page: usize,
+    num_logical: usize,
+    remap_threshold: u32,
+    /// Entry of every physical page after the metadata pages.
+    table: TakeCell<'static, [PageEntry]>,
+    /// Whether `table` differs from the stored one.
+    dirty: Cell<bool>,
+    metadata: TakeCell<'static, F::Page>,
+    /// Metadata page holding the latest table.
+    metadata_slot: Cell<usize>,
+    sequence: OptionalCell<u32>,
+    state: Cell<State>,
+    /// Whether the table has been loaded, or the region set up.
+    initialized: Cell<bool>,
+    pending: Cell<Op>,
+    report: Cell<Report>,
+    /// Client buffer of the operation in progress.
+    buffer: TakeCell<'static, F::Page>,
+}
+
+impl<'a, F: hil::flash::Flash> WearLevelingFlash<'a, F> {
+    pub fn new(
+        flash: &'a F,
+        start_page: usize,
+        num_logical: usize,
+        remap_threshold: u32,
+        table: &'static mut [PageEntry],
+        metadata: &'static mut F::Page,
+    ) -> WearLevelingFlash<'a, F> {
+        WearLevelingFlash {
+            flash: flash,
+            client: OptionalCell::empty(),
+            start_page: start_page,
+            num_logical: num_logical,
+            remap_threshold: remap_threshold,
+            table: TakeCell::new(table),
+            dirty: Cell::new(false),
+            metadata: TakeCell::new(metadata),
+            metadata_slot: Cell::new(METADATA_PAGES - 1),
+            sequence: OptionalCell::empty(),
+            state: Cell::new(State::Uninitialized),
+            initialized: Cell::new(false),
+            pending: Cell::new(Op::Idle),
+            report: Cell::new(Report::Initialized),
+            buffer: TakeCell::empty(),
+        }
+    }
+
+    /// Load the table from flash, or set the region up if it has none.
+    pub fn initialize(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Uninitialized {
+            return Err(ErrorCode::ALREADY);
+        }
+        let pages = self.table.map_or(0, |table| table.len());
+        let fits = self.metadata.map_or(false, |page| {
+            page.as_mut().len() >= HEADER_LEN + ENTRY_LEN * pages
+        });
+        if !fits || self.num_logical > pages || self.num_logical >= BAD as usize {
+            return Err(ErrorCode::SIZE);
+        }
+        self.read_metadata(0)
+    }
+
+    fn physical_page(&self, index: usize) -> usize {
+        self.start_page + METADATA_PAGES + index
+    }
+
+    /// Index of the physical page holding `logical`.
+    fn lookup(&self, logical: usize) -> Option<usize> {
+        self.table.map_or(None, |table| {
+            table
+                .iter()
+                .position(|entry| entry.logical as usize == logical)
+        })
+    }
+
+    /// Index of the least worn spare.
+    fn coldest_spare(&self) -> Option<usize> {
+        self.table.map_or(None, |table| {
+            table
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| entry.logical == FREE)
+                .min_by_key(|(_, entry)| entry.erase_count)
+                .map(|(index, _)| index)
+        })
+    }
+
+    fn erase_count(&self, index: usize) -> u32 {
+        self.table.map_or(0, |table| table[index].erase_count)
+    }
+
+    fn count_erase(&self, index: usize) {
+        self.table.map(|table| {
+            table[index].erase_count = table[index].erase_count.saturating_add(1);
+        });
+    }
+
+    /// Move the logical page stored in physical page `from` to `to`.
+    fn move_page(&self, from: usize, to: usize, from_state: u16) {
+        self.table.map(|table| {
+            table[to].logical = table[from].logical;
+            table[from].logical = from_state;
+        });
+        self.dirty.set(true);
+    }
+
+    /// Mark physical page `index` bad and move its logical page to a spare.
+    /// Returns the spare, or `None` if there are none left, in which case
+    /// the page stays in use.
+    fn retire(&self, index: usize) -> Option<usize> {
+        let spare = self.coldest_spare()?;
+        self.move_page(index, spare, BAD);
+        Some(spare)
+    }
+
+    fn read_metadata(&self, slot: usize) -> Result<(), ErrorCode> {
+        let page = self.metadata.take().ok_or(ErrorCode::NOMEM)?;
+        self.state.set(State::ReadMetadata(slot));
+        self.flash
+            .read_page(self.start_page + slot, page)
+            .map_err(|(e, page)| {
+                self.metadata.replace(page);
+                self.state.set(State::Uninitialized);
+                e
+            })
+    }
+
+    /// Load the table from a metadata page if it is valid and newer than the
+    /// table loaded so far.
+    fn load_metadata(&self, slot: usize, page: &mut F::Page) {
+        let bytes = page.as_mut();
+        let pages = self.table.map_or(0, |table| table.len());
+        let entries_end = HEADER_LEN + ENTRY_LEN * pages;
+        if read_u32(bytes, 0) != MAGIC
+            || read_u32(bytes, 8) as usize != pages
+            || read_u32(bytes, 12) != checksum(&bytes[..12], &bytes[HEADER_LEN..entries_end])
+        {
+            return;
+        }
+        let sequence = read_u32(bytes, 4);
+        let newer = self
+            .sequence
+            .map_or(true, |loaded| sequence.wrapping_sub(*loaded) as i32 > 0);
+        if !newer {
+            return;
+        }
+
+        self.table.map(|table| {
+            for (entry, stored) in table
+                .iter_mut()
+                .zip(bytes[HEADER_LEN..entries_end].chunks(ENTRY_LEN))
+            {
+                entry.logical = u16::from_le_bytes([stored[0], stored[1]]);
+                entry.erase_count = read_u32(stored, 4);
+            }
+        });
+        self.sequence.set(sequence);
+        self.metadata_slot.set(slot);
+    }
+
+    /// Map every logical page to the physical page of the same index.
+    fn format(&self) {
+        let num_logical = self.num_logical;
+        self.table.map(|table| {
+            for (index, entry) in table.iter_mut().enumerate() {
+                entry.logical = if index < num_logical {
+                    index as u16
+                } else {
+                    FREE
+                };
+                entry.erase_count = 0;
+            }
+        });
+        self.sequence.set(0);
+    }
+
+    /// Write the table to the older metadata page, then report `report`.
+    fn save_metadata(&self, report: Report) {
+        self.dirty.set(false);
+        self.report.set(report);
+        let slot = (self.metadata_slot.get() + 1) % METADATA_PAGES;
+        self.state.set(State::EraseMetadata(slot));
+        if self.flash.erase_page(self.start_page + slot).is_err() {
+            self.dirty.set(true);
+            self.report_done(report);
+        }
+    }
+
+    fn encode_metadata(&self, page: &mut F::Page) {
+        let bytes = page.as_mut();
+        let sequence = self.sequence.map_or(0, |sequence| sequence.wrapping_add(1));
+        let pages = self.table.map_or(0, |table| {
+            for (entry, stored) in table.iter().zip(bytes[HEADER_LEN..].chunks_mut(ENTRY_LEN)) {
+                stored[0..2].copy_from_slice(&entry.logical.to_le_bytes());
+                stored[2..4].copy_from_slice(&[0xff, 0xff]);
+                stored[4..8].copy_from_slice(&entry.erase_count.to_le_bytes());
+            }
+            table.len()
+        });
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&sequence.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(pages as u32).to_le_bytes());
+        let sum = checksum(
+            &bytes[..12],
+            &bytes[HEADER_LEN..HEADER_LEN + ENTRY_LEN * pages],
+        );
+        bytes[12..16].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    /// Finish a client operation, saving the table first if it changed.
+    fn finish(&self, report: Report) {
+        if self.dirty.get() {
+            self.save_metadata(report);
+        } else {
+            self.report_done(report);
+        }
+    }
+
+    fn report_done(&self, report: Report) {
+        self.state.set(State::Idle);
+        match report {
+            Report::Initialized => {
+                self.initialized.set(true);
+                self.run_pending();
+            }
+            Report::Write(error) => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.write_complete(buffer, error));
+                });
+            }
+            Report::Erase(error) => {
+                self.client.map(|client| client.erase_complete(error));
+            }
+        }
+    }
+
+    /// Whether a new operation has to wait for initialization to finish.
+    /// Only one can, and none once it has: the buffer of the operation in
+    /// progress must not be replaced.
+    fn can_queue(&self) -> bool {
+        !self.initialized.get() && self.pending.get() == Op::Idle
+    }
+
+    /// Run an operation requested before initialization finished.
+    fn run_pending(&self) {
+        match self.pending.replace(Op::Idle) {
+            Op::Idle => {}
+            Op::Read(logical) => {
+                self.buffer.take().map(|buffer| {
+                    if let Err((_, buffer)) = self.read(logical, buffer) {
+                        self.client
+                            .map(move |client| client.read_complete(buffer, Error::FlashError));
+                    }
+                });
+            }
+            Op::Write(logical) => {
+                self.buffer.take().map(|buffer| {
+                    if let Err((_, buffer)) = self.write(logical, buffer) {
+                        self.client
+                            .map(move |client| client.write_complete(buffer, Error::FlashError));
+                    }
+                });
+            }
+            Op::Erase(logical) => {
+                if self.erase(logical).is_err() {
+                    self.client
+                        .map(|client| client.erase_complete(Error::FlashError));
+                }
+            }
+        }
+    }
+
+    fn read(
+        &self,
+        logical: usize,
+        buffer: &'static mut F::Page,
+    ) -> Result<(), (ErrorCode, &'static mut F::Page)> {
+        let index = match self.lookup(logical) {
+            Some(index) => index,
+            None => return Err((ErrorCode::FAIL, buffer)),
+        };
+        self.state.set(State::Read);
+        self.flash
+            .read_page(self.physical_page(index), buffer)
+            .map_err(|e| {
+                self.state.set(State::Idle);
+                e
+            })
+    }
+
+    fn write(
+        &self,
+        logical: usize,
+        buffer: &'static mut F::Page,
+    ) -> Result<(), (ErrorCode, &'static mut F::Page)> {
+        let index = match self.lookup(logical) {
+            Some(index) => index,
+            None => return Err((ErrorCode::FAIL, buffer)),
+        };
+        self.state.set(State::Write(index));
+        self.flash
+            .write_page(self.physical_page(index), buffer)
+            .map_err(|e| {
+                self.state.set(State::Idle);
+                e
+            })
+    }
+
+    fn erase(&self, logical: usize) -> Result<(), ErrorCode> {
+        let mut index = self.lookup(logical).ok_or(ErrorCode::FAIL)?;
+        if let Some(spare) = self.coldest_spare() {
+            let worn = self.erase_count(index);
+            if worn >= self.erase_count(spare).saturating_add(self.remap_threshold) {
+                self.move_page(index, spare, FREE);
+                index = spare;
+            }
+        }
+        self.state.set(State::Erase(index));
+        self.flash
+            .erase_page(self.physical_page(index))
+            .map_err(|e| {
+                self.state.set(State::Idle);
+                e
+            })
+    }
+
+    /// Erase the spare a failed write is retried on.
+    fn erase_spare(&self, index: usize) -> Result<(), ErrorCode> {
+        self.state.set(State::EraseSpare(index));
+        self.flash.erase_page(self.physical_page(index))
+    }
+}
+
+impl<'a, F: hil::flash::Flash, C: hil::flash::Client<Self>> hil::flash::HasClient<'a, C>
+    for WearLevelingFlash<'a, F>
+{
+    fn set_client(&'a self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for WearLevelingFlash<'_, F> {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, error: Error) {
+        match self.state.get() {
+            State::ReadMetadata(slot) => {
+                if error == Error::CommandComplete {
+                    self.load_metadata(slot, pagebuffer);
+                }
+                self.metadata.replace(pagebuffer);
+                if slot + 1 < METADATA_PAGES && self.read_metadata(slot + 1).is_ok() {
+                    return;
+                }
+                if self.sequence.is_none() {
+                    self.format();
+                    self.save_metadata(Report::Initialized);
+                } else {
+                    self.report_done(Report::Initialized);
+                }
+            }
+            _ => {
+                self.state.set(State::Idle);
+                self.client
+                    .map(move |client| client.read_complete(pagebuffer, error));
+            }
+        }
+    }
+
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: Error) {
+        match self.state.get() {
+            State::WriteMetadata(slot) => {
+                self.metadata.replace(pagebuffer);
+                if error == Error::CommandComplete {
+                    self.metadata_slot.set(slot);
+                    self.sequence
+                        .set(self.sequence.map_or(0, |sequence| sequence.wrapping_add(1)));
+                } else {
+                    self.dirty.set(true);
+                }
+                self.report_done(self.report.get());
+            }
+            State::Write(index) => {
+                self.buffer.replace(pagebuffer);
+                if error == Error::FlashError {
+                    if let Some(spare) = self.retire(index) {
+                        if self.erase_spare(spare).is_ok() {
+                            return;
+                        }
+                    }
+                }
+                self.finish(Report::Write(error));
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_complete(&self, error: Error) {
+        match self.state.get() {
+            State::EraseMetadata(slot) => {
+                let written = if error == Error::CommandComplete {
+                    self.metadata.take().map_or(false, |page| {
+                        self.encode_metadata(page);
+                        self.state.set(State::WriteMetadata(slot));
+                        self.flash
+                            .write_page(self.start_page + slot, page)
+                            .map_err(|(_, page)| self.metadata.replace(page))
+                            .is_ok()
+                    })
+                } else {
+                    false
+                };
+                if !written {
+                    self.dirty.set(true);
+                    self.report_done(self.report.get());
+                }
+            }
+            State::Erase(index) => {
+                if error == Error::CommandComplete {
+                    self.count_erase(index);
+                } else if let Some(spare) = self.retire(index) {
+                    self.state.set(State::Erase(spare));
+                    if self.flash.erase_page(self.physical_page(spare)).is_ok() {
+                        return;
+                    }
+                }
+                self.finish(Report::Erase(error));
+            }
+            State::EraseSpare(index) => {
+                let retried = if error == Error::CommandComplete {
+                    self.count_erase(index);
+                    self.buffer.take().map_or(false, |buffer| {
+                        self.state.set(State::Write(index));
+                        self.flash
+                            .write_page(self.physical_page(index), buffer)
+                            .map_err(|(_, buffer)| self.buffer.replace(buffer))
+                            .is_ok()
+                    })
+                } else {
+                    self.retire(index)
+                        .map_or(false, |spare| self.erase_spare(spare).is_ok())
+                };
+                if !retried {
+                    self.finish(Report::Write(Error::FlashError));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Flash for WearLevelingFlash<'_, F> {
+    type Page = F::Page;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if page_number >= self.num_logical {
+            Err((ErrorCode::INVAL, buf))
+        } else if self.state.get() == State::Idle {
+            self.read(page_number, buf)
+        } else if self.can_queue() {
+            self.buffer.replace(buf);
+            self.pending.set(Op::Read(page_number));
+            Ok(())
+        } else {
+            Err((ErrorCode::BUSY, buf))
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if page_number >= self.num_logical {
+            Err((ErrorCode::INVAL, buf))
+        } else if self.state.get() == State::Idle {
+            self.write(page_number, buf)
+        } else if self.can_queue() {
+            self.buffer.replace(buf);
+            self.pending.set(Op::Write(page_number));
+            Ok(())
+        } else {
+            Err((ErrorCode::BUSY, buf))
+        }
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        if page_number >= self.num_logical {
+            Err(ErrorCode::INVAL)
+        } else if self.state.get() == State::Idle {
+            self.erase(page_number)
+        } else if self.can_queue() {
+            self.pending.set(Op::Erase(page_number));
+            Ok(())
+        } else {
+            Err(ErrorCode::BUSY)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{PageEntry, WearLevelingFlash, METADATA_PAGES};
+    use core::cell::{Cell, RefCell};
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::flash::{self, Error, Flash};
+    use kernel::ErrorCode;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const PAGE_LEN: usize = 64;
+    /// Physical pages after the metadata pages, as many as fit the table in
+    /// a page.
+    const PAGES: usize = 6;
+    const LOGICAL: usize = 4;
+
+    pub struct TestPage([u8; PAGE_LEN]);
+
+    impl Default for TestPage {
+        fn default() -> TestPage {
+            TestPage([0; PAGE_LEN])
+        }
+    }
+
+    impl AsMut<[u8]> for TestPage {
+        fn as_mut(&mut self) -> &mut [u8] {
+            &mut self.0
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum Access {
+        Read(usize),
+        Write(usize),
+        Erase(usize),
+    }
+
+    /// Flash that completes an access when the test pumps it.
+    struct MockFlash {
+        pages: RefCell<Vec<[u8; PAGE_LEN]>>,
+        access: Cell<Option<Access>>,
+        buffer: TakeCell<'static, TestPage>,
+    }
+
+    impl Flash for MockFlash {
+        type Page = TestPage;
+
+        fn read_page(
+            &self,
+            page_number: usize,
+            buf: &'static mut TestPage,
+        ) -> Result<(), (ErrorCode, &'static mut TestPage)> {
+            assert!(self.access.get().is_none());
+            self.access.set(Some(Access::Read(page_number)));
+            self.buffer.replace(buf);
+            Ok(())
+        }
+
+        fn write_page(
+            &self,
+            page_number: usize,
+            buf: &'static mut TestPage,
+        ) -> Result<(), (ErrorCode, &'static mut TestPage)> {
+            assert!(self.access.get().is_none());
+            self.access.set(Some(Access::Write(page_number)));
+            self.buffer.replace(buf);
+            Ok(())
+        }
+
+        fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+            assert!(self.access.get().is_none());
+            self.access.set(Some(Access::Erase(page_number)));
+            Ok(())
+        }
+    }
+
+    struct MockClient {
+        buffer: TakeCell<'static, TestPage>,
+        results: RefCell<Vec<(Access, Error)>>,
+    }
+
+    impl flash::Client<WearLevelingFlash<'static, MockFlash>> for MockClient {
+        fn read_complete(&self, buffer: &'static mut TestPage, error: Error) {
+            self.results
+                .borrow_mut()
+                .push((Access::Read(buffer.0[0] as usize), error));
+            self.buffer.replace(buffer);
+        }
+
+        fn write_complete(&self, buffer: &'static mut TestPage, error: Error) {
+            self.results
+                .borrow_mut()
+                .push((Access::Write(buffer.0[0] as usize), error));
+            self.buffer.replace(buffer);
+        }
+
+        fn erase_complete(&self, error: Error) {
+            self.results.borrow_mut().push((Access::Erase(0), error));
+        }
+    }
+
+    struct Harness {
+        flash: &'static MockFlash,
+        wear_leveling: &'static WearLevelingFlash<'static, MockFlash>,
+        client: &'static MockClient,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let flash = Box::leak(Box::new(MockFlash {
+                pages: RefCell::new([[0xff; PAGE_LEN]; METADATA_PAGES + PAGES].to_vec()),
+                access: Cell::new(None),
+                buffer: TakeCell::empty(),
+            }));
+            let wear_leveling = Box::leak(Box::new(WearLevelingFlash::new(
+                &*flash,
+                0,
+                LOGICAL,
+                2,
+                Box::leak(Box::new([PageEntry::new(); PAGES])),
+                Box::leak(Box::new(TestPage::default())),
+            )));
+            let client = Box::leak(Box::new(MockClient {
+                buffer: TakeCell::new(Box::leak(Box::new(TestPage::default()))),
+                results: RefCell::new(Vec::new()),
+            }));
+            flash::HasClient::set_client(&*wear_leveling, &*client);
+            Harness {
+                flash,
+                wear_leveling,
+                client,
+            }
+        }
+
+        /// Complete flash accesses until there are none left.
+        fn pump(&self) {
+            while let Some(access) = self.flash.access.take() {
+                let mut pages = self.flash.pages.borrow_mut();
+                match access {
+                    Access::Read(page) => {
+                        let buffer = self.flash.buffer.take().unwrap();
+                        buffer.0 = pages[page];
+                        drop(pages);
+                        flash::Client::read_complete(
+                            self.wear_leveling,
+                            buffer,
+                            Error::CommandComplete,
+                        );
+                    }
+                    Access::Write(page) => {
+                        let buffer = self.flash.buffer.take().unwrap();
+                        pages[page] = buffer.0;
+                        drop(pages);
+                        flash::Client::write_complete(
+                            self.wear_leveling,
+                            buffer,
+                            Error::CommandComplete,
+                        );
+                    }
+                    Access::Erase(page) => {
+                        pages[page] = [0xff; PAGE_LEN];
+                        drop(pages);
+                        flash::Client::erase_complete(self.wear_leveling, Error::CommandComplete);
+                    }
+                }
+            }
+        }
+
+        /// A client buffer whose first byte is `tag`.
+        fn buffer(&self, tag: u8) -> &'static mut TestPage {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer.0 = [tag; PAGE_LEN];
+            buffer
+        }
+    }
+
+    #[test]
+    fn test_pending_before_initialization() {
+        let h = Harness::new();
+        let buffer = h.buffer(7);
+        assert!(h.wear_leveling.write_page(1, buffer).is_ok());
+        // Only one operation can wait.
+        assert_eq!(h.wear_leveling.erase_page(2), Err(ErrorCode::BUSY));
+
+        assert!(h.wear_leveling.initialize().is_ok());
+        h.pump();
+        assert_eq!(
+            h.client.results.take(),
+            [(Access::Write(7), Error::CommandComplete)]
+        );
+        // Logical pages start on the physical page of the same index.
+        assert_eq!(h.flash.pages.borrow()[METADATA_PAGES + 1], [7; PAGE_LEN]);
+    }
+
+    #[test]
+    fn test_busy() {
+        let h = Harness::new();
+        assert!(h.wear_leveling.initialize().is_ok());
+        h.pump();
+
+        let buffer = h.buffer(1);
+        assert!(h.wear_leveling.write_page(0, buffer).is_ok());
+        let other = Box::leak(Box::new(TestPage([2; PAGE_LEN])));
+        let other = match h.wear_leveling.write_page(1, other) {
+            Err((ErrorCode::BUSY, other)) => other,
+            _ => panic!("a write was accepted during another"),
+        };
+        let other = match h.wear_leveling.read_page(1, other) {
+            Err((ErrorCode::BUSY, other)) => other,
+            _ => panic!("a read was accepted during a write"),
+        };
+        assert_eq!(h.wear_leveling.erase_page(1), Err(ErrorCode::BUSY));
+
+        // The write in progress still completes with its own buffer.
+        h.pump();
+        assert_eq!(
+            h.client.results.take(),
+            [(Access::Write(1), Error::CommandComplete)]
+        );
+        assert!(h.wear_leveling.write_page(1, other).is_ok());
+        h.pump();
+        assert_eq!(h.flash.pages.borrow()[METADATA_PAGES], [1; PAGE_LEN]);
+        assert_eq!(h.flash.pages.borrow()[METADATA_PAGES + 1], [2; PAGE_LEN]);
+    }
+
+    #[test]
+    fn test_remap() {
+        let h = Harness::new();
+        assert!(h.wear_leveling.initialize().is_ok());
+        h.pump();
+
+        // Erasing logical page 0 past the threshold moves it to a spare.
+        for _ in 0..3 {
+            assert!(h.wear_leveling.erase_page(0).is_ok());
+            h.pump();
+        }
+        let buffer = h.buffer(9);
+        assert!(h.wear_leveling.write_page(0, buffer).is_ok());
+        h.pump();
+        h.client.results.take();
+        assert_ne!(h.flash.pages.borrow()[METADATA_PAGES], [9; PAGE_LEN]);
+
+        // It reads back from where it went.
+        let buffer = h.buffer(0);
+        assert!(h.wear_leveling.read_page(0, buffer).is_ok());
+        h.pump();
+        assert_eq!(
+            h.client.results.take(),
+            [(Access::Read(9), Error::CommandComplete)]
+        );
+
+        // And the mapping survives a restart.
+        let pages = h.flash.pages.borrow().clone();
+        let h = Harness::new();
+        *h.flash.pages.borrow_mut() = pages;
+        assert!(h.wear_leveling.initialize().is_ok());
+        h.pump();
+        let buffer = h.buffer(0);
+        assert!(h.wear_leveling.read_page(0, buffer).is_ok());
+        h.pump();
+        assert_eq!(
+            h.client.results.take(),
+            [(Access::Read(9), Error::CommandComplete)]
+        );
+    }
+}
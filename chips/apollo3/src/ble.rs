@@ -254,6 +254,16 @@ register_bitfields![u32,
 
 static mut PAYLOAD: [u8; 40] = [0x00; 40];
 
+/// Received packets, kept apart from `PAYLOAD` so an event read while a write
+/// is pending does not overwrite the data being sent. The receive client
+/// takes the length as a `u8`, so this holds the longest packet it can be
+/// given, rounded up to the FIFO word size.
+static mut RX_PAYLOAD: [u8; 256] = [0x00; 256];
+
+/// HCI packet types, the first byte of every packet.
+const HCI_ACL_DATA: u8 = 0x02;
+const HCI_EVENT: u8 = 0x04;
+
 pub struct Ble<'a> {
     registers: StaticRef<BleRegisters>,
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
@@ -313,6 +323,10 @@ impl<'a> Ble<'a> {
         self.registers.cqcfg.modify(CQCFG::CQEN::CLEAR);
 
         // TODO: Apply the BLE patch
+
+        // Listen for events from the controller, which it can send at any
+        // time, not only in response to our writes.
+        self.enable_interrupts();
     }
 
     fn reset_fifo(&self) {
@@ -351,27 +365,104 @@ impl<'a> Ble<'a> {
         self.registers.blecfg.modify(BLECFG::WAKEUPCTL::OFF);
     }
 
+    /// The length of the HCI packet starting with `header`, or `None` if
+    /// not enough of the header has been read to know it.
+    fn packet_len(header: &[u8]) -> Option<usize> {
+        match header.first()? {
+            // type, event code, parameter length
+            &HCI_EVENT => Some(3 + *header.get(2)? as usize),
+            // type, handle (2), data length (2)
+            &HCI_ACL_DATA => {
+                Some(5 + u16::from_le_bytes([*header.get(3)?, *header.get(4)?]) as usize)
+            }
+            // Anything else is reported as it was read.
+            _ => None,
+        }
+    }
+
+    /// Read a packet the controller has raised BLEIRQ for and pass it to the
+    /// receive client.
+    ///
+    /// The FIFO is drained until the whole packet, as long as its HCI header
+    /// says, has been read, so packets longer than the FIFO threshold are not
+    /// cut short. A packet longer than `RX_PAYLOAD` is drained from the FIFO
+    /// and reported with `SIZE`.
+    fn receive_data(&self) {
+        self.registers
+            .cmd
+            .modify(CMD::TSIZE.val(0) + CMD::CMD::READ);
+
+        let mut len = 0;
+        let mut packet_len = None;
+        // Bound the wait for the rest of a packet, so a controller that stops
+        // mid-packet does not hang the kernel.
+        let mut spins = 0;
+        unsafe {
+            loop {
+                if packet_len.map_or(false, |packet_len| len >= packet_len) {
+                    break;
+                }
+                if self.registers.fifoptr.read(FIFOPTR::FIFO1SIZ) < 4 {
+                    // Without a length, all of the packet that is coming is
+                    // what is in the FIFO.
+                    if packet_len.is_none() || spins >= 10_000 {
+                        break;
+                    }
+                    spins += 1;
+                    continue;
+                }
+
+                let temp = self.registers.fifopop.get().to_ne_bytes();
+                if len + 4 <= RX_PAYLOAD.len() {
+                    RX_PAYLOAD[len..len + 4].copy_from_slice(&temp);
+                }
+                len += 4;
+
+                if packet_len.is_none() {
+                    packet_len = Self::packet_len(&RX_PAYLOAD[..len.min(RX_PAYLOAD.len())]);
+                }
+            }
+
+            let len = packet_len.map_or(len, |packet_len| packet_len.min(len));
+            let result = if len > u8::MAX as usize {
+                Err(ErrorCode::SIZE)
+            } else {
+                Ok(())
+            };
+            self.rx_client.map(|client| {
+                client.receive_event(&mut RX_PAYLOAD, len.min(u8::MAX as usize) as u8, result);
+            });
+        }
+    }
+
     pub fn handle_interrupt(&self) {
         let irqs = self.registers.intstat.extract();
 
         // Disable and clear interrupts
         self.disable_interrupts();
 
-        if irqs.is_set(INT::BLECSSTAT) || irqs.is_set(INT::B2MST) {
-            // Enable interrupts
-            self.enable_interrupts();
+        // Both interrupts below can report the same pending packet. Read it
+        // once: a second read would find the FIFO empty and hand the client
+        // a zero length packet, and the client may still hold `RX_PAYLOAD`.
+        let mut received = false;
 
-            if self.registers.bstatus.is_set(BSTATUS::BLEIRQ) {
-                panic!("Read requested while trying to write");
-            }
-
-            if !self.registers.bstatus.is_set(BSTATUS::SPISTATUS) {
-                panic!("SPI not ready");
-            }
+        if irqs.is_set(INT::BLECIRQ) {
+            // The controller has an event for us, whether or not we asked
+            // for one.
+            self.receive_data();
+            received = true;
+        }
 
-            // If we have data, send it
-            if self.buffer.is_some() {
-                // Send the data
+        if irqs.is_set(INT::BLECSSTAT) || irqs.is_set(INT::B2MST) {
+            if self.registers.bstatus.is_set(BSTATUS::BLEIRQ) {
+                // The controller wants to send before it accepts our write.
+                // Read its event first; the write goes out on the next
+                // status interrupt.
+                if !received {
+                    self.receive_data();
+                }
+            } else if self.buffer.is_some() && self.registers.bstatus.is_set(BSTATUS::SPISTATUS) {
+                // If we have data and the controller is ready, send it
                 self.send_data();
             }
         }
@@ -386,43 +477,25 @@ impl<'a> Ble<'a> {
             // Reset FIFOs
             self.reset_fifo();
 
-            if self.buffer.is_some() {
-                self.tx_client.map(|client| {
-                    client.transmit_event(self.buffer.take().unwrap(), Ok(()));
+            if let Some(buffer) = self.buffer.take() {
+                self.tx_client.map(move |client| {
+                    client.transmit_event(buffer, Ok(()));
                 });
             }
-
-            self.enable_interrupts();
         }
 
-        if irqs.is_set(INT::BLECIRQ) {
-            self.rx_client.map(|client| {
-                self.registers
-                    .cmd
-                    .modify(CMD::TSIZE.val(0) + CMD::CMD::READ);
-
-                unsafe {
-                    let mut i = 0;
-
-                    while self.registers.fifoptr.read(FIFOPTR::FIFO1SIZ) > 0 && i < 40 {
-                        let temp = self.registers.fifopop.get().to_ne_bytes();
-
-                        PAYLOAD[i + 0] = temp[0];
-                        PAYLOAD[i + 1] = temp[1];
-                        PAYLOAD[i + 2] = temp[2];
-                        PAYLOAD[i + 3] = temp[3];
-
-                        i = i + 4;
-                    }
-
-                    client.receive_event(&mut PAYLOAD, 10, Ok(()));
-                }
-            });
-        }
+        self.enable_interrupts();
     }
 
     pub fn enable_interrupts(&self) {
-        self.registers.inten.set(0x18381);
+        self.registers.inten.write(
+            INT::CMDCMP::SET
+                + INT::BLECIRQ::SET
+                + INT::BLECSSTAT::SET
+                + INT::DCMP::SET
+                + INT::B2MACTIVE::SET
+                + INT::B2MSHUTDN::SET,
+        );
     }
 
     pub fn disable_interrupts(&self) {
@@ -430,9 +503,9 @@ impl<'a> Ble<'a> {
         self.registers.inten.set(0x00);
     }
 
-    fn replace_radio_buffer(&self, buf: &'static mut [u8]) -> &'static mut [u8] {
+    fn replace_radio_buffer(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8] {
         // set payload
-        for (i, c) in buf.as_ref().iter().enumerate() {
+        for (i, c) in buf.as_ref().iter().take(len).enumerate() {
             unsafe {
                 PAYLOAD[i] = *c;
             }
@@ -443,7 +516,7 @@ impl<'a> Ble<'a> {
 
 impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Ble<'a> {
     fn transmit_advertisement(&self, buf: &'static mut [u8], len: usize, _channel: RadioChannel) {
-        let res = self.replace_radio_buffer(buf);
+        let res = self.replace_radio_buffer(buf, len);
 
         // Setup all of the buffers
         self.buffer.replace(res);
@@ -474,10 +547,64 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Ble<'a> {
     fn set_transmit_client(&self, client: &'a dyn ble_advertising::TxClient) {
         self.tx_client.set(client);
     }
+    fn last_rssi(&self) -> Option<i8> {
+        None
+    }
+}
+
+impl<'a> ble_advertising::BleExtendedAdvertisementDriver<'a> for Ble<'a> {
+    fn supports_secondary_phy(&self, _phy: ble_advertising::Phy) -> bool {
+        false
+    }
+
+    fn aux_offset_us(&self) -> u32 {
+        0
+    }
+
+    fn transmit_extended_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        _primary_len: usize,
+        _aux_len: usize,
+        _channel: RadioChannel,
+        _secondary: RadioChannel,
+        _phy: ble_advertising::Phy,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        Err((ErrorCode::NOSUPPORT, buf))
+    }
+}
+
+impl<'a> ble_advertising::BleScanResponseDriver<'a> for Ble<'a> {
+    fn supports_scan_response(&self) -> bool {
+        false
+    }
+
+    fn transmit_scannable_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        _adv_len: usize,
+        _rsp_len: usize,
+        _channel: RadioChannel,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        Err((ErrorCode::NOSUPPORT, buf))
+    }
 }
 
 impl ble_advertising::BleConfig for Ble<'_> {
+    fn check_tx_power(&self, _tx_power: u8) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
     fn set_tx_power(&self, _tx_power: u8) -> Result<(), ErrorCode> {
         Ok(())
     }
+
+    // The BLE core advertises on all three channels itself
+    fn check_channel_map(&self, channel_map: u8) -> Result<(), ErrorCode> {
+        if channel_map == 0b111 {
+            Ok(())
+        } else {
+            Err(ErrorCode::NOSUPPORT)
+        }
+    }
 }
@@ -0,0 +1,331 @@
+//! Capacitive sensing with the voltage comparator, timed with CTIMER.
+//!
+//! Each pad is measured by discharging it, releasing it with its pull-up
+//! enabled and timing how long it takes to charge past a reference voltage.
+//! The pad is an external input of the voltage comparator (VCOMP), which
+//! compares it against the reference from its DAC and interrupts once the
+//! pad is above it. A finger near the pad adds capacitance, so the pad
+//! takes longer to charge. The charge time of a pad is only a few ticks of
+//! the fastest CTIMER clock, so every measurement sums `SAMPLES` charge
+//! cycles.
+//!
+//! The comparator has two external inputs, so there are at most two
+//! channels: channel 0 is the pad wired to VEXT1, and channel 1 the pad
+//! wired to VEXT2.
+//!
+//! A CTIMER timer segment, claimed from `ctimer::CTimer` and given with the
+//! pads, counts each phase of a measurement. Its compare interrupt ends the
+//! discharge, and stops a charge that takes so long the pad must be
+//! shorted. Nothing waits in an interrupt handler: each step of
+//! a measurement is started from the interrupt that ends the previous one.
+
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::registers::{register_bitfields, register_structs, ReadOnly, ReadWrite};
+use kernel::common::StaticRef;
+use kernel::hil::capsense;
+use kernel::hil::gpio;
+use kernel::ErrorCode;
+
+use crate::ctimer::{CTimerRegisters, Segment, TimerSegment, CMPR, CTIMER_BASE, CTRL, TMR};
+
+const VCOMP_BASE: StaticRef<VCompRegisters> =
+    unsafe { StaticRef::new(0x4000_C000 as *const VCompRegisters) };
+
+/// Charge cycles summed for each measurement.
+const SAMPLES: u32 = 16;
+
+/// Ticks the pad is held low for before each charge.
+const DISCHARGE_TICKS: u32 = 2;
+
+/// Ticks after which a pad that has not charged is considered shorted.
+const TIMEOUT_TICKS: u32 = 1000;
+
+/// The comparator reference, about 1.9 V: 60% of the supply, which the pad
+/// reaches after about one RC time constant.
+const REFERENCE_LEVEL: u32 = 7;
+
+/// Writing this to `PWDKEY` powers the comparator down, anything else
+/// powers it up.
+const VCOMP_PWDKEY: u32 = 0x37;
+
+register_structs! {
+    pub VCompRegisters {
+        (0x000 => cfg: ReadWrite<u32, VCOMP_CFG::Register>),
+        (0x004 => stat: ReadOnly<u32, VCOMP_STAT::Register>),
+        (0x008 => pwdkey: ReadWrite<u32>),
+        (0x00C => _reserved0),
+        (0x200 => inten: ReadWrite<u32, VCOMP_INT::Register>),
+        (0x204 => intstat: ReadWrite<u32, VCOMP_INT::Register>),
+        (0x208 => intclr: ReadWrite<u32, VCOMP_INT::Register>),
+        (0x20C => intset: ReadWrite<u32, VCOMP_INT::Register>),
+        (0x210 => @END),
+    }
+}
+
+register_bitfields![u32,
+    VCOMP_CFG [
+        PSEL OFFSET(0) NUMBITS(2) [
+            VDDADJ = 0x0,
+            VTEMP = 0x1,
+            VEXT1 = 0x2,
+            VEXT2 = 0x3
+        ],
+        NSEL OFFSET(8) NUMBITS(2) [
+            VREFEXT1 = 0x0,
+            VREFEXT2 = 0x1,
+            VREFEXT3 = 0x2,
+            DAC = 0x3
+        ],
+        LVLSEL OFFSET(16) NUMBITS(4) []
+    ],
+    VCOMP_STAT [
+        CMPOUT OFFSET(0) NUMBITS(1) [],
+        PWDSTAT OFFSET(1) NUMBITS(1) []
+    ],
+    VCOMP_INT [
+        OUTLOW OFFSET(0) NUMBITS(1) [],
+        OUTHI OFFSET(1) NUMBITS(1) []
+    ]
+];
+
+/// The step of a measurement that is in progress.
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    /// The pad is held low until the timer expires.
+    Discharging,
+    /// The pad is charging, until the comparator or the timer interrupts.
+    Charging,
+}
+
+pub struct CapSense<'a> {
+    registers: StaticRef<CTimerRegisters>,
+    vcomp: StaticRef<VCompRegisters>,
+    pins: OptionalCell<&'a [&'a dyn gpio::Pin]>,
+    client: OptionalCell<&'a dyn capsense::CapSenseClient>,
+    timer: MapCell<TimerSegment>,
+    channel: OptionalCell<usize>,
+    phase: Cell<Phase>,
+    /// Charge cycles done so far in the current measurement.
+    samples: Cell<u32>,
+    /// Sum of their charge times.
+    total: Cell<u32>,
+}
+
+impl<'a> CapSense<'a> {
+    pub const fn new() -> CapSense<'a> {
+        CapSense {
+            registers: CTIMER_BASE,
+            vcomp: VCOMP_BASE,
+            pins: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            timer: MapCell::empty(),
+            channel: OptionalCell::empty(),
+            phase: Cell::new(Phase::Idle),
+            samples: Cell::new(0),
+            total: Cell::new(0),
+        }
+    }
+
+    /// Set the pads used as channels: the pad wired to VEXT1, then
+    /// optionally the pad wired to VEXT2. `timer` times their measurements.
+    pub fn set_pins(&self, pins: &'a [&'a dyn gpio::Pin], timer: TimerSegment) {
+        for pin in pins.iter() {
+            pin.set_floating_state(gpio::FloatingState::PullUp);
+            pin.make_input();
+            pin.make_output();
+            pin.clear();
+        }
+        self.pins.set(pins);
+        self.timer.replace(timer);
+    }
+
+    /// Count from zero and interrupt after `ticks` ticks.
+    fn start_timer(&self, ticks: u32) {
+        let regs = self.registers;
+        self.timer.map(|timer| {
+            let ctrl = &regs.timers[timer.timer()].ctrl;
+            regs.intclr.set(timer.interrupt_mask());
+            regs.inten.set(regs.inten.get() | timer.interrupt_mask());
+            match timer.segment() {
+                Segment::A => {
+                    ctrl.modify(CTRL::TMRAEN::CLEAR + CTRL::TMRACLR::SET);
+                    regs.timers[timer.timer()]
+                        .cmpra
+                        .write(CMPR::CMPR0.val(ticks));
+                    ctrl.modify(
+                        CTRL::TMRACLR::CLEAR
+                            + CTRL::TMRACLK::HFRC_DIV4
+                            + CTRL::TMRAFN::SINGLECOUNT
+                            + CTRL::TMRAIE0::SET
+                            + CTRL::TMRAEN::SET,
+                    );
+                }
+                Segment::B => {
+                    ctrl.modify(CTRL::TMRBEN::CLEAR + CTRL::TMRBCLR::SET);
+                    regs.timers[timer.timer()]
+                        .cmprb
+                        .write(CMPR::CMPR0.val(ticks));
+                    ctrl.modify(
+                        CTRL::TMRBCLR::CLEAR
+                            + CTRL::TMRBCLK::HFRC_DIV4
+                            + CTRL::TMRBFN::SINGLECOUNT
+                            + CTRL::TMRBIE0::SET
+                            + CTRL::TMRBEN::SET,
+                    );
+                }
+            }
+        });
+    }
+
+    /// Stop the timer, returning the ticks it counted.
+    fn stop_timer(&self) -> u32 {
+        let regs = self.registers;
+        self.timer
+            .map(|timer| {
+                let registers = &regs.timers[timer.timer()];
+                let elapsed = match timer.segment() {
+                    Segment::A => {
+                        let elapsed = registers.tmr.read(TMR::CTTMRA);
+                        registers
+                            .ctrl
+                            .modify(CTRL::TMRAEN::CLEAR + CTRL::TMRAIE0::CLEAR);
+                        elapsed
+                    }
+                    Segment::B => {
+                        let elapsed = registers.tmr.read(TMR::CTTMRB);
+                        registers
+                            .ctrl
+                            .modify(CTRL::TMRBEN::CLEAR + CTRL::TMRBIE0::CLEAR);
+                        elapsed
+                    }
+                };
+                regs.inten.set(regs.inten.get() & !timer.interrupt_mask());
+                regs.intclr.set(timer.interrupt_mask());
+                elapsed
+            })
+            .unwrap_or(0)
+    }
+
+    fn with_pin<F: FnOnce(&dyn gpio::Pin)>(&self, f: F) {
+        self.channel.map(|channel| {
+            self.pins.map(|pins| f(pins[*channel]));
+        });
+    }
+
+    fn discharge(&self) {
+        self.with_pin(|pin| {
+            pin.make_output();
+            pin.clear();
+        });
+        self.phase.set(Phase::Discharging);
+        self.start_timer(DISCHARGE_TICKS);
+    }
+
+    fn charge(&self) {
+        self.vcomp.intclr.write(VCOMP_INT::OUTHI::SET);
+        self.vcomp.inten.write(VCOMP_INT::OUTHI::SET);
+        self.phase.set(Phase::Charging);
+        self.start_timer(TIMEOUT_TICKS);
+        self.with_pin(|pin| {
+            pin.disable_output();
+        });
+    }
+
+    /// End the measurement, leaving the pad discharged and the comparator
+    /// off.
+    fn finish(&self, result: Result<u32, ErrorCode>) {
+        self.with_pin(|pin| {
+            pin.make_output();
+            pin.clear();
+        });
+        self.vcomp.inten.set(0);
+        self.vcomp
+            .intclr
+            .write(VCOMP_INT::OUTHI::SET + VCOMP_INT::OUTLOW::SET);
+        self.vcomp.pwdkey.set(VCOMP_PWDKEY);
+        self.phase.set(Phase::Idle);
+        self.channel.take().map(|channel| {
+            self.client
+                .map(|client| client.measurement_done(channel, result));
+        });
+    }
+
+    /// Handle the CTIMER interrupt: the end of a discharge, or a charge that
+    /// has timed out. Interrupts of other timer segments are ignored.
+    pub fn handle_timer_interrupt(&self) {
+        let regs = self.registers;
+        let pending = self.timer.map_or(false, |timer| {
+            regs.intstat.get() & regs.inten.get() & timer.interrupt_mask() != 0
+        });
+        if !pending {
+            return;
+        }
+        self.stop_timer();
+        match self.phase.get() {
+            Phase::Discharging => self.charge(),
+            Phase::Charging => self.finish(Err(ErrorCode::FAIL)),
+            Phase::Idle => {}
+        }
+    }
+
+    /// Handle the VCOMP interrupt: the pad has charged past the reference.
+    pub fn handle_comparator_interrupt(&self) {
+        self.vcomp.inten.set(0);
+        self.vcomp
+            .intclr
+            .write(VCOMP_INT::OUTHI::SET + VCOMP_INT::OUTLOW::SET);
+        if self.phase.get() != Phase::Charging {
+            return;
+        }
+        let elapsed = self.stop_timer();
+        self.total.set(self.total.get() + elapsed);
+        self.samples.set(self.samples.get() + 1);
+        if self.samples.get() < SAMPLES {
+            self.discharge();
+        } else {
+            self.finish(Ok(self.total.get()));
+        }
+    }
+}
+
+impl<'a> capsense::CapSense<'a> for CapSense<'a> {
+    fn set_client(&self, client: &'a dyn capsense::CapSenseClient) {
+        self.client.set(client);
+    }
+
+    fn channels(&self) -> usize {
+        // The comparator has two external inputs.
+        self.pins.map_or(0, |pins| core::cmp::min(pins.len(), 2))
+    }
+
+    fn measure(&self, channel: usize) -> Result<(), ErrorCode> {
+        if channel >= self.channels() {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.timer.is_none() {
+            return Err(ErrorCode::OFF);
+        }
+        if self.channel.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.channel.set(channel);
+        self.samples.set(0);
+        self.total.set(0);
+
+        let input = match channel {
+            0 => VCOMP_CFG::PSEL::VEXT1,
+            _ => VCOMP_CFG::PSEL::VEXT2,
+        };
+        self.vcomp
+            .cfg
+            .write(input + VCOMP_CFG::NSEL::DAC + VCOMP_CFG::LVLSEL.val(REFERENCE_LEVEL));
+        self.vcomp.pwdkey.set(0);
+
+        // The measurement continues from the interrupts, so the result is
+        // always delivered asynchronously.
+        self.discharge();
+        Ok(())
+    }
+}
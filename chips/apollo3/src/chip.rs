@@ -39,6 +39,8 @@ pub struct Apollo3DefaultPeripherals {
     pub iom4: crate::iom::Iom<'static>,
     pub iom5: crate::iom::Iom<'static>,
     pub ble: crate::ble::Ble<'static>,
+    pub ctimer: crate::ctimer::CTimer,
+    pub capsense: crate::capsense::CapSense<'static>,
 }
 
 impl Apollo3DefaultPeripherals {
@@ -55,6 +57,8 @@ impl Apollo3DefaultPeripherals {
             iom4: crate::iom::Iom::new4(),
             iom5: crate::iom::Iom::new5(),
             ble: crate::ble::Ble::new(),
+            ctimer: crate::ctimer::CTimer::new(),
+            capsense: crate::capsense::CapSense::new(),
         }
     }
 }
@@ -74,6 +78,8 @@ impl kernel::InterruptService<()> for Apollo3DefaultPeripherals {
             nvic::IOMSTR4 => self.iom4.handle_interrupt(),
             nvic::IOMSTR5 => self.iom5.handle_interrupt(),
             nvic::BLE => self.ble.handle_interrupt(),
+            nvic::CTIMER => self.capsense.handle_timer_interrupt(),
+            nvic::VCOMP => self.capsense.handle_comparator_interrupt(),
             _ => return false,
         }
         true
@@ -0,0 +1,153 @@
+//! Ownership of the CTIMER timer segments.
+//!
+//! Each of the eight CTIMER timers has two 16-bit segments, A and B, which
+//! drivers such as `pwm::Pwm` and `capsense::CapSense` use independently.
+//! A driver only uses the segments it is given as `TimerSegment`s. These are
+//! claimed from `CTimer`, which hands each segment out once, so a board
+//! cannot configure the same segment for two drivers.
+//!
+//! The timers share one interrupt. Drivers that use it check the status of
+//! their own segments, so it can be passed to each of them.
+
+use core::cell::Cell;
+use kernel::common::registers::{register_bitfields, ReadWrite};
+use kernel::common::StaticRef;
+
+pub(crate) const CTIMER_BASE: StaticRef<CTimerRegisters> =
+    unsafe { StaticRef::new(0x4000_8000 as *const CTimerRegisters) };
+
+/// Number of CTIMER timers.
+pub const NUM_TIMERS: usize = 8;
+
+#[repr(C)]
+pub(crate) struct TimerRegisters {
+    pub(crate) tmr: ReadWrite<u32, TMR::Register>,
+    pub(crate) cmpra: ReadWrite<u32, CMPR::Register>,
+    pub(crate) cmprb: ReadWrite<u32, CMPR::Register>,
+    pub(crate) ctrl: ReadWrite<u32, CTRL::Register>,
+    _reserved0: [u8; 4],
+    pub(crate) cmprauxa: ReadWrite<u32>,
+    pub(crate) cmprauxb: ReadWrite<u32>,
+    pub(crate) aux: ReadWrite<u32>,
+}
+
+#[repr(C)]
+pub(crate) struct CTimerRegisters {
+    pub(crate) timers: [TimerRegisters; NUM_TIMERS],
+    pub(crate) globen: ReadWrite<u32>,
+    /// Sources of CT0 to CT29, ten to a register.
+    pub(crate) outcfg: [ReadWrite<u32>; 3],
+    _reserved0: [u8; 4],
+    /// Sources of CT30 and CT31.
+    pub(crate) outcfg3: ReadWrite<u32>,
+    _reserved1: [u8; 0xE8],
+    /// Compare interrupts: CMPR0 of timer n segment A at bit 2n, segment B
+    /// at bit 2n + 1.
+    pub(crate) inten: ReadWrite<u32>,
+    pub(crate) intstat: ReadWrite<u32>,
+    pub(crate) intclr: ReadWrite<u32>,
+    pub(crate) intset: ReadWrite<u32>,
+}
+
+register_bitfields![u32,
+    pub(crate) TMR [
+        CTTMRA OFFSET(0) NUMBITS(16) [],
+        CTTMRB OFFSET(16) NUMBITS(16) []
+    ],
+    pub(crate) CMPR [
+        /// Count at which the output becomes active.
+        CMPR0 OFFSET(0) NUMBITS(16) [],
+        /// Count at which the output becomes inactive and the period restarts.
+        CMPR1 OFFSET(16) NUMBITS(16) []
+    ],
+    pub(crate) CTRL [
+        TMRAEN OFFSET(0) NUMBITS(1) [],
+        TMRACLK OFFSET(1) NUMBITS(5) [
+            HFRC_DIV4 = 0x1,
+            HFRC_DIV16 = 0x2,
+            HFRC_DIV256 = 0x3
+        ],
+        TMRAFN OFFSET(6) NUMBITS(3) [
+            SINGLECOUNT = 0x0,
+            PULSE_CONT = 0x3
+        ],
+        TMRAIE0 OFFSET(9) NUMBITS(1) [],
+        TMRACLR OFFSET(11) NUMBITS(1) [],
+        TMRAPOL OFFSET(12) NUMBITS(1) [],
+        TMRBEN OFFSET(16) NUMBITS(1) [],
+        TMRBCLK OFFSET(17) NUMBITS(5) [
+            HFRC_DIV4 = 0x1,
+            HFRC_DIV16 = 0x2,
+            HFRC_DIV256 = 0x3
+        ],
+        TMRBFN OFFSET(22) NUMBITS(3) [
+            SINGLECOUNT = 0x0,
+            PULSE_CONT = 0x3
+        ],
+        TMRBIE0 OFFSET(25) NUMBITS(1) [],
+        TMRBCLR OFFSET(27) NUMBITS(1) [],
+        TMRBPOL OFFSET(28) NUMBITS(1) [],
+        CTLINK OFFSET(31) NUMBITS(1) []
+    ]
+];
+
+/// A segment of a CTIMER timer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Segment {
+    A,
+    B,
+}
+
+/// A timer segment claimed from `CTimer`. It cannot be copied, so only the
+/// driver it is given to uses the segment.
+pub struct TimerSegment {
+    timer: usize,
+    segment: Segment,
+}
+
+impl TimerSegment {
+    pub fn timer(&self) -> usize {
+        self.timer
+    }
+
+    pub fn segment(&self) -> Segment {
+        self.segment
+    }
+
+    /// The bit of the segment's compare interrupt in `INTEN` and friends.
+    pub(crate) fn interrupt_mask(&self) -> u32 {
+        match self.segment {
+            Segment::A => 1 << (2 * self.timer),
+            Segment::B => 1 << (2 * self.timer + 1),
+        }
+    }
+}
+
+/// Hands out the timer segments.
+pub struct CTimer {
+    /// Bit 2n for segment A of timer n, bit 2n + 1 for segment B.
+    claimed: Cell<u16>,
+}
+
+impl CTimer {
+    pub const fn new() -> CTimer {
+        CTimer {
+            claimed: Cell::new(0),
+        }
+    }
+
+    /// Claim segment `segment` of timer `timer`. Returns `None` if there is
+    /// no such timer or the segment has already been claimed.
+    pub fn claim(&self, timer: usize, segment: Segment) -> Option<TimerSegment> {
+        if timer >= NUM_TIMERS {
+            return None;
+        }
+        let claimed = TimerSegment { timer, segment };
+        let mask = claimed.interrupt_mask() as u16;
+        if self.claimed.get() & mask != 0 {
+            return None;
+        }
+        self.claimed.set(self.claimed.get() | mask);
+        Some(claimed)
+    }
+}
@@ -4,7 +4,7 @@ use core::ops::{Index, IndexMut};
 use enum_primitive::cast::FromPrimitive;
 use enum_primitive::enum_from_primitive;
 use kernel::common::cells::OptionalCell;
-use kernel::common::registers::{register_bitfields, register_structs, ReadWrite};
+use kernel::common::registers::{register_bitfields, register_structs, Field, ReadWrite};
 use kernel::common::StaticRef;
 use kernel::hil::gpio;
 
@@ -150,6 +150,44 @@ impl Port<'_> {
         }
     }
 
+    pub fn enable_uart1(&self, tx_pin: &GpioPin, rx_pin: &GpioPin) {
+        let regs = GPIO_BASE;
+
+        match tx_pin.pin as usize {
+            39 => {
+                regs.padkey.set(115);
+                regs.padreg[9].modify(
+                    PADREG::PAD3PULL::CLEAR
+                        + PADREG::PAD3INPEN::CLEAR
+                        + PADREG::PAD3FNCSEL.val(0x1),
+                );
+                regs.cfg[4].modify(CFG::GPIO7INTD.val(0x00) + CFG::GPIO7OUTCFG.val(0x00));
+                regs.altpadcfgj
+                    .modify(ALTPADCFG::PAD3_DS1::CLEAR + ALTPADCFG::PAD3_SR::CLEAR);
+                regs.padkey.set(0x00);
+            }
+            _ => {
+                panic!("tx_pin not supported");
+            }
+        }
+
+        match rx_pin.pin as usize {
+            40 => {
+                regs.padkey.set(115);
+                regs.padreg[10].modify(
+                    PADREG::PAD0PULL::CLEAR + PADREG::PAD0INPEN::SET + PADREG::PAD0FNCSEL.val(0x1),
+                );
+                regs.cfg[5].modify(CFG::GPIO0INTD.val(0x00) + CFG::GPIO0OUTCFG.val(0x00));
+                regs.altpadcfgk
+                    .modify(ALTPADCFG::PAD0_DS1::CLEAR + ALTPADCFG::PAD0_SR::CLEAR);
+                regs.padkey.set(0x00);
+            }
+            _ => {
+                panic!("rx_pin not supported");
+            }
+        }
+    }
+
     pub fn enable_i2c(&self, sda: &GpioPin, scl: &GpioPin) {
         let regs = GPIO_BASE;
 
@@ -496,19 +534,175 @@ impl<'a> GpioPin<'a> {
     pub fn handle_interrupt(&self) {
         unimplemented!();
     }
+
+    /// The field of this pad's PADREG register out of the fields for each of
+    /// the four pads it holds.
+    fn pad_field(&self, fields: [Field<u32, PADREG::Register>; 4]) -> Field<u32, PADREG::Register> {
+        fields[self.pin as usize % 4]
+    }
+
+    fn modify_padreg(&self, field: Field<u32, PADREG::Register>, value: u32) {
+        let regs = self.registers;
+        regs.padkey.set(115);
+        regs.padreg[self.pin as usize / 4].modify(field.val(value));
+        regs.padkey.set(0x00);
+    }
+
+    fn read_padreg(&self, field: Field<u32, PADREG::Register>) -> u32 {
+        self.registers.padreg[self.pin as usize / 4].read(field)
+    }
+
+    /// The output configuration of this pad, which is disabled while zero.
+    fn outcfg_field(&self) -> Field<u32, CFG::Register> {
+        [
+            CFG::GPIO0OUTCFG,
+            CFG::GPIO1OUTCFG,
+            CFG::GPIO2OUTCFG,
+            CFG::GPIO3OUTCFG,
+            CFG::GPIO4OUTCFG,
+            CFG::GPIO5OUTCFG,
+            CFG::GPIO6OUTCFG,
+            CFG::GPIO7OUTCFG,
+        ][self.pin as usize % 8]
+    }
+
+    fn modify_outcfg(&self, value: u32) {
+        let regs = self.registers;
+        regs.padkey.set(115);
+        regs.cfg[self.pin as usize / 8].modify(self.outcfg_field().val(value));
+        regs.padkey.set(0x00);
+    }
+
+    fn input_enabled(&self) -> bool {
+        self.read_padreg(self.pad_field([
+            PADREG::PAD0INPEN,
+            PADREG::PAD1INPEN,
+            PADREG::PAD2INPEN,
+            PADREG::PAD3INPEN,
+        ])) != 0
+    }
+
+    fn set_input_enabled(&self, enabled: bool) {
+        self.modify_padreg(
+            self.pad_field([
+                PADREG::PAD0INPEN,
+                PADREG::PAD1INPEN,
+                PADREG::PAD2INPEN,
+                PADREG::PAD3INPEN,
+            ]),
+            enabled as u32,
+        );
+    }
+
+    fn output_enabled(&self) -> bool {
+        self.registers.cfg[self.pin as usize / 8].read(self.outcfg_field()) != 0
+    }
+
+    fn is_gpio(&self) -> bool {
+        self.read_padreg(self.pad_field([
+            PADREG::PAD0FNCSEL,
+            PADREG::PAD1FNCSEL,
+            PADREG::PAD2FNCSEL,
+            PADREG::PAD3FNCSEL,
+        ])) == 0x3
+    }
+
+    fn select_gpio(&self) {
+        self.modify_padreg(
+            self.pad_field([
+                PADREG::PAD0FNCSEL,
+                PADREG::PAD1FNCSEL,
+                PADREG::PAD2FNCSEL,
+                PADREG::PAD3FNCSEL,
+            ]),
+            0x3,
+        );
+    }
+
+    /// Connect the pad to CTIMER output `ct`, which pad function `function`
+    /// selects, as a push/pull output.
+    pub(crate) fn make_ctimer_output(&self, function: u32, ct: usize) {
+        let regs = self.registers;
+
+        // Set the key
+        regs.padkey.set(115);
+
+        // Select the CTIMER function of the pad
+        let pagreg_offset = self.pin as usize / 4;
+        let pagreg_value = match self.pin as usize % 4 {
+            0 => PADREG::PAD0FNCSEL.val(function),
+            1 => PADREG::PAD1FNCSEL.val(function),
+            2 => PADREG::PAD2FNCSEL.val(function),
+            3 => PADREG::PAD3FNCSEL.val(function),
+            _ => unreachable!(),
+        };
+        regs.padreg[pagreg_offset].modify(pagreg_value);
+
+        // Set to push/pull
+        let cfgreg_offset = self.pin as usize / 8;
+        let cfgreg_value = match self.pin as usize % 8 {
+            0 => CFG::GPIO0INTD::CLEAR + CFG::GPIO0OUTCFG.val(0x1),
+            1 => CFG::GPIO1INTD::CLEAR + CFG::GPIO1OUTCFG.val(0x1),
+            2 => CFG::GPIO2INTD::CLEAR + CFG::GPIO2OUTCFG.val(0x1),
+            3 => CFG::GPIO3INTD::CLEAR + CFG::GPIO3OUTCFG.val(0x1),
+            4 => CFG::GPIO4INTD::CLEAR + CFG::GPIO4OUTCFG.val(0x1),
+            5 => CFG::GPIO5INTD::CLEAR + CFG::GPIO5OUTCFG.val(0x1),
+            6 => CFG::GPIO6INTD::CLEAR + CFG::GPIO6OUTCFG.val(0x1),
+            7 => CFG::GPIO7INTD::CLEAR + CFG::GPIO7OUTCFG.val(0x1),
+            _ => unreachable!(),
+        };
+        regs.cfg[cfgreg_offset].modify(cfgreg_value);
+
+        // Enable the CTIMER output, which is disabled while its bit is set
+        regs.ctencfg.set(regs.ctencfg.get() & !(1 << ct));
+
+        // Unset key
+        regs.padkey.set(0x00);
+    }
 }
 
 impl<'a> gpio::Configure for GpioPin<'a> {
     fn configuration(&self) -> gpio::Configuration {
-        unimplemented!();
+        if !self.is_gpio() {
+            return gpio::Configuration::Function;
+        }
+        match (self.input_enabled(), self.output_enabled()) {
+            (true, true) => gpio::Configuration::InputOutput,
+            (true, false) => gpio::Configuration::Input,
+            (false, true) => gpio::Configuration::Output,
+            (false, false) => gpio::Configuration::LowPower,
+        }
     }
 
-    fn set_floating_state(&self, _mode: gpio::FloatingState) {
-        unimplemented!();
+    /// The pads only have pull-ups, so `PullDown` leaves the pad floating.
+    fn set_floating_state(&self, mode: gpio::FloatingState) {
+        let pull = match mode {
+            gpio::FloatingState::PullUp => 1,
+            gpio::FloatingState::PullDown | gpio::FloatingState::PullNone => 0,
+        };
+        self.modify_padreg(
+            self.pad_field([
+                PADREG::PAD0PULL,
+                PADREG::PAD1PULL,
+                PADREG::PAD2PULL,
+                PADREG::PAD3PULL,
+            ]),
+            pull,
+        );
     }
 
     fn floating_state(&self) -> gpio::FloatingState {
-        unimplemented!();
+        let pull = self.read_padreg(self.pad_field([
+            PADREG::PAD0PULL,
+            PADREG::PAD1PULL,
+            PADREG::PAD2PULL,
+            PADREG::PAD3PULL,
+        ]));
+        if pull != 0 {
+            gpio::FloatingState::PullUp
+        } else {
+            gpio::FloatingState::PullNone
+        }
     }
 
     fn deactivate_to_low_power(&self) {
@@ -555,15 +749,19 @@ impl<'a> gpio::Configure for GpioPin<'a> {
     }
 
     fn disable_output(&self) -> gpio::Configuration {
-        unimplemented!();
+        self.modify_outcfg(0x0);
+        self.configuration()
     }
 
     fn make_input(&self) -> gpio::Configuration {
-        unimplemented!();
+        self.select_gpio();
+        self.set_input_enabled(true);
+        self.configuration()
     }
 
     fn disable_input(&self) -> gpio::Configuration {
-        unimplemented!();
+        self.set_input_enabled(false);
+        self.configuration()
     }
 }
 
@@ -7,6 +7,7 @@ use kernel::common::registers::{register_bitfields, register_structs, ReadOnly,
 use kernel::common::StaticRef;
 use kernel::hil;
 use kernel::hil::i2c;
+use kernel::power::PowerDomain;
 
 const IOM0_BASE: StaticRef<IomRegisters> =
     unsafe { StaticRef::new(0x5000_4000 as *const IomRegisters) };
@@ -271,6 +272,10 @@ pub struct Iom<'a> {
     read_index: Cell<usize>,
 
     smbus: Cell<bool>,
+
+    power: OptionalCell<&'a PowerDomain<'a>>,
+    // Whether this IOM is counted as a user of its power domain.
+    powered: Cell<bool>,
 }
 
 impl<'a> Iom<'_> {
@@ -284,6 +289,8 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            power: OptionalCell::empty(),
+            powered: Cell::new(false),
         }
     }
     pub const fn new1() -> Iom<'a> {
@@ -296,6 +303,8 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            power: OptionalCell::empty(),
+            powered: Cell::new(false),
         }
     }
     pub const fn new2() -> Iom<'a> {
@@ -308,6 +317,8 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            power: OptionalCell::empty(),
+            powered: Cell::new(false),
         }
     }
     pub const fn new3() -> Iom<'a> {
@@ -320,6 +331,8 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            power: OptionalCell::empty(),
+            powered: Cell::new(false),
         }
     }
     pub const fn new4() -> Iom<'a> {
@@ -332,6 +345,8 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            power: OptionalCell::empty(),
+            powered: Cell::new(false),
         }
     }
     pub const fn new5() -> Iom<'a> {
@@ -344,6 +359,22 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            power: OptionalCell::empty(),
+            powered: Cell::new(false),
+        }
+    }
+
+    fn power_on(&self) {
+        if !self.powered.get() {
+            self.powered.set(true);
+            self.power.map(|power| power.in_use());
+        }
+    }
+
+    fn power_off(&self) {
+        if self.powered.get() {
+            self.powered.set(false);
+            self.power.map(|power| power.idle());
         }
     }
 
@@ -674,6 +705,16 @@ impl<'a> Iom<'_> {
     }
 }
 
+impl<'a> Iom<'a> {
+    /// Power the IOM only while it is enabled.
+    pub fn set_power_domain(&self, power: &'a PowerDomain<'a>) {
+        self.power.set(power);
+        if self.powered.get() {
+            power.in_use();
+        }
+    }
+}
+
 impl<'a> hil::i2c::I2CMaster for Iom<'a> {
     fn set_master_client(&self, master_client: &'a dyn i2c::I2CHwMasterClient) {
         self.master_client.set(master_client);
@@ -682,6 +723,8 @@ impl<'a> hil::i2c::I2CMaster for Iom<'a> {
     fn enable(&self) {
         let regs = self.registers;
 
+        self.power_on();
+
         // Setup the I2C
         regs.mi2ccfg.write(
             MI2CCFG::STRDIS.val(0)
@@ -715,6 +758,8 @@ impl<'a> hil::i2c::I2CMaster for Iom<'a> {
         let regs = self.registers;
 
         regs.submodctrl.write(SUBMODCTRL::SMOD1EN::CLEAR);
+
+        self.power_off();
     }
 
     fn write_read(&self, addr: u8, data: &'static mut [u8], write_len: u8, read_len: u8) {
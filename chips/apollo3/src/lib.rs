@@ -8,12 +8,15 @@
 // Peripherals
 pub mod ble;
 pub mod cachectrl;
+pub mod capsense;
 pub mod chip;
 pub mod clkgen;
+pub mod ctimer;
 pub mod gpio;
 pub mod iom;
 pub mod mcuctrl;
 pub mod nvic;
+pub mod pwm;
 pub mod pwrctrl;
 pub mod stimer;
 pub mod uart;
@@ -47,8 +50,8 @@ pub static BASE_VECTORS: [unsafe extern "C" fn(); 16] = [
     unhandled_interrupt,
     unhandled_interrupt,
     unhandled_interrupt,
-    svc_handler,         // SVC
-    unhandled_interrupt, // DebugMon
+    svc_handler,        // SVC
+    hard_fault_handler, // DebugMon, breakpoints in processes
     unhandled_interrupt,
     unhandled_interrupt, // PendSV
     systick_handler,     // SysTick
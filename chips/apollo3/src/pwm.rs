@@ -0,0 +1,186 @@
+//! PWM output on the CTIMER timers of the Apollo3.
+//!
+//! Each of the eight CTIMER timers has two 16-bit segments, A and B, and each
+//! segment can generate a PWM signal in its repeated pulse mode. The signal
+//! reaches a pad through one of the CTIMER outputs, CT0 to CT31. Which CT a pad
+//! carries, the pad function that selects it, and which timer outputs the CT
+//! can be driven from are fixed by the chip, and given in the pad and CTIMER
+//! output configuration tables of the datasheet. Boards describe each pin they
+//! use with a `PwmPin` built from those tables.
+//!
+//! The timer segment of each pin is claimed from `ctimer::CTimer`, so it is
+//! not used by any other driver.
+
+use kernel::common::StaticRef;
+use kernel::hil;
+use kernel::ErrorCode;
+
+use crate::ctimer::{CTimerRegisters, Segment, TimerSegment, CMPR, CTIMER_BASE, CTRL};
+use crate::gpio::GpioPin;
+
+/// The frequency of the high frequency RC oscillator the timers count.
+const HFRC_HZ: usize = 48_000_000;
+
+/// The highest frequency, a period of two ticks of the fastest clock.
+const MAX_FREQUENCY_HZ: usize = HFRC_HZ / 4 / 2;
+
+/// The `OUTCFG` source that holds a CT output low.
+const OUTCFG_FORCE_LOW: u32 = 0;
+
+/// The value of a 100% duty cycle.
+const MAX_DUTY_CYCLE: usize = 0xFFFF;
+
+/// A pad that carries the output of a CTIMER timer segment.
+pub struct PwmPin<'a> {
+    pad: &'a GpioPin<'a>,
+    function: u32,
+    ct: usize,
+    output: u32,
+    timer: TimerSegment,
+}
+
+impl<'a> PwmPin<'a> {
+    /// Describe a PWM pin, from the datasheet tables:
+    ///
+    /// - `function`: the pad function of `pad` that selects its CT output.
+    /// - `ct`: the number of that CT output.
+    /// - `output`: the `OUTCFG` source of the CT output that is the output of
+    ///   the timer segment `timer`.
+    pub const fn new(
+        pad: &'a GpioPin<'a>,
+        function: u32,
+        ct: usize,
+        output: u32,
+        timer: TimerSegment,
+    ) -> PwmPin<'a> {
+        PwmPin {
+            pad,
+            function,
+            ct,
+            output,
+            timer,
+        }
+    }
+}
+
+pub struct Pwm {
+    registers: StaticRef<CTimerRegisters>,
+}
+
+impl Pwm {
+    pub const fn new() -> Pwm {
+        Pwm {
+            registers: CTIMER_BASE,
+        }
+    }
+
+    /// Set the source of CT output `ct`.
+    fn set_output(&self, ct: usize, source: u32) {
+        let shift = (ct % 10) * 3;
+        // OUTCFG3 is not next to the others
+        let outcfg = match ct / 10 {
+            0..=2 => &self.registers.outcfg[ct / 10],
+            _ => &self.registers.outcfg3,
+        };
+        outcfg.set((outcfg.get() & !(0x7 << shift)) | (source << shift));
+    }
+
+    fn start_pwm(
+        &self,
+        pin: &PwmPin,
+        frequency_hz: usize,
+        duty_cycle: usize,
+    ) -> Result<(), ErrorCode> {
+        if pin.ct >= 32
+            || frequency_hz == 0
+            || frequency_hz > MAX_FREQUENCY_HZ
+            || duty_cycle > MAX_DUTY_CYCLE
+        {
+            return Err(ErrorCode::INVAL);
+        }
+
+        // Use the fastest clock that the period in ticks fits 16 bits at, for
+        // the finest duty cycle.
+        let (divider, clock_a, clock_b) = if HFRC_HZ / 4 / frequency_hz <= 0xFFFF {
+            (4, CTRL::TMRACLK::HFRC_DIV4, CTRL::TMRBCLK::HFRC_DIV4)
+        } else if HFRC_HZ / 16 / frequency_hz <= 0xFFFF {
+            (16, CTRL::TMRACLK::HFRC_DIV16, CTRL::TMRBCLK::HFRC_DIV16)
+        } else if HFRC_HZ / 256 / frequency_hz <= 0xFFFF {
+            (256, CTRL::TMRACLK::HFRC_DIV256, CTRL::TMRBCLK::HFRC_DIV256)
+        } else {
+            return Err(ErrorCode::INVAL);
+        };
+        let period = HFRC_HZ / divider / frequency_hz;
+        let on_time = (period as u64 * duty_cycle as u64 / MAX_DUTY_CYCLE as u64) as usize;
+
+        // The output is active from CMPR0 until the end of the period.
+        let compare = CMPR::CMPR0.val((period - on_time) as u32) + CMPR::CMPR1.val(period as u32);
+
+        let timer = &self.registers.timers[pin.timer.timer()];
+        match pin.timer.segment() {
+            Segment::A => {
+                timer.ctrl.modify(CTRL::TMRAEN::CLEAR + CTRL::TMRACLR::SET);
+                timer.cmpra.write(compare);
+                timer.ctrl.modify(
+                    CTRL::TMRACLR::CLEAR
+                        + CTRL::TMRAPOL::CLEAR
+                        + clock_a
+                        + CTRL::TMRAFN::PULSE_CONT
+                        + CTRL::TMRAEN::SET,
+                );
+            }
+            Segment::B => {
+                timer.ctrl.modify(CTRL::TMRBEN::CLEAR + CTRL::TMRBCLR::SET);
+                timer.cmprb.write(compare);
+                timer.ctrl.modify(
+                    CTRL::TMRBCLR::CLEAR
+                        + CTRL::TMRBPOL::CLEAR
+                        + clock_b
+                        + CTRL::TMRBFN::PULSE_CONT
+                        + CTRL::TMRBEN::SET,
+                );
+            }
+        }
+
+        self.set_output(pin.ct, pin.output);
+        pin.pad.make_ctimer_output(pin.function, pin.ct);
+
+        Ok(())
+    }
+
+    fn stop_pwm(&self, pin: &PwmPin) -> Result<(), ErrorCode> {
+        if pin.ct >= 32 {
+            return Err(ErrorCode::INVAL);
+        }
+
+        // Leave the pad low rather than wherever the period stopped.
+        self.set_output(pin.ct, OUTCFG_FORCE_LOW);
+
+        let timer = &self.registers.timers[pin.timer.timer()];
+        match pin.timer.segment() {
+            Segment::A => timer.ctrl.modify(CTRL::TMRAEN::CLEAR),
+            Segment::B => timer.ctrl.modify(CTRL::TMRBEN::CLEAR),
+        }
+        Ok(())
+    }
+}
+
+impl hil::pwm::Pwm for Pwm {
+    type Pin = PwmPin<'static>;
+
+    fn start(&self, pin: &Self::Pin, frequency: usize, duty_cycle: usize) -> Result<(), ErrorCode> {
+        self.start_pwm(pin, frequency, duty_cycle)
+    }
+
+    fn stop(&self, pin: &Self::Pin) -> Result<(), ErrorCode> {
+        self.stop_pwm(pin)
+    }
+
+    fn get_maximum_frequency_hz(&self) -> usize {
+        MAX_FREQUENCY_HZ
+    }
+
+    fn get_maximum_duty_cycle(&self) -> usize {
+        MAX_DUTY_CYCLE
+    }
+}
@@ -2,6 +2,7 @@
 
 use kernel::common::registers::{register_bitfields, register_structs, ReadOnly, ReadWrite};
 use kernel::common::StaticRef;
+use kernel::power::PowerController;
 
 const PWRCTRL_BASE: StaticRef<PwrCtrlRegisters> =
     unsafe { StaticRef::new(0x4002_1000 as *const PwrCtrlRegisters) };
@@ -65,6 +66,22 @@ register_bitfields![u32,
     ]
 ];
 
+/// Power domains of the peripherals, as used by `PowerController`. These are
+/// the bit offsets in `DEVPWREN`.
+pub const IOS: usize = 0;
+pub const IOM0: usize = 1;
+pub const IOM1: usize = 2;
+pub const IOM2: usize = 3;
+pub const IOM3: usize = 4;
+pub const IOM4: usize = 5;
+pub const IOM5: usize = 6;
+pub const UART0: usize = 7;
+pub const UART1: usize = 8;
+pub const ADC: usize = 9;
+pub const SCARD: usize = 10;
+pub const MSPI: usize = 11;
+pub const PDM: usize = 12;
+
 pub struct PwrCtrl {
     registers: StaticRef<PwrCtrlRegisters>,
 }
@@ -96,3 +113,31 @@ impl PwrCtrl {
         while !regs.devpwrstatus.is_set(DEVPWRSTATUS::BLEL) {}
     }
 }
+
+impl PowerController for PwrCtrl {
+    fn set_domain_power(&self, domain: usize, powered: bool) {
+        let regs = self.registers;
+
+        // The BLE domain needs a power-up sequence, see `enable_ble()`.
+        if domain > PDM {
+            return;
+        }
+        let mask = 1 << domain;
+        if powered {
+            regs.devpwren.set(regs.devpwren.get() | mask);
+            // The peripherals cannot be used until the power switch of their
+            // domain reports it is on.
+            let status = match domain {
+                IOS | UART0 | UART1 | SCARD => DEVPWRSTATUS::HCPA::SET,
+                IOM0 | IOM1 | IOM2 => DEVPWRSTATUS::HCPB::SET,
+                IOM3 | IOM4 | IOM5 => DEVPWRSTATUS::HCPC::SET,
+                ADC => DEVPWRSTATUS::PWRADC::SET,
+                MSPI => DEVPWRSTATUS::PWRMSPI::SET,
+                _ => DEVPWRSTATUS::PWRPDM::SET,
+            };
+            while !regs.devpwrstatus.matches_all(status) {}
+        } else {
+            regs.devpwren.set(regs.devpwren.get() & !mask);
+        }
+    }
+}
@@ -8,6 +8,9 @@ use kernel::common::cells::TakeCell;
 use kernel::common::registers::{register_bitfields, register_structs, ReadWrite};
 use kernel::common::StaticRef;
 use kernel::hil;
+use kernel::power::PowerDomain;
+
+use crate::nvic;
 
 const UART0_BASE: StaticRef<UartRegisters> =
     unsafe { StaticRef::new(0x4001_C000 as *const UartRegisters) };
@@ -160,6 +163,7 @@ register_bitfields![u32,
 
 pub struct Uart<'a> {
     registers: StaticRef<UartRegisters>,
+    nvic: cortexm4::nvic::Nvic,
     clock_frequency: u32,
     tx_client: OptionalCell<&'a dyn hil::uart::TransmitClient>,
     rx_client: OptionalCell<&'a dyn hil::uart::ReceiveClient>,
@@ -167,6 +171,17 @@ pub struct Uart<'a> {
     tx_buffer: TakeCell<'static, [u8]>,
     tx_len: Cell<usize>,
     tx_index: Cell<usize>,
+
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_index: Cell<usize>,
+    // An abort is waiting for the interrupt handler to return the receive
+    // buffer.
+    rx_abort: Cell<bool>,
+
+    power: OptionalCell<&'a PowerDomain<'a>>,
+    // Whether this UART is counted as a user of its power domain.
+    powered: Cell<bool>,
 }
 
 #[derive(Copy, Clone)]
@@ -179,25 +194,39 @@ impl Uart<'_> {
     pub const fn new_uart_0() -> Self {
         Self {
             registers: UART0_BASE,
+            nvic: unsafe { cortexm4::nvic::Nvic::new(nvic::UART0) },
             clock_frequency: 24_000_000,
             tx_client: OptionalCell::empty(),
             rx_client: OptionalCell::empty(),
             tx_buffer: TakeCell::empty(),
             tx_len: Cell::new(0),
             tx_index: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            rx_abort: Cell::new(false),
+            power: OptionalCell::empty(),
+            powered: Cell::new(false),
         }
     }
 
-    // unsafe bc of UART0_BASE usage, called twice would alias location
+    // unsafe bc of UART1_BASE usage, called twice would alias location
     pub const fn new_uart_1() -> Self {
         Self {
             registers: UART1_BASE,
+            nvic: unsafe { cortexm4::nvic::Nvic::new(nvic::UART1) },
             clock_frequency: 24_000_000,
             tx_client: OptionalCell::empty(),
             rx_client: OptionalCell::empty(),
             tx_buffer: TakeCell::empty(),
             tx_len: Cell::new(0),
             tx_index: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            rx_abort: Cell::new(false),
+            power: OptionalCell::empty(),
+            powered: Cell::new(false),
         }
     }
 
@@ -229,6 +258,107 @@ impl Uart<'_> {
         regs.iec.modify(IEC::TXIC::SET);
     }
 
+    fn enable_rx_interrupt(&self) {
+        let regs = self.registers;
+
+        // Set RX FIFO to fire at 1/8 full, the receive timeout interrupt
+        // takes care of any bytes left below the threshold.
+        regs.ifls.modify(IFLS::RXIFLSEL.val(0));
+
+        regs.ier.modify(
+            IER::RXIM::SET
+                + IER::RTIM::SET
+                + IER::FEIM::SET
+                + IER::PEIM::SET
+                + IER::BEIM::SET
+                + IER::OEIM::SET,
+        );
+    }
+
+    fn disable_rx_interrupt(&self) {
+        let regs = self.registers;
+
+        regs.ier.modify(
+            IER::RXIM::CLEAR
+                + IER::RTIM::CLEAR
+                + IER::FEIM::CLEAR
+                + IER::PEIM::CLEAR
+                + IER::BEIM::CLEAR
+                + IER::OEIM::CLEAR,
+        );
+        regs.iec.write(
+            IEC::RXIC::SET
+                + IEC::RTIC::SET
+                + IEC::FEIC::SET
+                + IEC::PEIC::SET
+                + IEC::BEIC::SET
+                + IEC::OEMC::SET,
+        );
+    }
+
+    /// Decode the error flags that the UART stores alongside each received
+    /// byte. A break also sets the framing error flag, so it is checked
+    /// first.
+    fn rx_error(data: u32) -> hil::uart::Error {
+        let dr = kernel::common::registers::LocalRegisterCopy::<u32, DR::Register>::new(data);
+
+        if dr.is_set(DR::BEDATA) {
+            hil::uart::Error::BreakError
+        } else if dr.is_set(DR::FEDATA) {
+            hil::uart::Error::FramingError
+        } else if dr.is_set(DR::PEDATA) {
+            hil::uart::Error::ParityError
+        } else if dr.is_set(DR::OEDATA) {
+            hil::uart::Error::OverrunError
+        } else {
+            hil::uart::Error::None
+        }
+    }
+
+    fn rx_complete(&self, rval: Result<(), ErrorCode>, error: hil::uart::Error) {
+        self.disable_rx_interrupt();
+        self.rx_abort.set(false);
+        self.rx_client.map(|client| {
+            self.rx_buffer.take().map(|rx_buf| {
+                client.received_buffer(rx_buf, self.rx_index.get(), rval, error);
+            });
+        });
+    }
+
+    fn rx_progress(&self) {
+        let regs = self.registers;
+        let mut error = hil::uart::Error::None;
+
+        // Drain the hardware FIFO into the receive buffer, stopping early if
+        // the hardware flagged a line error on one of the bytes.
+        self.rx_buffer.map(|rx_buf| {
+            while self.rx_index.get() < self.rx_len.get() && !regs.fr.is_set(FR::RXFE) {
+                let data = regs.dr.get();
+                error = Self::rx_error(data);
+                match error {
+                    // An overrun means bytes after this one were lost, this
+                    // byte itself is valid.
+                    hil::uart::Error::None | hil::uart::Error::OverrunError => {
+                        rx_buf[self.rx_index.get()] = DR::DATA.read(data) as u8;
+                        self.rx_index.set(self.rx_index.get() + 1);
+                    }
+                    _ => {}
+                }
+                if error != hil::uart::Error::None {
+                    // Clear the sticky error status.
+                    regs.rsr.set(0);
+                    break;
+                }
+            }
+        });
+
+        if error != hil::uart::Error::None {
+            self.rx_complete(Err(ErrorCode::FAIL), error);
+        } else if self.rx_buffer.is_some() && self.rx_index.get() >= self.rx_len.get() {
+            self.rx_complete(Ok(()), hil::uart::Error::None);
+        }
+    }
+
     fn tx_progress(&self) {
         let regs = self.registers;
         let idx = self.tx_index.get();
@@ -279,6 +409,37 @@ impl Uart<'_> {
                 self.tx_progress();
             }
         }
+
+        if irq.is_set(IES::RXIS)
+            || irq.is_set(IES::RTIS)
+            || irq.is_set(IES::FEIS)
+            || irq.is_set(IES::PEIS)
+            || irq.is_set(IES::BEIS)
+            || irq.is_set(IES::OEIS)
+        {
+            regs.iec.write(
+                IEC::RXIC::SET
+                    + IEC::RTIC::SET
+                    + IEC::FEIC::SET
+                    + IEC::PEIC::SET
+                    + IEC::BEIC::SET
+                    + IEC::OEMC::SET,
+            );
+
+            if self.rx_buffer.is_some() {
+                self.rx_progress();
+            }
+        }
+
+        if self.rx_abort.get() {
+            // Keep the bytes already in the FIFO, and return the buffer with
+            // them. `rx_progress()` completes the reception itself if they
+            // fill the buffer or carry a line error.
+            self.rx_progress();
+            if self.rx_buffer.is_some() {
+                self.rx_complete(Err(ErrorCode::CANCEL), hil::uart::Error::Aborted);
+            }
+        }
     }
 
     pub fn transmit_sync(&self, bytes: &[u8]) {
@@ -293,10 +454,27 @@ impl Uart<'_> {
 impl<'a> hil::uart::UartData<'a> for Uart<'a> {}
 impl<'a> hil::uart::Uart<'a> for Uart<'a> {}
 
+impl<'a> Uart<'a> {
+    /// Power the UART once it has been configured.
+    pub fn set_power_domain(&self, power: &'a PowerDomain<'a>) {
+        self.power.set(power);
+        if self.powered.get() {
+            power.in_use();
+        }
+    }
+}
+
 impl hil::uart::Configure for Uart<'_> {
     fn configure(&self, params: hil::uart::Parameters) -> Result<(), ErrorCode> {
         let regs = self.registers;
 
+        // The UART is in use from now on, as there is no way to deconfigure
+        // it.
+        if !self.powered.get() {
+            self.powered.set(true);
+            self.power.map(|power| power.in_use());
+        }
+
         // Disable UART
         regs.cr
             .write(CR::UARTEN::CLEAR + CR::RXE::CLEAR + CR::TXE::CLEAR);
@@ -364,13 +542,32 @@ impl<'a> hil::uart::Receive<'a> for Uart<'a> {
     fn receive_buffer(
         &self,
         rx_buffer: &'static mut [u8],
-        _rx_len: usize,
+        rx_len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        Err((ErrorCode::FAIL, rx_buffer))
+        if rx_len == 0 || rx_len > rx_buffer.len() {
+            Err((ErrorCode::SIZE, rx_buffer))
+        } else if self.rx_buffer.is_some() {
+            Err((ErrorCode::BUSY, rx_buffer))
+        } else {
+            self.rx_buffer.replace(rx_buffer);
+            self.rx_len.set(rx_len);
+            self.rx_index.set(0);
+
+            self.enable_rx_interrupt();
+            Ok(())
+        }
     }
 
     fn receive_abort(&self) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+        if self.rx_buffer.is_none() {
+            return Ok(());
+        }
+        // The client must not be called from here, so the buffer is returned
+        // from the interrupt handler: pend the UART interrupt, which the
+        // kernel loop services like one raised by the hardware.
+        self.rx_abort.set(true);
+        self.nvic.set_pending();
+        Err(ErrorCode::BUSY)
     }
 
     fn receive_word(&self) -> Result<(), ErrorCode> {
@@ -0,0 +1,150 @@
+//! Boot state left by the OpenTitan ROM_EXT.
+//!
+//! The ROM_EXT verifies the signature of the owner stage, the image the
+//! kernel is part of, before starting it, and only starts images whose
+//! security version is at least the minimum recorded in flash, for itself
+//! and for the owner stage. It leaves a boot log in the retention SRAM with
+//! what it did: the ID of the key the owner stage was verified with and the
+//! two minimum security versions, which are the anti-rollback counters.
+//!
+//! The log is the `boot_log_t` of the ROM_EXT, in the creator section of the
+//! retention SRAM. It starts with a SHA-256 digest of the rest of the log,
+//! which is checked with the HMAC block before the log is trusted, as the
+//! retention SRAM keeps whatever was written there across resets. The log
+//! does not record which key verified the owner stage, so `key_id()` is
+//! `None`.
+//!
+//! Read the log early, before the kernel could reuse the retention SRAM or
+//! use the HMAC block:
+//!
+//! ```rust
+//! let boot_status = static_init!(
+//!     earlgrey::boot_log::BootLog,
+//!     earlgrey::boot_log::BootLog::read(earlgrey::boot_log::BOOT_LOG_BASE, &peripherals.hmac)
+//! );
+//! ```
+
+use core::iter;
+
+use kernel::common::registers::{register_structs, ReadOnly};
+use kernel::common::StaticRef;
+use kernel::hil::secure_boot::{SecureBootStatus, Verification};
+use lowrisc::hmac::Hmac;
+
+/// The boot log follows the version of the retention SRAM layout, and the
+/// reset reasons and last shutdown reason of the creator section.
+pub const BOOT_LOG_BASE: StaticRef<BootLogRegisters> =
+    unsafe { StaticRef::new(0x4060_000C as *const BootLogRegisters) };
+
+/// "BLOG", the identifier of a boot log.
+const BOOT_LOG_IDENTIFIER: u32 = 0x474f_4c42;
+
+/// The SHA-256 digest at the start of the log, in words.
+const DIGEST_WORDS: usize = 8;
+
+/// The anti-rollback counters: the minimum security versions of the ROM_EXT
+/// and of the owner stage.
+const ROLLBACK_COUNTERS: usize = 2;
+
+register_structs! {
+    pub BootLogRegisters {
+        (0x00 => digest: [ReadOnly<u32>; DIGEST_WORDS]),
+        (0x20 => identifier: ReadOnly<u32>),
+        (0x24 => chip_version: [ReadOnly<u32>; 2]),
+        (0x2C => rom_ext_slot: ReadOnly<u32>),
+        (0x30 => rom_ext_version: ReadOnly<u32>),
+        (0x34 => rom_ext_size: ReadOnly<u32>),
+        (0x38 => rom_ext_nonce: [ReadOnly<u32>; 2]),
+        (0x40 => bl0_slot: ReadOnly<u32>),
+        (0x44 => ownership_state: ReadOnly<u32>),
+        (0x48 => ownership_transfers: ReadOnly<u32>),
+        (0x4C => rom_ext_min_sec_ver: ReadOnly<u32>),
+        (0x50 => bl0_min_sec_ver: ReadOnly<u32>),
+        (0x54 => primary_bl0_slot: ReadOnly<u32>),
+        (0x58 => reserved: [ReadOnly<u32>; 10]),
+        (0x80 => @END),
+    }
+}
+
+pub struct BootLog {
+    /// Whether the log is one the ROM_EXT wrote.
+    valid: bool,
+    rollback_counters: [u32; ROLLBACK_COUNTERS],
+}
+
+impl BootLog {
+    /// Read the log, and check it against its digest with `hmac`. A log
+    /// without the identifier or whose digest does not match reports
+    /// `Verification::Unknown`.
+    pub fn read(registers: StaticRef<BootLogRegisters>, hmac: &Hmac) -> BootLog {
+        let invalid = BootLog {
+            valid: false,
+            rollback_counters: [0; ROLLBACK_COUNTERS],
+        };
+        if registers.identifier.get() != BOOT_LOG_IDENTIFIER {
+            return invalid;
+        }
+
+        // The digest covers the log from the identifier on.
+        let covered = iter::once(&registers.identifier)
+            .chain(registers.chip_version.iter())
+            .chain(iter::once(&registers.rom_ext_slot))
+            .chain(iter::once(&registers.rom_ext_version))
+            .chain(iter::once(&registers.rom_ext_size))
+            .chain(registers.rom_ext_nonce.iter())
+            .chain(iter::once(&registers.bl0_slot))
+            .chain(iter::once(&registers.ownership_state))
+            .chain(iter::once(&registers.ownership_transfers))
+            .chain(iter::once(&registers.rom_ext_min_sec_ver))
+            .chain(iter::once(&registers.bl0_min_sec_ver))
+            .chain(iter::once(&registers.primary_bl0_slot))
+            .chain(registers.reserved.iter());
+        let digest = hmac.sha256_blocking(covered.map(|word| word.get()));
+        if digest
+            .iter()
+            .zip(registers.digest.iter())
+            .any(|(word, stored)| *word != stored.get())
+        {
+            return invalid;
+        }
+
+        BootLog {
+            valid: true,
+            rollback_counters: [
+                registers.rom_ext_min_sec_ver.get(),
+                registers.bl0_min_sec_ver.get(),
+            ],
+        }
+    }
+}
+
+impl SecureBootStatus for BootLog {
+    fn verification(&self) -> Verification {
+        // The ROM_EXT only starts owner stages whose signature it verified.
+        if self.valid {
+            Verification::Signature
+        } else {
+            Verification::Unknown
+        }
+    }
+
+    fn key_id(&self) -> Option<u32> {
+        None
+    }
+
+    fn rollback_counters(&self) -> usize {
+        if self.valid {
+            ROLLBACK_COUNTERS
+        } else {
+            0
+        }
+    }
+
+    fn rollback_counter(&self, index: usize) -> Option<u32> {
+        if self.valid {
+            self.rollback_counters.get(index).copied()
+        } else {
+            None
+        }
+    }
+}
@@ -9,6 +9,7 @@ pub mod chip_config;
 mod interrupts;
 
 pub mod aes;
+pub mod boot_log;
 pub mod chip;
 pub mod flash_ctrl;
 pub mod gpio;
@@ -143,6 +143,32 @@ impl Hmac<'_> {
         regs.intr_enable.modify(INTR_ENABLE::FIFO_EMPTY::CLEAR);
     }
 
+    /// Compute the SHA-256 digest of `words`, waiting for the hardware
+    /// instead of using interrupts, for use during early boot. The words are
+    /// hashed as little endian, and the digest is returned as the boot
+    /// stages store it: the least significant word first.
+    pub fn sha256_blocking<I: Iterator<Item = u32>>(&self, words: I) -> [u32; 8] {
+        let regs = self.registers;
+
+        regs.cfg.write(CFG::SHA_EN::SET);
+        regs.cmd.modify(CMD::START::SET);
+        for word in words {
+            while regs.status.is_set(STATUS::FIFO_FULL) {}
+            regs.msg_fifo.set(word);
+        }
+        regs.cmd.modify(CMD::PROCESS::SET);
+        while !regs.intr_state.is_set(INTR_STATE::HMAC_DONE) {}
+        regs.intr_state.write(INTR_STATE::HMAC_DONE::SET);
+
+        let mut digest = [0; 8];
+        for (i, word) in digest.iter_mut().enumerate() {
+            *word = regs.digest[7 - i].get();
+        }
+        regs.cfg.set(0);
+        regs.wipe_secret.set(1);
+        digest
+    }
+
     pub fn handle_interrupt(&self) {
         let regs = self.registers;
         let intrs = regs.intr_state.extract();
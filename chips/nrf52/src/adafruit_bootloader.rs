@@ -0,0 +1,80 @@
+//! Boot state left by the Adafruit nRF52 bootloader.
+//!
+//! The bootloader keeps its settings in the last page of flash. They record
+//! whether the application bank holds a valid image and, if the image was
+//! flashed through DFU, its CRC-16, which the bootloader checks before
+//! starting the application. Images copied over UF2 have no CRC and are
+//! started without being checked. The bootloader has no keys and no
+//! anti-rollback counters.
+//!
+//! Read the settings early, before anything writes to the last page of flash:
+//!
+//! ```rust
+//! let boot_status = static_init!(
+//!     nrf52::adafruit_bootloader::AdafruitBootloaderInfo,
+//!     nrf52::adafruit_bootloader::AdafruitBootloaderInfo::read(
+//!         nrf52::adafruit_bootloader::NRF52840_SETTINGS
+//!     )
+//! );
+//! ```
+
+use kernel::common::registers::ReadOnly;
+use kernel::common::StaticRef;
+use kernel::hil::secure_boot::{SecureBootStatus, Verification};
+
+/// Settings of the bootloader on the nRF52840.
+pub const NRF52840_SETTINGS: StaticRef<BootloaderSettings> =
+    unsafe { StaticRef::new(0x000F_F000 as *const BootloaderSettings) };
+/// Settings of the bootloader on the nRF52832.
+pub const NRF52832_SETTINGS: StaticRef<BootloaderSettings> =
+    unsafe { StaticRef::new(0x0007_F000 as *const BootloaderSettings) };
+
+/// The bank holds a valid application.
+const BANK_VALID_APP: u32 = 0x01;
+
+/// `bootloader_settings_t` of the bootloader.
+#[repr(C)]
+pub struct BootloaderSettings {
+    /// What bank 0 holds.
+    bank_0: ReadOnly<u32>,
+    /// CRC-16 of the image in bank 0, or 0 if it is not checked. The upper
+    /// half is padding.
+    bank_0_crc: ReadOnly<u32>,
+}
+
+pub struct AdafruitBootloaderInfo {
+    verification: Verification,
+}
+
+impl AdafruitBootloaderInfo {
+    pub fn read(settings: StaticRef<BootloaderSettings>) -> AdafruitBootloaderInfo {
+        let verification = if settings.bank_0.get() != BANK_VALID_APP {
+            // The bootloader did not start the application from its bank,
+            // so something else did.
+            Verification::Unknown
+        } else if settings.bank_0_crc.get() & 0xffff == 0 {
+            Verification::Unverified
+        } else {
+            Verification::Integrity
+        };
+        AdafruitBootloaderInfo { verification }
+    }
+}
+
+impl SecureBootStatus for AdafruitBootloaderInfo {
+    fn verification(&self) -> Verification {
+        self.verification
+    }
+
+    fn key_id(&self) -> Option<u32> {
+        None
+    }
+
+    fn rollback_counters(&self) -> usize {
+        0
+    }
+
+    fn rollback_counter(&self, _index: usize) -> Option<u32> {
+        None
+    }
+}
@@ -32,6 +32,30 @@
 //! * Payload - 2 to 255 bytes
 //!
 //! * CRC - 3 bytes
+//!
+//! ### Extended Advertising
+//!
+//! The auxiliary packet of an extended advertisement must start exactly
+//! `AUX_OFFSET_US` after the start of its primary packet, which interrupt
+//! handling cannot guarantee. Instead, TIMER2 is started by the address
+//! event of the primary packet, through PPI channel 18, and once it
+//! expires, PPI channel 19 starts the radio for the auxiliary packet. The
+//! interrupt at the end of the primary packet only configures the radio for
+//! the auxiliary packet in the meantime. Extended advertising needs
+//! `set_aux_timer()`.
+//!
+//! ### Scan Responses
+//!
+//! After a scannable advertisement, the radio listens for a scan request
+//! through a shortcut. The timer, started at the end of the advertisement
+//! through PPI channel 18 and stopped by an address event through PPI
+//! channel 17, disables the radio through PPI channel 19 if no request
+//! begins in time. Once the address of a request is received, the interrupt
+//! sets up the response, which a shortcut starts `T_IFS` after the end of the
+//! request. The interrupt at the end of the request cancels the response if
+//! the request is for another advertiser. Both interrupts must be handled
+//! within about 150 µs of their event, or the request is left unanswered.
+//! Scan responses need `set_aux_timer()` as well.
 
 use core::cell::Cell;
 use core::convert::TryFrom;
@@ -40,13 +64,54 @@ use kernel::common::cells::TakeCell;
 use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil::ble_advertising;
-use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_advertising::{Phy, RadioChannel};
 use kernel::ErrorCode;
 use nrf5x::constants::TxPower;
+use nrf5x::timer::Timer;
+
+use crate::ppi::{self, Ppi};
+
+use crate::radio_arbiter::{Protocol, RadioArbiter, RadioUser};
 
 const RADIO_BASE: StaticRef<RadioRegisters> =
     unsafe { StaticRef::new(0x40001000 as *const RadioRegisters) };
 
+/// Time from the start of a primary packet to the start of its auxiliary
+/// packet, in microseconds. It leaves the interrupt at the end of the
+/// primary packet over a millisecond to configure the auxiliary one.
+const AUX_OFFSET_US: u32 = 1500;
+/// Time from the start of a packet at 1 Mbit/s to its address event: the
+/// preamble and the access address.
+const ADDRESS_US: u32 = 40;
+/// Time for the radio to ramp up in fast mode.
+const FAST_RAMP_UP_US: u32 = 40;
+/// Time from the address event of the primary packet to enabling the radio
+/// for the auxiliary packet.
+const AUX_TXEN_US: u32 = AUX_OFFSET_US - ADDRESS_US - FAST_RAMP_UP_US;
+/// Time the auxiliary packet must be configured by before the radio is
+/// enabled for it.
+const AUX_MARGIN_US: u32 = 20;
+
+/// Inter frame space between a scannable advertisement and a scan request,
+/// and between the request and its response.
+const T_IFS_US: u32 = 150;
+/// Time from the end of a scannable advertisement to disabling the radio,
+/// unless the address of a scan request was received.
+const SCAN_REQ_TIMEOUT_US: u32 = T_IFS_US + ADDRESS_US + 20;
+/// Length of the payload of a scan request: ScanA and AdvA.
+const SCAN_REQ_LENGTH: u8 = 12;
+/// PDU type of a scan request.
+const SCAN_REQ: u8 = 0b0011;
+
+/// PPI channel stopping the timer at the address event of a scan request.
+const PPI_SCAN_REQ_ADDRESS: usize = 17;
+/// PPI channel starting the timer at the address event of the primary
+/// packet, or at the end of a scannable advertisement.
+const PPI_AUX_TIMER: usize = 18;
+/// PPI channel enabling the radio for the auxiliary packet, or disabling it
+/// after a scannable advertisement, once the timer expires.
+const PPI_AUX_TXEN: usize = 19;
+
 #[repr(C)]
 struct RadioRegisters {
     /// Enable Radio in TX mode
@@ -349,7 +414,8 @@ register_bitfields! [u32,
             NRF_1MBIT = 0,
             NRF_2MBIT = 1,
             NRF_250KBIT = 2,
-            BLE_1MBIT = 3
+            BLE_1MBIT = 3,
+            BLE_2MBIT = 4
         ]
     ],
     /// Packet configuration register 0
@@ -526,15 +592,46 @@ register_bitfields! [u32,
     ]
 ];
 
+/// An advertising operation, kept while waiting for the radio.
+#[derive(Clone, Copy)]
+enum Operation {
+    Transmit(RadioChannel),
+    Receive(RadioChannel),
+    /// The primary packet of an extended advertisement, on the first channel,
+    /// to be followed by its auxiliary packet on the second.
+    TransmitExtended(RadioChannel, RadioChannel, Phy),
+    /// The auxiliary packet of an extended advertisement.
+    TransmitAuxiliary,
+    /// A scannable advertisement, to be followed by a window for a scan
+    /// request.
+    TransmitScannable(RadioChannel),
+    /// Listening for a scan request after a scannable advertisement.
+    ScanRequestWindow,
+    /// Receiving what may be a scan request, with the scan response set up
+    /// to follow it.
+    ReceiveScanRequest,
+    /// The scan response.
+    TransmitScanResponse,
+}
+
 static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
     [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
 
+/// The auxiliary packet, or the scan response: S0, length and up to 255
+/// bytes of payload.
+static mut AUX_PAYLOAD: [u8; 257] = [0x00; 257];
+
 pub struct Radio<'a> {
     registers: StaticRef<RadioRegisters>,
     tx_power: Cell<TxPower>,
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
     tx_client: OptionalCell<&'a dyn ble_advertising::TxClient>,
     buffer: TakeCell<'static, [u8]>,
+    rssi: Cell<Option<i8>>,
+    operation: Cell<Option<Operation>>,
+    arbiter: OptionalCell<&'a dyn RadioArbiter>,
+    aux_timer: OptionalCell<&'a Timer>,
+    ppi: Ppi,
 }
 
 impl<'a> Radio<'a> {
@@ -545,11 +642,275 @@ impl<'a> Radio<'a> {
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
+            rssi: Cell::new(None),
+            operation: Cell::new(None),
+            arbiter: OptionalCell::empty(),
+            aux_timer: OptionalCell::empty(),
+            ppi: Ppi::new(),
+        }
+    }
+
+    /// Time auxiliary packets of extended advertisements with `timer`, which
+    /// is then reserved for the radio. Without it, the radio doesn't support
+    /// extended advertising.
+    pub fn set_aux_timer(&self, timer: &'a Timer) {
+        self.aux_timer.set(timer);
+    }
+
+    /// Share the radio with 802.15.4 through `arbiter`.
+    pub fn set_arbiter(&self, arbiter: &'a dyn RadioArbiter) {
+        self.arbiter.set(arbiter);
+    }
+
+    // Start `operation` once the arbiter grants the radio.
+    fn start(&self, operation: Operation) {
+        self.operation.set(Some(operation));
+        if self
+            .arbiter
+            .map_or(true, |arbiter| arbiter.request(Protocol::Ble))
+        {
+            self.start_operation();
+        }
+    }
+
+    fn start_operation(&self) {
+        match self.operation.get() {
+            Some(Operation::Transmit(channel)) => {
+                self.ble_initialize(channel);
+                self.tx();
+                self.enable_interrupts();
+            }
+            Some(Operation::Receive(channel)) => {
+                self.ble_initialize(channel);
+                self.rx();
+                self.enable_interrupts();
+            }
+            Some(Operation::TransmitExtended(channel, _, _)) => {
+                self.ble_initialize(channel);
+                self.arm_aux_timer();
+                // Disable the radio right after the primary packet, so it can
+                // be enabled again for the auxiliary one
+                self.registers.shorts.write(Shortcut::END_DISABLE::SET);
+                self.tx();
+                self.enable_interrupts();
+            }
+            Some(Operation::TransmitScannable(channel)) => {
+                self.ble_initialize(channel);
+                self.arm_scan_request_timer();
+                // Listen for a scan request right after the advertisement
+                self.registers.shorts.write(
+                    Shortcut::READY_START::SET
+                        + Shortcut::END_DISABLE::SET
+                        + Shortcut::DISABLED_RXEN::SET,
+                );
+                self.registers.modecnf0.write(RadioModeConfig::RU::FAST);
+                self.tx();
+                self.enable_interrupts();
+            }
+            // The auxiliary packet and the steps of a scan response only
+            // follow their advertisement
+            Some(Operation::TransmitAuxiliary)
+            | Some(Operation::ScanRequestWindow)
+            | Some(Operation::ReceiveScanRequest)
+            | Some(Operation::TransmitScanResponse)
+            | None => (),
         }
     }
 
     pub fn is_enabled(&self) -> bool {
-        self.registers.mode.matches_all(Mode::MODE::BLE_1MBIT)
+        self.registers.mode.matches_any(Mode::MODE::BLE_1MBIT)
+            || self.registers.mode.matches_any(Mode::MODE::BLE_2MBIT)
+    }
+
+    // Start the timer of the auxiliary packet at the address event of the
+    // primary packet.
+    fn arm_aux_timer(&self) {
+        self.aux_timer.map(|timer| {
+            timer.setup_oneshot_us(AUX_TXEN_US);
+            self.ppi.set_endpoints(
+                PPI_AUX_TIMER,
+                &self.registers.event_address as *const _ as u32,
+                timer.task_start_address(),
+            );
+            self.ppi.set_endpoints(
+                PPI_AUX_TXEN,
+                timer.event_compare0_address(),
+                &self.registers.task_txen as *const _ as u32,
+            );
+            self.ppi.enable(ppi::Channel::CH18::SET);
+        });
+    }
+
+    // Configure the radio for the auxiliary packet once the primary packet is
+    // sent, and let the timer enable it. Fails if the timer is about to
+    // expire, or did, before the radio could be configured.
+    fn start_auxiliary(&self, channel: RadioChannel, phy: Phy) -> Result<(), ErrorCode> {
+        let timer = self.aux_timer.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        self.ppi.disable(ppi::Channel::CH18::SET);
+
+        self.registers.shorts.write(Shortcut::READY_START::SET);
+        self.registers.modecnf0.write(RadioModeConfig::RU::FAST);
+        self.ble_set_phy(phy);
+        self.ble_set_channel_freq(channel);
+        self.ble_set_data_whitening(channel);
+        unsafe {
+            self.registers.packetptr.set(AUX_PAYLOAD.as_ptr() as u32);
+        }
+
+        if timer.now() + AUX_MARGIN_US >= AUX_TXEN_US {
+            return Err(ErrorCode::FAIL);
+        }
+        self.ppi.enable(ppi::Channel::CH19::SET);
+        // If the timer expired before the channel was enabled, nothing will
+        // enable the radio
+        if timer.now() >= AUX_TXEN_US
+            && self.registers.state.get() == nrf5x::constants::RADIO_STATE_DISABLE
+        {
+            self.ppi.disable(ppi::Channel::CH19::SET);
+            return Err(ErrorCode::FAIL);
+        }
+        self.operation.set(Some(Operation::TransmitAuxiliary));
+        Ok(())
+    }
+
+    // Start the timer at the end of a scannable advertisement, and disable
+    // the radio once it expires, unless the address of a scan request stopped
+    // it.
+    fn arm_scan_request_timer(&self) {
+        self.aux_timer.map(|timer| {
+            timer.setup_oneshot_us(SCAN_REQ_TIMEOUT_US);
+            self.ppi.set_endpoints(
+                PPI_SCAN_REQ_ADDRESS,
+                &self.registers.event_address as *const _ as u32,
+                timer.task_stop_address(),
+            );
+            self.ppi.set_endpoints(
+                PPI_AUX_TIMER,
+                &self.registers.event_end as *const _ as u32,
+                timer.task_start_address(),
+            );
+            self.ppi.set_endpoints(
+                PPI_AUX_TXEN,
+                timer.event_compare0_address(),
+                &self.registers.task_disable as *const _ as u32,
+            );
+            self.ppi.enable(
+                ppi::Channel::CH17::SET + ppi::Channel::CH18::SET + ppi::Channel::CH19::SET,
+            );
+        });
+    }
+
+    // Whether the packet received is a scan request for the AdvA of the scan
+    // response, with the same address type.
+    fn is_scan_request_for_us(&self) -> bool {
+        if !self.registers.crcstatus.is_set(Event::READY) {
+            return false;
+        }
+        unsafe {
+            PAYLOAD[0] & 0x0f == SCAN_REQ
+                && PAYLOAD[1] == SCAN_REQ_LENGTH
+                && (PAYLOAD[0] >> 7) == (AUX_PAYLOAD[0] >> 6) & 1
+                && PAYLOAD[8..14] == AUX_PAYLOAD[2..8]
+        }
+    }
+
+    // Follow a scannable advertisement through its scan request window and
+    // its scan response. Shortcuts move the radio from one to the next, so
+    // the events of several of them may be pending at once.
+    fn handle_scannable_interrupt(&self) {
+        let timer = match self.aux_timer.extract() {
+            Some(timer) => timer,
+            None => return self.timed_done(Err(ErrorCode::NOSUPPORT)),
+        };
+        self.registers.event_ready.write(Event::READY::CLEAR);
+        self.registers.event_payload.write(Event::READY::CLEAR);
+
+        if let Some(Operation::TransmitScannable(_)) = self.operation.get() {
+            // The address event of the advertisement itself
+            self.registers.event_address.write(Event::READY::CLEAR);
+            if !self.registers.event_end.is_set(Event::READY) {
+                return;
+            }
+            self.registers.event_end.write(Event::READY::CLEAR);
+            self.registers.event_disabled.write(Event::READY::CLEAR);
+            self.ppi.disable(ppi::Channel::CH18::SET);
+            // Listen only once
+            self.registers
+                .shorts
+                .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+            // A scan request may have come and gone already
+            if timer.now() >= T_IFS_US {
+                return self.timed_done(Ok(()));
+            }
+            self.operation.set(Some(Operation::ScanRequestWindow));
+        }
+
+        if let Some(Operation::ScanRequestWindow) = self.operation.get() {
+            if self.registers.event_address.is_set(Event::READY) {
+                self.registers.event_address.write(Event::READY::CLEAR);
+                // Send the scan response T_IFS after the end of the packet,
+                // unless it turns out not to be a scan request for us
+                unsafe {
+                    self.registers.packetptr.set(AUX_PAYLOAD.as_ptr() as u32);
+                }
+                self.registers.modecnf0.write(RadioModeConfig::RU::DEFAULT);
+                self.registers
+                    .tifs
+                    .write(InterFrameSpacing::TIFS.val(T_IFS_US));
+                self.registers.shorts.write(
+                    Shortcut::READY_START::SET
+                        + Shortcut::END_DISABLE::SET
+                        + Shortcut::DISABLED_TXEN::SET,
+                );
+                self.operation.set(Some(Operation::ReceiveScanRequest));
+            } else if self.registers.event_disabled.is_set(Event::READY) {
+                // The timer disabled the radio: no scan request came
+                return self.timed_done(Ok(()));
+            }
+        }
+
+        if let Some(Operation::ReceiveScanRequest) = self.operation.get() {
+            if !self.registers.event_end.is_set(Event::READY) {
+                return;
+            }
+            self.registers.event_end.write(Event::READY::CLEAR);
+            if !self.is_scan_request_for_us() {
+                return self.timed_done(Ok(()));
+            }
+            while self.registers.state.get() == nrf5x::constants::RADIO_STATE_RXDISABLE {}
+            // If the shortcut was set up too late, the response never started
+            if self.registers.state.get() == nrf5x::constants::RADIO_STATE_DISABLE {
+                return self.timed_done(Ok(()));
+            }
+            self.registers
+                .shorts
+                .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+            self.operation.set(Some(Operation::TransmitScanResponse));
+            return;
+        }
+
+        if let Some(Operation::TransmitScanResponse) = self.operation.get() {
+            if self.registers.event_end.is_set(Event::READY) {
+                self.registers.event_end.write(Event::READY::CLEAR);
+                self.timed_done(Ok(()));
+            }
+        }
+    }
+
+    fn stop_aux_timer(&self) {
+        self.ppi
+            .disable(ppi::Channel::CH17::SET + ppi::Channel::CH18::SET + ppi::Channel::CH19::SET);
+        self.aux_timer.map(|timer| timer.stop());
+    }
+
+    // The extended or scannable advertisement is over, whether all its
+    // packets were sent or not.
+    fn timed_done(&self, result: Result<(), ErrorCode>) {
+        self.stop_aux_timer();
+        self.operation.set(None);
+        self.radio_off();
+        self.tx_client
+            .map(|client| client.transmit_event(self.buffer.take().unwrap(), result));
     }
 
     fn tx(&self) {
@@ -559,6 +920,11 @@ impl<'a> Radio<'a> {
 
     fn rx(&self) {
         self.registers.event_ready.write(Event::READY::CLEAR);
+        self.registers.event_rssiend.write(Event::READY::CLEAR);
+        // Sample the signal strength once the access address is received
+        self.registers
+            .shorts
+            .write(Shortcut::ADDRESS_RSSISTART::SET + Shortcut::DISABLED_RSSISTOP::SET);
         self.registers.task_rxen.write(Task::ENABLE::SET);
     }
 
@@ -598,10 +964,23 @@ impl<'a> Radio<'a> {
     pub fn handle_interrupt(&self) {
         self.disable_all_interrupts();
 
+        if self.is_scannable() {
+            self.handle_scannable_interrupt();
+            if self.operation.get().is_none() {
+                self.arbiter.map(|arbiter| arbiter.release(Protocol::Ble));
+            }
+            self.enable_interrupts();
+            return;
+        }
+
         if self.registers.event_ready.is_set(Event::READY) {
             self.registers.event_ready.write(Event::READY::CLEAR);
-            self.registers.event_end.write(Event::READY::CLEAR);
-            self.registers.task_start.write(Task::ENABLE::SET);
+            // The auxiliary packet is started by a shortcut, and may already
+            // be over
+            if !matches!(self.operation.get(), Some(Operation::TransmitAuxiliary)) {
+                self.registers.event_end.write(Event::READY::CLEAR);
+                self.registers.task_start.write(Task::ENABLE::SET);
+            }
         }
 
         if self.registers.event_address.is_set(Event::READY) {
@@ -621,36 +1000,71 @@ impl<'a> Radio<'a> {
                 Err(ErrorCode::FAIL)
             };
 
-            match self.registers.state.get() {
-                nrf5x::constants::RADIO_STATE_TXRU
-                | nrf5x::constants::RADIO_STATE_TXIDLE
-                | nrf5x::constants::RADIO_STATE_TXDISABLE
-                | nrf5x::constants::RADIO_STATE_TX => {
-                    self.radio_off();
-                    self.tx_client
-                        .map(|client| client.transmit_event(self.buffer.take().unwrap(), result));
-                }
-                nrf5x::constants::RADIO_STATE_RXRU
-                | nrf5x::constants::RADIO_STATE_RXIDLE
-                | nrf5x::constants::RADIO_STATE_RXDISABLE
-                | nrf5x::constants::RADIO_STATE_RX => {
-                    self.radio_off();
-                    unsafe {
-                        self.rx_client.map(|client| {
-                            // Length is: S0 (1 Byte) + Length (1 Byte) + S1 (0 Bytes) + Payload
-                            // And because the length field is directly read from the packet
-                            // We need to add 2 to length to get the total length
-                            client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
-                        });
+            match self.operation.get() {
+                Some(Operation::TransmitExtended(_, secondary, phy)) => {
+                    if let Err(e) = self.start_auxiliary(secondary, phy) {
+                        self.timed_done(Err(e));
                     }
                 }
-                // Radio state - Disabled
-                _ => (),
+                Some(Operation::TransmitAuxiliary) => self.timed_done(Ok(())),
+                _ => self.end_operation(result),
+            }
+            if self.operation.get().is_none() {
+                self.arbiter.map(|arbiter| arbiter.release(Protocol::Ble));
             }
         }
         self.enable_interrupts();
     }
 
+    fn is_scannable(&self) -> bool {
+        matches!(
+            self.operation.get(),
+            Some(Operation::TransmitScannable(_))
+                | Some(Operation::ScanRequestWindow)
+                | Some(Operation::ReceiveScanRequest)
+                | Some(Operation::TransmitScanResponse)
+        )
+    }
+
+    // Hand the result of a legacy advertising operation to its client.
+    fn end_operation(&self, result: Result<(), ErrorCode>) {
+        // The client may start the next operation from the callback
+        self.operation.set(None);
+        match self.registers.state.get() {
+            nrf5x::constants::RADIO_STATE_TXRU
+            | nrf5x::constants::RADIO_STATE_TXIDLE
+            | nrf5x::constants::RADIO_STATE_TXDISABLE
+            | nrf5x::constants::RADIO_STATE_TX => {
+                self.radio_off();
+                self.tx_client
+                    .map(|client| client.transmit_event(self.buffer.take().unwrap(), result));
+            }
+            nrf5x::constants::RADIO_STATE_RXRU
+            | nrf5x::constants::RADIO_STATE_RXIDLE
+            | nrf5x::constants::RADIO_STATE_RXDISABLE
+            | nrf5x::constants::RADIO_STATE_RX => {
+                // The sample is the magnitude of the RSSI in dBm
+                self.rssi
+                    .set(if self.registers.event_rssiend.is_set(Event::READY) {
+                        Some(-(self.registers.rssisample.read(RssiSample::RSSISAMPLE) as i8))
+                    } else {
+                        None
+                    });
+                self.radio_off();
+                unsafe {
+                    self.rx_client.map(|client| {
+                        // Length is: S0 (1 Byte) + Length (1 Byte) + S1 (0 Bytes) + Payload
+                        // And because the length field is directly read from the packet
+                        // We need to add 2 to length to get the total length
+                        client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
+                    });
+                }
+            }
+            // Radio state - Disabled
+            _ => (),
+        }
+    }
+
     pub fn enable_interrupts(&self) {
         self.registers.intenset.write(
             Interrupt::READY::SET
@@ -658,6 +1072,11 @@ impl<'a> Radio<'a> {
                 + Interrupt::PAYLOAD::SET
                 + Interrupt::END::SET,
         );
+        // The end of a scan request window without a request is only seen
+        // as the radio being disabled
+        if let Some(Operation::ScanRequestWindow) = self.operation.get() {
+            self.registers.intenset.write(Interrupt::DISABLED::SET);
+        }
     }
 
     pub fn enable_interrupt(&self, intr: u32) {
@@ -673,9 +1092,9 @@ impl<'a> Radio<'a> {
         self.registers.intenclr.set(0xffffffff);
     }
 
-    fn replace_radio_buffer(&self, buf: &'static mut [u8]) -> &'static mut [u8] {
+    fn replace_radio_buffer(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8] {
         // set payload
-        for (i, c) in buf.as_ref().iter().enumerate() {
+        for (i, c) in buf.as_ref().iter().take(len).enumerate() {
             unsafe {
                 PAYLOAD[i] = *c;
             }
@@ -759,6 +1178,25 @@ impl<'a> Radio<'a> {
         self.registers.mode.write(Mode::MODE::BLE_1MBIT);
     }
 
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.1 Packet Format
+    // The LE 2M PHY has a 2-byte preamble
+    fn ble_set_phy(&self, phy: Phy) {
+        match phy {
+            Phy::Le2M => {
+                self.registers.mode.write(Mode::MODE::BLE_2MBIT);
+                self.registers
+                    .pcnf0
+                    .modify(PacketConfiguration0::PLEN::SIXTEEN);
+            }
+            Phy::Le1M | Phy::LeCoded => {
+                self.registers.mode.write(Mode::MODE::BLE_1MBIT);
+                self.registers
+                    .pcnf0
+                    .modify(PacketConfiguration0::PLEN::EIGHT);
+            }
+        }
+    }
+
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.2 Data Whitening
     // Configure channel index to the LFSR and the hardware solves the rest
     fn ble_set_data_whitening(&self, channel: RadioChannel) {
@@ -787,18 +1225,14 @@ impl<'a> Radio<'a> {
 }
 
 impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
-    fn transmit_advertisement(&self, buf: &'static mut [u8], _len: usize, channel: RadioChannel) {
-        let res = self.replace_radio_buffer(buf);
+    fn transmit_advertisement(&self, buf: &'static mut [u8], len: usize, channel: RadioChannel) {
+        let res = self.replace_radio_buffer(buf, len);
         self.buffer.replace(res);
-        self.ble_initialize(channel);
-        self.tx();
-        self.enable_interrupts();
+        self.start(Operation::Transmit(channel));
     }
 
     fn receive_advertisement(&self, channel: RadioChannel) {
-        self.ble_initialize(channel);
-        self.rx();
-        self.enable_interrupts();
+        self.start(Operation::Receive(channel));
     }
 
     fn set_receive_client(&self, client: &'a dyn ble_advertising::RxClient) {
@@ -808,9 +1242,92 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     fn set_transmit_client(&self, client: &'a dyn ble_advertising::TxClient) {
         self.tx_client.set(client);
     }
+
+    fn last_rssi(&self) -> Option<i8> {
+        self.rssi.get()
+    }
+}
+
+impl<'a> ble_advertising::BleExtendedAdvertisementDriver<'a> for Radio<'a> {
+    // The coded PHY needs a different packet format, which isn't supported
+    fn supports_secondary_phy(&self, phy: Phy) -> bool {
+        self.aux_timer.is_some() && phy != Phy::LeCoded
+    }
+
+    fn aux_offset_us(&self) -> u32 {
+        AUX_OFFSET_US
+    }
+
+    fn transmit_extended_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        primary_len: usize,
+        aux_len: usize,
+        channel: RadioChannel,
+        secondary: RadioChannel,
+        phy: Phy,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.supports_secondary_phy(phy) {
+            return Err((ErrorCode::NOSUPPORT, buf));
+        }
+        if primary_len + aux_len > buf.len() {
+            return Err((ErrorCode::SIZE, buf));
+        }
+        let buf = self.replace_radio_buffer(buf, primary_len);
+        unsafe {
+            for (dst, src) in AUX_PAYLOAD
+                .iter_mut()
+                .zip(buf[primary_len..primary_len + aux_len].iter())
+            {
+                *dst = *src;
+            }
+        }
+        self.buffer.replace(buf);
+        self.start(Operation::TransmitExtended(channel, secondary, phy));
+        Ok(())
+    }
+}
+
+impl<'a> ble_advertising::BleScanResponseDriver<'a> for Radio<'a> {
+    fn supports_scan_response(&self) -> bool {
+        self.aux_timer.is_some()
+    }
+
+    fn transmit_scannable_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        adv_len: usize,
+        rsp_len: usize,
+        channel: RadioChannel,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.supports_scan_response() {
+            return Err((ErrorCode::NOSUPPORT, buf));
+        }
+        if adv_len + rsp_len > buf.len() {
+            return Err((ErrorCode::SIZE, buf));
+        }
+        let buf = self.replace_radio_buffer(buf, adv_len);
+        unsafe {
+            for (dst, src) in AUX_PAYLOAD
+                .iter_mut()
+                .zip(buf[adv_len..adv_len + rsp_len].iter())
+            {
+                *dst = *src;
+            }
+        }
+        self.buffer.replace(buf);
+        self.start(Operation::TransmitScannable(channel));
+        Ok(())
+    }
 }
 
 impl ble_advertising::BleConfig for Radio<'_> {
+    fn check_tx_power(&self, tx_power: u8) -> Result<(), ErrorCode> {
+        nrf5x::constants::TxPower::try_from(tx_power)
+            .map(|_| ())
+            .map_err(|_| ErrorCode::NOSUPPORT)
+    }
+
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
     fn set_tx_power(&self, tx_power: u8) -> Result<(), ErrorCode> {
@@ -825,4 +1342,34 @@ impl ble_advertising::BleConfig for Radio<'_> {
             }
         }
     }
+
+    // Each advertisement is sent on the channel it is given
+    fn check_channel_map(&self, _channel_map: u8) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+}
+
+impl RadioUser for Radio<'_> {
+    fn preemptible(&self) -> bool {
+        match self.operation.get() {
+            Some(Operation::Transmit(_))
+            | Some(Operation::TransmitExtended(..))
+            | Some(Operation::TransmitAuxiliary)
+            | Some(Operation::TransmitScannable(_))
+            | Some(Operation::ScanRequestWindow)
+            | Some(Operation::ReceiveScanRequest)
+            | Some(Operation::TransmitScanResponse) => false,
+            Some(Operation::Receive(_)) | None => true,
+        }
+    }
+
+    fn radio_granted(&self) {
+        self.start_operation();
+    }
+
+    fn radio_revoked(&self) {
+        // The operation is kept, and restarted when the radio comes back
+        self.disable_all_interrupts();
+        self.radio_off();
+    }
 }
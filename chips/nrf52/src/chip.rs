@@ -1,15 +1,82 @@
 use crate::deferred_call_tasks::DeferredCallTask;
+use core::cell::Cell;
 use core::fmt::Write;
 use cortexm4::{self, nvic};
+use kernel::common::cells::OptionalCell;
 use kernel::common::deferred_call;
 use kernel::hil::time::Alarm;
+use kernel::interrupt_rate::{InterruptMask, InterruptRateMonitor};
 use kernel::InterruptService;
+use kernel::SchedulerTimer;
+
+/// Words of the NVIC interrupt mask, enough for all nRF52 interrupts.
+const MASK_WORDS: usize = 2;
+
+/// Interrupts that an interrupt monitor should never mask: RTC1 drives alarms,
+/// including the one that unmasks a source after a storm, and GPIOTE serves
+/// every GPIO pin, so masking it for one bouncing pin silences all of them.
+/// Limit GPIO interrupts per pin instead, with a monitor given to
+/// `nrf5x::gpio::Port::set_interrupt_monitor()`.
+pub const INTERRUPT_MONITOR_EXEMPT: [u32; 2] = [
+    crate::peripheral_interrupts::RTC1,
+    crate::peripheral_interrupts::GPIOTE,
+];
+
+/// The scheduler timer of the chip: the SysTick, unless the board chose an
+/// RTC compare channel with `NRF52::use_rtc_scheduler_timer()`.
+///
+/// The SysTick stops while the chip sleeps with the HFCLK off, so a process
+/// that sleeps in the middle of its timeslice is not charged for it. The RTC
+/// keeps counting on the 32 kHz clock.
+pub struct Nrf52SchedulerTimer<'a> {
+    systick: cortexm4::systick::SysTick,
+    rtc: OptionalCell<&'a crate::rtc::Rtc<'a>>,
+}
+
+impl SchedulerTimer for Nrf52SchedulerTimer<'_> {
+    fn start(&self, us: u32) {
+        match self.rtc.extract() {
+            Some(rtc) => SchedulerTimer::start(rtc, us),
+            None => self.systick.start(us),
+        }
+    }
+
+    fn reset(&self) {
+        match self.rtc.extract() {
+            Some(rtc) => SchedulerTimer::reset(rtc),
+            None => self.systick.reset(),
+        }
+    }
+
+    fn arm(&self) {
+        match self.rtc.extract() {
+            Some(rtc) => SchedulerTimer::arm(rtc),
+            None => self.systick.arm(),
+        }
+    }
+
+    fn disarm(&self) {
+        match self.rtc.extract() {
+            Some(rtc) => SchedulerTimer::disarm(rtc),
+            None => self.systick.disarm(),
+        }
+    }
+
+    fn get_remaining_us(&self) -> Option<u32> {
+        match self.rtc.extract() {
+            Some(rtc) => SchedulerTimer::get_remaining_us(rtc),
+            None => self.systick.get_remaining_us(),
+        }
+    }
+}
 
 pub struct NRF52<'a, I: InterruptService<DeferredCallTask> + 'a> {
     mpu: cortexm4::mpu::MPU,
     userspace_kernel_boundary: cortexm4::syscall::SysCall,
-    scheduler_timer: cortexm4::systick::SysTick,
+    scheduler_timer: Nrf52SchedulerTimer<'a>,
     interrupt_service: &'a I,
+    interrupt_monitor: OptionalCell<&'a dyn InterruptRateMonitor>,
+    masked_interrupts: [Cell<u32>; MASK_WORDS],
 }
 
 impl<'a, I: InterruptService<DeferredCallTask> + 'a> NRF52<'a, I> {
@@ -19,10 +86,52 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> NRF52<'a, I> {
             userspace_kernel_boundary: cortexm4::syscall::SysCall::new(),
             // The NRF52's systick is uncalibrated, but is clocked from the
             // 64Mhz CPU clock.
-            scheduler_timer: cortexm4::systick::SysTick::new_with_calibration(64000000),
+            scheduler_timer: Nrf52SchedulerTimer {
+                systick: cortexm4::systick::SysTick::new_with_calibration(64000000),
+                rtc: OptionalCell::empty(),
+            },
             interrupt_service,
+            interrupt_monitor: OptionalCell::empty(),
+            masked_interrupts: [Cell::new(0), Cell::new(0)],
         }
     }
+
+    /// Time timeslices with compare channel 1 of `rtc` instead of the
+    /// SysTick, so they keep running while the chip sleeps. The RTC must be
+    /// started and its interrupt serviced, as for alarms.
+    pub fn use_rtc_scheduler_timer(&self, rtc: &'a crate::rtc::Rtc<'a>) {
+        self.scheduler_timer.systick.reset();
+        self.scheduler_timer.rtc.set(rtc);
+    }
+
+    /// Have `monitor` check each interrupt before it is serviced. Interrupts
+    /// it rejects are masked until `unmask_interrupt()` is called.
+    pub fn set_interrupt_monitor(&self, monitor: &'a dyn InterruptRateMonitor) {
+        self.interrupt_monitor.set(monitor);
+    }
+
+    /// Service `interrupt` again after the monitor masked it.
+    pub fn unmask_interrupt(&self, interrupt: u32) {
+        if let Some(word) = self.masked_interrupts.get(interrupt as usize / 32) {
+            word.set(word.get() & !(1 << (interrupt % 32)));
+            unsafe {
+                nvic::Nvic::new(interrupt).enable();
+            }
+        }
+    }
+
+    fn mask_interrupt(&self, interrupt: u32) {
+        if let Some(word) = self.masked_interrupts.get(interrupt as usize / 32) {
+            word.set(word.get() | 1 << (interrupt % 32));
+        }
+    }
+
+    fn interrupt_mask(&self) -> [u32; MASK_WORDS] {
+        [
+            self.masked_interrupts[0].get(),
+            self.masked_interrupts[1].get(),
+        ]
+    }
 }
 
 /// This struct, when initialized, instantiates all peripheral drivers for the apollo3.
@@ -51,6 +160,7 @@ pub struct Nrf52DefaultPeripherals<'a> {
     pub nvmc: crate::nvmc::Nvmc,
     pub clock: crate::clock::Clock,
     pub pwm0: crate::pwm::Pwm,
+    pub qdec: crate::qdec::Qdec<'a>,
 }
 
 impl<'a> Nrf52DefaultPeripherals<'a> {
@@ -77,12 +187,15 @@ impl<'a> Nrf52DefaultPeripherals<'a> {
             nvmc: crate::nvmc::Nvmc::new(),
             clock: crate::clock::Clock::new(),
             pwm0: crate::pwm::Pwm::new(),
+            qdec: crate::qdec::Qdec::new(),
         }
     }
     // Necessary for setting up circular dependencies
     pub fn init(&'a self) {
         self.ieee802154_radio.set_timer_ref(&self.timer0);
         self.timer0.set_alarm_client(&self.ieee802154_radio);
+        // TIMER2 times extended advertisements, and isn't free for boards
+        self.ble_radio.set_aux_timer(&self.timer2);
     }
 }
 impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52DefaultPeripherals<'a> {
@@ -104,6 +217,7 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52DefaultPeripherals<
                     ),
                 }
             }
+            crate::peripheral_interrupts::QDEC => self.qdec.handle_interrupt(),
             crate::peripheral_interrupts::RNG => self.trng.handle_interrupt(),
             crate::peripheral_interrupts::RTC1 => self.rtc.handle_interrupt(),
             crate::peripheral_interrupts::TEMP => self.temp.handle_interrupt(),
@@ -153,10 +267,16 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52DefaultPeripherals<
     }
 }
 
+impl<'a, I: InterruptService<DeferredCallTask> + 'a> InterruptMask for NRF52<'a, I> {
+    fn unmask_interrupt(&self, interrupt: u32) {
+        NRF52::unmask_interrupt(self, interrupt);
+    }
+}
+
 impl<'a, I: InterruptService<DeferredCallTask> + 'a> kernel::Chip for NRF52<'a, I> {
     type MPU = cortexm4::mpu::MPU;
     type UserspaceKernelBoundary = cortexm4::syscall::SysCall;
-    type SchedulerTimer = cortexm4::systick::SysTick;
+    type SchedulerTimer = Nrf52SchedulerTimer<'a>;
     type WatchDog = ();
 
     fn mpu(&self) -> &Self::MPU {
@@ -182,11 +302,22 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> kernel::Chip for NRF52<'a,
                     if !self.interrupt_service.service_deferred_call(task) {
                         panic!("unhandled deferred call task");
                     }
-                } else if let Some(interrupt) = nvic::next_pending() {
+                } else if let Some(interrupt) = nvic::next_pending_with_mask(&self.interrupt_mask())
+                {
+                    let n = nvic::Nvic::new(interrupt);
+                    if !self
+                        .interrupt_monitor
+                        .map_or(true, |monitor| monitor.interrupt(interrupt))
+                    {
+                        // Leave the source disabled, and ignore it while it
+                        // stays pending, until it is unmasked.
+                        self.mask_interrupt(interrupt);
+                        n.clear_pending();
+                        continue;
+                    }
                     if !self.interrupt_service.service_interrupt(interrupt) {
                         panic!("unhandled interrupt {}", interrupt);
                     }
-                    let n = nvic::Nvic::new(interrupt);
                     n.clear_pending();
                     n.enable();
                 } else {
@@ -197,7 +328,7 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> kernel::Chip for NRF52<'a,
     }
 
     fn has_pending_interrupts(&self) -> bool {
-        unsafe { nvic::has_pending() || deferred_call::has_tasks() }
+        unsafe { nvic::has_pending_with_mask(&self.interrupt_mask()) || deferred_call::has_tasks() }
     }
 
     fn sleep(&self) {
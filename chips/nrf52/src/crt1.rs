@@ -50,8 +50,9 @@ pub static BASE_VECTORS: [unsafe extern "C" fn(); 16] = [
     unhandled_interrupt,
     // SVCall
     svc_handler,
-    // Reserved for Debug
-    unhandled_interrupt,
+    // DebugMonitor, which breakpoints in processes raise when a debugger
+    // enables it, treated as a fault of the process
+    hard_fault_handler,
     // Reserved
     unhandled_interrupt,
     // PendSv
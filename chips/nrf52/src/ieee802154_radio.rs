@@ -1,6 +1,7 @@
 //! IEEE 802.15.4 radio driver for nRF52
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryFrom;
 use kernel;
 use kernel::common::cells::{OptionalCell, TakeCell};
@@ -13,6 +14,8 @@ use kernel::ErrorCode;
 use nrf5x;
 use nrf5x::constants::TxPower;
 
+use crate::radio_arbiter::{Protocol, RadioArbiter, RadioUser};
+
 // This driver has some significant flaws -- no ACK support, power cycles
 // the radio after every transmission or reception,
 // doesn't always check hardware for errors and instead defaults to
@@ -31,6 +34,10 @@ pub const IEEE802154_MAX_BE: u8 = 5;
 pub const RAM_LEN_BITS: usize = 8;
 pub const RAM_S1_BITS: usize = 0;
 pub const PREBUF_LEN_BYTES: usize = 2;
+pub const IEEE802154_ED_PERIOD: u32 = 128; //microseconds = 8 symbols
+
+// Scale from the energy detect level to the IEEE 802.15.4 ED value
+const ED_RSSISCALE: u32 = 4;
 
 // artifact of entanglement with rf233 implementation, mac layer
 // places packet data starting PSDU_OFFSET=2 bytes after start of
@@ -150,8 +157,14 @@ struct RadioRegisters {
     /// Stop the bit counter
     /// - Address: 0x030 - 0x034
     task_ccastop: WriteOnly<u32, Task::Register>,
+    /// Start the energy detect measurement used in IEEE 802.15.4 mode
+    /// - Address: 0x034 - 0x038
+    task_edstart: WriteOnly<u32, Task::Register>,
+    /// Stop the energy detect measurement
+    /// - Address: 0x038 - 0x03c
+    task_edstop: WriteOnly<u32, Task::Register>,
     /// Reserved
-    _reserved2: [u32; 51],
+    _reserved2: [u32; 49],
     /// Radio has ramped up and is ready to be started
     /// - Address: 0x100 - 0x104
     event_ready: ReadWrite<u32, Event::Register>,
@@ -192,8 +205,12 @@ struct RadioRegisters {
     /// IEEE 802.15.4 length field received
     /// - Address: 0x138 - 0x13c
     event_framestart: ReadWrite<u32, Event::Register>,
-    /// Reserved
-    _reserved5: [u32; 2],
+    /// Sampling of energy detection complete
+    /// - Address: 0x13c - 0x140
+    event_edend: ReadWrite<u32, Event::Register>,
+    /// The sampling of energy detection has stopped
+    /// - Address: 0x140 - 0x144
+    event_edstopped: ReadWrite<u32, Event::Register>,
     /// Wireless medium in idle - clear to send
     /// - Address: 0x144-0x148
     event_ccaidle: ReadWrite<u32, Event::Register>,
@@ -320,7 +337,13 @@ struct RadioRegisters {
     /// - Address: 0x650 - 0x654
     modecnf0: ReadWrite<u32, RadioModeConfig::Register>,
     /// Reserved
-    _reserved16: [u32; 6],
+    _reserved16: [u32; 4],
+    /// IEEE 802.15.4 energy detect loop count
+    /// - Address: 0x664 - 0x668
+    edcnt: ReadWrite<u32, EnergyDetectCount::Register>,
+    /// IEEE 802.15.4 energy detect level
+    /// - Address: 0x668 - 0x66C
+    edsample: ReadOnly<u32, EnergyDetectSample::Register>,
     /// Clear Channel Assesment (CCA) control register
     /// - Address: 0x66C - 0x670
     ccactrl: ReadWrite<u32, CCAControl::Register>,
@@ -387,6 +410,8 @@ register_bitfields! [u32,
         CRCERROR OFFSET(13) NUMBITS(1),
         /// CCAIDLE event
         FRAMESTART OFFSET(14) NUMBITS(1),
+        /// EDEND event
+        EDEND OFFSET(15) NUMBITS(1),
         /// CCAIDLE event
         CCAIDLE OFFSET(17) NUMBITS(1),
         /// CCABUSY event
@@ -626,6 +651,17 @@ register_bitfields! [u32,
     MACHeaderMask [
         PATTERN OFFSET(0) NUMBITS(32)
     ],
+    /// Energy detect loop count register
+    EnergyDetectCount [
+        /// Number of energy detect scans after the first, whose maximum
+        /// is kept in EDSAMPLE
+        EDCNT OFFSET(0) NUMBITS(21)
+    ],
+    /// Energy detect level register
+    EnergyDetectSample [
+        /// Energy detect level
+        EDLVL OFFSET(0) NUMBITS(8)
+    ],
     CCAControl [
         CCAMODE OFFSET(0) NUMBITS(3) [
             ED_MODE = 0,
@@ -662,6 +698,7 @@ pub struct Radio<'p> {
     tx_power: Cell<TxPower>,
     rx_client: OptionalCell<&'static dyn radio::RxClient>,
     tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    ed_client: OptionalCell<&'static dyn radio::EnergyDetectClient>,
     tx_buf: TakeCell<'static, [u8]>,
     rx_buf: TakeCell<'static, [u8]>,
     addr: Cell<u16>,
@@ -672,6 +709,11 @@ pub struct Radio<'p> {
     random_nonce: Cell<u32>,
     channel: Cell<RadioChannel>,
     transmitting: Cell<bool>,
+    /// Channel energy is being measured on
+    ed_channel: OptionalCell<RadioChannel>,
+    /// A frame is being received
+    receiving: Cell<bool>,
+    arbiter: OptionalCell<&'p dyn RadioArbiter>,
     timer0: OptionalCell<&'p crate::timer::TimerAlarm<'p>>,
 }
 
@@ -688,6 +730,7 @@ impl<'p> Radio<'p> {
             tx_power: Cell::new(TxPower::ZerodBm),
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
+            ed_client: OptionalCell::empty(),
             tx_buf: TakeCell::empty(),
             rx_buf: TakeCell::empty(),
             addr: Cell::new(0),
@@ -698,6 +741,9 @@ impl<'p> Radio<'p> {
             random_nonce: Cell::new(0xDEADBEEF),
             channel: Cell::new(RadioChannel::DataChannel26),
             transmitting: Cell::new(false),
+            ed_channel: OptionalCell::empty(),
+            receiving: Cell::new(false),
+            arbiter: OptionalCell::empty(),
             timer0: OptionalCell::empty(),
         }
     }
@@ -706,6 +752,34 @@ impl<'p> Radio<'p> {
         self.timer0.set(timer);
     }
 
+    /// Share the radio with BLE through `arbiter`.
+    pub fn set_arbiter(&self, arbiter: &'p dyn RadioArbiter) {
+        self.arbiter.set(arbiter);
+    }
+
+    // Restart the radio to apply a new configuration or start an operation,
+    // once the arbiter grants the radio.
+    fn restart(&self) {
+        if self
+            .arbiter
+            .map_or(true, |arbiter| arbiter.request(Protocol::Ieee802154))
+        {
+            self.radio_off();
+            self.radio_initialize();
+        }
+    }
+
+    // Go back to listening, unless the arbiter gives the radio to BLE.
+    fn resume_rx(&self) {
+        self.radio_off();
+        if !self
+            .arbiter
+            .map_or(false, |arbiter| arbiter.idle(Protocol::Ieee802154))
+        {
+            self.radio_initialize();
+        }
+    }
+
     pub fn is_enabled(&self) -> bool {
         self.registers
             .mode
@@ -780,6 +854,10 @@ impl<'p> Radio<'p> {
                 && self.registers.state.get() == nrf5x::constants::RADIO_STATE_RXIDLE
             {
                 self.registers.task_ccastart.write(Task::ENABLE::SET);
+            } else if self.ed_channel.is_some()
+                && self.registers.state.get() == nrf5x::constants::RADIO_STATE_RXIDLE
+            {
+                self.registers.task_edstart.write(Task::ENABLE::SET);
             } else {
                 self.registers.task_start.write(Task::ENABLE::SET);
             }
@@ -787,6 +865,7 @@ impl<'p> Radio<'p> {
 
         if self.registers.event_framestart.is_set(Event::READY) {
             self.registers.event_framestart.write(Event::READY::CLEAR);
+            self.receiving.set(true);
         }
 
         //   IF we receive the go ahead (channel is clear)
@@ -833,9 +912,23 @@ impl<'p> Radio<'p> {
             self.enable_interrupts();
         }
 
+        if self.registers.event_edend.is_set(Event::READY) {
+            self.registers.event_edend.write(Event::READY::CLEAR);
+            let level = self.registers.edsample.read(EnergyDetectSample::EDLVL) * ED_RSSISCALE;
+            let level = cmp::min(level, 255) as u8;
+            let channel = self.ed_channel.take();
+            // Go back to receiving on the configured channel
+            self.resume_rx();
+            channel.map(|channel| {
+                self.ed_client
+                    .map(|client| client.energy_detect_done(channel.get_channel_index(), Ok(level)))
+            });
+        }
+
         // tx or rx finished!
         if self.registers.event_end.is_set(Event::READY) {
             self.registers.event_end.write(Event::READY::CLEAR);
+            self.receiving.set(false);
 
             let result = if self.registers.crcstatus.is_set(Event::READY) {
                 Ok(())
@@ -879,9 +972,7 @@ impl<'p> Radio<'p> {
                 // Radio state - Disabled
                 _ => (),
             }
-            self.radio_off();
-            self.radio_initialize();
-            self.rx();
+            self.resume_rx();
         }
         self.enable_interrupts();
     }
@@ -892,7 +983,8 @@ impl<'p> Radio<'p> {
                 + Interrupt::CCAIDLE::SET
                 + Interrupt::CCABUSY::SET
                 + Interrupt::END::SET
-                + Interrupt::FRAMESTART::SET,
+                + Interrupt::FRAMESTART::SET
+                + Interrupt::EDEND::SET,
         );
     }
 
@@ -930,7 +1022,7 @@ impl<'p> Radio<'p> {
 
         self.ieee802154_set_tx_power();
 
-        self.ieee802154_set_channel_freq(self.channel.get());
+        self.ieee802154_set_channel_freq(self.ed_channel.unwrap_or(self.channel.get()));
 
         self.set_tx_address();
         self.set_rx_address();
@@ -996,7 +1088,7 @@ impl<'p> Radio<'p> {
     }
 
     pub fn startup(&self) -> Result<(), ErrorCode> {
-        self.radio_initialize();
+        self.restart();
         Ok(())
     }
 
@@ -1024,7 +1116,7 @@ impl<'p> kernel::hil::radio::RadioConfig for Radio<'p> {
         _reg_write: &'static mut [u8],
         _reg_read: &'static mut [u8],
     ) -> Result<(), ErrorCode> {
-        self.radio_initialize();
+        self.restart();
         Ok(())
     }
 
@@ -1063,8 +1155,7 @@ impl<'p> kernel::hil::radio::RadioConfig for Radio<'p> {
     /// PAN ID, TX power, and channel to the specified values, issues
     /// a callback to the config client when done.
     fn config_commit(&self) {
-        self.radio_off();
-        self.radio_initialize();
+        self.restart();
     }
 
     fn set_config_client(&self, _client: &'static dyn radio::ConfigClient) {}
@@ -1153,7 +1244,7 @@ impl<'p> kernel::hil::radio::RadioData for Radio<'p> {
         buf: &'static mut [u8],
         frame_len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        if self.tx_buf.is_some() || self.transmitting.get() {
+        if self.tx_buf.is_some() || self.transmitting.get() || self.ed_channel.is_some() {
             return Err((ErrorCode::BUSY, buf));
         } else if radio::PSDU_OFFSET + frame_len >= buf.len() {
             // Not enough room for CRC
@@ -1168,8 +1259,50 @@ impl<'p> kernel::hil::radio::RadioData for Radio<'p> {
         self.cca_count.set(0);
         self.cca_be.set(IEEE802154_MIN_BE);
 
+        self.restart();
+        Ok(())
+    }
+}
+
+impl<'p> kernel::hil::radio::RadioEnergyDetect for Radio<'p> {
+    fn set_energy_detect_client(&self, client: &'static dyn radio::EnergyDetectClient) {
+        self.ed_client.set(client);
+    }
+
+    fn energy_detect(&self, channel: u8, duration_us: u32) -> Result<(), ErrorCode> {
+        if self.tx_buf.is_some() || self.transmitting.get() || self.ed_channel.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let channel = RadioChannel::try_from(channel).map_err(|_| ErrorCode::INVAL)?;
+
+        // The radio measures as many periods as asked, and keeps the peak
+        let count = (duration_us / IEEE802154_ED_PERIOD).saturating_sub(1);
+        self.registers
+            .edcnt
+            .write(EnergyDetectCount::EDCNT.val(cmp::min(count, 0x1fffff)));
+        self.ed_channel.set(channel);
+
+        self.restart();
+        Ok(())
+    }
+}
+
+impl<'p> RadioUser for Radio<'p> {
+    fn preemptible(&self) -> bool {
+        !self.transmitting.get()
+            && self.tx_buf.is_none()
+            && self.ed_channel.is_none()
+            && !self.receiving.get()
+    }
+
+    fn radio_granted(&self) {
         self.radio_off();
         self.radio_initialize();
-        Ok(())
+    }
+
+    fn radio_revoked(&self) {
+        self.disable_all_interrupts();
+        self.receiving.set(false);
+        self.radio_off();
     }
 }
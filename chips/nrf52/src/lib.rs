@@ -4,6 +4,7 @@
 #![crate_type = "rlib"]
 
 pub mod acomp;
+pub mod adafruit_bootloader;
 pub mod adc;
 pub mod ble_radio;
 pub mod chip;
@@ -17,6 +18,8 @@ pub mod nvmc;
 pub mod power;
 pub mod ppi;
 pub mod pwm;
+pub mod qdec;
+pub mod radio_arbiter;
 pub mod spi;
 pub mod uart;
 pub mod uicr;
@@ -0,0 +1,239 @@
+//! Quadrature decoder (QDEC), nRF52-family
+//!
+//! The QDEC samples the A and B signals of an encoder and adds each valid
+//! transition to its accumulator. Once every report period, if the encoder has
+//! moved, it raises `REPORTRDY`. The driver folds the accumulator into a 32-bit
+//! position then, so it only interrupts while the encoder turns.
+//!
+//! The QDEC has no index input. Boards whose encoder has one can connect it to
+//! a GPIO interrupt pin with `set_index_pin()`, and the driver reports the
+//! position at each rising edge of the index signal.
+//!
+//! The QDEC does not pull its inputs up, so boards with mechanical encoders
+//! that only switch the A and B signals to ground must enable the pull-ups of
+//! those pins.
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::common::registers::{
+    register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly,
+};
+use kernel::common::StaticRef;
+use kernel::hil::gpio;
+use kernel::hil::qdec::{QuadratureDecoder, QuadratureDecoderClient};
+use kernel::ErrorCode;
+
+use crate::pinmux::Pinmux;
+
+const QDEC_BASE: StaticRef<QdecRegisters> =
+    unsafe { StaticRef::new(0x40012000 as *const QdecRegisters) };
+
+register_structs! {
+    QdecRegisters {
+        (0x000 => tasks_start: WriteOnly<u32, Task::Register>),
+        (0x004 => tasks_stop: WriteOnly<u32, Task::Register>),
+        /// Move the accumulator to `accread` and clear it.
+        (0x008 => tasks_readclracc: WriteOnly<u32, Task::Register>),
+        (0x00c => _reserved0),
+        (0x104 => events_reportrdy: ReadWrite<u32, Event::Register>),
+        (0x108 => events_accof: ReadWrite<u32, Event::Register>),
+        (0x10c => _reserved1),
+        (0x304 => intenset: ReadWrite<u32, Interrupt::Register>),
+        (0x308 => intenclr: ReadWrite<u32, Interrupt::Register>),
+        (0x30c => _reserved2),
+        (0x500 => enable: ReadWrite<u32, Task::Register>),
+        (0x504 => _reserved3),
+        (0x508 => sampleper: ReadWrite<u32, SamplePeriod::Register>),
+        (0x50c => _reserved4),
+        (0x510 => reportper: ReadWrite<u32, ReportPeriod::Register>),
+        (0x514 => _reserved5),
+        (0x518 => accread: ReadOnly<u32>),
+        (0x51c => psel_led: ReadWrite<u32, Psel::Register>),
+        (0x520 => psel_a: ReadWrite<u32, Psel::Register>),
+        (0x524 => psel_b: ReadWrite<u32, Psel::Register>),
+        (0x528 => dbfen: ReadWrite<u32, Task::Register>),
+        (0x52c => @END),
+    }
+}
+
+register_bitfields![u32,
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+    Interrupt [
+        SAMPLERDY OFFSET(0) NUMBITS(1),
+        REPORTRDY OFFSET(1) NUMBITS(1),
+        ACCOF OFFSET(2) NUMBITS(1),
+        DBLRDY OFFSET(3) NUMBITS(1),
+        STOPPED OFFSET(4) NUMBITS(1)
+    ],
+    SamplePeriod [
+        SAMPLEPER OFFSET(0) NUMBITS(4) [
+            US128 = 0,
+            US256 = 1,
+            US512 = 2,
+            US1024 = 3
+        ]
+    ],
+    ReportPeriod [
+        REPORTPER OFFSET(0) NUMBITS(4) [
+            SAMPLES10 = 0,
+            SAMPLES40 = 1,
+            SAMPLES80 = 2,
+            SAMPLES120 = 3,
+            SAMPLES160 = 4,
+            SAMPLES200 = 5,
+            SAMPLES240 = 6,
+            SAMPLES280 = 7
+        ]
+    ],
+    Psel [
+        // Pin number, including the port bit, see the UARTE driver.
+        PIN OFFSET(0) NUMBITS(6),
+        // Connect/Disconnect
+        CONNECT OFFSET(31) NUMBITS(1)
+    ]
+];
+
+pub struct Qdec<'a> {
+    registers: StaticRef<QdecRegisters>,
+    client: OptionalCell<&'a dyn QuadratureDecoderClient>,
+    index_pin: OptionalCell<&'a dyn gpio::InterruptPin<'a>>,
+    enabled: Cell<bool>,
+    /// Steps counted before the last read of the accumulator.
+    position: Cell<i32>,
+}
+
+impl<'a> Qdec<'a> {
+    pub const fn new() -> Qdec<'a> {
+        Qdec {
+            registers: QDEC_BASE,
+            client: OptionalCell::empty(),
+            index_pin: OptionalCell::empty(),
+            enabled: Cell::new(false),
+            position: Cell::new(0),
+        }
+    }
+
+    /// Connect the A and B signals of the encoder. Must be called before
+    /// the decoder is enabled.
+    pub fn set_pins(&self, a: Pinmux, b: Pinmux) {
+        let regs = &*self.registers;
+        regs.psel_a.write(Psel::PIN.val(a.into()));
+        regs.psel_b.write(Psel::PIN.val(b.into()));
+        // The LED output is for optical encoders, which the driver does not
+        // drive.
+        regs.psel_led.write(Psel::CONNECT::SET);
+    }
+
+    /// Report the pulses of the encoder's index signal on `pin`. The pin's
+    /// client must be set to this decoder.
+    pub fn set_index_pin(&self, pin: &'a dyn gpio::InterruptPin<'a>) {
+        self.index_pin.set(pin);
+    }
+
+    /// Fold the accumulator into the position.
+    fn accumulate(&self) -> i32 {
+        let regs = &*self.registers;
+        regs.tasks_readclracc.write(Task::ENABLE::SET);
+        let steps = regs.accread.get() as i32;
+        self.position.set(self.position.get().wrapping_add(steps));
+        self.position.get()
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+        if regs.events_accof.get() != 0 {
+            // Only possible if interrupts were held off for thousands of
+            // samples; the position is off by the lost steps.
+            regs.events_accof.set(0);
+        }
+        if regs.events_reportrdy.get() != 0 {
+            regs.events_reportrdy.set(0);
+
+            let position = self.accumulate();
+            self.client.map(|client| client.position_changed(position));
+        }
+    }
+}
+
+impl<'a> QuadratureDecoder<'a> for Qdec<'a> {
+    fn set_client(&self, client: &'a dyn QuadratureDecoderClient) {
+        self.client.set(client);
+    }
+
+    fn enable(&self) -> Result<(), ErrorCode> {
+        if self.enabled.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let regs = &*self.registers;
+        // Sampling every 128 µs counts encoders turning at up to about 7800
+        // steps per second. Reporting every 80 samples, about every 10 ms,
+        // keeps the accumulator far from its limit of 1023 steps.
+        regs.sampleper.write(SamplePeriod::SAMPLEPER::US128);
+        regs.reportper.write(ReportPeriod::REPORTPER::SAMPLES80);
+        regs.dbfen.write(Task::ENABLE::SET);
+        regs.events_reportrdy.set(0);
+        regs.events_accof.set(0);
+        regs.intenset
+            .write(Interrupt::REPORTRDY::SET + Interrupt::ACCOF::SET);
+        regs.enable.write(Task::ENABLE::SET);
+        regs.tasks_start.write(Task::ENABLE::SET);
+
+        self.index_pin.map(|pin| {
+            pin.make_input();
+            pin.enable_interrupts(gpio::InterruptEdge::RisingEdge);
+        });
+        self.enabled.set(true);
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        if !self.enabled.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let regs = &*self.registers;
+        regs.tasks_stop.write(Task::ENABLE::SET);
+        // Keep the steps counted before the decoder stopped.
+        self.accumulate();
+        regs.intenclr
+            .write(Interrupt::REPORTRDY::SET + Interrupt::ACCOF::SET);
+        regs.enable.write(Task::ENABLE::CLEAR);
+
+        self.index_pin.map(|pin| pin.disable_interrupts());
+        self.enabled.set(false);
+        Ok(())
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    fn position(&self) -> i32 {
+        if self.enabled.get() {
+            self.accumulate()
+        } else {
+            self.position.get()
+        }
+    }
+
+    fn set_position(&self, position: i32) {
+        if self.enabled.get() {
+            // Drop the steps counted so far.
+            self.registers.tasks_readclracc.write(Task::ENABLE::SET);
+        }
+        self.position.set(position);
+    }
+}
+
+impl<'a> gpio::Client for Qdec<'a> {
+    fn fired(&self) {
+        if self.enabled.get() {
+            let position = self.position();
+            self.client.map(|client| client.index(position));
+        }
+    }
+}
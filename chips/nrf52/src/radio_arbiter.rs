@@ -0,0 +1,204 @@
+//! Arbiter sharing the RADIO peripheral between BLE and IEEE 802.15.4
+//!
+//! The nRF52840 has one radio, which the BLE advertising driver and the
+//! 802.15.4 driver both drive. Without an arbiter, a board can only use one
+//! of them. With one, each driver asks for the radio before using it, and
+//! the arbiter grants the radio to one protocol at a time.
+//!
+//! Priority rules:
+//!
+//! - An operation that is under way is never preempted. This covers an
+//!   802.15.4 transmission, with its CSMA backoffs, an 802.15.4 frame being
+//!   received, an energy detection, and a BLE advertisement.
+//! - 802.15.4 idle listening and BLE scanning can be preempted. The
+//!   preempted protocol waits, and gets the radio back when the other one
+//!   is done or is idle again. A preempted scan resumes on the same
+//!   channel.
+//! - A protocol that gets the radio keeps it for at least its guard time.
+//!   Only then can the other protocol preempt it. If both protocols only
+//!   listen, they take turns every guard time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let arbiter_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let arbiter = static_init!(
+//!     nrf52840::radio_arbiter::Arbiter<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     nrf52840::radio_arbiter::Arbiter::new(arbiter_alarm)
+//! );
+//! arbiter_alarm.set_alarm_client(arbiter);
+//! arbiter.set_user(Protocol::Ieee802154, &base_peripherals.ieee802154_radio);
+//! arbiter.set_user(Protocol::Ble, &base_peripherals.ble_radio);
+//! base_peripherals.ieee802154_radio.set_arbiter(arbiter);
+//! base_peripherals.ble_radio.set_arbiter(arbiter);
+//! ```
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{Alarm, AlarmClient};
+
+/// Time 802.15.4 keeps the radio once it gets it, in milliseconds. This
+/// gives neighbours time to send the frames they held back.
+pub const IEEE802154_GUARD_MS: u32 = 20;
+/// Time BLE keeps the radio once it gets it, in milliseconds. This is the
+/// shortest scan window on a channel.
+pub const BLE_GUARD_MS: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Ieee802154,
+    Ble,
+}
+
+/// A radio driver that shares the radio through the arbiter.
+pub trait RadioUser {
+    /// Whether the driver can give the radio up now, which it can only do
+    /// while listening.
+    fn preemptible(&self) -> bool;
+    /// The driver can use the radio. It starts the operation it waited for
+    /// or, without one, goes back to listening.
+    fn radio_granted(&self);
+    /// The driver must stop using the radio straight away. It resumes what
+    /// it was doing when it gets the radio back.
+    fn radio_revoked(&self);
+}
+
+/// The interface the radio drivers use to share the radio.
+pub trait RadioArbiter {
+    /// Ask for the radio. Returns `true` if the caller can use it now.
+    /// Otherwise the caller waits for `radio_granted`.
+    fn request(&self, protocol: Protocol) -> bool;
+    /// The caller is done with the radio.
+    fn release(&self, protocol: Protocol);
+    /// The caller is only listening. If the other protocol is waiting and
+    /// the guard time is over, the radio goes to the other protocol, and
+    /// this returns `true`.
+    fn idle(&self, protocol: Protocol) -> bool;
+}
+
+pub struct Arbiter<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    ieee802154: OptionalCell<&'a dyn RadioUser>,
+    ble: OptionalCell<&'a dyn RadioUser>,
+    owner: OptionalCell<Protocol>,
+    waiting: OptionalCell<Protocol>,
+}
+
+impl<'a, A: Alarm<'a>> Arbiter<'a, A> {
+    pub fn new(alarm: &'a A) -> Arbiter<'a, A> {
+        Arbiter {
+            alarm: alarm,
+            ieee802154: OptionalCell::empty(),
+            ble: OptionalCell::empty(),
+            owner: OptionalCell::empty(),
+            waiting: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_user(&self, protocol: Protocol, user: &'a dyn RadioUser) {
+        match protocol {
+            Protocol::Ieee802154 => self.ieee802154.set(user),
+            Protocol::Ble => self.ble.set(user),
+        }
+    }
+
+    fn user(&self, protocol: Protocol) -> Option<&'a dyn RadioUser> {
+        match protocol {
+            Protocol::Ieee802154 => self.ieee802154.extract(),
+            Protocol::Ble => self.ble.extract(),
+        }
+    }
+
+    fn preemptible(&self, protocol: Protocol) -> bool {
+        self.user(protocol).map_or(true, |user| user.preemptible())
+    }
+
+    /// Give the radio to `protocol` and start its guard time.
+    fn grant(&self, protocol: Protocol) {
+        self.owner.set(protocol);
+        let guard_ms = match protocol {
+            Protocol::Ieee802154 => IEEE802154_GUARD_MS,
+            Protocol::Ble => BLE_GUARD_MS,
+        };
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(guard_ms));
+    }
+
+    /// Take the radio from its owner, which waits to get it back, and give
+    /// it to `protocol`.
+    fn switch(&self, protocol: Protocol) {
+        let previous = self.owner.extract();
+        // The new owner is set first, so a request the revoked driver makes
+        // meanwhile waits.
+        self.grant(protocol);
+        self.waiting.clear();
+        if let Some(previous) = previous {
+            self.user(previous).map(|user| user.radio_revoked());
+            self.waiting.set(previous);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> RadioArbiter for Arbiter<'a, A> {
+    fn request(&self, protocol: Protocol) -> bool {
+        match self.owner.extract() {
+            None => {
+                self.grant(protocol);
+                true
+            }
+            Some(owner) if owner == protocol => true,
+            Some(owner) => {
+                if self.preemptible(owner) && !self.alarm.is_armed() {
+                    self.switch(protocol);
+                    true
+                } else {
+                    self.waiting.set(protocol);
+                    false
+                }
+            }
+        }
+    }
+
+    fn release(&self, protocol: Protocol) {
+        if self.owner.extract() != Some(protocol) {
+            // Not granted yet, so it no longer waits.
+            if self.waiting.extract() == Some(protocol) {
+                self.waiting.clear();
+            }
+            return;
+        }
+        self.owner.clear();
+        let _ = self.alarm.disarm();
+        self.waiting.take().map(|next| {
+            self.grant(next);
+            self.user(next).map(|user| user.radio_granted());
+        });
+    }
+
+    fn idle(&self, protocol: Protocol) -> bool {
+        if self.owner.extract() != Some(protocol) || self.alarm.is_armed() {
+            return false;
+        }
+        match self.waiting.extract() {
+            Some(next) if self.preemptible(protocol) => {
+                self.switch(next);
+                self.user(next).map(|user| user.radio_granted());
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> AlarmClient for Arbiter<'a, A> {
+    /// The guard time of the owner is over, so a protocol waiting for the
+    /// radio gets it, if the owner is only listening.
+    fn alarm(&self) {
+        if let Some(owner) = self.owner.extract() {
+            let _ = self.idle(owner);
+        }
+    }
+}
@@ -168,6 +168,7 @@ pub struct Uarte<'a> {
     rx_buffer: kernel::common::cells::TakeCell<'static, [u8]>,
     rx_remaining_bytes: Cell<usize>,
     rx_abort_in_progress: Cell<bool>,
+    rx_error: Cell<uart::Error>,
     offset: Cell<usize>,
 }
 
@@ -190,6 +191,7 @@ impl<'a> Uarte<'a> {
             rx_buffer: kernel::common::cells::TakeCell::empty(),
             rx_remaining_bytes: Cell::new(0),
             rx_abort_in_progress: Cell::new(false),
+            rx_error: Cell::new(uart::Error::None),
             offset: Cell::new(0),
         }
     }
@@ -268,7 +270,9 @@ impl<'a> Uarte<'a> {
     }
 
     fn enable_rx_interrupts(&self) {
-        self.registers.intenset.write(Interrupt::ENDRX::SET);
+        self.registers
+            .intenset
+            .write(Interrupt::ENDRX::SET + Interrupt::ERROR::SET);
     }
 
     fn enable_tx_interrupts(&self) {
@@ -276,7 +280,30 @@ impl<'a> Uarte<'a> {
     }
 
     fn disable_rx_interrupts(&self) {
-        self.registers.intenclr.write(Interrupt::ENDRX::SET);
+        self.registers
+            .intenclr
+            .write(Interrupt::ENDRX::SET + Interrupt::ERROR::SET);
+    }
+
+    /// Read and clear the ERRORSRC register, returning the most significant
+    /// error. A break also sets the framing error bit, so it is checked
+    /// first.
+    fn take_rx_error(&self) -> uart::Error {
+        let errorsrc = self.registers.errorsrc.extract();
+        // ERRORSRC bits are cleared by writing one to them.
+        self.registers.errorsrc.set(errorsrc.get());
+
+        if errorsrc.is_set(ErrorSrc::BREAK) {
+            uart::Error::BreakError
+        } else if errorsrc.is_set(ErrorSrc::FRAMING) {
+            uart::Error::FramingError
+        } else if errorsrc.is_set(ErrorSrc::PARITY) {
+            uart::Error::ParityError
+        } else if errorsrc.is_set(ErrorSrc::OVERRUN) {
+            uart::Error::OverrunError
+        } else {
+            uart::Error::None
+        }
     }
 
     fn disable_tx_interrupts(&self) {
@@ -317,6 +344,17 @@ impl<'a> Uarte<'a> {
             }
         }
 
+        if self.registers.event_error.is_set(Event::READY) {
+            self.registers.event_error.write(Event::READY::CLEAR);
+            let error = self.take_rx_error();
+            if error != uart::Error::None && self.rx_buffer.is_some() {
+                // Stop the reception. The resulting ENDRX event reports the
+                // error to the client along with the bytes received so far.
+                self.rx_error.set(error);
+                self.registers.task_stoprx.write(Task::ENABLE::SET);
+            }
+        }
+
         if self.rx_ready() {
             self.disable_rx_interrupts();
 
@@ -326,9 +364,22 @@ impl<'a> Uarte<'a> {
             // Get the number of bytes in the buffer that was received this time
             let rx_bytes = self.registers.rxd_amount.get() as usize;
 
-            // Check if this ENDRX is due to an abort. If so, we want to
-            // do the receive callback immediately.
-            if self.rx_abort_in_progress.get() {
+            // Check if this ENDRX is due to a line error or an abort. If so,
+            // we want to do the receive callback immediately.
+            let error = self.rx_error.replace(uart::Error::None);
+            if error != uart::Error::None {
+                self.rx_abort_in_progress.set(false);
+                self.rx_client.map(|client| {
+                    self.rx_buffer.take().map(|rx_buffer| {
+                        client.received_buffer(
+                            rx_buffer,
+                            self.offset.get() + rx_bytes,
+                            Err(ErrorCode::FAIL),
+                            error,
+                        );
+                    });
+                });
+            } else if self.rx_abort_in_progress.get() {
                 self.rx_abort_in_progress.set(false);
                 self.rx_client.map(|client| {
                     self.rx_buffer.take().map(|rx_buffer| {
@@ -505,6 +556,10 @@ impl<'a> uart::Receive<'a> for Uarte<'a> {
 
         self.rx_remaining_bytes.set(truncated_length);
         self.offset.set(0);
+        // Discard any error latched while no reception was in progress.
+        self.registers.event_error.write(Event::READY::CLEAR);
+        self.take_rx_error();
+        self.rx_error.set(uart::Error::None);
         self.rx_buffer.replace(rx_buf);
         self.set_rx_dma_pointer_to_buffer();
 
@@ -0,0 +1,466 @@
+//! I2S audio controller with EasyDMA, nRF52840.
+//!
+//! The controller runs as I2S master. Output and input are supported, one
+//! direction at a time. Samples are transferred by EasyDMA from
+//! double-buffered RAM buffers: the hardware latches the buffer pointer when
+//! it starts on a buffer (the PTRUPD events), which is when the driver
+//! passes it the next one and returns the finished one to the client.
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::registers::{register_bitfields, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::i2s;
+use kernel::ErrorCode;
+use nrf52::pinmux::Pinmux;
+
+const I2S_BASE: StaticRef<I2sRegisters> =
+    unsafe { StaticRef::new(0x40025000 as *const I2sRegisters) };
+
+/// Largest buffer EasyDMA can transfer, in 32-bit words.
+const MAX_BUFFER_WORDS: usize = (1 << 14) - 1;
+
+#[repr(C)]
+struct I2sRegisters {
+    tasks_start: WriteOnly<u32, Task::Register>,
+    tasks_stop: WriteOnly<u32, Task::Register>,
+    _reserved0: [u32; 63],
+    events_rxptrupd: ReadWrite<u32, Event::Register>,
+    events_stopped: ReadWrite<u32, Event::Register>,
+    _reserved1: [u32; 2],
+    events_txptrupd: ReadWrite<u32, Event::Register>,
+    _reserved2: [u32; 122],
+    inten: ReadWrite<u32, Interrupt::Register>,
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    intenclr: ReadWrite<u32, Interrupt::Register>,
+    _reserved3: [u32; 125],
+    enable: ReadWrite<u32, Enable::Register>,
+    config_mode: ReadWrite<u32, Mode::Register>,
+    config_rxen: ReadWrite<u32, Enable::Register>,
+    config_txen: ReadWrite<u32, Enable::Register>,
+    config_mcken: ReadWrite<u32, Enable::Register>,
+    config_mckfreq: ReadWrite<u32>,
+    config_ratio: ReadWrite<u32, Ratio::Register>,
+    config_swidth: ReadWrite<u32, Swidth::Register>,
+    config_align: ReadWrite<u32, Align::Register>,
+    config_format: ReadWrite<u32, Format::Register>,
+    config_channels: ReadWrite<u32, Channels::Register>,
+    _reserved4: [u32; 3],
+    rxd_ptr: ReadWrite<u32>,
+    _reserved5: [u32; 1],
+    txd_ptr: ReadWrite<u32>,
+    _reserved6: [u32; 3],
+    rxtxd_maxcnt: ReadWrite<u32, MaxCnt::Register>,
+    _reserved7: [u32; 3],
+    psel_mck: ReadWrite<u32, Psel::Register>,
+    psel_sck: ReadWrite<u32, Psel::Register>,
+    psel_lrck: ReadWrite<u32, Psel::Register>,
+    psel_sdin: ReadWrite<u32, Psel::Register>,
+    psel_sdout: ReadWrite<u32, Psel::Register>,
+}
+
+register_bitfields! [u32,
+    /// Start task
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+
+    /// Read event
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    /// I2S Interrupts
+    Interrupt [
+        RXPTRUPD OFFSET(1) NUMBITS(1),
+        STOPPED OFFSET(2) NUMBITS(1),
+        TXPTRUPD OFFSET(5) NUMBITS(1)
+    ],
+
+    Enable [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+
+    Mode [
+        MODE OFFSET(0) NUMBITS(1) [
+            Master = 0,
+            Slave = 1
+        ]
+    ],
+
+    /// MCK / LRCK ratio
+    Ratio [
+        RATIO OFFSET(0) NUMBITS(4) []
+    ],
+
+    Swidth [
+        SWIDTH OFFSET(0) NUMBITS(2) [
+            Bits8 = 0,
+            Bits16 = 1,
+            Bits24 = 2
+        ]
+    ],
+
+    Align [
+        ALIGN OFFSET(0) NUMBITS(1) [
+            Left = 0,
+            Right = 1
+        ]
+    ],
+
+    Format [
+        FORMAT OFFSET(0) NUMBITS(1) [
+            I2S = 0,
+            Aligned = 1
+        ]
+    ],
+
+    Channels [
+        CHANNELS OFFSET(0) NUMBITS(2) [
+            Stereo = 0,
+            Left = 1,
+            Right = 2
+        ]
+    ],
+
+    MaxCnt [
+        MAXCNT OFFSET(0) NUMBITS(14)
+    ],
+
+    Psel [
+        // Pin number, including the port bit, see the UARTE driver.
+        PIN OFFSET(0) NUMBITS(6),
+        // Connect/Disconnect
+        CONNECT OFFSET(31) NUMBITS(1)
+    ]
+];
+
+/// MCKFREQ register values and the division of the 32 MHz clock they
+/// select.
+const MCK_DIVIDERS: [(u32, u32); 13] = [
+    (0x20000000, 8),
+    (0x18000000, 10),
+    (0x16000000, 11),
+    (0x11000000, 15),
+    (0x10000000, 16),
+    (0x0C000000, 21),
+    (0x0B000000, 23),
+    (0x08800000, 30),
+    (0x08400000, 31),
+    (0x08000000, 32),
+    (0x06000000, 42),
+    (0x04100000, 63),
+    (0x020C0000, 125),
+];
+
+/// MCK / LRCK ratios, indexed by the RATIO register value.
+const RATIOS: [u32; 9] = [32, 48, 64, 96, 128, 192, 256, 384, 512];
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    Output,
+    Input,
+    Stopping,
+}
+
+pub struct I2s<'a> {
+    registers: StaticRef<I2sRegisters>,
+    client: OptionalCell<&'a dyn i2s::Client>,
+    state: Cell<State>,
+    /// Buffer the hardware is transferring.
+    active: TakeCell<'static, [u32]>,
+    active_len: Cell<usize>,
+    /// Buffer whose pointer has been given to the hardware, but which has
+    /// not been latched yet.
+    queued: TakeCell<'static, [u32]>,
+    queued_len: Cell<usize>,
+    /// The second buffer passed when starting, queued once the first one
+    /// has been latched.
+    spare: TakeCell<'static, [u32]>,
+    spare_len: Cell<usize>,
+}
+
+impl<'a> I2s<'a> {
+    pub fn new() -> I2s<'a> {
+        I2s {
+            registers: I2S_BASE,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            active: TakeCell::empty(),
+            active_len: Cell::new(0),
+            queued: TakeCell::empty(),
+            queued_len: Cell::new(0),
+            spare: TakeCell::empty(),
+            spare_len: Cell::new(0),
+        }
+    }
+
+    /// Configure the pins used. `mck` is only needed by codecs that need a
+    /// master clock, `sdout` for output and `sdin` for input.
+    pub fn set_pins(
+        &self,
+        sck: Pinmux,
+        lrck: Pinmux,
+        mck: Option<Pinmux>,
+        sdout: Option<Pinmux>,
+        sdin: Option<Pinmux>,
+    ) {
+        let regs = &*self.registers;
+        regs.psel_sck.write(Psel::PIN.val(sck.into()));
+        regs.psel_lrck.write(Psel::PIN.val(lrck.into()));
+        for (psel, pin) in [
+            (&regs.psel_mck, mck),
+            (&regs.psel_sdout, sdout),
+            (&regs.psel_sdin, sdin),
+        ]
+        .iter()
+        {
+            match pin {
+                Some(pin) => psel.write(Psel::PIN.val((*pin).into())),
+                None => psel.write(Psel::CONNECT::SET),
+            }
+        }
+        regs.config_mcken
+            .write(Enable::ENABLE.val(mck.is_some() as u32));
+    }
+
+    /// Point the hardware at `buffer` for the next transfer.
+    fn queue(&self, buffer: &'static mut [u32], length: usize) {
+        let regs = &*self.registers;
+        let ptr = buffer.as_ptr() as u32;
+        if self.state.get() == State::Input {
+            regs.rxd_ptr.set(ptr);
+        } else {
+            regs.txd_ptr.set(ptr);
+        }
+        regs.rxtxd_maxcnt.write(MaxCnt::MAXCNT.val(length as u32));
+        self.queued.replace(buffer);
+        self.queued_len.set(length);
+    }
+
+    fn start(
+        &self,
+        state: State,
+        buffer1: &'static mut [u32],
+        length1: usize,
+        buffer2: &'static mut [u32],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u32], &'static mut [u32])> {
+        if self.state.get() != State::Idle || self.queued.is_some() || self.active.is_some() {
+            return Err((ErrorCode::BUSY, buffer1, buffer2));
+        }
+        if length1 == 0
+            || length1 > buffer1.len()
+            || length1 > MAX_BUFFER_WORDS
+            || length2 == 0
+            || length2 > buffer2.len()
+            || length2 > MAX_BUFFER_WORDS
+        {
+            return Err((ErrorCode::SIZE, buffer1, buffer2));
+        }
+
+        let regs = &*self.registers;
+        self.state.set(state);
+        self.queue(buffer1, length1);
+        self.spare.replace(buffer2);
+        self.spare_len.set(length2);
+
+        let output = state == State::Output;
+        regs.config_txen.write(Enable::ENABLE.val(output as u32));
+        regs.config_rxen.write(Enable::ENABLE.val(!output as u32));
+        regs.events_txptrupd.write(Event::READY::CLEAR);
+        regs.events_rxptrupd.write(Event::READY::CLEAR);
+        regs.events_stopped.write(Event::READY::CLEAR);
+        if output {
+            regs.intenset
+                .write(Interrupt::TXPTRUPD::SET + Interrupt::STOPPED::SET);
+        } else {
+            regs.intenset
+                .write(Interrupt::RXPTRUPD::SET + Interrupt::STOPPED::SET);
+        }
+        regs.enable.write(Enable::ENABLE::SET);
+        regs.tasks_start.write(Task::ENABLE::SET);
+        Ok(())
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+
+        if regs.events_stopped.is_set(Event::READY) {
+            regs.events_stopped.write(Event::READY::CLEAR);
+            regs.intenclr.write(
+                Interrupt::TXPTRUPD::SET + Interrupt::RXPTRUPD::SET + Interrupt::STOPPED::SET,
+            );
+            regs.enable.write(Enable::ENABLE::CLEAR);
+            self.state.set(State::Idle);
+            self.client.map(|client| client.stopped());
+            return;
+        }
+
+        let updated = if regs.events_txptrupd.is_set(Event::READY) {
+            regs.events_txptrupd.write(Event::READY::CLEAR);
+            true
+        } else if regs.events_rxptrupd.is_set(Event::READY) {
+            regs.events_rxptrupd.write(Event::READY::CLEAR);
+            true
+        } else {
+            false
+        };
+        if !updated || self.state.get() == State::Stopping {
+            return;
+        }
+
+        // The queued buffer has been latched by the hardware, so the one it
+        // was transferring until now is finished.
+        let done = self.active.take();
+        let done_len = self.active_len.get();
+        match self.queued.take() {
+            Some(buffer) => {
+                self.active.replace(buffer);
+                self.active_len.set(self.queued_len.get());
+            }
+            None => {
+                // No new buffer was provided in time, the hardware repeats
+                // the one it just finished.
+                if let Some(buffer) = done {
+                    self.active.replace(buffer);
+                }
+                return;
+            }
+        }
+        if let Some(buffer) = self.spare.take() {
+            self.queue(buffer, self.spare_len.get());
+        }
+        if let Some(buffer) = done {
+            self.client
+                .map(move |client| client.buffer_done(buffer, done_len));
+        }
+    }
+}
+
+impl<'a> i2s::I2s<'a> for I2s<'a> {
+    fn set_client(&self, client: &'a dyn i2s::Client) {
+        self.client.set(client);
+    }
+
+    fn configure(&self, config: i2s::Configuration) -> Result<u32, ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        if config.sample_rate == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+
+        // Each LRCK period holds two samples, so the ratio has to be at
+        // least twice the sample width.
+        let min_ratio = 2 * config.sample_width.bits();
+        let mut best: Option<(u32, u32, u32)> = None;
+        for &(mckfreq, divider) in MCK_DIVIDERS.iter() {
+            for (ratio_index, &ratio) in RATIOS.iter().enumerate() {
+                if ratio < min_ratio {
+                    continue;
+                }
+                let rate = 32_000_000 / divider / ratio;
+                let error = if rate > config.sample_rate {
+                    rate - config.sample_rate
+                } else {
+                    config.sample_rate - rate
+                };
+                let better = best.map_or(true, |(_, _, best_rate)| {
+                    let best_error = if best_rate > config.sample_rate {
+                        best_rate - config.sample_rate
+                    } else {
+                        config.sample_rate - best_rate
+                    };
+                    error < best_error
+                });
+                if better {
+                    best = Some((mckfreq, ratio_index as u32, rate));
+                }
+            }
+        }
+
+        let (mckfreq, ratio, rate) = best.ok_or(ErrorCode::INVAL)?;
+        let regs = &*self.registers;
+        regs.config_mode.write(Mode::MODE::Master);
+        regs.config_mckfreq.set(mckfreq);
+        regs.config_ratio.write(Ratio::RATIO.val(ratio));
+        regs.config_swidth.write(match config.sample_width {
+            i2s::SampleWidth::Bits8 => Swidth::SWIDTH::Bits8,
+            i2s::SampleWidth::Bits16 => Swidth::SWIDTH::Bits16,
+            i2s::SampleWidth::Bits24 => Swidth::SWIDTH::Bits24,
+        });
+        regs.config_align.write(Align::ALIGN::Left);
+        regs.config_format.write(Format::FORMAT::I2S);
+        regs.config_channels.write(match config.channels {
+            i2s::Channels::Stereo => Channels::CHANNELS::Stereo,
+            i2s::Channels::Left => Channels::CHANNELS::Left,
+            i2s::Channels::Right => Channels::CHANNELS::Right,
+        });
+        Ok(rate)
+    }
+
+    fn start_output(
+        &self,
+        buffer1: &'static mut [u32],
+        length1: usize,
+        buffer2: &'static mut [u32],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u32], &'static mut [u32])> {
+        self.start(State::Output, buffer1, length1, buffer2, length2)
+    }
+
+    fn start_input(
+        &self,
+        buffer1: &'static mut [u32],
+        length1: usize,
+        buffer2: &'static mut [u32],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u32], &'static mut [u32])> {
+        self.start(State::Input, buffer1, length1, buffer2, length2)
+    }
+
+    fn provide_buffer(
+        &self,
+        buffer: &'static mut [u32],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u32])> {
+        match self.state.get() {
+            State::Output | State::Input => {}
+            _ => return Err((ErrorCode::OFF, buffer)),
+        }
+        if length == 0 || length > buffer.len() || length > MAX_BUFFER_WORDS {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+        if self.queued.is_some() || self.spare.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        self.queue(buffer, length);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Output | State::Input => {
+                self.state.set(State::Stopping);
+                self.registers.tasks_stop.write(Task::ENABLE::SET);
+                Ok(())
+            }
+            State::Stopping => Err(ErrorCode::ALREADY),
+            State::Idle => Err(ErrorCode::OFF),
+        }
+    }
+
+    fn retrieve_buffers(
+        &self,
+    ) -> Result<(Option<&'static mut [u32]>, Option<&'static mut [u32]>), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        // At most two of these hold a buffer at any time.
+        let first = self.active.take().or_else(|| self.queued.take());
+        let second = self.queued.take().or_else(|| self.spare.take());
+        Ok((first, second))
+    }
+}
@@ -10,6 +10,7 @@ pub struct Nrf52840DefaultPeripherals<'a> {
     pub nrf52: Nrf52DefaultPeripherals<'a>,
     pub usbd: crate::usbd::Usbd<'a>,
     pub gpio_port: crate::gpio::Port<'a, { crate::gpio::NUM_PINS }>,
+    pub i2s: crate::i2s::I2s<'a>,
 }
 
 impl<'a> Nrf52840DefaultPeripherals<'a> {
@@ -18,6 +19,7 @@ impl<'a> Nrf52840DefaultPeripherals<'a> {
             nrf52: Nrf52DefaultPeripherals::new(),
             usbd: crate::usbd::Usbd::new(),
             gpio_port: crate::gpio::nrf52840_gpio_create(),
+            i2s: crate::i2s::I2s::new(),
         }
     }
     // Necessary for setting up circular dependencies
@@ -32,6 +34,7 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52840DefaultPeriphera
         match interrupt {
             crate::peripheral_interrupts::USBD => self.usbd.handle_interrupt(),
             nrf52::peripheral_interrupts::GPIOTE => self.gpio_port.handle_interrupt(),
+            nrf52::peripheral_interrupts::I2S => self.i2s.handle_interrupt(),
             _ => return self.nrf52.service_interrupt(interrupt),
         }
         true
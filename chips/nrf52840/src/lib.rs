@@ -1,10 +1,12 @@
 #![no_std]
 pub use nrf52::{
-    acomp, adc, aes, ble_radio, chip, clock, constants, crt1, deferred_call_tasks, ficr, i2c,
-    ieee802154_radio, init, nvmc, peripheral_interrupts as base_interrupts, pinmux, power, ppi,
-    pwm, rtc, spi, temperature, timer, trng, uart, uicr, usbd,
+    acomp, adafruit_bootloader, adc, aes, ble_radio, chip, clock, constants, crt1,
+    deferred_call_tasks, ficr, i2c, ieee802154_radio, init, nvmc,
+    peripheral_interrupts as base_interrupts, pinmux, power, ppi, pwm, qdec, radio_arbiter, rtc,
+    spi, temperature, timer, trng, uart, uicr, usbd,
 };
 pub mod gpio;
+pub mod i2s;
 pub mod interrupt_service;
 
 pub mod peripheral_interrupts;
@@ -12,6 +12,7 @@ use kernel::common::registers::{register_bitfields, ReadWrite};
 use kernel::common::StaticRef;
 use kernel::debug;
 use kernel::hil;
+use kernel::interrupt_rate::{InterruptMask, InterruptRateMonitor};
 
 #[cfg(feature = "nrf51")]
 const NUM_GPIOTE: usize = 4;
@@ -550,6 +551,7 @@ impl GPIOPin<'_> {
 
 pub struct Port<'a, const N: usize> {
     pub pins: [GPIOPin<'a>; N],
+    interrupt_monitor: OptionalCell<&'a dyn InterruptRateMonitor>,
 }
 
 impl<'a, const N: usize> Index<Pin> for Port<'a, N> {
@@ -568,7 +570,18 @@ impl<'a, const N: usize> IndexMut<Pin> for Port<'a, N> {
 
 impl<'a, const N: usize> Port<'a, N> {
     pub fn new(pins: [GPIOPin<'a>; N]) -> Self {
-        Self { pins }
+        Self {
+            pins,
+            interrupt_monitor: OptionalCell::empty(),
+        }
+    }
+
+    /// Have `monitor` check each pin interrupt before it is delivered, with
+    /// the pin's index in the port as the interrupt number. The GPIOTE
+    /// channel of a pin it rejects is masked until `unmask_interrupt()` is
+    /// called, so one bouncing pin doesn't silence the others.
+    pub fn set_interrupt_monitor(&self, monitor: &'a dyn InterruptRateMonitor) {
+        self.interrupt_monitor.set(monitor);
     }
 
     /// GPIOTE interrupt: check each GPIOTE channel, if any has
@@ -583,8 +596,30 @@ impl<'a, const N: usize> Port<'a, N> {
                 ev.write(EventsIn::EVENT::NotReady);
                 // Get pin number for the event and `trigger` an interrupt manually on that pin
                 let pin = pin_registers.config[i].read(Config::PSEL) as usize;
+                if !self
+                    .interrupt_monitor
+                    .map_or(true, |monitor| monitor.interrupt(pin as u32))
+                {
+                    pin_registers.intenclr.set(1 << i);
+                    continue;
+                }
                 self.pins[pin].handle_interrupt();
             }
         }
     }
 }
+
+impl<const N: usize> InterruptMask for Port<'_, N> {
+    /// Unmask the GPIOTE channel of pin `interrupt` after the monitor masked
+    /// it. Edges while it was masked are lost.
+    fn unmask_interrupt(&self, interrupt: u32) {
+        let pin_registers = self.pins[0].gpiote_registers;
+
+        for (i, ch) in pin_registers.config.iter().enumerate() {
+            if ch.matches_all(Config::MODE::Event + Config::PSEL.val(interrupt)) {
+                pin_registers.event_in[i].write(EventsIn::EVENT::NotReady);
+                pin_registers.intenset.set(1 << i);
+            }
+        }
+    }
+}
@@ -4,8 +4,9 @@ use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
-use kernel::hil::time::{self, Alarm, Ticks, Time};
+use kernel::hil::time::{self, Alarm, Frequency, Ticks, Time};
 use kernel::ErrorCode;
+use kernel::SchedulerTimer;
 
 const RTC1_BASE: StaticRef<RtcRegisters> =
     unsafe { StaticRef::new(0x40011000 as *const RtcRegisters) };
@@ -88,6 +89,9 @@ pub struct Rtc<'a> {
     overflow_client: OptionalCell<&'a dyn time::OverflowClient>,
     alarm_client: OptionalCell<&'a dyn time::AlarmClient>,
     enabled: Cell<bool>,
+    /// Length of the current timeslice, in ticks, while the RTC is the
+    /// scheduler timer.
+    timeslice: Cell<u32>,
 }
 
 impl<'a> Rtc<'a> {
@@ -97,6 +101,7 @@ impl<'a> Rtc<'a> {
             overflow_client: OptionalCell::empty(),
             alarm_client: OptionalCell::empty(),
             enabled: Cell::new(false),
+            timeslice: Cell::new(0),
         }
     }
 
@@ -112,6 +117,11 @@ impl<'a> Rtc<'a> {
                 client.alarm();
             });
         }
+        // The timeslice is over. The interrupt only needs to stop the
+        // process, and the event is left for `get_remaining_us()`.
+        if self.registers.events_compare[1].is_set(Event::READY) {
+            self.registers.intenclr.write(Inte::COMPARE1::SET);
+        }
     }
 }
 
@@ -198,3 +208,54 @@ impl<'a> Alarm<'a> for Rtc<'a> {
         Self::Ticks::from(10)
     }
 }
+
+/// The RTC keeps counting while the chip sleeps with the HFCLK off, unlike
+/// the SysTick, so it can time timeslices in low power modes. It uses compare
+/// channel 1, and its resolution is a tick of 30.5 µs. The RTC must be
+/// started.
+impl SchedulerTimer for Rtc<'_> {
+    fn start(&self, us: u32) {
+        const SYNC_TICS: u32 = 2;
+        let regs = &*self.registers;
+
+        let hertz = <Self as Time>::Frequency::frequency() as u64;
+        let ticks = core::cmp::max((hertz * us as u64 / 1_000_000) as u32, SYNC_TICS + 1);
+        let expire = self.now().wrapping_add(time::Ticks24::from(ticks));
+        self.timeslice.set(ticks);
+
+        regs.cc[1].write(Counter::VALUE.val(expire.into_u32()));
+        regs.events_compare[1].write(Event::READY::CLEAR);
+        // Without the interrupt, only this generates the event
+        regs.evtenset.write(Inte::COMPARE1::SET);
+    }
+
+    fn reset(&self) {
+        let regs = &*self.registers;
+        regs.intenclr.write(Inte::COMPARE1::SET);
+        regs.evtenclr.write(Inte::COMPARE1::SET);
+        regs.events_compare[1].write(Event::READY::CLEAR);
+    }
+
+    fn arm(&self) {
+        self.registers.intenset.write(Inte::COMPARE1::SET);
+    }
+
+    fn disarm(&self) {
+        self.registers.intenclr.write(Inte::COMPARE1::SET);
+    }
+
+    fn get_remaining_us(&self) -> Option<u32> {
+        if self.registers.events_compare[1].is_set(Event::READY) {
+            return None;
+        }
+        let expire = time::Ticks24::from(self.registers.cc[1].read(Counter::VALUE));
+        let remaining = expire.wrapping_sub(self.now()).into_u32();
+        // Past the compare value, the difference wraps around
+        if remaining == 0 || remaining > self.timeslice.get() {
+            None
+        } else {
+            let hertz = <Self as Time>::Frequency::frequency() as u64;
+            Some((remaining as u64 * 1_000_000 / hertz) as u32)
+        }
+    }
+}
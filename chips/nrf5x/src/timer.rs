@@ -226,6 +226,49 @@ impl Timer {
         self.client.set(client);
     }
 
+    /// Stop the timer and configure it as a 32-bit timer counting
+    /// microseconds from 0, that stops when it reaches compare 0.
+    pub fn setup_oneshot_us(&self, compare: u32) {
+        self.registers.tasks_stop.write(Task::ENABLE::SET);
+        self.registers.tasks_clear.write(Task::ENABLE::SET);
+        self.registers.events_compare[0].write(Event::READY::CLEAR);
+        self.registers.mode.set(0);
+        self.registers.bitmode.write(Bitmode::BITMODE::Bit32);
+        // 16 MHz / 2^4
+        self.registers.prescaler.set(4);
+        self.registers.cc[0].write(CC::CC.val(compare));
+        self.registers
+            .shorts
+            .write(Shorts::COMPARE0_STOP::EnableShortcut);
+    }
+
+    /// Stop the timer and clear its count.
+    pub fn stop(&self) {
+        self.registers.tasks_stop.write(Task::ENABLE::SET);
+        self.registers.tasks_clear.write(Task::ENABLE::SET);
+    }
+
+    /// The current count, captured with capture register 1.
+    pub fn now(&self) -> u32 {
+        self.registers.tasks_capture[1].write(Task::ENABLE::SET);
+        self.registers.cc[1].read(CC::CC)
+    }
+
+    /// Address of the start task, to trigger it through the PPI.
+    pub fn task_start_address(&self) -> u32 {
+        &self.registers.tasks_start as *const _ as u32
+    }
+
+    /// Address of the stop task, to trigger it through the PPI.
+    pub fn task_stop_address(&self) -> u32 {
+        &self.registers.tasks_stop as *const _ as u32
+    }
+
+    /// Address of the event of compare 0, to use it through the PPI.
+    pub fn event_compare0_address(&self) -> u32 {
+        &self.registers.events_compare[0] as *const _ as u32
+    }
+
     /// When an interrupt occurs, check if any of the 4 compares have
     /// created an event, and if so, add it to the bitmask of triggered
     /// events that is passed to the client.
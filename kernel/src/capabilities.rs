@@ -90,3 +90,15 @@ pub unsafe trait CreatePortTableCapability {}
 /// of the networking stack. A capsule would never hold this capability although
 /// it may hold capabilities created via this capability.
 pub unsafe trait NetworkCapabilityCreationCapability {}
+
+/// The `RawFlashAccessCapability` allows the holder to write flash outside of
+/// the storage it was given for its own data, such as the app flash or the
+/// board configuration. Capsules that load or update apps, or provision the
+/// board, need it: a bug in one of them can replace the code that runs on the
+/// board.
+pub unsafe trait RawFlashAccessCapability {}
+
+/// The `RawRadioAccessCapability` allows the holder to use a radio below the
+/// protocol stacks that share it, for instance to sample the energy on a
+/// channel or to change how the radio is configured for every user.
+pub unsafe trait RawRadioAccessCapability {}
@@ -70,6 +70,30 @@ pub fn log_base_two_u64(num: u64) -> u32 {
     }
 }
 
+/// Starting value of `fnv1a_32()`.
+pub const FNV1A_32_OFFSET: u32 = 0x811c_9dc5;
+/// Starting value of `fnv1a_64()`.
+pub const FNV1A_64_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// 32-bit FNV-1a hash of `bytes`, continuing from `hash`. Start from
+/// `FNV1A_32_OFFSET`, and pass the result back in to hash more bytes.
+///
+/// FNV-1a is fast and small, not cryptographic: use it to spread keys, not
+/// where an attacker could choose inputs to collide.
+pub fn fnv1a_32(hash: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// 64-bit FNV-1a hash of `bytes`, continuing from `hash`. Start from
+/// `FNV1A_64_OFFSET`, and pass the result back in to hash more bytes.
+pub fn fnv1a_64(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
 // f32 log10 function adapted from [micromath](https://github.com/NeoBirth/micromath)
 const EXPONENT_MASK: u32 = 0b01111111_10000000_00000000_00000000;
 const EXPONENT_BIAS: u32 = 127;
@@ -132,3 +156,23 @@ pub fn log10(x: f32) -> f32 {
 }
 
 //-----------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::{fnv1a_32, fnv1a_64, FNV1A_32_OFFSET, FNV1A_64_OFFSET};
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a_32(FNV1A_32_OFFSET, b""), FNV1A_32_OFFSET);
+        assert_eq!(fnv1a_32(FNV1A_32_OFFSET, b"a"), 0xe40c_292c);
+        assert_eq!(fnv1a_32(FNV1A_32_OFFSET, b"foobar"), 0xbf9c_f968);
+        assert_eq!(fnv1a_64(FNV1A_64_OFFSET, b""), FNV1A_64_OFFSET);
+        assert_eq!(fnv1a_64(FNV1A_64_OFFSET, b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a_64(FNV1A_64_OFFSET, b"foobar"), 0x8594_4171_f739_67e8);
+        // Hashing in parts is the same as hashing all at once.
+        assert_eq!(
+            fnv1a_64(fnv1a_64(FNV1A_64_OFFSET, b"foo"), b"bar"),
+            fnv1a_64(FNV1A_64_OFFSET, b"foobar")
+        );
+    }
+}
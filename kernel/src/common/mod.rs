@@ -22,6 +22,7 @@ pub mod list;
 pub mod math;
 pub mod peripherals;
 pub mod queue;
+pub mod reservation;
 pub mod ring_buffer;
 pub mod utils;
 
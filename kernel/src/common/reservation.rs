@@ -0,0 +1,362 @@
+//! Share a static buffer between processes, queueing requests while it is in
+//! use.
+//!
+//! Capsules often own a single static buffer that every process's requests go
+//! through. Without a reservation a second process asking for the buffer while
+//! it is in use gets `BUSY` and has to retry until it succeeds. With a
+//! `BufferReservation`, the second request is queued instead, and when the
+//! buffer is released it is handed to the next queued process through
+//! `ReservationClient::reserved`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! match self.reservation.try_reserve(processid) {
+//!     Ok(Some(buffer)) => self.start(processid, buffer),
+//!     Ok(None) => { /* queued, `reserved()` is called later */ }
+//!     Err(e) => return Err(e),
+//! }
+//!
+//! // When the operation completes:
+//! self.reservation.release(buffer);
+//! ```
+
+use crate::common::cells::{MapCell, OptionalCell, TakeCell};
+use crate::common::{Queue, RingBuffer};
+use crate::ErrorCode;
+use crate::ProcessId;
+
+/// Receives the buffer on behalf of queued processes.
+pub trait ReservationClient {
+    /// The buffer was released and `processid` was at the front of the queue.
+    ///
+    /// Return `Some(buffer)` if the buffer cannot be used for `processid`, for
+    /// example because the process has exited, and it is offered to the next
+    /// process in the queue.
+    fn reserved(
+        &self,
+        processid: ProcessId,
+        buffer: &'static mut [u8],
+    ) -> Option<&'static mut [u8]>;
+}
+
+pub struct BufferReservation<'a> {
+    buffer: TakeCell<'static, [u8]>,
+    buffer_len: usize,
+    waiting: MapCell<RingBuffer<'a, Option<ProcessId>>>,
+    client: OptionalCell<&'a dyn ReservationClient>,
+}
+
+impl<'a> BufferReservation<'a> {
+    /// Share `buffer`, queueing up to `waiting.len() - 1` processes while it
+    /// is in use.
+    pub fn new(
+        buffer: &'static mut [u8],
+        waiting: &'a mut [Option<ProcessId>],
+    ) -> BufferReservation<'a> {
+        BufferReservation {
+            buffer_len: buffer.len(),
+            buffer: TakeCell::new(buffer),
+            waiting: MapCell::new(RingBuffer::new(waiting)),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn ReservationClient) {
+        self.client.set(client);
+    }
+
+    /// Length of the buffer, whether or not it is reserved.
+    pub fn buffer_len(&self) -> usize {
+        self.buffer_len
+    }
+
+    /// Whether the buffer is currently available.
+    pub fn is_available(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// Reserve the buffer for `processid`.
+    ///
+    /// Returns `Ok(Some(buffer))` if the buffer was free, or `Ok(None)` if the
+    /// buffer is in use and `processid` was queued, in which case the client
+    /// is given the buffer once it is released. Returns `ALREADY` if
+    /// `processid` is already queued and `NOMEM` if the queue is full.
+    ///
+    /// If there is no client, a released buffer waits for the process at the
+    /// front of the queue, which gets it by calling `try_reserve()` again.
+    pub fn try_reserve(
+        &self,
+        processid: ProcessId,
+    ) -> Result<Option<&'static mut [u8]>, ErrorCode> {
+        if self.is_queued(processid) {
+            if self.buffer.is_some() && self.dequeue_front(processid) {
+                return Ok(self.buffer.take());
+            }
+            return Err(ErrorCode::ALREADY);
+        }
+        let queue_empty = self.waiting.map_or(true, |waiting| !waiting.has_elements());
+        if queue_empty {
+            if let Some(buffer) = self.buffer.take() {
+                return Ok(Some(buffer));
+            }
+        }
+        if self
+            .waiting
+            .map_or(false, |waiting| waiting.enqueue(Some(processid)))
+        {
+            Ok(None)
+        } else {
+            Err(ErrorCode::NOMEM)
+        }
+    }
+
+    /// Release the buffer, handing it to the first queued process that can
+    /// use it.
+    pub fn release(&self, buffer: &'static mut [u8]) {
+        let client = match self.client.extract() {
+            Some(client) => client,
+            None => {
+                // Keep the queue, so the first process in it can take the
+                // buffer in `try_reserve()`.
+                self.buffer.replace(buffer);
+                return;
+            }
+        };
+        let mut buffer = buffer;
+        while let Some(processid) = self.waiting.map_or(None, |waiting| waiting.dequeue()) {
+            if let Some(next) = processid {
+                buffer = match client.reserved(next, buffer) {
+                    Some(buffer) => buffer,
+                    None => return,
+                };
+            }
+        }
+        self.buffer.replace(buffer);
+    }
+
+    /// Remove `processid` from the queue. Returns whether it was queued.
+    pub fn cancel(&self, processid: ProcessId) -> bool {
+        let queued = self.is_queued(processid);
+        if queued {
+            self.waiting
+                .map(|waiting| waiting.retain(|waiter| *waiter != Some(processid)));
+        }
+        queued
+    }
+
+    /// Remove `processid` from the queue if it is at the front. Returns
+    /// whether it was.
+    fn dequeue_front(&self, processid: ProcessId) -> bool {
+        self.waiting.map_or(false, |waiting| {
+            let len = waiting.len();
+            match waiting.dequeue() {
+                Some(Some(front)) if front == processid => true,
+                Some(front) => {
+                    // Put the front back and rotate the rest behind it.
+                    waiting.enqueue(front);
+                    for _ in 1..len {
+                        if let Some(waiter) = waiting.dequeue() {
+                            waiting.enqueue(waiter);
+                        }
+                    }
+                    false
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Whether `processid` is waiting for the buffer.
+    pub fn is_queued(&self, processid: ProcessId) -> bool {
+        self.waiting.map_or(false, |waiting| {
+            // Rotate through the whole queue so it ends up in the same order.
+            let mut found = false;
+            for _ in 0..waiting.len() {
+                if let Some(waiter) = waiting.dequeue() {
+                    found |= waiter == Some(processid);
+                    waiting.enqueue(waiter);
+                }
+            }
+            found
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::Kernel;
+    use core::cell::Cell;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    fn kernel() -> &'static Kernel {
+        Box::leak(Box::new(Kernel::new(&[])))
+    }
+
+    fn reservation(queue: usize) -> BufferReservation<'static> {
+        let buffer = Box::leak(Box::new([0u8; 4]));
+        let waiting = Box::leak(std::vec![None; queue].into_boxed_slice());
+        BufferReservation::new(buffer, waiting)
+    }
+
+    /// Records which processes were handed the buffer, refusing it for
+    /// `refuse`.
+    struct Client {
+        reserved: Cell<Option<&'static mut [u8]>>,
+        handed: Cell<Vec<ProcessId>>,
+        refuse: Option<ProcessId>,
+    }
+
+    impl Client {
+        fn new(refuse: Option<ProcessId>) -> &'static Client {
+            Box::leak(Box::new(Client {
+                reserved: Cell::new(None),
+                handed: Cell::new(Vec::new()),
+                refuse,
+            }))
+        }
+
+        fn handed(&self) -> Vec<ProcessId> {
+            let handed = self.handed.take();
+            self.handed.set(handed.clone());
+            handed
+        }
+    }
+
+    impl ReservationClient for Client {
+        fn reserved(
+            &self,
+            processid: ProcessId,
+            buffer: &'static mut [u8],
+        ) -> Option<&'static mut [u8]> {
+            let mut handed = self.handed.take();
+            handed.push(processid);
+            self.handed.set(handed);
+            if self.refuse == Some(processid) {
+                Some(buffer)
+            } else {
+                self.reserved.set(Some(buffer));
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_queue_in_order() {
+        let kernel = kernel();
+        let (a, b, c) = (
+            ProcessId::new(kernel, 1, 0),
+            ProcessId::new(kernel, 2, 1),
+            ProcessId::new(kernel, 3, 2),
+        );
+        let reservation = reservation(4);
+        let client = Client::new(None);
+        reservation.set_client(client);
+
+        let buffer = reservation.try_reserve(a).unwrap().unwrap();
+        assert!(!reservation.is_available());
+        assert_eq!(reservation.try_reserve(b), Ok(None));
+        assert_eq!(reservation.try_reserve(c), Ok(None));
+        assert_eq!(reservation.try_reserve(b), Err(ErrorCode::ALREADY));
+        assert!(reservation.is_queued(b) && reservation.is_queued(c));
+
+        reservation.release(buffer);
+        assert_eq!(client.handed(), [b]);
+        assert!(!reservation.is_queued(b) && reservation.is_queued(c));
+
+        reservation.release(client.reserved.take().unwrap());
+        assert_eq!(client.handed(), [b, c]);
+        reservation.release(client.reserved.take().unwrap());
+        assert!(reservation.is_available());
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let kernel = kernel();
+        let reservation = reservation(2);
+        let _buffer = reservation.try_reserve(ProcessId::new(kernel, 1, 0));
+        assert_eq!(
+            reservation.try_reserve(ProcessId::new(kernel, 2, 1)),
+            Ok(None)
+        );
+        assert_eq!(
+            reservation.try_reserve(ProcessId::new(kernel, 3, 2)),
+            Err(ErrorCode::NOMEM)
+        );
+    }
+
+    #[test]
+    fn test_refused_goes_to_next() {
+        let kernel = kernel();
+        let (a, b, c) = (
+            ProcessId::new(kernel, 1, 0),
+            ProcessId::new(kernel, 2, 1),
+            ProcessId::new(kernel, 3, 2),
+        );
+        let reservation = reservation(4);
+        let client = Client::new(Some(b));
+        reservation.set_client(client);
+
+        let buffer = reservation.try_reserve(a).unwrap().unwrap();
+        assert_eq!(reservation.try_reserve(b), Ok(None));
+        assert_eq!(reservation.try_reserve(c), Ok(None));
+        reservation.release(buffer);
+        assert_eq!(client.handed(), [b, c]);
+        assert!(client.reserved.take().is_some());
+        assert!(!reservation.is_available());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let kernel = kernel();
+        let (a, b, c) = (
+            ProcessId::new(kernel, 1, 0),
+            ProcessId::new(kernel, 2, 1),
+            ProcessId::new(kernel, 3, 2),
+        );
+        let reservation = reservation(4);
+        let client = Client::new(None);
+        reservation.set_client(client);
+
+        let buffer = reservation.try_reserve(a).unwrap().unwrap();
+        assert_eq!(reservation.try_reserve(b), Ok(None));
+        assert_eq!(reservation.try_reserve(c), Ok(None));
+        assert!(reservation.cancel(b));
+        assert!(!reservation.cancel(b));
+        reservation.release(buffer);
+        assert_eq!(client.handed(), [c]);
+    }
+
+    #[test]
+    fn test_release_without_client() {
+        let kernel = kernel();
+        let (a, b, c) = (
+            ProcessId::new(kernel, 1, 0),
+            ProcessId::new(kernel, 2, 1),
+            ProcessId::new(kernel, 3, 2),
+        );
+        let reservation = reservation(4);
+
+        let buffer = reservation.try_reserve(a).unwrap().unwrap();
+        assert_eq!(reservation.try_reserve(b), Ok(None));
+        assert_eq!(reservation.try_reserve(c), Ok(None));
+        reservation.release(buffer);
+
+        // The waiters stay queued, and the buffer goes to the first of them
+        // rather than to a newcomer or the second in line.
+        assert!(reservation.is_queued(b) && reservation.is_queued(c));
+        assert_eq!(reservation.try_reserve(a), Ok(None));
+        assert_eq!(reservation.try_reserve(c), Err(ErrorCode::ALREADY));
+        assert!(reservation.is_queued(c));
+        let buffer = reservation.try_reserve(b).unwrap().unwrap();
+        assert!(!reservation.is_queued(b));
+
+        reservation.release(buffer);
+        assert!(reservation.try_reserve(c).unwrap().is_some());
+    }
+}
@@ -40,6 +40,15 @@ pub(crate) struct Config {
     /// into which SRAM addresses. This can be useful to debug whether the kernel could
     /// successfully load processes, and whether the allocated SRAM is as expected.
     pub(crate) debug_load_processes: bool,
+
+    /// How many return addresses the kernel should capture from the stack of a process when it
+    /// faults, or 0 to capture none.
+    ///
+    /// If enabled, the kernel walks the frame pointers of a faulting process and shows the return
+    /// addresses it finds when it prints the process. This only works for processes compiled with
+    /// frame pointers (e.g. `-fno-omit-frame-pointer` or `-C force-frame-pointers=yes`), and
+    /// stops at the first frame that does not look valid.
+    pub(crate) stack_trace_depth: usize,
 }
 
 /// A unique instance of `Config` where compile-time configuration options are defined. These
@@ -47,4 +56,5 @@ pub(crate) struct Config {
 pub(crate) const CONFIG: Config = Config {
     trace_syscalls: false,
     debug_load_processes: false,
+    stack_trace_depth: 0,
 };
@@ -16,6 +16,11 @@
 //! components::debug_writer::DebugWriterComponent::new(uart_mux).finalize(());
 //! ```
 //!
+//! Boards with timing-sensitive peripherals can limit how many bytes the
+//! debug writer passes to the UART per kernel loop iteration with
+//! `DebugWriter::set_max_burst()`. `debug_writer_stats()` reports how full the
+//! debug buffer has been and how many bytes were dropped.
+//!
 //! The debug queue is optional, if not set in the board it is just ignored.
 //! You can add one in the board file as follows:
 //!
@@ -56,7 +61,10 @@ use core::panic::PanicInfo;
 use core::str;
 
 use crate::common::cells::NumericCellExt;
-use crate::common::cells::{MapCell, TakeCell};
+use crate::common::cells::{MapCell, OptionalCell, TakeCell};
+use crate::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
 use crate::common::queue::Queue;
 use crate::common::ring_buffer::RingBuffer;
 use crate::hil;
@@ -365,6 +373,25 @@ pub struct DebugWriter {
     internal_buffer: TakeCell<'static, RingBuffer<'static, u8>>,
     // Number of debug!() calls.
     count: Cell<usize>,
+    // Most bytes passed to the writing mechanism per kernel loop iteration.
+    max_burst: Cell<usize>,
+    // Used to continue writing in the next kernel loop iteration when
+    // `max_burst` is set.
+    deferred_caller: OptionalCell<&'static DynamicDeferredCall>,
+    handle: OptionalCell<DeferredCallHandle>,
+    // Most bytes waiting in the internal buffer so far.
+    high_water: Cell<usize>,
+    // Bytes dropped because the internal buffer was full.
+    dropped: Cell<usize>,
+}
+
+/// Statistics on the use of the `debug!()` buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct DebugWriterStats {
+    /// Most bytes that have been waiting in the buffer at once.
+    pub high_water: usize,
+    /// Bytes dropped because the buffer was full.
+    pub dropped: usize,
 }
 
 /// Static variable that holds the kernel's reference to the debug tool. This is
@@ -403,6 +430,33 @@ impl DebugWriter {
             output_buffer: TakeCell::new(out_buffer),
             internal_buffer: TakeCell::new(internal_buffer),
             count: Cell::new(0), // how many debug! calls
+            max_burst: Cell::new(usize::MAX),
+            deferred_caller: OptionalCell::empty(),
+            handle: OptionalCell::empty(),
+            high_water: Cell::new(0),
+            dropped: Cell::new(0),
+        }
+    }
+
+    /// Write at most `max_burst` bytes per kernel loop iteration, so that
+    /// bursts of `debug!()` output do not hold up timing-sensitive work such
+    /// as radio operations. `handle` must be the handle `deferred_caller`
+    /// returned when registering this `DebugWriter`.
+    pub fn set_max_burst(
+        &self,
+        max_burst: usize,
+        deferred_caller: &'static DynamicDeferredCall,
+        handle: DeferredCallHandle,
+    ) {
+        self.max_burst.set(max_burst.max(1));
+        self.deferred_caller.set(deferred_caller);
+        self.handle.set(handle);
+    }
+
+    pub fn stats(&self) -> DebugWriterStats {
+        DebugWriterStats {
+            high_water: self.high_water.get(),
+            dropped: self.dropped.get(),
         }
     }
 
@@ -422,8 +476,9 @@ impl DebugWriter {
         self.internal_buffer.map(|ring_buffer| {
             if let Some(out_buffer) = self.output_buffer.take() {
                 let mut count = 0;
+                let burst_len = out_buffer.len().min(self.max_burst.get());
 
-                for dst in out_buffer.iter_mut() {
+                for dst in out_buffer[..burst_len].iter_mut() {
                     match ring_buffer.dequeue() {
                         Some(src) => {
                             *dst = src;
@@ -463,13 +518,25 @@ impl hil::uart::TransmitClient for DebugWriter {
         self.output_buffer.replace(buffer);
 
         if self.internal_buffer.map_or(false, |buf| buf.has_elements()) {
-            // Buffer not empty, go around again
-            self.publish_bytes();
+            // Buffer not empty, go around again, in the next kernel loop
+            // iteration if bursts are limited.
+            match (self.deferred_caller.extract(), self.handle.extract()) {
+                (Some(deferred_caller), Some(handle)) => {
+                    deferred_caller.set(handle);
+                }
+                _ => self.publish_bytes(),
+            }
         }
     }
     fn transmitted_word(&self, _rcode: core::result::Result<(), ErrorCode>) {}
 }
 
+impl DynamicDeferredCallClient for DebugWriter {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.publish_bytes();
+    }
+}
+
 /// Pass through functions.
 impl DebugWriterWrapper {
     fn increment_count(&self) {
@@ -491,6 +558,10 @@ impl DebugWriterWrapper {
     fn extract(&self) -> Option<&mut RingBuffer<'static, u8>> {
         self.dw.map_or(None, |dw| dw.extract())
     }
+
+    fn stats(&self) -> Option<DebugWriterStats> {
+        self.dw.map(|dw| dw.stats())
+    }
 }
 
 impl IoWrite for DebugWriterWrapper {
@@ -514,6 +585,13 @@ impl IoWrite for DebugWriterWrapper {
                     for &b in FULL_MSG {
                         ring_buffer.enqueue(b);
                     }
+                    dw.dropped
+                        .set(dw.dropped.get() + bytes.len() - available_len_for_msg);
+                }
+
+                let used = ring_buffer.len();
+                if used > dw.high_water.get() {
+                    dw.high_water.set(used);
                 }
             });
         });
@@ -527,6 +605,11 @@ impl Write for DebugWriterWrapper {
     }
 }
 
+/// Statistics on the `debug!()` buffer, if the board set up a debug writer.
+pub fn debug_writer_stats() -> Option<DebugWriterStats> {
+    unsafe { try_get_debug_writer() }.and_then(|writer| writer.stats())
+}
+
 pub fn begin_debug_fmt(args: Arguments) {
     let writer = unsafe { get_debug_writer() };
 
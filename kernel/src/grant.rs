@@ -432,8 +432,14 @@ impl<'a, T: Default> ProcessGrant<'a, T> {
                 // earlier (i.e. before the grant can be applied).
 
                 // If `panic_on_reenter` is false, we skip this error and do
-                // nothing with this grant.
+                // nothing with this grant, other than count it.
                 if !panic_on_reenter {
+                    self.process
+                        .processid()
+                        .kernel
+                        .count_grant_failure(|failures| {
+                            failures.already_entered = failures.already_entered.saturating_add(1)
+                        });
                     return;
                 }
 
@@ -547,8 +553,14 @@ impl<'a, T: Default> ProcessGrant<'a, T> {
                 // earlier (i.e. before the grant can be applied).
 
                 // If `panic_on_reenter` is false, we skip this error and do
-                // nothing with this grant.
+                // nothing with this grant, other than count it.
                 if !panic_on_reenter {
+                    self.process
+                        .processid()
+                        .kernel
+                        .count_grant_failure(|failures| {
+                            failures.already_entered = failures.already_entered.saturating_add(1)
+                        });
                     return;
                 }
 
@@ -790,6 +802,30 @@ impl GrantRegionAllocator {
     }
 }
 
+/// Counts of the ways entering a grant failed, for introspection. The counts
+/// stop at `u16::MAX`.
+#[derive(Copy, Clone, Default)]
+pub struct GrantFailures {
+    /// The process did not exist.
+    pub no_such_app: u16,
+    /// The process was not running.
+    pub inactive_app: u16,
+    /// The grant could not be allocated in the process's grant region.
+    pub out_of_memory: u16,
+    /// `try_enter()` skipped the grant because it was already entered.
+    pub already_entered: u16,
+}
+
+impl GrantFailures {
+    /// Whether no failures were counted.
+    pub fn is_empty(&self) -> bool {
+        self.no_such_app == 0
+            && self.inactive_app == 0
+            && self.out_of_memory == 0
+            && self.already_entered == 0
+    }
+}
+
 /// Type for storing an object of type T in process memory that is only
 /// accessible by the kernel.
 ///
@@ -850,6 +886,7 @@ impl<T: Default> Grant<T> {
                 // already entered, at which point the kernel will panic.
                 Ok(pg.enter(fun))
             })
+            .map_err(|err| self.count_failure(err))
     }
 
     /// Enter the grant for a specific process with access to an allocator.
@@ -880,6 +917,18 @@ impl<T: Default> Grant<T> {
                 // already entered, at which point the kernel will panic.
                 Ok(pg.enter_with_allocator(fun))
             })
+            .map_err(|err| self.count_failure(err))
+    }
+
+    /// Count a failure to enter this grant, for introspection.
+    fn count_failure(&self, err: Error) -> Error {
+        self.kernel.count_grant_failure(|failures| match err {
+            Error::NoSuchApp => failures.no_such_app = failures.no_such_app.saturating_add(1),
+            Error::InactiveApp => failures.inactive_app = failures.inactive_app.saturating_add(1),
+            Error::OutOfMemory => failures.out_of_memory = failures.out_of_memory.saturating_add(1),
+            _ => {}
+        });
+        err
     }
 
     /// Run a function on the grant for each active process if the grant has
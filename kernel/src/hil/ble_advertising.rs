@@ -54,10 +54,82 @@ pub trait BleAdvertisementDriver<'a> {
     fn receive_advertisement(&self, channel: RadioChannel);
     fn set_receive_client(&self, client: &'a dyn RxClient);
     fn set_transmit_client(&self, client: &'a dyn TxClient);
+    /// Signal strength of the last advertisement received, in dBm, if the
+    /// radio measures it.
+    fn last_rssi(&self) -> Option<i8>;
+}
+
+/// BLE 5 extended advertising, for radios that support it.
+///
+/// An extended advertisement is a pair of packets. The primary packet, an
+/// `ADV_EXT_IND` sent on an advertising channel at 1 Mbit/s, holds an
+/// `AuxPtr` to the auxiliary packet, an `AUX_ADV_IND` sent on a data channel
+/// (the secondary channel) on any PHY, which carries up to 255 bytes of
+/// payload. The radio sends the auxiliary packet `aux_offset_us()` after the
+/// start of the primary one, so the `AuxPtr` can announce it.
+pub trait BleExtendedAdvertisementDriver<'a> {
+    /// Whether the radio can send auxiliary packets on `phy`.
+    fn supports_secondary_phy(&self, phy: Phy) -> bool;
+    /// Time from the start of the primary packet to the start of the
+    /// auxiliary packet, in microseconds. A multiple of 30 that is less than
+    /// 245 ms, so it fits an `AuxPtr` in 30 µs units.
+    fn aux_offset_us(&self) -> u32;
+    /// Send the `primary_len` bytes of the primary packet at the start of
+    /// `buf` on `channel`, then the `aux_len` bytes of the auxiliary packet
+    /// that follow them on `secondary` with `phy`. Both packets start with
+    /// their 2-byte header. The transmit client gets `buf` back once both are
+    /// sent.
+    ///
+    /// On error, `buf` is returned at once. `NOSUPPORT` means the radio
+    /// cannot send on `phy`.
+    fn transmit_extended_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        primary_len: usize,
+        aux_len: usize,
+        channel: RadioChannel,
+        secondary: RadioChannel,
+        phy: Phy,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
+
+/// Answering scan requests, for radios that support it.
+///
+/// A scanner sends a `SCAN_REQ` to a scannable advertisement `T_IFS` (150
+/// µs) after its end, and expects the `SCAN_RSP` `T_IFS` after the end of
+/// the request. That is too soon to go through a client, so the radio
+/// listens for the request and sends the response by itself.
+pub trait BleScanResponseDriver<'a> {
+    /// Whether the radio can answer scan requests.
+    fn supports_scan_response(&self) -> bool;
+    /// Send the `adv_len` bytes of the advertisement at the start of `buf` on
+    /// `channel`, then listen for a `SCAN_REQ` for the advertisement's AdvA
+    /// and answer it with the `rsp_len` bytes of the `SCAN_RSP` that follow.
+    /// Both packets start with their 2-byte header. The transmit client gets
+    /// `buf` back once the response is sent, or once no request came in time.
+    ///
+    /// On error, `buf` is returned at once.
+    fn transmit_scannable_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        adv_len: usize,
+        rsp_len: usize,
+        channel: RadioChannel,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
 }
 
 pub trait BleConfig {
+    /// Check that the radio can transmit at `power` dBm, as a two's
+    /// complement byte, without changing the power it transmits at. Returns
+    /// `NOSUPPORT` if it cannot.
+    fn check_tx_power(&self, power: u8) -> Result<(), ErrorCode>;
+    /// Transmit at `power` dBm, as a two's complement byte, from the next
+    /// transmission on.
     fn set_tx_power(&self, power: u8) -> Result<(), ErrorCode>;
+    /// Check that the radio can advertise on only the primary advertising
+    /// channels in `channel_map`, with bit 0 for channel 37, bit 1 for
+    /// channel 38 and bit 2 for channel 39. Returns `NOSUPPORT` if it cannot.
+    fn check_channel_map(&self, channel_map: u8) -> Result<(), ErrorCode>;
 }
 
 pub trait RxClient {
@@ -68,6 +140,27 @@ pub trait TxClient {
     fn transmit_event(&self, buf: &'static mut [u8], result: Result<(), ErrorCode>);
 }
 
+/// Physical layers of BLE 5, numbered as in the `AuxPtr` field.
+///
+/// Bluetooth Core Specification 5.0: Vol. 6, Part B, section 2.3.4.5
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Phy {
+    Le1M = 0,
+    Le2M = 1,
+    LeCoded = 2,
+}
+
+impl Phy {
+    pub fn from_index(index: usize) -> Option<Phy> {
+        match index {
+            0 => Some(Phy::Le1M),
+            1 => Some(Phy::Le2M),
+            2 => Some(Phy::LeCoded),
+            _ => None,
+        }
+    }
+}
+
 // Bluetooth Core Specification:Vol. 6. Part B, section 1.4.1 Advertising and Data Channel Indices
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum RadioChannel {
@@ -158,4 +251,48 @@ impl RadioChannel {
             RadioChannel::AdvertisingChannel39 => 39,
         }
     }
+
+    /// The data channel with index `index`, 0 to 36.
+    pub fn data_channel(index: u32) -> Option<RadioChannel> {
+        match index {
+            0 => Some(RadioChannel::DataChannel0),
+            1 => Some(RadioChannel::DataChannel1),
+            2 => Some(RadioChannel::DataChannel2),
+            3 => Some(RadioChannel::DataChannel3),
+            4 => Some(RadioChannel::DataChannel4),
+            5 => Some(RadioChannel::DataChannel5),
+            6 => Some(RadioChannel::DataChannel6),
+            7 => Some(RadioChannel::DataChannel7),
+            8 => Some(RadioChannel::DataChannel8),
+            9 => Some(RadioChannel::DataChannel9),
+            10 => Some(RadioChannel::DataChannel10),
+            11 => Some(RadioChannel::DataChannel11),
+            12 => Some(RadioChannel::DataChannel12),
+            13 => Some(RadioChannel::DataChannel13),
+            14 => Some(RadioChannel::DataChannel14),
+            15 => Some(RadioChannel::DataChannel15),
+            16 => Some(RadioChannel::DataChannel16),
+            17 => Some(RadioChannel::DataChannel17),
+            18 => Some(RadioChannel::DataChannel18),
+            19 => Some(RadioChannel::DataChannel19),
+            20 => Some(RadioChannel::DataChannel20),
+            21 => Some(RadioChannel::DataChannel21),
+            22 => Some(RadioChannel::DataChannel22),
+            23 => Some(RadioChannel::DataChannel23),
+            24 => Some(RadioChannel::DataChannel24),
+            25 => Some(RadioChannel::DataChannel25),
+            26 => Some(RadioChannel::DataChannel26),
+            27 => Some(RadioChannel::DataChannel27),
+            28 => Some(RadioChannel::DataChannel28),
+            29 => Some(RadioChannel::DataChannel29),
+            30 => Some(RadioChannel::DataChannel30),
+            31 => Some(RadioChannel::DataChannel31),
+            32 => Some(RadioChannel::DataChannel32),
+            33 => Some(RadioChannel::DataChannel33),
+            34 => Some(RadioChannel::DataChannel34),
+            35 => Some(RadioChannel::DataChannel35),
+            36 => Some(RadioChannel::DataChannel36),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,27 @@
+//! Interface for hardware breakpoints.
+//!
+//! Debug hardware, such as the Flash Patch and Breakpoint unit of Cortex-M
+//! cores, provides a fixed number of comparators that each stop execution when
+//! the core fetches an instruction from a chosen address. On chips that route
+//! the resulting debug exception to the fault handler, a breakpoint hit in a
+//! process looks to the kernel like a fault of that process.
+
+use crate::ErrorCode;
+
+pub trait Breakpoints {
+    /// The number of breakpoints the hardware provides.
+    fn count(&self) -> usize;
+
+    /// Set breakpoint `index` to stop execution at the instruction at
+    /// `address`, replacing any address the breakpoint was set to before.
+    /// Returns `INVAL` if `index` is not below `count()`, or the hardware
+    /// cannot break at `address`.
+    ///
+    /// The breakpoint applies to all code, including the kernel, so callers
+    /// must check that `address` is in code they are allowed to stop, such as
+    /// the flash of the process being debugged.
+    fn set(&self, index: usize, address: usize) -> Result<(), ErrorCode>;
+
+    /// Clear breakpoint `index`. Does nothing if it is not set.
+    fn clear(&self, index: usize);
+}
@@ -0,0 +1,27 @@
+//! Interface for capacitive sense (CapSense) controllers.
+//!
+//! A CapSense controller measures the capacitance of a set of pads. Each
+//! measurement is a raw count that grows with the capacitance of the pad,
+//! so it increases when a finger is near. The absolute value depends on the
+//! pad, its trace and the controller, so users are expected to compare
+//! measurements against a baseline taken while the pad is not touched.
+
+use crate::ErrorCode;
+
+pub trait CapSense<'a> {
+    fn set_client(&self, client: &'a dyn CapSenseClient);
+
+    /// Number of channels (pads) the controller can measure.
+    fn channels(&self) -> usize;
+
+    /// Start measuring `channel`. The result is passed to the client's
+    /// `measurement_done` callback.
+    fn measure(&self, channel: usize) -> Result<(), ErrorCode>;
+}
+
+pub trait CapSenseClient {
+    /// Called when a measurement has finished, with the raw count for
+    /// `channel`, or an error if the measurement failed (for example because
+    /// the pad is shorted).
+    fn measurement_done(&self, channel: usize, result: Result<u32, ErrorCode>);
+}
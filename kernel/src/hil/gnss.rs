@@ -0,0 +1,25 @@
+//! Interface for GNSS receivers.
+//!
+//! Gives kernel capsules, such as geofences, the position fixes of a
+//! receiver without going through userspace.
+
+/// A position fix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fix {
+    /// Latitude, in 10^-7 degrees, positive north.
+    pub latitude: i32,
+    /// Longitude, in 10^-7 degrees, positive east.
+    pub longitude: i32,
+    /// Ground speed, in millimeters per second.
+    pub speed_mm_s: u32,
+}
+
+pub trait Gnss<'a> {
+    /// Set the client told about every valid fix.
+    fn set_fix_client(&self, client: &'a dyn FixClient);
+}
+
+pub trait FixClient {
+    /// The receiver has a new position fix.
+    fn fix(&self, fix: Fix);
+}
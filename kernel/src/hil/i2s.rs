@@ -0,0 +1,110 @@
+//! Interface for I2S (Inter-IC Sound) audio controllers.
+//!
+//! Audio is streamed through a pair of buffers: while the controller plays
+//! (or fills) one buffer, the client prepares the other. Each time the
+//! controller is done with a buffer it is handed back to the client, which
+//! is expected to give the controller a new one with `provide_buffer()` or
+//! to stop the stream.
+//!
+//! Samples are packed little-endian into 32-bit words. With 8-bit samples
+//! each word holds four samples, with 16-bit samples two and with 24-bit
+//! samples one, sign-extended. In stereo mode samples alternate between the
+//! left and the right channel, starting with the left one.
+
+use crate::ErrorCode;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SampleWidth {
+    Bits8,
+    Bits16,
+    Bits24,
+}
+
+impl SampleWidth {
+    pub fn bits(&self) -> u32 {
+        match *self {
+            SampleWidth::Bits8 => 8,
+            SampleWidth::Bits16 => 16,
+            SampleWidth::Bits24 => 24,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channels {
+    Stereo,
+    Left,
+    Right,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Configuration {
+    /// Samples per second, per channel.
+    pub sample_rate: u32,
+    pub sample_width: SampleWidth,
+    pub channels: Channels,
+}
+
+pub trait I2s<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Set the format of the audio stream. Can only be called while the
+    /// controller is stopped. Controllers derive their clocks from a fixed
+    /// set of dividers, so the sample rate used may differ from the one
+    /// requested: it is returned on success.
+    fn configure(&self, config: Configuration) -> Result<u32, ErrorCode>;
+
+    /// Start playing `length1` words of `buffer1` followed by `length2` words
+    /// of `buffer2`. If an error occurs, the buffers are returned.
+    fn start_output(
+        &self,
+        buffer1: &'static mut [u32],
+        length1: usize,
+        buffer2: &'static mut [u32],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u32], &'static mut [u32])>;
+
+    /// Start recording into `buffer1` and then `buffer2`. Length fields are
+    /// the number of words to record into each buffer. If an error occurs,
+    /// the buffers are returned.
+    fn start_input(
+        &self,
+        buffer1: &'static mut [u32],
+        length1: usize,
+        buffer2: &'static mut [u32],
+        length2: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u32], &'static mut [u32])>;
+
+    /// Provide the next buffer for the ongoing stream.
+    /// Expected to be called in a `buffer_done` callback. If it is not called
+    /// before the controller finishes the buffer in use, that buffer is
+    /// played (or recorded into) again.
+    fn provide_buffer(
+        &self,
+        buffer: &'static mut [u32],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u32])>;
+
+    /// Stop the stream. The client's `stopped` callback is called once the
+    /// controller has stopped.
+    fn stop(&self) -> Result<(), ErrorCode>;
+
+    /// Reclaim ownership of buffers.
+    /// Can only be called when the controller is stopped. Returns Ok() if it
+    /// was, but there may still be no buffers that are `some` if the driver
+    /// had already returned all buffers.
+    fn retrieve_buffers(
+        &self,
+    ) -> Result<(Option<&'static mut [u32]>, Option<&'static mut [u32]>), ErrorCode>;
+}
+
+pub trait Client {
+    /// Called when the controller is done with a buffer: for output it has
+    /// been played, for input it holds `length` words of recorded samples.
+    /// Expects either a call to provide another buffer or to stop the
+    /// stream.
+    fn buffer_done(&self, buffer: &'static mut [u32], length: usize);
+
+    /// Called when the stream has stopped after a call to `stop`.
+    fn stopped(&self);
+}
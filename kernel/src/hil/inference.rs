@@ -0,0 +1,74 @@
+//! Interface for running neural network inference.
+//!
+//! An inference engine loads a model, takes an input tensor, runs the model
+//! and provides the output tensor. Engines can be hardware accelerators or
+//! software running on the CPU; users do not need to know which.
+//!
+//! Tensors are quantized to signed 8-bit values and passed as bytes.
+//!
+//! Model format
+//! ------------
+//!
+//! Models are passed to engines as descriptors in the following format. An
+//! engine that cannot run a model returns `NOSUPPORT` from `load_model`.
+//! All multi-byte values are little-endian.
+//!
+//! ```text
+//! header:  magic "TMDL" | version (1): u8 | layers: u8 | input length: u16
+//! layer:   kind: u8 | activation: u8 | output length: u16 | shift: u8 |
+//!          padding: [u8; 3] | weights: [i8; input * output] | bias: [i32; output]
+//! ```
+//!
+//! The only layer kind is `1`, a fully connected layer. Weights are stored
+//! row by row, one row per output. Each output is the dot product of its row
+//! with the input plus its bias, shifted right by `shift` bits and saturated
+//! to 8 bits. Activation `0` is none and `1` is ReLU.
+
+use crate::ErrorCode;
+
+/// Magic bytes at the start of a model descriptor.
+pub const MODEL_MAGIC: [u8; 4] = *b"TMDL";
+
+/// Version of the model descriptor format.
+pub const MODEL_VERSION: u8 = 1;
+
+/// Layer kind of fully connected layers.
+pub const LAYER_FULLY_CONNECTED: u8 = 1;
+
+/// Activation functions applied to the output of a layer.
+pub const ACTIVATION_NONE: u8 = 0;
+pub const ACTIVATION_RELU: u8 = 1;
+
+/// Shape of a loaded model.
+#[derive(Clone, Copy, Debug)]
+pub struct ModelInfo {
+    /// Length of the input tensor, in bytes.
+    pub input_len: usize,
+    /// Length of the output tensor, in bytes.
+    pub output_len: usize,
+}
+
+pub trait Inference<'a> {
+    fn set_client(&self, client: &'a dyn InferenceClient);
+
+    /// Load `model`, replacing any model loaded before. Returns `NOSUPPORT`
+    /// if the engine cannot run the model, `SIZE` if it is too large for
+    /// the engine and `INVAL` if the descriptor is malformed.
+    fn load_model(&self, model: &'static [u8]) -> Result<ModelInfo, ErrorCode>;
+
+    /// Set the input tensor. `input` must have the input length of the model.
+    fn set_input(&self, input: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Run the model on the input tensor. The client's `inference_done` is
+    /// called when the output is ready.
+    fn run(&self) -> Result<(), ErrorCode>;
+
+    /// Copy the output tensor of the last run into `output`, returning its
+    /// length.
+    fn get_output(&self, output: &mut [u8]) -> Result<usize, ErrorCode>;
+}
+
+pub trait InferenceClient {
+    /// Called when a run has finished.
+    fn inference_done(&self, result: Result<(), ErrorCode>);
+}
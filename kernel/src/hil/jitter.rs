@@ -0,0 +1,29 @@
+//! Interface for random delays
+//!
+//! Inserting a random delay before a sensitive operation, such as signing,
+//! makes the time it starts, and so the time it completes, harder to
+//! correlate with the request that triggered it. This is a mitigation layer
+//! for timing side channels, not a replacement for constant-time code.
+
+use crate::ErrorCode;
+
+/// Implement this trait and use `set_client()` in order to receive callbacks
+/// when a delay is over.
+pub trait JitterClient {
+    /// Called once the delay requested with `delay()` is over.
+    fn delay_done(&self);
+}
+
+/// Waits for random times, drawn from a true random source.
+pub trait Jitter<'a> {
+    /// Set the client instance which will receive `delay_done()` callbacks.
+    fn set_client(&'a self, client: &'a dyn JitterClient);
+
+    /// Wait for a random time, uniformly distributed between 0 and `max_us`
+    /// microseconds, then call `delay_done()`.
+    ///
+    /// Returns `BUSY` while another delay is pending, and the error of the
+    /// random source if it cannot provide randomness. The client is only
+    /// called if this returns `Ok(())`.
+    fn delay(&self, max_us: u32) -> Result<(), ErrorCode>;
+}
@@ -0,0 +1,98 @@
+//! Interface for LoRa radio transceivers.
+//!
+//! This is the physical layer only: it sends and receives single LoRa
+//! packets with the modulation of a `Config`, and leaves channel plans,
+//! duty cycles and MAC commands to its users.
+//!
+//! Besides plain transmission and reception, radios can offer two ways to
+//! save power and share the channel. Listen-before-talk transmissions first
+//! run a channel activity detection (CAD), which finds LoRa preambles in a
+//! few symbols, and give up if the channel is in use. Duty-cycled reception
+//! lets the radio alternate between listening and sleeping on its own, only
+//! staying awake when it hears a preamble, so the receiver can be ready for
+//! downlinks at a fraction of the current of continuous reception.
+
+use crate::ErrorCode;
+
+/// Modulation and packet parameters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub frequency_hz: u32,
+    /// 7 to 12.
+    pub spreading_factor: u8,
+    pub bandwidth_hz: u32,
+    /// 1 to 4, for coding rates 4/5 to 4/8.
+    pub coding_rate: u8,
+    /// Number of preamble symbols.
+    pub preamble_len: u16,
+    /// Whether packets carry a payload CRC.
+    pub crc: bool,
+    /// Whether the I and Q signals are swapped, as LoRaWAN downlinks are.
+    pub invert_iq: bool,
+    pub tx_power_dbm: i8,
+}
+
+pub trait LoraPhy<'a> {
+    fn set_client(&self, client: &'a dyn LoraPhyClient);
+
+    /// Set the parameters of the next operations. Returns `INVAL` if the
+    /// radio does not support them.
+    fn set_config(&self, config: Config) -> Result<(), ErrorCode>;
+
+    /// Send the first `len` bytes of `buf`. With `listen_before_talk`, the
+    /// radio first checks the channel for activity, and the transmission
+    /// fails with `BUSY` if it finds any.
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        listen_before_talk: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Receive one packet into `buf`, giving up after `timeout_ms`, or
+    /// waiting as long as it takes if it is `0`.
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        timeout_ms: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Receive one packet into `buf`, listening for `listen_ms` and then
+    /// sleeping for `sleep_ms` until a preamble is heard. `listen_ms` must be
+    /// long enough to hear a few preamble symbols. Returns `NOSUPPORT` if the
+    /// radio cannot duty cycle reception.
+    fn receive_duty_cycled(
+        &self,
+        buf: &'static mut [u8],
+        listen_ms: u32,
+        sleep_ms: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Check the channel for activity, without transmitting.
+    fn channel_activity(&self) -> Result<(), ErrorCode>;
+
+    /// Stop a reception or transmission. Its buffer is returned to the
+    /// client with `CANCEL`.
+    fn stop(&self) -> Result<(), ErrorCode>;
+}
+
+pub trait LoraPhyClient {
+    /// A transmission finished, or failed with `BUSY` because listen before
+    /// talk found the channel in use.
+    fn transmit_done(&self, buf: &'static mut [u8], result: Result<(), ErrorCode>);
+
+    /// A packet of `len` bytes was received into `buf`, with its strength
+    /// and signal to noise ratio. Fails with `NOACK` if nothing was received
+    /// before the timeout, or `FAIL` if the packet was corrupted.
+    fn receive_done(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        rssi_dbm: i16,
+        snr_db: i8,
+        result: Result<(), ErrorCode>,
+    );
+
+    /// A channel activity check finished.
+    fn channel_activity_done(&self, detected: bool);
+}
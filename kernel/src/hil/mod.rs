@@ -3,25 +3,38 @@
 pub mod adc;
 pub mod analog_comparator;
 pub mod ble_advertising;
+pub mod breakpoint;
 pub mod bus8080;
+pub mod capsense;
 pub mod crc;
 pub mod dac;
 pub mod digest;
 pub mod eic;
 pub mod entropy;
 pub mod flash;
+pub mod gnss;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c;
+pub mod i2s;
+pub mod inference;
+pub mod jitter;
 pub mod kv_system;
 pub mod led;
 pub mod log;
+pub mod lora;
 pub mod nonvolatile_storage;
+pub mod onewire;
 pub mod pwm;
+pub mod qdec;
 pub mod radio;
+pub mod rf_switch;
 pub mod rng;
 pub mod screen;
+pub mod secure_boot;
+pub mod self_test;
 pub mod sensors;
+pub mod signature;
 pub mod spi;
 pub mod symmetric_encryption;
 pub mod text_screen;
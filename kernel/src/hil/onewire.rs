@@ -0,0 +1,69 @@
+//! Interface for 1-Wire bus masters.
+//!
+//! 1-Wire is a single-wire, open-drain bus used by sensors such as the
+//! DS18B20 temperature sensor. Every transaction starts with a reset pulse,
+//! to which devices on the bus answer with a presence pulse, followed by a
+//! ROM command selecting a device and then device specific commands.
+//!
+//! All operations are split-phase: if a call returns `Ok(())` the matching
+//! client callback will be called once it has finished, otherwise no
+//! callback will be generated.
+
+use crate::ErrorCode;
+
+/// ROM command addressing every device on the bus.
+pub const SKIP_ROM: u8 = 0xCC;
+/// ROM command selecting the device whose 64-bit ROM code follows.
+pub const MATCH_ROM: u8 = 0x55;
+/// ROM command used to enumerate the devices on the bus.
+pub const SEARCH_ROM: u8 = 0xF0;
+
+pub trait OneWireMaster<'a> {
+    fn set_client(&self, client: &'a dyn OneWireClient);
+
+    /// Send a reset pulse and check for a presence pulse.
+    fn reset(&self) -> Result<(), ErrorCode>;
+
+    /// Write the first `len` bytes of `buffer` to the bus, least significant
+    /// bit first.
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Read `len` bytes from the bus into `buffer`.
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Restart device enumeration, so that the next call to
+    /// `search_next()` returns the first device on the bus.
+    fn search_reset(&self);
+
+    /// Find the next device on the bus using the Search ROM command. This
+    /// issues its own reset pulse.
+    fn search_next(&self) -> Result<(), ErrorCode>;
+}
+
+pub trait OneWireClient {
+    /// Called when a reset pulse has been sent. `result` is `Err(NOACK)` if
+    /// no device answered with a presence pulse.
+    fn reset_done(&self, result: Result<(), ErrorCode>);
+
+    /// Called when a write has finished.
+    fn write_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>);
+
+    /// Called when a read has finished. The bytes read are at the start of
+    /// `buffer`.
+    fn read_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>);
+
+    /// Called when a search step has finished, with the 64-bit ROM code of
+    /// the device found (family code in the least significant byte), or
+    /// `None` if every device on the bus has already been returned.
+    ///
+    /// The CRC byte of the ROM code is not checked.
+    fn search_done(&self, result: Result<Option<u64>, ErrorCode>);
+}
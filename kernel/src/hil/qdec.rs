@@ -0,0 +1,42 @@
+//! Interface for quadrature decoders.
+//!
+//! A quadrature decoder counts the steps of a rotary or linear encoder from
+//! its two phase-shifted signals, A and B. Each step moves the position one
+//! count up or down, depending on which signal leads. Some encoders also have
+//! an index signal that pulses once per revolution, which lets users find an
+//! absolute position.
+//!
+//! Decoders count in hardware, so the position stays correct however fast the
+//! encoder turns, and users only hear about it at the rate the decoder reports.
+
+use crate::ErrorCode;
+
+pub trait QuadratureDecoder<'a> {
+    fn set_client(&self, client: &'a dyn QuadratureDecoderClient);
+
+    /// Start counting steps.
+    fn enable(&self) -> Result<(), ErrorCode>;
+
+    /// Stop counting steps. The position is kept, but steps taken while the
+    /// decoder is disabled are missed.
+    fn disable(&self) -> Result<(), ErrorCode>;
+
+    fn is_enabled(&self) -> bool;
+
+    /// The position, in steps counted up or down since the decoder was
+    /// created or the position was last set.
+    fn position(&self) -> i32;
+
+    fn set_position(&self, position: i32);
+}
+
+pub trait QuadratureDecoderClient {
+    /// Called when the position has changed since the last report, with the
+    /// new position. While the encoder turns, decoders report at a steady
+    /// rate rather than at every step.
+    fn position_changed(&self, position: i32);
+
+    /// Called when the index signal pulses, with the position at that
+    /// moment. Decoders without an index signal never call this.
+    fn index(&self, position: i32);
+}
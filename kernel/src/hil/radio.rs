@@ -31,6 +31,14 @@ pub trait PowerClient {
     fn changed(&self, on: bool);
 }
 
+pub trait EnergyDetectClient {
+    /// Called when an energy detection completes, with the peak energy
+    /// measured on `channel` as an IEEE 802.15.4 ED value: 0 to 255,
+    /// linear in dB, with 0 no more than 10 dB above the receiver
+    /// sensitivity.
+    fn energy_detect_done(&self, channel: u8, result: Result<u8, ErrorCode>);
+}
+
 /// These constants are used for interacting with the SPI buffer, which contains
 /// a 1-byte SPI command, a 1-byte PHY header, and then the 802.15.4 frame. In
 /// theory, the number of extra bytes in front of the frame can depend on the
@@ -101,6 +109,17 @@ pub trait RadioConfig {
     fn set_channel(&self, chan: u8) -> Result<(), ErrorCode>;
 }
 
+/// Measure the energy on 802.15.4 channels, such as to find quiet ones.
+pub trait RadioEnergyDetect {
+    fn set_energy_detect_client(&self, client: &'static dyn EnergyDetectClient);
+
+    /// Measure the energy on `channel` for about `duration_us`, then go
+    /// back to receiving on the configured channel. No frames are sent or
+    /// received meanwhile. Returns `BUSY` while transmitting or measuring,
+    /// and `INVAL` for a channel the radio does not support.
+    fn energy_detect(&self, channel: u8, duration_us: u32) -> Result<(), ErrorCode>;
+}
+
 pub trait RadioData {
     fn set_transmit_client(&self, client: &'static dyn TxClient);
     fn set_receive_client(&self, client: &'static dyn RxClient, receive_buffer: &'static mut [u8]);
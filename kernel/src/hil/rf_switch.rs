@@ -0,0 +1,41 @@
+//! Interface for RF switches that share one antenna between several radios.
+//!
+//! Some boards, such as the Seeed T1000-E, connect their LoRa, GNSS and BLE
+//! radios to a single antenna through a switch driven by GPIOs. Each radio
+//! driver gets its own `RfSwitch`, acquires it before it transmits or
+//! receives, and releases it when it is done, so only one radio uses the
+//! antenna at a time.
+//!
+//! Radios take turns: when a radio asks for the antenna while another one
+//! holds it, the holder is asked to release it at the next point where it
+//! can stop, and the waiting radio is told once the antenna is switched to
+//! it.
+
+use crate::ErrorCode;
+
+pub trait RfSwitch<'a> {
+    fn set_client(&self, client: &'a dyn RfSwitchClient);
+
+    /// Switch the antenna to this radio. Returns `Ok(())` if it now is, or
+    /// `ALREADY` if it already was. Returns `BUSY` if another radio holds
+    /// the antenna, in which case `granted()` is called once it is this
+    /// radio's turn.
+    fn acquire(&self) -> Result<(), ErrorCode>;
+
+    /// Let other radios use the antenna, or stop waiting for it.
+    fn release(&self);
+
+    /// Whether the antenna is switched to this radio.
+    fn is_acquired(&self) -> bool;
+}
+
+pub trait RfSwitchClient {
+    /// The antenna was switched to this radio, after `acquire()` returned
+    /// `BUSY`.
+    fn granted(&self);
+
+    /// Another radio is waiting for the antenna. The radio should release it
+    /// as soon as it can, for example after the packet it is sending or
+    /// between two GNSS fixes.
+    fn release_requested(&self);
+}
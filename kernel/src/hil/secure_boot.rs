@@ -0,0 +1,45 @@
+//! Interface for reporting how the boot stages verified the kernel.
+//!
+//! The boot stages that run before the kernel, such as a ROM extension or a
+//! bootloader, check the image they start and may enforce anti-rollback
+//! counters, minimum versions an image must have to be started. Most leave a
+//! record of what they did in memory. A chip implements this trait to report
+//! that record: how the image the kernel is part of was verified, with which
+//! key, and the values of the counters.
+//!
+//! Implementations read the record once, during early init, before the
+//! kernel may reuse the memory it is in, so the state they report never
+//! changes afterwards.
+
+/// How the boot stage checked the image before starting it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verification {
+    /// The boot stage left no record, or one that could not be read.
+    Unknown = 0,
+    /// The image was started without being checked.
+    Unverified = 1,
+    /// The integrity of the image was checked, for instance with a CRC, but
+    /// not who made it.
+    Integrity = 2,
+    /// The signature of the image was verified, with the key `key_id()` if
+    /// the boot stage records it.
+    Signature = 3,
+}
+
+/// The secure boot state the boot stages left for the kernel.
+pub trait SecureBootStatus {
+    /// How the image the kernel is part of was verified.
+    fn verification(&self) -> Verification;
+
+    /// Identifier of the key the signature of the image was verified with,
+    /// in the format of the boot stage. `None` unless the verification is
+    /// `Verification::Signature`, or if the boot stage does not record it.
+    fn key_id(&self) -> Option<u32>;
+
+    /// The number of anti-rollback counters the boot stages enforce.
+    fn rollback_counters(&self) -> usize;
+
+    /// The value of anti-rollback counter `index`, or `None` if there is no
+    /// such counter.
+    fn rollback_counter(&self, index: usize) -> Option<u32>;
+}
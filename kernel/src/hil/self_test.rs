@@ -0,0 +1,38 @@
+//! Interface for peripheral and driver self-tests.
+//!
+//! A self-test is a short, non-destructive check that a driver can run on
+//! itself to confirm the underlying hardware responds as expected (a timer
+//! advances, a known-answer test produces the right digest, a bus device
+//! acknowledges its address). Self-tests are split-phase like every other
+//! HIL: `run()` starts the test and `SelfTestClient::self_test_done()` reports
+//! the outcome.
+//!
+//! Self-tests are intended to be driven by a runner such as
+//! `capsules::self_test::SelfTestRunner`, which executes a list of tests in
+//! order and reports the aggregate result.
+
+use crate::ErrorCode;
+
+/// A single self-test exposed by a driver.
+pub trait SelfTest<'a> {
+    /// Short, human readable name used when reporting the result.
+    fn name(&self) -> &'static str;
+
+    /// Set the client that receives the test result.
+    fn set_client(&self, client: &'a dyn SelfTestClient);
+
+    /// Start the test. If this returns `Ok(())` the client will receive
+    /// exactly one `self_test_done()` callback. Otherwise no callback is
+    /// issued. Valid errors are:
+    /// - BUSY: the test or the underlying driver is already in use.
+    /// - OFF: the underlying hardware is not powered or configured.
+    /// - FAIL: the test could not be started for some other reason.
+    fn run(&self) -> Result<(), ErrorCode>;
+}
+
+/// Receives the outcome of a self-test.
+pub trait SelfTestClient {
+    /// The test finished. `result` is `Ok(())` if the check passed, or an
+    /// `ErrorCode` describing why it failed.
+    fn self_test_done(&self, result: Result<(), ErrorCode>);
+}
@@ -15,6 +15,12 @@ pub trait TemperatureClient {
     /// - `value`: the most recently read temperature in hundredths of degrees
     /// centigrate.
     fn callback(&self, value: usize);
+
+    /// Called when a temperature reading has failed. Clients that do not
+    /// handle errors get a `value` of `usize::MAX` instead.
+    fn error(&self, _error: ErrorCode) {
+        self.callback(usize::MAX);
+    }
 }
 
 /// A basic interface for a humidity sensor
@@ -117,6 +123,51 @@ pub trait NineDofClient {
     fn callback(&self, arg1: usize, arg2: usize, arg3: usize);
 }
 
+/// Direction in which a value has to cross a trigger threshold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriggerDirection {
+    /// The value rises above the threshold.
+    Rising,
+    /// The value falls below the threshold.
+    Falling,
+    /// The value crosses the threshold in either direction.
+    Both,
+}
+
+/// Interface for sensors that can signal when a reading crosses a threshold,
+/// so that users can wait for a condition instead of polling.
+///
+/// The units of the threshold and of reported values depend on the sensor:
+/// hundredths of degrees centigrade for temperature, raw counts for ADCs and
+/// milli-g of acceleration magnitude for accelerometers.
+///
+/// Once enabled, the trigger stays armed until it is disabled. After firing,
+/// it only fires again once the value has moved back past the threshold by
+/// more than the hysteresis.
+pub trait Trigger<'a> {
+    fn set_trigger_client(&self, client: &'a dyn TriggerClient);
+
+    /// Arm the trigger. Returns `INVAL` if the sensor cannot detect
+    /// crossings in `direction`, or `BUSY` if the sensor is in use.
+    fn enable_trigger(
+        &self,
+        threshold: i32,
+        hysteresis: u32,
+        direction: TriggerDirection,
+    ) -> Result<(), ErrorCode>;
+
+    /// Disarm the trigger. No further `triggered` callbacks will occur.
+    fn disable_trigger(&self) -> Result<(), ErrorCode>;
+}
+
+/// Client for receiving trigger events.
+pub trait TriggerClient {
+    /// Called when the value crossed the threshold.
+    ///
+    /// - `value`: the reading that crossed the threshold.
+    fn triggered(&self, value: i32);
+}
+
 /// Basic Interface for Sound Pressure
 pub trait SoundPressure<'a> {
     /// Read the sound pressure level
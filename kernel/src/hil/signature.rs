@@ -0,0 +1,90 @@
+//! Interface for digital signatures with keys held by the signer
+//!
+//! Private keys never leave the signer. They are addressed by slot, like the
+//! key slots of a secure element, and a client only ever sees public keys
+//! and signatures.
+//!
+//! Keys and signatures are for ECDSA over the NIST P-256 curve. Public keys
+//! are the big-endian X and Y coordinates, and signatures the big-endian R
+//! and S values, 32 bytes each.
+
+use crate::ErrorCode;
+
+/// Length of a public key: the X and Y coordinates.
+pub const PUBLIC_KEY_LEN: usize = 64;
+/// Length of a signature: the R and S values.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Implement this trait and use `set_key_client()` in order to receive
+/// callbacks when a key has been generated.
+pub trait KeyGenerateClient {
+    /// Called when the key pair of a slot has been generated. On success,
+    /// `public_key` holds its public key. On error or success `public_key`
+    /// is the buffer passed to `generate_key()`.
+    fn key_generated(
+        &self,
+        result: Result<(), ErrorCode>,
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    );
+}
+
+/// Generates key pairs in the key slots of a signer.
+pub trait KeyGenerate<'a> {
+    /// Set the client instance which will receive `key_generated()`
+    /// callbacks.
+    fn set_key_client(&'a self, client: &'a dyn KeyGenerateClient);
+
+    /// The number of key slots, which are numbered from 0.
+    fn key_slots(&self) -> usize;
+
+    /// Generate a new key pair in `slot`, replacing the key it held.
+    ///
+    /// Returns `INVAL` if there is no such slot, and `BUSY` while another
+    /// operation is in progress. On error the return value contains the
+    /// buffer passed in.
+    fn generate_key(
+        &self,
+        slot: usize,
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; PUBLIC_KEY_LEN])>;
+}
+
+/// Implement this trait and use `set_sign_client()` in order to receive
+/// callbacks when a hash has been signed.
+pub trait SignClient {
+    /// Called when a hash has been signed. On success, `signature` holds the
+    /// signature. On error or success `hash` and `signature` are the buffers
+    /// passed to `sign()`.
+    fn signing_done(
+        &self,
+        result: Result<(), ErrorCode>,
+        hash: &'static mut [u8; 32],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    );
+}
+
+/// Signs hashes with the private keys of a signer.
+pub trait SignatureSign<'a> {
+    /// Set the client instance which will receive `signing_done()`
+    /// callbacks.
+    fn set_sign_client(&'a self, client: &'a dyn SignClient);
+
+    /// Sign the SHA-256 hash `hash` with the private key in `slot`.
+    ///
+    /// Returns `INVAL` if there is no such slot, `RESERVE` if the slot holds
+    /// no key, and `BUSY` while another operation is in progress. On error
+    /// the return value contains the buffers passed in.
+    fn sign(
+        &self,
+        slot: usize,
+        hash: &'static mut [u8; 32],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ErrorCode,
+            &'static mut [u8; 32],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    >;
+}
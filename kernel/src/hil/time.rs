@@ -96,6 +96,20 @@ pub trait Time {
     }
 }
 
+/// Interface for the clock the kernel uses to timestamp events for upcalls.
+///
+/// It is implemented for every `Time`, so boards can pass any timer.
+pub trait EventClock {
+    /// Current tick count, truncated to 32 bits.
+    fn event_ticks(&self) -> u32;
+}
+
+impl<T: Time> EventClock for T {
+    fn event_ticks(&self) -> u32 {
+        self.now().into_u32()
+    }
+}
+
 fn ticks_from_val<T: Ticks>(val: u64) -> T {
     if val <= T::max_value().into_u32() as u64 {
         T::from(val as u32)
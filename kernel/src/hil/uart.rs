@@ -48,6 +48,10 @@ pub enum Error {
     /// Overrun error during receive
     OverrunError,
 
+    /// Break condition detected during receive: the line was held low for
+    /// longer than a full character frame.
+    BreakError,
+
     /// Repeat call of transmit or receive before initial command complete
     RepeatCallError,
 
@@ -58,6 +62,20 @@ pub enum Error {
     Aborted,
 }
 
+impl Error {
+    /// Returns true if this error was caused by a problem on the receive
+    /// line (parity, framing, overrun or break) rather than by software, for
+    /// example an aborted call.
+    pub fn is_line_error(&self) -> bool {
+        match *self {
+            Error::ParityError | Error::FramingError | Error::OverrunError | Error::BreakError => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
 pub trait Uart<'a>: Configure + Transmit<'a> + Receive<'a> {}
 pub trait UartData<'a>: Transmit<'a> + Receive<'a> {}
 pub trait UartAdvanced<'a>: Configure + Transmit<'a> + ReceiveAdvanced<'a> {}
@@ -280,6 +298,12 @@ pub trait ReceiveClient {
     ///     contains how many words were received.
     ///   - FAIL if reception failed in some way: `error` may contain further
     ///     information.
+    ///
+    /// Implementations that can detect line errors (parity, framing, overrun
+    /// or break) SHOULD end the reception when one occurs and report it in
+    /// `error`, with `rval` set to FAIL and `rx_len` set to the number of
+    /// words received before the error. They should not silently drop the
+    /// affected words.
     fn received_buffer(
         &self,
         rx_buffer: &'static mut [u8],
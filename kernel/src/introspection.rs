@@ -14,6 +14,7 @@ use core::cell::Cell;
 
 use crate::capabilities::ProcessManagementCapability;
 use crate::common::cells::NumericCellExt;
+use crate::grant::GrantFailures;
 use crate::process;
 use crate::process::ProcessId;
 use crate::sched::Kernel;
@@ -123,6 +124,17 @@ impl KernelInfo {
             .process_map_or(0, app, |process| process.debug_timeslice_expiration_count())
     }
 
+    /// Returns how many microseconds this app has executed for since it
+    /// last started.
+    pub fn app_cpu_time_us(
+        &self,
+        app: ProcessId,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> u64 {
+        self.kernel
+            .process_map_or(0, app, |process| process.debug_cpu_time_us())
+    }
+
     /// Returns a tuple of the (the number of grants in the grant region this
     /// app has allocated, total number of grants that exist in the system).
     pub fn number_app_grant_uses(
@@ -143,6 +155,37 @@ impl KernelInfo {
         (used, number_of_grants)
     }
 
+    /// Returns a tuple of (the bytes of RAM the app can access, from the
+    /// start of its memory to its break, the bytes of its grant region).
+    pub fn app_memory_usage(
+        &self,
+        app: ProcessId,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> (usize, usize) {
+        self.kernel.process_map_or((0, 0), app, |process| {
+            (
+                process.app_memory_break() as usize - process.mem_start() as usize,
+                process.mem_end() as usize - process.kernel_memory_break() as usize,
+            )
+        })
+    }
+
+    /// Returns the `index`th driver, counting from 0, that failed to enter a
+    /// grant, and how often it failed for any process. Failures are counted
+    /// against the driver handling the system call; the driver is `None` for
+    /// failures outside system calls, such as in interrupt handlers. Returns
+    /// `None` past the last driver with failures.
+    ///
+    /// This tells a process that crashed apart from a grant region that ran
+    /// out of memory when a driver starts failing.
+    pub fn grant_failures(
+        &self,
+        index: usize,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> Option<(Option<usize>, GrantFailures)> {
+        self.kernel.grant_failures(index)
+    }
+
     /// Returns the total number of times all processes have exceeded
     /// their timeslices.
     pub fn timeslice_expirations(&self, _capability: &dyn ProcessManagementCapability) -> usize {
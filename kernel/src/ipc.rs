@@ -2,19 +2,36 @@
 //!
 //! This is a special syscall driver that allows userspace applications to
 //! share memory.
+//!
+//! A process becomes an IPC service by subscribing with subscribe number `0`.
+//! Other processes can either look up a service by its package name, or list
+//! every registered service and be notified when services come and go.
+//!
+//! Any process can claim any package name. Processes that need to be sure
+//! who they talk to can instead look up a service by its `ShortID`, and a
+//! service can ask for the `ShortID` of a client that notified it. Depending
+//! on the board's `AppIdPolicy`, `ShortID`s are tied to the key the
+//! application is signed with.
 
 use crate::capabilities::MemoryAllocationCapability;
 use crate::grant::Grant;
-use crate::mem::Read;
+use crate::mem::{Read, ReadWrite};
 use crate::process;
 use crate::process::ProcessId;
 use crate::sched::Kernel;
 use crate::upcall::Upcall;
 use crate::{CommandReturn, Driver, ErrorCode, ReadOnlyAppSlice, ReadWriteAppSlice};
+use core::cell::Cell;
 
 /// Syscall number
 pub const DRIVER_NUM: usize = 0x10000;
 
+/// Subscribe number for notifications that the list of services changed.
+///
+/// Other subscribe numbers are service descriptors, which start at 1 and
+/// count up, so the largest subscribe number is reserved for this.
+pub const SERVICES_CHANGED_SUBSCRIBE_NUM: usize = usize::MAX;
+
 /// Enum to mark which type of upcall is scheduled for the IPC mechanism.
 #[derive(Copy, Clone, Debug)]
 pub enum IPCUpcallType {
@@ -37,6 +54,10 @@ struct IPCData<const NUM_PROCS: usize> {
     client_upcalls: [Upcall; NUM_PROCS],
     /// The upcall setup by a service. Each process can only be one service.
     upcall: Upcall,
+    /// Buffer the list of services is written to.
+    discovery_slice: ReadWriteAppSlice,
+    /// Upcall for when a service is registered or goes away.
+    services_changed_upcall: Upcall,
 }
 
 impl<const NUM_PROCS: usize> Default for IPCData<NUM_PROCS> {
@@ -47,6 +68,8 @@ impl<const NUM_PROCS: usize> Default for IPCData<NUM_PROCS> {
             search_slice: ReadOnlyAppSlice::default(),
             client_upcalls: [Upcall::default(); NUM_PROCS],
             upcall: Upcall::default(),
+            discovery_slice: ReadWriteAppSlice::default(),
+            services_changed_upcall: Upcall::default(),
         }
     }
 }
@@ -55,15 +78,103 @@ impl<const NUM_PROCS: usize> Default for IPCData<NUM_PROCS> {
 pub struct IPC<const NUM_PROCS: usize> {
     /// The grant regions for each process that holds the per-process IPC data.
     data: Grant<IPCData<NUM_PROCS>>,
+    /// The registered services, indexed by process index.
+    services: [Cell<Option<ProcessId>>; NUM_PROCS],
 }
 
 impl<const NUM_PROCS: usize> IPC<NUM_PROCS> {
     pub fn new(kernel: &'static Kernel, capability: &dyn MemoryAllocationCapability) -> Self {
+        const NO_SERVICE: Cell<Option<ProcessId>> = Cell::new(None);
         Self {
             data: kernel.create_grant(capability),
+            services: [NO_SERVICE; NUM_PROCS],
         }
     }
 
+    /// Tell every process that wants to know that the service of `service`
+    /// was registered or went away.
+    fn notify_services_changed(&self, service: ProcessId, registered: bool) {
+        self.data.each(|_, data| {
+            data.services_changed_upcall
+                .schedule(service.id() + 1, registered as usize, 0);
+        });
+    }
+
+    /// Record whether the process `appid` offers a service.
+    fn set_service(&self, appid: ProcessId, registered: bool) {
+        let entry = match appid.index().and_then(|i| self.services.get(i)) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let was_registered = entry.get() == Some(appid);
+        entry.set(if registered { Some(appid) } else { None });
+        if registered != was_registered {
+            self.notify_services_changed(appid, registered);
+        }
+    }
+
+    /// Forget services of processes that have exited, faulted or restarted.
+    /// This is called by the main scheduler loop when a process stops
+    /// running.
+    pub(crate) fn remove_stopped_services(&self) {
+        for entry in self.services.iter() {
+            if let Some(service) = entry.get() {
+                let running = self.data.kernel.process_map_or(false, service, |process| {
+                    match process.get_state() {
+                        process::State::Faulted
+                        | process::State::Terminated
+                        | process::State::Unstarted => false,
+                        _ => true,
+                    }
+                });
+                if !running {
+                    entry.set(None);
+                    self.notify_services_changed(service, false);
+                }
+            }
+        }
+    }
+
+    /// Write the registered services to the discovery buffer of `appid`.
+    /// Each entry is the service descriptor as a little-endian `u32`, the
+    /// length of the name as a `u8`, and the name. Returns the number of
+    /// entries written and the number of services.
+    fn list_services(&self, appid: ProcessId) -> Result<(u32, u32), ErrorCode> {
+        self.remove_stopped_services();
+        let total = self
+            .services
+            .iter()
+            .filter(|entry| entry.get().is_some())
+            .count();
+        self.data
+            .enter(appid, |data| {
+                let mut written = 0;
+                let mut offset = 0;
+                data.discovery_slice.mut_map_or((), |buf| {
+                    for service in self.services.iter().filter_map(|entry| entry.get()) {
+                        let name = self
+                            .data
+                            .kernel
+                            .process_map_or("", service, |process| process.get_process_name())
+                            .as_bytes();
+                        let name = &name[..core::cmp::min(name.len(), u8::MAX as usize)];
+                        let end = offset + 5 + name.len();
+                        if end > buf.len() {
+                            break;
+                        }
+                        let descriptor = service.id() as u32 + 1;
+                        buf[offset..offset + 4].copy_from_slice(&descriptor.to_le_bytes());
+                        buf[offset + 4] = name.len() as u8;
+                        buf[offset + 5..end].copy_from_slice(name);
+                        offset = end;
+                        written += 1;
+                    }
+                });
+                (written, total as u32)
+            })
+            .map_err(ErrorCode::from)
+    }
+
     /// Schedule an IPC upcall for a process. This is called by the main
     /// scheduler loop if an IPC task was queued for the process.
     pub(crate) unsafe fn schedule_upcall(
@@ -140,10 +251,29 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
             // application name stored in the TBF header of the application.
             // The upcall that is passed to subscribe is called when another
             // process notifies the server process.
-            0 => self
+            0 => {
+                let res = self
+                    .data
+                    .enter(app_id, |data| {
+                        core::mem::swap(&mut data.upcall, &mut upcall);
+                        (upcall, data.upcall.is_set())
+                    })
+                    .map_err(|e| (upcall, e.into()));
+                res.map(|(upcall, registered)| {
+                    self.set_service(app_id, registered);
+                    upcall
+                })
+            }
+
+            // subscribe(SERVICES_CHANGED_SUBSCRIBE_NUM)
+            //
+            // Register an upcall for when a service is registered or goes
+            // away. The upcall gets the service descriptor, and 1 if the
+            // service was registered or 0 if it went away.
+            SERVICES_CHANGED_SUBSCRIBE_NUM => self
                 .data
                 .enter(app_id, |data| {
-                    core::mem::swap(&mut data.upcall, &mut upcall);
+                    core::mem::swap(&mut data.services_changed_upcall, &mut upcall);
                     upcall
                 })
                 .map_err(|e| (upcall, e.into())),
@@ -208,6 +338,13 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
     /// - `3`: Notify a client with descriptor `target_id`, typically in response to a previous
     ///        notify from the client. Returns an error if `target_id` refers to an invalid client
     ///        or the notify fails to enqueue.
+    /// - `4`: List the registered services into the buffer passed to `allow_readwrite` with
+    ///        `target_id` 0. Returns the number of services listed and the number of services
+    ///        registered, which is larger if the buffer was too small.
+    /// - `5`: Return the `ShortID` of the process with descriptor `target_id`. Returns
+    ///        `INVAL` if the process doesn't exist and `NOSUPPORT` if it has no `ShortID`.
+    /// - `6`: Perform discovery on the `ShortID` in `target_id`. Returns the service
+    ///        descriptor if a process with that `ShortID` exists, otherwise `NODEVICE`.
     fn command(
         &self,
         command_number: usize,
@@ -292,6 +429,43 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
                         )
                     })
             }
+            4 => match self.list_services(appid) {
+                Ok((written, total)) => CommandReturn::success_u32_u32(written, total),
+                Err(e) => CommandReturn::failure(e),
+            },
+            5 =>
+            /* ShortID of a process */
+            {
+                self.data
+                    .kernel
+                    .lookup_app_by_identifier(target_id.wrapping_sub(1))
+                    .map_or(CommandReturn::failure(ErrorCode::INVAL), |otherapp| {
+                        self.data.kernel.process_map_or(
+                            CommandReturn::failure(ErrorCode::INVAL),
+                            otherapp,
+                            |target| match target.get_short_id() {
+                                Some(short_id) => CommandReturn::success_u32(short_id.id()),
+                                None => CommandReturn::failure(ErrorCode::NOSUPPORT),
+                            },
+                        )
+                    })
+            }
+            6 =>
+            /* Discover by ShortID */
+            {
+                self.data
+                    .kernel
+                    .process_until(|p| {
+                        if p.get_short_id()
+                            .map_or(false, |id| id.id() as usize == target_id)
+                        {
+                            Some(CommandReturn::success_u32(p.processid().id() as u32 + 1))
+                        } else {
+                            None
+                        }
+                    })
+                    .unwrap_or(CommandReturn::failure(ErrorCode::NODEVICE))
+            }
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -326,7 +500,8 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
     /// specified by the target_id). allow() simply allows both processes to
     /// access the buffer, it does not signal the service.
     ///
-    /// target_id == 0 is currently unsupported and reserved for future use.
+    /// target_id == 0 sets the buffer that command `4` lists the registered
+    /// services into.
     fn allow_readwrite(
         &self,
         appid: ProcessId,
@@ -334,7 +509,12 @@ impl<const NUM_PROCS: usize> Driver for IPC<NUM_PROCS> {
         mut slice: ReadWriteAppSlice,
     ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
         if target_id == 0 {
-            Err((slice, ErrorCode::NOSUPPORT))
+            match self.data.enter(appid, |data| {
+                core::mem::swap(&mut data.discovery_slice, &mut slice);
+            }) {
+                Ok(_) => Ok(slice),
+                Err(e) => Err((slice, e.into())),
+            }
         } else {
             match self.data.enter(appid, |data| {
                 // Lookup the index of the app based on the passed in
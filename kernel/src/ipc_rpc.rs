@@ -0,0 +1,355 @@
+//! Request/response messaging between processes, layered on IPC.
+//!
+//! Plain IPC only gives processes shared memory and notifications, so every
+//! service has to invent its own protocol over the shared buffers. This
+//! driver instead lets a service declare the messages it accepts and copies
+//! requests and responses between processes itself, checking their sizes
+//! against what the service declared.
+//!
+//! Services are identified by the same descriptors as in IPC: the value
+//! returned by IPC discovery, which is the process identifier plus one.
+//!
+//! A service registers the number of methods it offers and the largest
+//! request and response it handles. A client sends a request for a method,
+//! and the kernel copies it to the service's request buffer, tagged with a
+//! correlation ID. The service answers with that ID, and the kernel copies
+//! the response back to the client's response buffer. A service handles one
+//! request at a time; requests to a busy service fail with `BUSY`.
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - Read-write `0`: request buffer of a service.
+//! - Read-write `1`: response buffer of a client.
+//! - Read-only `0`: the outgoing message: the request of a client, or the
+//!   response of a service.
+//!
+//! ### Subscribe
+//!
+//! - `0`: request received by a service. The upcall gets the descriptor of
+//!   the client, the method in bits 0-15 and the correlation ID in bits
+//!   16-31, and the length of the request.
+//! - `1`: response received by a client. The upcall gets the status, the
+//!   correlation ID and the length of the response.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Register as a service with `arg1` methods. `arg2` holds the largest
+//!   request in bits 0-15 and the largest response in bits 16-31.
+//! - `2`: Send a request to service `arg1`. `arg2` holds the method in bits
+//!   0-15 and the length of the request in bits 16-31. Returns the
+//!   correlation ID.
+//! - `3`: Respond to request `arg1` with a response of `arg2` bytes.
+//! - `4`: Reject request `arg1`. The client gets a `FAIL` status.
+
+use core::cell::Cell;
+
+use crate::capabilities::MemoryAllocationCapability;
+use crate::grant::Grant;
+use crate::mem::{Read, ReadWrite};
+use crate::process::ProcessId;
+use crate::sched::Kernel;
+use crate::upcall::Upcall;
+use crate::{CommandReturn, Driver, ErrorCode, ReadOnlyAppSlice, ReadWriteAppSlice};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x10003;
+
+/// The messages a service accepts.
+#[derive(Clone, Copy)]
+struct ServiceConfig {
+    methods: usize,
+    max_request: usize,
+    max_response: usize,
+}
+
+/// State that is stored in each process's grant region.
+#[derive(Default)]
+struct RpcData {
+    service: Option<ServiceConfig>,
+    /// The request the service is handling: the client and correlation ID.
+    pending: Option<(ProcessId, u16)>,
+    request_buffer: ReadWriteAppSlice,
+    response_buffer: ReadWriteAppSlice,
+    message: ReadOnlyAppSlice,
+    request_upcall: Upcall,
+    response_upcall: Upcall,
+}
+
+pub struct IpcRpc {
+    data: Grant<RpcData>,
+    next_correlation_id: Cell<u16>,
+}
+
+impl IpcRpc {
+    pub fn new(kernel: &'static Kernel, capability: &dyn MemoryAllocationCapability) -> Self {
+        Self {
+            data: kernel.create_grant(capability),
+            next_correlation_id: Cell::new(0),
+        }
+    }
+
+    fn register(&self, appid: ProcessId, methods: usize, sizes: usize) -> Result<(), ErrorCode> {
+        let max_request = sizes & 0xffff;
+        let max_response = sizes >> 16;
+        if methods == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        self.data
+            .enter(appid, |data| {
+                if data.request_buffer.len() < max_request {
+                    return Err(ErrorCode::SIZE);
+                }
+                data.service = Some(ServiceConfig {
+                    methods,
+                    max_request,
+                    max_response,
+                });
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    /// Copy a request from `client` to the service with descriptor
+    /// `service_descriptor`. Returns the correlation ID.
+    fn request(
+        &self,
+        client: ProcessId,
+        service_descriptor: usize,
+        method_and_len: usize,
+    ) -> Result<u16, ErrorCode> {
+        let method = method_and_len & 0xffff;
+        let len = method_and_len >> 16;
+        let service = service_descriptor
+            .checked_sub(1)
+            .and_then(|id| self.data.kernel.lookup_app_by_identifier(id))
+            .ok_or(ErrorCode::INVAL)?;
+        if service == client {
+            return Err(ErrorCode::INVAL);
+        }
+
+        self.data
+            .enter(client, |client_data| {
+                if client_data.message.len() < len {
+                    return Err(ErrorCode::SIZE);
+                }
+                self.data
+                    .enter(service, |service_data| {
+                        let config = service_data.service.ok_or(ErrorCode::INVAL)?;
+                        if method >= config.methods || len > config.max_request {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        if service_data.pending.is_some() {
+                            return Err(ErrorCode::BUSY);
+                        }
+                        if service_data.request_buffer.len() < len {
+                            return Err(ErrorCode::SIZE);
+                        }
+
+                        client_data.message.map_or((), |message| {
+                            service_data.request_buffer.mut_map_or((), |buffer| {
+                                buffer[..len].copy_from_slice(&message[..len]);
+                            });
+                        });
+
+                        let correlation_id = self.next_correlation_id.get();
+                        self.next_correlation_id.set(correlation_id.wrapping_add(1));
+                        service_data.pending = Some((client, correlation_id));
+                        service_data.request_upcall.schedule(
+                            client.id() + 1,
+                            method | ((correlation_id as usize) << 16),
+                            len,
+                        );
+                        Ok(correlation_id)
+                    })
+                    .unwrap_or_else(|err| Err(err.into()))
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    /// Finish the pending request `correlation_id` of `service`, copying a
+    /// response of `len` bytes to the client, or rejecting the request if
+    /// `len` is `None`.
+    fn respond(
+        &self,
+        service: ProcessId,
+        correlation_id: usize,
+        len: Option<usize>,
+    ) -> Result<(), ErrorCode> {
+        self.data
+            .enter(service, |service_data| {
+                let (client, pending_id) = match service_data.pending {
+                    Some(pending) if pending.1 as usize == correlation_id => pending,
+                    _ => return Err(ErrorCode::INVAL),
+                };
+                let config = service_data.service.ok_or(ErrorCode::INVAL)?;
+                if let Some(len) = len {
+                    if len > config.max_response || len > service_data.message.len() {
+                        return Err(ErrorCode::SIZE);
+                    }
+                }
+                service_data.pending = None;
+
+                // The client may have exited since sending the request, in
+                // which case there is nobody to respond to.
+                let _ = self.data.enter(client, |client_data| {
+                    let status = match len {
+                        Some(len) if client_data.response_buffer.len() < len => {
+                            Err(ErrorCode::SIZE)
+                        }
+                        Some(len) => {
+                            service_data.message.map_or((), |message| {
+                                client_data.response_buffer.mut_map_or((), |buffer| {
+                                    buffer[..len].copy_from_slice(&message[..len]);
+                                });
+                            });
+                            Ok(())
+                        }
+                        None => Err(ErrorCode::FAIL),
+                    };
+                    client_data.response_upcall.schedule(
+                        crate::into_statuscode(status),
+                        pending_id as usize,
+                        if status.is_ok() { len.unwrap_or(0) } else { 0 },
+                    );
+                });
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+}
+
+impl Driver for IpcRpc {
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Request received (services)
+    /// - `1`: Response received (clients)
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut upcall: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .data
+                .enter(appid, |data| {
+                    core::mem::swap(&mut data.request_upcall, &mut upcall);
+                })
+                .map_err(ErrorCode::from),
+            1 => self
+                .data
+                .enter(appid, |data| {
+                    core::mem::swap(&mut data.response_upcall, &mut upcall);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((upcall, e))
+        } else {
+            Ok(upcall)
+        }
+    }
+
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, always returns Ok(())
+    /// - `1`: Register as a service
+    /// - `2`: Send a request to a service
+    /// - `3`: Respond to a request
+    /// - `4`: Reject a request
+    fn command(
+        &self,
+        command_number: usize,
+        arg1: usize,
+        arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        let res = match command_number {
+            0 => Ok(()),
+            1 => self.register(appid, arg1, arg2),
+            2 => match self.request(appid, arg1, arg2) {
+                Ok(correlation_id) => return CommandReturn::success_u32(correlation_id as u32),
+                Err(e) => Err(e),
+            },
+            3 => self.respond(appid, arg1, Some(arg2)),
+            4 => self.respond(appid, arg1, None),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+
+    /// ### `allow_num`
+    ///
+    /// - `0`: Request buffer (services)
+    /// - `1`: Response buffer (clients)
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .data
+                .enter(appid, |data| {
+                    if data.pending.is_some() {
+                        // The request being handled is in this buffer.
+                        return Err(ErrorCode::BUSY);
+                    }
+                    core::mem::swap(&mut data.request_buffer, &mut slice);
+                    // The service must register again with the new buffer.
+                    data.service = None;
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            1 => self
+                .data
+                .enter(appid, |data| {
+                    core::mem::swap(&mut data.response_buffer, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// ### `allow_num`
+    ///
+    /// - `0`: Outgoing message
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .data
+                .enter(appid, |data| {
+                    core::mem::swap(&mut data.message, &mut slice);
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+}
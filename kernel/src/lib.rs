@@ -96,6 +96,8 @@ pub mod debug;
 pub mod hil;
 pub mod introspection;
 pub mod ipc;
+pub mod ipc_rpc;
+pub mod replay;
 pub mod syscall;
 
 mod config;
@@ -115,13 +117,17 @@ mod upcall;
 pub use crate::driver::{CommandReturn, Driver};
 pub use crate::errorcode::into_statuscode;
 pub use crate::errorcode::ErrorCode;
-pub use crate::grant::{Grant, ProcessGrant};
+pub use crate::grant::{Grant, GrantFailures, ProcessGrant};
 pub use crate::mem::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+pub use crate::platform::interrupt_rate;
+pub use crate::platform::power;
 pub use crate::platform::scheduler_timer::{SchedulerTimer, VirtualSchedulerTimer};
+pub use crate::platform::simulation;
+pub use crate::platform::syscall_rate;
 pub use crate::platform::watchdog;
 pub use crate::platform::{mpu, Chip, InterruptService, Platform};
 pub use crate::platform::{ClockInterface, NoClockControl, NO_CLOCK_CONTROL};
-pub use crate::process::ProcessId;
+pub use crate::process::{ProcessId, ShortID};
 pub use crate::sched::cooperative::{CoopProcessNode, CooperativeSched};
 pub use crate::sched::mlfq::{MLFQProcessNode, MLFQSched};
 pub use crate::sched::priority::PrioritySched;
@@ -136,13 +142,16 @@ pub use crate::upcall::Upcall;
 /// Publicly available process-related objects.
 pub mod procs {
     pub use crate::process::{
-        Error, FaultAction, FunctionCall, FunctionCallSource, Process, State, Task,
+        Error, FaultAction, FunctionCall, FunctionCallSource, Process, ProcessEvent,
+        ProcessEventsClient, State, Task,
     };
     pub use crate::process_policies::{
-        PanicFaultPolicy, ProcessFaultPolicy, RestartFaultPolicy, StopFaultPolicy,
+        AppIdPolicy, PackageNameAppIdPolicy, PanicFaultPolicy, ProcessFaultPolicy,
+        RestartFaultPolicy, SignatureVerifier, SigningKeyAppIdPolicy, StopFaultPolicy,
         StopWithDebugFaultPolicy, ThresholdRestartFaultPolicy,
         ThresholdRestartThenPanicFaultPolicy,
     };
     pub use crate::process_standard::ProcessStandard;
     pub use crate::process_utilities::{load_processes, ProcessLoadError};
+    pub use tock_tbf::types::{CommandPermissions, TbfHeaderV2Credentials};
 }
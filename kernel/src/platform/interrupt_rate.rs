@@ -0,0 +1,351 @@
+//! Interface for detecting interrupt storms.
+//!
+//! A misbehaving source, such as a bouncing GPIO line, can interrupt so often
+//! that the kernel spends all its time servicing it and processes never run.
+//! Chips that support it consult an `InterruptRateMonitor` before servicing
+//! each interrupt. If the monitor rejects the interrupt, the chip masks the
+//! source and ignores it until the board unmasks it again, typically after
+//! the monitor's client was told about the storm.
+//!
+//! `InterruptRateLimiter` is a monitor that allows each source a fixed number
+//! of interrupts per window of time. Sources the kernel cannot run without,
+//! such as the timer behind alarms, should be exempt from the limit.
+//! `InterruptStormRecovery` is a client that unmasks a source again once it
+//! has been quiet for a while:
+//!
+//! ```rust,ignore
+//! let limiter = static_init!(
+//!     InterruptRateLimiter<'static, Rtc, 64>,
+//!     InterruptRateLimiter::new(rtc, 1000, 100)
+//! );
+//! limiter.set_exempt(&[RTC1, GPIOTE]);
+//! let recovery = static_init!(
+//!     InterruptStormRecovery<'static, VirtualMuxAlarm<'static, Rtc>, 64>,
+//!     InterruptStormRecovery::new(alarm, chip, 5000)
+//! );
+//! alarm.set_alarm_client(recovery);
+//! limiter.set_client(recovery);
+//! chip.set_interrupt_monitor(limiter);
+//! ```
+
+use core::cell::Cell;
+
+use crate::common::cells::OptionalCell;
+use crate::hil::time::{self, Alarm, Frequency, Ticks, Time};
+
+/// Decides whether an interrupt should be serviced.
+pub trait InterruptRateMonitor {
+    /// Called by the chip with each interrupt before it is serviced. Return
+    /// `false` to have the chip mask `interrupt` instead of servicing it.
+    fn interrupt(&self, interrupt: u32) -> bool;
+}
+
+/// Implement default InterruptRateMonitor trait for unit, which allows every
+/// interrupt.
+impl InterruptRateMonitor for () {
+    fn interrupt(&self, _interrupt: u32) -> bool {
+        true
+    }
+}
+
+/// Told when a source is masked for interrupting too often.
+pub trait InterruptStormClient {
+    /// `interrupt` exceeded its rate and is masked once this returns. It
+    /// stays masked until the chip is asked to unmask it.
+    fn interrupt_storm(&self, interrupt: u32);
+}
+
+/// A chip that can service a masked interrupt source again.
+pub trait InterruptMask {
+    /// Unmask `interrupt`. Interrupts that arrived while it was masked are
+    /// still pending and are serviced next.
+    fn unmask_interrupt(&self, interrupt: u32);
+}
+
+/// Allows each of the first `N` interrupt sources at most `max_interrupts`
+/// interrupts per window. Sources numbered `N` or higher, and sources in the
+/// exempt list, are not limited.
+pub struct InterruptRateLimiter<'a, T: Time, const N: usize> {
+    time: &'a T,
+    exempt: Cell<&'a [u32]>,
+    max_interrupts: u32,
+    window: T::Ticks,
+    window_start: Cell<T::Ticks>,
+    counts: [Cell<u32>; N],
+    client: OptionalCell<&'a dyn InterruptStormClient>,
+}
+
+impl<'a, T: Time, const N: usize> InterruptRateLimiter<'a, T, N> {
+    const COUNT: Cell<u32> = Cell::new(0);
+
+    /// Allow at most `max_interrupts` interrupts per source in each window of
+    /// `window_ms` milliseconds, measured with `time`.
+    pub fn new(time: &'a T, window_ms: u32, max_interrupts: u32) -> InterruptRateLimiter<'a, T, N> {
+        let window = (T::Frequency::frequency() as u64 * window_ms as u64 / 1000) as u32;
+        InterruptRateLimiter {
+            time,
+            exempt: Cell::new(&[]),
+            max_interrupts,
+            window: T::Ticks::from(window),
+            window_start: Cell::new(time.now()),
+            counts: [Self::COUNT; N],
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Never mask the sources in `exempt`. The timer that `time` and the
+    /// storm client's alarm run on must be exempt, or a storm could stop the
+    /// clock that ends it.
+    pub fn set_exempt(&self, exempt: &'a [u32]) {
+        self.exempt.set(exempt);
+    }
+
+    pub fn set_client(&self, client: &'a dyn InterruptStormClient) {
+        self.client.set(client);
+    }
+}
+
+impl<T: Time, const N: usize> InterruptRateMonitor for InterruptRateLimiter<'_, T, N> {
+    fn interrupt(&self, interrupt: u32) -> bool {
+        if self.exempt.get().contains(&interrupt) {
+            return true;
+        }
+        let count = match self.counts.get(interrupt as usize) {
+            Some(count) => count,
+            None => return true,
+        };
+
+        let now = self.time.now();
+        if now.wrapping_sub(self.window_start.get()) >= self.window {
+            self.window_start.set(now);
+            self.counts.iter().for_each(|count| count.set(0));
+        }
+
+        count.set(count.get().saturating_add(1));
+        if count.get() > self.max_interrupts {
+            count.set(0);
+            self.client.map(|client| client.interrupt_storm(interrupt));
+            false
+        } else {
+            true
+        }
+    }
+}
+
+/// Unmasks the sources masked for a storm once `backoff_ms` milliseconds have
+/// passed since the first of them was masked.
+///
+/// The backoff starts at the first storm, so sources masked while it is
+/// running are unmasked with the rest and may be masked for less than
+/// `backoff_ms`. Only the first `N` sources are tracked, which matches an
+/// `InterruptRateLimiter` with the same `N`.
+pub struct InterruptStormRecovery<'a, A: Alarm<'a>, const N: usize> {
+    alarm: &'a A,
+    chip: &'a dyn InterruptMask,
+    backoff_ms: u32,
+    masked: [Cell<bool>; N],
+}
+
+impl<'a, A: Alarm<'a>, const N: usize> InterruptStormRecovery<'a, A, N> {
+    const MASKED: Cell<bool> = Cell::new(false);
+
+    pub fn new(
+        alarm: &'a A,
+        chip: &'a dyn InterruptMask,
+        backoff_ms: u32,
+    ) -> InterruptStormRecovery<'a, A, N> {
+        InterruptStormRecovery {
+            alarm,
+            chip,
+            backoff_ms,
+            masked: [Self::MASKED; N],
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, const N: usize> InterruptStormClient for InterruptStormRecovery<'a, A, N> {
+    fn interrupt_storm(&self, interrupt: u32) {
+        if let Some(masked) = self.masked.get(interrupt as usize) {
+            masked.set(true);
+            if !self.alarm.is_armed() {
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(self.backoff_ms));
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, const N: usize> time::AlarmClient for InterruptStormRecovery<'a, A, N> {
+    fn alarm(&self) {
+        for (interrupt, masked) in self.masked.iter().enumerate() {
+            if masked.replace(false) {
+                self.chip.unmask_interrupt(interrupt as u32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hil::time::{AlarmClient, Freq1KHz, Ticks32};
+    use crate::ErrorCode;
+
+    /// A millisecond counter with an alarm that the test fires by hand.
+    struct MockAlarm {
+        now: Cell<u32>,
+        armed: Cell<bool>,
+        expiry: Cell<u32>,
+    }
+
+    impl MockAlarm {
+        fn new() -> MockAlarm {
+            MockAlarm {
+                now: Cell::new(0),
+                armed: Cell::new(false),
+                expiry: Cell::new(0),
+            }
+        }
+
+        fn advance(&self, ms: u32) {
+            self.now.set(self.now.get().wrapping_add(ms));
+        }
+    }
+
+    impl Time for MockAlarm {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(self.now.get())
+        }
+    }
+
+    impl<'a> Alarm<'a> for MockAlarm {
+        fn set_alarm_client(&'a self, _client: &'a dyn AlarmClient) {}
+
+        fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+            self.armed.set(true);
+            self.expiry.set(reference.wrapping_add(dt).into_u32());
+        }
+
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32::from(self.expiry.get())
+        }
+
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            self.armed.set(false);
+            Ok(())
+        }
+
+        fn is_armed(&self) -> bool {
+            self.armed.get()
+        }
+
+        fn minimum_dt(&self) -> Ticks32 {
+            Ticks32::from(1)
+        }
+    }
+
+    struct MockChip {
+        unmasked: Cell<u32>,
+    }
+
+    impl InterruptMask for MockChip {
+        fn unmask_interrupt(&self, interrupt: u32) {
+            self.unmasked.set(self.unmasked.get() | 1 << interrupt);
+        }
+    }
+
+    struct Storms {
+        storms: Cell<u32>,
+    }
+
+    impl InterruptStormClient for Storms {
+        fn interrupt_storm(&self, interrupt: u32) {
+            self.storms.set(self.storms.get() | 1 << interrupt);
+        }
+    }
+
+    #[test]
+    fn test_limit_per_window() {
+        let time = MockAlarm::new();
+        let storms = Storms {
+            storms: Cell::new(0),
+        };
+        let limiter: InterruptRateLimiter<MockAlarm, 8> = InterruptRateLimiter::new(&time, 100, 3);
+        limiter.set_client(&storms);
+
+        for _ in 0..3 {
+            assert!(limiter.interrupt(2));
+        }
+        assert!(limiter.interrupt(3));
+        assert!(!limiter.interrupt(2));
+        assert_eq!(storms.storms.get(), 1 << 2);
+
+        // A new window starts with a clean count for every source.
+        time.advance(100);
+        for _ in 0..3 {
+            assert!(limiter.interrupt(2));
+            assert!(limiter.interrupt(3));
+        }
+        assert_eq!(storms.storms.get(), 1 << 2);
+
+        // Sources past the end of the table are never limited.
+        for _ in 0..10 {
+            assert!(limiter.interrupt(8));
+        }
+    }
+
+    #[test]
+    fn test_exempt() {
+        let time = MockAlarm::new();
+        let storms = Storms {
+            storms: Cell::new(0),
+        };
+        let limiter: InterruptRateLimiter<MockAlarm, 8> = InterruptRateLimiter::new(&time, 100, 3);
+        limiter.set_client(&storms);
+        limiter.set_exempt(&[1, 6]);
+
+        for _ in 0..100 {
+            assert!(limiter.interrupt(1));
+            assert!(limiter.interrupt(6));
+        }
+        assert_eq!(storms.storms.get(), 0);
+        for _ in 0..3 {
+            assert!(limiter.interrupt(2));
+        }
+        assert!(!limiter.interrupt(2));
+    }
+
+    #[test]
+    fn test_recovery_unmasks() {
+        let alarm = MockAlarm::new();
+        let chip = MockChip {
+            unmasked: Cell::new(0),
+        };
+        let recovery: InterruptStormRecovery<MockAlarm, 8> =
+            InterruptStormRecovery::new(&alarm, &chip, 5000);
+
+        recovery.interrupt_storm(2);
+        assert!(alarm.is_armed());
+        assert_eq!(alarm.expiry.get(), 5000);
+
+        // A second storm during the backoff does not push it back.
+        alarm.advance(1000);
+        recovery.interrupt_storm(5);
+        assert_eq!(alarm.expiry.get(), 5000);
+
+        // Untracked sources are left alone.
+        recovery.interrupt_storm(9);
+
+        alarm.armed.set(false);
+        recovery.alarm();
+        assert_eq!(chip.unmasked.get(), 1 << 2 | 1 << 5);
+
+        // Each source is unmasked once.
+        chip.unmasked.set(0);
+        recovery.alarm();
+        assert_eq!(chip.unmasked.get(), 0);
+    }
+}
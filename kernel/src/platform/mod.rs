@@ -6,8 +6,12 @@ use crate::process;
 use crate::syscall;
 use core::fmt::Write;
 
+pub mod interrupt_rate;
 pub mod mpu;
+pub mod power;
 pub(crate) mod scheduler_timer;
+pub mod simulation;
+pub mod syscall_rate;
 pub mod watchdog;
 
 /// Interface for individual boards.
@@ -0,0 +1,115 @@
+//! Interface for gating the power of peripherals that are not in use.
+//!
+//! Chips with power domains or clock gates implement `PowerController`. The
+//! board creates a `PowerDomain` for each domain it wants gated and registers
+//! it with a `PowerRegistry`. Peripheral drivers given a `PowerDomain` call
+//! `in_use()` before they touch the hardware and `idle()` once they are done
+//! with it. A domain is powered while at least one of its users is in use, so
+//! peripherals that no driver uses stop drawing current without boards having
+//! to turn them off by hand.
+//!
+//! ```ignore
+//! let power_registry = static_init!(PowerRegistry, PowerRegistry::new(pwr_ctrl));
+//! let iom2_power = static_init!(PowerDomain, PowerDomain::new(pwrctrl::IOM2));
+//! power_registry.register(iom2_power);
+//! peripherals.iom2.set_power_domain(iom2_power);
+//! ```
+
+use core::cell::Cell;
+
+use crate::common::cells::OptionalCell;
+use crate::common::{List, ListLink, ListNode};
+
+/// Implemented by chips to power domains on and off.
+pub trait PowerController {
+    /// Power `domain` on or off. Domains are numbered by the chip.
+    fn set_domain_power(&self, domain: usize, powered: bool);
+}
+
+/// A power domain, and how many drivers are using it.
+pub struct PowerDomain<'a> {
+    domain: usize,
+    users: Cell<usize>,
+    controller: OptionalCell<&'a dyn PowerController>,
+    next: ListLink<'a, PowerDomain<'a>>,
+}
+
+impl<'a> ListNode<'a, PowerDomain<'a>> for PowerDomain<'a> {
+    fn next(&self) -> &'a ListLink<PowerDomain<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> PowerDomain<'a> {
+    pub const fn new(domain: usize) -> PowerDomain<'a> {
+        PowerDomain {
+            domain: domain,
+            users: Cell::new(0),
+            controller: OptionalCell::empty(),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// The chip's number for this domain.
+    pub fn domain(&self) -> usize {
+        self.domain
+    }
+
+    /// Called by a driver before using the peripherals in this domain. Each
+    /// call must be matched by a call to `idle()`.
+    pub fn in_use(&self) {
+        self.users.set(self.users.get() + 1);
+        if self.users.get() == 1 {
+            self.controller
+                .map(|controller| controller.set_domain_power(self.domain, true));
+        }
+    }
+
+    /// Called by a driver once it no longer needs the peripherals in this
+    /// domain.
+    pub fn idle(&self) {
+        if self.users.get() == 0 {
+            return;
+        }
+        self.users.set(self.users.get() - 1);
+        if self.users.get() == 0 {
+            self.controller
+                .map(|controller| controller.set_domain_power(self.domain, false));
+        }
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.users.get() > 0
+    }
+}
+
+/// The power domains of a chip that are gated according to their use.
+pub struct PowerRegistry<'a> {
+    controller: &'a dyn PowerController,
+    domains: List<'a, PowerDomain<'a>>,
+}
+
+impl<'a> PowerRegistry<'a> {
+    pub fn new(controller: &'a dyn PowerController) -> PowerRegistry<'a> {
+        PowerRegistry {
+            controller: controller,
+            domains: List::new(),
+        }
+    }
+
+    /// Start gating `domain`. It is powered off right away unless a driver
+    /// is already using it.
+    pub fn register(&self, domain: &'a PowerDomain<'a>) {
+        domain.controller.set(self.controller);
+        self.controller
+            .set_domain_power(domain.domain, domain.is_powered());
+        self.domains.push_tail(domain);
+    }
+
+    /// Call `f` with each registered domain and whether it is powered.
+    pub fn each_domain<F: FnMut(usize, bool)>(&self, mut f: F) {
+        for domain in self.domains.iter() {
+            f(domain.domain, domain.is_powered());
+        }
+    }
+}
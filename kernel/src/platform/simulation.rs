@@ -0,0 +1,63 @@
+//! Interface for reporting test results from simulated platforms.
+//!
+//! When a board runs under QEMU or Verilator there is nobody watching the
+//! console, so automated runs need a way to learn whether the kernel booted
+//! and its self-tests passed. Boards report the outcome by printing one of
+//! the markers below on the console and, where the simulator supports it,
+//! asking the simulator to exit with a status code. `tools/run_sim` scans for
+//! these markers.
+
+use core::fmt::Write;
+
+use crate::debug::{self, IoWrite};
+
+/// Printed once all self-tests have passed.
+pub const PASS_MARKER: &str = "TOCK-SIM: PASS";
+
+/// Printed when any self-test failed. Followed by the failure code.
+pub const FAIL_MARKER: &str = "TOCK-SIM: FAIL";
+
+/// Outcome of a simulated test run.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SimulationResult {
+    Pass,
+    /// The run failed. The value is passed to the simulator as the exit
+    /// code and must be non-zero.
+    Fail(u32),
+}
+
+impl SimulationResult {
+    /// The exit code a simulator should return for this result.
+    pub fn code(&self) -> u32 {
+        match *self {
+            SimulationResult::Pass => 0,
+            SimulationResult::Fail(code) => code,
+        }
+    }
+}
+
+/// A device (real or emulated) that can end a simulation.
+///
+/// Boards that support running in a simulator implement this, typically in
+/// their `io.rs` next to the panic writer.
+pub trait SimulationExit {
+    /// Report `result` and stop the simulation. Implementations for
+    /// simulators without an exit device must print the result and then
+    /// sleep forever, leaving it to the host to stop the simulator.
+    fn exit(&self, result: SimulationResult) -> !;
+}
+
+/// Flush any pending kernel debug output and print the result marker to
+/// `writer`.
+///
+/// `writer` must write synchronously, like the writer used by the panic
+/// handler, as the caller is about to stop the simulation.
+pub unsafe fn report<W: Write + IoWrite>(writer: &mut W, result: SimulationResult) {
+    debug::flush(writer);
+    let _ = match result {
+        SimulationResult::Pass => writer.write_fmt(format_args!("\r\n{}\r\n", PASS_MARKER)),
+        SimulationResult::Fail(code) => {
+            writer.write_fmt(format_args!("\r\n{} {}\r\n", FAIL_MARKER, code))
+        }
+    };
+}
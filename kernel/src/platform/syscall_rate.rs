@@ -0,0 +1,288 @@
+//! Rate limiting of command system calls.
+//!
+//! A buggy process that calls `command` in a tight loop can keep a shared bus
+//! or radio busy so that other processes barely get to use it. The
+//! `SyscallRateLimiter` gives each process a token bucket for each driver the
+//! board configures a limit for. Each command to the driver takes a token,
+//! and tokens come back at a fixed rate up to the size of the bucket. Commands
+//! that find the bucket empty fail with `BUSY` without reaching the driver.
+//!
+//! Boards use the limiter from their `Platform::filter_syscall()`:
+//!
+//! ```rust,ignore
+//! static RATE_LIMITS: [SyscallRateLimit; 2] = [
+//!     SyscallRateLimit::new(capsules::i2c_master::DRIVER_NUM, 20, 100),
+//!     SyscallRateLimit::new(capsules::ble_advertising_driver::DRIVER_NUM, 5, 10),
+//! ];
+//!
+//! let rate_limiter = static_init!(
+//!     SyscallRateLimiter<'static, VirtualMuxAlarm<'static, Rtc>, NUM_PROCS, 2>,
+//!     SyscallRateLimiter::new(alarm, &RATE_LIMITS)
+//! );
+//!
+//! impl Platform for Board {
+//!     fn filter_syscall(
+//!         &self,
+//!         process: &dyn process::Process,
+//!         syscall: &syscall::Syscall,
+//!     ) -> Result<(), ErrorCode> {
+//!         self.rate_limiter.filter_syscall(process, syscall)
+//!     }
+//! }
+//! ```
+
+use core::cell::Cell;
+
+use crate::errorcode::ErrorCode;
+use crate::hil::time::{Frequency, Ticks, Time};
+use crate::process;
+use crate::syscall::Syscall;
+
+/// The command rate allowed to each process for one driver.
+pub struct SyscallRateLimit {
+    driver_num: usize,
+    burst: u32,
+    per_second: u32,
+}
+
+impl SyscallRateLimit {
+    /// Allow each process bursts of up to `burst` commands to `driver_num`,
+    /// and `per_second` commands per second over time.
+    pub const fn new(driver_num: usize, burst: u32, per_second: u32) -> SyscallRateLimit {
+        SyscallRateLimit {
+            driver_num,
+            burst,
+            per_second,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Bucket {
+    /// Identifier of the process the bucket belongs to, so a new process in
+    /// the same slot starts with a full bucket.
+    app_id: Option<usize>,
+    tokens: u32,
+    last_refill: u32,
+}
+
+const BUCKET: Cell<Bucket> = Cell::new(Bucket {
+    app_id: None,
+    tokens: 0,
+    last_refill: 0,
+});
+
+/// Limits the commands of the first `N` processes to the `M` drivers in its
+/// limits. Commands to other drivers, and all other system calls, are not
+/// limited.
+///
+/// An idle period is measured with `T`, so one longer than `T` takes to wrap
+/// may count as a shorter one. It still refills at least the tokens of the
+/// shorter period, so use a `T` that wraps well after buckets refill.
+pub struct SyscallRateLimiter<'a, T: Time, const N: usize, const M: usize> {
+    time: &'a T,
+    limits: &'a [SyscallRateLimit; M],
+    buckets: [[Cell<Bucket>; M]; N],
+}
+
+impl<'a, T: Time, const N: usize, const M: usize> SyscallRateLimiter<'a, T, N, M> {
+    const BUCKETS: [Cell<Bucket>; M] = [BUCKET; M];
+
+    pub fn new(time: &'a T, limits: &'a [SyscallRateLimit; M]) -> SyscallRateLimiter<'a, T, N, M> {
+        SyscallRateLimiter {
+            time,
+            limits,
+            buckets: [Self::BUCKETS; N],
+        }
+    }
+
+    /// Take a token for `syscall` from the bucket of `process`. Returns
+    /// `BUSY` if the bucket is empty.
+    pub fn filter_syscall(
+        &self,
+        process: &dyn process::Process,
+        syscall: &Syscall,
+    ) -> Result<(), ErrorCode> {
+        let driver_num = match *syscall {
+            Syscall::Command { driver_number, .. } => driver_number,
+            _ => return Ok(()),
+        };
+        let processid = process.processid();
+        let buckets = match processid.index().and_then(|index| self.buckets.get(index)) {
+            Some(buckets) => buckets,
+            None => return Ok(()),
+        };
+        let (limit, bucket) = match self
+            .limits
+            .iter()
+            .zip(buckets.iter())
+            .find(|(limit, _)| limit.driver_num == driver_num)
+        {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+
+        self.take_token(limit, bucket, processid.id())
+    }
+
+    /// Take a token from `bucket`, which belongs to the process with
+    /// identifier `app_id`, after adding the tokens that came back since the
+    /// last refill.
+    fn take_token(
+        &self,
+        limit: &SyscallRateLimit,
+        bucket: &Cell<Bucket>,
+        app_id: usize,
+    ) -> Result<(), ErrorCode> {
+        let now = self.time.now();
+        let mut state = bucket.get();
+        if state.app_id != Some(app_id) {
+            state = Bucket {
+                app_id: Some(app_id),
+                tokens: limit.burst,
+                last_refill: now.into_u32(),
+            };
+        }
+
+        let frequency = T::Frequency::frequency() as u64;
+        let per_second = limit.per_second as u64;
+        let last_refill = T::Ticks::from(state.last_refill);
+        let elapsed = now.wrapping_sub(last_refill).into_u32() as u64;
+        // The time a whole bucket takes to refill. Waiting that long fills
+        // the bucket however much longer it was, which also bounds what a
+        // wrap of the tick counter after a long idle period can give.
+        let refill_time = match per_second {
+            0 => u64::MAX,
+            _ => limit.burst as u64 * frequency / per_second,
+        };
+        if elapsed >= refill_time {
+            state.tokens = limit.burst;
+            state.last_refill = now.into_u32();
+        } else {
+            let refill = elapsed * per_second / frequency;
+            if refill > 0 {
+                state.tokens = (state.tokens as u64 + refill).min(limit.burst as u64) as u32;
+                // Only move the refill time on by the time the added tokens
+                // took, so the part of a token already earned is kept.
+                let used = (refill * frequency / per_second) as u32;
+                state.last_refill = last_refill.wrapping_add(T::Ticks::from(used)).into_u32();
+            }
+            if state.tokens == limit.burst {
+                // A full bucket earns nothing while it stays full.
+                state.last_refill = now.into_u32();
+            }
+        }
+
+        let result = if state.tokens > 0 {
+            state.tokens -= 1;
+            Ok(())
+        } else {
+            Err(ErrorCode::BUSY)
+        };
+        bucket.set(state);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SyscallRateLimit, SyscallRateLimiter};
+    use crate::hil::time::{Freq1KHz, Ticks16, Time};
+    use core::cell::Cell;
+
+    /// A millisecond counter that wraps after 65.536 seconds.
+    struct MockTime {
+        now: Cell<u16>,
+    }
+
+    impl MockTime {
+        fn advance(&self, ms: u16) {
+            self.now.set(self.now.get().wrapping_add(ms));
+        }
+    }
+
+    impl Time for MockTime {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks16;
+
+        fn now(&self) -> Ticks16 {
+            Ticks16::from(self.now.get())
+        }
+    }
+
+    // Bursts of 4 commands, then one every 100 ms.
+    const LIMITS: [SyscallRateLimit; 1] = [SyscallRateLimit::new(1, 4, 10)];
+
+    fn take(limiter: &SyscallRateLimiter<MockTime, 1, 1>) -> bool {
+        limiter
+            .take_token(&limiter.limits[0], &limiter.buckets[0][0], 1)
+            .is_ok()
+    }
+
+    #[test]
+    fn test_steady_rate() {
+        let time = MockTime { now: Cell::new(0) };
+        let limiter = SyscallRateLimiter::new(&time, &LIMITS);
+
+        // Polling every 30 ms for 3 s gets the burst and then a token every
+        // 100 ms, with no part of a token lost between polls.
+        let mut allowed = 0;
+        for _ in 0..100 {
+            if take(&limiter) {
+                allowed += 1;
+            }
+            time.advance(30);
+        }
+        assert_eq!(allowed, 4 + 29);
+    }
+
+    #[test]
+    fn test_burst() {
+        let time = MockTime { now: Cell::new(0) };
+        let limiter = SyscallRateLimiter::new(&time, &LIMITS);
+
+        for _ in 0..4 {
+            assert!(take(&limiter));
+        }
+        assert!(!take(&limiter));
+
+        // Idling for longer than the bucket takes to refill gives back the
+        // burst and no more.
+        time.advance(10_000);
+        for _ in 0..4 {
+            assert!(take(&limiter));
+        }
+        assert!(!take(&limiter));
+
+        // Part of the way, only the tokens earned come back.
+        time.advance(250);
+        assert!(take(&limiter));
+        assert!(take(&limiter));
+        assert!(!take(&limiter));
+    }
+
+    #[test]
+    fn test_wrap() {
+        let time = MockTime {
+            now: Cell::new(u16::MAX - 50),
+        };
+        let limiter = SyscallRateLimiter::new(&time, &LIMITS);
+
+        for _ in 0..4 {
+            assert!(take(&limiter));
+        }
+        assert!(!take(&limiter));
+
+        // The counter wraps during the 100 ms it takes to earn a token.
+        time.advance(100);
+        assert!(take(&limiter));
+        assert!(!take(&limiter));
+
+        // A long idle period across a wrap fills the bucket, but no more.
+        time.advance(u16::MAX - 100);
+        for _ in 0..4 {
+            assert!(take(&limiter));
+        }
+        assert!(!take(&limiter));
+    }
+}
@@ -7,13 +7,16 @@ use core::ptr::NonNull;
 use core::str;
 
 use crate::capabilities;
+use crate::common::math::{fnv1a_32, FNV1A_32_OFFSET};
 use crate::errorcode::ErrorCode;
 use crate::ipc;
 use crate::mem::{ReadOnlyAppSlice, ReadWriteAppSlice};
 use crate::platform::mpu::{self};
+use crate::process_policies::ProcessFaultPolicy;
 use crate::sched::Kernel;
 use crate::syscall::{self, Syscall, SyscallReturn};
 use crate::upcall::UpcallId;
+use tock_tbf::types::{CommandPermissions, TbfHeaderV2Credentials};
 
 /// Userspace process identifier.
 ///
@@ -86,6 +89,52 @@ impl fmt::Debug for ProcessId {
     }
 }
 
+/// A short identifier for an application that, unlike a `ProcessId`, stays
+/// the same when the process restarts and across reboots.
+///
+/// The kernel's `AppIdPolicy` assigns each process its `ShortID` when the
+/// process is loaded, so capsules can use it to key state an application
+/// stores persistently or to decide what an application may access. The top
+/// bit is set for `ShortID`s derived from a verified signing key, so an
+/// unsigned application can never take the `ShortID` of a signed one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ShortID(u32);
+
+/// Bit set in `ShortID`s derived from a signing key.
+const SHORT_ID_SIGNED: u32 = 0x8000_0000;
+
+impl ShortID {
+    /// The `ShortID` of an unsigned application with package name `name`.
+    ///
+    /// Any application can claim any name, so this identifies an application
+    /// but does not authenticate it. Applications with the same name, such as
+    /// two versions of one application, share a `ShortID`.
+    pub fn from_name(name: &str) -> ShortID {
+        ShortID(fnv1a_32(FNV1A_32_OFFSET, name.as_bytes()) & !SHORT_ID_SIGNED)
+    }
+
+    /// The `ShortID` of an application with package name `name`, whose
+    /// signature with `public_key` has been verified.
+    ///
+    /// Updates of the application keep the `ShortID` as long as they are
+    /// signed with the same key and keep the name.
+    pub fn from_signing_key(public_key: &[u8], name: &str) -> ShortID {
+        let hash = fnv1a_32(FNV1A_32_OFFSET, public_key);
+        // Separate the key from the name, so they can't trade bytes.
+        let hash = fnv1a_32(hash, &(public_key.len() as u32).to_le_bytes());
+        ShortID(fnv1a_32(hash, name.as_bytes()) | SHORT_ID_SIGNED)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+
+    /// Whether this `ShortID` was derived from a verified signing key.
+    pub fn is_signed(&self) -> bool {
+        self.0 & SHORT_ID_SIGNED != 0
+    }
+}
+
 impl ProcessId {
     /// Create a new `ProcessId` object based on the app identifier and its index
     /// in the processes array.
@@ -147,6 +196,13 @@ impl ProcessId {
         self.identifier
     }
 
+    /// Get the `ShortID` of the app this `ProcessId` refers to, or `None` if
+    /// the app no longer exists or the `AppIdPolicy` gave it none.
+    pub fn short_id(&self) -> Option<ShortID> {
+        self.kernel
+            .process_map_or(None, *self, |process| process.get_short_id())
+    }
+
     /// Returns the full address of the start and end of the flash region that
     /// the app owns and can write to. This includes the app's code and data and
     /// any padding at the end of the app. It does not include the TBF header,
@@ -228,6 +284,59 @@ pub trait Process {
     /// Get the name of the process. Used for IPC.
     fn get_process_name(&self) -> &'static str;
 
+    /// Get the `ShortID` the `AppIdPolicy` assigned to the process when it
+    /// was loaded, if any.
+    fn get_short_id(&self) -> Option<ShortID>;
+
+    /// Get the credentials in the TBF header of the process, if it has any.
+    fn get_credentials(&self) -> Option<TbfHeaderV2Credentials>;
+
+    /// Get the TBF header of the process, as it is in flash.
+    fn get_tbf_header(&self) -> &'static [u8];
+
+    /// Get the process's flash after the protected region, which its
+    /// credentials cover along with the TBF header.
+    fn get_app_binary(&self) -> &'static [u8];
+
+    /// Attach or detach a debugger. While a debugger is attached, a fault
+    /// stops the process where it faulted, in the `StoppedRunning` state,
+    /// instead of applying the fault policy, so the debugger can inspect the
+    /// process and `resume()` it.
+    fn set_debugger_attached(&self, attached: bool);
+
+    /// Whether a fault stopped the process while a debugger was attached,
+    /// and the process has not been resumed since.
+    fn debug_faulted(&self) -> bool;
+
+    /// Copy the memory of the process at `address` into `buffer`, for a
+    /// debugger. Only memory the process can access, its flash and its RAM
+    /// below the app break, can be read; anything else returns `INVAL`.
+    fn debug_read_memory(&self, address: usize, buffer: &mut [u8]) -> Result<(), ErrorCode>;
+
+    /// Write `data` to the RAM of the process at `address`, for a debugger.
+    /// Only RAM below the app break can be written; anything else returns
+    /// `INVAL`.
+    fn debug_write_memory(&self, address: usize, data: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Read register `index` of the process, numbered the way GDB numbers
+    /// the registers of the architecture. Returns `None` for registers that
+    /// do not exist or cannot be read.
+    fn debug_read_register(&self, index: usize) -> Option<usize>;
+
+    /// Write register `index` of the process, numbered the way GDB numbers
+    /// the registers of the architecture.
+    fn debug_write_register(&self, index: usize, value: usize) -> Result<(), ErrorCode>;
+
+    /// Get which commands of driver `driver_num` the process's TBF header
+    /// allows it to call, for command numbers `offset * 64` to
+    /// `offset * 64 + 63`. Privileged drivers use this to check whether a
+    /// process may use them.
+    fn get_command_permissions(&self, driver_num: usize, offset: usize) -> CommandPermissions;
+
+    /// Replace the policy deciding what the kernel does when this process
+    /// faults.
+    fn set_fault_policy(&self, fault_policy: &'static dyn ProcessFaultPolicy);
+
     /// Stop and clear a process's state, putting it into the `Terminated`
     /// state.
     ///
@@ -294,6 +403,10 @@ pub trait Process {
     /// The lowest address of the grant region for the process.
     fn kernel_memory_break(&self) -> *const u8;
 
+    /// The first address after the memory the process can access, i.e. the
+    /// top of its heap.
+    fn app_memory_break(&self) -> *const u8;
+
     /// How many writeable flash regions defined in the TBF header for this
     /// process.
     fn number_writeable_flash_regions(&self) -> usize;
@@ -526,6 +639,14 @@ pub trait Process {
     /// Increment the number of times the process has exceeded its timeslice.
     fn debug_timeslice_expired(&self);
 
+    /// Returns how many microseconds this process has executed for. Only
+    /// time spent running with a timeslice is counted, so this is always zero
+    /// with cooperative scheduling.
+    fn debug_cpu_time_us(&self) -> u64;
+
+    /// Add `time_us` microseconds to the time this process has executed for.
+    fn debug_cpu_time_used(&self, time_us: u32);
+
     /// Increment the number of times the process called a syscall and record
     /// the last syscall that was called.
     fn debug_syscall_called(&self, last_syscall: Syscall);
@@ -688,6 +809,32 @@ pub enum FaultAction {
     Stop,
 }
 
+/// A change in the lifecycle of a process, reported to the kernel's
+/// `ProcessEventsClient`.
+#[derive(Copy, Clone)]
+pub enum ProcessEvent {
+    /// The process is about to run its init function, either for the first
+    /// time or after a restart.
+    Started,
+
+    /// The process called the exit system call with the given completion
+    /// code. `restart` is set if it asked to be restarted.
+    Exited { completion_code: u32, restart: bool },
+
+    /// The process faulted and the kernel is about to take `action`.
+    Faulted(FaultAction),
+}
+
+/// Client notified of process lifecycle events.
+///
+/// Set with `Kernel::set_process_events_client()`.
+pub trait ProcessEventsClient {
+    /// Called when `process` has started, exited or faulted. For exits and
+    /// faults this is called before the kernel terminates or restarts the
+    /// process, so `process` is still valid.
+    fn process_event(&self, process: ProcessId, event: ProcessEvent);
+}
+
 /// Tasks that can be enqueued for a process.
 ///
 /// This is public for external implementations of `Process`.
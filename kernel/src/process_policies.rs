@@ -2,10 +2,12 @@
 //!
 //! This file contains definitions and implementations of policies the Tock
 //! kernel can use when managing processes. For example, these policies control
-//! decisions such as whether a specific process should be restarted.
+//! decisions such as whether a specific process should be restarted, or how
+//! a process is identified.
 
 use crate::process;
-use crate::process::Process;
+use crate::process::{Process, ShortID};
+use tock_tbf::types::TbfHeaderV2Credentials;
 
 /// Generic trait for implementing a policy on what to do when a process faults.
 ///
@@ -105,3 +107,214 @@ impl ProcessFaultPolicy for ThresholdRestartThenPanicFaultPolicy {
         }
     }
 }
+
+/// Generic trait for implementing a policy on which `ShortID` each process
+/// gets.
+///
+/// The kernel applies the policy once, as each process is loaded. Capsules
+/// rely on `ShortID`s to decide what an application may access, such as its
+/// stored data, so a policy should only give out a `ShortID` it can tie to
+/// the application.
+pub trait AppIdPolicy {
+    /// Decide the `ShortID` of `process`, or `None` to give it none.
+    fn short_id(&self, process: &dyn Process) -> Option<ShortID>;
+}
+
+/// Derive the `ShortID` of each process from its package name.
+///
+/// This is the policy the kernel uses if the board sets none. Any process can
+/// claim any name, so it offers no protection against spoofing.
+pub struct PackageNameAppIdPolicy {}
+
+impl AppIdPolicy for PackageNameAppIdPolicy {
+    fn short_id(&self, process: &dyn Process) -> Option<ShortID> {
+        Some(ShortID::from_name(process.get_process_name()))
+    }
+}
+
+/// Checks the signatures of applications for `SigningKeyAppIdPolicy`.
+///
+/// Boards implement this with whichever signature scheme they support, in
+/// hardware or in software.
+pub trait SignatureVerifier {
+    /// Return whether `signature` is a valid signature, in the credentials
+    /// format `format`, of the concatenation of `message` under `public_key`.
+    ///
+    /// Verifiers must return `false` for formats they do not support.
+    fn verify(&self, format: u32, public_key: &[u8], message: &[&[u8]], signature: &[u8]) -> bool;
+}
+
+/// Derive the `ShortID` of each process from the key it is signed with and
+/// its package name.
+///
+/// A process gets a `ShortID` only if its TBF header has credentials, the
+/// public key in them is one of `trusted_keys`, and the signature of its TBF
+/// header and binary is valid. The signature covers the whole header, package
+/// name and permissions included, except for the header checksum, which is
+/// replaced by zeros, and the credentials TLV itself. Updates of an application keep its
+/// `ShortID` as long as they are signed with the same key, and no process can
+/// take the `ShortID` of another without a trusted key.
+///
+/// Processes without credentials get a `ShortID` from their package name if
+/// `allow_unsigned` is set, and none otherwise. Processes with credentials
+/// that do not check out get none.
+pub struct SigningKeyAppIdPolicy<'a> {
+    verifier: &'a dyn SignatureVerifier,
+    trusted_keys: &'a [&'a [u8]],
+    allow_unsigned: bool,
+}
+
+impl<'a> SigningKeyAppIdPolicy<'a> {
+    pub const fn new(
+        verifier: &'a dyn SignatureVerifier,
+        trusted_keys: &'a [&'a [u8]],
+        allow_unsigned: bool,
+    ) -> SigningKeyAppIdPolicy<'a> {
+        SigningKeyAppIdPolicy {
+            verifier,
+            trusted_keys,
+            allow_unsigned,
+        }
+    }
+}
+
+/// Where the checksum is in the TBF header.
+const CHECKSUM_OFFSET: usize = 12;
+
+/// The parts of an app that its credentials sign, in order: the TBF header
+/// up to the checksum, zeros for the checksum, the rest of the header around
+/// the credentials TLV, and the binary. Returns `None` if the credentials are
+/// not in the header after the checksum.
+fn signed_message<'a>(
+    header: &'a [u8],
+    credentials: &TbfHeaderV2Credentials,
+    binary: &'a [u8],
+) -> Option<[&'a [u8]; 5]> {
+    let (start, end) = credentials.tlv_range();
+    let end = end.min(header.len());
+    if start < CHECKSUM_OFFSET + 4 || start > end {
+        return None;
+    }
+    Some([
+        &header[..CHECKSUM_OFFSET],
+        &[0; 4],
+        &header[CHECKSUM_OFFSET + 4..start],
+        &header[end..],
+        binary,
+    ])
+}
+
+impl AppIdPolicy for SigningKeyAppIdPolicy<'_> {
+    fn short_id(&self, process: &dyn Process) -> Option<ShortID> {
+        let name = process.get_process_name();
+        let credentials = match process.get_credentials() {
+            Some(credentials) => credentials,
+            None if self.allow_unsigned => return Some(ShortID::from_name(name)),
+            None => return None,
+        };
+
+        let public_key = credentials.public_key();
+        if !self.trusted_keys.iter().any(|key| *key == public_key) {
+            return None;
+        }
+        let message = match signed_message(
+            process.get_tbf_header(),
+            &credentials,
+            process.get_app_binary(),
+        ) {
+            Some(message) => message,
+            None => return None,
+        };
+        if self.verifier.verify(
+            credentials.format(),
+            public_key,
+            &message,
+            credentials.signature(),
+        ) {
+            Some(ShortID::from_signing_key(public_key, name))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::signed_message;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// Build a TBF header with a package name, credentials, and a
+    /// permissions TLV that allows the commands in `mask`, with the right
+    /// checksum.
+    fn header(mask: u64) -> &'static [u8] {
+        let mut header = Vec::new();
+        header.extend(&2u16.to_le_bytes());
+        header.extend(&0u16.to_le_bytes()); // header size, set below
+        header.extend(&0x400u32.to_le_bytes());
+        header.extend(&1u32.to_le_bytes());
+        header.extend(&0u32.to_le_bytes()); // checksum, set below
+                                            // Package name
+        header.extend(&3u16.to_le_bytes());
+        header.extend(&4u16.to_le_bytes());
+        header.extend(b"test");
+        // Credentials: format, a 2-byte key and a 3-byte signature, padded
+        header.extend(&7u16.to_le_bytes());
+        header.extend(&13u16.to_le_bytes());
+        header.extend(&1u32.to_le_bytes());
+        header.extend(&2u16.to_le_bytes());
+        header.extend(&3u16.to_le_bytes());
+        header.extend(&[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0, 0, 0]);
+        // Permissions: one entry
+        header.extend(&6u16.to_le_bytes());
+        header.extend(&18u16.to_le_bytes());
+        header.extend(&1u16.to_le_bytes());
+        header.extend(&0x90000u32.to_le_bytes());
+        header.extend(&0u32.to_le_bytes());
+        header.extend(&mask.to_le_bytes());
+        header.extend(&[0, 0]);
+        let len = header.len() as u16;
+        header[2..4].copy_from_slice(&len.to_le_bytes());
+        let checksum = header.chunks_exact(4).fold(0, |sum, word| {
+            sum ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]])
+        });
+        header[12..16].copy_from_slice(&checksum.to_le_bytes());
+        Box::leak(header.into_boxed_slice())
+    }
+
+    fn parse(header: &'static [u8]) -> tock_tbf::types::TbfHeader {
+        tock_tbf::parse::parse_tbf_header(header, 2).unwrap()
+    }
+
+    #[test]
+    fn test_signed_message() {
+        let header = header(1);
+        let credentials = parse(header).get_credentials().unwrap();
+        assert_eq!(credentials.public_key(), [0xaa, 0xbb]);
+        assert_eq!(credentials.tlv_range(), (24, 44));
+
+        let binary = [1, 2, 3];
+        let message: Vec<u8> = signed_message(header, &credentials, &binary)
+            .unwrap()
+            .concat();
+        let mut expected = header[..12].to_vec();
+        expected.extend(&[0; 4]);
+        expected.extend(&header[16..24]);
+        expected.extend(&header[44..]);
+        expected.extend(&binary);
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn test_signed_message_covers_permissions() {
+        let signed = |header: &'static [u8]| -> Vec<u8> {
+            let credentials = parse(header).get_credentials().unwrap();
+            signed_message(header, &credentials, &[]).unwrap().concat()
+        };
+        // Granting more commands changes what is signed, even with the
+        // checksum fixed up.
+        assert_ne!(signed(header(1)), signed(header(u64::MAX)));
+    }
+}
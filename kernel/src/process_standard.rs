@@ -17,6 +17,8 @@ use crate::errorcode::ErrorCode;
 use crate::mem::{ReadOnlyAppSlice, ReadWriteAppSlice};
 use crate::platform::mpu::{self, MPU};
 use crate::platform::Chip;
+use crate::process::ProcessEvent;
+use crate::process::ShortID;
 use crate::process::{Error, FunctionCall, FunctionCallSource, Process, State, Task};
 use crate::process::{FaultAction, ProcessCustomGrantIdentifer, ProcessId, ProcessStateCell};
 use crate::process_policies::ProcessFaultPolicy;
@@ -24,6 +26,7 @@ use crate::process_utilities::ProcessLoadError;
 use crate::sched::Kernel;
 use crate::syscall::{self, Syscall, SyscallReturn, UserspaceKernelBoundary};
 use crate::upcall::UpcallId;
+use tock_tbf::types::{CommandPermissions, TbfHeaderV2Credentials};
 
 // The completion code for a process if it faulted.
 const COMPLETION_FAULT: u32 = 0xffffffff;
@@ -67,6 +70,17 @@ struct ProcessStandardDebug {
     /// How many times this process has been paused because it exceeded its
     /// timeslice.
     timeslice_expiration_count: usize,
+
+    /// How many microseconds this process has executed for.
+    cpu_time_us: u64,
+
+    /// The return addresses found on the stack when the process last
+    /// faulted, innermost first. Only captured if enabled in the kernel
+    /// configuration.
+    stack_trace: [usize; config::CONFIG.stack_trace_depth],
+
+    /// How many entries of `stack_trace` are valid.
+    stack_trace_length: usize,
 }
 
 /// A type for userspace processes in Tock.
@@ -161,7 +175,7 @@ pub struct ProcessStandard<'a, C: 'static + Chip> {
     state: ProcessStateCell<'static>,
 
     /// How to respond if this process faults.
-    fault_policy: &'a dyn ProcessFaultPolicy,
+    fault_policy: Cell<&'a dyn ProcessFaultPolicy>,
 
     /// Configuration data for the MPU
     mpu_config: MapCell<<<C as Chip>::MPU as MPU>::MpuConfig>,
@@ -181,6 +195,16 @@ pub struct ProcessStandard<'a, C: 'static + Chip> {
     /// Name of the app.
     process_name: &'static str,
 
+    /// Identifier the `AppIdPolicy` assigned to the app when it was loaded.
+    short_id: Option<ShortID>,
+
+    /// Whether a debugger is attached, and so faults stop the process
+    /// instead of applying the fault policy.
+    debugger_attached: Cell<bool>,
+
+    /// Whether a fault stopped the process while a debugger was attached.
+    debug_faulted: Cell<bool>,
+
     /// Values kept so that we can print useful debug messages when apps fault.
     debug: MapCell<ProcessStandardDebug>,
 }
@@ -273,12 +297,29 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             State::StoppedYielded => self.state.update(State::Yielded),
             _ => {} // Do nothing
         }
+        self.debug_faulted.set(false);
     }
 
     fn set_fault_state(&self) {
+        // Capture the stack trace before the fault policy gets a chance to
+        // restart the process and reuse its stack.
+        if config::CONFIG.stack_trace_depth > 0 {
+            self.capture_stack_trace();
+        }
+
+        // Leave the process as it faulted for the debugger, which may resume
+        // it once it has removed the cause, such as a breakpoint.
+        if self.debugger_attached.get() {
+            self.debug_faulted.set(true);
+            self.state.update(State::StoppedRunning);
+            return;
+        }
+
         // Use the per-process fault policy to determine what action the kernel
         // should take since the process faulted.
-        let action = self.fault_policy.action(self);
+        let action = self.fault_policy.get().action(self);
+        self.kernel
+            .process_event(self.processid(), ProcessEvent::Faulted(action));
 
         match action {
             FaultAction::Panic => {
@@ -377,6 +418,10 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         self.kernel_memory_break.get()
     }
 
+    fn app_memory_break(&self) -> *const u8 {
+        self.app_break.get()
+    }
+
     fn number_writeable_flash_regions(&self) -> usize {
         self.header.number_writeable_flash_regions()
     }
@@ -819,6 +864,101 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         self.process_name
     }
 
+    fn get_short_id(&self) -> Option<ShortID> {
+        self.short_id
+    }
+
+    fn get_credentials(&self) -> Option<TbfHeaderV2Credentials> {
+        self.header.get_credentials()
+    }
+
+    fn get_tbf_header(&self) -> &'static [u8] {
+        let header_size = self.header.get_header_size() as usize;
+        self.flash.get(..header_size).unwrap_or(&[])
+    }
+
+    fn get_app_binary(&self) -> &'static [u8] {
+        let protected_size = self.header.get_protected_size() as usize;
+        self.flash.get(protected_size..).unwrap_or(&[])
+    }
+
+    fn get_command_permissions(&self, driver_num: usize, offset: usize) -> CommandPermissions {
+        self.header.get_command_permissions(driver_num, offset)
+    }
+
+    fn set_debugger_attached(&self, attached: bool) {
+        self.debugger_attached.set(attached);
+    }
+
+    fn debug_faulted(&self) -> bool {
+        self.debug_faulted.get()
+    }
+
+    fn debug_read_memory(&self, address: usize, buffer: &mut [u8]) -> Result<(), ErrorCode> {
+        let end = address.checked_add(buffer.len()).ok_or(ErrorCode::INVAL)?;
+        let in_flash = address >= self.flash_start() as usize && end <= self.flash_end() as usize;
+        let in_ram = address >= self.mem_start() as usize && end <= self.app_break.get() as usize;
+        if !in_flash && !in_ram {
+            return Err(ErrorCode::INVAL);
+        }
+        // The range is within memory owned by the process, checked above.
+        unsafe {
+            buffer.copy_from_slice(slice::from_raw_parts(address as *const u8, buffer.len()));
+        }
+        Ok(())
+    }
+
+    fn debug_write_memory(&self, address: usize, data: &[u8]) -> Result<(), ErrorCode> {
+        let end = address.checked_add(data.len()).ok_or(ErrorCode::INVAL)?;
+        if address < self.mem_start() as usize || end > self.app_break.get() as usize {
+            return Err(ErrorCode::INVAL);
+        }
+        // The range is within RAM owned by the process, checked above.
+        unsafe {
+            slice::from_raw_parts_mut(address as *mut u8, data.len()).copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn debug_read_register(&self, index: usize) -> Option<usize> {
+        self.stored_state.map_or(None, |stored_state| {
+            // We guarantee the memory bounds pointers provided to the UKB are
+            // correct.
+            unsafe {
+                self.chip.userspace_kernel_boundary().debug_register(
+                    self.mem_start(),
+                    self.app_break.get(),
+                    stored_state,
+                    index,
+                )
+            }
+        })
+    }
+
+    fn debug_write_register(&self, index: usize, value: usize) -> Result<(), ErrorCode> {
+        self.stored_state
+            .map_or(Err(ErrorCode::FAIL), |stored_state| {
+                // We guarantee the memory bounds pointers provided to the UKB
+                // are correct.
+                unsafe {
+                    self.chip
+                        .userspace_kernel_boundary()
+                        .set_debug_register(
+                            self.mem_start(),
+                            self.app_break.get(),
+                            stored_state,
+                            index,
+                            value,
+                        )
+                        .map_err(|()| ErrorCode::INVAL)
+                }
+            })
+    }
+
+    fn set_fault_policy(&self, fault_policy: &'static dyn ProcessFaultPolicy) {
+        self.fault_policy.set(fault_policy);
+    }
+
     fn set_syscall_return_value(&self, return_value: SyscallReturn) {
         match self.stored_state.map(|stored_state| unsafe {
             // Actually set the return value for a particular process.
@@ -959,6 +1099,14 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             .map(|debug| debug.timeslice_expiration_count += 1);
     }
 
+    fn debug_cpu_time_us(&self) -> u64 {
+        self.debug.map_or(0, |debug| debug.cpu_time_us)
+    }
+
+    fn debug_cpu_time_used(&self, time_us: u32) {
+        self.debug.map(|debug| debug.cpu_time_us += time_us as u64);
+    }
+
     fn debug_syscall_called(&self, last_syscall: Syscall) {
         self.debug.map(|debug| {
             debug.syscall_count += 1;
@@ -1141,6 +1289,18 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             }
         });
 
+        self.debug.map(|debug| {
+            if debug.stack_trace_length > 0 {
+                let _ = writer.write_fmt(format_args!("\r\n Stack trace at last fault:\r\n"));
+                for (depth, address) in debug.stack_trace[..debug.stack_trace_length]
+                    .iter()
+                    .enumerate()
+                {
+                    let _ = writer.write_fmt(format_args!("  #{:<2} {:#010X}\r\n", depth, address));
+                }
+            }
+        });
+
         // Display grant information.
         let number_grants = self.kernel.get_grant_count_and_finalize();
         let _ = writer.write_fmt(format_args!(
@@ -1554,7 +1714,7 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         process.stored_state = MapCell::new(Default::default());
         // Mark this process as unstarted
         process.state = ProcessStateCell::new(process.kernel);
-        process.fault_policy = fault_policy;
+        process.fault_policy = Cell::new(fault_policy);
         process.restart_count = Cell::new(0);
 
         process.mpu_config = MapCell::new(mpu_config);
@@ -1568,6 +1728,9 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         ];
         process.tasks = MapCell::new(tasks);
         process.process_name = process_name.unwrap_or("");
+        process.short_id = None;
+        process.debugger_attached = Cell::new(false);
+        process.debug_faulted = Cell::new(false);
 
         process.debug = MapCell::new(ProcessStandardDebug {
             fixed_address_flash: fixed_address_flash,
@@ -1579,6 +1742,9 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
             last_syscall: None,
             dropped_upcall_count: 0,
             timeslice_expiration_count: 0,
+            cpu_time_us: 0,
+            stack_trace: [0; config::CONFIG.stack_trace_depth],
+            stack_trace_length: 0,
         });
 
         let flash_protected_size = process.header.get_protected_size() as usize;
@@ -1624,6 +1790,14 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
             }
         };
 
+        // The policy may look at any part of the process, so assign the
+        // identifier once everything else is set up.
+        let short_id = kernel.assign_short_id(process);
+        if short_id.is_none() && config::CONFIG.debug_load_processes {
+            debug!("[!] process={:?} - no ShortID assigned", process_name);
+        }
+        process.short_id = short_id;
+
         kernel.increment_work();
 
         // Return the process object and a remaining memory for processes slice.
@@ -1651,6 +1825,7 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
             debug.last_syscall = None;
             debug.dropped_upcall_count = 0;
             debug.timeslice_expiration_count = 0;
+            debug.cpu_time_us = 0;
         });
 
         // FLASH
@@ -1790,6 +1965,25 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         Ok(())
     }
 
+    /// Walk the stack of the process and keep the return addresses found, so
+    /// they can be shown when the process is printed.
+    fn capture_stack_trace(&self) {
+        self.stored_state.map(|stored_state| {
+            self.debug.map(|debug| {
+                // We guarantee the memory bounds pointers provided to the UKB
+                // are correct.
+                debug.stack_trace_length = unsafe {
+                    self.chip.userspace_kernel_boundary().stack_trace(
+                        self.mem_start(),
+                        self.app_break.get(),
+                        stored_state,
+                        &mut debug.stack_trace,
+                    )
+                };
+            });
+        });
+    }
+
     /// Checks if the buffer represented by the passed in base pointer and size
     /// is within the RAM bounds currently exposed to the processes (i.e.
     /// ending at `app_break`). If this method returns `true`, the buffer
@@ -0,0 +1,401 @@
+//! Recording and deterministic replay of the events that drive capsules.
+//!
+//! Capsules only run in response to interrupts, deferred calls and system
+//! calls, so a bug that depends on the order these arrive in can be
+//! reproduced by feeding a capsule the same events in the same order. This
+//! module provides both halves:
+//!
+//! - Recording: a `TraceRecorder` is told of every interrupt and deferred
+//!   call the chip services (by wrapping the chip's `InterruptService` in a
+//!   `RecordingInterruptService`) and of every system call a process makes
+//!   (through `Kernel::set_trace_recorder`). `TraceBuffer` keeps the most
+//!   recent events in RAM and prints them in a text format, typically from
+//!   the panic handler.
+//!
+//! - Replay: `tools/trace_replay.py` turns that text into a Rust array of
+//!   `TraceEvent`s, and a `Replayer` re-injects them into the capsules of a
+//!   board running in a simulator (see `tools/run_sim`). Interrupts and
+//!   deferred calls are passed to the board's `InterruptService` and
+//!   commands to the driver through `Platform::with_driver`. The replayer
+//!   stops at the first command whose return variant differs from the
+//!   recorded one, as from there on the capsule no longer follows the
+//!   recorded run.
+//!
+//! Only commands are replayed. The arguments of allow and subscribe calls
+//! point into process memory that does not exist on the replaying board, so
+//! those (and the other system calls) are recorded for context only.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use kernel::static_init;
+//! use kernel::replay::{RecordingInterruptService, TraceBuffer, TraceEvent};
+//!
+//! static mut TRACE_EVENTS: [Option<TraceEvent>; 256] = [None; 256];
+//!
+//! let trace = static_init!(TraceBuffer, TraceBuffer::new(&mut TRACE_EVENTS));
+//! let interrupt_service = static_init!(
+//!     RecordingInterruptService<'static, EarlGreyDefaultPeripherals>,
+//!     RecordingInterruptService::new(peripherals, trace)
+//! );
+//! board_kernel.set_trace_recorder(trace, &process_mgmt_cap);
+//! ```
+
+use core::cell::Cell;
+use core::fmt::Write;
+use core::marker::PhantomData;
+
+use crate::capabilities;
+use crate::common::cells::{NumericCellExt, TakeCell};
+use crate::common::dynamic_deferred_call::DynamicDeferredCall;
+use crate::driver::CommandReturn;
+use crate::errorcode::ErrorCode;
+use crate::platform::{InterruptService, Platform};
+use crate::process::ProcessId;
+use crate::sched::Kernel;
+use crate::syscall::{Syscall, SyscallClass, SyscallReturn};
+
+/// Prefix of every line of a printed trace.
+pub const TRACE_MARKER: &str = "TOCK-TRACE:";
+
+/// One event that drove the kernel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TraceEvent {
+    /// The chip serviced interrupt `number`.
+    Interrupt(u32),
+    /// The chip serviced the deferred call task with this number.
+    DeferredCall(usize),
+    /// A process issued a command. `result` is the `SyscallReturnVariant`
+    /// the driver returned.
+    Command {
+        process: usize,
+        driver: usize,
+        subdriver: usize,
+        arg0: usize,
+        arg1: usize,
+        result: u32,
+    },
+    /// A process issued a system call other than a command.
+    Syscall { process: usize, class: SyscallClass },
+}
+
+/// Receives the events the kernel handles, in the order it handles them.
+pub trait TraceRecorder {
+    fn record(&self, event: TraceEvent);
+}
+
+/// Deferred call task types that can be recorded and replayed.
+///
+/// Chips with deferred call tasks implement this for their task enum.
+pub trait TraceTask: Copy {
+    fn to_trace(self) -> usize;
+    fn from_trace(task: usize) -> Option<Self>;
+}
+
+impl TraceTask for () {
+    fn to_trace(self) -> usize {
+        0
+    }
+
+    fn from_trace(task: usize) -> Option<()> {
+        if task == 0 {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+/// Build the event for a system call that is not a command.
+pub(crate) fn syscall_event(process: ProcessId, syscall: &Syscall) -> TraceEvent {
+    let class = match *syscall {
+        Syscall::Yield { .. } => SyscallClass::Yield,
+        Syscall::Subscribe { .. } => SyscallClass::Subscribe,
+        Syscall::Command { .. } => SyscallClass::Command,
+        Syscall::ReadWriteAllow { .. } => SyscallClass::ReadWriteAllow,
+        Syscall::ReadOnlyAllow { .. } => SyscallClass::ReadOnlyAllow,
+        Syscall::Memop { .. } => SyscallClass::Memop,
+        Syscall::Exit { .. } => SyscallClass::Exit,
+    };
+    TraceEvent::Syscall {
+        process: process.id(),
+        class,
+    }
+}
+
+/// The `SyscallReturnVariant` of a command's return value.
+fn result_variant(result: &SyscallReturn) -> u32 {
+    let (mut a0, mut a1, mut a2, mut a3) = (0, 0, 0, 0);
+    result.encode_syscall_return(&mut a0, &mut a1, &mut a2, &mut a3);
+    a0
+}
+
+/// Build the event for a command and the value the driver returned.
+pub(crate) fn command_event(
+    process: ProcessId,
+    driver: usize,
+    subdriver: usize,
+    arg0: usize,
+    arg1: usize,
+    result: &SyscallReturn,
+) -> TraceEvent {
+    TraceEvent::Command {
+        process: process.id(),
+        driver,
+        subdriver,
+        arg0,
+        arg1,
+        result: result_variant(result),
+    }
+}
+
+/// Keeps the most recent events in a ring buffer.
+pub struct TraceBuffer {
+    events: TakeCell<'static, [Option<TraceEvent>]>,
+    /// Index the next event is stored at.
+    next: Cell<usize>,
+    /// Number of events recorded since the buffer was last cleared.
+    recorded: Cell<usize>,
+    recording: Cell<bool>,
+}
+
+impl TraceBuffer {
+    pub fn new(events: &'static mut [Option<TraceEvent>]) -> TraceBuffer {
+        TraceBuffer {
+            events: TakeCell::new(events),
+            next: Cell::new(0),
+            recorded: Cell::new(0),
+            recording: Cell::new(true),
+        }
+    }
+
+    /// Stop recording, e.g. so the events of interest are not overwritten by
+    /// the ones printing them causes.
+    pub fn stop(&self) {
+        self.recording.set(false);
+    }
+
+    /// Resume recording.
+    pub fn start(&self) {
+        self.recording.set(true);
+    }
+
+    /// Forget all recorded events.
+    pub fn clear(&self) {
+        self.events.map(|events| {
+            for event in events.iter_mut() {
+                *event = None;
+            }
+        });
+        self.next.set(0);
+        self.recorded.set(0);
+    }
+
+    /// Stop recording and print the recorded events to `writer`, oldest
+    /// first, in the format `tools/trace_replay.py` reads.
+    ///
+    /// `writer` should write synchronously, like the panic writer, as
+    /// asynchronous output would be interleaved with the events it causes.
+    pub fn dump(&self, writer: &mut dyn Write) {
+        self.stop();
+        self.events.map(|events| {
+            let dropped = self.recorded.get().saturating_sub(events.len());
+            let _ = writer.write_fmt(format_args!(
+                "\r\n{} begin {} {}\r\n",
+                TRACE_MARKER,
+                self.recorded.get(),
+                dropped
+            ));
+            let (newer, older) = events.split_at(self.next.get());
+            for event in older.iter().chain(newer.iter()).filter_map(|e| *e) {
+                let _ = match event {
+                    TraceEvent::Interrupt(number) => {
+                        writer.write_fmt(format_args!("{} irq {}\r\n", TRACE_MARKER, number))
+                    }
+                    TraceEvent::DeferredCall(task) => {
+                        writer.write_fmt(format_args!("{} deferred {}\r\n", TRACE_MARKER, task))
+                    }
+                    TraceEvent::Command {
+                        process,
+                        driver,
+                        subdriver,
+                        arg0,
+                        arg1,
+                        result,
+                    } => writer.write_fmt(format_args!(
+                        "{} cmd {} {:#x} {} {:#x} {:#x} {}\r\n",
+                        TRACE_MARKER, process, driver, subdriver, arg0, arg1, result
+                    )),
+                    TraceEvent::Syscall { process, class } => writer.write_fmt(format_args!(
+                        "{} syscall {} {}\r\n",
+                        TRACE_MARKER, process, class as u8
+                    )),
+                };
+            }
+            let _ = writer.write_fmt(format_args!("{} end\r\n", TRACE_MARKER));
+        });
+    }
+}
+
+impl TraceRecorder for TraceBuffer {
+    fn record(&self, event: TraceEvent) {
+        if !self.recording.get() {
+            return;
+        }
+        self.events.map(|events| {
+            if events.is_empty() {
+                return;
+            }
+            events[self.next.get()] = Some(event);
+            self.next.set((self.next.get() + 1) % events.len());
+            self.recorded.increment();
+        });
+    }
+}
+
+/// Wraps the `InterruptService` of a chip to record every interrupt and
+/// deferred call it services.
+pub struct RecordingInterruptService<'a, I> {
+    service: &'a I,
+    recorder: &'a dyn TraceRecorder,
+}
+
+impl<'a, I> RecordingInterruptService<'a, I> {
+    pub fn new(service: &'a I, recorder: &'a dyn TraceRecorder) -> Self {
+        RecordingInterruptService { service, recorder }
+    }
+}
+
+impl<'a, T: TraceTask, I: InterruptService<T>> InterruptService<T>
+    for RecordingInterruptService<'a, I>
+{
+    unsafe fn service_interrupt(&self, interrupt: u32) -> bool {
+        self.recorder.record(TraceEvent::Interrupt(interrupt));
+        self.service.service_interrupt(interrupt)
+    }
+
+    unsafe fn service_deferred_call(&self, task: T) -> bool {
+        self.recorder
+            .record(TraceEvent::DeferredCall(task.to_trace()));
+        self.service.service_deferred_call(task)
+    }
+}
+
+/// Why a replay stopped before the end of the trace.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReplayError {
+    /// The board could not service the interrupt or deferred call at
+    /// `index`.
+    Unhandled { index: usize },
+    /// The command at `index` returned the variant `actual` instead of the
+    /// recorded `expected`.
+    Diverged {
+        index: usize,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+/// Re-injects a recorded trace into the capsules of a board.
+pub struct Replayer<'a, I, T> {
+    kernel: &'static Kernel,
+    trace: &'a [TraceEvent],
+    interrupt_service: &'a I,
+    next: Cell<usize>,
+    _task: PhantomData<T>,
+}
+
+impl<'a, T: TraceTask, I: InterruptService<T>> Replayer<'a, I, T> {
+    /// Replaying hands commands to drivers on behalf of processes, so it is
+    /// restricted with a capability.
+    pub fn new(
+        kernel: &'static Kernel,
+        trace: &'a [TraceEvent],
+        interrupt_service: &'a I,
+        _capability: &dyn capabilities::ExternalProcessCapability,
+    ) -> Self {
+        Replayer {
+            kernel,
+            trace,
+            interrupt_service,
+            next: Cell::new(0),
+            _task: PhantomData,
+        }
+    }
+
+    /// Index of the next event to replay.
+    pub fn position(&self) -> usize {
+        self.next.get()
+    }
+
+    /// Replay the next event and run the deferred calls it caused. Returns
+    /// the event, or `None` at the end of the trace.
+    ///
+    /// Commands from a process that is not loaded on the replaying board
+    /// are issued with a `ProcessId` that refers to no process, so drivers
+    /// see them as coming from a process that has exited.
+    pub unsafe fn step<P: Platform>(
+        &self,
+        platform: &P,
+    ) -> Result<Option<TraceEvent>, ReplayError> {
+        let index = self.next.get();
+        let event = match self.trace.get(index) {
+            Some(event) => *event,
+            None => return Ok(None),
+        };
+        self.next.set(index + 1);
+
+        match event {
+            TraceEvent::Interrupt(number) => {
+                if !self.interrupt_service.service_interrupt(number) {
+                    return Err(ReplayError::Unhandled { index });
+                }
+            }
+            TraceEvent::DeferredCall(task) => {
+                let handled = T::from_trace(task).map_or(false, |task| {
+                    self.interrupt_service.service_deferred_call(task)
+                });
+                if !handled {
+                    return Err(ReplayError::Unhandled { index });
+                }
+            }
+            TraceEvent::Command {
+                process,
+                driver,
+                subdriver,
+                arg0,
+                arg1,
+                result,
+            } => {
+                let processid = self
+                    .kernel
+                    .lookup_app_by_identifier(process)
+                    .unwrap_or_else(|| ProcessId::new(self.kernel, process, usize::MAX));
+                let actual = result_variant(&SyscallReturn::from_command_return(
+                    platform.with_driver(driver, |d| match d {
+                        Some(d) => d.command(subdriver, arg0, arg1, processid),
+                        None => CommandReturn::failure(ErrorCode::NODEVICE),
+                    }),
+                ));
+                if actual != result {
+                    return Err(ReplayError::Diverged {
+                        index,
+                        expected: result,
+                        actual,
+                    });
+                }
+            }
+            TraceEvent::Syscall { .. } => {}
+        }
+
+        DynamicDeferredCall::call_global_instance();
+        Ok(Some(event))
+    }
+
+    /// Replay the rest of the trace.
+    pub unsafe fn replay<P: Platform>(&self, platform: &P) -> Result<(), ReplayError> {
+        while self.step(platform)?.is_some() {}
+        Ok(())
+    }
+}
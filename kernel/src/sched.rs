@@ -14,24 +14,28 @@ use core::cell::Cell;
 use core::ptr::NonNull;
 
 use crate::capabilities;
-use crate::common::cells::NumericCellExt;
+use crate::common::cells::{NumericCellExt, OptionalCell};
 use crate::common::dynamic_deferred_call::DynamicDeferredCall;
 use crate::config;
 use crate::debug;
 use crate::driver::CommandReturn;
 use crate::errorcode::ErrorCode;
-use crate::grant::Grant;
+use crate::grant::{Grant, GrantFailures};
+use crate::hil::time;
 use crate::ipc;
 use crate::memop;
 use crate::platform::mpu::MPU;
 use crate::platform::scheduler_timer::SchedulerTimer;
 use crate::platform::watchdog::WatchDog;
 use crate::platform::{Chip, Platform};
-use crate::process::ProcessId;
 use crate::process::{self, Task};
+use crate::process::{ProcessId, ShortID};
+use crate::process_policies::{AppIdPolicy, PackageNameAppIdPolicy};
+use crate::replay;
 use crate::syscall::{ContextSwitchReason, SyscallReturn};
 use crate::syscall::{Syscall, YieldCall};
 use crate::upcall::{Upcall, UpcallId};
+use tock_tbf::types::CommandPermissions;
 
 /// Threshold in microseconds to consider a process's timeslice to be exhausted.
 /// That is, Tock will skip re-scheduling a process if its remaining timeslice
@@ -146,8 +150,38 @@ pub struct Kernel {
     /// created and the data structures for grants have already been
     /// established.
     grants_finalized: Cell<bool>,
+
+    /// Clock used to timestamp events, if the board provided one.
+    event_clock: OptionalCell<&'static dyn time::EventClock>,
+
+    /// Tick count of `event_clock` when the kernel last started handling
+    /// interrupts.
+    event_ticks: Cell<u32>,
+
+    /// Client notified when processes start, exit or fault.
+    process_events_client: OptionalCell<&'static dyn process::ProcessEventsClient>,
+
+    /// Policy assigning `ShortID`s to processes as they are loaded.
+    app_id_policy: OptionalCell<&'static dyn AppIdPolicy>,
+
+    /// Recorder told of every system call, for deterministic replay.
+    trace_recorder: OptionalCell<&'static dyn replay::TraceRecorder>,
+
+    /// The driver handling the system call in progress, if any.
+    syscall_driver: OptionalCell<usize>,
+
+    /// How often entering a grant failed, for each of the first
+    /// `COUNTED_DRIVERS` drivers with failures. A driver number of `None`
+    /// counts failures outside system calls, such as in interrupt handlers.
+    grant_failures: [Cell<Option<(Option<usize>, GrantFailures)>>; COUNTED_DRIVERS],
 }
 
+/// Number of drivers whose failures to enter grants are counted for
+/// introspection. Failures of drivers beyond these are not counted.
+const COUNTED_DRIVERS: usize = 8;
+
+const NO_GRANT_FAILURES: Cell<Option<(Option<usize>, GrantFailures)>> = Cell::new(None);
+
 /// Enum used to inform scheduler why a process stopped executing (aka why
 /// `do_process()` returned).
 #[derive(PartialEq, Eq)]
@@ -181,9 +215,89 @@ impl Kernel {
             process_identifier_max: Cell::new(0),
             grant_counter: Cell::new(0),
             grants_finalized: Cell::new(false),
+            event_clock: OptionalCell::empty(),
+            event_ticks: Cell::new(0),
+            process_events_client: OptionalCell::empty(),
+            app_id_policy: OptionalCell::empty(),
+            trace_recorder: OptionalCell::empty(),
+            syscall_driver: OptionalCell::empty(),
+            grant_failures: [NO_GRANT_FAILURES; COUNTED_DRIVERS],
         }
     }
 
+    /// Set the client notified when processes start, exit or fault.
+    ///
+    /// This reveals what other processes are doing, so it is restricted with
+    /// a capability.
+    pub fn set_process_events_client(
+        &self,
+        client: &'static dyn process::ProcessEventsClient,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.process_events_client.set(client);
+    }
+
+    /// Report a lifecycle event of `process` to the process events client.
+    pub(crate) fn process_event(&self, process: ProcessId, event: process::ProcessEvent) {
+        self.process_events_client
+            .map(|client| client.process_event(process, event));
+    }
+
+    /// Set the recorder told of every system call processes make.
+    ///
+    /// This reveals what other processes are doing, so it is restricted with
+    /// a capability. See `kernel::replay`.
+    pub fn set_trace_recorder(
+        &self,
+        recorder: &'static dyn replay::TraceRecorder,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.trace_recorder.set(recorder);
+    }
+
+    /// Set the policy that assigns `ShortID`s to processes.
+    ///
+    /// The policy is applied as processes are loaded, so this must be called
+    /// before `load_processes()`. Without a policy, the `ShortID` of each
+    /// process is derived from its package name.
+    pub fn set_app_id_policy(
+        &self,
+        policy: &'static dyn AppIdPolicy,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.app_id_policy.set(policy);
+    }
+
+    /// Decide the `ShortID` of a process being loaded.
+    pub(crate) fn assign_short_id(&self, process: &dyn process::Process) -> Option<ShortID> {
+        self.app_id_policy.map_or_else(
+            || PackageNameAppIdPolicy {}.short_id(process),
+            |policy| policy.short_id(process),
+        )
+    }
+
+    /// Set the clock used to timestamp events for upcalls.
+    ///
+    /// Timestamping is opt-in: until a clock is set, `Upcall::event_timestamp`
+    /// returns `None`. Boards should use the same clock as the alarm driver so
+    /// that processes can compare event times with the time they read.
+    pub fn set_event_clock(&self, clock: &'static dyn time::EventClock) {
+        self.event_clock.set(clock);
+    }
+
+    /// Record the current time as the time of the events about to be
+    /// handled.
+    fn record_event_time(&self) {
+        self.event_clock
+            .map(|clock| self.event_ticks.set(clock.event_ticks()));
+    }
+
+    /// Tick count when the kernel started handling the interrupts that
+    /// caused the current event, or `None` if no event clock is set.
+    pub(crate) fn event_timestamp(&self) -> Option<u32> {
+        self.event_clock.map(|_| self.event_ticks.get())
+    }
+
     /// Something was scheduled for a process, so there is more work to do.
     ///
     /// This is only exposed in the core kernel crate.
@@ -345,6 +459,19 @@ impl Kernel {
         None
     }
 
+    /// Whether the TBF header of `appid` allows it to call command `cmd_num`
+    /// of driver `driver_num`. Privileged drivers use this to check whether a
+    /// process may use them, so a process whose header grants no permissions
+    /// for the driver is not allowed.
+    pub fn command_permitted(&self, appid: ProcessId, driver_num: usize, cmd_num: usize) -> bool {
+        self.process_map_or(false, appid, |process| {
+            match process.get_command_permissions(driver_num, cmd_num / 64) {
+                CommandPermissions::Mask(mask) => mask & (1 << (cmd_num % 64)) != 0,
+                _ => false,
+            }
+        })
+    }
+
     /// Retrieve the `ProcessId` of the given app based on its identifier. This is
     /// useful if an app identifier is passed to the kernel from somewhere (such
     /// as from userspace) and needs to be expanded to a full `ProcessId` for use
@@ -398,6 +525,32 @@ impl Kernel {
         Grant::new(self, grant_index)
     }
 
+    /// Update the grant failure counts of the driver handling the system call
+    /// in progress with `count`.
+    pub(crate) fn count_grant_failure<F>(&self, count: F)
+    where
+        F: FnOnce(&mut GrantFailures),
+    {
+        let driver_num = self.syscall_driver.extract();
+        let slot = self
+            .grant_failures
+            .iter()
+            .find(|slot| slot.get().map_or(true, |(driver, _)| driver == driver_num));
+        if let Some(slot) = slot {
+            let mut counts = slot
+                .get()
+                .map_or(GrantFailures::default(), |(_, counts)| counts);
+            count(&mut counts);
+            slot.set(Some((driver_num, counts)));
+        }
+    }
+
+    /// Returns the driver number and grant failure counts in slot `index`,
+    /// if a driver has failures counted there.
+    pub(crate) fn grant_failures(&self, index: usize) -> Option<(Option<usize>, GrantFailures)> {
+        self.grant_failures.get(index).and_then(|slot| slot.get())
+    }
+
     /// Returns the number of grants that have been setup in the system and
     /// marks the grants as "finalized". This means that no more grants can
     /// be created because data structures have been setup based on the number
@@ -478,6 +631,7 @@ impl Kernel {
                         // Execute kernel work. This includes handling
                         // interrupts and is how code in the chips/ and capsules
                         // crates is able to execute.
+                        self.record_event_time();
                         scheduler.execute_kernel_work(chip);
                     }
                     false => {
@@ -644,7 +798,9 @@ impl Kernel {
                             }
                         }
                         Some(ContextSwitchReason::SyscallFired { syscall }) => {
+                            self.syscall_driver.insert(syscall.driver_number());
                             self.handle_syscall(platform, process, syscall);
+                            self.syscall_driver.clear();
                         }
                         Some(ContextSwitchReason::Interrupted) => {
                             if scheduler_timer.get_remaining_us().is_none() {
@@ -675,6 +831,12 @@ impl Kernel {
                         None => break,
                         Some(cb) => match cb {
                             Task::FunctionCall(ccb) => {
+                                if process.get_state() == process::State::Unstarted {
+                                    self.process_event(
+                                        process.processid(),
+                                        process::ProcessEvent::Started,
+                                    );
+                                }
                                 if config::CONFIG.trace_syscalls {
                                     debug!(
                                         "[{:?}] function_call @{:#x}({:#x}, {:#x}, {:#x}, {:#x})",
@@ -728,6 +890,15 @@ impl Kernel {
             }
         }
 
+        // If the process exited, faulted or restarted, it no longer offers
+        // its IPC service.
+        match process.get_state() {
+            process::State::Faulted | process::State::Terminated | process::State::Unstarted => {
+                ipc.map(|ipc| ipc.remove_stopped_services());
+            }
+            _ => {}
+        }
+
         // Check how much time the process used while it was executing, and
         // return the value so we can provide it to the scheduler.
         let time_executed_us = timeslice_us.map_or(None, |timeslice| {
@@ -744,6 +915,8 @@ impl Kernel {
             }
         });
 
+        time_executed_us.map(|time_us| process.debug_cpu_time_used(time_us));
+
         // Reset the scheduler timer in case it unconditionally triggers
         // interrupts upon expiration. We do not want it to expire while the
         // chip is sleeping, for example.
@@ -767,6 +940,16 @@ impl Kernel {
         // Hook for process debugging.
         process.debug_syscall_called(syscall);
 
+        // Commands are recorded once the driver has returned.
+        match syscall {
+            Syscall::Command { .. } => {}
+            _ => {
+                self.trace_recorder.map(|recorder| {
+                    recorder.record(replay::syscall_event(process.processid(), &syscall))
+                });
+            }
+        }
+
         // Enforce platform-specific syscall filtering here.
         //
         // Before continuing to handle non-yield syscalls
@@ -925,6 +1108,17 @@ impl Kernel {
 
                 let res = SyscallReturn::from_command_return(cres);
 
+                self.trace_recorder.map(|recorder| {
+                    recorder.record(replay::command_event(
+                        process.processid(),
+                        driver_number,
+                        subdriver_number,
+                        arg0,
+                        arg1,
+                        &res,
+                    ))
+                });
+
                 if config::CONFIG.trace_syscalls {
                     debug!(
                         "[{:?}] cmd({:#x}, {}, {:#x}, {:#x}) = {:?}",
@@ -1090,9 +1284,27 @@ impl Kernel {
                 completion_code,
             } => match which {
                 // The process called the `exit-terminate` system call.
-                0 => process.terminate(completion_code as u32),
+                0 => {
+                    self.process_event(
+                        process.processid(),
+                        process::ProcessEvent::Exited {
+                            completion_code: completion_code as u32,
+                            restart: false,
+                        },
+                    );
+                    process.terminate(completion_code as u32)
+                }
                 // The process called the `exit-restart` system call.
-                1 => process.try_restart(completion_code as u32),
+                1 => {
+                    self.process_event(
+                        process.processid(),
+                        process::ProcessEvent::Exited {
+                            completion_code: completion_code as u32,
+                            restart: true,
+                        },
+                    );
+                    process.try_restart(completion_code as u32)
+                }
                 // The process called an invalid variant of the Exit
                 // system call class.
                 _ => process.set_syscall_return_value(SyscallReturn::Failure(ErrorCode::NOSUPPORT)),
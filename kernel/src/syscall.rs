@@ -28,7 +28,7 @@ fn u64_to_be_u32s(src: u64) -> (u32, u32) {
 /// These are encoded as 8 bit values as on some architectures the value can
 /// be encoded in the instruction itself.
 #[repr(u8)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum SyscallClass {
     Yield = 0,
     Subscribe = 1,
@@ -132,6 +132,18 @@ pub enum Syscall {
 }
 
 impl Syscall {
+    /// The driver a system call is addressed to, if it is addressed to a
+    /// driver rather than handled by the kernel.
+    pub fn driver_number(&self) -> Option<usize> {
+        match *self {
+            Syscall::Subscribe { driver_number, .. }
+            | Syscall::Command { driver_number, .. }
+            | Syscall::ReadWriteAllow { driver_number, .. }
+            | Syscall::ReadOnlyAllow { driver_number, .. } => Some(driver_number),
+            Syscall::Yield { .. } | Syscall::Memop { .. } | Syscall::Exit { .. } => None,
+        }
+    }
+
     /// Helper function for converting raw values passed back from an application
     /// into a `Syscall` type in Tock, representing an typed version of a system
     /// call invocation. The method returns None if the values do not specify
@@ -586,4 +598,71 @@ pub trait UserspaceKernelBoundary {
         state: &Self::StoredState,
         writer: &mut dyn Write,
     );
+
+    /// Walk the stack of a process by following its frame pointers, storing
+    /// the return addresses of the innermost frames in `trace`. Returns how
+    /// many were stored.
+    ///
+    /// This is best effort: it only works for processes compiled with frame
+    /// pointers, and stops at the first frame that is not in process
+    /// accessible memory. Architectures that cannot walk the stack keep this
+    /// default, which finds nothing.
+    ///
+    /// ### Safety
+    ///
+    /// This function guarantees that it will only read memory starting at
+    /// `accessible_memory_start` and before `app_brk`, and will not change
+    /// process memory. The caller is responsible for guaranteeing that those
+    /// pointers are valid for the process.
+    unsafe fn stack_trace(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &Self::StoredState,
+        _trace: &mut [usize],
+    ) -> usize {
+        0
+    }
+
+    /// Read register `index` of a process for a debugger, numbered the way
+    /// GDB numbers the registers of the architecture. Returns `None` if there
+    /// is no such register, or it cannot be read. Architectures without
+    /// debugger support keep this default, which reads nothing.
+    ///
+    /// ### Safety
+    ///
+    /// This function guarantees that it will only read memory starting at
+    /// `accessible_memory_start` and before `app_brk`. The caller is
+    /// responsible for guaranteeing that those pointers are valid for the
+    /// process.
+    unsafe fn debug_register(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &Self::StoredState,
+        _index: usize,
+    ) -> Option<usize> {
+        None
+    }
+
+    /// Write register `index` of a process for a debugger, numbered as for
+    /// `debug_register()`. Returns an error if there is no such register, or
+    /// it cannot be written.
+    ///
+    /// ### Safety
+    ///
+    /// This function guarantees that it will only change memory starting at
+    /// `accessible_memory_start` and before `app_brk`. The caller is
+    /// responsible for guaranteeing that those pointers are valid for the
+    /// process.
+    unsafe fn set_debug_register(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut Self::StoredState,
+        _index: usize,
+        _value: usize,
+    ) -> Result<(), ()> {
+        Err(())
+    }
 }
@@ -53,6 +53,23 @@ impl Upcall {
         self.cb.map_or(true, |mut cb| cb.schedule(r0, r1, r2))
     }
 
+    /// Whether the process has subscribed this upcall, rather than passing a
+    /// null function pointer.
+    pub(crate) fn is_set(&self) -> bool {
+        self.cb.is_some()
+    }
+
+    /// Time of the event being handled, for passing to the process as an
+    /// upcall argument.
+    ///
+    /// This is the tick count of the board's event clock when the kernel
+    /// started handling the interrupt that led to this event, so it does not
+    /// include the delay until the process runs. Returns `None` if the board
+    /// has not set an event clock, or if no upcall is subscribed.
+    pub fn event_timestamp(&self) -> Option<u32> {
+        self.cb.and_then(|cb| cb.app_id.kernel.event_timestamp())
+    }
+
     pub(crate) fn into_subscribe_success(self) -> SyscallReturn {
         match self.cb {
             None => SyscallReturn::SubscribeSuccess(0 as *mut u8, 0),
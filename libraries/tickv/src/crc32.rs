@@ -307,14 +307,17 @@ const fn crc32(poly: u32, mut byte: u8) -> u32 {
     [value, reflect_32(value)][0]
 }
 
+/// The CRC-32 algorithm described above.
 pub struct Crc {}
 
+/// A CRC-32 being computed, which data can be added to in several parts.
 pub struct Digest<'a> {
     crc: &'a Crc,
     value: u32,
 }
 
 impl Crc {
+    /// Create the CRC-32 algorithm.
     pub const fn new() -> Self {
         Self {}
     }
@@ -340,6 +343,7 @@ impl Crc {
         crc ^ 0xffffffff
     }
 
+    /// Start a CRC-32 of new data.
     pub const fn digest(&self) -> Digest {
         Digest::new(self)
     }
@@ -351,10 +355,12 @@ impl<'a> Digest<'a> {
         Digest { crc, value }
     }
 
+    /// Add `bytes` to the data.
     pub fn update(&mut self, bytes: &[u8]) {
         self.value = self.crc.update(self.value, bytes);
     }
 
+    /// The CRC-32 of all the data added.
     pub const fn finalise(self) -> u32 {
         self.crc.finalise(self.value)
     }
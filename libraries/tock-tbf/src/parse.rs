@@ -130,6 +130,8 @@ pub fn parse_tbf_header(
                     Default::default();
                 let mut app_name_str = "";
                 let mut fixed_address_pointer: Option<types::TbfHeaderV2FixedAddresses> = None;
+                let mut permissions_pointer: Option<types::TbfHeaderV2Permissions> = None;
+                let mut credentials_pointer: Option<types::TbfHeaderV2Credentials> = None;
 
                 // Iterate the remainder of the header looking for TLV entries.
                 while remaining.len() > 0 {
@@ -219,6 +221,57 @@ pub fn parse_tbf_header(
                             }
                         }
 
+                        types::TbfHeaderTypes::TbfHeaderPermissions => {
+                            // A count followed by that many permission
+                            // entries.
+                            let perm_len = mem::size_of::<types::TbfHeaderDriverPermission>();
+                            let perms_slice = remaining
+                                .get(0..tlv_header.length as usize)
+                                .ok_or(types::TbfParseError::NotEnoughFlash)?;
+                            let number_perms = u16::from_le_bytes(
+                                perms_slice
+                                    .get(0..2)
+                                    .ok_or(types::TbfParseError::BadTlvEntry(
+                                        tlv_header.tipe as usize,
+                                    ))?
+                                    .try_into()?,
+                            );
+                            if tlv_header.length as usize == 2 + number_perms as usize * perm_len {
+                                permissions_pointer = Some(perms_slice.try_into()?);
+                            } else {
+                                return Err(types::TbfParseError::BadTlvEntry(
+                                    tlv_header.tipe as usize,
+                                ));
+                            }
+                        }
+
+                        types::TbfHeaderTypes::TbfHeaderCredentials => {
+                            // The format and the lengths of the public key
+                            // and signature, followed by both.
+                            let credentials_slice = remaining
+                                .get(0..tlv_header.length as usize)
+                                .ok_or(types::TbfParseError::NotEnoughFlash)?;
+                            let lengths = credentials_slice.get(4..8).ok_or(
+                                types::TbfParseError::BadTlvEntry(tlv_header.tipe as usize),
+                            )?;
+                            let key_length = u16::from_le_bytes([lengths[0], lengths[1]]);
+                            let signature_length = u16::from_le_bytes([lengths[2], lengths[3]]);
+                            if tlv_header.length as usize
+                                == 8 + key_length as usize + signature_length as usize
+                            {
+                                let mut credentials: types::TbfHeaderV2Credentials =
+                                    credentials_slice.try_into()?;
+                                let start = header.len() - remaining.len() - 4;
+                                credentials.tlv_range =
+                                    (start, start + 4 + align4!(tlv_header.length as usize));
+                                credentials_pointer = Some(credentials);
+                            } else {
+                                return Err(types::TbfParseError::BadTlvEntry(
+                                    tlv_header.tipe as usize,
+                                ));
+                            }
+                        }
+
                         _ => {}
                     }
 
@@ -236,6 +289,8 @@ pub fn parse_tbf_header(
                     package_name: Some(app_name_str),
                     writeable_regions: Some(wfr_pointer),
                     fixed_addresses: fixed_address_pointer,
+                    permissions: permissions_pointer,
+                    credentials: credentials_pointer,
                 };
 
                 Ok(types::TbfHeader::TbfHeaderV2(tbf_header))
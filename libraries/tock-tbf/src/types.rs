@@ -105,6 +105,8 @@ pub enum TbfHeaderTypes {
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
     TbfHeaderFixedAddresses = 5,
+    TbfHeaderPermissions = 6,
+    TbfHeaderCredentials = 7,
 
     /// Some field in the header that we do not understand. Since the TLV format
     /// specifies the length of each section, if we get a field we do not
@@ -164,6 +166,78 @@ pub struct TbfHeaderV2FixedAddresses {
     start_process_flash: u32,
 }
 
+/// Commands a process is allowed to call on one syscall driver.
+///
+/// `allowed_commands` is a bitmask of command numbers `offset * 64` to
+/// `offset * 64 + 63`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TbfHeaderDriverPermission {
+    driver_number: u32,
+    offset: u32,
+    allowed_commands: u64,
+}
+
+/// Permissions the process has for privileged syscall drivers.
+///
+/// This struct limits the number of entries to eight, since we need to
+/// statically know the length of the array to store in this type.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TbfHeaderV2Permissions {
+    length: u16,
+    perms: [TbfHeaderDriverPermission; 8],
+}
+
+/// Credentials an app is signed with: the public key of the signer, and the
+/// signature of the TBF header, without its checksum and this TLV, followed
+/// by the app's flash after its protected region.
+///
+/// The TBF header only carries the credentials. Checking them, and what
+/// `format` identifies, is up to the kernel.
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2Credentials {
+    format: u32,
+    public_key: &'static [u8],
+    signature: &'static [u8],
+    /// Where the TLV is in the header, from its type to its padding.
+    pub(crate) tlv_range: (usize, usize),
+}
+
+impl TbfHeaderV2Credentials {
+    /// Get the signature scheme of the credentials.
+    pub fn format(&self) -> u32 {
+        self.format
+    }
+
+    /// Get the public key the app claims to be signed with.
+    pub fn public_key(&self) -> &'static [u8] {
+        self.public_key
+    }
+
+    /// Get the signature.
+    pub fn signature(&self) -> &'static [u8] {
+        self.signature
+    }
+
+    /// Get the start and end offsets of the credentials TLV in the TBF
+    /// header, type, length and padding included. The signature cannot
+    /// cover these bytes.
+    pub fn tlv_range(&self) -> (usize, usize) {
+        self.tlv_range
+    }
+}
+
+/// Result of looking up the permissions of a process for a command.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandPermissions {
+    /// The header does not include permissions.
+    NoPermsAtAll,
+    /// The header includes permissions, but none for this driver and
+    /// offset.
+    NoPermsThisDriver,
+    /// Bitmask of the allowed commands.
+    Mask(u64),
+}
+
 // Conversion functions from slices to the various TBF fields.
 
 impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Base {
@@ -209,6 +283,8 @@ impl core::convert::TryFrom<u16> for TbfHeaderTypes {
             2 => Ok(TbfHeaderTypes::TbfHeaderWriteableFlashRegions),
             3 => Ok(TbfHeaderTypes::TbfHeaderPackageName),
             5 => Ok(TbfHeaderTypes::TbfHeaderFixedAddresses),
+            6 => Ok(TbfHeaderTypes::TbfHeaderPermissions),
+            7 => Ok(TbfHeaderTypes::TbfHeaderCredentials),
             _ => Ok(TbfHeaderTypes::Unknown),
         }
     }
@@ -296,6 +372,89 @@ impl core::convert::TryFrom<&[u8]> for TbfHeaderV2FixedAddresses {
     }
 }
 
+impl core::convert::TryFrom<&[u8]> for TbfHeaderDriverPermission {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderDriverPermission, Self::Error> {
+        Ok(TbfHeaderDriverPermission {
+            driver_number: u32::from_le_bytes(
+                b.get(0..4)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+            offset: u32::from_le_bytes(
+                b.get(4..8)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+            allowed_commands: u64::from_le_bytes(
+                b.get(8..16)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+        })
+    }
+}
+
+impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Permissions {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderV2Permissions, Self::Error> {
+        let number_perms = u16::from_le_bytes(
+            b.get(0..2)
+                .ok_or(TbfParseError::InternalError)?
+                .try_into()?,
+        );
+        let perm_len = core::mem::size_of::<TbfHeaderDriverPermission>();
+        let mut perms: [TbfHeaderDriverPermission; 8] = Default::default();
+        // Only keep the first eight entries.
+        let length = core::cmp::min(number_perms as usize, perms.len());
+        for (i, perm) in perms[..length].iter_mut().enumerate() {
+            let start = 2 + i * perm_len;
+            *perm = b
+                .get(start..start + perm_len)
+                .ok_or(TbfParseError::NotEnoughFlash)?
+                .try_into()?;
+        }
+        Ok(TbfHeaderV2Permissions {
+            length: length as u16,
+            perms: perms,
+        })
+    }
+}
+
+impl core::convert::TryFrom<&'static [u8]> for TbfHeaderV2Credentials {
+    type Error = TbfParseError;
+
+    fn try_from(b: &'static [u8]) -> Result<TbfHeaderV2Credentials, Self::Error> {
+        let format = u32::from_le_bytes(
+            b.get(0..4)
+                .ok_or(TbfParseError::InternalError)?
+                .try_into()?,
+        );
+        let key_length = u16::from_le_bytes(
+            b.get(4..6)
+                .ok_or(TbfParseError::InternalError)?
+                .try_into()?,
+        ) as usize;
+        let signature_length = u16::from_le_bytes(
+            b.get(6..8)
+                .ok_or(TbfParseError::InternalError)?
+                .try_into()?,
+        ) as usize;
+        Ok(TbfHeaderV2Credentials {
+            format,
+            public_key: b
+                .get(8..8 + key_length)
+                .ok_or(TbfParseError::NotEnoughFlash)?,
+            signature: b
+                .get(8 + key_length..8 + key_length + signature_length)
+                .ok_or(TbfParseError::NotEnoughFlash)?,
+            tlv_range: (0, 0),
+        })
+    }
+}
+
 /// Single header that can contain all parts of a v2 header.
 ///
 /// Note, this struct limits the number of writeable regions an app can have to
@@ -308,6 +467,8 @@ pub struct TbfHeaderV2 {
     pub(crate) package_name: Option<&'static str>,
     pub(crate) writeable_regions: Option<[Option<TbfHeaderV2WriteableFlashRegion>; 4]>,
     pub(crate) fixed_addresses: Option<TbfHeaderV2FixedAddresses>,
+    pub(crate) permissions: Option<TbfHeaderV2Permissions>,
+    pub(crate) credentials: Option<TbfHeaderV2Credentials>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -353,6 +514,15 @@ impl TbfHeader {
         }
     }
 
+    /// Get the length of the TBF header, from the start of the app's region
+    /// in flash.
+    pub fn get_header_size(&self) -> u32 {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.base.header_size as u32,
+            TbfHeader::Padding(base) => base.header_size as u32,
+        }
+    }
+
     /// Get the number of bytes from the start of the app's region in flash that
     /// is for kernel use only. The app cannot write this region.
     pub fn get_protected_size(&self) -> u32 {
@@ -434,4 +604,32 @@ impl TbfHeader {
             start => Some(start),
         }
     }
+
+    /// Get the commands this process may call on driver `driver_num`, for
+    /// command numbers `offset * 64` to `offset * 64 + 63`.
+    pub fn get_command_permissions(&self, driver_num: usize, offset: usize) -> CommandPermissions {
+        let perms = match self {
+            TbfHeader::TbfHeaderV2(hd) => match hd.permissions {
+                Some(perms) => perms,
+                None => return CommandPermissions::NoPermsAtAll,
+            },
+            _ => return CommandPermissions::NoPermsAtAll,
+        };
+        perms.perms[..perms.length as usize]
+            .iter()
+            .find(|perm| {
+                perm.driver_number as usize == driver_num && perm.offset as usize == offset
+            })
+            .map_or(CommandPermissions::NoPermsThisDriver, |perm| {
+                CommandPermissions::Mask(perm.allowed_commands)
+            })
+    }
+
+    /// Get the credentials the app is signed with, if it has any.
+    pub fn get_credentials(&self) -> Option<TbfHeaderV2Credentials> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.credentials,
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,148 @@
+#!/usr/bin/env python3
+
+# Reports which kernel capabilities each board grants, and to whom.
+#
+# Usage: capability_audit.py [BOARD_DIR ...]
+#
+# With no arguments, audits every board under boards/.
+
+'''
+Script to report which capabilities a board grants to which capsules.
+
+Capabilities are the trusted computing base of a board: every capsule that
+holds one can do something that no other capsule can. This script reads the
+`main.rs` of a board and lists, for each capability the board creates, the
+calls it is passed to. It also lists the capabilities that the components the
+board uses create for themselves.
+
+The script works on the source, not on the compiled kernel, so it only finds
+capabilities that are created with `create_capability!()` or by implementing
+a capability trait for a type, and passed to a call by name.
+
+Usage: capability_audit.py [BOARD_DIR ...]
+'''
+
+import os
+import re
+import sys
+
+TOCK_ROOT = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+COMPONENTS = os.path.join(TOCK_ROOT, 'boards', 'components', 'src')
+
+CREATE_RE = re.compile(
+    r'let\s+(?:mut\s+)?(\w+)\s*=\s*create_capability!\(\s*(?:kernel::)?capabilities::(\w+)\s*\)')
+INLINE_CREATE_RE = re.compile(
+    r'create_capability!\(\s*(?:kernel::)?capabilities::(\w+)\s*\)')
+IMPL_RE = re.compile(
+    r'unsafe\s+impl\s+(?:kernel::)?capabilities::(\w+)\s+for\s+(\w+)')
+COMPONENT_USE_RE = re.compile(r'components::(\w+)::')
+CALLEE_RE = re.compile(r'([\w:.]+(?:::<[^()]*>)?)\s*!?$')
+
+
+def strip_comments(source):
+    '''Blank out comments, keeping offsets the same.'''
+    def blank(match):
+        return re.sub(r'[^\n]', ' ', match.group(0))
+    return re.sub(r'//[^\n]*|/\*.*?\*/', blank, source, flags=re.S)
+
+
+def enclosing_call(source, offset):
+    '''Name of the innermost call whose arguments contain `offset`.'''
+    depth = 0
+    for i in range(offset - 1, -1, -1):
+        c = source[i]
+        if c in ')]}':
+            depth += 1
+        elif c in '([{':
+            if depth > 0:
+                depth -= 1
+            elif c == '(':
+                match = CALLEE_RE.search(source[:i].rstrip())
+                if match:
+                    return match.group(1)
+                return '(expression)'
+            elif c == '{':
+                # Reached the enclosing block without finding a call
+                return None
+    return None
+
+
+def grants(source):
+    '''Map each capability to the calls it is passed to, with counts.'''
+    holders = {}
+    for match in CREATE_RE.finditer(source):
+        holders.setdefault(match.group(1), set()).add(match.group(2))
+    for match in IMPL_RE.finditer(source):
+        holders.setdefault(match.group(2), set()).add(match.group(1))
+
+    result = {}
+    for holder, capabilities in holders.items():
+        for use in re.finditer(r'\b%s\b' % re.escape(holder), source):
+            callee = enclosing_call(source, use.start())
+            if callee is None:
+                continue
+            for capability in capabilities:
+                calls = result.setdefault(capability, {})
+                calls[callee] = calls.get(callee, 0) + 1
+
+    # Capabilities created right where they are passed
+    for match in INLINE_CREATE_RE.finditer(source):
+        prefix = source[max(0, match.start() - 40):match.start()]
+        if re.search(r'=\s*$', prefix):
+            continue
+        callee = enclosing_call(source, match.start())
+        if callee is None:
+            continue
+        calls = result.setdefault(match.group(1), {})
+        calls[callee] = calls.get(callee, 0) + 1
+    return result
+
+
+def component_capabilities(component):
+    '''Capabilities a component creates for itself.'''
+    path = os.path.join(COMPONENTS, component + '.rs')
+    if not os.path.exists(path):
+        path = os.path.join(COMPONENTS, component, 'mod.rs')
+    if not os.path.exists(path):
+        return set()
+    with open(path) as f:
+        source = strip_comments(f.read())
+    return set(INLINE_CREATE_RE.findall(source)) | set(m.group(1) for m in IMPL_RE.finditer(source))
+
+
+def audit(board_dir):
+    main = os.path.join(board_dir, 'src', 'main.rs')
+    if not os.path.exists(main):
+        return
+    with open(main) as f:
+        source = strip_comments(f.read())
+
+    print(os.path.relpath(board_dir, TOCK_ROOT))
+    for capability, calls in sorted(grants(source).items()):
+        print('  {}'.format(capability))
+        for callee, count in sorted(calls.items()):
+            print('    {}{}'.format(callee, ' (x{})'.format(count) if count > 1 else ''))
+
+    components = sorted(set(COMPONENT_USE_RE.findall(source)))
+    internal = [(c, component_capabilities(c)) for c in components]
+    internal = [(c, caps) for c, caps in internal if caps]
+    if internal:
+        print('  created by components')
+        for component, capabilities in internal:
+            print('    components::{}: {}'.format(component, ', '.join(sorted(capabilities))))
+    print()
+
+
+def main():
+    boards = sys.argv[1:]
+    if not boards:
+        boards = []
+        for root, _, files in os.walk(os.path.join(TOCK_ROOT, 'boards')):
+            if 'Makefile' in files and os.path.exists(os.path.join(root, 'src', 'main.rs')):
+                boards.append(root)
+    for board in sorted(boards):
+        audit(os.path.abspath(board))
+
+
+if __name__ == '__main__':
+    main()
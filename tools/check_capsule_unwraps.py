@@ -0,0 +1,73 @@
+#!/usr/bin/env python3
+
+'''
+Check that capsules implementing a system call driver cannot panic on
+`unwrap()` or `expect()`.
+
+Every function of a capsule that implements `Driver` can be reached, directly
+or through a callback, from a system call a process makes, so a process must
+not be able to make any of them panic. This script flags each `.unwrap()` and
+`.expect(` in the source files of those capsules, outside of tests. Use
+`unwrap_or`, `map_or` or `?` and return an `ErrorCode` instead.
+
+The check works on the source: it does not follow calls into other files, so
+helpers a driver uses from other capsules are only checked if they implement
+`Driver` themselves.
+
+Run from the root of the repository. Exits with status 1 if it finds any.
+'''
+
+import os
+import re
+import sys
+
+SKIP = ['/test/']
+
+DRIVER_IMPL = re.compile(r'\bimpl\b[^{;]*\bDriver\s+for\b')
+PANIC_CALL = re.compile(r'\.(unwrap|expect)\(')
+TEST_MODULE = re.compile(r'#\[cfg\(test\)\]\s*mod\s+\w+\s*\{')
+
+
+def strip_comments(source):
+    '''Blank out comments and strings, keeping line numbers the same.'''
+    def blank(match):
+        return re.sub(r'[^\n]', ' ', match.group(0))
+    return re.sub(r'//[^\n]*|/\*.*?\*/|"(?:\\.|[^"\\])*"', blank, source, flags=re.S)
+
+
+def strip_tests(source):
+    '''Blank out `#[cfg(test)]` modules.'''
+    for match in reversed(list(TEST_MODULE.finditer(source))):
+        depth = 1
+        end = match.end()
+        while depth and end < len(source):
+            if source[end] == '{':
+                depth += 1
+            elif source[end] == '}':
+                depth -= 1
+            end += 1
+        source = source[:match.start()] + re.sub(r'[^\n]', ' ', source[match.start():end]) + source[end:]
+    return source
+
+
+found = []
+for subdir, dirs, files in os.walk('capsules/src/'):
+    for file in files:
+        filepath = os.path.join(subdir, file)
+        if not filepath.endswith('.rs') or any(skip in filepath for skip in SKIP):
+            continue
+        with open(filepath) as f:
+            original = f.read()
+        source = strip_tests(strip_comments(original))
+        if not DRIVER_IMPL.search(source):
+            continue
+        lines = original.splitlines()
+        for match in PANIC_CALL.finditer(source):
+            line = source.count('\n', 0, match.start()) + 1
+            found.append('{}:{}: {}'.format(filepath, line, lines[line - 1].strip()))
+
+if found:
+    print('The following syscall drivers can panic on unwrap()/expect():')
+    for f in found:
+        print(' - {}'.format(f))
+    sys.exit(1)
@@ -0,0 +1,277 @@
+//! Boot a Tock board in a simulator and report pass/fail as an exit code.
+//!
+//! See the README for the supported boards and the meaning of the exit codes.
+
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::process::{exit, Child, Command, Stdio};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Markers printed by `kernel::simulation::report()`.
+const PASS_MARKER: &str = "TOCK-SIM: PASS";
+const FAIL_MARKER: &str = "TOCK-SIM: FAIL";
+/// Printed by `kernel::debug::panic_banner()`.
+const PANIC_MARKER: &str = "Kernel panic";
+
+const EXIT_PASS: i32 = 0;
+const EXIT_FAIL: i32 = 1;
+const EXIT_TIMEOUT: i32 = 2;
+const EXIT_USAGE: i32 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Simulator {
+    Qemu,
+    Verilator,
+}
+
+struct Board {
+    name: &'static str,
+    /// Printed once the board has finished initialisation.
+    banner: &'static str,
+    /// Whether the board can be built with the `self_test` feature.
+    self_test: bool,
+    simulators: &'static [Simulator],
+}
+
+const BOARDS: &[Board] = &[
+    Board {
+        name: "earlgrey-nexysvideo",
+        banner: "OpenTitan initialisation complete. Entering main loop",
+        self_test: true,
+        simulators: &[Simulator::Qemu, Simulator::Verilator],
+    },
+    Board {
+        name: "hifive1",
+        banner: "HiFive1 initialization complete.",
+        self_test: false,
+        simulators: &[Simulator::Qemu],
+    },
+];
+
+/// What the console output tells us about the run so far.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Running,
+    Pass,
+    Fail(String),
+}
+
+struct Monitor {
+    wait_for_self_test: bool,
+    booted: bool,
+}
+
+impl Monitor {
+    fn new(wait_for_self_test: bool) -> Monitor {
+        Monitor {
+            wait_for_self_test,
+            booted: false,
+        }
+    }
+
+    fn line(&mut self, board: &Board, line: &str) -> Outcome {
+        if line.contains(PANIC_MARKER) {
+            return Outcome::Fail(String::from("kernel panic"));
+        }
+        if line.contains(FAIL_MARKER) {
+            return Outcome::Fail(line.trim().to_string());
+        }
+        if line.contains(board.banner) {
+            self.booted = true;
+        }
+        if self.booted && (!self.wait_for_self_test || line.contains(PASS_MARKER)) {
+            return Outcome::Pass;
+        }
+        Outcome::Running
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: run_sim <board> [--sim qemu|verilator] [--timeout <seconds>]");
+    eprintln!();
+    eprintln!("boards:");
+    for board in BOARDS {
+        eprintln!("    {}", board.name);
+    }
+    exit(EXIT_USAGE);
+}
+
+/// `make` invocation for the board, with self-tests enabled if supported.
+fn make(board: &Board, sim: Simulator) -> Command {
+    let mut make = Command::new("make");
+    make.arg("-C").arg(format!("../../boards/{}", board.name));
+    if board.self_test {
+        let config = match sim {
+            Simulator::Qemu => "fpga_nexysvideo",
+            Simulator::Verilator => "sim_verilator",
+        };
+        make.arg(format!("BOARD_CONFIGURATION={},self_test", config));
+    }
+    make
+}
+
+fn build(board: &Board, sim: Simulator) {
+    let status = make(board, sim).status().unwrap_or_else(|e| {
+        eprintln!("run_sim: failed to run make: {}", e);
+        exit(EXIT_USAGE);
+    });
+    if !status.success() {
+        eprintln!("run_sim: build of {} failed", board.name);
+        exit(EXIT_USAGE);
+    }
+}
+
+fn spawn(board: &Board, sim: Simulator) -> Child {
+    let mut command = match sim {
+        Simulator::Qemu => {
+            let mut make = make(board, sim);
+            make.arg("qemu");
+            make
+        }
+        Simulator::Verilator => {
+            let cmdline = env::var("VERILATOR_SIM").unwrap_or_else(|_| {
+                eprintln!("run_sim: set VERILATOR_SIM to the Verilator command line");
+                exit(EXIT_USAGE);
+            });
+            let mut sh = Command::new("sh");
+            sh.arg("-c").arg(cmdline);
+            sh
+        }
+    };
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| {
+            eprintln!("run_sim: failed to start simulator: {}", e);
+            exit(EXIT_USAGE);
+        })
+}
+
+fn run(board: &Board, sim: Simulator, timeout: Duration) -> i32 {
+    build(board, sim);
+    let mut child = spawn(board, sim);
+
+    let (tx, rx) = channel();
+    let stdout = child.stdout.take().unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let mut monitor = Monitor::new(board.self_test);
+    let code = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(remaining) {
+            Ok(line) => {
+                println!("{}", line);
+                match monitor.line(board, &line) {
+                    Outcome::Running => {}
+                    Outcome::Pass => {
+                        println!("run_sim: {} PASS", board.name);
+                        break EXIT_PASS;
+                    }
+                    Outcome::Fail(reason) => {
+                        println!("run_sim: {} FAIL: {}", board.name, reason);
+                        break EXIT_FAIL;
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                println!("run_sim: {} timed out after {:?}", board.name, timeout);
+                break EXIT_TIMEOUT;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                println!("run_sim: simulator exited before the run finished");
+                break EXIT_TIMEOUT;
+            }
+        }
+    };
+
+    let _ = child.kill();
+    let _ = child.wait();
+    code
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let board_name = args.next().unwrap_or_else(|| usage());
+    let board = BOARDS
+        .iter()
+        .find(|b| b.name == board_name)
+        .unwrap_or_else(|| usage());
+
+    let mut sim = Simulator::Qemu;
+    let mut timeout = Duration::from_secs(60);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sim" => {
+                sim = match args.next().as_deref() {
+                    Some("qemu") => Simulator::Qemu,
+                    Some("verilator") => Simulator::Verilator,
+                    _ => usage(),
+                }
+            }
+            "--timeout" => {
+                let secs = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_else(|| usage());
+                timeout = Duration::from_secs(secs);
+            }
+            _ => usage(),
+        }
+    }
+    if !board.simulators.contains(&sim) {
+        eprintln!("run_sim: {} does not support that simulator", board.name);
+        exit(EXIT_USAGE);
+    }
+
+    exit(run(board, sim, timeout));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_requires_banner_and_marker() {
+        let board = &BOARDS[0];
+        let mut monitor = Monitor::new(true);
+        assert_eq!(monitor.line(board, PASS_MARKER), Outcome::Running);
+        assert_eq!(monitor.line(board, board.banner), Outcome::Running);
+        assert_eq!(monitor.line(board, PASS_MARKER), Outcome::Pass);
+    }
+
+    #[test]
+    fn banner_is_enough_without_self_tests() {
+        let board = &BOARDS[1];
+        let mut monitor = Monitor::new(false);
+        assert_eq!(monitor.line(board, board.banner), Outcome::Pass);
+    }
+
+    #[test]
+    fn panic_and_failure_marker_fail() {
+        let board = &BOARDS[0];
+        let mut monitor = Monitor::new(true);
+        assert!(matches!(
+            monitor.line(board, "Kernel panic at main.rs:1:"),
+            Outcome::Fail(_)
+        ));
+        assert!(matches!(
+            monitor.line(board, "TOCK-SIM: FAIL 2"),
+            Outcome::Fail(_)
+        ));
+    }
+}
@@ -0,0 +1,484 @@
+//! Fuzz the system call paths of capsules on the host.
+//!
+//! Each input builds a fresh board of capsules on mock HILs, with one process
+//! loaded into host memory, and runs a sequence of operations decoded from
+//! the input: commands, allows and subscribes from the process dispatched to
+//! drivers through `Platform::with_driver`, as the kernel does, interleaved
+//! with the mocks completing outstanding operations. An input fails if any
+//! capsule panics.
+//!
+//! See the README for the input format and how to run it.
+
+use std::env;
+use std::fs;
+use std::panic;
+use std::process::exit;
+
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::capabilities;
+use kernel::hil::gnss::{Fix, FixClient};
+use kernel::hil::pwm::PwmPin;
+use kernel::hil::qdec::QuadratureDecoder;
+use kernel::hil::rng::Rng;
+use kernel::hil::secure_boot::Verification;
+use kernel::hil::time::Alarm;
+use kernel::{create_capability, static_init};
+use kernel::{CommandReturn, ErrorCode, Platform, ProcessId};
+use kernel::{ReadOnlyAppSlice, ReadWriteAppSlice, Upcall};
+
+mod mock;
+
+use mock::{MockAlarm, MockBootStatus, MockChip, MockLed, MockPwmPin, MockQdec, MockRng};
+
+const EXIT_PASS: i32 = 0;
+const EXIT_CRASH: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+/// Bytes of the input that configure the mocks.
+const HEADER_LEN: usize = 2;
+/// Bytes of each operation: kind, driver, number, two 32-bit arguments.
+const OP_LEN: usize = 11;
+
+const NUM_LEDS: usize = 4;
+const NUM_SERVOS: usize = 2;
+
+/// Bytes of flash of the process, including its TBF header.
+const APP_FLASH_LEN: usize = 1024;
+/// Bytes of RAM the process asks for, which hold its grant region.
+const APP_MIN_RAM: u32 = 8192;
+const APP_MEMORY_LEN: usize = 16384;
+
+type FuzzAlarm = VirtualMuxAlarm<'static, MockAlarm<'static>>;
+
+static mut PROCESSES: [Option<&'static dyn kernel::procs::Process>; 1] = [None];
+
+static FAULT_POLICY: kernel::procs::StopFaultPolicy = kernel::procs::StopFaultPolicy {};
+
+struct ProcessMgmtCap;
+unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+
+/// Process memory, aligned for the kernel structures placed in it.
+#[repr(align(8))]
+struct AppMemory([u8; APP_MEMORY_LEN]);
+
+struct FuzzBoard {
+    led: &'static capsules::led::LedDriver<'static, MockLed>,
+    alarm: &'static capsules::alarm::AlarmDriver<'static, FuzzAlarm>,
+    rng: &'static capsules::rng::RngDriver<'static>,
+    secure_boot: &'static capsules::secure_boot::SecureBoot<'static, MockBootStatus>,
+    geofence: &'static capsules::geofence::Geofence,
+    process_events: &'static capsules::process_events::ProcessEvents,
+    process_manager: &'static capsules::process_manager::ProcessManager<ProcessMgmtCap>,
+    qdec: &'static capsules::qdec::Qdec<'static, FuzzAlarm>,
+    servo: &'static capsules::servo::Servo<'static, FuzzAlarm>,
+}
+
+impl Platform for FuzzBoard {
+    fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+    where
+        F: FnOnce(Option<&dyn kernel::Driver>) -> R,
+    {
+        match driver_num {
+            capsules::led::DRIVER_NUM => f(Some(self.led)),
+            capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
+            capsules::rng::DRIVER_NUM => f(Some(self.rng)),
+            capsules::secure_boot::DRIVER_NUM => f(Some(self.secure_boot)),
+            capsules::geofence::DRIVER_NUM => f(Some(self.geofence)),
+            capsules::process_events::DRIVER_NUM => f(Some(self.process_events)),
+            capsules::process_manager::DRIVER_NUM => f(Some(self.process_manager)),
+            capsules::qdec::DRIVER_NUM => f(Some(self.qdec)),
+            capsules::servo::DRIVER_NUM => f(Some(self.servo)),
+            _ => f(None),
+        }
+    }
+}
+
+/// Drivers operations are sent to, chosen by the driver byte of each
+/// operation. Values past the end select a driver number no board has.
+const DRIVERS: [usize; 9] = [
+    capsules::led::DRIVER_NUM,
+    capsules::alarm::DRIVER_NUM,
+    capsules::rng::DRIVER_NUM,
+    capsules::secure_boot::DRIVER_NUM,
+    capsules::geofence::DRIVER_NUM,
+    capsules::process_events::DRIVER_NUM,
+    capsules::process_manager::DRIVER_NUM,
+    capsules::qdec::DRIVER_NUM,
+    capsules::servo::DRIVER_NUM,
+];
+
+/// Reads the fuzz input, returning zeros once it runs out.
+struct Input<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Input<'a> {
+    fn byte(&mut self) -> u8 {
+        let byte = self.data.get(self.position).copied().unwrap_or(0);
+        self.position += 1;
+        byte
+    }
+
+    fn word(&mut self) -> u32 {
+        u32::from_le_bytes([self.byte(), self.byte(), self.byte(), self.byte()])
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.position)
+    }
+}
+
+/// The TBF image of an enabled process named "fuzz" that needs
+/// `APP_MIN_RAM` bytes of RAM, padded to `APP_FLASH_LEN` bytes.
+fn app_flash() -> &'static [u8] {
+    let mut header = Vec::new();
+    header.extend(&2u16.to_le_bytes());
+    header.extend(&0u16.to_le_bytes()); // header size, set below
+    header.extend(&(APP_FLASH_LEN as u32).to_le_bytes());
+    header.extend(&1u32.to_le_bytes()); // enabled
+    header.extend(&0u32.to_le_bytes()); // checksum, set below
+                                        // Main: init function offset, protected size, minimum RAM size
+    header.extend(&1u16.to_le_bytes());
+    header.extend(&12u16.to_le_bytes());
+    header.extend(&0u32.to_le_bytes());
+    header.extend(&0u32.to_le_bytes());
+    header.extend(&APP_MIN_RAM.to_le_bytes());
+    // Package name
+    header.extend(&3u16.to_le_bytes());
+    header.extend(&4u16.to_le_bytes());
+    header.extend(b"fuzz");
+    let len = header.len() as u16;
+    header[2..4].copy_from_slice(&len.to_le_bytes());
+    let checksum = header.chunks_exact(4).fold(0, |sum, word| {
+        sum ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]])
+    });
+    header[12..16].copy_from_slice(&checksum.to_le_bytes());
+    header.resize(APP_FLASH_LEN, 0);
+    Box::leak(header.into_boxed_slice())
+}
+
+/// Build the mocks and capsules for one input, and load the process.
+unsafe fn build(
+    input: &mut Input,
+) -> (
+    FuzzBoard,
+    ProcessId,
+    &'static MockAlarm<'static>,
+    &'static MockRng<'static>,
+    &'static MockQdec<'static>,
+) {
+    let memory_allocation_cap = create_capability!(capabilities::MemoryAllocationCapability);
+    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+    let chip = static_init!(MockChip, MockChip::default());
+
+    let leds = static_init!(
+        [MockLed; NUM_LEDS],
+        [
+            MockLed::default(),
+            MockLed::default(),
+            MockLed::default(),
+            MockLed::default()
+        ]
+    );
+    let led_refs = static_init!(
+        [&'static MockLed; NUM_LEDS],
+        [&leds[0], &leds[1], &leds[2], &leds[3]]
+    );
+    let led = static_init!(
+        capsules::led::LedDriver<'static, MockLed>,
+        capsules::led::LedDriver::new(led_refs)
+    );
+
+    let mock_alarm = static_init!(MockAlarm<'static>, MockAlarm::new());
+    let mux_alarm = static_init!(
+        MuxAlarm<'static, MockAlarm<'static>>,
+        MuxAlarm::new(mock_alarm)
+    );
+    mock_alarm.set_alarm_client(mux_alarm);
+
+    let driver_alarm = static_init!(FuzzAlarm, VirtualMuxAlarm::new(mux_alarm));
+    let alarm = static_init!(
+        capsules::alarm::AlarmDriver<'static, FuzzAlarm>,
+        capsules::alarm::AlarmDriver::new(
+            driver_alarm,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+    driver_alarm.set_alarm_client(alarm);
+
+    let mock_rng = static_init!(MockRng<'static>, MockRng::new());
+    let rng = static_init!(
+        capsules::rng::RngDriver<'static>,
+        capsules::rng::RngDriver::new(mock_rng, board_kernel.create_grant(&memory_allocation_cap))
+    );
+    mock_rng.set_client(rng);
+
+    let verification = match input.byte() % 4 {
+        0 => Verification::Unknown,
+        1 => Verification::Unverified,
+        2 => Verification::Integrity,
+        _ => Verification::Signature,
+    };
+    let num_counters = (input.byte() % 5) as usize;
+    let boot_status = static_init!(
+        MockBootStatus,
+        MockBootStatus {
+            verification,
+            key_id: if verification == Verification::Signature {
+                Some(0x5eed)
+            } else {
+                None
+            },
+            counters: [1, 2, 3, 4],
+            num_counters,
+        }
+    );
+    let secure_boot = static_init!(
+        capsules::secure_boot::SecureBoot<'static, MockBootStatus>,
+        capsules::secure_boot::SecureBoot::new(boot_status)
+    );
+
+    let geofence = static_init!(
+        capsules::geofence::Geofence,
+        capsules::geofence::Geofence::new(board_kernel.create_grant(&memory_allocation_cap))
+    );
+
+    let process_events = static_init!(
+        capsules::process_events::ProcessEvents,
+        capsules::process_events::ProcessEvents::new(
+            board_kernel,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+
+    let process_manager = static_init!(
+        capsules::process_manager::ProcessManager<ProcessMgmtCap>,
+        capsules::process_manager::ProcessManager::new(
+            board_kernel,
+            ProcessMgmtCap,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+
+    let mock_qdec = static_init!(MockQdec<'static>, MockQdec::new());
+    let qdec_alarm = static_init!(FuzzAlarm, VirtualMuxAlarm::new(mux_alarm));
+    let qdec = static_init!(
+        capsules::qdec::Qdec<'static, FuzzAlarm>,
+        capsules::qdec::Qdec::new(
+            mock_qdec,
+            qdec_alarm,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+    mock_qdec.set_client(qdec);
+    qdec_alarm.set_alarm_client(qdec);
+
+    let pwm_pins = static_init!([MockPwmPin; NUM_SERVOS], [MockPwmPin, MockPwmPin]);
+    let servo_pins = static_init!(
+        [&'static dyn PwmPin; NUM_SERVOS],
+        [&pwm_pins[0], &pwm_pins[1]]
+    );
+    let servo_alarm = static_init!(FuzzAlarm, VirtualMuxAlarm::new(mux_alarm));
+    let servo = static_init!(
+        capsules::servo::Servo<'static, FuzzAlarm>,
+        capsules::servo::Servo::new(
+            servo_pins,
+            servo_alarm,
+            500,
+            2500,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+    servo_alarm.set_alarm_client(servo);
+
+    // Load the process once every grant exists, as a board does.
+    let app_memory = static_init!(AppMemory, AppMemory([0; APP_MEMORY_LEN]));
+    kernel::procs::load_processes(
+        board_kernel,
+        chip,
+        app_flash(),
+        &mut app_memory.0,
+        &mut PROCESSES,
+        &FAULT_POLICY,
+        &ProcessMgmtCap,
+    )
+    .expect("the fuzz process does not load");
+    let process = PROCESSES[0]
+        .expect("the fuzz process does not load")
+        .processid();
+
+    let board = FuzzBoard {
+        led,
+        alarm,
+        rng,
+        secure_boot,
+        geofence,
+        process_events,
+        process_manager,
+        qdec,
+        servo,
+    };
+    (board, process, mock_alarm, mock_rng, mock_qdec)
+}
+
+/// Run one input. Panics if a capsule does.
+fn run(data: &[u8]) {
+    let mut input = Input { data, position: 0 };
+    unsafe {
+        let (board, process, mock_alarm, mock_rng, mock_qdec) = build(&mut input);
+
+        while input.remaining() >= OP_LEN {
+            let kind = input.byte();
+            let driver = input.byte() as usize;
+            let driver_num = DRIVERS.get(driver).copied().unwrap_or(0x9_0000 + driver);
+            let number = input.byte() as usize;
+            let arg0 = input.word() as usize;
+            let arg1 = input.word() as usize;
+
+            match kind % 9 {
+                0 => {
+                    let _ = board.with_driver(driver_num, |d| match d {
+                        Some(d) => d.command(number, arg0, arg1, process),
+                        None => CommandReturn::failure(ErrorCode::NODEVICE),
+                    });
+                }
+                1 => {
+                    board.with_driver(driver_num, |d| {
+                        d.map(|d| d.allow_readwrite(process, number, ReadWriteAppSlice::default()))
+                    });
+                }
+                2 => {
+                    board.with_driver(driver_num, |d| {
+                        d.map(|d| d.allow_readonly(process, number, ReadOnlyAppSlice::default()))
+                    });
+                }
+                3 => {
+                    board.with_driver(driver_num, |d| {
+                        d.map(|d| d.subscribe(number, Upcall::default(), process))
+                    });
+                }
+                4 => mock_alarm.advance(arg0 as u32),
+                5 => mock_alarm.fire(),
+                6 => mock_rng.deliver(arg0 % 64),
+                7 => mock_qdec.turn(arg0 as i32),
+                _ => board.geofence.fix(Fix {
+                    latitude: arg0 as i32,
+                    longitude: arg1 as i32,
+                    speed_mm_s: number as u32,
+                }),
+            }
+        }
+    }
+}
+
+/// xorshift64, so runs are reproducible from the seed.
+struct Generator(u64);
+
+impl Generator {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// An input of a header and up to 32 operations. Most operations go to
+    /// one of the board's drivers with a small command number, so they get
+    /// past the first checks of the capsule.
+    fn input(&mut self) -> Vec<u8> {
+        let ops = (self.next() % 33) as usize;
+        let mut data = Vec::with_capacity(HEADER_LEN + ops * OP_LEN);
+        data.push(self.next() as u8);
+        data.push(self.next() as u8);
+        for _ in 0..ops {
+            let r = self.next();
+            data.push(r as u8);
+            data.push(if r & 0x100 == 0 {
+                (r >> 16) as u8 % DRIVERS.len() as u8
+            } else {
+                (r >> 16) as u8
+            });
+            data.push(if r & 0x200 == 0 {
+                (r >> 24) as u8 % 8
+            } else {
+                (r >> 24) as u8
+            });
+            let args = self.next();
+            for &arg in &[args as u32, (args >> 32) as u32] {
+                let arg = match self.next() % 4 {
+                    0 => arg,
+                    1 => u32::MAX - (arg % 4),
+                    _ => arg % 64,
+                };
+                data.extend_from_slice(&arg.to_le_bytes());
+            }
+        }
+        data
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn usage() -> ! {
+    eprintln!("usage: syscall_fuzz [--iterations <n>] [--seed <n>]");
+    eprintln!("       syscall_fuzz <input file>...");
+    exit(EXIT_USAGE);
+}
+
+fn main() {
+    let mut iterations: u64 = 100_000;
+    let mut seed: u64 = 1;
+    let mut files = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--iterations" => {
+                iterations = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--seed" => {
+                seed = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n != 0)
+                    .unwrap_or_else(|| usage())
+            }
+            _ if arg.starts_with("--") => usage(),
+            _ => files.push(arg),
+        }
+    }
+
+    if !files.is_empty() {
+        let mut crashed = false;
+        for file in files {
+            let data = fs::read(&file).unwrap_or_else(|e| {
+                eprintln!("{}: {}", file, e);
+                exit(EXIT_USAGE);
+            });
+            if panic::catch_unwind(|| run(&data)).is_err() {
+                eprintln!("{}: crashed", file);
+                crashed = true;
+            }
+        }
+        exit(if crashed { EXIT_CRASH } else { EXIT_PASS });
+    }
+
+    let mut generator = Generator(seed);
+    for iteration in 0..iterations {
+        let data = generator.input();
+        if panic::catch_unwind(|| run(&data)).is_err() {
+            let file = format!("crash-{}-{}.bin", seed, iteration);
+            let _ = fs::write(&file, &data);
+            eprintln!("iteration {} crashed, input saved to {}", iteration, file);
+            eprintln!("input: {}", hex(&data));
+            exit(EXIT_CRASH);
+        }
+    }
+    println!("{} inputs, no crashes", iterations);
+}
@@ -0,0 +1,387 @@
+//! Host implementations of the HILs the fuzzed capsules use.
+//!
+//! Each mock records what the capsule asked of it, and exposes a method the
+//! fuzzer calls to complete an outstanding operation, standing in for the
+//! interrupt the hardware would raise.
+
+use std::cell::Cell;
+use std::fmt::Write;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::led::Led;
+use kernel::hil::pwm::PwmPin;
+use kernel::hil::qdec::{QuadratureDecoder, QuadratureDecoderClient};
+use kernel::hil::rng::{self, Rng};
+use kernel::hil::secure_boot::{SecureBootStatus, Verification};
+use kernel::hil::time::{self, Alarm, Ticks, Time};
+use kernel::procs::FunctionCall;
+use kernel::syscall::{ContextSwitchReason, SyscallReturn, UserspaceKernelBoundary};
+use kernel::{Chip, ErrorCode};
+
+/// A chip that can load processes into host memory but never runs them, so
+/// the fuzzed system calls have a process with a grant region behind them.
+#[derive(Default)]
+pub struct MockChip {
+    boundary: MockBoundary,
+}
+
+impl Chip for MockChip {
+    type MPU = ();
+    type UserspaceKernelBoundary = MockBoundary;
+    type SchedulerTimer = ();
+    type WatchDog = ();
+
+    fn service_pending_interrupts(&self) {}
+
+    fn has_pending_interrupts(&self) -> bool {
+        false
+    }
+
+    fn mpu(&self) -> &() {
+        &()
+    }
+
+    fn scheduler_timer(&self) -> &() {
+        &()
+    }
+
+    fn watchdog(&self) -> &() {
+        &()
+    }
+
+    fn userspace_kernel_boundary(&self) -> &MockBoundary {
+        &self.boundary
+    }
+
+    fn sleep(&self) {}
+
+    unsafe fn atomic<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        f()
+    }
+
+    unsafe fn print_state(&self, _writer: &mut dyn Write) {}
+}
+
+/// Accepts every change to a process's context, which is never switched to.
+#[derive(Default)]
+pub struct MockBoundary;
+
+impl UserspaceKernelBoundary for MockBoundary {
+    type StoredState = ();
+
+    fn initial_process_app_brk_size(&self) -> usize {
+        0
+    }
+
+    unsafe fn initialize_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+    ) -> Result<(), ()> {
+        Ok(())
+    }
+
+    unsafe fn set_syscall_return_value(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+        _return_value: SyscallReturn,
+    ) -> Result<(), ()> {
+        Ok(())
+    }
+
+    unsafe fn set_process_function(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+        _upcall: FunctionCall,
+    ) -> Result<(), ()> {
+        Ok(())
+    }
+
+    unsafe fn switch_to_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+    ) -> (ContextSwitchReason, Option<*const u8>) {
+        (ContextSwitchReason::Interrupted, None)
+    }
+
+    unsafe fn print_context(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &(),
+        _writer: &mut dyn Write,
+    ) {
+    }
+}
+
+#[derive(Default)]
+pub struct MockLed {
+    on: Cell<bool>,
+}
+
+impl Led for MockLed {
+    fn init(&self) {}
+
+    fn on(&self) {
+        self.on.set(true);
+    }
+
+    fn off(&self) {
+        self.on.set(false);
+    }
+
+    fn toggle(&self) {
+        self.on.set(!self.on.get());
+    }
+
+    fn read(&self) -> bool {
+        self.on.get()
+    }
+}
+
+/// A PWM pin that only checks its arguments.
+#[derive(Default)]
+pub struct MockPwmPin;
+
+impl PwmPin for MockPwmPin {
+    fn start(&self, frequency_hz: usize, duty_cycle: usize) -> Result<(), ErrorCode> {
+        if frequency_hz == 0
+            || frequency_hz > self.get_maximum_frequency_hz()
+            || duty_cycle > self.get_maximum_duty_cycle()
+        {
+            Err(ErrorCode::INVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn stop(&self) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    fn get_maximum_frequency_hz(&self) -> usize {
+        16_000_000
+    }
+
+    fn get_maximum_duty_cycle(&self) -> usize {
+        0x7fff
+    }
+}
+
+/// A quadrature decoder whose position the fuzzer moves.
+pub struct MockQdec<'a> {
+    enabled: Cell<bool>,
+    position: Cell<i32>,
+    client: OptionalCell<&'a dyn QuadratureDecoderClient>,
+}
+
+impl<'a> MockQdec<'a> {
+    pub fn new() -> MockQdec<'a> {
+        MockQdec {
+            enabled: Cell::new(false),
+            position: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Move the position by `steps` and tell the client, if enabled.
+    pub fn turn(&self, steps: i32) {
+        if self.enabled.get() {
+            let position = self.position.get().wrapping_add(steps);
+            self.position.set(position);
+            self.client.map(|client| client.position_changed(position));
+        }
+    }
+}
+
+impl<'a> QuadratureDecoder<'a> for MockQdec<'a> {
+    fn set_client(&self, client: &'a dyn QuadratureDecoderClient) {
+        self.client.set(client);
+    }
+
+    fn enable(&self) -> Result<(), ErrorCode> {
+        self.enabled.set(true);
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        self.enabled.set(false);
+        Ok(())
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    fn position(&self) -> i32 {
+        self.position.get()
+    }
+
+    fn set_position(&self, position: i32) {
+        self.position.set(position);
+    }
+}
+
+/// An alarm whose time only moves when the fuzzer advances it.
+pub struct MockAlarm<'a> {
+    now: Cell<u32>,
+    reference: Cell<u32>,
+    dt: Cell<u32>,
+    armed: Cell<bool>,
+    client: OptionalCell<&'a dyn time::AlarmClient>,
+}
+
+impl<'a> MockAlarm<'a> {
+    pub fn new() -> MockAlarm<'a> {
+        MockAlarm {
+            now: Cell::new(0),
+            reference: Cell::new(0),
+            dt: Cell::new(0),
+            armed: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Move time forward by `ticks` and fire the alarm if it expired.
+    pub fn advance(&self, ticks: u32) {
+        let now = self.now.get().wrapping_add(ticks);
+        self.now.set(now);
+        let elapsed = now.wrapping_sub(self.reference.get());
+        if self.armed.get() && elapsed >= self.dt.get() {
+            self.fire();
+        }
+    }
+
+    /// Fire the alarm if it is armed, whether or not it expired.
+    pub fn fire(&self) {
+        if self.armed.get() {
+            self.armed.set(false);
+            self.client.map(|client| client.alarm());
+        }
+    }
+}
+
+impl Time for MockAlarm<'_> {
+    type Frequency = time::Freq1KHz;
+    type Ticks = time::Ticks32;
+
+    fn now(&self) -> Self::Ticks {
+        self.now.get().into()
+    }
+}
+
+impl<'a> Alarm<'a> for MockAlarm<'a> {
+    fn set_alarm_client(&'a self, client: &'a dyn time::AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) {
+        self.reference.set(reference.into_u32());
+        self.dt.set(dt.into_u32());
+        self.armed.set(true);
+    }
+
+    fn get_alarm(&self) -> Self::Ticks {
+        self.reference.get().wrapping_add(self.dt.get()).into()
+    }
+
+    fn disarm(&self) -> Result<(), ErrorCode> {
+        self.armed.set(false);
+        Ok(())
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+
+    fn minimum_dt(&self) -> Self::Ticks {
+        1.into()
+    }
+}
+
+/// A random number generator that delivers as many numbers as the fuzzer
+/// tells it to.
+pub struct MockRng<'a> {
+    requested: Cell<bool>,
+    next: Cell<u32>,
+    client: OptionalCell<&'a dyn rng::Client>,
+}
+
+impl<'a> MockRng<'a> {
+    pub fn new() -> MockRng<'a> {
+        MockRng {
+            requested: Cell::new(false),
+            next: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Deliver `count` numbers if any were requested.
+    pub fn deliver(&self, count: usize) {
+        if !self.requested.get() {
+            return;
+        }
+        self.requested.set(false);
+        let start = self.next.get();
+        self.next.set(start.wrapping_add(count as u32));
+        let mut randomness = (0..count as u32).map(|i| start.wrapping_add(i));
+        let more = self.client.map_or(rng::Continue::Done, |client| {
+            client.randomness_available(&mut randomness, Ok(()))
+        });
+        if more == rng::Continue::More {
+            self.requested.set(true);
+        }
+    }
+}
+
+impl<'a> Rng<'a> for MockRng<'a> {
+    fn get(&self) -> Result<(), ErrorCode> {
+        self.requested.set(true);
+        Ok(())
+    }
+
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        self.requested.set(false);
+        Ok(())
+    }
+
+    fn set_client(&'a self, client: &'a dyn rng::Client) {
+        self.client.set(client);
+    }
+}
+
+/// Boot status taken from the fuzz input.
+pub struct MockBootStatus {
+    pub verification: Verification,
+    pub key_id: Option<u32>,
+    pub counters: [u32; 4],
+    pub num_counters: usize,
+}
+
+impl SecureBootStatus for MockBootStatus {
+    fn verification(&self) -> Verification {
+        self.verification
+    }
+
+    fn key_id(&self) -> Option<u32> {
+        self.key_id
+    }
+
+    fn rollback_counters(&self) -> usize {
+        self.num_counters
+    }
+
+    fn rollback_counter(&self, index: usize) -> Option<u32> {
+        self.counters[..self.num_counters].get(index).copied()
+    }
+}
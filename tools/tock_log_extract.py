@@ -0,0 +1,159 @@
+#!/usr/bin/env python3
+
+# Extracts the entries of a Tock persistent log from console output.
+#
+# Usage: tock_log_extract.py [options] [FILE]
+#
+# The log is dumped by `capsules::log_driver`, from the process console's
+# `log` command or by a process.
+
+'''
+Script to extract the entries of a Tock persistent log (`capsules::log_driver`)
+from console output.
+
+Reads the console output from FILE, from stdin if no FILE is given, or from a
+serial port with --port. Output captured with `tockloader listen` works as
+is. Lines that are not part of the dump are ignored.
+
+Usage: tock_log_extract.py [options] [FILE]
+Options:
+  -p, --port=PORT     Read from serial port PORT and send the process console
+                      `log` command to start the dump. Needs pyserial.
+  -b, --baud=BAUD     Baud rate of the serial port. Default: 115200
+  -o, --output=FILE   Write the entries to FILE instead of stdout.
+  -f, --format=FMT    Write the entries as `hex` (one entry per line, the
+                      default), `raw` (the entries one after another) or
+                      `prefixed` (each entry preceded by its length as a
+                      little-endian 32 bit integer).
+  -h, --help          Print this message.
+'''
+
+import getopt
+import struct
+import sys
+
+MARKER = 'TOCKLOG'
+
+
+def usage(message=None):
+    if message:
+        print(message, file=sys.stderr)
+    print(__doc__, file=sys.stderr)
+    sys.exit(1)
+
+
+def serial_lines(port, baud):
+    '''Start a dump over the serial port and yield the lines it sends.'''
+    try:
+        import serial  # pylint: disable=import-outside-toplevel
+    except ImportError:
+        print('Reading from a serial port needs pyserial.', file=sys.stderr)
+        sys.exit(1)
+
+    connection = serial.Serial(port, baud, timeout=5)
+    connection.write(b'log\r\n')
+    while True:
+        line = connection.readline()
+        if not line:
+            print('Timed out waiting for the log dump.', file=sys.stderr)
+            sys.exit(1)
+        yield line.decode('ascii', errors='replace')
+
+
+def parse_dump(lines):
+    '''Return the (entry id, data) pairs of the first dump in lines.
+
+    Exits with an error if the dump is incomplete or reports a failure.
+    '''
+    entries = []
+    started = False
+    for line in lines:
+        # Console output from the kernel may precede the marker on the line.
+        index = line.find(MARKER + ' ')
+        if index < 0:
+            continue
+        fields = line[index:].split()
+        if fields[1] == 'BEGIN':
+            entries = []
+            started = True
+        elif not started:
+            continue
+        elif fields[1] in ('END', 'ERROR'):
+            count = int(fields[2])
+            if count != len(entries):
+                print('Dump has {} entries but {} were received.'.format(
+                    count, len(entries)), file=sys.stderr)
+                sys.exit(1)
+            if fields[1] == 'ERROR':
+                print('Reading the log failed after {} entries.'.format(count),
+                      file=sys.stderr)
+                sys.exit(1)
+            return entries
+        else:
+            entries.append((int(fields[1], 16), bytes.fromhex(fields[2])))
+
+    if started:
+        print('Dump ended before its END marker.', file=sys.stderr)
+    else:
+        print('No log dump found.', file=sys.stderr)
+    sys.exit(1)
+
+
+def write_entries(entries, output, fmt):
+    if fmt == 'hex':
+        for (entry_id, data) in entries:
+            output.write('{:08x} {}\n'.format(entry_id, data.hex()).encode())
+    elif fmt == 'raw':
+        for (_, data) in entries:
+            output.write(data)
+    else:
+        for (_, data) in entries:
+            output.write(struct.pack('<I', len(data)))
+            output.write(data)
+
+
+def main():
+    try:
+        opts, args = getopt.getopt(sys.argv[1:], 'p:b:o:f:h',
+                                   ['port=', 'baud=', 'output=', 'format=',
+                                    'help'])
+    except getopt.GetoptError as err:
+        usage(str(err))
+
+    port = None
+    baud = 115200
+    output_name = None
+    fmt = 'hex'
+    for opt, val in opts:
+        if opt in ('-p', '--port'):
+            port = val
+        elif opt in ('-b', '--baud'):
+            baud = int(val)
+        elif opt in ('-o', '--output'):
+            output_name = val
+        elif opt in ('-f', '--format'):
+            if val not in ('hex', 'raw', 'prefixed'):
+                usage('Unknown format: ' + val)
+            fmt = val
+        elif opt in ('-h', '--help'):
+            usage()
+
+    if port and args:
+        usage('Give either a serial port or a file, not both.')
+    if port:
+        entries = parse_dump(serial_lines(port, baud))
+    elif args:
+        with open(args[0], encoding='ascii', errors='replace') as capture:
+            entries = parse_dump(capture)
+    else:
+        entries = parse_dump(sys.stdin)
+
+    if output_name:
+        with open(output_name, 'wb') as output:
+            write_entries(entries, output, fmt)
+    else:
+        write_entries(entries, sys.stdout.buffer, fmt)
+
+
+if __name__ == '__main__':
+    main()
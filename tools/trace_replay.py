@@ -0,0 +1,121 @@
+#!/usr/bin/env python3
+
+# Converts a trace printed by `kernel::replay::TraceBuffer` into Rust.
+#
+# Usage: trace_replay.py CONSOLE_LOG [OUTPUT]
+
+'''
+Script to turn a recorded kernel trace into a trace a board can replay.
+
+Boards built with trace recording print the events the kernel handled, one
+per line prefixed with `TOCK-TRACE:`, usually from the panic handler. This
+script reads a console log containing such a trace and writes a Rust module
+defining `TRACE`, an array of `kernel::replay::TraceEvent`, for a board built
+for replay to include (for earlgrey-nexysvideo, `src/replay_trace.rs` with the
+`trace_replay` feature). If the log holds several traces, the last is used.
+
+Usage: trace_replay.py CONSOLE_LOG [OUTPUT]
+
+With no OUTPUT, the module is printed to stdout.
+'''
+
+import sys
+
+MARKER = 'TOCK-TRACE:'
+
+SYSCALL_CLASSES = {
+    0: 'Yield',
+    1: 'Subscribe',
+    2: 'Command',
+    3: 'ReadWriteAllow',
+    4: 'ReadOnlyAllow',
+    5: 'Memop',
+    6: 'Exit',
+}
+
+
+def parse_int(value):
+    return int(value, 0)
+
+
+def parse_event(fields):
+    '''The Rust expression for one trace line, split into fields.'''
+    kind, args = fields[0], [parse_int(f) for f in fields[1:]]
+    if kind == 'irq' and len(args) == 1:
+        return 'TraceEvent::Interrupt({})'.format(args[0])
+    if kind == 'deferred' and len(args) == 1:
+        return 'TraceEvent::DeferredCall({})'.format(args[0])
+    if kind == 'cmd' and len(args) == 6:
+        return ('TraceEvent::Command {{ process: {}, driver: {:#x}, subdriver: {}, '
+                'arg0: {:#x}, arg1: {:#x}, result: {} }}').format(*args)
+    if kind == 'syscall' and len(args) == 2 and args[1] in SYSCALL_CLASSES:
+        return 'TraceEvent::Syscall {{ process: {}, class: SyscallClass::{} }}'.format(
+            args[0], SYSCALL_CLASSES[args[1]])
+    raise ValueError('bad trace line: {}'.format(' '.join(fields)))
+
+
+def read_trace(lines):
+    '''Events of the last complete trace in `lines`, and how many were lost.'''
+    trace = None
+    complete = None
+    for line in lines:
+        position = line.find(MARKER)
+        if position < 0:
+            continue
+        fields = line[position + len(MARKER):].split()
+        if not fields:
+            continue
+        if fields[0] == 'begin':
+            trace = ([], parse_int(fields[2]) if len(fields) > 2 else 0)
+        elif fields[0] == 'end':
+            if trace is not None:
+                complete = trace
+            trace = None
+        elif trace is not None:
+            trace[0].append(parse_event(fields))
+    if complete is None:
+        raise ValueError('no complete trace found')
+    return complete
+
+
+def render(events, dropped, source):
+    out = []
+    out.append('// Trace recorded in `{}`.'.format(source))
+    out.append('//')
+    out.append('// Generated by `tools/trace_replay.py`; do not edit.')
+    if dropped:
+        out.append('//')
+        out.append('// The {} oldest events were overwritten before the trace was printed.'.format(dropped))
+    out.append('')
+    out.append('#[allow(unused_imports)]')
+    out.append('use kernel::replay::TraceEvent;')
+    out.append('#[allow(unused_imports)]')
+    out.append('use kernel::syscall::SyscallClass;')
+    out.append('')
+    out.append('pub static TRACE: [TraceEvent; {}] = ['.format(len(events)))
+    for event in events:
+        out.append('    {},'.format(event))
+    out.append('];')
+    return '\n'.join(out) + '\n'
+
+
+def main():
+    if len(sys.argv) not in (2, 3):
+        print(__doc__)
+        sys.exit(1)
+    with open(sys.argv[1], errors='replace') as f:
+        try:
+            events, dropped = read_trace(f)
+        except ValueError as e:
+            print('{}: {}'.format(sys.argv[1], e), file=sys.stderr)
+            sys.exit(1)
+    module = render(events, dropped, sys.argv[1])
+    if len(sys.argv) == 3:
+        with open(sys.argv[2], 'w') as f:
+            f.write(module)
+    else:
+        sys.stdout.write(module)
+
+
+if __name__ == '__main__':
+    main()